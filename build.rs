@@ -12,14 +12,25 @@ use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
-    // on the linker search path.
+    // on the linker search path. Builds for the embassy-boot bootloader
+    // get its layout instead.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory: &[u8] = match env::var_os("CARGO_FEATURE_DFU") {
+        Some(_) => include_bytes!("./memory-dfu.x"),
+        None => include_bytes!("./memory.x"),
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("./memory.x"))
+        .write_all(memory)
+        .unwrap();
+    // the linker sections both layouts include
+    File::create(out.join("sections.x"))
+        .unwrap()
+        .write_all(include_bytes!("./sections.x"))
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
     println!("cargo:rerun-if-changed={{layout}}");
@@ -35,7 +46,37 @@ fn main() {
     // The `link-rp.x` linker script provided by `embassy_rp` that defines the
     // BOOT2 section.
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
+    println!("cargo:rustc-link-arg-examples=-Tlink-rp.x");
+
+    // The `defmt.x` linker script provided by `defmt`, for builds logging
+    // with it.
+    if env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+        println!("cargo:rustc-link-arg-examples=-Tdefmt.x");
+    }
+
+    // The commit we're built from, for the firmware revision in the Device
+    // Information Service. "unknown" outside a git checkout.
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash());
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+}
 
-    // The `defmt.x` linker script provided by `defmt`.
-    println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+/// Short hash of HEAD, with "-dirty" if there are uncommitted changes
+fn git_hash() -> String {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|out| out.status.success())
+            .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+    };
+    let Some(hash) = git(&["rev-parse", "--short", "HEAD"]) else {
+        return "unknown".into();
+    };
+    match git(&["status", "--porcelain", "--untracked-files=no"]) {
+        Some(status) if !status.is_empty() => format!("{hash}-dirty"),
+        _ => hash,
+    }
 }
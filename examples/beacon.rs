@@ -0,0 +1,156 @@
+//! BTHome beacon
+//!
+//! Broadcasts the readings of the environmental sensor as BTHome v2, which
+//! Home Assistant picks up on its own, and nothing else: no connections,
+//! no GATT server. Every [`PERIOD`] the readings are sent for
+//! [`BROADCAST_WINDOW`], and the radio is quiet in between. With the
+//! `aes` feature and `BTHOME_BINDKEY` set at build time the broadcasts are
+//! encrypted, see [`emb_test::bthome`].
+//!
+//! Wiring, as on the original board: the sensor on I²C0 with SDA on GPIO0
+//! and SCL on GPIO1.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_rp::bind_interrupts;
+use embassy_rp::i2c;
+use embassy_rp::i2c::I2c;
+use embassy_rp::i2c::InterruptHandler as I2CInterruptHandler;
+use embassy_rp::peripherals::I2C0;
+use embassy_rp::peripherals::PIO0;
+use embassy_rp::peripherals::USB;
+use embassy_rp::pio::InterruptHandler as PIOInterruptHandler;
+use embassy_rp::usb::InterruptHandler as USBInterruptHandler;
+use embassy_time::Duration;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+
+use emb_test::adv;
+use emb_test::board;
+use emb_test::bthome;
+use emb_test::bus;
+use emb_test::environment;
+use emb_test::error;
+use emb_test::fmt;
+use emb_test::info;
+use emb_test::modules;
+use emb_test::modules::Resources;
+use emb_test::params;
+use emb_test::system;
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => USBInterruptHandler<USB>;
+    I2C0_IRQ => I2CInterruptHandler<I2C0>;
+    PIO0_IRQ_0 => PIOInterruptHandler<PIO0>;
+});
+
+/// Time from one broadcast to the next
+const PERIOD: Duration = Duration::from_secs(10);
+
+/// How long each broadcast goes on for
+const BROADCAST_WINDOW: Duration = Duration::from_millis(1000);
+
+/// Broadcast the latest readings for a while
+async fn broadcast<C: Controller>(
+    peripheral: &mut Peripheral<'_, C>,
+    own: [u8; 6],
+) -> Result<(), BleHostError<C::Error>> {
+    let counter = bthome::next_counter();
+    let temperature = i16::from_le_bytes(environment::temperature());
+    let humidity = u16::from_le_bytes(environment::humidity());
+    let mut objects = [bthome::Object::PacketId(counter as u8); 3];
+    let mut len = 1;
+    if temperature != environment::TEMPERATURE_UNKNOWN {
+        objects[len] = bthome::Object::Temperature(temperature);
+        len += 1;
+    }
+    if humidity != environment::HUMIDITY_UNKNOWN {
+        objects[len] = bthome::Object::Humidity(humidity);
+        len += 1;
+    }
+
+    let mut service_data = [0; bthome::MAX_SERVICE_DATA];
+    #[cfg(feature = "aes")]
+    let service_len = match option_env!("BTHOME_BINDKEY") {
+        Some(key) => bthome::Encryptor::new(&bthome::parse_bindkey(key), own).encode(
+            counter,
+            &objects[..len],
+            &mut service_data,
+        ),
+        None => bthome::encode(&objects[..len], &mut service_data),
+    };
+    #[cfg(not(feature = "aes"))]
+    let service_len = {
+        let _ = own;
+        bthome::encode(&objects[..len], &mut service_data)
+    };
+
+    let payload = adv::Builder::new()
+        .flags(BR_EDR_NOT_SUPPORTED)
+        // the buffer is sized for these objects
+        .service_data16(bthome::UUID, &service_data[..service_len.unwrap()])
+        .build()
+        // and all of them fit in the advertising data
+        .unwrap();
+
+    info!("[beacon] broadcasting {} objects", len);
+    let _advertiser = peripheral
+        .advertise(
+            &Default::default(),
+            Advertisement::NonconnectableNonscannableUndirected {
+                adv_data: payload.adv_data(),
+            },
+        )
+        .await?;
+    Timer::after(BROADCAST_WINDOW).await;
+    Ok(())
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let mut p = embassy_rp::init(Default::default());
+    board::init(spawner, p.FLASH, &mut p.ADC, &mut p.PIN_27).await;
+    #[cfg(feature = "log")]
+    board::logger(spawner, p.USB, Irqs);
+    info!("BTHome beacon");
+
+    // the environment module measures on its own
+    let i2c = I2c::new_async(p.I2C0, p.PIN_1, p.PIN_0, Irqs, i2c::Config::default());
+    let bus = bus::share(i2c);
+    modules::init_all(&Resources { spawner, bus });
+
+    let radio = board::radio(
+        spawner, p.PIN_23, p.PIN_25, p.PIN_24, p.PIN_29, p.PIO0, p.DMA_CH0, Irqs,
+    )
+    .await;
+
+    let own = params::Config::DEFAULT.address.resolve().await;
+    let mut resources: HostResources<_, 0, 1, 27> = HostResources::new(PacketQos::None);
+    let (_, mut peripheral, _, mut runner) = trouble_host::new(radio.controller, &mut resources)
+        .set_random_address(Address::random(own))
+        .build();
+
+    let broadcasting = async {
+        loop {
+            if let Err(e) = broadcast(&mut peripheral, own).await {
+                return e;
+            }
+            Timer::after(PERIOD - BROADCAST_WINDOW).await;
+        }
+    };
+    match select(runner.run(), broadcasting).await {
+        Either::First(Ok(())) => error!("ble runner stopped"),
+        Either::First(Err(e)) | Either::Second(e) => {
+            error!("ble stack stopped: {:?}", fmt::Dbg(&e))
+        }
+    }
+    // the radio only starts once, start over
+    system::reboot();
+}
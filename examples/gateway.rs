@@ -0,0 +1,79 @@
+//! BLE to MQTT gateway
+//!
+//! Scans for advertisements and forwards them to an MQTT broker over
+//! Wi-Fi, see [`emb_test::gateway`]. The BLE side only observes: no
+//! advertising, no GATT server. The network services of the firmware run
+//! too, the HTTP API among them.
+//!
+//! Nothing is forwarded until the network and the broker are set, over the
+//! console:
+//!
+//! ```text
+//! config wifi.ssid <network>
+//! config wifi.password <password>
+//! config gateway.broker <host[:port]>
+//! ```
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::PIO0;
+use embassy_rp::peripherals::USB;
+use embassy_rp::pio::InterruptHandler as PIOInterruptHandler;
+use embassy_rp::usb::InterruptHandler as USBInterruptHandler;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+
+use emb_test::board;
+use emb_test::error;
+use emb_test::info;
+use emb_test::lighting::Message;
+use emb_test::net;
+use emb_test::observer;
+use emb_test::system;
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => USBInterruptHandler<USB>;
+    PIO0_IRQ_0 => PIOInterruptHandler<PIO0>;
+});
+
+/// Nothing to light, the HTTP API's lighting messages go nowhere
+static LIGHTING: Channel<CriticalSectionRawMutex, Message, 1> = Channel::new();
+
+#[embassy_executor::task]
+async fn lighting_task() -> ! {
+    loop {
+        LIGHTING.receive().await;
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let mut p = embassy_rp::init(Default::default());
+    board::init(spawner, p.FLASH, &mut p.ADC, &mut p.PIN_27).await;
+    #[cfg(feature = "log")]
+    board::logger(spawner, p.USB, Irqs);
+    info!("BLE to MQTT gateway");
+    spawner.must_spawn(lighting_task());
+
+    let radio = board::radio(
+        spawner, p.PIN_23, p.PIN_25, p.PIN_24, p.PIN_29, p.PIO0, p.DMA_CH0, Irqs,
+    )
+    .await;
+    let control = net::Control::new(radio.control);
+    // the network side never returns, the observer logs why it stopped
+    select(
+        net::run(&control, radio.net, LIGHTING.sender()),
+        observer::run(radio.controller),
+    )
+    .await;
+    // the radio only starts once, start over
+    error!("restarting");
+    system::reboot();
+}
@@ -0,0 +1,295 @@
+//! HID remote
+//!
+//! A media remote over HID over GATT: three buttons for volume up, volume
+//! down and play/pause, which a phone or a computer takes as its media
+//! keys. One host at a time, advertising again once it disconnects.
+//!
+//! The BLE host we're on can't pair yet (see [`emb_test::hid`]), and
+//! many hosts only take HID devices over an encrypted link. Those won't
+//! use the remote until it can.
+//!
+//! Wiring: the buttons between GND and GPIO14 (volume up), GPIO13 (volume
+//! down) and GPIO15 (play/pause, the button of the original board).
+
+#![no_std]
+#![no_main]
+
+use core::convert::Infallible;
+
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3;
+use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::Input;
+use embassy_rp::gpio::Pull;
+use embassy_rp::peripherals::PIO0;
+use embassy_rp::peripherals::USB;
+use embassy_rp::pio::InterruptHandler as PIOInterruptHandler;
+use embassy_rp::usb::InterruptHandler as USBInterruptHandler;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+
+use emb_test::adv;
+use emb_test::battery;
+use emb_test::board;
+use emb_test::error;
+use emb_test::fmt;
+use emb_test::info;
+use emb_test::latency;
+use emb_test::params;
+use emb_test::session;
+use emb_test::subscriptions;
+use emb_test::system;
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => USBInterruptHandler<USB>;
+    PIO0_IRQ_0 => PIOInterruptHandler<PIO0>;
+});
+
+const NAME: &str = "hid remote";
+
+/// GAP appearance, generic remote control
+const APPEARANCE: u16 = 0x0180;
+
+/// Attributes of the services below, with room to spare
+const ATTRIBUTES_MAX: usize = 32;
+
+/// Consumer control, one input report of a bit per key
+#[rustfmt::skip]
+const REPORT_MAP: [u8; 29] = [
+    0x05, 0x0c, // usage page (consumer)
+    0x09, 0x01, // usage (consumer control)
+    0xa1, 0x01, // collection (application)
+    0x85, REPORT_ID, // report id
+    0x15, 0x00, // logical minimum (0)
+    0x25, 0x01, // logical maximum (1)
+    0x75, 0x01, // report size (1)
+    0x95, 0x03, // report count (3)
+    0x09, 0xe9, // usage (volume increment)
+    0x09, 0xea, // usage (volume decrement)
+    0x09, 0xcd, // usage (play/pause)
+    0x81, 0x02, // input (data, variable, absolute)
+    0x95, 0x05, // report count (5), padding
+    0x81, 0x03, // input (constant)
+    0xc0,       // end collection
+];
+
+const REPORT_ID: u8 = 1;
+
+/// Report Reference descriptor: the report id, and 1 for an input report
+const REPORT_REFERENCE: [u8; 2] = [REPORT_ID, 0x01];
+
+/// HID Information: HID 1.11, no country, normally connectable
+const HID_INFORMATION: [u8; 4] = [0x11, 0x01, 0x00, 0x02];
+
+// report bits, in the order of the report map
+const VOLUME_UP: u8 = 0x01;
+const VOLUME_DOWN: u8 = 0x02;
+const PLAY_PAUSE: u8 = 0x04;
+
+#[gatt_server(attribute_data_size = 32)]
+struct Server {}
+
+struct Buttons {
+    volume_up: Input<'static>,
+    volume_down: Input<'static>,
+    play_pause: Input<'static>,
+}
+
+impl Buttons {
+    /// Wait for a button to be pressed, returning its report bit and the
+    /// button
+    async fn pressed(&mut self) -> (u8, &mut Input<'static>) {
+        let pressed = select3(
+            self.volume_up.wait_for_falling_edge(),
+            self.volume_down.wait_for_falling_edge(),
+            self.play_pause.wait_for_falling_edge(),
+        )
+        .await;
+        match pressed {
+            Either3::First(()) => (VOLUME_UP, &mut self.volume_up),
+            Either3::Second(()) => (VOLUME_DOWN, &mut self.volume_down),
+            Either3::Third(()) => (PLAY_PAUSE, &mut self.play_pause),
+        }
+    }
+}
+
+/// Send a report for every press and release of a button, while the host
+/// is subscribed to them
+async fn send_keys<C: Controller>(
+    server: &Server<'_, '_, C>,
+    report: Characteristic,
+    conn: &Connection<'_>,
+    buttons: &mut Buttons,
+) -> ! {
+    loop {
+        let (key, button) = buttons.pressed().await;
+        // contacts bounce
+        Timer::after_millis(20).await;
+        for bits in [key, 0] {
+            let _ = server.set(report, &[bits]);
+            if subscriptions::is_subscribed(conn.handle(), report.handle) {
+                if let Err(e) = server.notify(report, conn, &[bits]).await {
+                    error!("[hid] report failed: {:?}", fmt::Dbg(&e));
+                }
+            }
+            if bits != 0 {
+                button.wait_for_high().await;
+                Timer::after_millis(20).await;
+            }
+        }
+    }
+}
+
+/// Keep track of what the host subscribed to
+async fn handle_events<C: Controller>(server: &Server<'_, '_, C>, report: Characteristic) -> ! {
+    loop {
+        match server.next().await {
+            Ok(GattEvent::Write { handle, connection }) => {
+                if Some(handle.handle) == report.cccd_handle {
+                    let on = server
+                        .get(handle, |value| {
+                            value.first().is_some_and(|bits| bits & 0x01 != 0)
+                        })
+                        .unwrap_or(false);
+                    info!("[hid] reports {}", if on { "on" } else { "off" });
+                    subscriptions::set(connection.handle(), report.handle, on);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("[hid] GATT error: {:?}", fmt::Dbg(&e)),
+        }
+    }
+}
+
+/// Advertise, and serve hosts one after the other
+async fn serve<C: Controller>(
+    peripheral: &mut Peripheral<'_, C>,
+    server: &Server<'_, '_, C>,
+    report: Characteristic,
+    buttons: &mut Buttons,
+) -> Result<Infallible, BleHostError<C::Error>> {
+    let payload = adv::Builder::new()
+        .flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED)
+        .service_uuids16(&[0x1812])
+        .name(NAME)
+        .build()
+        // a fixed name and one UUID always fit
+        .unwrap();
+
+    loop {
+        info!("[hid] advertising");
+        let advertiser = peripheral
+            .advertise(
+                &Default::default(),
+                Advertisement::ConnectableScannableUndirected {
+                    adv_data: payload.adv_data(),
+                    scan_data: payload.scan_data(),
+                },
+            )
+            .await?;
+        let conn = advertiser.accept().await?;
+        info!("[hid] connected to {:?}", conn.peer_address());
+        session::open(conn.handle());
+        select(
+            send_keys(server, report, &conn, buttons),
+            latency::wait_disconnected(&conn),
+        )
+        .await;
+        session::close(conn.handle());
+        info!("[hid] disconnected");
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let mut p = embassy_rp::init(Default::default());
+    board::init(spawner, p.FLASH, &mut p.ADC, &mut p.PIN_27).await;
+    #[cfg(feature = "log")]
+    board::logger(spawner, p.USB, Irqs);
+    info!("HID remote");
+
+    let mut buttons = Buttons {
+        volume_up: Input::new(p.PIN_14, Pull::Up),
+        volume_down: Input::new(p.PIN_13, Pull::Up),
+        play_pause: Input::new(p.PIN_15, Pull::Up),
+    };
+
+    let radio = board::radio(
+        spawner, p.PIN_23, p.PIN_25, p.PIN_24, p.PIN_29, p.PIO0, p.DMA_CH0, Irqs,
+    )
+    .await;
+
+    let own = params::Config::DEFAULT.address.resolve().await;
+    let mut resources: HostResources<_, 1, 2, 27> = HostResources::new(PacketQos::None);
+    let (stack, mut peripheral, _, mut runner) =
+        trouble_host::new(radio.controller, &mut resources)
+            .set_random_address(Address::random(own))
+            .build();
+
+    let mut table: AttributeTable<'_, NoopRawMutex, ATTRIBUTES_MAX> = AttributeTable::new();
+
+    // Generic Access and Generic Attribute (mandatory)
+    let appearance = APPEARANCE.to_le_bytes();
+    let mut svc = table.add_service(Service::new(0x1800));
+    let _ = svc.add_characteristic_ro(0x2a00, NAME.as_bytes());
+    let _ = svc.add_characteristic_ro(0x2a01, &appearance[..]);
+    svc.build();
+    table.add_service(Service::new(0x1801));
+
+    // Battery Service, required next to HID. Full without a battery pin.
+    let battery_level = [battery::level().unwrap_or(100)];
+    let mut svc = table.add_service(Service::new(0x180f));
+    let _ = svc.add_characteristic_ro(0x2a19, &battery_level[..]);
+    svc.build();
+
+    // HID Service
+    let mut report_value = [0u8; 1];
+    let mut control_point_value = [0u8; 1];
+    let report = {
+        let mut svc = table.add_service(Service::new(0x1812));
+        let _ = svc.add_characteristic_ro(0x2a4a, &HID_INFORMATION[..]);
+        let _ = svc.add_characteristic_ro(0x2a4b, &REPORT_MAP[..]);
+        // suspend and exit suspend, nothing to do for either
+        let _ = svc
+            .add_characteristic(
+                0x2a4c,
+                &[CharacteristicProp::WriteWithoutResponse],
+                &mut control_point_value,
+            )
+            .build();
+        let mut report = svc.add_characteristic(
+            0x2a4d,
+            &[CharacteristicProp::Read, CharacteristicProp::Notify],
+            &mut report_value,
+        );
+        report.add_descriptor_ro(0x2908, &REPORT_REFERENCE[..]);
+        let report = report.build();
+        svc.build();
+        report
+    };
+
+    let server = Server::new(stack, &mut table);
+    let stopped = match select3(
+        runner.run(),
+        handle_events(&server, report),
+        serve(&mut peripheral, &server, report, &mut buttons),
+    )
+    .await
+    {
+        Either3::First(stopped) => stopped,
+        Either3::Second(never) => never,
+        Either3::Third(Err(e)) => Err(e),
+        Either3::Third(Ok(never)) => match never {},
+    };
+    if let Err(e) = stopped {
+        error!("ble stack stopped: {:?}", fmt::Dbg(&e));
+    }
+    // the radio only starts once, start over
+    system::reboot();
+}
@@ -0,0 +1,119 @@
+//! Sensor node
+//!
+//! The environmental sensor on the I²C bus and the sensor on the ADC,
+//! served over BLE with the full GATT server: the Environmental Sensing
+//! Service, the ADC summaries, the battery level and the diagnostics. No
+//! LEDs, relays or Wi-Fi. The registered modules start as in the firmware,
+//! so whatever else is on the bus, like the RTC or the energy meter, is
+//! picked up too.
+//!
+//! Wiring, as on the original board: the sensor on I²C0 with SDA on GPIO0
+//! and SCL on GPIO1, the analog sensor on GPIO26.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_rp::adc;
+use embassy_rp::adc::Adc;
+use embassy_rp::adc::InterruptHandler as ADCInterruptHandler;
+use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::Pull;
+use embassy_rp::i2c;
+use embassy_rp::i2c::I2c;
+use embassy_rp::i2c::InterruptHandler as I2CInterruptHandler;
+use embassy_rp::peripherals::DMA_CH1;
+use embassy_rp::peripherals::I2C0;
+use embassy_rp::peripherals::PIO0;
+use embassy_rp::peripherals::USB;
+use embassy_rp::pio::InterruptHandler as PIOInterruptHandler;
+use embassy_rp::usb::InterruptHandler as USBInterruptHandler;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+
+use emb_test::accept;
+use emb_test::adcstream;
+use emb_test::blue;
+use emb_test::board;
+use emb_test::bus;
+use emb_test::error;
+use emb_test::handoff;
+use emb_test::info;
+use emb_test::lighting::Message;
+use emb_test::modules;
+use emb_test::modules::Resources;
+use emb_test::params;
+use emb_test::preset;
+use emb_test::system;
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => USBInterruptHandler<USB>;
+    I2C0_IRQ => I2CInterruptHandler<I2C0>;
+    PIO0_IRQ_0 => PIOInterruptHandler<PIO0>;
+    ADC_IRQ_FIFO => ADCInterruptHandler;
+});
+
+/// Rate of the sensor on the ADC
+const ADC_RATE_HZ: u32 = 1000;
+
+static CONFIG: params::Config = preset::Preset::Sensor
+    .apply(params::Config::DEFAULT)
+    .with_name("sensor node");
+
+/// Nothing to light, the GATT server's lighting messages go nowhere
+static LIGHTING: Channel<CriticalSectionRawMutex, Message, 1> = Channel::new();
+
+#[embassy_executor::task]
+async fn lighting_task() -> ! {
+    loop {
+        LIGHTING.receive().await;
+    }
+}
+
+#[embassy_executor::task]
+async fn adc_task(
+    mut adc: Adc<'static, adc::Async>,
+    mut channel: adc::Channel<'static>,
+    dma: DMA_CH1,
+) -> ! {
+    let div = adcstream::divider(ADC_RATE_HZ).unwrap();
+    adcstream::run(&mut adc, &mut channel, None, dma, div).await;
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let mut p = embassy_rp::init(Default::default());
+    board::init(spawner, p.FLASH, &mut p.ADC, &mut p.PIN_27).await;
+    #[cfg(feature = "log")]
+    board::logger(spawner, p.USB, Irqs);
+    info!("sensor node");
+
+    let i2c = I2c::new_async(p.I2C0, p.PIN_1, p.PIN_0, Irqs, i2c::Config::default());
+    let bus = bus::share(i2c);
+    modules::init_all(&Resources { spawner, bus });
+
+    let adc = Adc::new(p.ADC, Irqs, adc::Config::default());
+    let channel = adc::Channel::new_pin(p.PIN_26, Pull::None);
+    spawner.must_spawn(adc_task(adc, channel, p.DMA_CH1));
+    spawner.must_spawn(lighting_task());
+
+    let radio = board::radio(
+        spawner, p.PIN_23, p.PIN_25, p.PIN_24, p.PIN_29, p.PIO0, p.DMA_CH0, Irqs,
+    )
+    .await;
+    let mut updates = handoff::UPDATES.consumer().unwrap();
+    let exit = blue::run(
+        radio.controller,
+        LIGHTING.sender(),
+        &CONFIG,
+        &accept::AcceptAll,
+        &mut updates,
+    )
+    .await;
+    // the radio only starts once, start over
+    error!("ble stack stopped: {:?}", exit);
+    system::reboot();
+}
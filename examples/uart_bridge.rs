@@ -0,0 +1,285 @@
+//! UART bridge
+//!
+//! A serial port over BLE, with the Nordic UART Service that terminal apps
+//! like nRF Connect or Serial Bluetooth Terminal speak: what the client
+//! writes to the RX characteristic goes out the UART, and what comes in on
+//! the UART is notified on the TX characteristic, as much per notification
+//! as the ATT MTU takes. One client at a time. What comes in while nobody
+//! is connected or subscribed is dropped.
+//!
+//! Wiring: UART1 at [`BAUD_RATE`], TX on GPIO4 and RX on GPIO5.
+
+#![no_std]
+#![no_main]
+
+use core::convert::Infallible;
+
+use embassy_executor::Spawner;
+use embassy_futures::select::select;
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3;
+use embassy_rp::bind_interrupts;
+use embassy_rp::peripherals::PIO0;
+use embassy_rp::peripherals::UART1;
+use embassy_rp::peripherals::USB;
+use embassy_rp::pio::InterruptHandler as PIOInterruptHandler;
+use embassy_rp::uart;
+use embassy_rp::uart::BufferedInterruptHandler;
+use embassy_rp::uart::BufferedUart;
+use embassy_rp::uart::BufferedUartRx;
+use embassy_rp::uart::BufferedUartTx;
+use embassy_rp::usb::InterruptHandler as USBInterruptHandler;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embedded_io_async::Read;
+use embedded_io_async::Write;
+use static_cell::ConstStaticCell;
+use trouble_host::prelude::*;
+
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+
+use emb_test::adv;
+use emb_test::board;
+use emb_test::error;
+use emb_test::fmt;
+use emb_test::info;
+use emb_test::latency;
+use emb_test::params;
+use emb_test::session;
+use emb_test::subscriptions;
+use emb_test::system;
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => USBInterruptHandler<USB>;
+    PIO0_IRQ_0 => PIOInterruptHandler<PIO0>;
+    UART1_IRQ => BufferedInterruptHandler<UART1>;
+});
+
+const NAME: &str = "uart bridge";
+
+/// GAP appearance, generic computer peripheral
+const APPEARANCE: u16 = 0x0080;
+
+/// Speed of the UART
+const BAUD_RATE: u32 = 115_200;
+
+/// Attributes of the services below, with room to spare
+const ATTRIBUTES_MAX: usize = 16;
+
+/// L2CAP MTU, which leaves an ATT MTU of 247 if the client asks for it
+const L2CAP_MTU: usize = 251;
+
+/// Most bytes per write or notification
+const CHUNK_MAX: usize = L2CAP_MTU - 4 - 3;
+
+// the Nordic UART Service, little endian
+const NUS_UUID: [u8; 16] = nus_uuid(0x01);
+const RX_UUID: [u8; 16] = nus_uuid(0x02);
+const TX_UUID: [u8; 16] = nus_uuid(0x03);
+
+/// 6e4000xx-b5a3-f393-e0a9-e50e24dcca9e
+const fn nus_uuid(short: u8) -> [u8; 16] {
+    [
+        0x9e, 0xca, 0xdc, 0x24, 0x0e, 0xe5, 0xa9, 0xe0, 0x93, 0xf3, 0xa3, 0xb5, short, 0x00, 0x40,
+        0x6e,
+    ]
+}
+
+static TX_BUFFER: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0; 256]);
+static RX_BUFFER: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0; 256]);
+
+#[gatt_server(attribute_data_size = 32)]
+struct Server {}
+
+/// Notify what comes in on the UART, while the client is subscribed
+async fn uart_to_ble<C: Controller>(
+    server: &Server<'_, '_, C>,
+    tx: Characteristic,
+    conn: &Connection<'_>,
+    uart: &mut BufferedUartRx<'static, UART1>,
+) -> ! {
+    let mut buf = [0u8; CHUNK_MAX];
+    loop {
+        let chunk = (conn.att_mtu() as usize - 3).min(CHUNK_MAX);
+        let len = match uart.read(&mut buf[..chunk]).await {
+            Ok(len) => len,
+            Err(e) => {
+                error!("[uart] read failed: {:?}", fmt::Dbg(&e));
+                continue;
+            }
+        };
+        if !subscriptions::is_subscribed(conn.handle(), tx.handle) {
+            continue;
+        }
+        if let Err(e) = server.notify(tx, conn, &buf[..len]).await {
+            error!("[uart] notify failed: {:?}", fmt::Dbg(&e));
+        }
+    }
+}
+
+/// Send what the client writes out the UART, and keep track of its
+/// subscription
+async fn ble_to_uart<C: Controller>(
+    server: &Server<'_, '_, C>,
+    rx: Characteristic,
+    tx: Characteristic,
+    uart: &mut BufferedUartTx<'static, UART1>,
+) -> ! {
+    let mut buf = [0u8; CHUNK_MAX];
+    loop {
+        match server.next().await {
+            Ok(GattEvent::Write { handle, .. }) if handle == rx => {
+                let len = server
+                    .get(handle, |value| {
+                        let n = value.len().min(buf.len());
+                        buf[..n].copy_from_slice(&value[..n]);
+                        n
+                    })
+                    .unwrap_or(0);
+                if let Err(e) = uart.write_all(&buf[..len]).await {
+                    error!("[uart] write failed: {:?}", fmt::Dbg(&e));
+                }
+            }
+            Ok(GattEvent::Write { handle, connection })
+                if Some(handle.handle) == tx.cccd_handle =>
+            {
+                let on = server
+                    .get(handle, |value| {
+                        value.first().is_some_and(|bits| bits & 0x01 != 0)
+                    })
+                    .unwrap_or(false);
+                info!("[uart] notifications {}", if on { "on" } else { "off" });
+                subscriptions::set(connection.handle(), tx.handle, on);
+            }
+            Ok(_) => {}
+            Err(e) => error!("[uart] GATT error: {:?}", fmt::Dbg(&e)),
+        }
+    }
+}
+
+/// Advertise, and serve clients one after the other
+async fn serve<C: Controller>(
+    peripheral: &mut Peripheral<'_, C>,
+    server: &Server<'_, '_, C>,
+    tx: Characteristic,
+    uart: &mut BufferedUartRx<'static, UART1>,
+) -> Result<Infallible, BleHostError<C::Error>> {
+    let payload = adv::Builder::new()
+        .flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED)
+        .name(NAME)
+        .build()
+        // a fixed name always fits
+        .unwrap();
+
+    loop {
+        info!("[uart] advertising");
+        let advertiser = peripheral
+            .advertise(
+                &Default::default(),
+                Advertisement::ConnectableScannableUndirected {
+                    adv_data: payload.adv_data(),
+                    scan_data: payload.scan_data(),
+                },
+            )
+            .await?;
+        let conn = advertiser.accept().await?;
+        info!("[uart] connected to {:?}", conn.peer_address());
+        session::open(conn.handle());
+        select(
+            uart_to_ble(server, tx, &conn, uart),
+            latency::wait_disconnected(&conn),
+        )
+        .await;
+        session::close(conn.handle());
+        info!("[uart] disconnected");
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let mut p = embassy_rp::init(Default::default());
+    board::init(spawner, p.FLASH, &mut p.ADC, &mut p.PIN_27).await;
+    #[cfg(feature = "log")]
+    board::logger(spawner, p.USB, Irqs);
+    info!("UART bridge");
+
+    let mut config = uart::Config::default();
+    config.baudrate = BAUD_RATE;
+    let serial = BufferedUart::new(
+        p.UART1,
+        Irqs,
+        p.PIN_4,
+        p.PIN_5,
+        TX_BUFFER.take(),
+        RX_BUFFER.take(),
+        config,
+    );
+    let (mut uart_tx, mut uart_rx) = serial.split();
+
+    let radio = board::radio(
+        spawner, p.PIN_23, p.PIN_25, p.PIN_24, p.PIN_29, p.PIO0, p.DMA_CH0, Irqs,
+    )
+    .await;
+
+    let own = params::Config::DEFAULT.address.resolve().await;
+    let mut resources: HostResources<_, 1, 2, L2CAP_MTU> = HostResources::new(PacketQos::None);
+    let (stack, mut peripheral, _, mut runner) =
+        trouble_host::new(radio.controller, &mut resources)
+            .set_random_address(Address::random(own))
+            .build();
+
+    let mut table: AttributeTable<'_, NoopRawMutex, ATTRIBUTES_MAX> = AttributeTable::new();
+
+    // Generic Access and Generic Attribute (mandatory)
+    let appearance = APPEARANCE.to_le_bytes();
+    let mut svc = table.add_service(Service::new(0x1800));
+    let _ = svc.add_characteristic_ro(0x2a00, NAME.as_bytes());
+    let _ = svc.add_characteristic_ro(0x2a01, &appearance[..]);
+    svc.build();
+    table.add_service(Service::new(0x1801));
+
+    // Nordic UART Service
+    let mut rx_value = [0u8; CHUNK_MAX];
+    let mut tx_value = [0u8; CHUNK_MAX];
+    let (rx, tx) = {
+        let mut svc = table.add_service(Service::new(Uuid::new_long(NUS_UUID)));
+        let rx = svc
+            .add_characteristic(
+                Uuid::new_long(RX_UUID),
+                &[
+                    CharacteristicProp::Write,
+                    CharacteristicProp::WriteWithoutResponse,
+                ],
+                &mut rx_value,
+            )
+            .build();
+        let tx = svc
+            .add_characteristic(
+                Uuid::new_long(TX_UUID),
+                &[CharacteristicProp::Notify],
+                &mut tx_value,
+            )
+            .build();
+        svc.build();
+        (rx, tx)
+    };
+
+    let server = Server::new(stack, &mut table);
+    let stopped = match select3(
+        runner.run(),
+        ble_to_uart(&server, rx, tx, &mut uart_tx),
+        serve(&mut peripheral, &server, tx, &mut uart_rx),
+    )
+    .await
+    {
+        Either3::First(stopped) => stopped,
+        Either3::Second(never) => never,
+        Either3::Third(Err(e)) => Err(e),
+        Either3::Third(Ok(never)) => match never {},
+    };
+    if let Err(e) = stopped {
+        error!("ble stack stopped: {:?}", fmt::Dbg(&e));
+    }
+    // the radio only starts once, start over
+    system::reboot();
+}
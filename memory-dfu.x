@@ -0,0 +1,30 @@
+/* Memory regions for builds with the dfu feature, which run under the
+   embassy-boot bootloader (src/dfu.rs) */
+MEMORY {
+    BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
+
+    /* The bootloader, flashed separately */
+    BOOTLOADER : ORIGIN = 0x10000100, LENGTH = 24K - 0x100
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+
+    /* The active partition we run from, and the DFU partition updates are
+       written to, a sector larger for the swap. The last 464K are kept for
+       the partitions in src/partitions.rs: the radio firmware package
+       (256K), then the assets, settings and records as in memory.x */
+    FLASH : ORIGIN = 0x10007000, LENGTH = 776K
+    DFU : ORIGIN = 0x100c9000, LENGTH = 780K
+
+    RAM   : ORIGIN = 0x20000000, LENGTH = 264K
+}
+
+/* Partition offsets from the start of flash, for embassy-boot */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_active_start = ORIGIN(FLASH) - ORIGIN(BOOT2);
+__bootloader_active_end = ORIGIN(FLASH) + LENGTH(FLASH) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
+
+INCLUDE sections.x
@@ -5,8 +5,12 @@ MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
 
     /* Define the memory region for the application to be loaded next */
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* The last 208K are kept for the partitions in src/partitions.rs: */
+    /* records (64K), the settings log (16K) and the assets (128K) */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 208K
 
     /* Define the memory region for SRAM */
     RAM   : ORIGIN = 0x20000000, LENGTH = 264K
 }
+
+INCLUDE sections.x
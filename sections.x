@@ -0,0 +1,20 @@
+/* Modules registered with register_module! (src/modules.rs), console
+   commands registered with register_command! (src/console.rs) and settings
+   declared with config_key! (src/config.rs) */
+SECTIONS {
+    .modules : ALIGN(4) {
+        __smodules = .;
+        KEEP(*(.modules .modules.*));
+        __emodules = .;
+    } > FLASH
+    .commands : ALIGN(4) {
+        __scommands = .;
+        KEEP(*(.commands .commands.*));
+        __ecommands = .;
+    } > FLASH
+    .config_keys : ALIGN(4) {
+        __sconfig_keys = .;
+        KEEP(*(.config_keys .config_keys.*));
+        __econfig_keys = .;
+    } > FLASH
+} INSERT AFTER .rodata;
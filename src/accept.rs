@@ -0,0 +1,83 @@
+//! Connection accept policies
+//!
+//! A [`Policy`] gets to look at every central that connects. Rejected
+//! centrals are disconnected right away and advertising carries on.
+//! Closures work as policies, and policies combine as tuples (every member
+//! has to accept).
+
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use bt_hci::param::BdAddr;
+
+use crate::clock;
+
+pub trait Policy {
+    fn accept(&self, peer: BdAddr) -> bool;
+}
+
+impl<F: Fn(BdAddr) -> bool> Policy for F {
+    fn accept(&self, peer: BdAddr) -> bool {
+        self(peer)
+    }
+}
+
+impl<A: Policy, B: Policy> Policy for (A, B) {
+    fn accept(&self, peer: BdAddr) -> bool {
+        self.0.accept(peer) && self.1.accept(peer)
+    }
+}
+
+/// Accept everyone
+pub struct AcceptAll;
+
+impl Policy for AcceptAll {
+    fn accept(&self, _peer: BdAddr) -> bool {
+        true
+    }
+}
+
+/// Only accept the listed addresses
+pub struct AllowList<'a>(pub &'a [BdAddr]);
+
+impl Policy for AllowList<'_> {
+    fn accept(&self, peer: BdAddr) -> bool {
+        self.0.contains(&peer)
+    }
+}
+
+/// Only accept between `start` and `end` o'clock UTC (wrapping past
+/// midnight if `end < start`). Accepts while the time is unknown so a
+/// device that hasn't synced its clock yet can still be reached.
+pub struct Hours {
+    pub start: u8,
+    pub end: u8,
+}
+
+impl Policy for Hours {
+    fn accept(&self, _peer: BdAddr) -> bool {
+        let Some((unix_ms, _)) = clock::now() else {
+            return true;
+        };
+        let hour = ((unix_ms / 1000 / 3600) % 24) as u8;
+
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+/// Set to turn every connection away, e.g. while the installation is
+/// being serviced
+pub static LOCKOUT: AtomicBool = AtomicBool::new(false);
+
+/// Reject everyone while [`LOCKOUT`] is set
+pub struct Lockout;
+
+impl Policy for Lockout {
+    fn accept(&self, _peer: BdAddr) -> bool {
+        !LOCKOUT.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,186 @@
+//! Continuous ADC capture
+//!
+//! The ADC fills blocks of samples by DMA at a fixed rate. Capture is
+//! double buffered: one block fills while the last full one waits for the
+//! consumer in a one-deep queue. If the consumer still hasn't taken it by
+//! the time the next block is full, the new block is dropped and counted
+//! as an overrun. Each block carries a sequence number and the overrun
+//! count so consumers see the gaps.
+//!
+//! DMA is restarted for every block, which leaves a gap of a few samples
+//! between blocks.
+//!
+//! Monitors that have to see every block even when nobody is consuming
+//! them get the [`levels`] of each block instead.
+//!
+//! An auxiliary channel, if there is one, is read once every
+//! [`AUX_EVERY`] blocks in the gap between them (see [`aux`]).
+//!
+//! Capture stops while [`crate::power`] has us dormant, and only the
+//! auxiliary channel is read, every [`DORMANT_AUX_EVERY`].
+
+use core::cell::Cell;
+
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_rp::adc;
+use embassy_rp::adc::Adc;
+use embassy_rp::dma;
+use embassy_rp::Peripheral;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embassy_time::Timer;
+
+use crate::error;
+use crate::fault;
+use crate::info;
+use crate::monitor;
+use crate::power;
+
+/// Samples per block
+pub const BLOCK_LEN: usize = 256;
+
+/// Blocks between reads of the auxiliary channel
+pub const AUX_EVERY: u32 = 16;
+
+/// Time between reads of the auxiliary channel while dormant
+pub const DORMANT_AUX_EVERY: Duration = Duration::from_secs(60);
+
+/// ADC clock
+const ADC_CLOCK_HZ: u32 = 48_000_000;
+
+/// A conversion takes 96 ADC clocks, which caps the sample rate
+pub const MAX_RATE_HZ: u32 = ADC_CLOCK_HZ / 96;
+
+#[derive(Clone)]
+pub struct Block {
+    /// Counts every block captured, dropped ones included
+    pub seq: u32,
+    /// Blocks dropped so far because the consumer fell behind
+    pub overruns: u32,
+    /// 12-bit samples
+    pub samples: [u16; BLOCK_LEN],
+}
+
+static FULL: Channel<CriticalSectionRawMutex, Block, 1> = Channel::new();
+
+/// Next full block
+pub async fn receive() -> Block {
+    FULL.receive().await
+}
+
+/// Lowest, highest and mean sample of a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Levels {
+    pub min: u16,
+    pub max: u16,
+    pub mean: u16,
+}
+
+static LEVELS: Signal<CriticalSectionRawMutex, Levels> = Signal::new();
+
+static LATEST: Mutex<CriticalSectionRawMutex, Cell<Option<Levels>>> = Mutex::new(Cell::new(None));
+
+/// Levels of the latest block, dropped ones included. Only meant for a
+/// single waiter.
+pub async fn levels() -> Levels {
+    LEVELS.wait().await
+}
+
+/// Levels of the latest block, `None` before the first
+pub fn latest() -> Option<Levels> {
+    LATEST.lock(|latest| latest.get())
+}
+
+static AUX: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+
+/// Next sample of the auxiliary channel. Only meant for a single waiter.
+pub async fn aux() -> u16 {
+    AUX.wait().await
+}
+
+/// Clock divider for `rate_hz`, `None` if the ADC can't go that fast
+pub fn divider(rate_hz: u32) -> Option<u16> {
+    if rate_hz == 0 || rate_hz > MAX_RATE_HZ {
+        return None;
+    }
+    // one conversion every (div + 1) ADC clocks, 0 runs back to back
+    let div = ADC_CLOCK_HZ / rate_hz - 1;
+    Some(if div < 96 {
+        0
+    } else {
+        div.min(u16::MAX as u32) as u16
+    })
+}
+
+/// Capture from `channel` forever, with `div` from [`divider`], reading
+/// `aux` now and then
+pub async fn run(
+    adc: &mut Adc<'_, adc::Async>,
+    channel: &mut adc::Channel<'_>,
+    mut aux: Option<&mut adc::Channel<'_>>,
+    dma: impl Peripheral<P = impl dma::Channel>,
+    div: u16,
+) -> ! {
+    let mut dma = dma.into_ref();
+    info!("[adc] streaming with divider {}", div);
+
+    let mut block = Block {
+        seq: 0,
+        overruns: 0,
+        samples: [0; BLOCK_LEN],
+    };
+    let mut power_levels = power::subscribe();
+    loop {
+        monitor::ADC.ping();
+        if power::is_dormant() {
+            monitor::ADC.pause();
+            let woke = power::changed(power_levels.as_mut());
+            if let Either::First(()) = select(Timer::after(DORMANT_AUX_EVERY), woke).await {
+                if let Some(aux) = aux.as_deref_mut() {
+                    read_aux(adc, aux).await;
+                }
+            }
+            continue;
+        }
+        if let Err(e) = adc
+            .read_many(channel, &mut block.samples, div, dma.reborrow())
+            .await
+        {
+            // a FIFO error only spoils this block
+            error!("[adc] capture failed: {:?}", e);
+            fault::raise(fault::ADC_CAPTURE);
+            continue;
+        }
+
+        let sum: u32 = block.samples.iter().map(|&s| s as u32).sum();
+        let levels = Levels {
+            min: block.samples.iter().copied().min().unwrap(),
+            max: block.samples.iter().copied().max().unwrap(),
+            mean: (sum / BLOCK_LEN as u32) as u16,
+        };
+        LATEST.lock(|latest| latest.set(Some(levels)));
+        LEVELS.signal(levels);
+        if FULL.try_send(block.clone()).is_err() {
+            block.overruns += 1;
+        }
+        if let Some(aux) = aux.as_deref_mut().filter(|_| block.seq % AUX_EVERY == 0) {
+            read_aux(adc, aux).await;
+        }
+        block.seq = block.seq.wrapping_add(1);
+    }
+}
+
+async fn read_aux(adc: &mut Adc<'_, adc::Async>, aux: &mut adc::Channel<'_>) {
+    match adc.read(aux).await {
+        Ok(sample) => AUX.signal(sample),
+        Err(e) => {
+            error!("[adc] auxiliary read failed: {:?}", e);
+            fault::raise(fault::ADC_AUX);
+        }
+    }
+}
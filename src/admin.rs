@@ -0,0 +1,337 @@
+//! Admin lock
+//!
+//! Anyone in range can connect, and without pairing, which the BLE host
+//! can't do, anyone connected can write. With an admin PIN set, writes
+//! that change the configuration (alarm limits, calibration, the pin map,
+//! Wi-Fi and the like) are refused until the connection unlocks with the
+//! PIN; telemetry reads and notifications, and the everyday controls, stay
+//! open. An unlock lasts as long as the connection.
+//!
+//! Only a salted hash of the PIN is kept, in [`crate::settings`]: `[salt: 8,
+//! first 24 bytes of HMAC-SHA256(salt, device ID, PIN)]`. Setting it goes
+//! through [`crate::persist`], so a PIN set just before a power cut can be
+//! lost, leaving the old one.
+//!
+//! After [`ATTEMPTS`] wrong PINs in a row every unlock is refused for a
+//! lockout of [`LOCKOUT_MIN`], doubling with every wrong PIN after that up
+//! to [`LOCKOUT_MAX`]. The right PIN resets the count. The count is for the
+//! whole device, so reconnecting doesn't help a guesser.
+//!
+//! The admin characteristic takes `[op, PIN]`, the PIN [`PIN_MIN`] to
+//! [`PIN_MAX`] bytes:
+//!
+//! | op | does                                                           |
+//! |----|----------------------------------------------------------------|
+//! | 0  | unlock this connection                                         |
+//! | 1  | lock this connection again, no PIN                             |
+//! | 2  | set a new PIN, from an unlocked connection or, while there's   |
+//! |    | none, in maintenance mode                                      |
+//! | 3  | clear the PIN and with it the lock, from an unlocked connection|
+//!
+//! and answers each with a notification of `[`[`Status`]`, lockout left in
+//! s: u16]`. The `admin` console command sets and clears the PIN too.
+
+use core::cell::Cell;
+
+use bt_hci::param::ConnHandle;
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use rand_core::RngCore;
+use trouble_host::prelude::CharacteristicProp;
+use trouble_host::prelude::Uuid;
+
+use crate::blue::gen_uuid;
+use crate::error;
+use crate::gattcheck;
+use crate::info;
+use crate::integrity;
+use crate::mode;
+use crate::modules;
+use crate::persist;
+use crate::session::PerConnection;
+use crate::settings;
+use crate::system;
+use crate::writes;
+
+/// Shortest PIN
+pub const PIN_MIN: usize = 4;
+
+/// Longest PIN
+pub const PIN_MAX: usize = 16;
+
+/// Size of the admin characteristic, op and PIN
+pub const REQUEST_MAX: usize = 1 + PIN_MAX;
+
+/// Size of a response
+pub const RESPONSE_SIZE: usize = 3;
+
+/// Wrong PINs in a row before the lockout starts
+pub const ATTEMPTS: u32 = 5;
+
+/// First lockout
+pub const LOCKOUT_MIN: Duration = Duration::from_secs(30);
+
+/// Longest lockout
+pub const LOCKOUT_MAX: Duration = Duration::from_secs(60 * 60);
+
+const SALT_SIZE: usize = 8;
+
+const HASH_SIZE: usize = 24;
+
+const RECORD_SIZE: usize = SALT_SIZE + HASH_SIZE;
+
+/// How a request went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Status {
+    Done = 0,
+    Malformed = 1,
+    WrongPin = 2,
+    /// Too many wrong PINs, try again once the lockout is over
+    LockedOut = 3,
+    /// The connection isn't unlocked, or there's no PIN to unlock with
+    Refused = 4,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Guard {
+    /// Wrong PINs in a row
+    failures: u32,
+    /// Unlocks are refused until then
+    until: Option<Instant>,
+}
+
+/// The salted hash of the PIN, `None` without a lock
+static PIN: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; RECORD_SIZE]>>> =
+    Mutex::new(Cell::new(None));
+
+static GUARD: Mutex<CriticalSectionRawMutex, Cell<Guard>> = Mutex::new(Cell::new(Guard {
+    failures: 0,
+    until: None,
+}));
+
+/// Connections that unlocked
+static UNLOCKED: PerConnection<bool> = PerConnection::new(false);
+
+/// Load the PIN from flash, at boot
+pub async fn load() {
+    let mut record = [0; RECORD_SIZE];
+    match settings::get(settings::Key::ADMIN_PIN, &mut record).await {
+        Ok(Some(RECORD_SIZE)) => {
+            PIN.lock(|pin| pin.set(Some(record)));
+            info!("[admin] locked");
+        }
+        Ok(Some(0)) | Ok(None) => {}
+        Ok(Some(len)) => error!("[admin] stored PIN has {} bytes, ignored", len),
+        Err(e) => error!("[admin] loading the PIN failed: {:?}", e),
+    }
+}
+
+/// Whether a PIN is set
+pub fn enabled() -> bool {
+    PIN.lock(|pin| pin.get().is_some())
+}
+
+/// Whether `conn` may change the configuration
+pub fn allows(conn: ConnHandle) -> bool {
+    !enabled() || UNLOCKED.get(conn)
+}
+
+fn hash(salt: &[u8], pin: &[u8]) -> [u8; HASH_SIZE] {
+    let mac = integrity::hmac_sha256(salt, &[&system::DeviceId::get().0, pin]);
+    mac[..HASH_SIZE].try_into().unwrap()
+}
+
+/// Set the PIN, or clear it with `None`, and forget every unlock
+pub fn set_pin(pin: Option<&[u8]>) {
+    let record = pin.map(|pin| {
+        let mut record = [0; RECORD_SIZE];
+        RoscRng.fill_bytes(&mut record[..SALT_SIZE]);
+        let hash = hash(&record[..SALT_SIZE], pin);
+        record[SALT_SIZE..].copy_from_slice(&hash);
+        record
+    });
+    PIN.lock(|pin| pin.set(record));
+    UNLOCKED.clear();
+    persist::store(
+        settings::Key::ADMIN_PIN,
+        record.as_ref().map_or(&[][..], |r| &r[..]),
+    );
+    info!(
+        "[admin] {}",
+        if record.is_some() {
+            "PIN set"
+        } else {
+            "PIN cleared"
+        }
+    );
+}
+
+/// Time left of the lockout
+fn lockout() -> Duration {
+    GUARD
+        .lock(|guard| guard.get().until)
+        .map_or(Duration::from_ticks(0), |until| {
+            until.saturating_duration_since(Instant::now())
+        })
+}
+
+/// Check `pin` against the stored hash, counting wrong ones
+fn check(pin: &[u8]) -> Status {
+    let Some(record) = PIN.lock(|pin| pin.get()) else {
+        return Status::Refused;
+    };
+    if lockout() > Duration::from_ticks(0) {
+        return Status::LockedOut;
+    }
+    let right = integrity::constant_time_eq(&hash(&record[..SALT_SIZE], pin), &record[SALT_SIZE..]);
+    GUARD.lock(|guard| {
+        let mut state = guard.get();
+        if right {
+            state = Guard {
+                failures: 0,
+                until: None,
+            };
+        } else {
+            state.failures = state.failures.saturating_add(1);
+            if state.failures >= ATTEMPTS {
+                let doublings = (state.failures - ATTEMPTS).min(16);
+                let lockout = Duration::from_ticks(LOCKOUT_MIN.as_ticks() << doublings);
+                state.until = Some(Instant::now() + lockout.min(LOCKOUT_MAX));
+            }
+        }
+        guard.set(state);
+    });
+    if right {
+        Status::Done
+    } else {
+        error!("[admin] wrong PIN");
+        Status::WrongPin
+    }
+}
+
+fn request(conn: ConnHandle, value: &[u8]) -> Status {
+    let Some((&op, pin)) = value.split_first() else {
+        return Status::Malformed;
+    };
+    let pin_valid = (PIN_MIN..=PIN_MAX).contains(&pin.len());
+    match op {
+        0 if pin_valid => {
+            let status = check(pin);
+            if status == Status::Done {
+                UNLOCKED.set(conn, true);
+                info!("[admin] {:?} unlocked", conn);
+            }
+            status
+        }
+        1 if pin.is_empty() => {
+            UNLOCKED.set(conn, false);
+            Status::Done
+        }
+        2 if pin_valid => {
+            let allowed = if enabled() {
+                UNLOCKED.get(conn)
+            } else {
+                mode::is_maintenance()
+            };
+            if !allowed {
+                return Status::Refused;
+            }
+            set_pin(Some(pin));
+            // the connection that set it stays unlocked
+            UNLOCKED.set(conn, true);
+            Status::Done
+        }
+        3 if pin.is_empty() => {
+            if !enabled() || !UNLOCKED.get(conn) {
+                return Status::Refused;
+            }
+            set_pin(None);
+            Status::Done
+        }
+        _ => Status::Malformed,
+    }
+}
+
+/// Take a write to the admin characteristic from `conn`, returning the
+/// response to notify
+fn execute(conn: ConnHandle, value: &[u8]) -> [u8; RESPONSE_SIZE] {
+    let status = request(conn, value);
+    let left = lockout().as_secs().min(u16::MAX as u64) as u16;
+    let mut out = [0; RESPONSE_SIZE];
+    out[0] = status as u8;
+    out[1..].copy_from_slice(&left.to_le_bytes());
+    out
+}
+
+crate::register_command!(
+    ADMIN,
+    "admin",
+    "[pin <pin>|clear]",
+    "show, set or clear the admin PIN",
+    |args| {
+        match args.opt_str() {
+            Some("pin") => {
+                let pin = args.str("pin")?;
+                args.end()?;
+                if !(PIN_MIN..=PIN_MAX).contains(&pin.len()) {
+                    return Err(crate::console::Error::Invalid("pin"));
+                }
+                set_pin(Some(pin.as_bytes()));
+            }
+            Some("clear") => {
+                args.end()?;
+                set_pin(None);
+            }
+            Some(_) => return Err(crate::console::Error::Invalid("pin|clear")),
+            None => {
+                let lockout = lockout();
+                let failures = GUARD.lock(|guard| guard.get().failures);
+                info!(
+                    "{}, {} wrong PINs, locked out for {}s",
+                    if enabled() { "locked" } else { "no PIN" },
+                    failures,
+                    lockout.as_secs()
+                );
+            }
+        }
+        Ok(())
+    }
+);
+
+const SERVICE_UUID: Uuid = gen_uuid("admin lock");
+const PIN_UUID: Uuid = gen_uuid("admin pin");
+const _: () = gattcheck::assert_fit(&[REQUEST_MAX, RESPONSE_SIZE], writes::READ_MAX);
+
+fn write_pin(conn: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    modules::respond(conn, PIN_UUID, &execute(conn, value));
+    Ok(())
+}
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    svc.add(
+        PIN_UUID,
+        &[CharacteristicProp::Write, CharacteristicProp::Notify],
+        REQUEST_MAX,
+        writes::Spec::new()
+            .with_write(write_pin)
+            // don't leave the PIN in the attribute table
+            .with_read(|| writes::Value::new(&[0; REQUEST_MAX]))
+            .as_event(),
+    );
+}
+
+crate::register_module!(
+    MODULE,
+    "admin",
+    |_| {},
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |_| true,
+        build: service,
+    },
+);
@@ -0,0 +1,237 @@
+//! Advertising data decoder
+//!
+//! Splits advertising and scan response payloads into their AD structures
+//! and decodes the ones we care about into typed values, including the
+//! iBeacon and Eddystone formats riding on top of them. Everything borrows
+//! from the payload, nothing is copied.
+
+/// AD type bytes, from the Core Specification Supplement
+const FLAGS: u8 = 0x01;
+const INCOMPLETE_UUIDS16: u8 = 0x02;
+const COMPLETE_UUIDS16: u8 = 0x03;
+const INCOMPLETE_UUIDS128: u8 = 0x06;
+const COMPLETE_UUIDS128: u8 = 0x07;
+const SHORT_NAME: u8 = 0x08;
+const COMPLETE_NAME: u8 = 0x09;
+const TX_POWER: u8 = 0x0a;
+const SERVICE_DATA16: u8 = 0x16;
+const MANUFACTURER_DATA: u8 = 0xff;
+
+const APPLE_COMPANY_ID: u16 = 0x004c;
+const EDDYSTONE_UUID: u16 = 0xfeaa;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A structure's length runs past the end of the payload
+    Truncated,
+    /// A structure is too short for its type
+    Malformed(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdStructure<'a> {
+    Flags(u8),
+    /// 16-bit service UUIDs, and whether the list is complete
+    Uuids16(Uuids16<'a>, bool),
+    /// 128-bit service UUIDs (little endian, as sent), and whether the list
+    /// is complete
+    Uuids128(&'a [u8], bool),
+    /// Local name, and whether it's complete rather than shortened
+    Name(&'a [u8], bool),
+    TxPower(i8),
+    ServiceData16 {
+        uuid: u16,
+        data: &'a [u8],
+    },
+    Manufacturer {
+        company: u16,
+        data: &'a [u8],
+    },
+    IBeacon(IBeacon),
+    Eddystone(Eddystone<'a>),
+    Unknown {
+        ty: u8,
+        data: &'a [u8],
+    },
+}
+
+/// 16-bit UUIDs of a service list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Uuids16<'a>(&'a [u8]);
+
+impl Iterator for Uuids16<'_> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        let (uuid, rest) = self.0.split_first_chunk::<2>()?;
+        self.0 = rest;
+        Some(u16::from_le_bytes(*uuid))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IBeacon {
+    pub uuid: [u8; 16],
+    pub major: u16,
+    pub minor: u16,
+    /// Calibrated RSSI at 1m
+    pub tx_power: i8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Eddystone<'a> {
+    Uid {
+        tx_power: i8,
+        namespace: [u8; 10],
+        instance: [u8; 6],
+    },
+    /// The URL is left encoded (scheme prefix byte and expansion codes)
+    Url {
+        tx_power: i8,
+        url: &'a [u8],
+    },
+    /// Unencrypted telemetry
+    Tlm {
+        battery_mv: u16,
+        /// Beacon temperature in 1/256 °C
+        temperature: i16,
+        adv_count: u32,
+        /// Time since boot in 0.1 s
+        uptime: u32,
+    },
+    Other(&'a [u8]),
+}
+
+/// Iterate over the AD structures of `data`. Stops after the first error.
+pub fn parse(data: &[u8]) -> Parser<'_> {
+    Parser { data }
+}
+
+pub struct Parser<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Parser<'a> {
+    type Item = Result<AdStructure<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&len, rest) = self.data.split_first()?;
+        // a zero length ends the significant part of the payload
+        if len == 0 {
+            self.data = &[];
+            return None;
+        }
+
+        let Some((structure, rest)) = rest.split_at_checked(len as usize) else {
+            self.data = &[];
+            return Some(Err(Error::Truncated));
+        };
+        self.data = rest;
+
+        let (&ty, data) = structure.split_first().unwrap();
+        let decoded = decode(ty, data);
+        if decoded.is_err() {
+            self.data = &[];
+        }
+        Some(decoded)
+    }
+}
+
+fn decode(ty: u8, data: &[u8]) -> Result<AdStructure<'_>, Error> {
+    let malformed = Error::Malformed(ty);
+
+    Ok(match ty {
+        FLAGS => AdStructure::Flags(*data.first().ok_or(malformed)?),
+        INCOMPLETE_UUIDS16 | COMPLETE_UUIDS16 if data.len() % 2 == 0 => {
+            AdStructure::Uuids16(Uuids16(data), ty == COMPLETE_UUIDS16)
+        }
+        INCOMPLETE_UUIDS128 | COMPLETE_UUIDS128 if data.len() % 16 == 0 => {
+            AdStructure::Uuids128(data, ty == COMPLETE_UUIDS128)
+        }
+        INCOMPLETE_UUIDS16 | COMPLETE_UUIDS16 | INCOMPLETE_UUIDS128 | COMPLETE_UUIDS128 => {
+            return Err(malformed)
+        }
+        SHORT_NAME | COMPLETE_NAME => AdStructure::Name(data, ty == COMPLETE_NAME),
+        TX_POWER => AdStructure::TxPower(*data.first().ok_or(malformed)? as i8),
+        SERVICE_DATA16 => {
+            let (uuid, data) = data.split_first_chunk::<2>().ok_or(malformed)?;
+            match u16::from_le_bytes(*uuid) {
+                EDDYSTONE_UUID => AdStructure::Eddystone(eddystone(data).ok_or(malformed)?),
+                uuid => AdStructure::ServiceData16 { uuid, data },
+            }
+        }
+        MANUFACTURER_DATA => {
+            let (company, data) = data.split_first_chunk::<2>().ok_or(malformed)?;
+            match u16::from_le_bytes(*company) {
+                APPLE_COMPANY_ID => match ibeacon(data) {
+                    Some(beacon) => AdStructure::IBeacon(beacon),
+                    None => AdStructure::Manufacturer {
+                        company: APPLE_COMPANY_ID,
+                        data,
+                    },
+                },
+                company => AdStructure::Manufacturer { company, data },
+            }
+        }
+        ty => AdStructure::Unknown { ty, data },
+    })
+}
+
+/// Apple's iBeacon layout: type 0x02, length 0x15, UUID, major and minor
+/// (big endian), tx power
+fn ibeacon(data: &[u8]) -> Option<IBeacon> {
+    let [0x02, 0x15, rest @ ..] = data else {
+        return None;
+    };
+    let rest: &[u8; 21] = rest.try_into().ok()?;
+
+    Some(IBeacon {
+        uuid: rest[..16].try_into().unwrap(),
+        major: u16::from_be_bytes([rest[16], rest[17]]),
+        minor: u16::from_be_bytes([rest[18], rest[19]]),
+        tx_power: rest[20] as i8,
+    })
+}
+
+/// Eddystone frames, big endian throughout
+fn eddystone(data: &[u8]) -> Option<Eddystone<'_>> {
+    let (&frame, rest) = data.split_first()?;
+
+    Some(match frame {
+        0x00 => {
+            // the two trailing reserved bytes are optional in practice
+            let rest = rest.get(..17)?;
+            Eddystone::Uid {
+                tx_power: rest[0] as i8,
+                namespace: rest[1..11].try_into().unwrap(),
+                instance: rest[11..17].try_into().unwrap(),
+            }
+        }
+        0x10 => {
+            let (&tx_power, url) = rest.split_first()?;
+            Eddystone::Url {
+                tx_power: tx_power as i8,
+                url,
+            }
+        }
+        0x20 => {
+            // version 0x00 is the unencrypted frame
+            let [0x00, rest @ ..] = rest else {
+                return Some(Eddystone::Other(data));
+            };
+            let rest = rest.get(..12)?;
+            Eddystone::Tlm {
+                battery_mv: u16::from_be_bytes([rest[0], rest[1]]),
+                temperature: i16::from_be_bytes([rest[2], rest[3]]),
+                adv_count: u32::from_be_bytes(rest[4..8].try_into().unwrap()),
+                uptime: u32::from_be_bytes(rest[8..12].try_into().unwrap()),
+            }
+        }
+        _ => Eddystone::Other(data),
+    })
+}
@@ -0,0 +1,269 @@
+//! Advertising schedule and payloads
+//!
+//! The controller only runs one legacy advertising set at a time, so
+//! connectable advertising and broadcast-only telemetry take turns.
+//!
+//! A [`Builder`] lays AD structures out over the 31 bytes of advertising
+//! data, moving what doesn't fit into the scan response. The local name
+//! goes last: complete where there's room for it, shortened to fill the
+//! emptier of the two otherwise.
+//!
+//! [`set_manufacturer_data`] puts a value of our own into connectable
+//! advertising, a sensor reading for example. Advertising restarts with
+//! it right away.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+
+/// Company identifier reserved by the SIG for testing, used for our
+/// manufacturer-specific telemetry
+pub const TEST_COMPANY_ID: u16 = 0xffff;
+
+/// How long each advertising window lasts
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Schedule {
+    /// Time spent advertising as connectable
+    pub connectable: Duration,
+    /// Time spent broadcasting telemetry, zero disables broadcasting
+    pub broadcast: Duration,
+}
+
+impl Schedule {
+    pub const DEFAULT: Self = Self::with_duty_cycle(Duration::from_secs(5), 80);
+
+    /// Split `period` so `percent_connectable` percent of it is connectable
+    pub const fn with_duty_cycle(period: Duration, percent_connectable: u8) -> Self {
+        let percent = if percent_connectable > 100 {
+            100
+        } else {
+            percent_connectable as u64
+        };
+        let connectable = period.as_millis() * percent / 100;
+
+        Self {
+            connectable: Duration::from_millis(connectable),
+            broadcast: Duration::from_millis(period.as_millis() - connectable),
+        }
+    }
+
+    /// Connectable advertising only, no broadcast windows
+    pub const fn connectable_only() -> Self {
+        Self {
+            connectable: Duration::MAX,
+            broadcast: Duration::from_ticks(0),
+        }
+    }
+
+    pub const fn broadcasts(&self) -> bool {
+        self.broadcast.as_ticks() != 0
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Size of legacy advertising data, and of a scan response
+pub const DATA_MAX: usize = 31;
+
+/// Longest manufacturer-specific payload, after its AD header and company
+/// identifier
+pub const MANUFACTURER_MAX: usize = DATA_MAX - 4;
+
+const FLAGS: u8 = 0x01;
+const UUIDS16_COMPLETE: u8 = 0x03;
+const NAME_SHORTENED: u8 = 0x08;
+const NAME_COMPLETE: u8 = 0x09;
+const SERVICE_DATA16: u8 = 0x16;
+const MANUFACTURER: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A structure fits neither the advertising data nor the scan response
+    TooLarge,
+}
+
+/// Advertising data and scan response, ready to advertise
+#[derive(Clone, Copy)]
+pub struct Payload {
+    adv: [u8; DATA_MAX],
+    adv_len: usize,
+    scan: [u8; DATA_MAX],
+    scan_len: usize,
+}
+
+impl Payload {
+    pub fn adv_data(&self) -> &[u8] {
+        &self.adv[..self.adv_len]
+    }
+
+    pub fn scan_data(&self) -> &[u8] {
+        &self.scan[..self.scan_len]
+    }
+
+    /// Put `[len, ty, parts...]` where it fits, advertising data first
+    fn push(&mut self, ty: u8, parts: &[&[u8]]) -> Result<(), Error> {
+        let len = 2 + parts.iter().map(|p| p.len()).sum::<usize>();
+        let (buf, at) = if self.adv_len + len <= DATA_MAX {
+            (&mut self.adv, &mut self.adv_len)
+        } else if self.scan_len + len <= DATA_MAX {
+            (&mut self.scan, &mut self.scan_len)
+        } else {
+            return Err(Error::TooLarge);
+        };
+        buf[*at] = (len - 1) as u8;
+        buf[*at + 1] = ty;
+        let mut offset = *at + 2;
+        for part in parts {
+            buf[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
+        *at = offset;
+        Ok(())
+    }
+
+    /// Put the name where it fits, shortened if need be. Without room for
+    /// a single character it's left out.
+    fn push_name(&mut self, name: &str) {
+        if self.push(NAME_COMPLETE, &[name.as_bytes()]).is_ok() {
+            return;
+        }
+        let room = (DATA_MAX - self.adv_len.min(self.scan_len)).saturating_sub(2);
+        if let Some(len) = (1..=room).rev().find(|&i| name.is_char_boundary(i)) {
+            // the emptier one has room for it
+            let _ = self.push(NAME_SHORTENED, &[&name.as_bytes()[..len]]);
+        }
+    }
+}
+
+/// Lays out a [`Payload`]: `Builder::new().flags(..).name(..).build()`
+pub struct Builder<'a> {
+    payload: Payload,
+    name: Option<&'a str>,
+    result: Result<(), Error>,
+}
+
+impl<'a> Builder<'a> {
+    pub fn new() -> Self {
+        Self {
+            payload: Payload {
+                adv: [0; DATA_MAX],
+                adv_len: 0,
+                scan: [0; DATA_MAX],
+                scan_len: 0,
+            },
+            name: None,
+            result: Ok(()),
+        }
+    }
+
+    fn push(mut self, ty: u8, parts: &[&[u8]]) -> Self {
+        if self.result.is_ok() {
+            self.result = self.payload.push(ty, parts);
+        }
+        self
+    }
+
+    pub fn flags(self, flags: u8) -> Self {
+        self.push(FLAGS, &[&[flags]])
+    }
+
+    /// The complete list of 16-bit service UUIDs, at most eight
+    pub fn service_uuids16(self, uuids: &[u16]) -> Self {
+        let mut bytes = [0; 16];
+        let n = uuids.len().min(bytes.len() / 2);
+        for (pair, uuid) in bytes.chunks_exact_mut(2).zip(&uuids[..n]) {
+            pair.copy_from_slice(&uuid.to_le_bytes());
+        }
+        self.push(UUIDS16_COMPLETE, &[&bytes[..2 * n]])
+    }
+
+    pub fn service_data16(self, uuid: u16, data: &[u8]) -> Self {
+        self.push(SERVICE_DATA16, &[&uuid.to_le_bytes(), data])
+    }
+
+    pub fn manufacturer(self, company: u16, data: &[u8]) -> Self {
+        self.push(MANUFACTURER, &[&company.to_le_bytes(), data])
+    }
+
+    /// The local name, placed once everything else is
+    pub fn name(mut self, name: &'a str) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn build(self) -> Result<Payload, Error> {
+        let mut payload = self.payload;
+        self.result?;
+        if let Some(name) = self.name {
+            payload.push_name(name);
+        }
+        Ok(payload)
+    }
+}
+
+impl Default for Builder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Manufacturer-specific data set at runtime
+#[derive(Clone, Copy)]
+pub struct Manufacturer {
+    pub company: u16,
+    len: usize,
+    data: [u8; MANUFACTURER_MAX],
+}
+
+impl Manufacturer {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+static MANUFACTURER_DATA: Mutex<CriticalSectionRawMutex, RefCell<Option<Manufacturer>>> =
+    Mutex::new(RefCell::new(None));
+
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Advertise `data` as manufacturer-specific data of `company` from now on
+pub fn set_manufacturer_data(company: u16, data: &[u8]) -> Result<(), Error> {
+    let mut manufacturer = Manufacturer {
+        company,
+        len: data.len(),
+        data: [0; MANUFACTURER_MAX],
+    };
+    manufacturer
+        .data
+        .get_mut(..data.len())
+        .ok_or(Error::TooLarge)?
+        .copy_from_slice(data);
+    MANUFACTURER_DATA.lock(|m| *m.borrow_mut() = Some(manufacturer));
+    CHANGED.signal(());
+    Ok(())
+}
+
+/// Stop advertising manufacturer-specific data
+pub fn clear_manufacturer_data() {
+    MANUFACTURER_DATA.lock(|m| *m.borrow_mut() = None);
+    CHANGED.signal(());
+}
+
+pub fn manufacturer_data() -> Option<Manufacturer> {
+    MANUFACTURER_DATA.lock(|m| *m.borrow())
+}
+
+/// Wait for the advertised data to change. Only meant for a single
+/// waiter.
+pub async fn changed() {
+    CHANGED.wait().await
+}
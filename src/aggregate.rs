@@ -0,0 +1,144 @@
+//! Telemetry aggregation
+//!
+//! High-rate sources would flood the radio if every sample got notified.
+//! An [`Aggregator`] condenses the samples of a time window into a
+//! [`Summary`] (min, max, mean), and only that gets sent. Windows are set
+//! per characteristic, so clients can trade detail for radio time.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Instant;
+
+/// Window used for characteristics that never had one set
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Max number of characteristics with their own window
+const WINDOWS_MAX: usize = 4;
+
+/// Size of an encoded [`Summary`]
+pub const SUMMARY_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Summary {
+    pub min: i32,
+    pub max: i32,
+    pub mean: i32,
+    /// Number of samples summarized
+    pub count: u32,
+}
+
+impl Summary {
+    /// little endian: min, max, mean, count
+    pub fn encode(&self) -> [u8; SUMMARY_SIZE] {
+        let mut out = [0; SUMMARY_SIZE];
+        out[0..4].copy_from_slice(&self.min.to_le_bytes());
+        out[4..8].copy_from_slice(&self.max.to_le_bytes());
+        out[8..12].copy_from_slice(&self.mean.to_le_bytes());
+        out[12..16].copy_from_slice(&self.count.to_le_bytes());
+        out
+    }
+}
+
+pub struct Aggregator {
+    window: Duration,
+    started: Instant,
+    min: i32,
+    max: i32,
+    sum: i64,
+    count: u32,
+}
+
+impl Aggregator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            started: Instant::now(),
+            min: i32::MAX,
+            max: i32::MIN,
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    /// Change the window, taking effect from the current one
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// Add samples taken at `now`. Returns the summary of the previous
+    /// window if this closed it.
+    pub fn push(
+        &mut self,
+        samples: impl IntoIterator<Item = i32>,
+        now: Instant,
+    ) -> Option<Summary> {
+        let summary = if now.saturating_duration_since(self.started) >= self.window {
+            let summary = self.summary();
+            *self = Self {
+                started: now,
+                ..Self::new(self.window)
+            };
+            summary
+        } else {
+            None
+        };
+
+        for sample in samples {
+            self.min = self.min.min(sample);
+            self.max = self.max.max(sample);
+            self.sum += sample as i64;
+            self.count += 1;
+        }
+        summary
+    }
+
+    /// Summary of the window so far, `None` if it has no samples
+    pub fn summary(&self) -> Option<Summary> {
+        (self.count != 0).then(|| Summary {
+            min: self.min,
+            max: self.max,
+            mean: (self.sum / self.count as i64) as i32,
+            count: self.count,
+        })
+    }
+}
+
+static WINDOWS: Mutex<CriticalSectionRawMutex, RefCell<[Option<(u16, Duration)>; WINDOWS_MAX]>> =
+    Mutex::new(RefCell::new([None; WINDOWS_MAX]));
+
+/// Set the window of the characteristic with attribute handle `handle`.
+/// Ignored if [`WINDOWS_MAX`] others already have one.
+pub fn set_window(handle: u16, window: Duration) {
+    WINDOWS.lock(|windows| {
+        let mut windows = windows.borrow_mut();
+        if let Some(idx) = windows
+            .iter()
+            .position(|w| matches!(w, Some((h, _)) if *h == handle))
+            .or_else(|| windows.iter().position(|w| w.is_none()))
+        {
+            windows[idx] = Some((handle, window));
+        }
+    });
+}
+
+/// The window of `handle`, [`DEFAULT_WINDOW`] unless one was set
+pub fn window(handle: u16) -> Duration {
+    WINDOWS.lock(|windows| {
+        windows
+            .borrow()
+            .iter()
+            .flatten()
+            .find(|(h, _)| *h == handle)
+            .map_or(DEFAULT_WINDOW, |(_, w)| *w)
+    })
+}
+
+/// Parse a window write: milliseconds as a little endian u16, not zero
+pub fn parse_window(value: &[u8]) -> Option<Duration> {
+    let ms = u16::from_le_bytes(value.get(..2)?.try_into().unwrap());
+    (ms != 0).then(|| Duration::from_millis(ms as u64))
+}
@@ -0,0 +1,487 @@
+//! Limit alarms
+//!
+//! Each signal has optional low and high limits. Going past one trips the
+//! signal's alarm, which latches: it stays raised after the value comes
+//! back in range until someone acknowledges it. Acknowledging an alarm
+//! that is still active silences it until the value clears.
+//!
+//! An unacknowledged alarm lights the last LED of the strip, and sounds
+//! the buzzer too (if the board has one) if nobody acknowledged it within
+//! [`ESCALATE_AFTER`].
+//! Trips, clears and acknowledgements are kept in a history.
+//!
+//! The limits start out unset on every boot, unless `alarm.persist` keeps
+//! them in [`crate::settings`].
+
+use core::cell::RefCell;
+
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3;
+use embassy_rp::gpio::Output;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Sender;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::pubsub::Subscriber;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+
+use crate::adcstream;
+use crate::blue::CONNECTIONS_MAX;
+use crate::calibration;
+use crate::console;
+use crate::error;
+use crate::info;
+use crate::lighting::Message;
+use crate::monitor;
+use crate::persist;
+use crate::rollup;
+use crate::settings;
+
+crate::config_key!(
+    /// Keep the limits across reboots
+    pub PERSIST: bool = "alarm.persist",
+    false,
+);
+
+/// Number of monitored signals
+pub const SIGNALS: usize = 5;
+
+/// The sensor on the ADC, calibrated (raw 12-bit counts until it is)
+pub const ADC: usize = 0;
+
+/// The energy meter's bus voltage in mV
+pub const BUS_VOLTAGE: usize = 1;
+
+/// The energy meter's current in µA
+pub const CURRENT: usize = 2;
+
+/// The distance sensor's range in mm, see [`crate::distance`]
+pub const DISTANCE: usize = 3;
+
+/// The tank level in 0.01%, see [`crate::level`]
+pub const LEVEL: usize = 4;
+
+/// How long an alarm can go unacknowledged before the buzzer sounds
+pub const ESCALATE_AFTER: Duration = Duration::from_secs(30);
+
+/// Number of history records kept in RAM
+pub const HISTORY_LEN: usize = 16;
+
+/// Size of the alarm characteristic: state and condition of every signal
+pub const CHARACTERISTIC_SIZE: usize = 2 * SIGNALS;
+
+/// Size of a limits write
+pub const LIMITS_SIZE: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Limits {
+    pub low: Option<i32>,
+    pub high: Option<i32>,
+}
+
+impl Limits {
+    fn condition(&self, min: i32, max: i32) -> Condition {
+        if self.low.is_some_and(|low| min < low) {
+            Condition::Low
+        } else if self.high.is_some_and(|high| max > high) {
+            Condition::High
+        } else {
+            Condition::Normal
+        }
+    }
+}
+
+/// A write to the limits characteristic: `[signal, flags, low: i32,
+/// high: i32]` little endian, flags bit 0 enabling the low limit and bit 1
+/// the high one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LimitsRequest {
+    pub signal: usize,
+    pub limits: Limits,
+}
+
+impl LimitsRequest {
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let value: &[u8; LIMITS_SIZE] = value.get(..LIMITS_SIZE)?.try_into().unwrap();
+        let signal = value[0] as usize;
+        if signal >= SIGNALS || value[1] & !0b11 != 0 {
+            return None;
+        }
+        let low = i32::from_le_bytes(value[2..6].try_into().unwrap());
+        let high = i32::from_le_bytes(value[6..10].try_into().unwrap());
+
+        Some(Self {
+            signal,
+            limits: Limits {
+                low: (value[1] & 0b01 != 0).then_some(low),
+                high: (value[1] & 0b10 != 0).then_some(high),
+            },
+        })
+    }
+
+    pub fn encode(&self) -> [u8; LIMITS_SIZE] {
+        let mut out = [0; LIMITS_SIZE];
+        out[0] = self.signal as u8;
+        out[1] = self.limits.low.is_some() as u8 | (self.limits.high.is_some() as u8) << 1;
+        out[2..6].copy_from_slice(&self.limits.low.unwrap_or(0).to_le_bytes());
+        out[6..10].copy_from_slice(&self.limits.high.unwrap_or(0).to_le_bytes());
+        out
+    }
+}
+
+/// Where a signal is relative to its limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Condition {
+    Normal = 0,
+    Low = 1,
+    High = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    Normal,
+    /// Tripped and not acknowledged yet, `active` while still out of limits
+    Unacked {
+        active: bool,
+        since: Instant,
+    },
+    /// Acknowledged while still out of limits
+    Acked,
+}
+
+impl State {
+    fn encode(&self) -> u8 {
+        match self {
+            Self::Normal => 0,
+            Self::Unacked { active: true, .. } => 1,
+            Self::Unacked { active: false, .. } => 2,
+            Self::Acked => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Kind {
+    Tripped(Condition),
+    Cleared,
+    Acknowledged,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Record {
+    pub signal: u8,
+    pub kind: Kind,
+    /// The sample that tripped or cleared the alarm
+    pub value: i32,
+    /// Milliseconds since boot
+    pub timestamp_ms: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Alarm {
+    state: State,
+    condition: Condition,
+}
+
+struct Alarms {
+    limits: [Limits; SIGNALS],
+    alarms: [Alarm; SIGNALS],
+    history: [Option<Record>; HISTORY_LEN],
+    next: usize,
+}
+
+impl Alarms {
+    fn record(&mut self, signal: usize, kind: Kind, value: i32) {
+        info!("[alarm] signal {} {:?} at {}", signal, kind, value);
+        self.history[self.next] = Some(Record {
+            signal: signal as u8,
+            kind,
+            value,
+            timestamp_ms: Instant::now().as_millis() as u32,
+        });
+        self.next = (self.next + 1) % HISTORY_LEN;
+    }
+}
+
+static ALARMS: Mutex<CriticalSectionRawMutex, RefCell<Alarms>> = Mutex::new(RefCell::new(Alarms {
+    limits: [Limits {
+        low: None,
+        high: None,
+    }; SIGNALS],
+    alarms: [Alarm {
+        state: State::Normal,
+        condition: Condition::Normal,
+    }; SIGNALS],
+    history: [None; HISTORY_LEN],
+    next: 0,
+}));
+
+/// Max number of queued changes per subscriber
+const CHANGES_CAP: usize = 4;
+
+/// Max number of concurrent change subscribers: [`run`], and one per
+/// connection
+const CHANGE_SUBSCRIBERS_MAX: usize = 1 + CONNECTIONS_MAX;
+
+/// Index of a signal whose alarm changed
+pub type ChangeSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, usize, CHANGES_CAP, CHANGE_SUBSCRIBERS_MAX, 0>;
+
+static CHANGES: PubSubChannel<
+    CriticalSectionRawMutex,
+    usize,
+    CHANGES_CAP,
+    CHANGE_SUBSCRIBERS_MAX,
+    0,
+> = PubSubChannel::new();
+
+/// Subscribe to alarm changes, `None` if all subscriber slots are in use
+pub fn subscribe() -> Option<ChangeSubscriber> {
+    CHANGES.subscriber().ok()
+}
+
+pub fn set_limits(signal: usize, limits: Limits) {
+    info!("[alarm] signal {} limits {:?}", signal, limits);
+    let all = ALARMS.lock(|alarms| {
+        let mut alarms = alarms.borrow_mut();
+        alarms.limits[signal] = limits;
+        alarms.limits
+    });
+    if PERSIST.get() {
+        let mut record = [0; SIGNALS * LIMITS_SIZE];
+        for (signal, (limits, out)) in all
+            .into_iter()
+            .zip(record.chunks_mut(LIMITS_SIZE))
+            .enumerate()
+        {
+            out.copy_from_slice(&LimitsRequest { signal, limits }.encode());
+        }
+        persist::store(settings::Key::ALARM_LIMITS, &record);
+    }
+}
+
+/// Load the stored limits, if `alarm.persist` is set. Call once at boot,
+/// after [`crate::config::load`].
+pub async fn load() {
+    if !PERSIST.get() {
+        return;
+    }
+    let mut record = [0; SIGNALS * LIMITS_SIZE];
+    match settings::get(settings::Key::ALARM_LIMITS, &mut record).await {
+        Ok(Some(len)) if len == record.len() => {
+            for request in record.chunks(LIMITS_SIZE).filter_map(LimitsRequest::parse) {
+                ALARMS.lock(|alarms| alarms.borrow_mut().limits[request.signal] = request.limits);
+            }
+            info!("[alarm] limits loaded");
+        }
+        Ok(Some(_)) => error!("[alarm] invalid stored limits"),
+        Ok(None) => {}
+        Err(e) => error!("[alarm] loading limits failed: {:?}", e),
+    }
+}
+
+pub fn limits(signal: usize) -> Limits {
+    ALARMS.lock(|alarms| alarms.borrow().limits[signal])
+}
+
+/// Check the lowest and highest recent samples of `signal` against its
+/// limits
+pub fn check(signal: usize, min: i32, max: i32) {
+    rollup::sample(signal, min, max);
+    let changed = ALARMS.lock(|alarms| {
+        let mut alarms = alarms.borrow_mut();
+        let condition = alarms.limits[signal].condition(min, max);
+        let alarm = alarms.alarms[signal];
+        if condition == alarm.condition {
+            return false;
+        }
+
+        let value = if condition == Condition::Low {
+            min
+        } else {
+            max
+        };
+        let state = match (condition, alarm.state) {
+            (Condition::Normal, State::Unacked { since, .. }) => State::Unacked {
+                active: false,
+                since,
+            },
+            (Condition::Normal, _) => State::Normal,
+            // going from low straight to high (or back) is still the same
+            // alarm
+            (_, State::Unacked { since, .. }) => State::Unacked {
+                active: true,
+                since,
+            },
+            (_, State::Acked) if alarm.condition != Condition::Normal => State::Acked,
+            _ => State::Unacked {
+                active: true,
+                since: Instant::now(),
+            },
+        };
+        alarms.alarms[signal] = Alarm { state, condition };
+        let kind = match condition {
+            Condition::Normal => Kind::Cleared,
+            tripped => Kind::Tripped(tripped),
+        };
+        alarms.record(signal, kind, value);
+        true
+    });
+
+    if changed {
+        CHANGES.immediate_publisher().publish_immediate(signal);
+    }
+}
+
+/// Acknowledge the alarm of `signal`. Returns false if it wasn't raised.
+pub fn acknowledge(signal: usize) -> bool {
+    let acknowledged = ALARMS.lock(|alarms| {
+        let mut alarms = alarms.borrow_mut();
+        let State::Unacked { active, .. } = alarms.alarms[signal].state else {
+            return false;
+        };
+        alarms.alarms[signal].state = if active { State::Acked } else { State::Normal };
+        alarms.record(signal, Kind::Acknowledged, 0);
+        true
+    });
+
+    if acknowledged {
+        CHANGES.immediate_publisher().publish_immediate(signal);
+    }
+    acknowledged
+}
+
+/// Parse an acknowledge write, `[signal]`
+pub fn parse_acknowledge(value: &[u8]) -> Option<usize> {
+    let signal = *value.first()? as usize;
+    (signal < SIGNALS).then_some(signal)
+}
+
+/// The alarm characteristic value: state and condition of every signal.
+/// States are 0 normal, 1 raised, 2 raised but cleared, 3 acknowledged.
+pub fn encode() -> [u8; CHARACTERISTIC_SIZE] {
+    let mut out = [0; CHARACTERISTIC_SIZE];
+    ALARMS.lock(|alarms| {
+        for (i, alarm) in alarms.borrow().alarms.iter().enumerate() {
+            out[2 * i] = alarm.state.encode();
+            out[2 * i + 1] = alarm.condition as u8;
+        }
+    });
+    out
+}
+
+/// Iterate over the history, most recent first
+pub fn for_each_record(mut f: impl FnMut(&Record)) {
+    ALARMS.lock(|alarms| {
+        let alarms = alarms.borrow();
+        for i in 1..=HISTORY_LEN {
+            let idx = (alarms.next + HISTORY_LEN - i) % HISTORY_LEN;
+            if let Some(record) = &alarms.history[idx] {
+                f(record);
+            }
+        }
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Escalation {
+    None,
+    Led,
+    Buzzer,
+}
+
+/// How far the alarms have escalated at `now`, and when they escalate
+/// next
+fn escalation(now: Instant) -> (Escalation, Instant) {
+    ALARMS.lock(|alarms| {
+        let oldest = alarms
+            .borrow()
+            .alarms
+            .iter()
+            .filter_map(|alarm| match alarm.state {
+                State::Unacked { since, .. } => Some(since),
+                _ => None,
+            })
+            .min();
+        match oldest {
+            None => (Escalation::None, Instant::MAX),
+            Some(since) if now >= since + ESCALATE_AFTER => (Escalation::Buzzer, Instant::MAX),
+            Some(since) => (Escalation::Led, since + ESCALATE_AFTER),
+        }
+    })
+}
+
+/// Check the ADC against its limits and escalate unacknowledged alarms
+pub async fn run<M: RawMutex, const N: usize>(
+    sender: Sender<'_, M, Message, N>,
+    mut buzzer: Option<Output<'_>>,
+) -> ! {
+    // there's a slot for us as long as we subscribe first
+    let mut changes = subscribe().unwrap();
+    let mut level = Escalation::None;
+    let mut deadline = Instant::MAX;
+    loop {
+        monitor::ALARM.pause();
+        match select3(
+            adcstream::levels(),
+            changes.next_message_pure(),
+            Timer::at(deadline),
+        )
+        .await
+        {
+            Either3::First(levels) => {
+                monitor::ALARM.ping();
+                // a negative gain swaps the extremes
+                let a = calibration::apply(levels.min as i32);
+                let b = calibration::apply(levels.max as i32);
+                check(ADC, a.min(b), a.max(b));
+            }
+            Either3::Second(_) | Either3::Third(()) => monitor::ALARM.ping(),
+        }
+
+        let (next, at) = escalation(Instant::now());
+        deadline = at;
+        if next == level {
+            continue;
+        }
+
+        info!("[alarm] escalation {:?}", next);
+        if (next == Escalation::None) != (level == Escalation::None) {
+            sender
+                .send(Message::SetAlarm(next != Escalation::None))
+                .await;
+        }
+        if let Some(buzzer) = buzzer.as_mut() {
+            buzzer.set_level((next == Escalation::Buzzer).into());
+        }
+        level = next;
+    }
+}
+
+crate::register_command!(
+    ACK,
+    "ack",
+    "<signal>",
+    "acknowledge a signal's alarm",
+    |args| {
+        let signal = args.u32("signal")? as usize;
+        args.end()?;
+        if signal >= SIGNALS {
+            return Err(console::Error::Invalid("signal"));
+        }
+        if !acknowledge(signal) {
+            info!("[alarm] signal {} isn't raised", signal);
+        }
+        Ok(())
+    }
+);
@@ -0,0 +1,172 @@
+//! Connection event timing
+//!
+//! Sensors that notify often can sample just before a connection event
+//! rather than whenever their timer fires. That way a reading waits about
+//! `anchor.lead` for the radio instead of up to a whole interval.
+//!
+//! The controller never tells the host when connection events happen.
+//! HCI has no event for anchor points. But the central always sends first
+//! in an event, so each GATT request we get marks one, give or take the
+//! controller's and the bus's lag. [`observe`] notes when the requests
+//! arrive. The estimate starts from the interval the central accepted
+//! (see [`conninfo::params`]), which is only an upper bound. It narrows
+//! down to the shortest gap seen between events, then tracks the phase
+//! from there, so it follows the sleep clock's drift.
+//!
+//! A connection that hasn't sent anything for [`STALE`] has no estimate,
+//! and neither has one that's only ever had notifications go out. Sampling
+//! is aligned to whichever connection last sent something. When there's
+//! no estimate, [`align`] waits for the plain deadline, so nothing changes
+//! without a talkative central.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::conninfo;
+use crate::info;
+use crate::session;
+
+crate::config_key!(
+    /// How long before an anchor point samples are taken, in µs. Covers
+    /// queueing the notification and the lag of the request timestamps.
+    pub LEAD: u32 = "anchor.lead",
+    3000,
+);
+
+/// Shortest connection interval there is
+const MIN_INTERVAL: Duration = Duration::from_micros(7500);
+
+/// Longest connection interval there is, the bound before we've asked
+/// for parameters
+const MAX_INTERVAL: Duration = Duration::from_secs(4);
+
+/// How much sooner than an anchor point a request may come in. Requests
+/// within this much of the last are from the same event.
+const JITTER: Duration = Duration::from_micros(1250);
+
+/// How long the estimate holds without requests, before the drift of the
+/// sleep clocks could have moved the anchors by a good part of an interval
+pub const STALE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Estimate {
+    /// A past anchor point
+    anchor: Instant,
+    interval: Duration,
+    /// The interval the central accepted when this started
+    bound: Duration,
+    /// First request of the last event
+    seen: Instant,
+}
+
+static ESTIMATE: session::PerConnection<Option<Estimate>> = session::PerConnection::new(None);
+
+/// The connection that last sent a request
+static LEADER: Mutex<CriticalSectionRawMutex, Cell<Option<ConnHandle>>> =
+    Mutex::new(Cell::new(None));
+
+/// Fold a request from `conn` that arrived `at` into its estimate
+pub fn observe(conn: ConnHandle, at: Instant) {
+    let bound = conninfo::params(conn).map_or(MAX_INTERVAL, |p| p.interval);
+    LEADER.lock(|l| l.set(Some(conn)));
+    ESTIMATE.update(conn, |estimate| {
+        let fresh = Estimate {
+            anchor: at,
+            interval: bound,
+            bound,
+            seen: at,
+        };
+        let Some(mut e) = estimate else {
+            return Some(fresh);
+        };
+        // new parameters move the anchors, and a long silence lets them
+        // drift, so start over
+        if e.bound != bound || at - e.seen > STALE {
+            return Some(fresh);
+        }
+        let gap = at - e.seen;
+        if gap < MIN_INTERVAL - JITTER {
+            return Some(e);
+        }
+
+        // events come a whole number of intervals apart, so a gap shorter
+        // than the estimate is closer to the interval
+        if gap + JITTER < e.interval {
+            e.interval = gap.max(MIN_INTERVAL);
+        }
+        // move the phase a quarter of the way to the anchor this request
+        // came in, which smooths out the lag
+        let interval = e.interval.as_micros() as i64;
+        let since = at.as_micros() as i64 - e.anchor.as_micros() as i64;
+        let n = (since + interval / 2).div_euclid(interval);
+        let residual = since - n * interval;
+        let anchor = e.anchor.as_micros() as i64 + n * interval + residual / 4;
+        e.anchor = Instant::from_micros(anchor as u64);
+        e.seen = at;
+        Some(e)
+    });
+}
+
+/// The estimated interval of `conn`, `None` without a fresh estimate
+pub fn interval(conn: ConnHandle) -> Option<Duration> {
+    estimate(conn).map(|e| e.interval)
+}
+
+/// The first anchor point of `conn` at or after `after`, `None` without a
+/// fresh estimate
+pub fn next(conn: ConnHandle, after: Instant) -> Option<Instant> {
+    let e = estimate(conn)?;
+    let interval = e.interval.as_micros();
+    let since = after.saturating_duration_since(e.anchor).as_micros();
+    let n = since.div_ceil(interval);
+    Some(e.anchor + Duration::from_micros(n * interval))
+}
+
+fn estimate(conn: ConnHandle) -> Option<Estimate> {
+    ESTIMATE
+        .get(conn)
+        .filter(|e| Instant::now().saturating_duration_since(e.seen) <= STALE)
+}
+
+/// Wait until `lead` plus `anchor.lead` before the first anchor point of
+/// the leading connection that's at least that far past `deadline`. Just
+/// waits for `deadline` without an estimate. `lead` is how long the sample
+/// itself takes.
+pub async fn align(deadline: Instant, lead: Duration) {
+    let lead = lead + Duration::from_micros(LEAD.get() as u64);
+    let at = LEADER
+        .lock(|l| l.get())
+        .and_then(|conn| next(conn, deadline + lead))
+        .map_or(deadline, |anchor| anchor - lead);
+    Timer::at(at).await
+}
+
+crate::register_command!(
+    ANCHOR,
+    "anchor",
+    "",
+    "show the connection event timing estimated for the leading connection",
+    |args| {
+        args.end()?;
+        let Some(conn) = LEADER.lock(|l| l.get()) else {
+            info!("no requests yet");
+            return Ok(());
+        };
+        match (interval(conn), next(conn, Instant::now())) {
+            (Some(interval), Some(anchor)) => info!(
+                "{:?}: interval {} us, next anchor in {} us",
+                conn,
+                interval.as_micros(),
+                anchor.saturating_duration_since(Instant::now()).as_micros()
+            ),
+            _ => info!("{:?}: no fresh estimate", conn),
+        }
+        Ok(())
+    }
+);
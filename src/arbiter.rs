@@ -0,0 +1,147 @@
+//! Arbitration of a shared link
+//!
+//! An ATT bearer carries one request at a time, and the GATT client of the
+//! central's link is shared by everything talking to the target: the
+//! bridge, the proxy's reads and writes, and whatever comes next. An
+//! [`Arbiter`] runs their operations one at a time, highest
+//! [`Priority`] first and in no particular order within one, with a
+//! timeout on waiting for a turn and another on the operation itself, so
+//! that a stuck request can't hold everyone else up for good.
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::future::Future;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::MultiWakerRegistration;
+use embassy_time::with_timeout;
+use embassy_time::Duration;
+
+/// Max number of operations waiting at once. More still work, they just
+/// wake up more often.
+const WAITERS_MAX: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Priority {
+    /// Polling and other work nobody waits for
+    Background = 0,
+    /// Discovery, subscriptions
+    Normal = 1,
+    /// What a client on the other side is waiting for, writes through the
+    /// proxy
+    Interactive = 2,
+}
+
+const PRIORITIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No turn came up in time
+    Busy,
+    /// The operation took too long and was dropped
+    Timeout,
+}
+
+struct State {
+    busy: bool,
+    waiting: [u8; PRIORITIES],
+    wakers: MultiWakerRegistration<WAITERS_MAX>,
+}
+
+impl State {
+    fn outranked(&self, priority: Priority) -> bool {
+        self.waiting[priority as usize + 1..].iter().any(|&n| n > 0)
+    }
+}
+
+pub struct Arbiter {
+    state: Mutex<CriticalSectionRawMutex, RefCell<State>>,
+    /// How long an operation may wait for its turn
+    wait: Duration,
+    /// How long an operation may take once it has it
+    run: Duration,
+}
+
+/// Counts a waiter until it's granted its turn or gives up
+struct Waiting<'a> {
+    arbiter: &'a Arbiter,
+    priority: Priority,
+    granted: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if !self.granted {
+            self.arbiter.state.lock(|state| {
+                let mut state = state.borrow_mut();
+                state.waiting[self.priority as usize] -= 1;
+                // lower priorities may have been held back by us
+                state.wakers.wake();
+            });
+        }
+    }
+}
+
+/// A turn, given back when dropped
+struct Turn<'a>(&'a Arbiter);
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            state.busy = false;
+            state.wakers.wake();
+        });
+    }
+}
+
+impl Arbiter {
+    pub const fn new(wait: Duration, run: Duration) -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(State {
+                busy: false,
+                waiting: [0; PRIORITIES],
+                wakers: MultiWakerRegistration::new(),
+            })),
+            wait,
+            run,
+        }
+    }
+
+    async fn turn(&self, priority: Priority) -> Turn<'_> {
+        self.state
+            .lock(|state| state.borrow_mut().waiting[priority as usize] += 1);
+        let mut waiting = Waiting {
+            arbiter: self,
+            priority,
+            granted: false,
+        };
+        poll_fn(|cx| {
+            self.state.lock(|state| {
+                let mut state = state.borrow_mut();
+                if state.busy || state.outranked(priority) {
+                    state.wakers.register(cx.waker());
+                    return Poll::Pending;
+                }
+                state.busy = true;
+                state.waiting[priority as usize] -= 1;
+                waiting.granted = true;
+                Poll::Ready(())
+            })
+        })
+        .await;
+        Turn(self)
+    }
+
+    /// Run `op` once it's its turn
+    pub async fn run<F: Future>(&self, priority: Priority, op: F) -> Result<F::Output, Error> {
+        let _turn = with_timeout(self.wait, self.turn(priority))
+            .await
+            .map_err(|_| Error::Busy)?;
+        with_timeout(self.run, op).await.map_err(|_| Error::Timeout)
+    }
+}
@@ -0,0 +1,422 @@
+//! Asset filesystem
+//!
+//! Larger resources that don't belong in the firmware image, like web
+//! dashboard files, string tables or key material, kept as named files in
+//! the [`partitions::ASSETS`] partition. Files are read in place through
+//! XIP with [`open`], so there's nothing to copy out.
+//!
+//! Like littlefs, the filesystem is copy-on-write around a metadata pair.
+//! The first two sectors hold copies of the directory, `[magic, sequence
+//! number, count, crc]` followed by `count` entries of `[name: 16, offset,
+//! len, crc]`, numbers u32 little endian and NUL padded names. The
+//! directory CRC-32 covers the entries, a file's its contents. The valid
+//! copy with the highest sequence number is the directory.
+//!
+//! The rest is data, each file taking a run of whole sectors. A new file,
+//! or a new version of one, goes to sectors no file in the directory uses,
+//! and only becomes visible when the directory is committed to the other
+//! copy, header last. A power cut at any point leaves either the old
+//! directory and files, or the new ones. One file is written at a time:
+//! [`create`] reserves its sectors, [`write`] fills them a sector at a
+//! time and [`commit`] adds it to the directory, replacing any file of the
+//! same name. Builds with the `dfu` feature take files over the DFU
+//! characteristics (see [`crate::dfu`]).
+
+use core::cell::RefCell;
+
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::mutex;
+
+use crate::flash;
+use crate::info;
+use crate::integrity;
+use crate::partitions;
+
+const MAGIC: u32 = 0x5445_5341; // "ASET"
+
+/// magic, sequence number, count, CRC
+const HEADER_SIZE: usize = 16;
+
+/// name, offset, len, CRC
+const ENTRY_SIZE: usize = NAME_MAX + 12;
+
+/// Longest file name
+pub const NAME_MAX: usize = 16;
+
+/// Most files the directory holds
+pub const FILES_MAX: usize = 32;
+
+/// Sectors of the metadata pair, at the start of the partition
+const DIRECTORY_SECTORS: usize = 2;
+
+const DIRECTORY_SIZE: usize = HEADER_SIZE + FILES_MAX * ENTRY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Flash(flash::Error),
+    /// No run of free sectors large enough
+    NoSpace,
+    /// The directory has [`FILES_MAX`] files
+    Full,
+    NotFound,
+    /// No file being written, or a write outside it
+    Invalid,
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Self::Flash(e)
+    }
+}
+
+/// A file name, up to [`NAME_MAX`] bytes of UTF-8 without NULs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Name {
+    bytes: [u8; NAME_MAX],
+    len: u8,
+}
+
+impl Name {
+    pub fn new(name: &str) -> Option<Self> {
+        Self::from_bytes(name.as_bytes())
+    }
+
+    /// `None` unless `bytes` is a valid name
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.is_empty() || bytes.contains(&0) {
+            return None;
+        }
+        core::str::from_utf8(bytes).ok()?;
+        let mut name = Self {
+            bytes: [0; NAME_MAX],
+            len: bytes.len() as u8,
+        };
+        name.bytes.get_mut(..bytes.len())?.copy_from_slice(bytes);
+        Some(name)
+    }
+
+    /// Parse a NUL padded name from flash
+    fn parse(padded: &[u8]) -> Option<Self> {
+        let len = padded.iter().position(|&b| b == 0).unwrap_or(padded.len());
+        Self::from_bytes(&padded[..len])
+    }
+
+    pub fn as_str(&self) -> &str {
+        // checked when made
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    name: Name,
+    /// From the start of the partition, sector aligned
+    offset: u32,
+    len: u32,
+    crc: u32,
+}
+
+impl Entry {
+    fn sectors(&self) -> core::ops::Range<usize> {
+        let first = self.offset as usize / ERASE_SIZE;
+        first..first + (self.len as usize).div_ceil(ERASE_SIZE)
+    }
+
+    fn data(&self) -> &'static [u8] {
+        let start = self.offset as usize;
+        &partitions::ASSETS.mapped()[start..start + self.len as usize]
+    }
+}
+
+/// A file in the directory
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct File {
+    pub name: Name,
+    pub data: &'static [u8],
+}
+
+struct Directory {
+    /// Whether it was read from flash yet
+    loaded: bool,
+    sequence: u32,
+    /// The copy holding it
+    copy: usize,
+    files: [Option<Entry>; FILES_MAX],
+    /// The file being written, its CRC not known yet
+    pending: Option<Entry>,
+}
+
+impl Directory {
+    fn find(&self, name: &Name) -> Option<&Entry> {
+        self.files.iter().flatten().find(|e| e.name == *name)
+    }
+
+    fn is_used(&self, sector: usize) -> bool {
+        self.files
+            .iter()
+            .chain(core::iter::once(&self.pending))
+            .flatten()
+            .any(|e| e.sectors().contains(&sector))
+    }
+
+    /// First run of `count` free data sectors
+    fn allocate(&self, count: usize) -> Option<usize> {
+        let mut run = 0;
+        for sector in DIRECTORY_SECTORS..partitions::ASSETS.sectors() {
+            run = if self.is_used(sector) { 0 } else { run + 1 };
+            if run == count {
+                return Some(sector + 1 - count);
+            }
+        }
+        None
+    }
+}
+
+static DIRECTORY: Mutex<CriticalSectionRawMutex, RefCell<Directory>> =
+    Mutex::new(RefCell::new(Directory {
+        loaded: false,
+        sequence: 0,
+        copy: 1,
+        files: [None; FILES_MAX],
+        pending: None,
+    }));
+
+/// Held while the directory is committed
+static COMMITTING: mutex::Mutex<CriticalSectionRawMutex, ()> = mutex::Mutex::new(());
+
+fn word(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+/// Parse the directory in `copy`, with its sequence number, `None` if the
+/// copy isn't valid
+fn parse(copy: usize) -> Option<(u32, [Option<Entry>; FILES_MAX])> {
+    let at = copy * ERASE_SIZE;
+    let sector = &partitions::ASSETS.mapped()[at..at + ERASE_SIZE];
+    let count = word(sector, 8) as usize;
+    if word(sector, 0) != MAGIC || count > FILES_MAX {
+        return None;
+    }
+    let entries = &sector[HEADER_SIZE..HEADER_SIZE + count * ENTRY_SIZE];
+    if integrity::crc32(entries) != word(sector, 12) {
+        return None;
+    }
+
+    let mut files = [None; FILES_MAX];
+    for (slot, raw) in files.iter_mut().zip(entries.chunks(ENTRY_SIZE)) {
+        let entry = Entry {
+            name: Name::parse(&raw[..NAME_MAX])?,
+            offset: word(raw, NAME_MAX),
+            len: word(raw, NAME_MAX + 4),
+            crc: word(raw, NAME_MAX + 8),
+        };
+        let sectors = entry.sectors();
+        if entry.offset as usize % ERASE_SIZE != 0
+            || sectors.start < DIRECTORY_SECTORS
+            || sectors.end > partitions::ASSETS.sectors()
+        {
+            return None;
+        }
+        *slot = Some(entry);
+    }
+    Some((word(sector, 4), files))
+}
+
+/// Run `f` on the directory, reading it from flash the first time
+fn with_directory<R>(f: impl FnOnce(&mut Directory) -> R) -> R {
+    DIRECTORY.lock(|directory| {
+        let mut directory = directory.borrow_mut();
+        if !directory.loaded {
+            directory.loaded = true;
+            let best = (0..DIRECTORY_SECTORS)
+                .filter_map(|copy| parse(copy).map(|(sequence, files)| (copy, sequence, files)))
+                .max_by_key(|(_, sequence, _)| *sequence);
+            if let Some((copy, sequence, files)) = best {
+                directory.copy = copy;
+                directory.sequence = sequence;
+                directory.files = files;
+            }
+        }
+        f(&mut directory)
+    })
+}
+
+/// The contents of file `name`. They stay put until the file is replaced
+/// or removed and its sectors go to another file.
+pub fn open(name: &str) -> Option<&'static [u8]> {
+    let name = Name::new(name)?;
+    with_directory(|directory| directory.find(&name).map(Entry::data))
+}
+
+/// The files right now
+pub fn files() -> [Option<File>; FILES_MAX] {
+    with_directory(|directory| {
+        directory.files.map(|entry| {
+            entry.map(|e| File {
+                name: e.name,
+                data: e.data(),
+            })
+        })
+    })
+}
+
+/// Space for data, in bytes
+pub fn capacity() -> usize {
+    (partitions::ASSETS.sectors() - DIRECTORY_SECTORS) * ERASE_SIZE
+}
+
+/// Reserve sectors for a file of `len` bytes named `name`, dropping any
+/// file being written
+pub fn create(name: Name, len: u32) -> Result<(), Error> {
+    with_directory(|directory| {
+        directory.pending = None;
+        if directory.find(&name).is_none() && directory.files.iter().all(Option::is_some) {
+            return Err(Error::Full);
+        }
+        let count = (len as usize).div_ceil(ERASE_SIZE).max(1);
+        let first = directory.allocate(count).ok_or(Error::NoSpace)?;
+        directory.pending = Some(Entry {
+            name,
+            offset: (first * ERASE_SIZE) as u32,
+            len,
+            crc: 0,
+        });
+        Ok(())
+    })
+}
+
+/// Drop the file being written
+pub fn abandon() {
+    with_directory(|directory| directory.pending = None);
+}
+
+/// The file being written, if `offset..offset + len` lies within it
+fn pending_at(offset: u32, len: usize) -> Result<Entry, Error> {
+    let pending = with_directory(|directory| directory.pending).ok_or(Error::Invalid)?;
+    let end = offset as usize + len;
+    if end > pending.sectors().len() * ERASE_SIZE {
+        return Err(Error::Invalid);
+    }
+    Ok(pending)
+}
+
+/// Write `sector`, a whole one, at `offset` in the file being written
+pub async fn write(offset: u32, sector: &[u8; ERASE_SIZE]) -> Result<(), Error> {
+    let pending = pending_at(offset, ERASE_SIZE)?;
+    let at = partitions::ASSETS.start + pending.offset + offset;
+    flash::execute(flash::Op::Erase {
+        from: at,
+        to: at + ERASE_SIZE as u32,
+    })
+    .await?;
+    flash::write(at, sector).await?;
+    Ok(())
+}
+
+/// Read from the file being written, at `offset`
+pub async fn read(offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+    let pending = pending_at(offset, buf.len())?;
+    let at = partitions::ASSETS.start + pending.offset + offset;
+    flash::with_flash(|driver| driver.blocking_read(at, buf))
+        .await
+        .map_err(|e| Error::Flash(flash::Error::Flash(e)))
+}
+
+/// Write `files` to the copy the directory isn't in, header last, and
+/// make it the directory
+async fn store(files: [Option<Entry>; FILES_MAX]) -> Result<(), Error> {
+    let (copy, sequence) = with_directory(|d| (1 - d.copy, d.sequence.wrapping_add(1)));
+
+    let mut buf = [0xff; DIRECTORY_SIZE];
+    let mut count = 0;
+    for entry in files.iter().flatten() {
+        let raw = &mut buf[HEADER_SIZE + count * ENTRY_SIZE..][..ENTRY_SIZE];
+        raw[..NAME_MAX].fill(0);
+        raw[..entry.name.len as usize].copy_from_slice(entry.name.as_str().as_bytes());
+        raw[NAME_MAX..NAME_MAX + 4].copy_from_slice(&entry.offset.to_le_bytes());
+        raw[NAME_MAX + 4..NAME_MAX + 8].copy_from_slice(&entry.len.to_le_bytes());
+        raw[NAME_MAX + 8..].copy_from_slice(&entry.crc.to_le_bytes());
+        count += 1;
+    }
+    let entries_end = HEADER_SIZE + count * ENTRY_SIZE;
+    let crc = integrity::crc32(&buf[HEADER_SIZE..entries_end]);
+    buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    buf[4..8].copy_from_slice(&sequence.to_le_bytes());
+    buf[8..12].copy_from_slice(&(count as u32).to_le_bytes());
+    buf[12..16].copy_from_slice(&crc.to_le_bytes());
+
+    let at = partitions::ASSETS.sector(copy);
+    flash::execute(flash::Op::Erase {
+        from: at,
+        to: at + ERASE_SIZE as u32,
+    })
+    .await?;
+    if count > 0 {
+        flash::write(at + HEADER_SIZE as u32, &buf[HEADER_SIZE..entries_end]).await?;
+    }
+    flash::write(at, &buf[..HEADER_SIZE]).await?;
+
+    with_directory(|directory| {
+        directory.copy = copy;
+        directory.sequence = sequence;
+        directory.files = files;
+    });
+    Ok(())
+}
+
+/// Add the file being written, with the CRC-32 of its `len` bytes, to the
+/// directory
+pub async fn commit(crc: u32) -> Result<(), Error> {
+    let _committing = COMMITTING.lock().await;
+    let (pending, mut files) = with_directory(|directory| (directory.pending, directory.files));
+    let mut pending = pending.ok_or(Error::Invalid)?;
+    pending.crc = crc;
+
+    let slot = match files
+        .iter()
+        .position(|e| e.is_some_and(|e| e.name == pending.name))
+    {
+        Some(idx) => idx,
+        None => files.iter().position(Option::is_none).ok_or(Error::Full)?,
+    };
+    files[slot] = Some(pending);
+    store(files).await?;
+    with_directory(|directory| directory.pending = None);
+    info!(
+        "[assets] {} stored, {} bytes",
+        pending.name.as_str(),
+        pending.len
+    );
+    Ok(())
+}
+
+/// Remove file `name`
+pub async fn remove(name: &str) -> Result<(), Error> {
+    let _committing = COMMITTING.lock().await;
+    let name = Name::new(name).ok_or(Error::NotFound)?;
+    let mut files = with_directory(|directory| directory.files);
+    let slot = files
+        .iter_mut()
+        .find(|e| e.is_some_and(|e| e.name == name))
+        .ok_or(Error::NotFound)?;
+    *slot = None;
+    store(files).await?;
+    info!("[assets] {} removed", name.as_str());
+    Ok(())
+}
+
+crate::register_command!(ASSETS, "assets", "", "list the asset files", |args| {
+    args.end()?;
+    let mut used = 0;
+    for file in files().iter().flatten() {
+        info!("{} {}", file.name.as_str(), file.data.len());
+        used += file.data.len().div_ceil(ERASE_SIZE) * ERASE_SIZE;
+    }
+    info!("{} bytes free", capacity() - used);
+    Ok(())
+});
@@ -0,0 +1,173 @@
+//! Audit trail of writes to security-relevant characteristics
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+
+use crate::fmt;
+use crate::info;
+use crate::paging;
+
+/// Number of entries kept in RAM
+pub const TRAIL_LEN: usize = 8;
+
+/// Number of value bytes kept per entry
+pub const VALUE_LEN: usize = 3;
+
+/// Size of an encoded [`Entry`]
+pub const ENTRY_SIZE: usize = 16;
+
+/// Size of the audit characteristic: total write count followed by the
+/// most recent entry
+pub const CHARACTERISTIC_SIZE: usize = 2 + ENTRY_SIZE;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Entry {
+    /// Address of the peer that wrote
+    pub peer: [u8; 6],
+    /// Attribute handle that was written
+    pub handle: u16,
+    /// Milliseconds since boot
+    pub timestamp_ms: u32,
+    /// Full length of the written value
+    pub len: u8,
+    /// First bytes of the written value
+    pub value: [u8; VALUE_LEN],
+}
+
+impl Entry {
+    pub fn new(peer: &[u8], handle: u16, value: &[u8]) -> Self {
+        let mut entry = Self {
+            peer: [0; 6],
+            handle,
+            timestamp_ms: Instant::now().as_millis() as u32,
+            len: value.len().min(u8::MAX as usize) as u8,
+            value: [0; VALUE_LEN],
+        };
+        let n = peer.len().min(entry.peer.len());
+        entry.peer[..n].copy_from_slice(&peer[..n]);
+        let n = value.len().min(VALUE_LEN);
+        entry.value[..n].copy_from_slice(&value[..n]);
+
+        entry
+    }
+
+    /// little endian: peer, handle, timestamp, len, value
+    pub fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut out = [0; ENTRY_SIZE];
+        out[0..6].copy_from_slice(&self.peer);
+        out[6..8].copy_from_slice(&self.handle.to_le_bytes());
+        out[8..12].copy_from_slice(&self.timestamp_ms.to_le_bytes());
+        out[12] = self.len;
+        out[13..16].copy_from_slice(&self.value);
+
+        out
+    }
+}
+
+struct Trail {
+    entries: [Option<Entry>; TRAIL_LEN],
+    next: usize,
+    total: u16,
+}
+
+static TRAIL: Mutex<CriticalSectionRawMutex, RefCell<Trail>> = Mutex::new(RefCell::new(Trail {
+    entries: [None; TRAIL_LEN],
+    next: 0,
+    total: 0,
+}));
+
+/// Record an entry, returning the encoded audit characteristic value
+pub fn record(entry: Entry) -> [u8; CHARACTERISTIC_SIZE] {
+    info!(
+        "[audit] {:?} wrote {} bytes to handle {} at {}ms",
+        fmt::Bytes(&entry.peer),
+        entry.len,
+        entry.handle,
+        entry.timestamp_ms
+    );
+
+    let total = TRAIL.lock(|trail| {
+        let mut trail = trail.borrow_mut();
+        let next = trail.next;
+        trail.entries[next] = Some(entry);
+        trail.next = (next + 1) % TRAIL_LEN;
+        trail.total = trail.total.wrapping_add(1);
+        trail.total
+    });
+
+    let mut out = [0; CHARACTERISTIC_SIZE];
+    out[..2].copy_from_slice(&total.to_le_bytes());
+    out[2..].copy_from_slice(&entry.encode());
+
+    out
+}
+
+/// Number of writes recorded since boot, wrapping
+pub fn total() -> u16 {
+    TRAIL.lock(|trail| trail.borrow().total)
+}
+
+/// Replace the trail with `entries` (oldest first) and the total count,
+/// for restoring snapshots
+pub(crate) fn restore<'a>(entries: impl Iterator<Item = &'a Entry>, total: u16) {
+    TRAIL.lock(|trail| {
+        let mut trail = trail.borrow_mut();
+        *trail = Trail {
+            entries: [None; TRAIL_LEN],
+            next: 0,
+            total,
+        };
+        for entry in entries {
+            let next = trail.next;
+            trail.entries[next] = Some(*entry);
+            trail.next = (next + 1) % TRAIL_LEN;
+        }
+    });
+}
+
+/// Iterate over the recorded entries, most recent first
+pub fn for_each(mut f: impl FnMut(&Entry)) {
+    TRAIL.lock(|trail| {
+        let trail = trail.borrow();
+        for i in 1..=TRAIL_LEN {
+            let idx = (trail.next + TRAIL_LEN - i) % TRAIL_LEN;
+            if let Some(entry) = &trail.entries[idx] {
+                f(entry);
+            }
+        }
+    });
+}
+
+/// The whole trail as a pageable dataset, most recent entry first
+pub struct Dataset;
+
+impl paging::Dataset for Dataset {
+    fn len(&self) -> usize {
+        let mut len = 0;
+        for_each(|_| len += ENTRY_SIZE);
+        len
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        let mut pos = 0;
+        for_each(|entry| {
+            let encoded = entry.encode();
+            let start = pos;
+            pos += ENTRY_SIZE;
+            if pos <= offset || copied == buf.len() {
+                return;
+            }
+
+            let src = &encoded[offset.saturating_sub(start)..];
+            let n = src.len().min(buf.len() - copied);
+            buf[copied..copied + n].copy_from_slice(&src[..n]);
+            copied += n;
+        });
+        copied
+    }
+}
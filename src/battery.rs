@@ -0,0 +1,127 @@
+//! Battery level
+//!
+//! VSYS comes in through a 1:3 divider on the pin map's battery pin. The
+//! Pico W has a divider of its own on GPIO29, but that pin doubles as the
+//! radio's SPI clock and can't be read while the radio runs. The ADC
+//! stream samples the pin every few blocks (see [`adcstream::aux`]).
+//!
+//! The voltage is smoothed and mapped to a charge level along a LiPo
+//! discharge curve. The level only moves once it's [`HYSTERESIS`] points
+//! away from the last one, so noise doesn't flip it back and forth, and
+//! only those moves are notified, and fed to the [`power`] policy. Boards
+//! without a battery pin never get a level.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use trouble_host::prelude::*;
+
+use crate::adcstream;
+use crate::info;
+use crate::modules;
+use crate::power;
+use crate::telemetry;
+use crate::writes;
+
+/// Change in percent that moves the level
+pub const HYSTERESIS: u8 = 2;
+
+/// The smoothed voltage moves by 1/2^this of each sample's difference
+const SMOOTHING_SHIFT: u32 = 3;
+
+/// ADC reference in mV
+const VREF_MV: u32 = 3300;
+
+/// VSYS in mV and the charge level there, highest first. Linear in between.
+const CURVE: [(u16, u8); 9] = [
+    (4200, 100),
+    (4100, 90),
+    (4000, 80),
+    (3900, 65),
+    (3800, 50),
+    (3700, 30),
+    (3600, 15),
+    (3500, 5),
+    (3300, 0),
+];
+
+static LEVEL: Mutex<CriticalSectionRawMutex, Cell<Option<u8>>> = Mutex::new(Cell::new(None));
+
+/// The charge level in percent, `None` before the first sample
+pub fn level() -> Option<u8> {
+    LEVEL.lock(|level| level.get())
+}
+
+fn percent(mv: u16) -> u8 {
+    let (top_mv, top) = CURVE[0];
+    if mv >= top_mv {
+        return top;
+    }
+    for pair in CURVE.windows(2) {
+        let [(hi_mv, hi), (lo_mv, lo)] = [pair[0], pair[1]];
+        if mv >= lo_mv {
+            let span = (hi - lo) as u32 * (mv - lo_mv) as u32 / (hi_mv - lo_mv) as u32;
+            return lo + span as u8;
+        }
+    }
+    0
+}
+
+pub async fn run() -> ! {
+    // the smoothed voltage, scaled up by the smoothing
+    let mut smoothed: Option<u32> = None;
+    loop {
+        let mv = adcstream::aux().await as u32 * 3 * VREF_MV / 4096;
+        let scaled = match smoothed {
+            Some(s) => s - (s >> SMOOTHING_SHIFT) + mv,
+            None => mv << SMOOTHING_SHIFT,
+        };
+        smoothed = Some(scaled);
+
+        let mv = (scaled >> SMOOTHING_SHIFT) as u16;
+        let new = percent(mv);
+        telemetry::record(telemetry::Signal::Battery, new.into());
+        let moved = match level() {
+            None => true,
+            // full and empty are worth reporting however close they are
+            Some(last) => {
+                last.abs_diff(new) >= HYSTERESIS || (new != last && (new == 0 || new == 100))
+            }
+        };
+        if moved {
+            info!("[battery] {}% ({}mV)", new, mv);
+            LEVEL.lock(|level| level.set(Some(new)));
+            modules::notify(LEVEL_UUID, &[new]);
+            power::charge_changed(new);
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn task() -> ! {
+    run().await
+}
+
+const SERVICE_UUID: Uuid = Uuid::Uuid16(0x180fu16.to_le_bytes());
+const LEVEL_UUID: Uuid = Uuid::Uuid16(0x2a19u16.to_le_bytes());
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    svc.add(
+        LEVEL_UUID,
+        &[CharacteristicProp::Read, CharacteristicProp::Notify],
+        1,
+        writes::Spec::new().with_read(|| writes::Value::new(&[level().unwrap_or(0)])),
+    );
+}
+
+crate::register_module!(
+    MODULE,
+    "battery",
+    |resources| resources.spawner.must_spawn(task()),
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |_| true,
+        build: service,
+    },
+);
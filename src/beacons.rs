@@ -0,0 +1,397 @@
+//! Mirrored BTHome beacons
+//!
+//! Temperature broadcasters nearby (anything sending unencrypted BTHome
+//! v2) are picked up while [`crate::central`] scans, and their latest
+//! readings are mirrored into the beacons characteristic so a phone
+//! connected to us sees them too. The central scans for
+//! `beacons.window` ms every `beacons.period` s on top of its own
+//! scanning, next to advertising and the connections we serve.
+//!
+//! The characteristic is [`BEACONS_MAX`] entries of `[address: 6, rssi:
+//! i8, temperature: i16 in 0.01 °C, humidity: u16 in 0.01 %, battery %:
+//! u8, age s: u8]`, little endian. Readings a beacon didn't send are
+//! `0x8000`, `0xffff` and `0xff`, unused entries all zero.
+//!
+//! On top of that, specific broadcasters can be followed as sources by
+//! setting `beacons.source0` to `beacons.source3` to their address
+//! (`aa:bb:cc:dd:ee:ff`, as scanners show it). Each set source gets its own
+//! characteristic when the radio starts, with the latest frame of a format
+//! we know: `[format, rssi: i8, age s: u8, data]`, all zero until heard.
+//!
+//! | format | data |
+//! |---|---|
+//! | 1 BTHome | temperature: i16, humidity: u16, battery: u8, as above |
+//! | 2 iBeacon | UUID: 16, major: u16, minor: u16, tx power: i8 |
+//! | 3 Eddystone TLM | battery mV: u16, temperature: i16 in 1/256 °C, advertising count: u32, uptime: u32 in 0.1 s |
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use trouble_host::prelude::*;
+
+use crate::adparse::AdStructure;
+use crate::adparse::Eddystone;
+use crate::blue::gen_uuid;
+use crate::bthome;
+use crate::config;
+use crate::config::Text;
+use crate::error;
+use crate::fmt;
+use crate::gattcheck;
+use crate::info;
+use crate::modules;
+use crate::observer;
+use crate::observer::Report;
+use crate::writes;
+
+crate::config_key!(
+    /// Seconds between scan windows, 0 to only scan when the central does
+    pub PERIOD: u32 = "beacons.period",
+    30,
+);
+
+crate::config_key!(
+    /// Length of a scan window in ms
+    pub WINDOW: u32 = "beacons.window",
+    3000,
+);
+
+crate::config_key!(
+    /// Address of a source to follow, empty for none
+    pub SOURCE0: Text = "beacons.source0",
+    Text::new(""),
+);
+
+crate::config_key!(pub SOURCE1: Text = "beacons.source1", Text::new(""));
+crate::config_key!(pub SOURCE2: Text = "beacons.source2", Text::new(""));
+crate::config_key!(pub SOURCE3: Text = "beacons.source3", Text::new(""));
+
+/// Number of sources that can be followed
+pub const SOURCES_MAX: usize = 4;
+
+static SOURCE_KEYS: [&config::Key<Text>; SOURCES_MAX] = [&SOURCE0, &SOURCE1, &SOURCE2, &SOURCE3];
+
+/// Size of a source characteristic
+pub const SOURCE_SIZE: usize = 3 + 21;
+
+const FORMAT_BTHOME: u8 = 1;
+const FORMAT_IBEACON: u8 = 2;
+const FORMAT_TLM: u8 = 3;
+
+/// Number of beacons mirrored, the least recently heard one making room
+pub const BEACONS_MAX: usize = 4;
+
+/// Size of an encoded beacon
+const ENTRY_SIZE: usize = 13;
+
+/// Size of the beacons characteristic
+pub const CHARACTERISTIC_SIZE: usize = BEACONS_MAX * ENTRY_SIZE;
+
+/// Beacons not heard from for this long are dropped
+const STALE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy)]
+struct Beacon {
+    address: [u8; 6],
+    rssi: i8,
+    temperature: Option<i16>,
+    humidity: Option<u16>,
+    battery: Option<u8>,
+    heard: Instant,
+}
+
+impl Beacon {
+    fn encode(&self, out: &mut [u8]) {
+        let age = self.heard.elapsed().as_secs().min(u8::MAX as u64) as u8;
+        out[..6].copy_from_slice(&self.address);
+        out[6] = self.rssi as u8;
+        out[7..9].copy_from_slice(&self.temperature.unwrap_or(i16::MIN).to_le_bytes());
+        out[9..11].copy_from_slice(&self.humidity.unwrap_or(u16::MAX).to_le_bytes());
+        out[11] = self.battery.unwrap_or(u8::MAX);
+        out[12] = age;
+    }
+}
+
+static BEACONS: Mutex<CriticalSectionRawMutex, RefCell<[Option<Beacon>; BEACONS_MAX]>> =
+    Mutex::new(RefCell::new([None; BEACONS_MAX]));
+
+/// The latest frame of a source, encoded but for its age
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    value: [u8; SOURCE_SIZE],
+    len: usize,
+    heard: Instant,
+}
+
+static SOURCES: Mutex<CriticalSectionRawMutex, RefCell<[Option<Frame>; SOURCES_MAX]>> =
+    Mutex::new(RefCell::new([None; SOURCES_MAX]));
+
+/// Parse `aa:bb:cc:dd:ee:ff` into an address as sent over the air, least
+/// significant byte first
+pub(crate) fn parse_address(text: &str) -> Option<[u8; 6]> {
+    let mut address = [0; 6];
+    let mut parts = text.split(':');
+    for byte in address.iter_mut().rev() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(address)
+}
+
+/// The address source `idx` follows, `None` if it's unset or invalid
+fn source_address(idx: usize) -> Option<[u8; 6]> {
+    parse_address(SOURCE_KEYS.get(idx)?.get().as_str())
+}
+
+/// Log the sources that are set but won't be followed
+fn check_sources() {
+    for key in SOURCE_KEYS {
+        let text = key.get();
+        if !text.is_empty() && parse_address(text.as_str()).is_none() {
+            error!("[beacons] invalid {} {:?}", key.name, text.as_str());
+        }
+    }
+}
+
+/// Decode the BTHome readings of `report`, `None` if it has none
+fn decode(report: &Report) -> Option<Beacon> {
+    let mut address = [0; 6];
+    address.copy_from_slice(report.addr.raw());
+    let mut beacon = Beacon {
+        address,
+        rssi: report.rssi,
+        temperature: None,
+        humidity: None,
+        battery: None,
+        heard: Instant::now(),
+    };
+    let mut any = false;
+    for structure in report.structures().flatten() {
+        let AdStructure::ServiceData16 { uuid, data } = structure else {
+            continue;
+        };
+        if uuid != bthome::UUID {
+            continue;
+        }
+        for object in bthome::decode(data)? {
+            match object {
+                bthome::Object::Temperature(t) => beacon.temperature = Some(t),
+                bthome::Object::Humidity(h) => beacon.humidity = Some(h),
+                bthome::Object::Battery(b) => beacon.battery = Some(b),
+                _ => continue,
+            }
+            any = true;
+        }
+    }
+    any.then_some(beacon)
+}
+
+/// Encode the first frame of `report` in a format we know, for a source
+fn decode_frame(report: &Report) -> Option<Frame> {
+    let mut value = [0; SOURCE_SIZE];
+    value[1] = report.rssi as u8;
+    let data = &mut value[3..];
+    let (format, len) = report.structures().flatten().find_map(|s| match s {
+        AdStructure::IBeacon(beacon) => {
+            data[..16].copy_from_slice(&beacon.uuid);
+            data[16..18].copy_from_slice(&beacon.major.to_le_bytes());
+            data[18..20].copy_from_slice(&beacon.minor.to_le_bytes());
+            data[20] = beacon.tx_power as u8;
+            Some((FORMAT_IBEACON, 21))
+        }
+        AdStructure::Eddystone(Eddystone::Tlm {
+            battery_mv,
+            temperature,
+            adv_count,
+            uptime,
+        }) => {
+            data[..2].copy_from_slice(&battery_mv.to_le_bytes());
+            data[2..4].copy_from_slice(&temperature.to_le_bytes());
+            data[4..8].copy_from_slice(&adv_count.to_le_bytes());
+            data[8..12].copy_from_slice(&uptime.to_le_bytes());
+            Some((FORMAT_TLM, 12))
+        }
+        _ => None,
+    })?;
+    value[0] = format;
+    Some(Frame {
+        value,
+        len: 3 + len,
+        heard: Instant::now(),
+    })
+}
+
+/// A BTHome beacon's readings as a source frame
+fn bthome_frame(beacon: &Beacon) -> Frame {
+    let mut value = [0; SOURCE_SIZE];
+    let mut entry = [0; ENTRY_SIZE];
+    beacon.encode(&mut entry);
+    value[0] = FORMAT_BTHOME;
+    value[1] = beacon.rssi as u8;
+    // temperature, humidity and battery
+    value[3..8].copy_from_slice(&entry[7..12]);
+    Frame {
+        value,
+        len: 3 + 5,
+        heard: beacon.heard,
+    }
+}
+
+fn update_sources(report: &Report, beacon: Option<&Beacon>) {
+    for idx in 0..SOURCES_MAX {
+        if !source_address(idx).is_some_and(|address| address == report.addr.raw()) {
+            continue;
+        }
+        let Some(frame) = beacon.map(bthome_frame).or_else(|| decode_frame(report)) else {
+            continue;
+        };
+        SOURCES.lock(|sources| sources.borrow_mut()[idx] = Some(frame));
+        modules::notify(SOURCE_UUIDS[idx], &source(idx));
+    }
+}
+
+/// The characteristic value of source `idx`
+fn source(idx: usize) -> [u8; SOURCE_SIZE] {
+    let frame = SOURCES.lock(|sources| sources.borrow().get(idx).copied().flatten());
+    let mut out = [0; SOURCE_SIZE];
+    if let Some(frame) = frame {
+        out[..frame.len].copy_from_slice(&frame.value[..frame.len]);
+        out[2] = frame.heard.elapsed().as_secs().min(u8::MAX as u64) as u8;
+    }
+    out
+}
+
+fn update(beacon: Beacon) {
+    BEACONS.lock(|beacons| {
+        let mut beacons = beacons.borrow_mut();
+        let idx = beacons
+            .iter()
+            .position(|b| b.is_some_and(|b| b.address == beacon.address))
+            .or_else(|| beacons.iter().position(Option::is_none))
+            .or_else(|| (0..BEACONS_MAX).min_by_key(|&i| beacons[i].map(|b| b.heard)))
+            .unwrap_or(0);
+        if beacons[idx].is_none() {
+            info!("[beacons] mirroring {:?}", fmt::Bytes(&beacon.address));
+        }
+        beacons[idx] = Some(beacon);
+    });
+    modules::notify(READINGS_UUID, &encode());
+}
+
+fn expire() {
+    BEACONS.lock(|beacons| {
+        for slot in beacons.borrow_mut().iter_mut() {
+            if slot.is_some_and(|b| b.heard.elapsed() > STALE_AFTER) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// The beacons characteristic value
+pub fn encode() -> [u8; CHARACTERISTIC_SIZE] {
+    expire();
+    let mut out = [0; CHARACTERISTIC_SIZE];
+    BEACONS.lock(|beacons| {
+        for (beacon, entry) in beacons
+            .borrow()
+            .iter()
+            .zip(out.chunks_exact_mut(ENTRY_SIZE))
+        {
+            if let Some(beacon) = beacon {
+                beacon.encode(entry);
+            }
+        }
+    });
+    out
+}
+
+/// The scan window and the time between them, `None` if periodic scanning
+/// is off
+pub fn schedule() -> Option<(Duration, Duration)> {
+    let period = PERIOD.get();
+    let window = WINDOW.get();
+    if period == 0 || window == 0 {
+        return None;
+    }
+    Some((
+        Duration::from_millis(window as u64),
+        Duration::from_secs(period as u64),
+    ))
+}
+
+async fn run() -> ! {
+    let Some(mut reports) = observer::subscribe() else {
+        error!("[beacons] no report subscriber left");
+        return core::future::pending().await;
+    };
+    loop {
+        let report = reports.next_message_pure().await;
+        let beacon = decode(&report);
+        update_sources(&report, beacon.as_ref());
+        if let Some(beacon) = beacon {
+            update(beacon);
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn task() -> ! {
+    run().await
+}
+
+const SERVICE_UUID: Uuid = gen_uuid("beacons");
+const READINGS_UUID: Uuid = gen_uuid("beacon readings");
+const SOURCE_UUIDS: [Uuid; SOURCES_MAX] = [
+    gen_uuid("beacon source 0"),
+    gen_uuid("beacon source 1"),
+    gen_uuid("beacon source 2"),
+    gen_uuid("beacon source 3"),
+];
+const _: () = gattcheck::assert_distinct(&[&[READINGS_UUID], &SOURCE_UUIDS]);
+const _: () = gattcheck::assert_fit(&[CHARACTERISTIC_SIZE, SOURCE_SIZE], writes::READ_MAX);
+
+fn read_source<const IDX: usize>() -> writes::Value {
+    writes::Value::new(&source(IDX))
+}
+
+const SOURCE_READS: [writes::Reader; SOURCES_MAX] = [
+    read_source::<0>,
+    read_source::<1>,
+    read_source::<2>,
+    read_source::<3>,
+];
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    let props = [CharacteristicProp::Read, CharacteristicProp::Notify];
+    let spec = writes::Spec::new();
+    svc.add(
+        READINGS_UUID,
+        &props,
+        CHARACTERISTIC_SIZE,
+        spec.with_read(|| writes::Value::new(&encode())),
+    );
+    check_sources();
+    // only the sources set when the radio starts get one
+    for idx in (0..SOURCES_MAX).filter(|&idx| source_address(idx).is_some()) {
+        svc.add(
+            SOURCE_UUIDS[idx],
+            &props,
+            SOURCE_SIZE,
+            spec.with_read(SOURCE_READS[idx]),
+        );
+    }
+}
+
+crate::register_module!(
+    MODULE,
+    "beacons",
+    |resources| resources.spawner.must_spawn(task()),
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |_| true,
+        build: service,
+    },
+);
@@ -0,0 +1,113 @@
+//! Large characteristic values
+//!
+//! Values longer than an ATT PDU, like configuration blobs or JSON, go
+//! through the blob characteristic, up to [`SIZE`] bytes of them. Clients
+//! write it with a long (queued) write and read it back with long reads,
+//! which the host serves from the attribute at the offsets asked for.
+//!
+//! We only see the writes the host reports, not the prepare and execute
+//! requests behind them, and the parts of a queued write can be in the
+//! attribute before it's executed, or after it's cancelled. A shorter value
+//! written over a longer one leaves the tail of the old one too. So the
+//! value carries its length and a CRC32, little endian:
+//!
+//! | bytes | content                  |
+//! |-------|--------------------------|
+//! | 0..2  | length                   |
+//! | 2..6  | CRC32 of the data        |
+//! | 6..   | data, [`SIZE`] at most   |
+//!
+//! and is only taken once they check out. [`get`] has the last value
+//! taken, [`changed`] waits for the next one.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+
+use crate::gattcheck;
+use crate::info;
+use crate::integrity;
+
+/// Length, CRC32
+const HEADER_SIZE: usize = 6;
+
+/// Longest value, what's left of the longest attribute ATT allows
+pub const SIZE: usize = gattcheck::VALUE_MAX - HEADER_SIZE;
+
+/// Size of the blob characteristic
+pub const CHARACTERISTIC_SIZE: usize = HEADER_SIZE + SIZE;
+
+struct Value {
+    data: [u8; SIZE],
+    len: usize,
+}
+
+static VALUE: Mutex<CriticalSectionRawMutex, RefCell<Value>> = Mutex::new(RefCell::new(Value {
+    data: [0; SIZE],
+    len: 0,
+}));
+
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The data of a written characteristic value, `None` if it isn't all
+/// there (yet)
+pub fn decode(value: &[u8]) -> Option<&[u8]> {
+    let header = value.get(..HEADER_SIZE)?;
+    let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+    let crc = u32::from_le_bytes(header[2..6].try_into().unwrap());
+    let data = value
+        .get(HEADER_SIZE..HEADER_SIZE + len)
+        .filter(|_| len <= SIZE)?;
+    (integrity::crc32(data) == crc).then_some(data)
+}
+
+/// Encode `data` as a characteristic value into `out`, returning its
+/// length, `None` if it's longer than [`SIZE`] or `out`
+pub fn encode(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    let len = HEADER_SIZE + data.len();
+    if data.len() > SIZE || len > out.len() {
+        return None;
+    }
+    out[0..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    out[2..6].copy_from_slice(&integrity::crc32(data).to_le_bytes());
+    out[HEADER_SIZE..len].copy_from_slice(data);
+    Some(len)
+}
+
+/// Take a written characteristic value if it checks out, returning the
+/// length of its data
+pub fn receive(value: &[u8]) -> Option<usize> {
+    let data = decode(value)?;
+    let changed = VALUE.lock(|value| {
+        let mut value = value.borrow_mut();
+        if value.data[..value.len] == *data {
+            return false;
+        }
+        value.data[..data.len()].copy_from_slice(data);
+        value.len = data.len();
+        true
+    });
+    if changed {
+        info!("[blob] {} bytes written", data.len());
+        CHANGED.signal(());
+    }
+    Some(data.len())
+}
+
+/// Copy the last value taken into `out`, returning its length. `out` has
+/// to take [`SIZE`] bytes to be sure to get all of it.
+pub fn get(out: &mut [u8]) -> usize {
+    VALUE.lock(|value| {
+        let value = value.borrow();
+        let len = value.len.min(out.len());
+        out[..len].copy_from_slice(&value.data[..len]);
+        len
+    })
+}
+
+/// Wait for a new value. Only meant for a single caller at a time.
+pub async fn changed() {
+    CHANGED.wait().await
+}
@@ -1,137 +1,1393 @@
-use embassy_futures::yield_now;
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::channel::Sender;
-use log::error;
-use log::info;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
 
+use core::convert::Infallible;
+use core::fmt::Debug;
+use core::future::pending;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use embassy_futures::join::join4;
+use embassy_futures::join::join5;
+use embassy_futures::select::select;
 use embassy_futures::select::select3;
+use embassy_futures::select::select4;
+use embassy_futures::select::select_array;
+use embassy_futures::select::Either;
+use embassy_futures::select::Either3;
+use embassy_futures::select::Either4;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use trouble_host::prelude::*;
 
+use crate::accept;
+use crate::adcstream;
+#[cfg(feature = "admin")]
+use crate::admin;
+use crate::adv;
+use crate::aggregate;
+use crate::alarm;
+use crate::anchor;
+use crate::assets;
+use crate::audit;
+use crate::blob;
+use crate::boardrev;
+use crate::boost;
+use crate::bthome;
+use crate::calibration;
+use crate::central;
+use crate::clock;
+use crate::conninfo;
+use crate::controls;
+use crate::crash;
+#[cfg(feature = "dfu")]
+use crate::dfu;
+use crate::disconnects;
+use crate::echo;
+use crate::error;
+use crate::events;
+use crate::events::Event;
+use crate::explorer;
+use crate::fault;
+#[cfg(feature = "findnet")]
+use crate::findnet;
+use crate::fmt;
+use crate::gattcheck;
+use crate::gatttrace;
+use crate::handoff;
+use crate::hcilink;
+#[cfg(debug_assertions)]
+use crate::impair;
+use crate::info;
+use crate::integrity;
+use crate::latency;
 use crate::lighting::Message;
-use crate::Color;
+use crate::links::Links;
+use crate::matter;
+use crate::mode;
+use crate::modules;
+use crate::monitor;
+use crate::observer;
+use crate::ots;
+#[cfg(debug_assertions)]
+use crate::overrides;
+use crate::paging;
+use crate::params;
+use crate::peers;
+use crate::persist;
+use crate::pinmap;
+use crate::power;
+#[cfg(feature = "dfu")]
+use crate::radiofw;
+use crate::relay;
+use crate::roles;
+use crate::rollup;
+use crate::session;
+use crate::settings;
+use crate::stats;
+use crate::strings;
+use crate::subscriptions;
+use crate::supervisor;
+use crate::supervisor::Exit;
+use crate::system;
+use crate::telemetry;
+use crate::threshold;
+use crate::watchdog;
+use crate::writes;
 
 /// Size of L2CAP packets (ATT MTU is this - 4)
-const L2CAP_MTU: usize = 251;
+pub(crate) const L2CAP_MTU: usize = 251;
+
+/// Max number of connections from centrals
+pub(crate) const CONNECTIONS_MAX: usize = 3;
+
+/// Max number of host connections, the central's included
+const HOST_CONNECTIONS_MAX: usize = CONNECTIONS_MAX + central::LINKS_MAX;
+
+/// Max number of L2CAP channels: signal and att per connection, and the
+/// object channel per central's
+const L2CAP_CHANNELS_MAX: usize = 2 * HOST_CONNECTIONS_MAX + CONNECTIONS_MAX;
+
+pub(crate) const MAX_ATTRIBUTES: usize = 315;
+
+/// Manufacturer name in the Device Information Service
+const MANUFACTURER: &str = "micycle8778";
+
+/// Firmware revision in the Device Information Service: the crate version
+/// and the commit it was built from
+const FIRMWARE_REVISION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GIT_HASH"));
+
+/// Connections that finished the BTP handshake
+static BTP_OPEN: session::PerConnection<bool> = session::PerConnection::new(false);
 
-/// Max number of connections
-const CONNECTIONS_MAX: usize = 1;
+/// Page cursors for the audit trail
+static AUDIT_PAGER: paging::Pager = paging::Pager::new();
 
-/// Max number of L2CAP channels.
-const L2CAP_CHANNELS_MAX: usize = 2; // Signal + att
+/// Locale each connection reads the string table in
+static LOCALE: session::PerConnection<strings::Locale> =
+    session::PerConnection::new(strings::Locale::En);
 
-const MAX_ATTRIBUTES: usize = 32;
+/// Page cursors for the string table
+static STRINGS_PAGER: paging::Pager = paging::Pager::new();
 
-type Resources<C> = HostResources<C, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX, L2CAP_MTU>;
+/// Page cursors for the last crash report
+static CRASH_PAGER: paging::Pager = paging::Pager::new();
+
+/// Page cursors for the fault log
+static FAULT_PAGER: paging::Pager = paging::Pager::new();
+
+/// Page cursors for the characteristic statistics
+static STATS_PAGER: paging::Pager = paging::Pager::new();
+
+/// Page cursors for the daily statistics
+static ROLLUP_PAGER: paging::Pager = paging::Pager::new();
+
+/// Page cursors for the GATT operation trace
+static TRACE_PAGER: paging::Pager = paging::Pager::new();
+
+/// Whether the stored controls went to the lighting yet
+static CONTROLS_RESTORED: AtomicBool = AtomicBool::new(false);
+
+type Resources<C> = HostResources<C, HOST_CONNECTIONS_MAX, L2CAP_CHANNELS_MAX, L2CAP_MTU>;
 
 // GATT Server definition
 #[gatt_server(attribute_data_size = 32)]
 struct Server {}
 
+impl<C: Controller> Server<'_, '_, C> {
+    /// Whether `conn` enabled notifications or indications of `handle`,
+    /// and its peer didn't mute it
+    fn is_subscribed(&self, handle: Characteristic, conn: &Connection<'_>) -> bool {
+        subscriptions::is_subscribed(conn.handle(), handle.handle)
+            && !peers::muted(conn.handle(), handle.handle)
+    }
+
+    /// Read, change and write back the value we serve for `handle`, then,
+    /// with `notify`, send what it is afterwards to the connections in
+    /// `conns` that subscribed. No client write lands in between: there's
+    /// no await from the read to the write, and writes only reach the
+    /// table while the host runs on this executor. A value a demo override
+    /// froze is left alone, and that's what gets notified.
+    pub async fn update(
+        &self,
+        handle: Characteristic,
+        conns: &[Option<Connection<'_>>],
+        notify: bool,
+        f: impl FnOnce(&mut Served),
+    ) {
+        let mut served = Served {
+            bytes: [0; UPDATE_MAX],
+            len: 0,
+        };
+        let current = self.get(handle, |value| served.replace(value));
+        #[cfg(debug_assertions)]
+        let frozen = overrides::is_frozen(handle.handle);
+        #[cfg(not(debug_assertions))]
+        let frozen = false;
+        if current.is_ok() && !frozen {
+            f(&mut served);
+            let _ = self.set(handle, served.as_slice());
+        }
+        if !notify {
+            return;
+        }
+        for conn in conns.iter().flatten() {
+            if let Err(e) = notify_subscribed(self, handle, conn, served.as_slice()).await {
+                error!("[gatt] notify to {:?} failed: {:?}", handle, fmt::Dbg(&e));
+            }
+        }
+    }
+}
+
+impl<C: Controller> explorer::Values for Server<'_, '_, C> {
+    fn read(&self, characteristic: Characteristic, out: &mut [u8]) -> Option<usize> {
+        self.get(characteristic, |value| {
+            let len = value.len().min(out.len());
+            out[..len].copy_from_slice(&value[..len]);
+            value.len()
+        })
+        .ok()
+    }
+
+    fn write(&self, characteristic: Characteristic, value: &[u8]) -> bool {
+        self.set(characteristic, value).is_ok()
+    }
+}
+
+/// What a characteristic's value means to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Semantics {
+    /// Only the latest value matters. Updates waiting to be notified are
+    /// coalesced, including those replayed at connect, and a demo override
+    /// can freeze the value.
+    State,
+    /// Every occurrence matters. Waiting updates are all notified in
+    /// order, and it can't be frozen, which would swallow occurrences.
+    Event,
+}
+
+#[derive(Clone, Copy)]
 struct Handles {
-    base_color: Characteristic,
-    brightness: Characteristic,
-    skip: Characteristic,
+    controls: [Characteristic; controls::CONTROLS.len()],
+    control: Characteristic,
+    audit: Characteristic,
+    audit_index: Characteristic,
+    audit_page: Characteristic,
+    hash_request: Characteristic,
+    hash_result: Characteristic,
+    clock: Characteristic,
+    btp_c1: Characteristic,
+    btp_c2: Characteristic,
+    adc_summary: Characteristic,
+    summary_window: Characteristic,
+    report_delta: Characteristic,
+    alarm: Characteristic,
+    alarm_limits: Characteristic,
+    locale: Characteristic,
+    strings_index: Characteristic,
+    strings_page: Characteristic,
+    calibration: Characteristic,
+    calibration_status: Characteristic,
+    tasks: Characteristic,
+    recovery: Characteristic,
+    pin_map: Characteristic,
+    echo: Characteristic,
+    blob: Characteristic,
+    crash_index: Characteristic,
+    crash_page: Characteristic,
+    last_fault: Characteristic,
+    power: Characteristic,
+    boost: Characteristic,
+    fault_index: Characteristic,
+    fault_page: Characteristic,
+    stats_index: Characteristic,
+    stats_page: Characteristic,
+    rollup_index: Characteristic,
+    rollup_page: Characteristic,
+    trace_index: Characteristic,
+    trace_page: Characteristic,
+    peers: Characteristic,
+    telemetry: Characteristic,
+    /// The services [`params::Services`] leaves out have none
+    object_name: Option<Characteristic>,
+    object_size: Option<Characteristic>,
+    object_id: Option<Characteristic>,
+    object_properties: Option<Characteristic>,
+    oacp: Option<Characteristic>,
+    olcp: Option<Characteristic>,
+    #[cfg(debug_assertions)]
+    overrides: Characteristic,
+    #[cfg(debug_assertions)]
+    impairment: Characteristic,
+    #[cfg(feature = "dfu")]
+    dfu_control: Characteristic,
+    #[cfg(feature = "dfu")]
+    dfu_data: Characteristic,
+    #[cfg(feature = "dfu")]
+    dfu_status: Characteristic,
+    #[cfg(feature = "dfu")]
+    radio_firmware: Characteristic,
+}
+
+impl Handles {
+    /// Whether writes to `handle` go into the audit trail: every sensitive
+    /// command. A state write leaves its mark in the value.
+    fn audited(&self, handle: Characteristic) -> bool {
+        self.sensitive(handle) && self.semantics(handle.handle) == Semantics::Event
+    }
+
+    /// The characteristic `cccd` is the CCCD of
+    fn with_cccd(&self, cccd: Characteristic) -> Option<Characteristic> {
+        self.all().find(|c| c.cccd_handle == Some(cccd.handle))
+    }
+
+    fn all(&self) -> impl Iterator<Item = Characteristic> {
+        self.controls
+            .into_iter()
+            .chain([
+                self.control,
+                self.audit,
+                self.audit_index,
+                self.audit_page,
+                self.hash_request,
+                self.hash_result,
+                self.clock,
+                self.btp_c1,
+                self.btp_c2,
+                self.adc_summary,
+                self.summary_window,
+                self.report_delta,
+                self.alarm,
+                self.alarm_limits,
+                self.locale,
+                self.strings_index,
+                self.strings_page,
+                self.calibration,
+                self.calibration_status,
+                self.tasks,
+                self.recovery,
+                self.pin_map,
+                self.echo,
+                self.blob,
+                self.crash_index,
+                self.crash_page,
+                self.last_fault,
+                self.power,
+                self.boost,
+                self.fault_index,
+                self.fault_page,
+                self.stats_index,
+                self.stats_page,
+                self.rollup_index,
+                self.rollup_page,
+                self.trace_index,
+                self.trace_page,
+                self.peers,
+                self.telemetry,
+            ])
+            .chain(
+                [
+                    self.object_name,
+                    self.object_size,
+                    self.object_id,
+                    self.object_properties,
+                    self.oacp,
+                    self.olcp,
+                ]
+                .into_iter()
+                .flatten(),
+            )
+    }
+
+    /// Commands, packets and pings are events, everything else is state
+    fn semantics(&self, handle: u16) -> Semantics {
+        #[cfg(feature = "dfu")]
+        if handle == self.dfu_control.handle {
+            return Semantics::Event;
+        }
+        let events = [
+            self.control,
+            self.hash_request,
+            self.btp_c1,
+            self.btp_c2,
+            self.calibration,
+            self.echo,
+            self.peers,
+        ];
+        let optional = [self.oacp, self.olcp];
+        if events
+            .iter()
+            .chain(optional.iter().flatten())
+            .any(|c| c.handle == handle)
+        {
+            Semantics::Event
+        } else {
+            Semantics::State
+        }
+    }
+
+    /// Whether writes to `handle` are only accepted in maintenance mode
+    fn sensitive(&self, handle: Characteristic) -> bool {
+        #[cfg(feature = "dfu")]
+        if handle == self.dfu_control || handle == self.dfu_data {
+            return true;
+        }
+        handle == self.control
+            || handle == self.hash_request
+            || handle == self.calibration
+            || handle == self.pin_map
+    }
+
+    /// Whether writes to `handle` change the configuration: they need an
+    /// admin role, and an unlocked connection while the admin lock is on
+    fn configures(&self, handle: Characteristic) -> bool {
+        self.sensitive(handle)
+            || handle == self.summary_window
+            || handle == self.report_delta
+            || handle == self.alarm_limits
+            || handle == self.peers
+            || Some(handle) == self.object_name
+    }
+
+    /// What a write to `handle` takes, see [`roles::PERMISSIONS`]. The
+    /// object control points check what they change themselves, see
+    /// [`may_modify`].
+    fn access(&self, handle: Characteristic) -> roles::Access {
+        let views = [
+            self.audit_index,
+            self.locale,
+            self.strings_index,
+            self.echo,
+            self.crash_index,
+            self.boost,
+            self.fault_index,
+            self.stats_index,
+            self.rollup_index,
+            self.trace_index,
+        ];
+        if self.configures(handle) {
+            roles::Access::Configure
+        } else if views.contains(&handle) || [self.oacp, self.olcp].contains(&Some(handle)) {
+            roles::Access::View
+        } else {
+            roles::Access::Operate
+        }
+    }
 }
 
-const fn gen_uuid(s: &str) -> Uuid {
+/// The UUID of a custom service or characteristic called `s`. NULs are
+/// refused, so two names never make the same UUID, and so are names that
+/// land in the SIG's range.
+pub(crate) const fn gen_uuid(s: &str) -> Uuid {
     let bytes = s.as_bytes();
-    assert!(bytes.len() <= 16);
+    assert!(!bytes.is_empty() && bytes.len() <= 16);
     let mut result = [0u8; 16];
 
     let mut idx = 0;
     while idx != s.len() {
+        assert!(bytes[idx] != 0, "NUL in a UUID name");
         result[result.len() - 1 - idx] = bytes[idx];
 
         idx += 1;
     }
 
+    assert!(!gattcheck::is_sig(&result), "UUID name in the SIG's range");
     Uuid::new_long(result)
 }
 
-pub async fn run<C: Controller, M: RawMutex, const N: usize>(
+pub(crate) const fn gen_uuids<const N: usize>(names: [&str; N]) -> [Uuid; N] {
+    let mut uuids = [Uuid::Uuid16([0; 2]); N];
+    let mut idx = 0;
+    while idx < N {
+        uuids[idx] = gen_uuid(names[idx]);
+        idx += 1;
+    }
+    uuids
+}
+
+// our own services
+const STRINGS_UUID: Uuid = gen_uuid("string table");
+#[cfg(feature = "dfu")]
+const DFU_UUID: Uuid = gen_uuid("firmware update");
+const MANSION_UUID: Uuid = gen_uuid("michaels mansion");
+
+const _: () = gattcheck::assert_distinct(&[&[
+    STRINGS_UUID,
+    #[cfg(feature = "dfu")]
+    DFU_UUID,
+    MANSION_UUID,
+]]);
+
+/// Longest value one notification carries
+const NOTIFY_MAX: usize = L2CAP_MTU - 4 - 3;
+
+/// Size of the values `value` makes
+const fn len_of<const N: usize>(_value: fn() -> [u8; N]) -> usize {
+    N
+}
+
+// every value we serve
+const _: () = gattcheck::assert_fit(
+    &[
+        audit::CHARACTERISTIC_SIZE,
+        paging::INDEX_SIZE,
+        paging::PAGE_SIZE,
+        peers::REQUEST_MAX,
+        integrity::RESULT_SIZE,
+        clock::STATUS_SIZE,
+        SUMMARY_SIZE,
+        threshold::REQUEST_SIZE,
+        alarm::LIMITS_SIZE,
+        calibration::COMMAND_SIZE,
+        len_of(calibration::status),
+        len_of(monitor::report),
+        len_of(watchdog::report),
+        pinmap::ENCODED_SIZE,
+        blob::CHARACTERISTIC_SIZE,
+        #[cfg(debug_assertions)]
+        overrides::CHARACTERISTIC_SIZE,
+        #[cfg(debug_assertions)]
+        impair::CHARACTERISTIC_SIZE,
+        matter::MAX_SEGMENT,
+        assets::NAME_MAX,
+        ots::SIZE_SIZE,
+        ots::ID_SIZE,
+        ots::PROPERTIES_SIZE,
+        #[cfg(feature = "dfu")]
+        dfu::DATA_SIZE,
+        #[cfg(feature = "dfu")]
+        len_of(radiofw::info),
+    ],
+    gattcheck::VALUE_MAX,
+);
+
+// and the ones we notify, responses included
+const _: () = gattcheck::assert_fit(
+    &[
+        matter::MAX_SEGMENT,
+        ots::OACP_MAX,
+        ots::OLCP_MAX,
+        ots::RESPONSE_MAX,
+        #[cfg(feature = "dfu")]
+        dfu::CONTROL_SIZE,
+        #[cfg(feature = "dfu")]
+        len_of(dfu::status),
+        SUMMARY_SIZE,
+        len_of(alarm::encode),
+        echo::RESPONSE_SIZE,
+        len_of(fault::encode),
+        len_of(power::status),
+        peers::RESPONSE_SIZE,
+        telemetry::PAYLOAD_MAX,
+    ],
+    NOTIFY_MAX,
+);
+
+/// Run the BLE stack until a task fails for good, returning why
+///
+/// There's no running this off the board: the crate only builds for the
+/// RP2040, and a stand-in controller would have to answer every HCI
+/// command the host and [`conninfo::InfoController`] send, which bt-hci
+/// has a trait apiece for. What can be checked without the radio is kept
+/// in the modules as plain functions from bytes to values and back, like
+/// the parsers and encoders the GATT task calls.
+pub async fn run<
+    C: conninfo::InfoController + hcilink::HealthController,
+    M: RawMutex,
+    const N: usize,
+>(
     controller: C,
     sender: Sender<'_, M, Message, N>,
-) {
-    let address = Address::random([0xff, 0x9f, 0x1a, 0x05, 0xe4, 0xff]);
+    config: &params::Config,
+    policy: &impl accept::Policy,
+    updates: &mut handoff::UpdateConsumer,
+) -> Exit<C::Error> {
+    let own = config.address.resolve().await;
+    let address = Address::random(own);
     info!("Our address = {:?}", address);
 
+    let controller = hcilink::Guard::new(disconnects::Tap::new(controller));
     let mut resources = Resources::new(PacketQos::None);
-    let (stack, peripheral, _, runner) = trouble_host::new(controller, &mut resources)
+    let (stack, peripheral, central, runner) = trouble_host::new(controller, &mut resources)
         .set_random_address(address)
         .build();
 
     let mut table: AttributeTable<'_, NoopRawMutex, MAX_ATTRIBUTES> = AttributeTable::new();
 
+    let mut stored_name = [0; settings::VALUE_MAX];
+    let name = match settings::get(settings::Key::NAME, &mut stored_name).await {
+        Ok(Some(len)) => core::str::from_utf8(&stored_name[..len]).unwrap_or(config.name),
+        Ok(None) => config.name,
+        Err(e) => {
+            error!("[gatt] reading the stored name failed: {:?}", e);
+            config.name
+        }
+    };
+
     // Generic Access Service (mandatory)
-    let id = b"mansion lighting";
-    let appearance = [0x80, 0x07];
+    let appearance = config.appearance.to_le_bytes();
     let mut svc = table.add_service(Service::new(0x1800));
-    let _ = svc.add_characteristic_ro(0x2a00, id);
+    let _ = svc.add_characteristic_ro(0x2a00, name.as_bytes());
     let _ = svc.add_characteristic_ro(0x2a01, &appearance[..]);
     svc.build();
 
     // Generic attribute service (mandatory)
     table.add_service(Service::new(0x1801));
 
+    // Device Information Service
+    let serial = system::DeviceId::get().hex();
+    let mut svc = table.add_service(Service::new(0x180a));
+    let _ = svc.add_characteristic_ro(0x2a29, MANUFACTURER.as_bytes());
+    let _ = svc.add_characteristic_ro(0x2a24, env!("CARGO_PKG_NAME").as_bytes());
+    let _ = svc.add_characteristic_ro(0x2a25, &serial[..]);
+    let _ = svc.add_characteristic_ro(0x2a26, FIRMWARE_REVISION.as_bytes());
+    let _ = svc.add_characteristic_ro(0x2a27, boardrev::current().name.as_bytes());
+    svc.build();
+
     // mansion lighting
     // we're avoiding the host_macro stuff because those use static_cell
     // which panic if they're used more than once
-    let mut base_color = [0u8; 3];
-    let mut brightness = [0u8];
-    let mut skip = [0u8];
+    let mut control_values = [[0u8; controls::MAX_LEN]; controls::CONTROLS.len()];
+    restore_controls(&mut control_values, &sender).await;
+    let mut control = [0u8; 2];
+    let mut audit = [0u8; audit::CHARACTERISTIC_SIZE];
+    let mut audit_index = [0u8; paging::INDEX_SIZE];
+    let mut audit_page = [0u8; paging::PAGE_SIZE];
+    let mut crash_index = [0u8; paging::INDEX_SIZE];
+    let mut crash_page = [0u8; paging::PAGE_SIZE];
+    let mut last_fault = fault::encode();
+    let mut power = power::status();
+    let mut boost = [0u8; 1];
+    let mut fault_index = [0u8; paging::INDEX_SIZE];
+    let mut fault_page = [0u8; paging::PAGE_SIZE];
+    let mut stats_index = [0u8; paging::INDEX_SIZE];
+    let mut stats_page = [0u8; paging::PAGE_SIZE];
+    let mut rollup_index = [0u8; paging::INDEX_SIZE];
+    let mut rollup_page = [0u8; paging::PAGE_SIZE];
+    let mut trace_index = [0u8; paging::INDEX_SIZE];
+    let mut trace_page = [0u8; paging::PAGE_SIZE];
+    let mut peers = [0u8; peers::REQUEST_MAX];
+    let mut telemetry = [0u8; telemetry::PAYLOAD_MAX];
+    let mut hash_request = [0u8; 2];
+    let mut hash_result = [0u8; integrity::RESULT_SIZE];
+    let mut clock = [0u8; clock::STATUS_SIZE];
+    let mut adc_summary = [0u8; SUMMARY_SIZE];
+    let mut summary_window = [0u8; 2];
+    let mut report_delta = [0u8; threshold::REQUEST_SIZE];
+    let mut alarm = alarm::encode();
+    let mut alarm_limits = [0u8; alarm::LIMITS_SIZE];
+    let mut calibration = [0u8; calibration::COMMAND_SIZE];
+    let mut calibration_status = calibration::status();
+    let mut tasks = monitor::report();
+    let mut recovery = watchdog::report();
+    let mut pin_map = [0u8; pinmap::ENCODED_SIZE];
+    let mut echo = [0u8; echo::RESPONSE_SIZE];
+    let mut blob = [0u8; blob::CHARACTERISTIC_SIZE];
+    #[cfg(debug_assertions)]
+    let mut overrides = [0u8; overrides::CHARACTERISTIC_SIZE];
+    #[cfg(debug_assertions)]
+    let mut impairment = [0u8; impair::CHARACTERISTIC_SIZE];
+
+    // Matter BTP, for commissioning
+    let mut btp_c1_value = [0u8; matter::MAX_SEGMENT];
+    let mut btp_c2_value = [0u8; matter::MAX_SEGMENT];
+    let (btp_c1, btp_c2) = {
+        let mut svc = table.add_service(Service::new(matter::SERVICE_UUID));
+        let c1 = svc
+            .add_characteristic(
+                Uuid::new_long(matter::C1_UUID),
+                &[CharacteristicProp::Write],
+                &mut btp_c1_value,
+            )
+            .build();
+        let c2 = svc
+            .add_characteristic(
+                Uuid::new_long(matter::C2_UUID),
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut btp_c2_value,
+            )
+            .build();
+        svc.build();
+        (c1, c2)
+    };
+
+    // strings for the companion app, in the locale it asks for
+    let mut locale_value = [0u8; 5];
+    let mut strings_index_value = [0u8; paging::INDEX_SIZE];
+    let mut strings_page_value = [0u8; paging::PAGE_SIZE];
+    let (locale, strings_index, strings_page) = {
+        const LOCALE_UUID: Uuid = gen_uuid("locale");
+        const STRINGS_INDEX_UUID: Uuid = gen_uuid("strings index");
+        const STRINGS_PAGE_UUID: Uuid = gen_uuid("strings page");
+        const _: () =
+            gattcheck::assert_distinct(&[&[LOCALE_UUID, STRINGS_INDEX_UUID, STRINGS_PAGE_UUID]]);
+
+        let mut svc = table.add_service(Service::new(STRINGS_UUID));
+        let locale = svc
+            .add_characteristic(LOCALE_UUID, &[CharacteristicProp::Write], &mut locale_value)
+            .build();
+        let index = svc
+            .add_characteristic(
+                STRINGS_INDEX_UUID,
+                &[CharacteristicProp::Write],
+                &mut strings_index_value,
+            )
+            .build();
+        let page = svc
+            .add_characteristic(
+                STRINGS_PAGE_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut strings_page_value,
+            )
+            .build();
+        svc.build();
+        (locale, index, page)
+    };
+
+    // the services modules add themselves, see crate::modules. What's
+    // still built here needs more than a writes::Spec: values that differ
+    // per connection (string pages, BTP, objects), or a GATT task that
+    // waits on each write (firmware chunks)
+    let mut writes = writes::Registry::new();
+    let mut module_values = [0u8; modules::VALUES_MAX];
+    let mut values = &mut module_values[..];
+    for gatt in modules::services(&config.services) {
+        let builder = table.add_service(Service::new(gatt.uuid));
+        let mut svc = modules::Service::new(builder, &mut values, &mut writes, &config.services);
+        (gatt.build)(&mut svc);
+        svc.build();
+    }
+
+    // the asset files as objects, see ots.rs
+    let mut object_name_value = [0u8; assets::NAME_MAX];
+    let mut object_size_value = [0u8; ots::SIZE_SIZE];
+    let mut object_id_value = [0u8; ots::ID_SIZE];
+    let mut object_properties_value = [0u8; ots::PROPERTIES_SIZE];
+    let mut oacp_value = [0u8; ots::OACP_MAX];
+    let mut olcp_value = [0u8; ots::OLCP_MAX];
+    let (object_name, object_size, object_id, object_properties, oacp, olcp) =
+        if config.services.objects {
+            let mut svc = table.add_service(Service::new(ots::SERVICE_UUID));
+            let _ = svc.add_characteristic_ro(ots::FEATURE_UUID, &ots::FEATURES[..]);
+            let name = svc
+                .add_characteristic(
+                    ots::NAME_UUID,
+                    &[CharacteristicProp::Read, CharacteristicProp::Write],
+                    &mut object_name_value,
+                )
+                .build();
+            let _ = svc.add_characteristic_ro(ots::TYPE_UUID, &ots::TYPE[..]);
+            let size = svc
+                .add_characteristic(
+                    ots::SIZE_UUID,
+                    &[CharacteristicProp::Read],
+                    &mut object_size_value,
+                )
+                .build();
+            let id = svc
+                .add_characteristic(
+                    ots::ID_UUID,
+                    &[CharacteristicProp::Read],
+                    &mut object_id_value,
+                )
+                .build();
+            let properties = svc
+                .add_characteristic(
+                    ots::PROPERTIES_UUID,
+                    &[CharacteristicProp::Read],
+                    &mut object_properties_value,
+                )
+                .build();
+            let oacp = svc
+                .add_characteristic(
+                    ots::OACP_UUID,
+                    &[CharacteristicProp::Write, CharacteristicProp::Notify],
+                    &mut oacp_value,
+                )
+                .build();
+            let olcp = svc
+                .add_characteristic(
+                    ots::OLCP_UUID,
+                    &[CharacteristicProp::Write, CharacteristicProp::Notify],
+                    &mut olcp_value,
+                )
+                .build();
+            svc.build();
+            (
+                Some(name),
+                Some(size),
+                Some(id),
+                Some(properties),
+                Some(oacp),
+                Some(olcp),
+            )
+        } else {
+            (None, None, None, None, None, None)
+        };
+
+    // firmware updates
+    #[cfg(feature = "dfu")]
+    let mut dfu_control_value = [0u8; dfu::CONTROL_SIZE];
+    #[cfg(feature = "dfu")]
+    let mut dfu_data_value = [0u8; dfu::DATA_SIZE];
+    #[cfg(feature = "dfu")]
+    let mut dfu_status_value = dfu::status();
+    #[cfg(feature = "dfu")]
+    let mut radio_firmware_value = radiofw::info();
+    #[cfg(feature = "dfu")]
+    let (dfu_control, dfu_data, dfu_status, radio_firmware) = {
+        const CONTROL_POINT_UUID: Uuid = gen_uuid("dfu control");
+        const DATA_UUID: Uuid = gen_uuid("dfu data");
+        const STATUS_UUID: Uuid = gen_uuid("dfu status");
+        const RADIO_FIRMWARE_UUID: Uuid = gen_uuid("radio firmware");
+        const _: () = gattcheck::assert_distinct(&[&[
+            CONTROL_POINT_UUID,
+            DATA_UUID,
+            STATUS_UUID,
+            RADIO_FIRMWARE_UUID,
+        ]]);
+
+        let mut svc = table.add_service(Service::new(DFU_UUID));
+        let control = svc
+            .add_characteristic(
+                CONTROL_POINT_UUID,
+                &[CharacteristicProp::Write, CharacteristicProp::Notify],
+                &mut dfu_control_value,
+            )
+            .build();
+        let data = svc
+            .add_characteristic(
+                DATA_UUID,
+                &[
+                    CharacteristicProp::Write,
+                    CharacteristicProp::WriteWithoutResponse,
+                ],
+                &mut dfu_data_value,
+            )
+            .build();
+        let status = svc
+            .add_characteristic(
+                STATUS_UUID,
+                &[CharacteristicProp::Read],
+                &mut dfu_status_value,
+            )
+            .build();
+        let radio_firmware = svc
+            .add_characteristic(
+                RADIO_FIRMWARE_UUID,
+                &[CharacteristicProp::Read],
+                &mut radio_firmware_value,
+            )
+            .build();
+        svc.build();
+        (control, data, status, radio_firmware)
+    };
 
     let handles = {
-        const SERVICE_UUID: Uuid = gen_uuid("michaels mansion");
-        const BASE_COLOR_UUID: Uuid = gen_uuid("base color");
-        const BRIGHTNESS_UUID: Uuid = gen_uuid("brightness");
-        const SKIP_UUID: Uuid = gen_uuid("skip");
+        const CONTROL_UUID: Uuid = gen_uuid("control");
+        const AUDIT_UUID: Uuid = gen_uuid("audit");
+        const AUDIT_INDEX_UUID: Uuid = gen_uuid("audit page index");
+        const AUDIT_PAGE_UUID: Uuid = gen_uuid("audit page");
+        const HASH_REQUEST_UUID: Uuid = gen_uuid("hash request");
+        const HASH_RESULT_UUID: Uuid = gen_uuid("hash result");
+        const CLOCK_UUID: Uuid = gen_uuid("clock");
+        const ADC_SUMMARY_UUID: Uuid = gen_uuid("adc summary");
+        const SUMMARY_WINDOW_UUID: Uuid = gen_uuid("summary window");
+        const REPORT_DELTA_UUID: Uuid = gen_uuid("report delta");
+        const ALARM_UUID: Uuid = gen_uuid("alarm");
+        const ALARM_LIMITS_UUID: Uuid = gen_uuid("alarm limits");
+        const CALIBRATION_UUID: Uuid = gen_uuid("calibration");
+        const CALIBRATION_STATUS_UUID: Uuid = gen_uuid("calib state");
+        const TASKS_UUID: Uuid = gen_uuid("tasks");
+        const RECOVERY_UUID: Uuid = gen_uuid("recovery");
+        const PIN_MAP_UUID: Uuid = gen_uuid("pin map");
+        const ECHO_UUID: Uuid = gen_uuid("echo");
+        const BLOB_UUID: Uuid = gen_uuid("blob");
+        const CRASH_INDEX_UUID: Uuid = gen_uuid("crash page index");
+        const CRASH_PAGE_UUID: Uuid = gen_uuid("crash page");
+        const LAST_FAULT_UUID: Uuid = gen_uuid("last fault");
+        const POWER_UUID: Uuid = gen_uuid("power");
+        const BOOST_UUID: Uuid = gen_uuid("boost");
+        const FAULT_INDEX_UUID: Uuid = gen_uuid("fault page index");
+        const FAULT_PAGE_UUID: Uuid = gen_uuid("fault page");
+        const STATS_INDEX_UUID: Uuid = gen_uuid("stats page index");
+        const STATS_PAGE_UUID: Uuid = gen_uuid("stats page");
+        const ROLLUP_INDEX_UUID: Uuid = gen_uuid("daily page index");
+        const ROLLUP_PAGE_UUID: Uuid = gen_uuid("daily page");
+        const TRACE_INDEX_UUID: Uuid = gen_uuid("trace page index");
+        const TRACE_PAGE_UUID: Uuid = gen_uuid("trace page");
+        const PEERS_UUID: Uuid = gen_uuid("peers");
+        const TELEMETRY_UUID: Uuid = gen_uuid("telemetry");
+        #[cfg(debug_assertions)]
+        const OVERRIDE_UUID: Uuid = gen_uuid("override");
+        #[cfg(debug_assertions)]
+        const IMPAIRMENT_UUID: Uuid = gen_uuid("impairment");
+        const CONTROL_UUIDS: [Uuid; controls::CONTROLS.len()] = {
+            let mut uuids = [Uuid::Uuid16([0; 2]); controls::CONTROLS.len()];
+            let mut idx = 0;
+            while idx < uuids.len() {
+                uuids[idx] = gen_uuid(controls::CONTROLS[idx].name);
+                idx += 1;
+            }
+            uuids
+        };
+        const _: () = gattcheck::assert_distinct(&[
+            &CONTROL_UUIDS,
+            &[
+                CONTROL_UUID,
+                AUDIT_UUID,
+                AUDIT_INDEX_UUID,
+                AUDIT_PAGE_UUID,
+                HASH_REQUEST_UUID,
+                HASH_RESULT_UUID,
+                CLOCK_UUID,
+                ADC_SUMMARY_UUID,
+                SUMMARY_WINDOW_UUID,
+                REPORT_DELTA_UUID,
+                ALARM_UUID,
+                ALARM_LIMITS_UUID,
+                CALIBRATION_UUID,
+                CALIBRATION_STATUS_UUID,
+                TASKS_UUID,
+                RECOVERY_UUID,
+                PIN_MAP_UUID,
+                ECHO_UUID,
+                BLOB_UUID,
+                CRASH_INDEX_UUID,
+                CRASH_PAGE_UUID,
+                LAST_FAULT_UUID,
+                POWER_UUID,
+                BOOST_UUID,
+                FAULT_INDEX_UUID,
+                FAULT_PAGE_UUID,
+                STATS_INDEX_UUID,
+                STATS_PAGE_UUID,
+                ROLLUP_INDEX_UUID,
+                ROLLUP_PAGE_UUID,
+                TRACE_INDEX_UUID,
+                TRACE_PAGE_UUID,
+                PEERS_UUID,
+                TELEMETRY_UUID,
+                #[cfg(debug_assertions)]
+                OVERRIDE_UUID,
+                #[cfg(debug_assertions)]
+                IMPAIRMENT_UUID,
+            ],
+        ]);
+
+        let mut service = table.add_service(Service::new(MANSION_UUID));
+
+        let mut values = control_values.iter_mut();
+        let mut uuids = CONTROL_UUIDS.into_iter();
+        let controls = controls::CONTROLS.map(|control| {
+            let value = values.next().unwrap();
+            service
+                .add_characteristic(
+                    uuids.next().unwrap(),
+                    &[CharacteristicProp::Write],
+                    &mut value[..control.len],
+                )
+                .build()
+        });
 
-        let mut service = table.add_service(Service::new(SERVICE_UUID));
+        let control = service
+            .add_characteristic(CONTROL_UUID, &[CharacteristicProp::Write], &mut control)
+            .build();
+
+        let audit = service
+            .add_characteristic(AUDIT_UUID, &[CharacteristicProp::Read], &mut audit)
+            .build();
+
+        let audit_index = service
+            .add_characteristic(
+                AUDIT_INDEX_UUID,
+                &[CharacteristicProp::Write],
+                &mut audit_index,
+            )
+            .build();
+
+        let audit_page = service
+            .add_characteristic(
+                AUDIT_PAGE_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut audit_page,
+            )
+            .build();
+
+        let hash_request = service
+            .add_characteristic(
+                HASH_REQUEST_UUID,
+                &[CharacteristicProp::Write],
+                &mut hash_request,
+            )
+            .build();
+
+        let hash_result = service
+            .add_characteristic(
+                HASH_RESULT_UUID,
+                &[CharacteristicProp::Read],
+                &mut hash_result,
+            )
+            .build();
+
+        let clock = service
+            .add_characteristic(CLOCK_UUID, &[CharacteristicProp::Read], &mut clock)
+            .build();
+
+        let adc_summary = service
+            .add_characteristic(
+                ADC_SUMMARY_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut adc_summary,
+            )
+            .build();
+
+        let summary_window = service
+            .add_characteristic(
+                SUMMARY_WINDOW_UUID,
+                &[CharacteristicProp::Write],
+                &mut summary_window,
+            )
+            .build();
+
+        let report_delta = service
+            .add_characteristic(
+                REPORT_DELTA_UUID,
+                &[CharacteristicProp::Write],
+                &mut report_delta,
+            )
+            .build();
+
+        let alarm = service
+            .add_characteristic(
+                ALARM_UUID,
+                &[
+                    CharacteristicProp::Read,
+                    CharacteristicProp::Write,
+                    CharacteristicProp::Notify,
+                ],
+                &mut alarm,
+            )
+            .build();
+
+        let alarm_limits = service
+            .add_characteristic(
+                ALARM_LIMITS_UUID,
+                &[CharacteristicProp::Write],
+                &mut alarm_limits,
+            )
+            .build();
+
+        let calibration = service
+            .add_characteristic(
+                CALIBRATION_UUID,
+                &[CharacteristicProp::Write],
+                &mut calibration,
+            )
+            .build();
+
+        let calibration_status = service
+            .add_characteristic(
+                CALIBRATION_STATUS_UUID,
+                &[CharacteristicProp::Read],
+                &mut calibration_status,
+            )
+            .build();
+
+        let tasks = service
+            .add_characteristic(TASKS_UUID, &[CharacteristicProp::Read], &mut tasks)
+            .build();
 
-        let base_color = service
+        let recovery = service
+            .add_characteristic(RECOVERY_UUID, &[CharacteristicProp::Read], &mut recovery)
+            .build();
+
+        let pin_map = service
+            .add_characteristic(PIN_MAP_UUID, &[CharacteristicProp::Write], &mut pin_map)
+            .build();
+
+        let echo = service
+            .add_characteristic(
+                ECHO_UUID,
+                &[CharacteristicProp::Write, CharacteristicProp::Notify],
+                &mut echo,
+            )
+            .build();
+
+        // long reads and writes, see blob.rs
+        let blob = service
             .add_characteristic(
-                BASE_COLOR_UUID,
+                BLOB_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Write],
+                &mut blob,
+            )
+            .build();
+
+        let crash_index = service
+            .add_characteristic(
+                CRASH_INDEX_UUID,
                 &[CharacteristicProp::Write],
-                &mut base_color,
+                &mut crash_index,
             )
             .build();
 
-        let brightness = service
+        let crash_page = service
             .add_characteristic(
-                BRIGHTNESS_UUID,
+                CRASH_PAGE_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut crash_page,
+            )
+            .build();
+
+        let last_fault = service
+            .add_characteristic(
+                LAST_FAULT_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut last_fault,
+            )
+            .build();
+
+        let power = service
+            .add_characteristic(
+                POWER_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut power,
+            )
+            .build();
+
+        let boost = service
+            .add_characteristic(
+                BOOST_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Write],
+                &mut boost,
+            )
+            .build();
+
+        let fault_index = service
+            .add_characteristic(
+                FAULT_INDEX_UUID,
                 &[CharacteristicProp::Write],
-                &mut brightness,
+                &mut fault_index,
             )
             .build();
 
-        let skip = service
-            .add_characteristic(SKIP_UUID, &[CharacteristicProp::Write], &mut skip)
+        let fault_page = service
+            .add_characteristic(
+                FAULT_PAGE_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut fault_page,
+            )
+            .build();
+
+        let stats_index = service
+            .add_characteristic(
+                STATS_INDEX_UUID,
+                &[CharacteristicProp::Write],
+                &mut stats_index,
+            )
+            .build();
+
+        let stats_page = service
+            .add_characteristic(
+                STATS_PAGE_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut stats_page,
+            )
+            .build();
+
+        let rollup_index = service
+            .add_characteristic(
+                ROLLUP_INDEX_UUID,
+                &[CharacteristicProp::Write],
+                &mut rollup_index,
+            )
+            .build();
+
+        let rollup_page = service
+            .add_characteristic(
+                ROLLUP_PAGE_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut rollup_page,
+            )
+            .build();
+
+        let trace_index = service
+            .add_characteristic(
+                TRACE_INDEX_UUID,
+                &[CharacteristicProp::Write],
+                &mut trace_index,
+            )
+            .build();
+
+        let trace_page = service
+            .add_characteristic(
+                TRACE_PAGE_UUID,
+                &[CharacteristicProp::Read, CharacteristicProp::Notify],
+                &mut trace_page,
+            )
+            .build();
+
+        let peers = service
+            .add_characteristic(
+                PEERS_UUID,
+                &[CharacteristicProp::Write, CharacteristicProp::Notify],
+                &mut peers,
+            )
+            .build();
+
+        let telemetry = service
+            .add_characteristic(
+                TELEMETRY_UUID,
+                &[CharacteristicProp::Notify],
+                &mut telemetry,
+            )
+            .build();
+
+        #[cfg(debug_assertions)]
+        let overrides = service
+            .add_characteristic(OVERRIDE_UUID, &[CharacteristicProp::Write], &mut overrides)
+            .build();
+
+        #[cfg(debug_assertions)]
+        let impairment = service
+            .add_characteristic(
+                IMPAIRMENT_UUID,
+                &[CharacteristicProp::Write],
+                &mut impairment,
+            )
             .build();
 
         service.build();
 
         Handles {
-            base_color,
-            brightness,
-            skip,
+            controls,
+            control,
+            audit,
+            audit_index,
+            audit_page,
+            hash_request,
+            hash_result,
+            clock,
+            btp_c1,
+            btp_c2,
+            adc_summary,
+            summary_window,
+            report_delta,
+            alarm,
+            alarm_limits,
+            locale,
+            strings_index,
+            strings_page,
+            calibration,
+            calibration_status,
+            tasks,
+            recovery,
+            pin_map,
+            echo,
+            blob,
+            crash_index,
+            crash_page,
+            last_fault,
+            power,
+            boost,
+            fault_index,
+            fault_page,
+            stats_index,
+            stats_page,
+            rollup_index,
+            rollup_page,
+            trace_index,
+            trace_page,
+            peers,
+            telemetry,
+            object_name,
+            object_size,
+            object_id,
+            object_properties,
+            oacp,
+            olcp,
+            #[cfg(debug_assertions)]
+            overrides,
+            #[cfg(debug_assertions)]
+            impairment,
+            #[cfg(feature = "dfu")]
+            dfu_control,
+            #[cfg(feature = "dfu")]
+            dfu_data,
+            #[cfg(feature = "dfu")]
+            dfu_status,
+            #[cfg(feature = "dfu")]
+            radio_firmware,
         }
     };
 
+    gattcheck::check(&table);
+    explorer::index(&table);
+
+    writes.on_write(handles.alarm_limits, write_alarm_limits);
+    writes.register(&table, config.write_handlers);
+    writes.restore().await;
+
+    let targets = writes.targets();
     let server = Server::new(stack, &mut table);
+    for (handle, value) in writes.restored() {
+        match handles
+            .all()
+            .chain(writes.served())
+            .find(|c| c.handle == handle)
+        {
+            Some(characteristic) => set_value(&server, characteristic, value),
+            None => error!("[gatt] no characteristic for stored value of {}", handle),
+        }
+    }
 
     info!("Starting advertising and GATT service");
-    let _ = select3(
+    // a stall that ended the last run mustn't end this one
+    monitor::GATT.pause();
+    // whichever ends first ends the others, see crate::supervisor
+    let exit = match select3(
         ble_task(runner),
-        gatt_task(&server, sender, handles),
-        advertise_task(peripheral),
+        select4(
+            gatt_task(&server, sender, handles, writes),
+            advertise_supervised(
+                stack, peripheral, config, own, name, policy, &server, handles, &targets, updates,
+            ),
+            // a stuck handler can only be unstuck by starting over
+            monitor::GATT.stalled(),
+            central::run(stack, central),
+        ),
+        hcilink::watch(stack),
     )
-    .await;
+    .await
+    {
+        Either3::First(e) => Exit::Runner(e),
+        Either3::Second(Either4::First(never)) => never,
+        Either3::Second(Either4::Second(e)) => Exit::Advertising(e),
+        Either3::Second(Either4::Third(())) => Exit::Stalled(monitor::GATT.name),
+        Either3::Second(Either4::Fourth(never)) => never,
+        Either3::Third(loss) => Exit::Controller(loss),
+    };
+
+    // the serving slots were dropped mid-connection, so wind down what
+    // they would have on the disconnect
+    for conn in session::close_all().into_iter().flatten() {
+        events::publish(Event::Disconnected(conn, disconnects::Reason::Unknown));
+    }
+    exit
 }
 
-async fn ble_task<C: Controller>(mut runner: Runner<'_, C>) {
-    if let Err(e) = runner.run().await {
-        error!("ble_task ERROR: {e:?}");
+/// The runner can't be restarted on its own, it's tied to the host
+/// resources, so any error ends `run()`.
+async fn ble_task<C: Controller>(mut runner: Runner<'_, C>) -> BleHostError<C::Error> {
+    loop {
+        // the central scans through the observer's reports
+        if let Err(e) = runner.run_with_handler(&observer::Handler).await {
+            error!("[blue] runner failed: {:?}", fmt::Dbg(&e));
+            return e;
+        }
+    }
+}
+
+/// Advertise and serve connections. Connections keep being served while
+/// advertising restarts after a failure.
+async fn advertise_supervised<C: conninfo::InfoController>(
+    stack: Stack<'_, C>,
+    mut peripheral: Peripheral<'_, C>,
+    config: &params::Config,
+    own: [u8; 6],
+    name: &str,
+    policy: &impl accept::Policy,
+    server: &Server<'_, '_, C>,
+    handles: Handles,
+    targets: &writes::Targets,
+    updates: &mut handoff::UpdateConsumer,
+) -> BleHostError<C::Error> {
+    let links = Links::with_limit(config.connections_max);
+    let mut child = supervisor::Child::new("advertising", 5);
+    let advertising = async {
+        loop {
+            let Err(e) =
+                advertise_task(stack, &mut peripheral, config, own, name, policy, &links).await;
+            if !child.failed(&e).await {
+                return e;
+            }
+        }
+    };
+
+    let serving = serve(
+        stack,
+        server,
+        handles,
+        targets,
+        &links,
+        config.latency,
+        updates,
+    );
+    match select(advertising, serving).await {
+        Either::First(e) => e,
+        Either::Second(never) => never,
     }
 }
 
@@ -139,93 +1395,1229 @@ async fn gatt_task<C: Controller, M: RawMutex, const N: usize>(
     server: &Server<'_, '_, C>,
     sender: Sender<'_, M, Message, N>,
     handles: Handles,
-) {
+    mut writes: writes::Registry,
+) -> ! {
     loop {
-        match server.next().await {
-            Ok(GattEvent::Write {
-                handle,
-                connection: _,
-            }) => {
+        monitor::GATT.pause();
+        let event = server.next().await;
+        monitor::GATT.ping();
+        match event {
+            Ok(GattEvent::Write { handle, connection }) => {
+                let received = Instant::now();
+                anchor::observe(connection.handle(), received);
                 info!("[gatt] pre write event on {:?}", handle);
+                events::publish(Event::Write(handle));
+                stats::record(&connection, handle.handle, stats::Op::Write);
+                let len = server.get(handle, |value| value.len()).unwrap_or(0);
+                gatttrace::record(
+                    connection.handle(),
+                    handle.handle,
+                    gatttrace::Op::Write,
+                    len,
+                    gatttrace::OK,
+                );
+                conninfo::note_mtu(&connection);
 
-                if handle == handles.base_color {
-                    info!("setting base color");
-                    server
-                        .get(handles.base_color, |value| {
-                            let color = Color::new(value[0], value[1], value[2]);
-                            sender.send(Message::SetColor(color))
+                let served = writes
+                    .served()
+                    .find(|c| c.cccd_handle == Some(handle.handle));
+                if let Some(characteristic) = handles.with_cccd(handle).or(served) {
+                    // notifications and indications are the two low bits
+                    let on = server
+                        .get(handle, |value| {
+                            value.first().is_some_and(|bits| bits & 0x03 != 0)
                         })
-                        .unwrap()
-                        .await;
-                } else if handle == handles.brightness {
-                    info!("setting brightness");
-                    server
-                        .get(handles.brightness, |value| {
-                            sender.send(Message::SetBrightness(value[0]))
+                        .unwrap_or(false);
+                    let conn = connection.handle();
+                    subscriptions::set(conn, characteristic.handle, on);
+                    events::publish(if on {
+                        Event::Subscribed(conn, characteristic)
+                    } else {
+                        Event::Unsubscribed(conn, characteristic)
+                    });
+                    continue;
+                }
+
+                #[cfg(debug_assertions)]
+                if handle == handles.overrides {
+                    override_value(server, &handles, &writes);
+                    continue;
+                }
+                #[cfg(debug_assertions)]
+                if handle == handles.impairment {
+                    match server.get(handle, impair::Impairment::parse).unwrap() {
+                        Some(impairment) => impair::set(impairment),
+                        None => error!("[impair] invalid impairment"),
+                    }
+                    continue;
+                }
+                // put the frozen value back over whatever the client wrote
+                #[cfg(debug_assertions)]
+                if overrides::with_frozen(handle.handle, |value| {
+                    let _ = server.set(handle, value);
+                })
+                .is_some()
+                {
+                    info!("[override] ignoring write to frozen {:?}", handle);
+                    continue;
+                }
+
+                // what blue.rs doesn't serve says what it takes itself
+                let (access, sensitive) = writes
+                    .policy(handle.handle)
+                    .unwrap_or_else(|| (handles.access(handle), handles.sensitive(handle)));
+                if sensitive && !mode::is_maintenance() {
+                    error!("[gatt] write to {:?} outside maintenance mode", handle);
+                    gatttrace::refused(
+                        connection.handle(),
+                        handle.handle,
+                        writes::AttError::InsufficientAuthorization.code(),
+                    );
+                    restore(server, &handles, &writes, connection.handle(), handle);
+                    continue;
+                }
+
+                let role = roles::of(connection.peer_address());
+                if !roles::permits(role, access) {
+                    error!("[gatt] write to {:?} refused to a {:?}", handle, role);
+                    gatttrace::refused(
+                        connection.handle(),
+                        handle.handle,
+                        writes::AttError::InsufficientAuthorization.code(),
+                    );
+                    restore(server, &handles, &writes, connection.handle(), handle);
+                    continue;
+                }
+
+                #[cfg(feature = "admin")]
+                if access == roles::Access::Configure && !admin::allows(connection.handle()) {
+                    error!("[gatt] write to {:?} needs the admin PIN", handle);
+                    gatttrace::refused(
+                        connection.handle(),
+                        handle.handle,
+                        writes::AttError::InsufficientAuthorization.code(),
+                    );
+                    restore(server, &handles, &writes, connection.handle(), handle);
+                    continue;
+                }
+
+                // only what was taken goes on the trail
+                if handles.audited(handle) || writes.audited(handle.handle) {
+                    let peer = connection.peer_address();
+                    let trail = server
+                        .get(handle, |value| {
+                            audit::record(audit::Entry::new(peer.raw(), handle.handle, value))
                         })
+                        .unwrap();
+                    set_value(server, handles.audit, &trail);
+                }
+
+                let conn = connection.handle();
+                let verdict = server
+                    .get(handle, |value| writes.dispatch(handle.handle, conn, value))
+                    .unwrap();
+                match verdict {
+                    Some(Ok(())) => {
+                        if let Some(key) = writes.persisted(handle.handle) {
+                            if let Some(value) = writes.last(handle.handle) {
+                                persist::store(key, value);
+                            }
+                        }
+                        // serve what was made of it
+                        if let Some(value) = writes.read(handle.handle) {
+                            set_value(server, handle, value.as_bytes());
+                        }
+                        continue;
+                    }
+                    Some(Err(e)) => {
+                        error!("[gatt] write to {:?} rejected: {:?}", handle, e);
+                        gatttrace::refused(conn, handle.handle, e.code());
+                        restore(server, &handles, &writes, conn, handle);
+                        continue;
+                    }
+                    None => {}
+                }
+
+                #[cfg(feature = "dfu")]
+                if handle == handles.dfu_control {
+                    match server.get(handle, dfu::Request::parse).unwrap() {
+                        Some(request) => {
+                            let response = dfu::execute(request).await;
+                            set_value(server, handles.dfu_status, &dfu::status());
+                            if let Err(e) = notify_subscribed(
+                                server,
+                                handles.dfu_control,
+                                &connection,
+                                &response,
+                            )
+                            .await
+                            {
+                                error!("[gatt] dfu response failed: {:?}", fmt::Dbg(&e));
+                            }
+                        }
+                        None => error!("[dfu] invalid request"),
+                    }
+                    continue;
+                }
+                #[cfg(feature = "dfu")]
+                if handle == handles.dfu_data {
+                    match server.get(handle, dfu::Chunk::parse).unwrap() {
+                        Some(chunk) => {
+                            if let Err(status) = dfu::receive(chunk).await {
+                                error!("[dfu] chunk refused: {:?}", status);
+                            }
+                        }
+                        None => error!("[dfu] invalid chunk"),
+                    }
+                    continue;
+                }
+
+                if handle == handles.blob {
+                    // parts of a long write don't check out until the last
+                    // one is in
+                    let _ = server.get(handle, blob::receive);
+                } else if handle == handles.echo {
+                    match server
+                        .get(handle, |value| echo::Ping::parse(value, received))
+                        .unwrap()
+                    {
+                        Some(ping) => {
+                            let (response, len) = ping.response();
+                            if let Err(e) = notify_subscribed(
+                                server,
+                                handles.echo,
+                                &connection,
+                                &response[..len],
+                            )
+                            .await
+                            {
+                                error!("[gatt] echo failed: {:?}", fmt::Dbg(&e));
+                            }
+                        }
+                        None => error!("[gatt] invalid echo token"),
+                    }
+                } else if let Some(idx) = handles.controls.iter().position(|c| *c == handle) {
+                    info!("setting {}", controls::CONTROLS[idx].name);
+                    match server
+                        .get(handle, |value| controls::apply(idx, value))
                         .unwrap()
-                        .await;
-                } else if handle == handles.skip {
-                    info!("setting skip");
-                    server
-                        .get(handles.skip, |value| {
-                            sender.send(Message::SetSkip(value[0]))
+                    {
+                        Some(message) => {
+                            sender.send(message).await;
+                            let control = &controls::CONTROLS[idx];
+                            let key = settings::Key::characteristic(&gen_uuid(control.name));
+                            persist::store(key, &controls::value(idx)[..control.len]);
+                        }
+                        None => error!("[gatt] malformed {} write", controls::CONTROLS[idx].name),
+                    }
+                } else if handle == handles.control {
+                    let command = server.get(handles.control, system::Command::parse).unwrap();
+                    match command {
+                        Some(command) => {
+                            info!("[gatt] control command {:?}", command);
+                            persist::flush().await;
+                            command.execute();
+                        }
+                        None => error!("[gatt] rejected control write"),
+                    }
+                } else if handle == handles.audit_index {
+                    let index = server
+                        .get(handle, |value| {
+                            AUDIT_PAGER.select(connection.handle(), value)
                         })
+                        .unwrap();
+                    if index.is_some() {
+                        let page = AUDIT_PAGER.current(connection.handle(), &audit::Dataset);
+                        send_page(server, handles.audit_page, &connection, &page).await;
+                    }
+                } else if handle == handles.crash_index {
+                    let index = server
+                        .get(handle, |value| {
+                            CRASH_PAGER.select(connection.handle(), value)
+                        })
+                        .unwrap();
+                    if index.is_some() {
+                        let page = CRASH_PAGER.current(connection.handle(), &crash::Dataset);
+                        send_page(server, handles.crash_page, &connection, &page).await;
+                    }
+                } else if handle == handles.fault_index {
+                    let index = server
+                        .get(handle, |value| {
+                            FAULT_PAGER.select(connection.handle(), value)
+                        })
+                        .unwrap();
+                    if index.is_some() {
+                        let page = FAULT_PAGER.current(connection.handle(), &fault::Dataset);
+                        send_page(server, handles.fault_page, &connection, &page).await;
+                    }
+                } else if handle == handles.stats_index {
+                    let index = server
+                        .get(handle, |value| {
+                            STATS_PAGER.select(connection.handle(), value)
+                        })
+                        .unwrap();
+                    if index == Some(0) {
+                        stats::snapshot();
+                    }
+                    if index.is_some() {
+                        let page = STATS_PAGER.current(connection.handle(), &stats::Dataset);
+                        send_page(server, handles.stats_page, &connection, &page).await;
+                    }
+                } else if handle == handles.rollup_index {
+                    let index = server
+                        .get(handle, |value| {
+                            ROLLUP_PAGER.select(connection.handle(), value)
+                        })
+                        .unwrap();
+                    if index.is_some() {
+                        let page = ROLLUP_PAGER.current(connection.handle(), &rollup::Dataset);
+                        send_page(server, handles.rollup_page, &connection, &page).await;
+                    }
+                } else if handle == handles.trace_index {
+                    let index = server
+                        .get(handle, |value| {
+                            TRACE_PAGER.select(connection.handle(), value)
+                        })
+                        .unwrap();
+                    if index == Some(0) {
+                        gatttrace::snapshot();
+                    }
+                    if index.is_some() {
+                        let page = TRACE_PAGER.current(connection.handle(), &gatttrace::Dataset);
+                        send_page(server, handles.trace_page, &connection, &page).await;
+                    }
+                } else if handle == handles.hash_request {
+                    info!("hashing region");
+                    match server.get(handle, integrity::parse_request).unwrap() {
+                        Some((algorithm, region)) => {
+                            let result = integrity::hash_request(algorithm, region).await;
+                            set_value(server, handles.hash_result, &result);
+                        }
+                        None => error!("[gatt] invalid hash request"),
+                    }
+                } else if handle == handles.btp_c1 {
+                    let conn = connection.handle();
+                    if BTP_OPEN.get(conn) {
+                        let packet = server.get(handle, matter::Packet::new).unwrap();
+                        if !packet.is_some_and(matter::deliver) {
+                            error!("[matter] dropped BTP packet");
+                        }
+                    } else {
+                        match server.get(handle, matter::Handshake::parse).unwrap() {
+                            Some(handshake) => {
+                                info!("[matter] BTP session open, {:?}", handshake);
+                                BTP_OPEN.set(conn, true);
+                                let response = handshake.response();
+                                if let Err(e) = notify_subscribed(
+                                    server,
+                                    handles.btp_c2,
+                                    &connection,
+                                    &response,
+                                )
+                                .await
+                                {
+                                    error!(
+                                        "[matter] handshake response failed: {:?}",
+                                        fmt::Dbg(&e)
+                                    );
+                                }
+                            }
+                            None => error!("[matter] invalid BTP handshake"),
+                        }
+                    }
+                } else if handle == handles.summary_window {
+                    match server.get(handle, aggregate::parse_window).unwrap() {
+                        Some(window) => {
+                            info!("[gatt] summary window {}ms", window.as_millis());
+                            aggregate::set_window(handles.adc_summary.handle, window);
+                        }
+                        None => error!("[gatt] invalid summary window"),
+                    }
+                } else if handle == handles.report_delta {
+                    match server.get(handle, threshold::Request::parse).unwrap() {
+                        Some(request)
+                            if handles
+                                .all()
+                                .chain(writes.served())
+                                .any(|c| c.handle == request.handle) =>
+                        {
+                            info!("[gatt] report delta {:?}", request);
+                            if !threshold::set(request.handle, request.delta) {
+                                error!("[gatt] no free report delta slot");
+                            }
+                        }
+                        _ => error!("[gatt] invalid report delta"),
+                    }
+                } else if handle == handles.alarm {
+                    match server.get(handle, alarm::parse_acknowledge).unwrap() {
+                        Some(signal) => {
+                            if !alarm::acknowledge(signal) {
+                                info!("[gatt] no alarm to acknowledge on signal {}", signal);
+                            }
+                        }
+                        None => error!("[gatt] invalid alarm acknowledge"),
+                    }
+                    set_value(server, handles.alarm, &alarm::encode());
+                } else if handle == handles.locale || handle == handles.strings_index {
+                    let conn = connection.handle();
+                    let selected = if handle == handles.locale {
+                        let locale = server.get(handle, strings::Locale::parse).unwrap();
+                        if let Some(locale) = locale {
+                            info!("[gatt] locale {:?}", locale);
+                            LOCALE.set(conn, locale);
+                        }
+                        locale.is_some()
+                    } else {
+                        server
+                            .get(handle, |value| STRINGS_PAGER.select(conn, value))
+                            .unwrap()
+                            .is_some()
+                    };
+                    if selected {
+                        let table = strings::Table(LOCALE.get(conn));
+                        let page = STRINGS_PAGER.current(conn, &table);
+                        send_page(server, handles.strings_page, &connection, &page).await;
+                    } else {
+                        error!("[gatt] invalid string table write");
+                    }
+                } else if handle == handles.calibration {
+                    match server.get(handle, calibration::Command::parse).unwrap() {
+                        Some(command) => {
+                            if let Err(e) = calibration::execute(command).await {
+                                error!("[gatt] calibration {:?} failed: {:?}", command, e);
+                            }
+                        }
+                        None => error!("[gatt] invalid calibration command"),
+                    }
+                    set_value(server, handles.calibration_status, &calibration::status());
+                } else if handle == handles.pin_map {
+                    let mut value = [0u8; pinmap::ENCODED_SIZE];
+                    let len = server
+                        .get(handle, |v| {
+                            let n = v.len().min(value.len());
+                            value[..n].copy_from_slice(&v[..n]);
+                            n
+                        })
+                        .unwrap();
+                    match pinmap::commit(&value[..len]).await {
+                        Ok(map) => info!("[gatt] pin map {:?} stored, applies after reboot", map),
+                        Err(e) => error!("[gatt] pin map rejected: {:?}", e),
+                    }
+                } else if handle == handles.boost {
+                    match server.get(handle, boost::parse).unwrap() {
+                        Some(window) => boost::request(connection.handle(), window),
+                        None => error!("[gatt] invalid boost request"),
+                    }
+                    set_value(server, handle, &boost::remaining(connection.handle()));
+                } else if handle == handles.peers {
+                    let mut request = [0u8; peers::REQUEST_MAX];
+                    let len = server
+                        .get(handle, |v| {
+                            let n = v.len().min(request.len());
+                            request[..n].copy_from_slice(&v[..n]);
+                            n
+                        })
+                        .unwrap();
+                    let response = peers::execute(&request[..len]);
+                    if let Err(e) = notify_subscribed(server, handle, &connection, &response).await
+                    {
+                        error!("[gatt] peers response failed: {:?}", fmt::Dbg(&e));
+                    }
+                } else if Some(handle) == handles.olcp {
+                    let conn = connection.handle();
+                    let (response, len) =
+                        server.get(handle, |value| ots::list(conn, value)).unwrap();
+                    set_object(server, &handles, conn);
+                    if let Err(e) =
+                        notify_subscribed(server, handle, &connection, &response[..len]).await
+                    {
+                        error!("[gatt] olcp response failed: {:?}", fmt::Dbg(&e));
+                    }
+                } else if Some(handle) == handles.oacp {
+                    let conn = connection.handle();
+                    let mut request = [0u8; ots::OACP_MAX];
+                    let len = server
+                        .get(handle, |v| {
+                            let n = v.len().min(request.len());
+                            request[..n].copy_from_slice(&v[..n]);
+                            n
+                        })
+                        .unwrap();
+                    let (response, len) =
+                        ots::execute(conn, &request[..len], may_modify(&connection)).await;
+                    set_object(server, &handles, conn);
+                    if let Err(e) =
+                        notify_subscribed(server, handle, &connection, &response[..len]).await
+                    {
+                        error!("[gatt] oacp response failed: {:?}", fmt::Dbg(&e));
+                    }
+                    ots::release(conn);
+                } else if Some(handle) == handles.object_name {
+                    let conn = connection.handle();
+                    if !server
+                        .get(handle, |value| ots::rename(conn, value))
                         .unwrap()
-                        .await;
+                    {
+                        error!("[ots] only a created object can be named");
+                    }
+                    set_object(server, &handles, conn);
                 } else {
                     info!("[gatt] Write event on {:?}", handle);
                 }
             }
-            Ok(GattEvent::Read {
-                handle,
-                connection: _,
-            }) => {
+            Ok(GattEvent::Read { handle, connection }) => {
+                anchor::observe(connection.handle(), Instant::now());
                 info!("[gatt] Read event on {:?}", handle);
+                events::publish(Event::Read(handle));
+                stats::record(&connection, handle.handle, stats::Op::Read);
+                let len = server.get(handle, |value| value.len()).unwrap_or(0);
+                gatttrace::record(
+                    connection.handle(),
+                    handle.handle,
+                    gatttrace::Op::Read,
+                    len,
+                    gatttrace::OK,
+                );
+                conninfo::note_mtu(&connection);
+
+                #[cfg(feature = "dfu")]
+                if handle == handles.dfu_status {
+                    set_value(server, handle, &dfu::status());
+                } else if handle == handles.radio_firmware {
+                    set_value(server, handle, &radiofw::info());
+                }
+                // keep the estimate fresh for the next read
+                if handle == handles.clock {
+                    set_value(server, handles.clock, &clock::status());
+                } else if handle == handles.alarm {
+                    set_value(server, handles.alarm, &alarm::encode());
+                } else if handle == handles.tasks {
+                    set_value(server, handles.tasks, &monitor::report());
+                } else if handle == handles.recovery {
+                    set_value(server, handle, &watchdog::report());
+                } else if handle == handles.last_fault {
+                    set_value(server, handle, &fault::encode());
+                } else if handle == handles.power {
+                    set_value(server, handle, &power::status());
+                } else if handle == handles.boost {
+                    set_value(server, handle, &boost::remaining(connection.handle()));
+                } else if let Some(value) = writes.read(handle.handle) {
+                    set_value(server, handle, value.as_bytes());
+                } else if [
+                    handles.object_name,
+                    handles.object_size,
+                    handles.object_id,
+                    handles.object_properties,
+                ]
+                .contains(&Some(handle))
+                {
+                    set_object(server, &handles, connection.handle());
+                }
             }
             Err(e) => {
-                error!("[gatt] Error processing GATT events: {:?}", e);
+                error!("[gatt] Error processing GATT events: {:?}", fmt::Dbg(&e));
             }
         }
     }
 }
 
-async fn advertise_task<C: Controller>(
-    mut peripheral: Peripheral<'_, C>,
-) -> Result<(), BleHostError<C::Error>> {
-    let mut adv_data = [0; 31];
-    AdStructure::encode_slice(
-        &[
-            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-            AdStructure::ServiceUuids16(&[Uuid::Uuid16([0x0f, 0x18])]),
-            AdStructure::CompleteLocalName(b"mansion lighting"),
-        ],
-        &mut adv_data[..],
-    )?;
+/// Notify `value` to `conn` if it subscribed to `handle`, doing nothing if
+/// not
+async fn notify_subscribed<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: Characteristic,
+    conn: &Connection<'_>,
+    value: &[u8],
+) -> Result<(), impl Debug> {
+    if !server.is_subscribed(handle, conn) {
+        return Ok(());
+    }
+    notify(server, handle, conn, value).await
+}
+
+/// Give `conn` the page it selected. Pagers keep a cursor per connection,
+/// but the page characteristic is one value for all of them, so pages go
+/// out as notifications to the connection that asked. Only a connection
+/// that hasn't subscribed gets the page left in the table, where another
+/// connection's selection can replace it before it's read.
+async fn send_page<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: Characteristic,
+    conn: &Connection<'_>,
+    page: &[u8; paging::PAGE_SIZE],
+) {
+    if !server.is_subscribed(handle, conn) {
+        set_value(server, handle, page);
+    } else if let Err(e) = notify(server, handle, conn, page).await {
+        error!("[gatt] page notification failed: {:?}", fmt::Dbg(&e));
+    }
+}
+
+/// Notify `value` to `conn`. In debug builds this goes through the
+/// injected impairment, which may drop or delay it.
+///
+/// There's no indicating: the host only sends notifications, and its GATT
+/// events don't include the client's confirmations, so there's nothing an
+/// indication could wait on. No characteristic is declared `Indicate`,
+/// not even the ones the specs say indicate (see [`crate::ots`] and
+/// [`crate::matter`]).
+async fn notify<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: Characteristic,
+    conn: &Connection<'_>,
+    value: &[u8],
+) -> Result<(), impl Debug> {
+    #[cfg(debug_assertions)]
+    if !impair::admit().await {
+        return Ok(());
+    }
+    let sent = server.notify(handle, conn, value).await;
+    let status = if sent.is_ok() {
+        stats::record(conn, handle.handle, stats::Op::Notify);
+        gatttrace::OK
+    } else {
+        gatttrace::FAILED
+    };
+    gatttrace::record(
+        conn.handle(),
+        handle.handle,
+        gatttrace::Op::Notify,
+        value.len(),
+        status,
+    );
+    sent
+}
+
+/// The controls come back as they were last written over BLE: their
+/// characteristics every time the radio starts, the lighting only once
+/// after boot, as it's kept running through radio restarts.
+async fn restore_controls<M: RawMutex, const N: usize>(
+    values: &mut [[u8; controls::MAX_LEN]; controls::CONTROLS.len()],
+    sender: &Sender<'_, M, Message, N>,
+) {
+    let apply = !CONTROLS_RESTORED.load(Ordering::Relaxed);
+    CONTROLS_RESTORED.store(true, Ordering::Relaxed);
+    for (idx, control) in controls::CONTROLS.iter().enumerate() {
+        let key = settings::Key::characteristic(&gen_uuid(control.name));
+        let mut value = [0; controls::MAX_LEN];
+        match settings::get(key, &mut value).await {
+            Ok(Some(len)) if len == control.len => {
+                values[idx] = value;
+                if apply {
+                    if let Some(message) = controls::apply(idx, &value[..len]) {
+                        sender.send(message).await;
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("[gatt] restoring {} failed: {:?}", control.name, e),
+        }
+    }
+}
+
+fn write_alarm_limits(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let request = alarm::LimitsRequest::parse(value).ok_or(writes::AttError::ValueNotAllowed)?;
+    alarm::set_limits(request.signal, request.limits);
+    Ok(())
+}
+
+/// Put back what a refused write to `handle` replaced. The host has
+/// stored the written value by the time we see the write, and would serve
+/// it as if it had been taken. State we keep is encoded again. Control
+/// points and staged values like the Wi-Fi password have nothing to go
+/// back to, so they're cleared.
+fn restore<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handles: &Handles,
+    writes: &writes::Registry,
+    conn: ConnHandle,
+    handle: Characteristic,
+) {
+    if let Some(value) = writes.read(handle.handle) {
+        set_value(server, handle, value.as_bytes());
+    } else if let Some(value) = writes.last(handle.handle) {
+        set_value(server, handle, value);
+    } else if handle == handles.boost {
+        set_value(server, handle, &boost::remaining(conn));
+    } else {
+        set_value(server, handle, &[]);
+    }
+}
+
+/// Update a value we serve, unless a demo override has frozen it
+fn set_value<C: Controller>(server: &Server<'_, '_, C>, handle: Characteristic, value: &[u8]) {
+    #[cfg(debug_assertions)]
+    if overrides::is_frozen(handle.handle) {
+        return;
+    }
+    let _ = server.set(handle, value);
+}
+
+/// Put the current object of `conn` in the object metadata
+/// characteristics
+fn set_object<C: Controller>(server: &Server<'_, '_, C>, handles: &Handles, conn: ConnHandle) {
+    let (Some(name), Some(size), Some(id), Some(properties)) = (
+        handles.object_name,
+        handles.object_size,
+        handles.object_id,
+        handles.object_properties,
+    ) else {
+        return;
+    };
+    let (value, len) = ots::name(conn);
+    set_value(server, name, &value[..len]);
+    set_value(server, size, &ots::size(conn));
+    set_value(server, id, &ots::id(conn));
+    set_value(server, properties, &ots::properties(conn));
+}
+
+/// Whether `connection` may create, write and delete objects, changing
+/// what's in flash: what configuration writes take, in maintenance mode
+fn may_modify(connection: &Connection<'_>) -> bool {
+    let configures = roles::permits(
+        roles::of(connection.peer_address()),
+        roles::Access::Configure,
+    );
+    #[cfg(feature = "admin")]
+    let configures = configures && admin::allows(connection.handle());
+    configures && mode::is_maintenance()
+}
+
+/// Longest value [`Server::update`] works on
+const UPDATE_MAX: usize = 64;
+
+/// A characteristic value, as [`Server::update`] changes it
+pub struct Served {
+    bytes: [u8; UPDATE_MAX],
+    len: usize,
+}
+
+impl Served {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Put `value` in place of the current one, cut to [`UPDATE_MAX`]
+    pub fn replace(&mut self, value: &[u8]) {
+        self.len = value.len().min(UPDATE_MAX);
+        self.bytes[..self.len].copy_from_slice(&value[..self.len]);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn override_value<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handles: &Handles,
+    writes: &writes::Registry,
+) {
+    let mut request = [0u8; overrides::CHARACTERISTIC_SIZE];
+    let len = server
+        .get(handles.overrides, |value| {
+            request[..value.len()].copy_from_slice(value);
+            value.len()
+        })
+        .unwrap();
+
+    match overrides::Request::parse(&request[..len]) {
+        Some(overrides::Request::Override { handle, value }) => {
+            let Some(target) = handles
+                .all()
+                .chain(writes.served())
+                .find(|c| c.handle == handle)
+            else {
+                error!("[override] no characteristic with handle {}", handle);
+                return;
+            };
+            if handles.semantics(handle) == Semantics::Event || writes.is_event(handle) {
+                error!("[override] {:?} is an event, it can't be frozen", target);
+                return;
+            }
+            overrides::release(handle);
+            let _ = server.set(target, value);
+            if overrides::freeze(handle, value) {
+                info!("[override] froze {:?}", target);
+            } else {
+                error!("[override] no free override slot");
+            }
+        }
+        Some(overrides::Request::Release { handle }) => overrides::release(handle),
+        Some(overrides::Request::ReleaseAll) => overrides::release_all(),
+        None => error!("[override] invalid override request"),
+    }
+}
+
+/// Advertise whenever there's room for another connection, and hand the
+/// connections that get accepted to `links`
+async fn advertise_task<'d, C: conninfo::InfoController>(
+    stack: Stack<'d, C>,
+    peripheral: &mut Peripheral<'d, C>,
+    config: &params::Config,
+    own: [u8; 6],
+    name: &str,
+    policy: &impl accept::Policy,
+    links: &Links<'d>,
+) -> Result<Infallible, BleHostError<C::Error>> {
+    let schedule = config.schedule;
+    let mut levels = power::subscribe();
     loop {
-        info!("[adv] advertising");
-        let mut advertiser = match peripheral
-            .advertise(
-                &Default::default(),
-                Advertisement::ConnectableScannableUndirected {
-                    adv_data: &adv_data[..],
-                    scan_data: &[],
-                },
-            )
-            .await
-        {
-            Ok(x) => x,
+        links.wait_free().await;
+        // off while dormant, except when waking
+        power::until(power::Feature::Advertising, true, levels.as_mut()).await;
+        // slowed down while the battery is low
+        let params = AdvertisementParameters {
+            interval_min: power::adv_interval(config.adv_interval_min),
+            interval_max: power::adv_interval(config.adv_interval_max),
+            ..Default::default()
+        };
+        let mode = mode::current();
+        let builder = adv::Builder::new().flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED);
+        let payload = match mode {
+            mode::Mode::Normal => {
+                let builder = match config.service_uuids {
+                    [] => builder,
+                    uuids => builder.service_uuids16(uuids),
+                };
+                match adv::manufacturer_data() {
+                    Some(m) => builder.manufacturer(m.company, m.data()),
+                    None => builder,
+                }
+                .name(name)
+                .build()
+            }
+            // commissionable for Matter, the name moves to the scan
+            // response to make room
+            mode::Mode::Maintenance => builder
+                .service_data16(
+                    matter::SERVICE_UUID,
+                    &matter::Commissioning::TEST.service_data(),
+                )
+                .name(config.setup_name)
+                .build(),
+        };
+        let payload = match payload {
+            Ok(payload) => payload,
             Err(e) => {
-                error!("ADVERTISING ERROR: {:?}", e);
-                return Err(e);
+                // only data set at runtime can be too large
+                error!("[adv] {:?}, dropping the manufacturer data", e);
+                adv::clear_manufacturer_data();
+                continue;
             }
         };
-        info!("[adv] advertising2");
-        let conn = advertiser.accept().await?;
+
+        info!("[adv] advertising ({:?})", mode);
+        let conn = {
+            let mut advertiser = match peripheral
+                .advertise(
+                    &params,
+                    Advertisement::ConnectableScannableUndirected {
+                        adv_data: payload.adv_data(),
+                        scan_data: payload.scan_data(),
+                    },
+                )
+                .await
+            {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("ADVERTISING ERROR: {:?}", fmt::Dbg(&e));
+                    return Err(e);
+                }
+            };
+
+            let window = async {
+                if schedule.broadcasts() {
+                    Timer::after(schedule.connectable).await
+                } else {
+                    pending().await
+                }
+            };
+            let restart = select(mode::changed(), power::changed(levels.as_mut()));
+            select4(advertiser.accept(), window, restart, adv::changed()).await
+        };
+
+        let conn = match conn {
+            Either4::First(conn) => conn?,
+            Either4::Second(()) => {
+                #[cfg(feature = "findnet")]
+                findnet::broadcast(stack, peripheral, own, schedule.broadcast).await?;
+                #[cfg(not(feature = "findnet"))]
+                broadcast(peripheral, own, schedule.broadcast).await?;
+                continue;
+            }
+            // start over with the advertising data for the new mode, or
+            // the interval for the new power level
+            Either4::Third(_) => continue,
+            Either4::Fourth(()) => {
+                info!("[adv] advertising data changed");
+                continue;
+            }
+        };
+
+        let peer = conn.peer_address();
+        if !policy.accept(peer) {
+            info!("[adv] rejecting connection from {:?}", peer);
+            conn.disconnect();
+            continue;
+        }
+
         info!("[adv] connection established");
-        // wait until connection dies
-        while conn.is_connected() {
-            yield_now().await;
+        session::open(conn.handle());
+        events::publish(Event::Connected(conn.handle(), peer));
+        if !links.add(conn) {
+            error!("[adv] no room for the connection");
+        }
+    }
+}
+
+/// Serve the connection of every central that gets accepted, and notify
+/// the values that go to all of them
+async fn serve<C: conninfo::InfoController>(
+    stack: Stack<'_, C>,
+    server: &Server<'_, '_, C>,
+    handles: Handles,
+    targets: &writes::Targets,
+    links: &Links<'_>,
+    latency: &latency::Policy,
+    updates: &mut handoff::UpdateConsumer,
+) -> ! {
+    let slots = [(); CONNECTIONS_MAX].map(|_| serve_slot(stack, server, handles, links, latency));
+    let forwarding = select(
+        forward_updates(server, handles, updates, links),
+        forward_notes(server, targets, links),
+    );
+    match select4(
+        select_array(slots),
+        summarize(server, handles.adc_summary, links),
+        forwarding,
+        join4(
+            notify_faults(server, handles.last_fault, links),
+            notify_power(server, handles.power, links),
+            notify_telemetry(server, handles.telemetry, links),
+            explorer::run(server),
+        ),
+    )
+    .await
+    {
+        Either4::First((never, _)) | Either4::Fourth((never, _, _, _)) => never,
+        Either4::Second(never) => never,
+        Either4::Third(Either::First(never) | Either::Second(never)) => never,
+    }
+}
+
+/// Serve connections one after the other, each until it disconnects
+async fn serve_slot<C: conninfo::InfoController>(
+    stack: Stack<'_, C>,
+    server: &Server<'_, '_, C>,
+    handles: Handles,
+    links: &Links<'_>,
+    latency: &latency::Policy,
+) -> ! {
+    loop {
+        let conn = links.next().await;
+        peers::connected(conn.handle(), conn.peer_address());
+        // runs until the connection dies
+        join5(
+            latency::run(stack, &conn, latency),
+            conninfo::sample(stack, &conn),
+            boost::run(stack, &conn),
+            notify_alarms(server, handles.alarm, &conn),
+            async {
+                if handles.oacp.is_some() {
+                    ots::run(stack, &conn).await
+                } else {
+                    latency::wait_disconnected(&conn).await
+                }
+            },
+        )
+        .await;
+        let reason = disconnects::take(conn.handle());
+        disconnected(conn.handle(), reason);
+        session::close(conn.handle());
+        // nobody is left to watch the relays
+        if links.remove(&conn) == 0 {
+            relay::all_off();
+        }
+        events::publish(Event::Disconnected(conn.handle(), reason));
+    }
+}
+
+/// Act on why `conn` went away, while its session is still open
+fn disconnected(conn: ConnHandle, reason: disconnects::Reason) {
+    match reason {
+        // the slot is freed right after, and the advertiser is back as
+        // soon as there's room
+        disconnects::Reason::Remote(code) => {
+            info!("[gatt] disconnected by the central ({:#04x})", code)
+        }
+        // out of range or interference: what the link looked like last
+        disconnects::Reason::Timeout(code) => match conninfo::last(conn) {
+            Some(info) => error!(
+                "[gatt] link timed out ({:#04x}), last rssi {} tx {:?} rx {:?} params {:?}",
+                code, info.rssi, info.tx_phy, info.rx_phy, info.params
+            ),
+            None => error!("[gatt] link timed out ({:#04x}) before a sample", code),
+        },
+        disconnects::Reason::MicFailure => {
+            error!("[gatt] link dropped on a MIC failure");
+            fault::raise(fault::LINK_MIC);
+        }
+        disconnects::Reason::Local => info!("[gatt] disconnected"),
+        disconnects::Reason::Other(code) => info!("[gatt] disconnected ({:#04x})", code),
+        disconnects::Reason::Unknown => info!("[gatt] disconnected, reason unknown"),
+    }
+}
+
+/// Size of the summary characteristic: the summary, then flags
+const SUMMARY_SIZE: usize = aggregate::SUMMARY_SIZE + 1;
+
+/// Summary flag set when the samples were calibrated
+const SUMMARY_CALIBRATED: u8 = 0x01;
+
+/// Notify summaries of the ADC stream to every connection, one per window
+/// rather than one per block, and only when the mean moved past the report
+/// delta if there is one
+async fn summarize<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: Characteristic,
+    links: &Links<'_>,
+) -> ! {
+    let mut aggregator = aggregate::Aggregator::new(aggregate::window(handle.handle));
+    loop {
+        let block = adcstream::receive().await;
+        let window = power::notify_window(aggregate::window(handle.handle));
+        aggregator.set_window(boost::notify_window(window));
+        let coefficients = calibration::coefficients();
+        let samples = block.samples.iter().map(|&s| match coefficients {
+            Some(c) => c.apply(s as i32),
+            None => s as i32,
+        });
+        let Some(summary) = aggregator.push(samples, Instant::now()) else {
+            continue;
+        };
+
+        let mut value = [0; SUMMARY_SIZE];
+        value[..aggregate::SUMMARY_SIZE].copy_from_slice(&summary.encode());
+        if coefficients.is_some() {
+            value[aggregate::SUMMARY_SIZE] |= SUMMARY_CALIBRATED;
         }
+        let report = threshold::report(handle.handle, summary.mean);
+        server
+            .update(handle, &links.connections(), report, |served| {
+                served.replace(&value)
+            })
+            .await;
     }
 }
+
+/// Notify the samples the telemetry sink queues to every connection, see
+/// [`telemetry`]
+async fn notify_telemetry<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: Characteristic,
+    links: &Links<'_>,
+) -> ! {
+    loop {
+        let payload = telemetry::BLE_QUEUE.receive().await;
+        for conn in links.connections().iter().flatten() {
+            if let Err(e) = notify_subscribed(server, handle, conn, payload.as_bytes()).await {
+                error!("[gatt] telemetry notify failed: {:?}", fmt::Dbg(&e));
+            }
+        }
+    }
+}
+
+/// Keep the power status up to date, notifying every connection as the
+/// degradation level changes
+async fn notify_power<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: Characteristic,
+    links: &Links<'_>,
+) -> ! {
+    let Some(mut levels) = power::subscribe() else {
+        error!("[gatt] no power level subscriber slot left");
+        pending().await
+    };
+    loop {
+        levels.next_message_pure().await;
+        let value = power::status();
+        notify_value(server, handle, links, &value).await;
+    }
+}
+
+/// Keep the last fault up to date, notifying every connection as faults
+/// are raised
+async fn notify_faults<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: Characteristic,
+    links: &Links<'_>,
+) -> ! {
+    loop {
+        let value = fault::changed().await;
+        notify_value(server, handle, links, &value).await;
+    }
+}
+
+/// Set the value we serve for `handle` and notify it to every connection
+async fn notify_value<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: Characteristic,
+    links: &Links<'_>,
+    value: &[u8],
+) {
+    server
+        .update(handle, &links.connections(), true, |served| {
+            served.replace(value)
+        })
+        .await;
+}
+
+/// Notify alarm changes to `conn` until it disconnects
+async fn notify_alarms<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handle: Characteristic,
+    conn: &Connection<'_>,
+) {
+    let Some(mut changes) = alarm::subscribe() else {
+        error!("[gatt] no alarm subscriber slot left");
+        return;
+    };
+    let notifying = async {
+        loop {
+            changes.next_message_pure().await;
+            let value = alarm::encode();
+            server
+                .update(handle, &[Some(conn.clone())], true, |served| {
+                    served.replace(&value)
+                })
+                .await;
+        }
+    };
+
+    select(notifying, latency::wait_disconnected(conn)).await;
+}
+
+/// Forward updates from interrupt handlers to every connection that
+/// subscribed. Updates queue up while nobody is connected, until the queue
+/// overflows, and state updates are coalesced while a notification is going
+/// out. The value is set either way, so a connection that subscribes later
+/// can still read the latest.
+async fn forward_updates<C: Controller>(
+    server: &Server<'_, '_, C>,
+    handles: Handles,
+    updates: &mut handoff::UpdateConsumer,
+    links: &Links<'_>,
+) -> ! {
+    let mut reported = 0;
+    loop {
+        links.wait_any().await;
+        let overflows = updates.overflows();
+        if overflows != reported {
+            error!("[gatt] {} interrupt updates dropped so far", overflows);
+            reported = overflows;
+        }
+
+        let batch = updates
+            .receive_batch(|handle| handles.semantics(handle) == Semantics::State)
+            .await;
+        for update in batch.iter() {
+            let Some(target) = handles.all().find(|c| c.handle == update.handle) else {
+                error!("[gatt] update for unknown handle {}", update.handle);
+                continue;
+            };
+            server
+                .update(target, &links.connections(), true, |served| {
+                    served.replace(update.value())
+                })
+                .await;
+        }
+    }
+}
+
+/// Serve and notify what the modules have for the clients, see
+/// [`modules::notify`]
+async fn forward_notes<C: Controller>(
+    server: &Server<'_, '_, C>,
+    targets: &writes::Targets,
+    links: &Links<'_>,
+) -> ! {
+    loop {
+        let note = modules::next_note().await;
+        // not every module's service is on every board
+        let Some(target) = targets.find(&note.uuid) else {
+            continue;
+        };
+        let conns = links.connections();
+        if let Some(to) = note.to {
+            for conn in conns.iter().flatten().filter(|c| c.handle() == to) {
+                let value = note.value.as_bytes();
+                if let Err(e) = notify_subscribed(server, target, conn, value).await {
+                    error!("[gatt] response to {:?} failed: {:?}", target, fmt::Dbg(&e));
+                }
+            }
+            continue;
+        }
+        let notify = note
+            .reading
+            .map_or(true, |reading| threshold::report(target.handle, reading));
+        server
+            .update(target, &conns, notify, |served| {
+                served.replace(note.value.as_bytes())
+            })
+            .await;
+    }
+}
+
+/// Broadcast the current lighting state as non-connectable advertising for
+/// `window`, so scanners can pick it up without connecting. The on/off
+/// state also goes out as BTHome for Home Assistant.
+#[cfg_attr(feature = "findnet", allow(dead_code))]
+async fn broadcast<C: Controller>(
+    peripheral: &mut Peripheral<'_, C>,
+    own: [u8; 6],
+    window: Duration,
+) -> Result<(), BleHostError<C::Error>> {
+    let mut telemetry = [0u8; 5];
+    telemetry[..3].copy_from_slice(&controls::value(controls::BASE_COLOR));
+    telemetry[3] = controls::value(controls::BRIGHTNESS)[0];
+    telemetry[4] = controls::value(controls::SKIP)[0];
+
+    let counter = bthome::next_counter();
+    let on = controls::value(controls::BRIGHTNESS)[0] != 0 && telemetry[..3] != [0; 3];
+    let objects = [
+        bthome::Object::PacketId(counter as u8),
+        bthome::Object::Power(on),
+    ];
+    let mut service_data = [0; bthome::MAX_SERVICE_DATA];
+    #[cfg(feature = "aes")]
+    let service_len = match option_env!("BTHOME_BINDKEY") {
+        Some(key) => bthome::Encryptor::new(&bthome::parse_bindkey(key), own).encode(
+            counter,
+            &objects,
+            &mut service_data,
+        ),
+        None => bthome::encode(&objects, &mut service_data),
+    };
+    #[cfg(not(feature = "aes"))]
+    let service_len = bthome::encode(&objects, &mut service_data);
+
+    let payload = adv::Builder::new()
+        .flags(BR_EDR_NOT_SUPPORTED)
+        .manufacturer(adv::TEST_COMPANY_ID, &telemetry)
+        // the buffer is sized for these objects
+        .service_data16(bthome::UUID, &service_data[..service_len.unwrap()])
+        .build()
+        // and all of them fit in the advertising data
+        .unwrap();
+
+    info!("[adv] broadcasting telemetry");
+    let _advertiser = peripheral
+        .advertise(
+            &Default::default(),
+            Advertisement::NonconnectableNonscannableUndirected {
+                adv_data: payload.adv_data(),
+            },
+        )
+        .await?;
+    Timer::after(window).await;
+
+    Ok(())
+}
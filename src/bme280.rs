@@ -0,0 +1,238 @@
+//! BME280 and BMP280 environmental sensors
+//!
+//! Both measure temperature and pressure, the BME280 humidity as well; the
+//! chip ID tells them apart. Each measurement is a forced one, the sensor
+//! sleeping in between, with no oversampling and the filter off. Readings
+//! are compensated with the calibration read at start-up, using the
+//! integer formulas from the datasheet.
+
+use embassy_rp::i2c;
+use embassy_time::Timer;
+
+use crate::bus::Bus;
+use crate::environment::Reading;
+use crate::environment::Sensor;
+
+/// With SDO tied low, the usual breakout boards have it high
+const ADDRESSES: [u8; 2] = [0x76, 0x77];
+
+// registers
+const CHIP_ID: u8 = 0xd0;
+const CALIBRATION_TP: u8 = 0x88;
+const CALIBRATION_H1: u8 = 0xa1;
+const CALIBRATION_H: u8 = 0xe1;
+const CTRL_HUM: u8 = 0xf2;
+const STATUS: u8 = 0xf3;
+const CTRL_MEAS: u8 = 0xf4;
+const DATA: u8 = 0xf7;
+
+const BME280_ID: u8 = 0x60;
+const BMP280_ID: u8 = 0x58;
+
+/// Humidity oversampling x1
+const HUM_X1: u8 = 0x01;
+
+/// Temperature and pressure oversampling x1, forced mode
+const MEAS_FORCED: u8 = 0x25;
+
+/// Set while a measurement is running
+const STATUS_MEASURING: u8 = 0x08;
+
+/// A measurement with no oversampling takes under 10ms
+const MEASUREMENT_MS: u64 = 10;
+
+/// Polls of the status before giving up on a measurement
+const MAX_POLLS: u32 = 10;
+
+#[derive(Debug, Clone, Copy)]
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p2: i16,
+    p3: i16,
+    p4: i16,
+    p5: i16,
+    p6: i16,
+    p7: i16,
+    p8: i16,
+    p9: i16,
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
+
+impl Calibration {
+    fn parse(tp: &[u8; 24], h1: u8, h: &[u8; 7]) -> Self {
+        let u = |at: usize| u16::from_le_bytes([tp[at], tp[at + 1]]);
+        let s = |at: usize| u(at) as i16;
+        Self {
+            t1: u(0),
+            t2: s(2),
+            t3: s(4),
+            p1: u(6),
+            p2: s(8),
+            p3: s(10),
+            p4: s(12),
+            p5: s(14),
+            p6: s(16),
+            p7: s(18),
+            p8: s(20),
+            p9: s(22),
+            h1,
+            h2: i16::from_le_bytes([h[0], h[1]]),
+            h3: h[2],
+            // 12 bits each, sharing the nibbles of h[4]
+            h4: ((h[3] as i8 as i16) << 4) | (h[4] & 0x0f) as i16,
+            h5: ((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16,
+            h6: h[6] as i8,
+        }
+    }
+
+    /// Fine temperature, which the other two depend on, and the
+    /// temperature in 0.01°C
+    fn temperature(&self, adc: i32) -> (i32, i16) {
+        let t1 = self.t1 as i32;
+        let var1 = (((adc >> 3) - (t1 << 1)) * self.t2 as i32) >> 11;
+        let var2 = (((((adc >> 4) - t1) * ((adc >> 4) - t1)) >> 12) * self.t3 as i32) >> 14;
+        let fine = var1 + var2;
+        (fine, ((fine * 5 + 128) >> 8) as i16)
+    }
+
+    /// Pressure in 0.1Pa
+    fn pressure(&self, adc: i32, fine: i32) -> u32 {
+        let mut var1 = fine as i64 - 128_000;
+        let mut var2 = var1 * var1 * self.p6 as i64;
+        var2 += (var1 * self.p5 as i64) << 17;
+        var2 += (self.p4 as i64) << 35;
+        var1 = ((var1 * var1 * self.p3 as i64) >> 8) + ((var1 * self.p2 as i64) << 12);
+        var1 = (((1i64 << 47) + var1) * self.p1 as i64) >> 33;
+        if var1 == 0 {
+            // not calibrated, and we'd divide by it
+            return 0;
+        }
+        let mut p = 1_048_576 - adc as i64;
+        p = (((p << 31) - var2) * 3125) / var1;
+        var1 = (self.p9 as i64 * (p >> 13) * (p >> 13)) >> 25;
+        var2 = (self.p8 as i64 * p) >> 19;
+        p = ((p + var1 + var2) >> 8) + ((self.p7 as i64) << 4);
+        // p is in 1/256 Pa
+        (p * 10 / 256) as u32
+    }
+
+    /// Humidity in 0.01%
+    fn humidity(&self, adc: i32, fine: i32) -> u16 {
+        let v = fine - 76_800;
+        let mut v = (((adc << 14) - ((self.h4 as i32) << 20) - (self.h5 as i32 * v) + 16_384)
+            >> 15)
+            * (((((((v * self.h6 as i32) >> 10) * (((v * self.h3 as i32) >> 11) + 32_768))
+                >> 10)
+                + 2_097_152)
+                * self.h2 as i32
+                + 8_192)
+                >> 14);
+        v -= ((((v >> 15) * (v >> 15)) >> 7) * self.h1 as i32) >> 4;
+        let v = v.clamp(0, 419_430_400);
+        // v >> 12 is in 1/1024 %
+        ((v >> 12) as u32 * 100 / 1024) as u16
+    }
+}
+
+pub struct Bme280 {
+    address: u8,
+    humidity: bool,
+    calibration: Calibration,
+}
+
+async fn read(bus: &Bus, address: u8, reg: u8, out: &mut [u8]) -> Result<(), i2c::Error> {
+    bus.lock()
+        .await
+        .write_read_async(address as u16, [reg], out)
+        .await
+}
+
+async fn write(bus: &Bus, address: u8, reg: u8, value: u8) -> Result<(), i2c::Error> {
+    bus.lock()
+        .await
+        .write_async(address as u16, [reg, value])
+        .await
+}
+
+impl Bme280 {
+    /// Look for the sensor at either address and read its calibration.
+    /// `None` if something else answers, the error if nothing does.
+    pub async fn probe(bus: &Bus) -> Result<Option<Self>, i2c::Error> {
+        let mut answered = false;
+        let mut error = None;
+        for address in ADDRESSES {
+            let mut id = [0];
+            if let Err(e) = read(bus, address, CHIP_ID, &mut id).await {
+                error = Some(e);
+                continue;
+            }
+            answered = true;
+            let humidity = match id[0] {
+                BME280_ID => true,
+                BMP280_ID => false,
+                _ => continue,
+            };
+
+            let mut tp = [0; 24];
+            let mut h1 = [0];
+            let mut h = [0; 7];
+            read(bus, address, CALIBRATION_TP, &mut tp).await?;
+            if humidity {
+                read(bus, address, CALIBRATION_H1, &mut h1).await?;
+                read(bus, address, CALIBRATION_H, &mut h).await?;
+            }
+            return Ok(Some(Self {
+                address,
+                humidity,
+                calibration: Calibration::parse(&tp, h1[0], &h),
+            }));
+        }
+        match error {
+            Some(e) if !answered => Err(e),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Sensor for Bme280 {
+    async fn measure(&mut self, bus: &Bus) -> Result<Reading, i2c::Error> {
+        // the humidity setting only applies with the next write to CTRL_MEAS
+        if self.humidity {
+            write(bus, self.address, CTRL_HUM, HUM_X1).await?;
+        }
+        write(bus, self.address, CTRL_MEAS, MEAS_FORCED).await?;
+        for _ in 0..MAX_POLLS {
+            Timer::after_millis(MEASUREMENT_MS).await;
+            let mut status = [0];
+            read(bus, self.address, STATUS, &mut status).await?;
+            if status[0] & STATUS_MEASURING == 0 {
+                break;
+            }
+        }
+
+        let mut data = [0; 8];
+        read(bus, self.address, DATA, &mut data).await?;
+        let adc20 = |at: usize| {
+            ((data[at] as i32) << 12) | ((data[at + 1] as i32) << 4) | (data[at + 2] >> 4) as i32
+        };
+        let (fine, temperature) = self.calibration.temperature(adc20(3));
+        let pressure = self.calibration.pressure(adc20(0), fine);
+        let humidity = self.humidity.then(|| {
+            let adc = i32::from(u16::from_be_bytes([data[6], data[7]]));
+            self.calibration.humidity(adc, fine)
+        });
+        Ok(Reading {
+            temperature: Some(temperature),
+            humidity,
+            pressure: Some(pressure),
+        })
+    }
+}
@@ -0,0 +1,166 @@
+//! Board bring-up for the examples
+//!
+//! Each example in `examples/` wires up one use case out of the library
+//! modules. What all of them start with, the flash and what's stored in
+//! it, the task monitor, the logger and the radio, is here, so an example
+//! only spawns the tasks of its use case. The examples use the pins of
+//! the original board ([`crate::pinmap::PinMap::DEFAULT`]) rather than
+//! loading a pin map.
+//!
+//! `main.rs` brings the full firmware up by hand, with more to it: it
+//! restarts the radio when the BLE stack stops, and falls back to the
+//! built-in radio firmware when an update doesn't come up. The radio here
+//! is started once.
+
+use bt_hci::controller::ExternalController;
+use cyw43_pio::PioSpi;
+use embassy_executor::Spawner;
+use embassy_rp::flash::Flash;
+use embassy_rp::gpio::Level;
+use embassy_rp::gpio::Output;
+use embassy_rp::interrupt::typelevel::Binding;
+use embassy_rp::interrupt::typelevel::PIO0_IRQ_0;
+#[cfg(feature = "log")]
+use embassy_rp::interrupt::typelevel::USBCTRL_IRQ;
+use embassy_rp::peripherals::ADC;
+use embassy_rp::peripherals::DMA_CH0;
+use embassy_rp::peripherals::FLASH;
+use embassy_rp::peripherals::PIN_23;
+use embassy_rp::peripherals::PIN_24;
+use embassy_rp::peripherals::PIN_25;
+use embassy_rp::peripherals::PIN_27;
+use embassy_rp::peripherals::PIN_29;
+use embassy_rp::peripherals::PIO0;
+#[cfg(feature = "log")]
+use embassy_rp::peripherals::USB;
+use embassy_rp::pio;
+use embassy_rp::pio::Pio;
+#[cfg(feature = "log")]
+use embassy_rp::usb;
+use static_cell::StaticCell;
+
+use crate::alarm;
+use crate::boardrev;
+use crate::calibration;
+use crate::config;
+use crate::conninfo;
+#[cfg(feature = "log")]
+use crate::console;
+use crate::crash;
+use crate::flash;
+use crate::monitor;
+use crate::persist;
+use crate::radiofw;
+use crate::system;
+
+/// The radio firmware built in
+pub const BUILT_IN: radiofw::Blobs = radiofw::Blobs {
+    firmware: include_bytes!("../firmware/43439A0.bin"),
+    clm: include_bytes!("../firmware/43439A0_clm.bin"),
+    bluetooth: include_bytes!("../firmware/43439A0_btfw.bin"),
+    source: radiofw::Source::BuiltIn,
+};
+
+type Spi = PioSpi<'static, PIO0, 0, DMA_CH0>;
+
+#[embassy_executor::task]
+async fn flash_task() -> ! {
+    flash::run().await
+}
+
+#[embassy_executor::task]
+async fn monitor_task() -> ! {
+    monitor::run().await
+}
+
+#[embassy_executor::task]
+async fn persist_task() -> ! {
+    persist::run().await
+}
+
+#[embassy_executor::task]
+async fn radio_task(runner: cyw43::Runner<'static, Output<'static>, Spi>) -> ! {
+    runner.run().await
+}
+
+#[cfg(feature = "log")]
+#[embassy_executor::task]
+async fn logger_task(driver: usb::Driver<'static, USB>) {
+    embassy_usb_logger::run!(1024, log::LevelFilter::Info, driver, console::Handler);
+}
+
+/// Set up the flash and load what's stored in it: the crash report, the
+/// calibration, the settings and the alarm limits. Detects the board revision on
+/// the way, which it returns.
+pub async fn init(
+    spawner: Spawner,
+    mut flash: FLASH,
+    adc: &mut ADC,
+    id_pin: &mut PIN_27,
+) -> &'static boardrev::Revision {
+    system::handle_boot_request();
+    system::DeviceId::init(&mut flash);
+    flash::init(Flash::new_blocking(flash));
+    spawner.must_spawn(flash_task());
+    spawner.must_spawn(monitor_task());
+    let revision = boardrev::detect(adc, id_pin);
+    crash::recover().await;
+    calibration::load().await;
+    config::load().await;
+    alarm::load().await;
+    spawner.must_spawn(persist_task());
+    revision
+}
+
+/// Log over the USB serial port, which also carries the console
+#[cfg(feature = "log")]
+pub fn logger(
+    spawner: Spawner,
+    usb: USB,
+    irqs: impl Binding<USBCTRL_IRQ, usb::InterruptHandler<USB>>,
+) {
+    spawner.must_spawn(logger_task(usb::Driver::new(usb, irqs)));
+}
+
+/// The radio, up and running
+pub struct Radio<C> {
+    /// The Wi-Fi side, for [`crate::net::run`]
+    pub net: cyw43::NetDriver<'static>,
+    /// The Bluetooth side, for the BLE host
+    pub controller: C,
+    pub control: cyw43::Control<'static>,
+}
+
+/// Bring the radio up and run its driver. The firmware is [`BUILT_IN`],
+/// unless an update was installed over DFU.
+#[allow(clippy::too_many_arguments)]
+pub async fn radio(
+    spawner: Spawner,
+    pwr: PIN_23,
+    cs: PIN_25,
+    dio: PIN_24,
+    clk: PIN_29,
+    pio: PIO0,
+    dma: DMA_CH0,
+    irqs: impl Binding<PIO0_IRQ_0, pio::InterruptHandler<PIO0>>,
+) -> Radio<impl conninfo::InfoController> {
+    let mut pio = Pio::new(pio, irqs);
+    let pwr = Output::new(pwr, Level::Low);
+    let cs = Output::new(cs, Level::High);
+    let spi = PioSpi::new(&mut pio.common, pio.sm0, pio.irq0, cs, dio, clk, dma);
+
+    static STATE: StaticCell<cyw43::State> = StaticCell::new();
+    let state = STATE.init(cyw43::State::new());
+    let blobs = radiofw::select(&BUILT_IN);
+    let (net, bt, mut control, runner) =
+        cyw43::new_with_bluetooth(state, pwr, spi, blobs.firmware, blobs.bluetooth).await;
+    spawner.must_spawn(radio_task(runner));
+    control.init(blobs.clm).await;
+
+    let controller: ExternalController<_, 10> = ExternalController::new(bt);
+    Radio {
+        net,
+        controller,
+        control,
+    }
+}
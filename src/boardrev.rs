@@ -0,0 +1,115 @@
+//! Board revision detection
+//!
+//! Every board revision has an ID resistor from GPIO27 to 3V3, forming a
+//! divider with the pin's internal pull-down. We read its voltage once at
+//! boot, before anything else has the ADC, and the band it falls in picks
+//! the revision. The original board has no resistor and reads as zero.
+//!
+//! The revision decides the default pin map, which optional peripherals
+//! are fitted and which expander the relay header has. It's reported as
+//! the hardware revision string in the Device Information Service.
+
+use core::cell::Cell;
+
+use embassy_rp::adc;
+use embassy_rp::adc::Adc;
+use embassy_rp::gpio::Pull;
+use embassy_rp::peripherals::ADC;
+use embassy_rp::peripherals::PIN_27;
+use embassy_rp::Peripheral;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::error;
+use crate::expander::Expander;
+use crate::info;
+use crate::pinmap::PinMap;
+
+/// The pin the ID resistor is on
+pub const ID_PIN: u8 = 27;
+
+/// Readings averaged for the ID voltage
+const READINGS: u32 = 8;
+
+/// The ADC range is split into this many bands, one per revision
+const BANDS: u16 = 8;
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Revision {
+    /// Hardware revision string
+    pub name: &'static str,
+    pub pins: PinMap,
+    /// Whether the SSD1306 display is fitted
+    pub display: bool,
+    /// What the relay bank is driven through
+    pub expander: Expander,
+}
+
+/// Known revisions, by band
+pub static REVISIONS: [Revision; 2] = [
+    Revision {
+        name: "A",
+        pins: PinMap::DEFAULT,
+        display: true,
+        expander: Expander::Pcf8574,
+    },
+    // the display header made room for the buzzer and the LED moved next
+    // to the level shifter, freeing its ADC pin for the battery. The relay
+    // header also takes inputs.
+    Revision {
+        name: "B",
+        pins: PinMap {
+            led: 22,
+            buzzer: Some(20),
+            expander_int: Some(18),
+            battery: Some(28),
+            ..PinMap::DEFAULT
+        },
+        display: false,
+        expander: Expander::Mcp23017,
+    },
+];
+
+static DETECTED: Mutex<CriticalSectionRawMutex, Cell<usize>> = Mutex::new(Cell::new(0));
+
+/// Detect the revision, falling back to the original board if the ID pin
+/// reads as something we don't know. Call once at boot.
+pub fn detect(
+    adc: impl Peripheral<P = ADC>,
+    pin: impl Peripheral<P = PIN_27>,
+) -> &'static Revision {
+    let mut adc = Adc::new_blocking(adc, adc::Config::default());
+    let mut channel = adc::Channel::new_pin(pin, Pull::Down);
+
+    let mut sum = 0;
+    for _ in 0..READINGS {
+        match adc.blocking_read(&mut channel) {
+            Ok(sample) => sum += sample as u32,
+            Err(e) => {
+                error!("[boardrev] reading the ID pin failed: {:?}", e);
+                return &REVISIONS[0];
+            }
+        }
+    }
+
+    // the resistors put the level in the middle of a band
+    let level = (sum / READINGS) as u16;
+    let band = ((level as u32 * BANDS as u32 + 2048) / 4096) as usize;
+    let idx = if band < REVISIONS.len() {
+        band
+    } else {
+        error!("[boardrev] unknown ID band {} ({})", band, level);
+        0
+    };
+
+    let revision = &REVISIONS[idx];
+    info!("[boardrev] revision {} (ID level {})", revision.name, level);
+    DETECTED.lock(|detected| detected.set(idx));
+    revision
+}
+
+/// The revision [`detect`] found, the original board before that
+pub fn current() -> &'static Revision {
+    &REVISIONS[DETECTED.lock(|detected| detected.get())]
+}
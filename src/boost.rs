@@ -0,0 +1,158 @@
+//! Time-boxed high-performance mode
+//!
+//! A client about to move a lot of data, a firmware download or a sync,
+//! writes the boost characteristic to [`request`] a boost for a number of
+//! seconds, up to [`MAX_WINDOW`]. For that long its connection is pinned
+//! to [`latency::Profile::Boost`], the shortest connection interval there
+//! is, asks for the 2M PHY, and summaries are notified as often as the ADC
+//! stream delivers blocks, whatever the power level. Once the time is up,
+//! or the client writes 0, the connection goes back to the profile it had
+//! and the 1M PHY, and the power-saving policy has its way again.
+//!
+//! The central doesn't have to take the parameters or the PHY, the boost
+//! is on either way. Writing the characteristic again while a boost is on
+//! replaces its deadline. Reading it gives the seconds left.
+
+use core::cell::Cell;
+
+use bt_hci::cmd::le::LeSetPhy;
+use bt_hci::param::AllPhys;
+use bt_hci::param::PhyMask;
+use bt_hci::param::PhyOptions;
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::conninfo;
+use crate::error;
+use crate::events;
+use crate::events::Event;
+use crate::fmt;
+use crate::info;
+use crate::latency;
+use crate::session;
+
+/// Boost asked for with an empty write
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(30);
+
+/// Longest boost a client can ask for
+pub const MAX_WINDOW: Duration = Duration::from_secs(120);
+
+/// Summary window while any connection is boosted
+pub const NOTIFY_WINDOW: Duration = Duration::from_millis(100);
+
+/// When each connection's boost ends, `None` if it has none
+static UNTIL: session::PerConnection<Option<Instant>> = session::PerConnection::new(None);
+
+/// Number of connections boosted
+static BOOSTED: Mutex<CriticalSectionRawMutex, Cell<usize>> = Mutex::new(Cell::new(0));
+
+/// Parse a boost write: `[seconds]`, where 0 ends the boost. Missing is
+/// [`DEFAULT_WINDOW`], and more than [`MAX_WINDOW`] is capped.
+pub fn parse(value: &[u8]) -> Option<Duration> {
+    match value {
+        [] => Some(DEFAULT_WINDOW),
+        [seconds] => Some(Duration::from_secs(*seconds as u64).min(MAX_WINDOW)),
+        _ => None,
+    }
+}
+
+/// Boost `conn` for `window` from now, or with a zero `window` end its
+/// boost
+pub fn request(conn: ConnHandle, window: Duration) {
+    let until = (window != Duration::from_ticks(0)).then(|| Instant::now() + window);
+    UNTIL.set(conn, until);
+    events::publish(Event::BoostRequested(conn));
+}
+
+/// Seconds left of the boost of `conn`, for the characteristic
+pub fn remaining(conn: ConnHandle) -> [u8; 1] {
+    let left = UNTIL.get(conn).map_or(0, |until| {
+        until.saturating_duration_since(Instant::now()).as_secs()
+    });
+    [left.min(u8::MAX as u64) as u8]
+}
+
+/// Whether any connection is boosted
+pub fn active() -> bool {
+    BOOSTED.lock(|boosted| boosted.get() > 0)
+}
+
+/// The summary window to use instead of `window`
+pub fn notify_window(window: Duration) -> Duration {
+    if active() {
+        window.min(NOTIFY_WINDOW)
+    } else {
+        window
+    }
+}
+
+/// Boost `conn` whenever it asks, until it disconnects
+pub async fn run<C: conninfo::InfoController>(stack: Stack<'_, C>, conn: &Connection<'_>) {
+    let Some(mut events) = events::subscribe() else {
+        error!("[boost] no event subscriber available, boost disabled");
+        latency::wait_disconnected(conn).await;
+        return;
+    };
+
+    let handle = conn.handle();
+    let mut restore = None;
+    loop {
+        let until = UNTIL.get(handle).filter(|until| *until > Instant::now());
+        match (until, restore) {
+            (Some(_), None) => {
+                info!("[boost] on for {}s", remaining(handle)[0]);
+                restore = Some(latency::pinned(handle));
+                latency::pin(handle, Some(latency::Profile::Boost));
+                BOOSTED.lock(|boosted| boosted.set(boosted.get() + 1));
+                set_phy(stack, conn, PhyMask::new().set_le_2m_preferred(true)).await;
+            }
+            (None, Some(pinned)) => {
+                info!("[boost] off");
+                restore = None;
+                UNTIL.set(handle, None);
+                latency::pin(handle, pinned);
+                BOOSTED.lock(|boosted| boosted.set(boosted.get() - 1));
+                set_phy(stack, conn, PhyMask::new().set_le_1m_preferred(true)).await;
+            }
+            _ => {}
+        }
+
+        match select3(
+            events.next_message_pure(),
+            Timer::at(until.unwrap_or(Instant::MAX)),
+            latency::wait_disconnected(conn),
+        )
+        .await
+        {
+            Either3::First(_) | Either3::Second(()) => {}
+            Either3::Third(()) => break,
+        }
+    }
+    if restore.is_some() {
+        BOOSTED.lock(|boosted| boosted.set(boosted.get() - 1));
+    }
+}
+
+/// Tell the controller which PHY `conn` should prefer in both directions
+async fn set_phy<C: conninfo::InfoController>(
+    stack: Stack<'_, C>,
+    conn: &Connection<'_>,
+    phys: PhyMask,
+) {
+    let cmd = LeSetPhy::new(
+        conn.handle(),
+        AllPhys::new(),
+        phys,
+        phys,
+        PhyOptions::default(),
+    );
+    if let Err(e) = stack.async_command(cmd).await {
+        error!("[boost] PHY update failed: {:?}", fmt::Dbg(&e));
+    }
+}
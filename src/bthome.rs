@@ -0,0 +1,212 @@
+//! BTHome v2 broadcasts
+//!
+//! Sensor readings in the BTHome service data format, which Home Assistant
+//! picks up without a custom integration. With the `aes` feature and a
+//! bindkey in `BTHOME_BINDKEY` at build time the objects are encrypted
+//! (AES-CCM); the same key has to be entered in Home Assistant.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Service UUID the BTHome service data is sent under
+pub const UUID: u16 = 0xfcd2;
+
+/// Device info byte: version 2 in the top bits, bit 0 set if encrypted
+const DEVICE_INFO: u8 = 0x40;
+#[cfg(feature = "aes")]
+const DEVICE_INFO_ENCRYPTED: u8 = DEVICE_INFO | 0x01;
+
+/// Buffer size for the service data we send
+pub const MAX_SERVICE_DATA: usize = 16;
+
+static COUNTER: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Count a new reading. The low byte makes a good packet id, the whole
+/// value is the encryption counter.
+pub fn next_counter() -> u32 {
+    COUNTER.lock(|c| {
+        let n = c.get();
+        c.set(n.wrapping_add(1));
+        n
+    })
+}
+
+/// The value [`next_counter`] returns next
+pub fn counter() -> u32 {
+    COUNTER.lock(|c| c.get())
+}
+
+/// For restoring snapshots
+pub(crate) fn set_counter(n: u32) {
+    COUNTER.lock(|c| c.set(n));
+}
+
+/// A measurement, encoded as its object id and little endian value.
+/// Receivers expect objects in ascending id order, which is the order of
+/// the variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Object {
+    /// Lets receivers drop duplicates of the same reading
+    PacketId(u8),
+    /// Battery level in %
+    Battery(u8),
+    /// Temperature in 0.01 °C
+    Temperature(i16),
+    /// Humidity in 0.01 %
+    Humidity(u16),
+    /// Illuminance in 0.01 lx, 24 bits
+    Illuminance(u32),
+    /// Voltage in mV
+    Voltage(u16),
+    /// Binary power state
+    Power(bool),
+}
+
+impl Object {
+    fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let mut buf = [0u8; 5];
+        let len = match *self {
+            Self::PacketId(v) => put(&mut buf, 0x00, &[v]),
+            Self::Battery(v) => put(&mut buf, 0x01, &[v]),
+            Self::Temperature(v) => put(&mut buf, 0x02, &v.to_le_bytes()),
+            Self::Humidity(v) => put(&mut buf, 0x03, &v.to_le_bytes()),
+            Self::Illuminance(v) => put(&mut buf, 0x05, &v.min(0xff_ffff).to_le_bytes()[..3]),
+            Self::Voltage(v) => put(&mut buf, 0x0c, &v.to_le_bytes()),
+            Self::Power(v) => put(&mut buf, 0x10, &[v as u8]),
+        };
+        out.get_mut(..len)?.copy_from_slice(&buf[..len]);
+        Some(len)
+    }
+}
+
+fn put(buf: &mut [u8; 5], id: u8, value: &[u8]) -> usize {
+    buf[0] = id;
+    buf[1..1 + value.len()].copy_from_slice(value);
+    1 + value.len()
+}
+
+/// Encode `objects` as BTHome service data (everything after the UUID),
+/// `None` if they don't fit in `out`
+pub fn encode(objects: &[Object], out: &mut [u8]) -> Option<usize> {
+    *out.first_mut()? = DEVICE_INFO;
+    let mut len = 1;
+    for object in objects {
+        len += object.encode(&mut out[len..])?;
+    }
+    Some(len)
+}
+
+/// Decode the objects of someone else's unencrypted BTHome service data
+/// (everything after the UUID), `None` if it's encrypted or not v2
+pub fn decode(data: &[u8]) -> Option<Objects<'_>> {
+    let (&info, objects) = data.split_first()?;
+    if info != DEVICE_INFO {
+        return None;
+    }
+    Some(Objects(objects))
+}
+
+/// Objects of received service data. Stops at the first object we don't
+/// know the size of, the ones after it can't be found.
+pub struct Objects<'a>(&'a [u8]);
+
+impl Iterator for Objects<'_> {
+    type Item = Object;
+
+    fn next(&mut self) -> Option<Object> {
+        let (&id, rest) = self.0.split_first()?;
+        let len = match id {
+            0x00 | 0x01 | 0x10 => 1,
+            0x02 | 0x03 | 0x0c => 2,
+            0x05 => 3,
+            _ => 0,
+        };
+        let Some((value, rest)) = rest.split_at_checked(len).filter(|_| len != 0) else {
+            self.0 = &[];
+            return None;
+        };
+        self.0 = rest;
+
+        let u16_at = || u16::from_le_bytes([value[0], value[1]]);
+        Some(match id {
+            0x00 => Object::PacketId(value[0]),
+            0x01 => Object::Battery(value[0]),
+            0x02 => Object::Temperature(u16_at() as i16),
+            0x03 => Object::Humidity(u16_at()),
+            0x05 => Object::Illuminance(u32::from_le_bytes([value[0], value[1], value[2], 0])),
+            0x0c => Object::Voltage(u16_at()),
+            _ => Object::Power(value[0] != 0),
+        })
+    }
+}
+
+/// Encrypts BTHome payloads with a bindkey
+#[cfg(feature = "aes")]
+pub struct Encryptor {
+    aes: crate::aes::Aes128,
+    /// Our address, most significant byte first
+    mac: [u8; 6],
+}
+
+#[cfg(feature = "aes")]
+impl Encryptor {
+    /// `address` is our own address as sent over the air (least
+    /// significant byte first)
+    pub fn new(bindkey: &[u8; 16], address: [u8; 6]) -> Self {
+        let mut mac = address;
+        mac.reverse();
+
+        Self {
+            aes: crate::aes::Aes128::new(bindkey),
+            mac,
+        }
+    }
+
+    /// Like [`encode`], with the objects encrypted and followed by the
+    /// counter and a 4 byte tag. `counter` must not repeat for a key.
+    pub fn encode(&self, counter: u32, objects: &[Object], out: &mut [u8]) -> Option<usize> {
+        let len = encode(objects, out)?;
+        if out.len() < len + 8 {
+            return None;
+        }
+        out[0] = DEVICE_INFO_ENCRYPTED;
+
+        let counter = counter.to_le_bytes();
+
+        let mut nonce = [0; crate::aes::CCM_NONCE_SIZE];
+        nonce[..6].copy_from_slice(&self.mac);
+        nonce[6..8].copy_from_slice(&UUID.to_le_bytes());
+        nonce[8] = DEVICE_INFO_ENCRYPTED;
+        nonce[9..].copy_from_slice(&counter);
+
+        let tag = crate::aes::ccm_encrypt::<4>(&self.aes, &nonce, &[], &mut out[1..len]);
+        out[len..len + 4].copy_from_slice(&counter);
+        out[len + 4..len + 8].copy_from_slice(&tag);
+        Some(len + 8)
+    }
+}
+
+/// Parse a bindkey given as 32 hex digits, at compile time
+pub const fn parse_bindkey(hex: &str) -> [u8; 16] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("bindkey must be hex"),
+        }
+    }
+
+    let hex = hex.as_bytes();
+    assert!(hex.len() == 32, "bindkey must be 32 hex digits");
+    let mut key = [0; 16];
+    let mut i = 0;
+    while i < 16 {
+        key[i] = nibble(hex[2 * i]) << 4 | nibble(hex[2 * i + 1]);
+        i += 1;
+    }
+    key
+}
@@ -0,0 +1,21 @@
+//! Shared I²C bus
+//!
+//! The display has the bus to itself at boot. After that it's shared by
+//! the relay bank, the RTC, the energy meter, the environmental sensor and
+//! the distance sensor, each locking it for a transfer or a short burst of
+//! them.
+
+use embassy_rp::i2c;
+use embassy_rp::i2c::I2c;
+use embassy_rp::peripherals::I2C0;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use static_cell::StaticCell;
+
+pub type Bus = Mutex<CriticalSectionRawMutex, I2c<'static, I2C0, i2c::Async>>;
+
+/// Hand the bus over to be shared. Call once.
+pub fn share(i2c: I2c<'static, I2C0, i2c::Async>) -> &'static Bus {
+    static BUS: StaticCell<Bus> = StaticCell::new();
+    BUS.init(Mutex::new(i2c))
+}
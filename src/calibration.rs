@@ -0,0 +1,252 @@
+//! Sensor calibration
+//!
+//! Guided multi-point calibration of the ADC sensor. The client applies a
+//! known reference to the sensor, tells us its value, and repeats for as
+//! many points as it likes, with writes to the calibration characteristic
+//! (integers little endian):
+//!
+//! | op     | args             | effect                                            |
+//! |--------|------------------|---------------------------------------------------|
+//! | `0x01` |                  | start over, dropping any captured points          |
+//! | `0x02` | `reference: i32` | capture the current raw level as `reference`     |
+//! | `0x03` |                  | fit, store and apply the coefficients             |
+//! | `0x04` |                  | cancel, keeping the coefficients we had           |
+//!
+//! The fit is a least squares line through the points, so zero and full
+//! scale alone give a plain two-point calibration and more points average
+//! out noise. Coefficients are stored in flash and loaded at boot. Until
+//! there are any, samples pass through as raw counts and telemetry says so.
+
+use core::cell::Cell;
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::adcstream;
+use crate::error;
+use crate::info;
+use crate::store;
+
+/// Most points one calibration can capture
+pub const MAX_POINTS: usize = 8;
+
+/// Size of the status characteristic: state, points captured, valid flag,
+/// then gain and offset as f32
+pub const STATUS_SIZE: usize = 11;
+
+/// Size of a command write
+pub const COMMAND_SIZE: usize = 5;
+
+/// Version byte leading the stored record
+const RECORD_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Coefficients {
+    pub gain: f32,
+    pub offset: f32,
+}
+
+impl Coefficients {
+    pub fn apply(&self, raw: i32) -> i32 {
+        (raw as f32 * self.gain + self.offset) as i32
+    }
+
+    fn encode(&self) -> [u8; 9] {
+        let mut out = [0; 9];
+        out[0] = RECORD_VERSION;
+        out[1..5].copy_from_slice(&self.gain.to_le_bytes());
+        out[5..9].copy_from_slice(&self.offset.to_le_bytes());
+        out
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let [RECORD_VERSION, g0, g1, g2, g3, o0, o1, o2, o3] = *data else {
+            return None;
+        };
+        let coefficients = Self {
+            gain: f32::from_le_bytes([g0, g1, g2, g3]),
+            offset: f32::from_le_bytes([o0, o1, o2, o3]),
+        };
+        coefficients.is_sane().then_some(coefficients)
+    }
+
+    fn is_sane(&self) -> bool {
+        self.gain.is_finite() && self.offset.is_finite() && self.gain != 0.0
+    }
+}
+
+/// Least squares line through `(raw, reference)` points. `None` without
+/// at least two distinct raw values.
+pub fn fit(points: &[(i32, i32)]) -> Option<Coefficients> {
+    let n = points.len() as i64;
+    let (mut sx, mut sy, mut sxx, mut sxy) = (0i64, 0i64, 0i64, 0i64);
+    for &(x, y) in points {
+        let (x, y) = (x as i64, y as i64);
+        sx += x;
+        sy += y;
+        sxx += x * x;
+        sxy += x * y;
+    }
+
+    let denominator = n * sxx - sx * sx;
+    if denominator == 0 {
+        return None;
+    }
+    let gain = (n * sxy - sx * sy) as f64 / denominator as f64;
+    let offset = (sy as f64 - gain * sx as f64) / n as f64;
+
+    let coefficients = Coefficients {
+        gain: gain as f32,
+        offset: offset as f32,
+    };
+    coefficients.is_sane().then_some(coefficients)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    Begin,
+    Capture(i32),
+    Finish,
+    Cancel,
+}
+
+impl Command {
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        match *value {
+            [0x01, ..] => Some(Self::Begin),
+            [0x02, r0, r1, r2, r3, ..] => Some(Self::Capture(i32::from_le_bytes([r0, r1, r2, r3]))),
+            [0x03, ..] => Some(Self::Finish),
+            [0x04, ..] => Some(Self::Cancel),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Capture or finish without a begin
+    NotStarted,
+    /// Already [`MAX_POINTS`] captured
+    Full,
+    /// No samples from the ADC yet
+    NoSignal,
+    /// The points don't determine a line
+    Degenerate,
+    Store(store::Error),
+}
+
+struct Session {
+    active: bool,
+    points: [(i32, i32); MAX_POINTS],
+    len: usize,
+}
+
+static SESSION: Mutex<CriticalSectionRawMutex, RefCell<Session>> =
+    Mutex::new(RefCell::new(Session {
+        active: false,
+        points: [(0, 0); MAX_POINTS],
+        len: 0,
+    }));
+
+static COEFFICIENTS: Mutex<CriticalSectionRawMutex, Cell<Option<Coefficients>>> =
+    Mutex::new(Cell::new(None));
+
+/// The coefficients in use, `None` if uncalibrated
+pub fn coefficients() -> Option<Coefficients> {
+    COEFFICIENTS.lock(|c| c.get())
+}
+
+pub fn is_calibrated() -> bool {
+    coefficients().is_some()
+}
+
+/// Calibrate a raw sample, passing it through while uncalibrated
+pub fn apply(raw: i32) -> i32 {
+    match coefficients() {
+        Some(c) => c.apply(raw),
+        None => raw,
+    }
+}
+
+/// Load the stored coefficients. Call once at boot, after [`crate::flash::init`].
+pub async fn load() {
+    let mut record = [0; 9];
+    match store::load(store::Slot::CALIBRATION, &mut record).await {
+        Ok(Some(len)) => match Coefficients::decode(&record[..len]) {
+            Some(c) => {
+                info!("[calibration] loaded {:?}", c);
+                COEFFICIENTS.lock(|cell| cell.set(Some(c)));
+            }
+            None => error!("[calibration] invalid stored record"),
+        },
+        Ok(None) => info!("[calibration] uncalibrated"),
+        Err(e) => error!("[calibration] loading failed: {:?}", e),
+    }
+}
+
+/// Run a step of the calibration workflow
+pub async fn execute(command: Command) -> Result<(), Error> {
+    info!("[calibration] {:?}", command);
+    match command {
+        Command::Begin => SESSION.lock(|session| {
+            let mut session = session.borrow_mut();
+            session.active = true;
+            session.len = 0;
+            Ok(())
+        }),
+        Command::Capture(reference) => {
+            let raw = adcstream::latest().ok_or(Error::NoSignal)?.mean as i32;
+            SESSION.lock(|session| {
+                let mut session = session.borrow_mut();
+                if !session.active {
+                    return Err(Error::NotStarted);
+                }
+                let len = session.len;
+                *session.points.get_mut(len).ok_or(Error::Full)? = (raw, reference);
+                session.len += 1;
+                Ok(())
+            })
+        }
+        Command::Finish => {
+            let coefficients = SESSION.lock(|session| {
+                let session = session.borrow();
+                if !session.active {
+                    return Err(Error::NotStarted);
+                }
+                fit(&session.points[..session.len]).ok_or(Error::Degenerate)
+            })?;
+            store::commit(store::Slot::CALIBRATION, &coefficients.encode())
+                .await
+                .map_err(Error::Store)?;
+
+            info!("[calibration] applying {:?}", coefficients);
+            COEFFICIENTS.lock(|cell| cell.set(Some(coefficients)));
+            SESSION.lock(|session| session.borrow_mut().active = false);
+            Ok(())
+        }
+        Command::Cancel => {
+            SESSION.lock(|session| session.borrow_mut().active = false);
+            Ok(())
+        }
+    }
+}
+
+/// The status characteristic value
+pub fn status() -> [u8; STATUS_SIZE] {
+    let mut out = [0; STATUS_SIZE];
+    SESSION.lock(|session| {
+        let session = session.borrow();
+        out[0] = session.active as u8;
+        out[1] = session.len as u8;
+    });
+    if let Some(c) = coefficients() {
+        out[2] = 1;
+        out[3..7].copy_from_slice(&c.gain.to_le_bytes());
+        out[7..11].copy_from_slice(&c.offset.to_le_bytes());
+    }
+    out
+}
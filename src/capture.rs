@@ -0,0 +1,156 @@
+//! GPIO edge timestamping
+//!
+//! A PIO state machine watches a pin and pushes a timestamp for every
+//! edge, so pulse timings (anemometers, tachometers) come out accurate to
+//! a microsecond no matter how busy the executor is. Polling the pin from
+//! a task only gets as close as its wakeup latency. The weather station's
+//! switches are read this way, see [`crate::weather`].
+//!
+//! The program counts down in `x`, one count every two PIO cycles, with
+//! the clock divided so a count is 1µs. When the counter wraps, the
+//! program falls out of its loop and pushes a spurious all-ones value.
+//! These wrap markers are filtered out here and used to extend the
+//! timestamps to 64 bits.
+
+use embassy_rp::gpio::Pull;
+use embassy_rp::pio;
+use embassy_rp::pio::Common;
+use embassy_rp::pio::Instance;
+use embassy_rp::pio::PioPin;
+use embassy_rp::pio::StateMachine;
+use embassy_time::Duration;
+use fixed::traits::ToFixed;
+use fixed_macro::types::U56F8;
+
+/// Value pushed when the counter wraps
+const WRAP_MARKER: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Edge {
+    /// Microseconds since the capture started
+    pub at_us: u64,
+    pub rising: bool,
+}
+
+pub struct EdgeCapture<'d, PIO: Instance, const SM: usize> {
+    sm: StateMachine<'d, PIO, SM>,
+    /// Level after the last edge; the program starts out assuming low
+    high: bool,
+    last: u32,
+    epoch: u32,
+}
+
+impl<'d, PIO: Instance, const SM: usize> EdgeCapture<'d, PIO, SM> {
+    pub fn new(
+        common: &mut Common<'d, PIO>,
+        mut sm: StateMachine<'d, PIO, SM>,
+        pin: impl PioPin,
+        pull: Pull,
+    ) -> Self {
+        let prg = pio_proc::pio_asm!(
+            "still_high:",
+            "jmp x-- high", // 1 cycle, total 2 per count while high
+            ".wrap_target",
+            "low:",
+            "jmp pin rise",
+            "jmp x-- low", // 2 cycles per count while low
+            "rise:",
+            "in x, 32", // autopush the timestamp of the rising edge
+            "high:",
+            "jmp pin still_high",
+            "in x, 32", // falling edge
+            ".wrap"
+        );
+
+        let mut pin = common.make_pio_pin(pin);
+        pin.set_pull(pull);
+        let program = common.load_program(&prg.program);
+
+        let mut cfg = pio::Config::default();
+        cfg.use_program(&program, &[]);
+        cfg.set_in_pins(&[&pin]);
+        cfg.set_jmp_pin(&pin);
+        cfg.shift_in.auto_fill = true;
+        cfg.shift_in.threshold = 32;
+        // two cycles per count, a count per microsecond
+        cfg.clock_divider = (U56F8!(125_000_000) / 2_000_000).to_fixed();
+
+        sm.set_pin_dirs(pio::Direction::In, &[&pin]);
+        sm.set_config(&cfg);
+        sm.set_enable(true);
+
+        Self {
+            sm,
+            high: false,
+            last: 0,
+            epoch: 0,
+        }
+    }
+
+    /// Wait for the next edge
+    pub async fn next(&mut self) -> Edge {
+        loop {
+            // the counter runs down, flip it so time runs up
+            let raw = self.sm.rx().wait_pull().await;
+            let count = !raw;
+            if count < self.last {
+                self.epoch += 1;
+            }
+            self.last = count;
+
+            if raw == WRAP_MARKER {
+                continue;
+            }
+
+            self.high = !self.high;
+            return Edge {
+                at_us: (self.epoch as u64) << 32 | count as u64,
+                rising: self.high,
+            };
+        }
+    }
+
+    /// Wait for the next rising edge
+    pub async fn next_rising(&mut self) -> Edge {
+        loop {
+            let edge = self.next().await;
+            if edge.rising {
+                return edge;
+            }
+        }
+    }
+}
+
+/// Measures the period of a pulse train from its rising edges
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PulseMeter {
+    last_rise: Option<u64>,
+}
+
+impl PulseMeter {
+    pub const fn new() -> Self {
+        Self { last_rise: None }
+    }
+
+    /// Feed an edge, returning the period once two rising edges have come in
+    pub fn feed(&mut self, edge: Edge) -> Option<Duration> {
+        if !edge.rising {
+            return None;
+        }
+        let period = self
+            .last_rise
+            .map(|last| Duration::from_micros(edge.at_us - last));
+        self.last_rise = Some(edge.at_us);
+        period
+    }
+
+    /// Pulses per minute for `period`, e.g. RPM with one pulse per turn
+    pub fn per_minute(period: Duration) -> u32 {
+        match period.as_micros() {
+            0 => 0,
+            us => (60_000_000 / us) as u32,
+        }
+    }
+}
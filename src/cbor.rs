@@ -0,0 +1,203 @@
+//! Minimal CBOR (RFC 8949)
+//!
+//! Just the types we need: unsigned integers, booleans, byte and text
+//! strings, and definite-length arrays and maps. No floats, tags or
+//! indefinite lengths.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Out of room while encoding, or out of data while decoding
+    Eof,
+    /// Found a different type than expected
+    Type,
+    /// Something we don't support, or not well-formed CBOR
+    Unsupported,
+}
+
+const UINT: u8 = 0;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const SIMPLE: u8 = 7;
+
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+
+pub struct Encoder<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Bytes written so far
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+
+    fn put(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.pos + data.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(Error::Eof)?
+            .copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn head(&mut self, major: u8, arg: u64) -> Result<(), Error> {
+        let major = major << 5;
+        match arg {
+            0..=23 => self.put(&[major | arg as u8]),
+            24..=0xff => self.put(&[major | 24, arg as u8]),
+            0x100..=0xffff => {
+                self.put(&[major | 25])?;
+                self.put(&(arg as u16).to_be_bytes())
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.put(&[major | 26])?;
+                self.put(&(arg as u32).to_be_bytes())
+            }
+            _ => {
+                self.put(&[major | 27])?;
+                self.put(&arg.to_be_bytes())
+            }
+        }
+    }
+
+    pub fn uint(&mut self, value: u64) -> Result<(), Error> {
+        self.head(UINT, value)
+    }
+
+    pub fn bool(&mut self, value: bool) -> Result<(), Error> {
+        self.put(&[if value { TRUE } else { FALSE }])
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> Result<(), Error> {
+        self.head(BYTES, value.len() as u64)?;
+        self.put(value)
+    }
+
+    pub fn text(&mut self, value: &str) -> Result<(), Error> {
+        self.head(TEXT, value.len() as u64)?;
+        self.put(value.as_bytes())
+    }
+
+    /// Start an array of `len` items
+    pub fn array(&mut self, len: usize) -> Result<(), Error> {
+        self.head(ARRAY, len as u64)
+    }
+
+    /// Start a map of `len` key/value pairs
+    pub fn map(&mut self, len: usize) -> Result<(), Error> {
+        self.head(MAP, len as u64)
+    }
+}
+
+pub struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Whether everything has been decoded
+    pub fn is_done(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let (taken, rest) = self.data.split_at_checked(n).ok_or(Error::Eof)?;
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn head(&mut self) -> Result<(u8, u64), Error> {
+        let initial = self.take(1)?[0];
+        let major = initial >> 5;
+        let arg = match initial & 0x1f {
+            n @ 0..=23 => n as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            _ => return Err(Error::Unsupported),
+        };
+        Ok((major, arg))
+    }
+
+    fn expect(&mut self, major: u8) -> Result<u64, Error> {
+        match self.head()? {
+            (m, arg) if m == major => Ok(arg),
+            _ => Err(Error::Type),
+        }
+    }
+
+    pub fn uint(&mut self) -> Result<u64, Error> {
+        self.expect(UINT)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        match self.take(1)?[0] {
+            FALSE => Ok(false),
+            TRUE => Ok(true),
+            _ => Err(Error::Type),
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.expect(BYTES)?;
+        self.take(len as usize)
+    }
+
+    pub fn text(&mut self) -> Result<&'a str, Error> {
+        let len = self.expect(TEXT)?;
+        core::str::from_utf8(self.take(len as usize)?).map_err(|_| Error::Unsupported)
+    }
+
+    /// Start an array, returning its length
+    pub fn array(&mut self) -> Result<usize, Error> {
+        Ok(self.expect(ARRAY)? as usize)
+    }
+
+    /// Start a map, returning its number of pairs
+    pub fn map(&mut self) -> Result<usize, Error> {
+        Ok(self.expect(MAP)? as usize)
+    }
+
+    /// Skip over the next item, nested items included
+    pub fn skip(&mut self) -> Result<(), Error> {
+        let (major, arg) = self.head()?;
+        match major {
+            BYTES | TEXT => {
+                self.take(arg as usize)?;
+            }
+            ARRAY => {
+                for _ in 0..arg {
+                    self.skip()?;
+                }
+            }
+            MAP => {
+                for _ in 0..arg * 2 {
+                    self.skip()?;
+                }
+            }
+            // bool, null and undefined are single bytes, the rest don't
+            // carry data beyond the head
+            UINT | 1 | SIMPLE => {}
+            _ => return Err(Error::Unsupported),
+        }
+        Ok(())
+    }
+}
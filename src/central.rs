@@ -0,0 +1,408 @@
+//! Central role
+//!
+//! Runs next to the peripheral in [`crate::blue::run`], using one extra host
+//! connection, so the device can bridge a sensor to the phones connected to
+//! it. The target is set with `central.name` and/or `central.service`: the
+//! first advertiser matching all of the set ones is connected to, and
+//! `central.notify` is subscribed to in the first service with the
+//! `central.service` UUID (any service if unset). Notifications are
+//! published for subscribers. The same link serves [`crate::proxy`] when
+//! it has slots set. A lost link goes back to scanning.
+//!
+//! Requests of the GATT client go through [`exclusive`], which takes turns
+//! by priority between everything sharing the link.
+//!
+//! Scanning reuses the reports of [`crate::observer`], which the runner
+//! feeds while the central is looking for its target or scanning for
+//! [`crate::beacons`].
+
+use core::convert::Infallible;
+use core::future::Future;
+
+use embassy_futures::select::select;
+use embassy_futures::select::select3;
+use embassy_futures::select::Either;
+use embassy_futures::select::Either3;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::pubsub::Subscriber;
+use embassy_time::with_timeout;
+use embassy_time::Duration;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::adparse::AdStructure;
+use crate::arbiter::Arbiter;
+use crate::arbiter::Priority;
+use crate::beacons;
+use crate::config;
+use crate::config::Text;
+use crate::error;
+use crate::gateway;
+use crate::info;
+use crate::observer;
+use crate::observer::Report;
+use crate::proxy;
+
+crate::config_key!(
+    /// Advertised name of the target, empty for any
+    pub NAME: Text = "central.name",
+    Text::new(""),
+);
+
+crate::config_key!(
+    /// Advertised 16-bit service UUID of the target, 0 for any
+    pub SERVICE: u32 = "central.service",
+    0,
+);
+
+crate::config_key!(
+    /// 16-bit UUID of the characteristic to subscribe to
+    pub NOTIFY: u32 = "central.notify",
+    0,
+);
+
+/// Host connections used by the central, on top of the peripheral's
+pub(crate) const LINKS_MAX: usize = 1;
+
+/// Max number of services discovered on the target
+const SERVICES_MAX: usize = 8;
+
+/// GATT client of the link to the target
+pub(crate) type Client<'a, C> = GattClient<'a, C, SERVICES_MAX, { crate::blue::L2CAP_MTU }>;
+
+/// Takes turns on the link to the target
+static LINK: Arbiter = Arbiter::new(Duration::from_secs(10), Duration::from_secs(5));
+
+/// Run a request of the GATT client once it's `priority`'s turn on the
+/// link, see [`crate::arbiter`]. Waiting or running too long is a timeout.
+pub(crate) async fn exclusive<T, E>(
+    priority: Priority,
+    request: impl Future<Output = Result<T, BleHostError<E>>>,
+) -> Result<T, BleHostError<E>> {
+    match LINK.run(priority, request).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!("[central] {:?} request: {:?}", priority, e);
+            Err(BleHostError::BleHost(Error::Timeout))
+        }
+    }
+}
+
+/// How long a matching advertiser gets to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait after a failed attempt
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How often to look again at beacon scanning while it's off
+const RECHECK_AFTER: Duration = Duration::from_secs(10);
+
+/// Max size of a forwarded notification
+pub const NOTIFICATION_MAX: usize = 64;
+
+/// Max number of queued notifications per subscriber
+const NOTIFICATIONS_CAP: usize = 4;
+
+/// Max number of concurrent subscribers
+const SUBSCRIBERS_MAX: usize = 2;
+
+/// A notification from the target, truncated to [`NOTIFICATION_MAX`]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Notification {
+    len: u8,
+    data: [u8; NOTIFICATION_MAX],
+}
+
+impl Notification {
+    fn new(value: &[u8]) -> Self {
+        let len = value.len().min(NOTIFICATION_MAX);
+        let mut data = [0; NOTIFICATION_MAX];
+        data[..len].copy_from_slice(&value[..len]);
+        Self {
+            len: len as u8,
+            data,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+pub type NotificationSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    Notification,
+    NOTIFICATIONS_CAP,
+    SUBSCRIBERS_MAX,
+    0,
+>;
+
+static NOTIFICATIONS: PubSubChannel<
+    CriticalSectionRawMutex,
+    Notification,
+    NOTIFICATIONS_CAP,
+    SUBSCRIBERS_MAX,
+    0,
+> = PubSubChannel::new();
+
+/// Subscribe to the target's notifications, `None` if all subscriber slots
+/// are in use
+pub fn subscribe() -> Option<NotificationSubscriber> {
+    NOTIFICATIONS.subscriber().ok()
+}
+
+/// Which advertisers to connect to. Every set field has to match.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Filter<'a> {
+    /// Complete or shortened local name
+    pub name: Option<&'a [u8]>,
+    pub uuid16: Option<u16>,
+    /// Little endian, as advertised
+    pub uuid128: Option<[u8; 16]>,
+}
+
+impl Filter<'_> {
+    fn is_empty(&self) -> bool {
+        self.name.is_none() && self.uuid16.is_none() && self.uuid128.is_none()
+    }
+
+    pub fn matches(&self, report: &Report) -> bool {
+        let mut name = self.name.is_none();
+        let mut uuid16 = self.uuid16.is_none();
+        let mut uuid128 = self.uuid128.is_none();
+        for structure in report.structures().flatten() {
+            match structure {
+                AdStructure::Name(n, _) => name |= self.name == Some(n),
+                AdStructure::Uuids16(mut uuids, _) => {
+                    uuid16 |= uuids.any(|u| self.uuid16 == Some(u));
+                }
+                AdStructure::Uuids128(uuids, _) => {
+                    uuid128 |= uuids
+                        .chunks_exact(16)
+                        .any(|u| self.uuid128.is_some_and(|want| want == u));
+                }
+                AdStructure::ServiceData16 { uuid, .. } => uuid16 |= self.uuid16 == Some(uuid),
+                _ => {}
+            }
+        }
+        name && uuid16 && uuid128
+    }
+}
+
+/// Scan until an advertiser matches `filter`
+pub async fn find<C: Controller>(
+    scanner: &mut Scanner<'_, C>,
+    filter: &Filter<'_>,
+) -> Result<Report, BleHostError<C::Error>> {
+    // subscribing first so that no report is missed
+    let Some(mut reports) = observer::subscribe() else {
+        error!("[central] no report subscriber left");
+        return core::future::pending().await;
+    };
+    let config = ScanConfig {
+        active: true,
+        interval: Duration::from_millis(100),
+        window: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let _session = scanner.scan(&config).await?;
+    loop {
+        let report = reports.next_message_pure().await;
+        if filter.matches(&report) {
+            return Ok(report);
+        }
+    }
+}
+
+/// Connect to the advertiser of `report`
+pub async fn connect<'a, C: Controller>(
+    central: &mut Central<'a, C>,
+    report: &Report,
+) -> Result<Connection<'a>, BleHostError<C::Error>> {
+    let config = ConnectConfig {
+        connect_params: Default::default(),
+        scan_config: ScanConfig {
+            filter_accept_list: &[(report.kind, &report.addr)],
+            ..Default::default()
+        },
+    };
+    match with_timeout(CONNECT_TIMEOUT, central.connect(&config)).await {
+        Ok(result) => result,
+        Err(_) => Err(BleHostError::BleHost(Error::Timeout)),
+    }
+}
+
+/// Forward notifications of `characteristic`, found in `services`
+async fn bridge<C: Controller>(
+    client: &Client<'_, C>,
+    services: &[ServiceHandle],
+    characteristic: Uuid,
+) -> Result<Infallible, BleHostError<C::Error>> {
+    let mut found = None;
+    for service in services {
+        let lookup = client.characteristic_by_uuid(service, &characteristic);
+        if let Ok(c) = exclusive(Priority::Normal, lookup).await {
+            found = Some(c);
+            break;
+        }
+    }
+    let Some(found) = found else {
+        info!("[central] target lacks {:?}", characteristic);
+        return Err(BleHostError::BleHost(Error::NotFound));
+    };
+
+    let mut listener = exclusive(Priority::Normal, client.subscribe(&found, false)).await?;
+    info!("[central] subscribed to {:?}", characteristic);
+    loop {
+        let notification = listener.next().await;
+        NOTIFICATIONS
+            .immediate_publisher()
+            .publish_immediate(Notification::new(notification.as_ref()));
+    }
+}
+
+/// Discover the target's services, then forward notifications of
+/// `characteristic` if there is one and serve the proxy, until the link
+/// drops
+pub async fn subscribe_to<C: Controller>(
+    stack: Stack<'_, C>,
+    connection: &Connection<'_>,
+    service: Option<Uuid>,
+    characteristic: Option<Uuid>,
+) -> Result<(), BleHostError<C::Error>> {
+    let client = Client::<C>::new(stack, connection).await?;
+
+    let link = async {
+        let services = match service {
+            Some(uuid) => exclusive(Priority::Normal, client.services_by_uuid(&uuid)).await?,
+            None => exclusive(Priority::Normal, client.services()).await?,
+        };
+        let bridging = async {
+            match characteristic {
+                Some(characteristic) => bridge(&client, &services, characteristic).await,
+                None => core::future::pending().await,
+            }
+        };
+        let proxying = async {
+            if proxy::enabled() {
+                proxy::relay(&client, &services).await
+            } else {
+                core::future::pending().await
+            }
+        };
+        match select(bridging, proxying).await {
+            Either::First(result) | Either::Second(result) => result.map(|never| match never {}),
+        }
+    };
+
+    let result = match select(client.task(), link).await {
+        Either::First(result) => result,
+        Either::Second(result) => result,
+    };
+    proxy::clear();
+    result
+}
+
+/// Wait for a `central.*` or `proxy.*` key to change
+async fn changed(changes: &mut Option<config::ChangeSubscriber>) {
+    let Some(changes) = changes.as_mut() else {
+        core::future::pending::<()>().await;
+        return;
+    };
+    loop {
+        let name = changes.next_message_pure().await;
+        if NAME.is(name) || SERVICE.is(name) || NOTIFY.is(name) || proxy::is_key(name) {
+            return;
+        }
+    }
+}
+
+/// Scan passively for a window every so often, for [`beacons`], forever
+async fn scan_windows<C: Controller>(scanner: &mut Scanner<'_, C>) -> ! {
+    loop {
+        // the gateway wants everything there is to hear
+        let schedule = if gateway::enabled() {
+            Some((RECHECK_AFTER, Duration::from_ticks(0)))
+        } else {
+            beacons::schedule()
+        };
+        let Some((window, period)) = schedule else {
+            Timer::after(RECHECK_AFTER).await;
+            continue;
+        };
+        Timer::after(period).await;
+        let config = ScanConfig {
+            active: false,
+            interval: Duration::from_millis(100),
+            window: Duration::from_millis(50),
+            ..Default::default()
+        };
+        match scanner.scan(&config).await {
+            Ok(_session) => Timer::after(window).await,
+            Err(e) => error!("[central] beacon scan failed: {:?}", e),
+        }
+    }
+}
+
+/// Look for, connect to and bridge the configured target, forever. Beacon
+/// scan windows run whenever we aren't scanning for the target anyway.
+pub async fn run<C: Controller>(stack: Stack<'_, C>, central: Central<'_, C>) -> ! {
+    let mut changes = config::subscribe();
+    let mut scanner = Scanner::new(central);
+    loop {
+        let name = NAME.get();
+        let service = SERVICE.get() as u16;
+        let filter = Filter {
+            name: (!name.is_empty()).then(|| name.as_str().as_bytes()),
+            uuid16: (service != 0).then_some(service),
+            uuid128: None,
+        };
+        let notify = NOTIFY.get() as u16;
+        if filter.is_empty() || (notify == 0 && !proxy::enabled()) {
+            info!("[central] no target set");
+            select(changed(&mut changes), scan_windows(&mut scanner)).await;
+            continue;
+        }
+
+        let report = match select(find(&mut scanner, &filter), changed(&mut changes)).await {
+            Either::First(Ok(report)) => report,
+            Either::First(Err(e)) => {
+                error!("[central] scan failed: {:?}", e);
+                Timer::after(RETRY_DELAY).await;
+                continue;
+            }
+            // new target
+            Either::Second(()) => continue,
+        };
+
+        info!("[central] found {:?}, rssi {}", report.addr, report.rssi);
+        let mut central = scanner.into_inner();
+        let connected = connect(&mut central, &report).await;
+        scanner = Scanner::new(central);
+        let connection = match connected {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("[central] connect to {:?} failed: {:?}", report.addr, e);
+                Timer::after(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        let service = filter.uuid16.map(Uuid::new_short);
+        let notify = (notify != 0).then(|| Uuid::new_short(notify));
+        let bridge = subscribe_to(stack, &connection, service, notify);
+        match select3(bridge, changed(&mut changes), scan_windows(&mut scanner)).await {
+            Either3::First(Ok(())) => {}
+            Either3::First(Err(e)) => {
+                error!("[central] link to {:?} failed: {:?}", report.addr, e);
+                Timer::after(RETRY_DELAY).await;
+            }
+            Either3::Second(()) => info!("[central] target changed"),
+            Either3::Third(never) => never,
+        }
+        connection.disconnect();
+    }
+}
@@ -0,0 +1,280 @@
+//! Wall clock
+//!
+//! Several sources can tell us the time: SNTP over Wi-Fi, a phone writing
+//! the Current Time characteristic, or an RTC. Each report comes with an
+//! uncertainty. Between reports the error grows with the local oscillator
+//! drift, so a fresh, rough source can beat a stale, precise one. We keep
+//! whichever sync currently has the smallest estimated error, and estimate
+//! the local drift from consecutive syncs.
+//!
+//! Local time is UTC moved by `clock.utc_offset`, there's no daylight saving.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use trouble_host::prelude::*;
+
+use crate::error;
+use crate::info;
+use crate::modules;
+use crate::roles;
+use crate::writes;
+
+crate::config_key!(
+    /// Local time's offset from UTC in minutes, east of Greenwich positive
+    pub UTC_OFFSET: i32 = "clock.utc_offset",
+    0,
+);
+
+/// Worst case drift of the crystal when we haven't estimated it yet
+const DEFAULT_DRIFT_PPM: u32 = 50;
+
+/// Residual drift once we have an estimate
+const ESTIMATED_DRIFT_PPM: u32 = 5;
+
+/// Shortest gap between syncs we'll estimate drift over
+const MIN_DRIFT_WINDOW_MS: u64 = 10 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Source {
+    None = 0,
+    Sntp = 1,
+    Cts = 2,
+    Rtc = 3,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sync {
+    source: Source,
+    unix_ms: u64,
+    at: Instant,
+    error_ms: u32,
+}
+
+struct Clock {
+    active: Option<Sync>,
+    /// Estimated local drift in parts per billion, positive when the local
+    /// clock runs slow
+    drift_ppb: i64,
+    drift_known: bool,
+}
+
+static CLOCK: Mutex<CriticalSectionRawMutex, RefCell<Clock>> = Mutex::new(RefCell::new(Clock {
+    active: None,
+    drift_ppb: 0,
+    drift_known: false,
+}));
+
+impl Clock {
+    fn elapsed_ms(sync: &Sync, at: Instant) -> u64 {
+        at.saturating_duration_since(sync.at).as_millis()
+    }
+
+    fn estimate(&self, sync: &Sync, at: Instant) -> (u64, u32) {
+        let elapsed = Self::elapsed_ms(sync, at);
+        let corrected = elapsed as i64 + elapsed as i64 * self.drift_ppb / 1_000_000_000;
+        let drift_ppm = if self.drift_known {
+            ESTIMATED_DRIFT_PPM
+        } else {
+            DEFAULT_DRIFT_PPM
+        };
+        let error = sync.error_ms as u64 + elapsed * drift_ppm as u64 / 1_000_000;
+
+        (
+            sync.unix_ms.saturating_add_signed(corrected),
+            error.min(u32::MAX as u64) as u32,
+        )
+    }
+}
+
+/// A source reporting the current time with an uncertainty
+pub fn report(source: Source, unix_ms: u64, error_ms: u32) {
+    let at = Instant::now();
+    let new = Sync {
+        source,
+        unix_ms,
+        at,
+        error_ms,
+    };
+
+    CLOCK.lock(|clock| {
+        let mut clock = clock.borrow_mut();
+        let Some(active) = clock.active else {
+            info!("[clock] first sync from {:?}, ±{}ms", source, error_ms);
+            clock.active = Some(new);
+            return;
+        };
+
+        let (predicted, current_error) = clock.estimate(&active, at);
+
+        // the same source twice, far enough apart: learn the drift
+        let elapsed = Clock::elapsed_ms(&active, at);
+        if active.source == source && elapsed >= MIN_DRIFT_WINDOW_MS {
+            let uncorrected = active.unix_ms + elapsed;
+            let ppb = (unix_ms as i64 - uncorrected as i64) * 1_000_000_000 / elapsed as i64;
+            clock.drift_ppb = if clock.drift_known {
+                (clock.drift_ppb * 3 + ppb) / 4
+            } else {
+                ppb
+            };
+            clock.drift_known = true;
+            info!("[clock] drift estimate {}ppb", clock.drift_ppb);
+        }
+
+        if error_ms <= current_error {
+            if active.source != source {
+                info!(
+                    "[clock] switching to {:?} (±{}ms) from {:?} (±{}ms)",
+                    source, error_ms, active.source, current_error
+                );
+            }
+            let step = unix_ms as i64 - predicted as i64;
+            if step.unsigned_abs() > 1000 {
+                info!("[clock] stepping by {}ms", step);
+            }
+            clock.active = Some(new);
+        }
+    });
+}
+
+/// Current time in milliseconds since the unix epoch, with the estimated error
+pub fn now() -> Option<(u64, u32)> {
+    CLOCK.lock(|clock| {
+        let clock = clock.borrow();
+        let active = clock.active?;
+        Some(clock.estimate(&active, Instant::now()))
+    })
+}
+
+/// Current local time in milliseconds since the unix epoch
+pub fn local_now() -> Option<u64> {
+    let (unix_ms, _) = now()?;
+    Some(unix_ms.saturating_add_signed(UTC_OFFSET.get() as i64 * 60_000))
+}
+
+/// Days since the unix epoch in local time, changing at local midnight
+pub fn local_day() -> Option<u32> {
+    local_now().map(|ms| (ms / 86_400_000) as u32)
+}
+
+/// The source of the sync in use
+pub fn source() -> Source {
+    CLOCK.lock(|clock| clock.borrow().active.map_or(Source::None, |s| s.source))
+}
+
+/// Size of the clock status value
+pub const STATUS_SIZE: usize = 1 + 4 + 8;
+
+/// little endian: active source, estimated error in ms, unix time in ms
+pub fn status() -> [u8; STATUS_SIZE] {
+    let source = source();
+    let (unix_ms, error_ms) = now().unwrap_or((0, u32::MAX));
+
+    let mut out = [0; STATUS_SIZE];
+    out[0] = source as u8;
+    out[1..5].copy_from_slice(&error_ms.to_le_bytes());
+    out[5..13].copy_from_slice(&unix_ms.to_le_bytes());
+    out
+}
+
+/// Days since the unix epoch for a civil date (proleptic Gregorian)
+pub const fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year } as i64;
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Civil date (year, month, day) for days since the unix epoch, the
+/// inverse of [`days_from_civil`]
+pub const fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as i32;
+    (year, month, day)
+}
+
+/// Parse a Current Time characteristic (0x2A2B) value into unix milliseconds
+fn parse_current_time(value: &[u8]) -> Option<u64> {
+    let year = u16::from_le_bytes([*value.first()?, *value.get(1)?]) as i32;
+    let [month, day, hours, minutes, seconds, _day_of_week, fractions256] =
+        *value.get(2..9)?.first_chunk::<7>()?;
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hours > 23
+        || minutes > 59
+        || seconds > 59
+    {
+        return None;
+    }
+
+    let days = days_from_civil(year, month as u32, day as u32);
+    let secs = days * 86400 + hours as i64 * 3600 + minutes as i64 * 60 + seconds as i64;
+    let ms = u64::try_from(secs).ok()? * 1000 + fractions256 as u64 * 1000 / 256;
+    Some(ms)
+}
+
+const SERVICE_UUID: Uuid = Uuid::Uuid16(0x1805u16.to_le_bytes());
+const CURRENT_TIME_UUID: Uuid = Uuid::Uuid16(0x2a2bu16.to_le_bytes());
+
+fn write_current_time(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let Some(unix_ms) = parse_current_time(value) else {
+        error!("[clock] invalid current time");
+        return Err(writes::AttError::ValueNotAllowed);
+    };
+    // the characteristic has a resolution of 1/256s, but the phone's own
+    // clock is only so good
+    report(Source::Cts, unix_ms, 1000);
+    Ok(())
+}
+
+// the Current Time Service, written by the phone to give us the time
+fn service(svc: &mut modules::Service<'_, '_>) {
+    svc.add(
+        CURRENT_TIME_UUID,
+        &[CharacteristicProp::Write],
+        10,
+        writes::Spec::new()
+            .with_write(write_current_time)
+            .with_access(roles::Access::Configure),
+    );
+}
+
+crate::register_module!(
+    MODULE,
+    "clock",
+    |_| {},
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |_| true,
+        build: service,
+    },
+);
+
+crate::register_command!(TIME, "time", "", "show the time and its source", |args| {
+    args.end()?;
+    match now() {
+        Some((unix_ms, error_ms)) => info!(
+            "{}.{:03} ±{}ms from {:?}",
+            unix_ms / 1000,
+            unix_ms % 1000,
+            error_ms,
+            source()
+        ),
+        None => info!("no time yet"),
+    }
+    Ok(())
+});
@@ -0,0 +1,81 @@
+//! LZSS compression for bulk transfers
+//!
+//! The stream is a sequence of groups, each a flag byte followed by up to
+//! eight items. Flag bit `i` (LSB first) set means item `i` is a literal
+//! byte, clear means it's a two byte back reference:
+//!
+//! ```text
+//! oooooooo oooollll
+//! ```
+//!
+//! where `o` is the distance back (1-based, 12 bits) and `l` is the match
+//! length minus [`MIN_MATCH`]. Only compression lives on the device, the
+//! decoder is trivial to write on the client side.
+
+/// How far back a reference can point
+const WINDOW: usize = 4095;
+
+/// Shortest match worth encoding as a reference
+pub const MIN_MATCH: usize = 3;
+
+/// Longest match a reference can encode
+const MAX_MATCH: usize = MIN_MATCH + 0xf;
+
+/// Worst case size of compressing `len` bytes
+pub const fn max_compressed_len(len: usize) -> usize {
+    len + len.div_ceil(8)
+}
+
+/// Compress `input` into `out`, `None` if `out` is too small
+pub fn compress(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut written = 0;
+
+    while pos < input.len() {
+        let flag_idx = written;
+        *out.get_mut(flag_idx)? = 0;
+        written += 1;
+
+        for bit in 0..8 {
+            if pos >= input.len() {
+                break;
+            }
+
+            let (distance, len) = longest_match(input, pos);
+            if len >= MIN_MATCH {
+                let word = ((distance as u16) << 4) | (len - MIN_MATCH) as u16;
+                out.get_mut(written..written + 2)?
+                    .copy_from_slice(&word.to_be_bytes());
+                written += 2;
+                pos += len;
+            } else {
+                out[flag_idx] |= 1 << bit;
+                *out.get_mut(written)? = input[pos];
+                written += 1;
+                pos += 1;
+            }
+        }
+    }
+
+    Some(written)
+}
+
+/// Find the longest earlier match for `input[pos..]`, as (distance, length)
+fn longest_match(input: &[u8], pos: usize) -> (usize, usize) {
+    let max_len = MAX_MATCH.min(input.len() - pos);
+    let mut best = (0, 0);
+
+    for start in pos.saturating_sub(WINDOW)..pos {
+        let len = (0..max_len)
+            .take_while(|&i| input[start + i] == input[pos + i])
+            .count();
+        if len > best.1 {
+            best = (pos - start, len);
+            if len == max_len {
+                break;
+            }
+        }
+    }
+
+    best
+}
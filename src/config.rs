@@ -0,0 +1,623 @@
+//! Runtime settings
+//!
+//! Settings are typed keys with dotted names, namespaced by the module
+//! that owns them (`wifi.ssid`, `adv.period_ms`). A module declares its
+//! keys with [`config_key!`], which also lists them in the `.config_keys`
+//! linker section (see `sections.x`), so the console and imports know
+//! every key and its type without a central table. A key that was never
+//! set reads as its default, and only keys set away from their default
+//! are kept.
+//!
+//! Setting a key takes effect right away and publishes its name to
+//! [`subscribe`]rs, so the owning module can pick the new value up. The
+//! flash write follows in the config task once changes stop coming, so a
+//! burst of them is one write.
+//!
+//! Stored, exported and imported as `[version, count, entries...]`, each
+//! entry `[name length, name, kind, value length, value]` with numbers
+//! little endian. Keys go by name, so a dump from one firmware loads into
+//! the next; keys it doesn't know are skipped.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::ptr::addr_of;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::pubsub::Subscriber;
+use embassy_sync::signal::Signal;
+use embassy_time::with_timeout;
+use embassy_time::Duration;
+
+use crate::error;
+use crate::fault;
+use crate::info;
+use crate::store;
+
+/// Longest key name
+pub const NAME_MAX: usize = 32;
+
+/// Longest value, enough for a WPA2 passphrase
+pub const VALUE_MAX: usize = 64;
+
+/// Max number of keys set away from their default
+pub const MAX_SET: usize = 16;
+
+const VERSION: u8 = 1;
+
+const ENTRY_MAX: usize = 1 + NAME_MAX + 1 + 1 + VALUE_MAX;
+
+/// Largest export, and stored record
+pub const EXPORT_MAX: usize = 2 + MAX_SET * ENTRY_MAX;
+
+/// How long changes have to stop for before they're written to flash
+const COMMIT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Kind {
+    U32 = 0,
+    I32 = 1,
+    Bool = 2,
+    /// UTF-8, up to [`VALUE_MAX`] bytes
+    Text = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No key by that name
+    Unknown,
+    /// The value doesn't fit the key's type
+    Invalid,
+    /// [`MAX_SET`] keys are set already
+    Full,
+    /// Unknown version or truncated
+    Malformed,
+}
+
+/// A value in its key's encoding
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Raw {
+    buf: [u8; VALUE_MAX],
+    len: u8,
+}
+
+impl Raw {
+    const EMPTY: Self = Self {
+        buf: [0; VALUE_MAX],
+        len: 0,
+    };
+
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        let mut raw = Self::EMPTY;
+        raw.buf.get_mut(..bytes.len())?.copy_from_slice(bytes);
+        raw.len = bytes.len() as u8;
+        Some(raw)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+
+    /// Parse a value typed on the console. Numbers are decimal, booleans
+    /// `on`/`off`, `1`/`0` or `true`/`false`.
+    pub fn parse(kind: Kind, text: &str) -> Option<Self> {
+        match kind {
+            Kind::U32 => text.parse::<u32>().ok().map(|v| v.to_raw()),
+            Kind::I32 => text.parse::<i32>().ok().map(|v| v.to_raw()),
+            Kind::Bool => match text {
+                "on" | "1" | "true" => Some(true.to_raw()),
+                "off" | "0" | "false" => Some(false.to_raw()),
+                _ => None,
+            },
+            Kind::Text => Self::new(text.as_bytes()),
+        }
+    }
+
+    fn valid(&self, kind: Kind) -> bool {
+        match kind {
+            Kind::U32 => u32::from_raw(self).is_some(),
+            Kind::I32 => i32::from_raw(self).is_some(),
+            Kind::Bool => bool::from_raw(self).is_some(),
+            Kind::Text => Text::from_raw(self).is_some(),
+        }
+    }
+
+    /// For logging, as a value of `kind`
+    pub fn show(&self, kind: Kind) -> Shown {
+        Shown(kind, *self)
+    }
+}
+
+pub struct Shown(Kind, Raw);
+
+impl fmt::Display for Shown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self(kind, raw) = self;
+        match kind {
+            Kind::U32 => write!(f, "{}", u32::from_raw(raw).unwrap_or_default()),
+            Kind::I32 => write!(f, "{}", i32::from_raw(raw).unwrap_or_default()),
+            Kind::Bool => write!(f, "{}", bool::from_raw(raw).unwrap_or_default()),
+            Kind::Text => write!(f, "{:?}", Text::from_raw(raw).unwrap_or_default().as_str()),
+        }
+    }
+}
+
+/// Types keys can have
+pub trait Value: Copy {
+    const KIND: Kind;
+    fn to_raw(&self) -> Raw;
+    /// `None` if `raw` isn't a value of this type
+    fn from_raw(raw: &Raw) -> Option<Self>;
+}
+
+impl Value for u32 {
+    const KIND: Kind = Kind::U32;
+
+    fn to_raw(&self) -> Raw {
+        Raw::new(&self.to_le_bytes()).unwrap()
+    }
+
+    fn from_raw(raw: &Raw) -> Option<Self> {
+        raw.bytes().try_into().ok().map(u32::from_le_bytes)
+    }
+}
+
+impl Value for i32 {
+    const KIND: Kind = Kind::I32;
+
+    fn to_raw(&self) -> Raw {
+        Raw::new(&self.to_le_bytes()).unwrap()
+    }
+
+    fn from_raw(raw: &Raw) -> Option<Self> {
+        raw.bytes().try_into().ok().map(i32::from_le_bytes)
+    }
+}
+
+impl Value for bool {
+    const KIND: Kind = Kind::Bool;
+
+    fn to_raw(&self) -> Raw {
+        Raw::new(&[*self as u8]).unwrap()
+    }
+
+    fn from_raw(raw: &Raw) -> Option<Self> {
+        match raw.bytes() {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// A string value, up to [`VALUE_MAX`] bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Text {
+    buf: [u8; VALUE_MAX],
+    len: u8,
+}
+
+impl Text {
+    /// Panics if `text` is longer than [`VALUE_MAX`], at compile time for
+    /// defaults
+    pub const fn new(text: &str) -> Self {
+        let bytes = text.as_bytes();
+        assert!(bytes.len() <= VALUE_MAX);
+        let mut buf = [0; VALUE_MAX];
+        let mut i = 0;
+        while i < bytes.len() {
+            buf[i] = bytes[i];
+            i += 1;
+        }
+        Self {
+            buf,
+            len: bytes.len() as u8,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever built from strings
+        core::str::from_utf8(&self.buf[..self.len as usize]).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for Text {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl Value for Text {
+    const KIND: Kind = Kind::Text;
+
+    fn to_raw(&self) -> Raw {
+        Raw {
+            buf: self.buf,
+            len: self.len,
+        }
+    }
+
+    fn from_raw(raw: &Raw) -> Option<Self> {
+        core::str::from_utf8(raw.bytes()).ok()?;
+        Some(Self {
+            buf: raw.buf,
+            len: raw.len,
+        })
+    }
+}
+
+/// What the registry knows about a key, see [`config_key!`]
+pub struct Entry {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub default: fn() -> Raw,
+}
+
+/// A typed key, declared with [`config_key!`]
+pub struct Key<T> {
+    pub name: &'static str,
+    pub default: T,
+}
+
+impl<T: Value> Key<T> {
+    pub fn get(&self) -> T {
+        let raw = SETTINGS.lock(|settings| settings.borrow().find(self.name));
+        raw.and_then(|raw| T::from_raw(&raw))
+            .unwrap_or(self.default)
+    }
+
+    pub fn set(&self, value: T) -> Result<(), Error> {
+        set(self.name, value.to_raw())
+    }
+
+    /// Whether a name from [`subscribe`] is this key's
+    pub fn is(&self, name: &str) -> bool {
+        self.name == name
+    }
+}
+
+/// Declare a key: `config_key!(pub NAME: u32 = "module.name", 100);`. The
+/// name has to be unique and at most [`NAME_MAX`] long.
+#[macro_export]
+macro_rules! config_key {
+    ($(#[$attr:meta])* $vis:vis $static:ident: $ty:ty = $name:literal, $default:expr $(,)?) => {
+        $(#[$attr])*
+        $vis static $static: $crate::config::Key<$ty> = $crate::config::Key {
+            name: $name,
+            default: $default,
+        };
+
+        const _: () = {
+            assert!($name.len() <= $crate::config::NAME_MAX);
+
+            #[used]
+            #[link_section = ".config_keys"]
+            static ENTRY: $crate::config::Entry = $crate::config::Entry {
+                name: $name,
+                kind: <$ty as $crate::config::Value>::KIND,
+                default: || $crate::config::Value::to_raw(&$static.default),
+            };
+        };
+    };
+}
+
+extern "C" {
+    // bounds of the section, from sections.x
+    static __sconfig_keys: u8;
+    static __econfig_keys: u8;
+}
+
+/// Every declared key
+pub fn entries() -> &'static [Entry] {
+    // SAFETY: the section only holds `Entry`s, placed there by
+    // `config_key!`, and is aligned for them
+    unsafe {
+        let start = addr_of!(__sconfig_keys) as *const Entry;
+        let end = addr_of!(__econfig_keys) as *const Entry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static Entry> {
+    entries().iter().find(|e| e.name == name)
+}
+
+/// Keys set away from their default
+struct Settings {
+    names: [&'static str; MAX_SET],
+    values: [Raw; MAX_SET],
+    len: usize,
+}
+
+impl Settings {
+    fn position(&self, name: &str) -> Option<usize> {
+        self.names[..self.len].iter().position(|&n| n == name)
+    }
+
+    fn find(&self, name: &str) -> Option<Raw> {
+        self.position(name).map(|idx| self.values[idx])
+    }
+
+    fn put(&mut self, entry: &'static Entry, raw: Raw) -> Result<(), Error> {
+        let idx = self.position(entry.name);
+        if raw == (entry.default)() {
+            if let Some(idx) = idx {
+                self.names.copy_within(idx + 1..self.len, idx);
+                self.values.copy_within(idx + 1..self.len, idx);
+                self.len -= 1;
+            }
+            return Ok(());
+        }
+        let idx = match idx {
+            Some(idx) => idx,
+            None if self.len < MAX_SET => {
+                self.len += 1;
+                self.len - 1
+            }
+            None => return Err(Error::Full),
+        };
+        self.names[idx] = entry.name;
+        self.values[idx] = raw;
+        Ok(())
+    }
+}
+
+static SETTINGS: Mutex<CriticalSectionRawMutex, RefCell<Settings>> =
+    Mutex::new(RefCell::new(Settings {
+        names: [""; MAX_SET],
+        values: [Raw::EMPTY; MAX_SET],
+        len: 0,
+    }));
+
+/// Max number of queued changes per subscriber
+const CHANGES_CAP: usize = 4;
+
+/// Max number of concurrent change subscribers
+const SUBSCRIBERS_MAX: usize = 4;
+
+/// Name of a key whose value changed
+pub type ChangeSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, &'static str, CHANGES_CAP, SUBSCRIBERS_MAX, 0>;
+
+static CHANGES: PubSubChannel<
+    CriticalSectionRawMutex,
+    &'static str,
+    CHANGES_CAP,
+    SUBSCRIBERS_MAX,
+    0,
+> = PubSubChannel::new();
+
+/// Changes waiting to be written to flash
+static DIRTY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Subscribe to changes, `None` if all subscriber slots are in use
+pub fn subscribe() -> Option<ChangeSubscriber> {
+    CHANGES.subscriber().ok()
+}
+
+/// A key's current value
+pub fn value(entry: &Entry) -> Raw {
+    SETTINGS
+        .lock(|settings| settings.borrow().find(entry.name))
+        .unwrap_or_else(entry.default)
+}
+
+/// Whether a key is set away from its default
+pub fn is_set(entry: &Entry) -> bool {
+    SETTINGS.lock(|settings| settings.borrow().position(entry.name).is_some())
+}
+
+fn put(entry: &'static Entry, raw: Raw) -> Result<(), Error> {
+    if !raw.valid(entry.kind) {
+        return Err(Error::Invalid);
+    }
+    SETTINGS.lock(|settings| settings.borrow_mut().put(entry, raw))
+}
+
+fn changed(entry: &'static Entry) {
+    info!(
+        "[config] {} = {}",
+        entry.name,
+        value(entry).show(entry.kind)
+    );
+    CHANGES.immediate_publisher().publish_immediate(entry.name);
+    DIRTY.signal(());
+}
+
+/// Set a key by name
+pub fn set(name: &str, raw: Raw) -> Result<(), Error> {
+    let entry = find(name).ok_or(Error::Unknown)?;
+    put(entry, raw)?;
+    changed(entry);
+    Ok(())
+}
+
+/// Put a key back to its default
+pub fn reset(name: &str) -> Result<(), Error> {
+    let entry = find(name).ok_or(Error::Unknown)?;
+    set(name, (entry.default)())
+}
+
+/// Dump the keys that are set, returning the length
+pub fn export(out: &mut [u8; EXPORT_MAX]) -> usize {
+    SETTINGS.lock(|settings| {
+        let settings = settings.borrow();
+        out[0] = VERSION;
+        out[1] = settings.len as u8;
+        let mut at = 2;
+        for (name, raw) in settings
+            .names
+            .iter()
+            .zip(&settings.values)
+            .take(settings.len)
+        {
+            // every name is an entry's
+            let kind = find(name).map_or(Kind::Text, |e| e.kind);
+            out[at] = name.len() as u8;
+            at += 1;
+            out[at..at + name.len()].copy_from_slice(name.as_bytes());
+            at += name.len();
+            out[at] = kind as u8;
+            out[at + 1] = raw.len;
+            at += 2;
+            out[at..at + raw.bytes().len()].copy_from_slice(raw.bytes());
+            at += raw.bytes().len();
+        }
+        at
+    })
+}
+
+/// Walk a dump, calling `f` with each entry's name, kind and value
+fn walk(data: &[u8], mut f: impl FnMut(&[u8], u8, &[u8])) -> Result<(), Error> {
+    let [VERSION, count, ref rest @ ..] = *data else {
+        return Err(Error::Malformed);
+    };
+    let mut rest = rest;
+    for _ in 0..count {
+        let (&name_len, tail) = rest.split_first().ok_or(Error::Malformed)?;
+        let (name, tail) = tail
+            .split_at_checked(name_len as usize)
+            .ok_or(Error::Malformed)?;
+        let [kind, len, ref tail @ ..] = *tail else {
+            return Err(Error::Malformed);
+        };
+        let (value, tail) = tail
+            .split_at_checked(len as usize)
+            .ok_or(Error::Malformed)?;
+        f(name, kind, value);
+        rest = tail;
+    }
+    match rest {
+        [] => Ok(()),
+        _ => Err(Error::Malformed),
+    }
+}
+
+/// The entry and value for a dumped key, if we know it with that kind
+fn accept(name: &[u8], kind: u8, value: &[u8]) -> Option<(&'static Entry, Raw)> {
+    let entry = find(core::str::from_utf8(name).ok()?)?;
+    let raw = Raw::new(value)?;
+    (entry.kind as u8 == kind && raw.valid(entry.kind)).then_some((entry, raw))
+}
+
+/// Set every key in a dump, returning how many were. Keys it doesn't
+/// mention are left alone, unknown ones are skipped.
+pub fn import(data: &[u8]) -> Result<usize, Error> {
+    // don't apply half of a broken dump
+    walk(data, |_, _, _| {})?;
+
+    let mut count = 0;
+    let mut result = Ok(());
+    walk(data, |name, kind, value| {
+        let Some((entry, raw)) = accept(name, kind, value) else {
+            error!("[config] skipping {:?}", core::str::from_utf8(name));
+            return;
+        };
+        match put(entry, raw) {
+            Ok(()) => {
+                changed(entry);
+                count += 1;
+            }
+            Err(e) => result = Err(e),
+        }
+    })?;
+    result.map(|()| count)
+}
+
+/// Load the settings from flash. Call once at boot, before anything reads
+/// them.
+pub async fn load() {
+    let mut record = [0; EXPORT_MAX];
+    match store::load(store::Slot::CONFIG, &mut record).await {
+        Ok(Some(len)) => {
+            let loaded = walk(&record[..len], |name, kind, value| {
+                match accept(name, kind, value).map(|(entry, raw)| put(entry, raw)) {
+                    Some(Ok(())) => {}
+                    _ => error!("[config] dropping {:?}", core::str::from_utf8(name)),
+                }
+            });
+            match loaded {
+                Ok(()) => info!(
+                    "[config] {} keys set",
+                    SETTINGS.lock(|settings| settings.borrow().len)
+                ),
+                Err(e) => {
+                    error!("[config] invalid stored record: {:?}", e);
+                    fault::raise(fault::CONFIG_INVALID);
+                }
+            }
+        }
+        Ok(None) => info!("[config] defaults"),
+        Err(e) => error!("[config] loading failed: {:?}", e),
+    }
+}
+
+/// Write changes to flash once they settle
+pub async fn run() -> ! {
+    loop {
+        DIRTY.wait().await;
+        while with_timeout(COMMIT_DELAY, DIRTY.wait()).await.is_ok() {}
+
+        let mut record = [0; EXPORT_MAX];
+        let len = export(&mut record);
+        if let Err(e) = store::commit(store::Slot::CONFIG, &record[..len]).await {
+            error!("[config] storing failed: {:?}", e);
+            fault::raise(fault::CONFIG_STORE);
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn task() -> ! {
+    run().await
+}
+
+crate::register_module!(MODULE, "config", |resources| {
+    resources.spawner.must_spawn(task())
+});
+
+fn show_entry(entry: &Entry) {
+    let marker = if is_set(entry) { "*" } else { "" };
+    info!(
+        "{}{} = {}",
+        entry.name,
+        marker,
+        value(entry).show(entry.kind)
+    );
+}
+
+crate::register_command!(
+    CONFIG,
+    "config",
+    "[key [value|default]]",
+    "list, show or set settings, * marks ones set",
+    |args| {
+        let Some(name) = args.opt_str() else {
+            entries().iter().for_each(show_entry);
+            return Ok(());
+        };
+        let entry = find(name).ok_or(crate::console::Error::Invalid("key"))?;
+        let Some(text) = args.opt_str() else {
+            show_entry(entry);
+            return Ok(());
+        };
+        args.end()?;
+        let raw = match text {
+            "default" => (entry.default)(),
+            _ => Raw::parse(entry.kind, text).ok_or(crate::console::Error::Invalid("value"))?,
+        };
+        set(entry.name, raw).map_err(|e| {
+            error!("[config] {}: {:?}", entry.name, e);
+            crate::console::Error::Failed
+        })
+    },
+);
@@ -0,0 +1,141 @@
+//! Connection diagnostics
+//!
+//! [`query`] reads the state of a live connection: signal strength, PHY,
+//! ATT MTU and the connection parameters. [`sample`] does that
+//! periodically and publishes the results on the event bus for the display
+//! and diagnostics code.
+
+use bt_hci::cmd::le::LeReadPhy;
+use bt_hci::cmd::le::LeSetPhy;
+use bt_hci::controller::ControllerCmdAsync;
+use bt_hci::controller::ControllerCmdSync;
+use bt_hci::param::PhyKind;
+use embassy_futures::select::select;
+use embassy_time::Duration;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::error;
+use crate::events;
+use crate::events::Event;
+use crate::latency;
+use crate::session;
+
+/// How often [`sample`] takes a reading
+pub const SAMPLE_PERIOD: Duration = Duration::from_secs(5);
+
+/// A controller that can answer all of [`query`], and switch PHYs for
+/// [`crate::boost`]
+pub trait InfoController:
+    Controller + ControllerCmdSync<LeReadPhy> + ControllerCmdAsync<LeSetPhy>
+{
+}
+
+impl<C: Controller + ControllerCmdSync<LeReadPhy> + ControllerCmdAsync<LeSetPhy>> InfoController
+    for C
+{
+}
+
+/// Connection parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Params {
+    /// Upper bound of the connection interval
+    pub interval: Duration,
+    /// Connection events the peripheral may skip
+    pub latency: u16,
+    pub supervision_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnectionInfo {
+    pub rssi: i8,
+    pub tx_phy: PhyKind,
+    pub rx_phy: PhyKind,
+    pub att_mtu: u16,
+    /// The last parameters the central accepted, `None` until we've asked
+    /// for any
+    pub params: Option<Params>,
+}
+
+static PARAMS: session::PerConnection<Option<Params>> = session::PerConnection::new(None);
+
+/// ATT MTU before an exchange
+const MTU_DEFAULT: u16 = 23;
+
+static MTU: session::PerConnection<u16> = session::PerConnection::new(MTU_DEFAULT);
+
+static LAST: session::PerConnection<Option<ConnectionInfo>> = session::PerConnection::new(None);
+
+/// Publish [`Event::MtuChanged`] if the ATT MTU of `conn` changed since
+/// the last call. The host doesn't tell us about exchanges, so this is
+/// called on every access and sample.
+pub(crate) fn note_mtu(conn: &Connection<'_>) {
+    let mtu = conn.att_mtu();
+    if MTU.get(conn.handle()) != mtu {
+        MTU.set(conn.handle(), mtu);
+        events::publish(Event::MtuChanged(conn.handle(), mtu));
+    }
+}
+
+/// Remember parameters the central accepted for `conn`
+pub(crate) fn record_params(conn: ConnHandle, params: &ConnectParams) -> Params {
+    let params = Params {
+        interval: params.max_connection_interval,
+        latency: params.max_latency,
+        supervision_timeout: params.supervision_timeout,
+    };
+    PARAMS.set(conn, Some(params));
+    params
+}
+
+/// The last parameters the central accepted for `conn`, `None` until we've
+/// asked for any
+pub fn params(conn: ConnHandle) -> Option<Params> {
+    PARAMS.get(conn)
+}
+
+/// The last reading [`sample`] took of `conn`, `None` before the first
+pub fn last(conn: ConnHandle) -> Option<ConnectionInfo> {
+    LAST.get(conn)
+}
+
+/// Read the current state of `conn`
+pub async fn query<C: InfoController>(
+    stack: Stack<'_, C>,
+    conn: &Connection<'_>,
+) -> Result<ConnectionInfo, BleHostError<C::Error>> {
+    let rssi = conn.rssi(&stack).await?;
+    let phy = stack.command(LeReadPhy::new(conn.handle())).await?;
+
+    Ok(ConnectionInfo {
+        rssi,
+        tx_phy: phy.tx_phy,
+        rx_phy: phy.rx_phy,
+        att_mtu: conn.att_mtu(),
+        params: params(conn.handle()),
+    })
+}
+
+/// Publish a [`ConnectionInfo`] for `conn` every [`SAMPLE_PERIOD`] until
+/// it disconnects
+pub async fn sample<C: InfoController>(stack: Stack<'_, C>, conn: &Connection<'_>) {
+    let sampling = async {
+        loop {
+            monitor::CONNINFO.ping();
+            Timer::after(SAMPLE_PERIOD).await;
+            note_mtu(conn);
+            match query(stack, conn).await {
+                Ok(info) => {
+                    LAST.set(conn.handle(), Some(info));
+                    events::publish(Event::ConnectionInfo(conn.handle(), info));
+                }
+                Err(e) => error!("[conninfo] query failed: {:?}", e),
+            }
+        }
+    };
+
+    select(sampling, latency::wait_disconnected(conn)).await;
+    monitor::CONNINFO.pause();
+}
@@ -0,0 +1,273 @@
+//! USB serial console
+//!
+//! Lines typed into the USB serial port that carries the logs are run as
+//! commands and the replies are logged. Terminals need local echo on, we
+//! don't echo back.
+//!
+//! Modules contribute their own commands with [`register_command!`], which
+//! puts them in the `.commands` linker section (see `sections.x`) next to
+//! each other, so there's no central list to edit. A command has a usage
+//! string for its arguments along with its help, and gets the rest of the
+//! line as [`Args`] to parse. Handlers run right in the USB task, so they
+//! have to be quick. Slow work gets handed to the task that does it.
+//!
+//! Tab lists the commands starting with what was typed so far, and
+//! completes the name if only one does.
+//!
+//! The serial port belongs to the USB logger, so there's only a console in
+//! builds logging with the `log` feature.
+
+#[cfg(feature = "log")]
+use core::cell::RefCell;
+#[cfg(feature = "log")]
+use core::fmt::Write;
+use core::ptr::addr_of;
+use core::str::SplitAsciiWhitespace;
+
+#[cfg(feature = "log")]
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(feature = "log")]
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::error;
+#[cfg(feature = "log")]
+use crate::fmtbuf;
+use crate::info;
+
+/// Longest line, the rest is dropped
+#[cfg(feature = "log")]
+const LINE_MAX: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// A required argument wasn't given, by name
+    Missing(&'static str),
+    /// An argument didn't parse, by name
+    Invalid(&'static str),
+    /// More arguments than the command takes
+    Extra,
+    /// The command ran and failed, it logged why
+    Failed,
+}
+
+pub struct Command {
+    pub name: &'static str,
+    /// The arguments, like `<channel> on|off`
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub handler: fn(&mut Args) -> Result<(), Error>,
+}
+
+/// Register a command: `register_command!(NAME, "name", "<arg>", "help",
+/// |args| ...)`. The closure can't capture anything.
+#[macro_export]
+macro_rules! register_command {
+    ($static:ident, $name:expr, $usage:expr, $help:expr, $handler:expr $(,)?) => {
+        #[used]
+        #[link_section = ".commands"]
+        static $static: $crate::console::Command = $crate::console::Command {
+            name: $name,
+            usage: $usage,
+            help: $help,
+            handler: $handler,
+        };
+    };
+}
+
+/// A command's arguments, split on whitespace
+pub struct Args<'a>(SplitAsciiWhitespace<'a>);
+
+impl<'a> Args<'a> {
+    pub fn str(&mut self, name: &'static str) -> Result<&'a str, Error> {
+        self.0.next().ok_or(Error::Missing(name))
+    }
+
+    pub fn opt_str(&mut self) -> Option<&'a str> {
+        self.0.next()
+    }
+
+    pub fn u32(&mut self, name: &'static str) -> Result<u32, Error> {
+        self.str(name)?.parse().map_err(|_| Error::Invalid(name))
+    }
+
+    pub fn i32(&mut self, name: &'static str) -> Result<i32, Error> {
+        self.str(name)?.parse().map_err(|_| Error::Invalid(name))
+    }
+
+    /// `on`/`off`, `1`/`0` or `true`/`false`
+    pub fn bool(&mut self, name: &'static str) -> Result<bool, Error> {
+        match self.str(name)? {
+            "on" | "1" | "true" => Ok(true),
+            "off" | "0" | "false" => Ok(false),
+            _ => Err(Error::Invalid(name)),
+        }
+    }
+
+    /// Fail if anything's left
+    pub fn end(&mut self) -> Result<(), Error> {
+        match self.0.next() {
+            Some(_) => Err(Error::Extra),
+            None => Ok(()),
+        }
+    }
+}
+
+extern "C" {
+    // bounds of the section, from sections.x
+    static __scommands: u8;
+    static __ecommands: u8;
+}
+
+/// Every registered command
+pub fn commands() -> &'static [Command] {
+    // SAFETY: the section only holds `Command`s, placed there by
+    // `register_command!`, and is aligned for them
+    unsafe {
+        let start = addr_of!(__scommands) as *const Command;
+        let end = addr_of!(__ecommands) as *const Command;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Run a command line
+pub fn execute(line: &str) {
+    let mut words = line.split_ascii_whitespace();
+    let Some(name) = words.next() else {
+        return;
+    };
+    let Some(command) = commands().iter().find(|c| c.name == name) else {
+        error!("unknown command {:?}, try help", name);
+        return;
+    };
+    let mut args = Args(words);
+    match (command.handler)(&mut args) {
+        Ok(()) => {}
+        Err(Error::Failed) => {}
+        Err(e) => error!("{:?}, usage: {} {}", e, command.name, command.usage),
+    }
+}
+
+/// List the commands starting with `prefix`, returning the only one if
+/// there's exactly one
+#[cfg(feature = "log")]
+fn complete(prefix: &str) -> Option<&'static Command> {
+    let mut matches = commands().iter().filter(|c| c.name.starts_with(prefix));
+    let first = matches.next()?;
+    let Some(second) = matches.next() else {
+        return Some(first);
+    };
+    let mut list = fmtbuf::Buf::<LINE_MAX>::new();
+    let _ = write!(list, "{} {}", first.name, second.name);
+    for command in matches {
+        let _ = write!(list, " {}", command.name);
+    }
+    if list.is_truncated() {
+        info!("{}...", list);
+    } else {
+        info!("{}", list);
+    }
+    None
+}
+
+#[cfg(feature = "log")]
+struct Line {
+    buf: [u8; LINE_MAX],
+    len: usize,
+}
+
+#[cfg(feature = "log")]
+static LINE: Mutex<CriticalSectionRawMutex, RefCell<Line>> = Mutex::new(RefCell::new(Line {
+    buf: [0; LINE_MAX],
+    len: 0,
+}));
+
+/// Take a byte of input, returning a finished line
+#[cfg(feature = "log")]
+fn feed(byte: u8) -> Option<([u8; LINE_MAX], usize)> {
+    LINE.lock(|line| {
+        let mut line = line.borrow_mut();
+        match byte {
+            b'\r' | b'\n' => {
+                let len = core::mem::take(&mut line.len);
+                return Some((line.buf, len));
+            }
+            // backspace and delete
+            0x08 | 0x7f => line.len = line.len.saturating_sub(1),
+            _ if line.len < LINE_MAX => {
+                let len = line.len;
+                line.buf[len] = byte;
+                line.len += 1;
+            }
+            _ => {}
+        }
+        None
+    })
+}
+
+/// Complete the command name typed so far
+#[cfg(feature = "log")]
+fn tab() {
+    let (buf, len) = LINE.lock(|line| {
+        let line = line.borrow();
+        (line.buf, line.len)
+    });
+    let Ok(text) = core::str::from_utf8(&buf[..len]) else {
+        return;
+    };
+    // only the command name completes
+    if text.contains(' ') {
+        return;
+    }
+    let Some(command) = complete(text) else {
+        return;
+    };
+    let name = command.name.as_bytes();
+    if name.len() < LINE_MAX {
+        LINE.lock(|line| {
+            let mut line = line.borrow_mut();
+            line.buf[..name.len()].copy_from_slice(name);
+            line.buf[name.len()] = b' ';
+            line.len = name.len() + 1;
+        });
+    }
+    info!("{} {}", command.name, command.usage);
+}
+
+/// Takes what comes in on the USB logger's serial port
+#[cfg(feature = "log")]
+pub struct Handler;
+
+#[cfg(feature = "log")]
+impl embassy_usb_logger::ReceiverHandler for Handler {
+    async fn handle_data(&self, data: &[u8]) {
+        for &byte in data {
+            if byte == b'\t' {
+                tab();
+                continue;
+            }
+            let Some((buf, len)) = feed(byte) else {
+                continue;
+            };
+            match core::str::from_utf8(&buf[..len]) {
+                Ok(line) => execute(line),
+                Err(_) => error!("not UTF-8"),
+            }
+        }
+    }
+
+    fn new() -> Self {
+        Self
+    }
+}
+
+crate::register_command!(HELP, "help", "[command]", "list the commands", |args| {
+    let wanted = args.opt_str();
+    args.end()?;
+    for command in commands() {
+        if wanted.map_or(true, |name| name == command.name) {
+            info!("{} {} - {}", command.name, command.usage, command.help);
+        }
+    }
+    Ok(())
+});
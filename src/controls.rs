@@ -0,0 +1,109 @@
+//! Lighting controls
+//!
+//! The single description of the lighting controls, shared by the GATT
+//! service and the HTTP API so the two can't drift apart. Only these are
+//! on the HTTP API, see [`crate::http`] for why. Each control
+//! keeps its last accepted value for state snapshots, and changes are
+//! published for anyone streaming them.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::pubsub::Subscriber;
+
+use crate::lighting::Message;
+use crate::Color;
+
+/// Longest value any control takes
+pub const MAX_LEN: usize = 3;
+
+pub struct Control {
+    /// Name, also used to derive the characteristic UUID
+    pub name: &'static str,
+    /// Length of the value in bytes
+    pub len: usize,
+    parse: fn(&[u8]) -> Message,
+}
+
+impl Control {
+    /// Name with spaces replaced by underscores, for URLs and JSON keys
+    pub fn key_matches(&self, key: &str) -> bool {
+        self.name.len() == key.len()
+            && self
+                .name
+                .bytes()
+                .zip(key.bytes())
+                .all(|(n, k)| n == k || (n == b' ' && k == b'_'))
+    }
+}
+
+pub const CONTROLS: [Control; 3] = [
+    Control {
+        name: "base color",
+        len: 3,
+        parse: |v| Message::SetColor(Color::new(v[0], v[1], v[2])),
+    },
+    Control {
+        name: "brightness",
+        len: 1,
+        parse: |v| Message::SetBrightness(v[0]),
+    },
+    Control {
+        name: "skip",
+        len: 1,
+        parse: |v| Message::SetSkip(v[0]),
+    },
+];
+
+pub const BASE_COLOR: usize = 0;
+pub const BRIGHTNESS: usize = 1;
+pub const SKIP: usize = 2;
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<[[u8; MAX_LEN]; CONTROLS.len()]>> =
+    Mutex::new(RefCell::new([[0; MAX_LEN]; CONTROLS.len()]));
+
+/// Max number of queued changes per subscriber
+const CHANGES_CAP: usize = 4;
+
+/// Max number of concurrent change subscribers
+const CHANGE_SUBSCRIBERS_MAX: usize = 2;
+
+/// Index of a control whose value changed
+pub type ChangeSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, usize, CHANGES_CAP, CHANGE_SUBSCRIBERS_MAX, 0>;
+
+static CHANGES: PubSubChannel<
+    CriticalSectionRawMutex,
+    usize,
+    CHANGES_CAP,
+    CHANGE_SUBSCRIBERS_MAX,
+    0,
+> = PubSubChannel::new();
+
+/// Subscribe to control changes, `None` if all subscriber slots are in use
+pub fn subscribe() -> Option<ChangeSubscriber> {
+    CHANGES.subscriber().ok()
+}
+
+/// Look a control up by its key
+pub fn find(key: &str) -> Option<usize> {
+    CONTROLS.iter().position(|c| c.key_matches(key))
+}
+
+/// Validate a write to control `idx`, remembering the value and returning
+/// the message for the lighting task. `None` if the value is malformed.
+pub fn apply(idx: usize, value: &[u8]) -> Option<Message> {
+    let control = CONTROLS.get(idx)?;
+    let value = value.get(..control.len)?;
+
+    STATE.lock(|state| state.borrow_mut()[idx][..control.len].copy_from_slice(value));
+    CHANGES.immediate_publisher().publish_immediate(idx);
+    Some((control.parse)(value))
+}
+
+/// The last accepted value of control `idx`
+pub fn value(idx: usize) -> [u8; MAX_LEN] {
+    STATE.lock(|state| state.borrow()[idx])
+}
@@ -0,0 +1,188 @@
+//! Crash capture
+//!
+//! Panics and HardFaults leave a report in a RAM section the runtime
+//! doesn't initialize, so it survives the reset that follows. On the next
+//! boot [`recover`] moves it to flash, where the latest crash stays until
+//! another one replaces it, and the crash characteristics serve it in
+//! pages. The PC and LR symbolize against the `.elf` with `addr2line`.
+//!
+//! Encoded little endian as:
+//!
+//! | bytes   | content                                          |
+//! |---------|--------------------------------------------------|
+//! | 0       | kind: 1 panic, 2 HardFault                       |
+//! | 1..5    | uptime in ms                                     |
+//! | 5..9    | PC (HardFault only)                              |
+//! | 9..13   | LR (HardFault only)                              |
+//! | 13..17  | xPSR (HardFault only)                            |
+//! | 17..21  | SP of the stacked frame (HardFault only)         |
+//! | 21..41  | stacked r0, r1, r2, r3, r12 (HardFault only)     |
+//! | 41..45  | panic line                                       |
+//! | 45      | panic file length, then [`FILE_LEN`] bytes       |
+//! | 78      | panic message length, then [`MESSAGE_LEN`] bytes |
+
+use core::cell::Cell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+
+use cortex_m_rt::ExceptionFrame;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+
+use crate::error;
+use crate::fault;
+use crate::fmtbuf;
+use crate::info;
+use crate::integrity;
+use crate::paging;
+use crate::store;
+
+/// Panic file bytes kept, from the end of the path
+pub const FILE_LEN: usize = 32;
+
+/// Panic message bytes kept
+pub const MESSAGE_LEN: usize = 44;
+
+/// Size of an encoded report
+pub const ENCODED_SIZE: usize = 46 + FILE_LEN + 1 + MESSAGE_LEN;
+
+const MAGIC: u32 = 0xdead_c0de;
+
+const KIND_PANIC: u8 = 1;
+const KIND_FAULT: u8 = 2;
+
+#[repr(C)]
+struct Saved {
+    magic: u32,
+    report: [u8; ENCODED_SIZE],
+    crc: u32,
+}
+
+#[link_section = ".uninit.crash"]
+static mut SAVED: MaybeUninit<Saved> = MaybeUninit::uninit();
+
+fn new_report(kind: u8) -> [u8; ENCODED_SIZE] {
+    let mut report = [0; ENCODED_SIZE];
+    report[0] = kind;
+    report[1..5].copy_from_slice(&(Instant::now().as_millis() as u32).to_le_bytes());
+    report
+}
+
+fn save(report: [u8; ENCODED_SIZE]) {
+    // SAFETY: only the panic and fault handlers write this, and nothing
+    // runs after them but the reset
+    unsafe {
+        addr_of_mut!(SAVED).write(MaybeUninit::new(Saved {
+            magic: MAGIC,
+            report,
+            crc: integrity::crc32(&report),
+        }));
+    }
+}
+
+/// Record a panic, for the panic handler
+pub fn record_panic(info: &PanicInfo) {
+    let mut report = new_report(KIND_PANIC);
+    if let Some(location) = info.location() {
+        report[41..45].copy_from_slice(&location.line().to_le_bytes());
+        let file = location.file().as_bytes();
+        let file = &file[file.len().saturating_sub(FILE_LEN)..];
+        report[45] = file.len() as u8;
+        report[46..46 + file.len()].copy_from_slice(file);
+    }
+
+    let at = 46 + FILE_LEN;
+    let mut message = fmtbuf::Writer::new(&mut report[at + 1..]);
+    let _ = fmt::write(&mut message, format_args!("{}", info.message()));
+    report[at] = message.len() as u8;
+
+    save(report);
+}
+
+/// Record a HardFault from its stacked exception frame
+pub fn record_fault(frame: &ExceptionFrame) {
+    let mut report = new_report(KIND_FAULT);
+    let words = [
+        frame.pc(),
+        frame.lr(),
+        frame.xpsr(),
+        frame as *const ExceptionFrame as u32,
+        frame.r0(),
+        frame.r1(),
+        frame.r2(),
+        frame.r3(),
+        frame.r12(),
+    ];
+    for (i, word) in words.iter().enumerate() {
+        report[5 + 4 * i..9 + 4 * i].copy_from_slice(&word.to_le_bytes());
+    }
+
+    save(report);
+}
+
+#[cortex_m_rt::exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    record_fault(frame);
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+static LAST: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; ENCODED_SIZE]>>> =
+    Mutex::new(Cell::new(None));
+
+/// The last crash report, `None` if there never was one
+pub fn last() -> Option<[u8; ENCODED_SIZE]> {
+    LAST.lock(|last| last.get())
+}
+
+/// Move a crash report left by the last reset to flash, and load the
+/// latest one. Call once at boot, after [`crate::flash::init`].
+pub async fn recover() {
+    // SAFETY: the handlers that write this can't have run yet
+    let saved = unsafe { addr_of_mut!(SAVED).read().assume_init() };
+    if saved.magic == MAGIC && integrity::crc32(&saved.report) == saved.crc {
+        error!(
+            "[crash] crashed before the last reset, kind {}",
+            saved.report[0]
+        );
+        fault::raise(fault::CRASHED);
+        // SAFETY: as above
+        unsafe { addr_of_mut!(SAVED).cast::<u32>().write(0) };
+        if let Err(e) = store::commit(store::Slot::CRASH, &saved.report).await {
+            error!("[crash] storing the report failed: {:?}", e);
+        }
+        LAST.lock(|last| last.set(Some(saved.report)));
+        return;
+    }
+
+    let mut report = [0; ENCODED_SIZE];
+    match store::load(store::Slot::CRASH, &mut report).await {
+        Ok(Some(ENCODED_SIZE)) => {
+            info!("[crash] last crash report loaded");
+            LAST.lock(|last| last.set(Some(report)));
+        }
+        Ok(_) => {}
+        Err(e) => error!("[crash] loading the report failed: {:?}", e),
+    }
+}
+
+/// The last crash report as a paged dataset, empty if there never was one
+pub struct Dataset;
+
+impl paging::Dataset for Dataset {
+    fn len(&self) -> usize {
+        last().map_or(0, |report| report.len())
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let Some(report) = last() else {
+            return 0;
+        };
+        let rest = report.get(offset..).unwrap_or(&[]);
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        n
+    }
+}
@@ -0,0 +1,158 @@
+//! Block-level delta patches
+//!
+//! A patch rebuilds a new image from the running one. It's a sequence of
+//! operations, all integers little endian:
+//!
+//! | op     | layout                               | effect                          |
+//! |--------|--------------------------------------|---------------------------------|
+//! | `0x01` | `offset: u32, len: u32`              | copy `len` bytes of the old image from `offset` |
+//! | `0x02` | `len: u16`, then `len` bytes         | insert the bytes as they are     |
+//!
+//! Patches arrive in arbitrary chunks, so [`Patcher`] is a streaming
+//! decoder: give it what's come in of the patch and room for the new
+//! image, and it fills as much as it can. [`crate::dfu`] takes patches as
+//! updates of the firmware, patched against the running image.
+
+const OP_COPY: u8 = 0x01;
+const OP_INSERT: u8 = 0x02;
+
+/// Size of the largest op header, op byte included
+const MAX_HEADER: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Unknown op byte
+    InvalidOp(u8),
+    /// A copy reaches outside the old image
+    OutOfBounds,
+    /// The patch ended in the middle of an op
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    /// Reading an op header into `header`
+    Header,
+    /// Copying `remaining` bytes of the old image from `offset`
+    Copy { offset: usize, remaining: usize },
+    /// Passing through `remaining` literal bytes
+    Insert { remaining: usize },
+}
+
+pub struct Patcher<'a> {
+    old: &'a [u8],
+    state: State,
+    header: [u8; MAX_HEADER],
+    header_len: usize,
+    /// Bytes of new image produced so far
+    written: usize,
+}
+
+impl<'a> Patcher<'a> {
+    /// Start patching against `old`, the image the patch was built from
+    pub const fn new(old: &'a [u8]) -> Self {
+        Self {
+            old,
+            state: State::Header,
+            header: [0; MAX_HEADER],
+            header_len: 0,
+            written: 0,
+        }
+    }
+
+    /// Bytes of new image produced so far
+    pub const fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Decode what fits of `patch`, the next bytes of the patch, into
+    /// `out`. Returns the bytes of the patch taken and of the new image
+    /// produced. A copy can go on after the patch is all taken, so call
+    /// again with the rest, or nothing, while `out` comes back full.
+    pub fn decode(&mut self, patch: &[u8], out: &mut [u8]) -> Result<(usize, usize), Error> {
+        let mut taken = 0;
+        let mut produced = 0;
+        while produced < out.len() {
+            match self.state {
+                State::Copy { offset, remaining } => {
+                    let n = remaining.min(out.len() - produced);
+                    out[produced..produced + n].copy_from_slice(&self.old[offset..offset + n]);
+                    produced += n;
+                    self.state = match remaining - n {
+                        0 => State::Header,
+                        remaining => State::Copy {
+                            offset: offset + n,
+                            remaining,
+                        },
+                    };
+                }
+                State::Insert { remaining } => {
+                    let n = remaining.min(out.len() - produced).min(patch.len() - taken);
+                    if n == 0 {
+                        break;
+                    }
+                    out[produced..produced + n].copy_from_slice(&patch[taken..taken + n]);
+                    taken += n;
+                    produced += n;
+                    self.state = match remaining - n {
+                        0 => State::Header,
+                        remaining => State::Insert { remaining },
+                    };
+                }
+                State::Header => {
+                    let Some(&byte) = patch.get(taken) else {
+                        break;
+                    };
+                    self.header[self.header_len] = byte;
+                    self.header_len += 1;
+                    taken += 1;
+                    self.parse_header()?;
+                }
+            }
+        }
+        self.written += produced;
+        Ok((taken, produced))
+    }
+
+    /// Check the patch didn't stop in the middle of an op
+    pub fn finish(&self) -> Result<usize, Error> {
+        match self.state {
+            State::Header if self.header_len == 0 => Ok(self.written),
+            _ => Err(Error::Truncated),
+        }
+    }
+
+    fn parse_header(&mut self) -> Result<(), Error> {
+        let header = &self.header[..self.header_len];
+        match header[0] {
+            OP_COPY if header.len() == 9 => {
+                let offset = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+                let len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+                self.header_len = 0;
+
+                let end = offset.checked_add(len).ok_or(Error::OutOfBounds)?;
+                if end > self.old.len() {
+                    return Err(Error::OutOfBounds);
+                }
+                if len != 0 {
+                    self.state = State::Copy {
+                        offset,
+                        remaining: len,
+                    };
+                }
+            }
+            OP_INSERT if header.len() == 3 => {
+                let len = u16::from_le_bytes(header[1..3].try_into().unwrap()) as usize;
+                self.header_len = 0;
+                if len != 0 {
+                    self.state = State::Insert { remaining: len };
+                }
+            }
+            OP_COPY | OP_INSERT => {}
+            op => return Err(Error::InvalidOp(op)),
+        }
+
+        Ok(())
+    }
+}
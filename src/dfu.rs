@@ -0,0 +1,773 @@
+//! Firmware updates over BLE
+//!
+//! Builds with the `dfu` feature only. They're linked for the embassy-boot
+//! layout (`memory-dfu.x`): the embassy-boot bootloader after BOOT2, its
+//! state partition, the active partition we run from and the DFU partition
+//! an update is written to. The bootloader has to be flashed once, after
+//! that every update can come over the air.
+//!
+//! An update is streamed into the DFU partition, checked against its
+//! CRC-32 read back from flash, and marked for the bootloader, which swaps
+//! it in on the next reboot. The new firmware confirms itself once it has
+//! run for [`CONFIRM_AFTER`]. If it resets before then, after a crash or
+//! a power cut, the bootloader swaps the old one back.
+//!
+//! The same transfer can carry a package of radio firmware instead (see
+//! [`crate::radiofw`]), written straight to the radio partition. It's
+//! checked the same way, and used the next time the radio starts. Or a
+//! file for the [`crate::assets`] filesystem, which replaces the file of
+//! that name once it's checked.
+//!
+//! An update of our firmware can also come as a patch against the running
+//! image (see [`crate::delta`]), a good deal smaller when little changed.
+//! The patch is decoded into the DFU partition as it arrives. The size and
+//! CRC given are of the new image, and it's checked and swapped in like a
+//! whole one.
+//!
+//! The control point takes `[op, args...]` and notifies `[op, status]`:
+//! - `[START, size: u32, crc: u32]` begins an update of `size` bytes
+//! - `[START_RADIO, size: u32, crc: u32]` begins a radio firmware update
+//! - `[START_ASSET, size: u32, crc: u32, name...]` begins a file upload
+//! - `[START_DELTA, size: u32, crc: u32]` begins an update patched from
+//!   the running firmware
+//! - `[FETCH, size: u32, crc: u32, url...]` has the firmware downloaded
+//!   over Wi-Fi instead, see [`crate::ota`]. Only builds with the `tls`
+//!   feature take it.
+//! - `[FINISH]` checks the image and marks it for the swap, or checks the
+//!   radio package or stores the file. A downloaded image is checked once
+//!   it's in, and waits in the verified state for the FINISH.
+//! - `[ABORT]` drops the update in progress
+//!
+//! The data characteristic takes `[offset: u32, bytes...]`, in order
+//! within a sector. Each sector in flash is marked in a
+//! [`resume::Transfer`], so an update cut short by a disconnect goes on
+//! where it stopped. Starting the same update again (same target, size and
+//! CRC) keeps what was received, and chunks can go on from the next
+//! offset, or from the start of any sector that's still missing. Chunks of
+//! sectors already in flash are taken and dropped. A reboot starts over.
+//! The offsets of a patch are into the patch, and it goes strictly in
+//! order, as the decoder can't skip ahead. It still resumes from where it
+//! stopped.
+//!
+//! The status characteristic is `[state, next offset: u32, size: u32,
+//! sector size: u16, sectors in flash: u16, first missing sector: u16]`,
+//! the missing sector `0xffff` once there are none. The sectors are of the
+//! image, the next offset of a patch is into the patch.
+//!
+//! Once an update is finished, the reboot command on the control
+//! characteristic (or `reboot` on the console) starts the swap. Numbers
+//! are little endian. Like the other sensitive writes, updates are only
+//! taken in maintenance mode.
+
+use core::cell::RefCell;
+use core::ops::Range;
+
+use embassy_boot::AlignedBuffer;
+use embassy_boot::BlockingFirmwareUpdater;
+use embassy_boot::BlockingPartition;
+use embassy_boot::FirmwareUpdaterConfig;
+use embassy_boot::State;
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_rp::flash::WRITE_SIZE;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Timer;
+
+use crate::assets;
+use crate::delta;
+use crate::error;
+use crate::flash;
+use crate::info;
+use crate::integrity::Crc32;
+#[cfg(feature = "tls")]
+use crate::ota;
+use crate::radiofw;
+use crate::resume;
+
+/// How long new firmware has to run before it's kept
+pub const CONFIRM_AFTER: Duration = Duration::from_secs(60);
+
+/// Longest chunk in a data write, what fits the ATT MTU after the offset
+pub const CHUNK_MAX: usize = 240;
+
+/// Size of the data characteristic
+pub const DATA_SIZE: usize = 4 + CHUNK_MAX;
+
+/// Longest URL a FETCH takes, none without TLS
+#[cfg(feature = "tls")]
+const URL_MAX: usize = ota::URL_MAX;
+#[cfg(not(feature = "tls"))]
+const URL_MAX: usize = 0;
+
+/// Size of the control point, a start with the longest file name or URL
+pub const CONTROL_SIZE: usize = 9 + if assets::NAME_MAX > URL_MAX {
+    assets::NAME_MAX
+} else {
+    URL_MAX
+};
+
+/// Size of the status characteristic
+pub const STATUS_SIZE: usize = 5 + resume::STATUS_SIZE;
+
+const START: u8 = 0x01;
+const FINISH: u8 = 0x02;
+const ABORT: u8 = 0x03;
+const START_RADIO: u8 = 0x04;
+const START_ASSET: u8 = 0x05;
+const START_DELTA: u8 = 0x06;
+#[cfg(feature = "tls")]
+const FETCH: u8 = 0x07;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    /// Malformed, or not possible in the current state
+    Invalid = 1,
+    /// Bigger than the partition it goes to
+    TooLarge = 2,
+    /// Not the offset the next chunk goes to, nor the start of a missing
+    /// sector
+    OutOfOrder = 3,
+    /// The image isn't what the CRC says, or is short
+    Mismatch = 4,
+    /// Flash or bootloader state error, see the log
+    Failed = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Phase {
+    Idle = 0,
+    Receiving = 1,
+    /// Checked and marked, waiting for the reboot
+    Ready = 2,
+    /// Checked, waiting for the FINISH that marks it
+    Verified = 3,
+}
+
+/// What an update replaces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Target {
+    /// Our own firmware, through the bootloader
+    Application,
+    /// The radio firmware package
+    Radio,
+    /// A file in the asset filesystem
+    Asset(assets::Name),
+    /// Our own firmware, patched from the running image
+    Delta,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Request {
+    Start {
+        target: Target,
+        size: u32,
+        crc: u32,
+    },
+    #[cfg(feature = "tls")]
+    Fetch {
+        size: u32,
+        crc: u32,
+        url: ota::Url,
+    },
+    Finish,
+    Abort,
+}
+
+impl Request {
+    const fn op(&self) -> u8 {
+        match self {
+            Self::Start {
+                target: Target::Application,
+                ..
+            } => START,
+            Self::Start {
+                target: Target::Radio,
+                ..
+            } => START_RADIO,
+            Self::Start {
+                target: Target::Asset(_),
+                ..
+            } => START_ASSET,
+            Self::Start {
+                target: Target::Delta,
+                ..
+            } => START_DELTA,
+            #[cfg(feature = "tls")]
+            Self::Fetch { .. } => FETCH,
+            Self::Finish => FINISH,
+            Self::Abort => ABORT,
+        }
+    }
+
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        match *value {
+            [op @ (START | START_RADIO | START_ASSET | START_DELTA), s0, s1, s2, s3, c0, c1, c2, c3, ref name @ ..] =>
+            {
+                let target = match op {
+                    START if name.is_empty() => Target::Application,
+                    START_RADIO if name.is_empty() => Target::Radio,
+                    START_ASSET => Target::Asset(assets::Name::from_bytes(name)?),
+                    START_DELTA if name.is_empty() => Target::Delta,
+                    _ => return None,
+                };
+                Some(Self::Start {
+                    target,
+                    size: u32::from_le_bytes([s0, s1, s2, s3]),
+                    crc: u32::from_le_bytes([c0, c1, c2, c3]),
+                })
+            }
+            #[cfg(feature = "tls")]
+            [FETCH, s0, s1, s2, s3, c0, c1, c2, c3, ref url @ ..] => Some(Self::Fetch {
+                size: u32::from_le_bytes([s0, s1, s2, s3]),
+                crc: u32::from_le_bytes([c0, c1, c2, c3]),
+                url: ota::Url::from_bytes(url)?,
+            }),
+            [FINISH] => Some(Self::Finish),
+            [ABORT] => Some(Self::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// A data write
+#[derive(Clone)]
+pub struct Chunk {
+    offset: u32,
+    len: usize,
+    data: [u8; CHUNK_MAX],
+}
+
+impl Chunk {
+    /// The `data` at `offset`, `None` if it's empty or longer than
+    /// [`CHUNK_MAX`]
+    pub fn new(offset: u32, data: &[u8]) -> Option<Self> {
+        let mut chunk = Self {
+            offset,
+            len: data.len(),
+            data: [0; CHUNK_MAX],
+        };
+        chunk.data.get_mut(..data.len())?.copy_from_slice(data);
+        (!data.is_empty()).then_some(chunk)
+    }
+
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let [o0, o1, o2, o3, ref data @ ..] = *value else {
+            return None;
+        };
+        Self::new(u32::from_le_bytes([o0, o1, o2, o3]), data)
+    }
+}
+
+struct Update {
+    phase: Phase,
+    target: Target,
+    size: u32,
+    crc: u32,
+    /// Offset of the sector being filled
+    written: u32,
+    /// The sector being filled
+    sector: [u8; ERASE_SIZE],
+    buffered: usize,
+    /// Sectors in flash, `None` before the first start
+    transfer: Option<resume::Transfer>,
+    /// Decoding a patch
+    patcher: Option<delta::Patcher<'static>>,
+    /// Bytes of the patch decoded
+    patch_received: u32,
+}
+
+impl Update {
+    /// Where the next chunk goes
+    fn received(&self) -> u32 {
+        self.written + self.buffered as u32
+    }
+
+    /// Whether every sector the `len` bytes at `offset` touch is in flash
+    fn stored(&self, offset: u32, len: usize) -> bool {
+        let Some(transfer) = &self.transfer else {
+            return false;
+        };
+        let first = offset as usize / ERASE_SIZE;
+        let last = (offset as usize + len - 1) / ERASE_SIZE;
+        (first..=last).all(|sector| transfer.has(sector))
+    }
+}
+
+static UPDATE: Mutex<CriticalSectionRawMutex, RefCell<Update>> = Mutex::new(RefCell::new(Update {
+    phase: Phase::Idle,
+    target: Target::Application,
+    size: 0,
+    crc: 0,
+    written: 0,
+    sector: [0; ERASE_SIZE],
+    buffered: 0,
+    transfer: None,
+    patcher: None,
+    patch_received: 0,
+}));
+
+type Partition<'a, 'd> = BlockingPartition<'a, NoopRawMutex, &'d mut flash::Driver>;
+
+/// Run `f` with a firmware updater over the flash
+async fn with_updater<R>(
+    f: impl FnOnce(&mut BlockingFirmwareUpdater<'_, Partition<'_, '_>, Partition<'_, '_>>) -> R,
+) -> R {
+    flash::with_flash(|driver| {
+        let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(driver));
+        let config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+        let mut aligned = AlignedBuffer([0; WRITE_SIZE]);
+        let mut updater = BlockingFirmwareUpdater::new(config, &mut aligned.0);
+        f(&mut updater)
+    })
+    .await
+}
+
+/// The DFU partition, as offsets from the start of flash
+fn dfu_partition() -> Range<u32> {
+    extern "C" {
+        static __bootloader_dfu_start: u32;
+        static __bootloader_dfu_end: u32;
+    }
+    // SAFETY: only the addresses of the linker symbols are taken, see
+    // memory-dfu.x
+    unsafe {
+        core::ptr::addr_of!(__bootloader_dfu_start) as u32
+            ..core::ptr::addr_of!(__bootloader_dfu_end) as u32
+    }
+}
+
+/// The firmware we run, a patch's old image
+fn active_image() -> &'static [u8] {
+    extern "C" {
+        static __bootloader_active_start: u32;
+        static __bootloader_active_end: u32;
+    }
+    const FLASH_BASE: usize = 0x1000_0000;
+
+    // SAFETY: the symbols come from memory-dfu.x, and flash is mapped
+    // read-only through XIP. The active partition is only written by the
+    // bootloader.
+    unsafe {
+        let start = core::ptr::addr_of!(__bootloader_active_start) as usize;
+        let end = core::ptr::addr_of!(__bootloader_active_end) as usize;
+        core::slice::from_raw_parts((FLASH_BASE + start) as *const u8, end - start)
+    }
+}
+
+/// Largest image for `target`. The DFU partition holds one sector more
+/// than the image, for the swap.
+fn capacity(target: Target) -> usize {
+    match target {
+        Target::Application | Target::Delta => dfu_partition().len().saturating_sub(ERASE_SIZE),
+        Target::Radio => radiofw::capacity(),
+        Target::Asset(_) => assets::capacity(),
+    }
+}
+
+/// CRC-32 of the first `size` bytes of the partition `target` goes to
+async fn crc_of_image(target: Target, size: u32) -> Result<u32, ()> {
+    let mut crc = Crc32::new();
+    let mut buf = [0; 256];
+    let mut offset = 0;
+    while offset < size {
+        let len = buf.len().min((size - offset) as usize);
+        // a bit at a time, so the flash isn't held for long
+        match target {
+            Target::Application | Target::Delta => flash::with_flash(|driver| {
+                driver.blocking_read(dfu_partition().start + offset, &mut buf[..len])
+            })
+            .await
+            .map_err(|e| error!("[dfu] {:?}", e))?,
+            Target::Radio => radiofw::read(offset, &mut buf[..len])
+                .await
+                .map_err(|e| error!("[dfu] {:?}", e))?,
+            Target::Asset(_) => assets::read(offset, &mut buf[..len])
+                .await
+                .map_err(|e| error!("[dfu] {:?}", e))?,
+        }
+        crc.update(&buf[..len]);
+        offset += len as u32;
+    }
+    Ok(crc.finish())
+}
+
+/// Erase the sector at `at`, from the start of flash, and program it.
+/// Both go through the flash worker, so the BLE tasks get to run between
+/// the pages.
+async fn write_sector(at: u32, sector: &[u8; ERASE_SIZE]) -> Result<(), flash::Error> {
+    flash::execute(flash::Op::Erase {
+        from: at,
+        to: at + ERASE_SIZE as u32,
+    })
+    .await?;
+    flash::write(at, sector).await
+}
+
+/// Write the filled sector to flash, padded if it's the last
+async fn flush() -> Result<(), Status> {
+    let (target, offset, mut sector, len) = UPDATE.lock(|update| {
+        let mut update = update.borrow_mut();
+        let buffered = core::mem::take(&mut update.buffered);
+        (update.target, update.written, update.sector, buffered)
+    });
+    sector[len..].fill(0xff);
+    let written = match target {
+        // the bootloader state was checked on the start, there's nothing
+        // else the updater would do
+        Target::Application | Target::Delta => {
+            write_sector(dfu_partition().start + offset, &sector)
+                .await
+                .map_err(|e| error!("[dfu] writing at {} failed: {:?}", offset, e))
+        }
+        Target::Radio => radiofw::write(offset, &sector)
+            .await
+            .map_err(|e| error!("[dfu] writing at {} failed: {:?}", offset, e)),
+        Target::Asset(_) => assets::write(offset, &sector)
+            .await
+            .map_err(|e| error!("[dfu] writing at {} failed: {:?}", offset, e)),
+    };
+    if written.is_err() {
+        abort();
+        return Err(Status::Failed);
+    }
+    UPDATE.lock(|update| {
+        let mut update = update.borrow_mut();
+        update.written += len as u32;
+        if let Some(transfer) = &mut update.transfer {
+            // can't be out of range, the chunk was checked against the size
+            let _ = transfer.mark(offset as usize / ERASE_SIZE);
+        }
+    });
+    Ok(())
+}
+
+fn abort() {
+    UPDATE.lock(|update| {
+        let mut update = update.borrow_mut();
+        update.phase = Phase::Idle;
+        update.buffered = 0;
+        update.patcher = None;
+    });
+    assets::abandon();
+}
+
+/// Take a data write
+pub async fn receive(chunk: Chunk) -> Result<(), Status> {
+    if UPDATE.lock(|update| update.borrow().target) == Target::Delta {
+        return receive_patch(chunk).await;
+    }
+    let (flush_now, n) = UPDATE.lock(|update| {
+        let mut update = update.borrow_mut();
+        if update.phase != Phase::Receiving {
+            return Err(Status::Invalid);
+        }
+        if chunk.offset + chunk.len as u32 > update.size {
+            return Err(Status::TooLarge);
+        }
+        if chunk.offset != update.received() {
+            if update.stored(chunk.offset, chunk.len) {
+                // sent again after a reconnect
+                return Ok((false, chunk.len));
+            }
+            let sector = chunk.offset as usize / ERASE_SIZE;
+            let missing = update.transfer.as_ref().is_some_and(|t| !t.has(sector));
+            if chunk.offset as usize % ERASE_SIZE != 0 || !missing {
+                return Err(Status::OutOfOrder);
+            }
+            // what's buffered of another sector gets sent again
+            update.written = chunk.offset;
+            update.buffered = 0;
+        }
+        // a chunk can straddle sectors, the rest goes after the flush
+        let start = update.buffered;
+        let n = chunk.len.min(ERASE_SIZE - start);
+        update.sector[start..start + n].copy_from_slice(&chunk.data[..n]);
+        update.buffered += n;
+        let done = update.received() == update.size;
+        Ok((update.buffered == ERASE_SIZE || done, n))
+    })?;
+    if flush_now {
+        flush().await?;
+    }
+    if n == chunk.len {
+        return Ok(());
+    }
+    let done = UPDATE.lock(|update| {
+        let mut update = update.borrow_mut();
+        let rest = chunk.len - n;
+        update.sector[..rest].copy_from_slice(&chunk.data[n..chunk.len]);
+        update.buffered = rest;
+        update.received() == update.size
+    });
+    if done {
+        flush().await?;
+    }
+    Ok(())
+}
+
+/// Take a data write of a patch, decoding it into sectors of the image
+async fn receive_patch(chunk: Chunk) -> Result<(), Status> {
+    let mut patch = &chunk.data[..chunk.len];
+    let fresh = UPDATE.lock(|update| {
+        let update = update.borrow();
+        if update.phase != Phase::Receiving {
+            return Err(Status::Invalid);
+        }
+        let end = chunk.offset + chunk.len as u32;
+        if end <= update.patch_received {
+            // sent again after a reconnect
+            return Ok(false);
+        }
+        if chunk.offset != update.patch_received {
+            return Err(Status::OutOfOrder);
+        }
+        Ok(true)
+    })?;
+    if !fresh {
+        return Ok(());
+    }
+    loop {
+        let (flush_now, more) = UPDATE
+            .lock(|update| {
+                let update = &mut *update.borrow_mut();
+                let Some(patcher) = &mut update.patcher else {
+                    return Err(Status::Invalid);
+                };
+                let start = update.buffered;
+                let (taken, produced) = patcher
+                    .decode(patch, &mut update.sector[start..])
+                    .map_err(|e| error!("[dfu] bad patch: {:?}", e))
+                    .map_err(|()| Status::Invalid)?;
+                patch = &patch[taken..];
+                update.patch_received += taken as u32;
+                update.buffered += produced;
+                if update.received() > update.size {
+                    error!("[dfu] the patch makes more than {} bytes", update.size);
+                    return Err(Status::TooLarge);
+                }
+                let done = update.buffered > 0 && update.received() == update.size;
+                Ok((
+                    update.buffered == ERASE_SIZE || done,
+                    produced > 0 || taken > 0,
+                ))
+            })
+            .inspect_err(|_| abort())?;
+        if flush_now {
+            flush().await?;
+        }
+        if !more {
+            return Ok(());
+        }
+    }
+}
+
+pub async fn start(target: Target, size: u32, crc: u32) -> Status {
+    let resumed = UPDATE.lock(|update| {
+        let update = update.borrow();
+        let same = update.target == target && update.size == size && update.crc == crc;
+        (update.phase == Phase::Receiving && same).then(|| update.received())
+    });
+    if let Some(offset) = resumed {
+        info!("[dfu] resuming {:?} at {}", target, offset);
+        return Status::Ok;
+    }
+    abort();
+    if size == 0 || size as usize > capacity(target) {
+        return Status::TooLarge;
+    }
+    if let Target::Asset(name) = target {
+        if let Err(e) = assets::create(name, size) {
+            error!("[dfu] no room for {}: {:?}", name.as_str(), e);
+            return Status::TooLarge;
+        }
+    }
+    // updating from firmware that hasn't been kept yet would lose the way
+    // back
+    if matches!(target, Target::Application | Target::Delta) {
+        match with_updater(|updater| updater.get_state()).await {
+            Ok(State::Boot) => {}
+            Ok(_) => {
+                error!("[dfu] can't start before the running firmware is kept");
+                return Status::Invalid;
+            }
+            Err(e) => {
+                error!("[dfu] reading the bootloader state failed: {:?}", e);
+                return Status::Failed;
+            }
+        }
+    }
+    // every partition has fewer sectors than the bitmap has bits
+    let Ok(transfer) = resume::Transfer::new(crc, size, ERASE_SIZE as u16) else {
+        return Status::TooLarge;
+    };
+    UPDATE.lock(|update| {
+        let mut update = update.borrow_mut();
+        update.phase = Phase::Receiving;
+        update.target = target;
+        update.size = size;
+        update.crc = crc;
+        update.written = 0;
+        update.transfer = Some(transfer);
+        update.patcher = (target == Target::Delta).then(|| delta::Patcher::new(active_image()));
+        update.patch_received = 0;
+    });
+    info!("[dfu] receiving {} bytes of {:?}", size, target);
+    Status::Ok
+}
+
+/// Check a received image against its CRC, leaving it verified
+pub async fn verify() -> Status {
+    let (phase, target, size, crc, missing, patched) = UPDATE.lock(|update| {
+        let update = update.borrow();
+        (
+            update.phase,
+            update.target,
+            update.size,
+            update.crc,
+            update.transfer.as_ref().and_then(|t| t.first_missing()),
+            update.patcher.as_ref().map(|p| p.finish()),
+        )
+    });
+    if phase != Phase::Receiving {
+        return Status::Invalid;
+    }
+    if let Some(Err(e)) = patched {
+        error!("[dfu] patch: {:?}", e);
+        return Status::Mismatch;
+    }
+    if let Some(sector) = missing {
+        error!("[dfu] sector {} of {} bytes is missing", sector, size);
+        return Status::Mismatch;
+    }
+    match crc_of_image(target, size).await {
+        Ok(actual) if actual == crc => {}
+        Ok(actual) => {
+            error!("[dfu] CRC {:08x}, expected {:08x}", actual, crc);
+            abort();
+            return Status::Mismatch;
+        }
+        Err(()) => {
+            error!("[dfu] reading the image back failed");
+            return Status::Failed;
+        }
+    }
+    UPDATE.lock(|update| update.borrow_mut().phase = Phase::Verified);
+    Status::Ok
+}
+
+async fn finish() -> Status {
+    if UPDATE.lock(|update| update.borrow().phase) != Phase::Verified {
+        let verified = verify().await;
+        if verified != Status::Ok {
+            return verified;
+        }
+    }
+    let (target, crc) = UPDATE.lock(|update| {
+        let update = update.borrow();
+        (update.target, update.crc)
+    });
+    if let Target::Asset(name) = target {
+        let stored = assets::commit(crc).await;
+        abort();
+        if let Err(e) = stored {
+            error!("[dfu] storing {} failed: {:?}", name.as_str(), e);
+            return Status::Failed;
+        }
+        return Status::Ok;
+    }
+    if target == Target::Radio {
+        abort();
+        if !radiofw::installed() {
+            error!("[dfu] not an intact radio firmware package");
+            return Status::Mismatch;
+        }
+        return Status::Ok;
+    }
+    if let Err(e) = with_updater(|updater| updater.mark_updated()).await {
+        error!("[dfu] marking the update failed: {:?}", e);
+        return Status::Failed;
+    }
+    UPDATE.lock(|update| update.borrow_mut().phase = Phase::Ready);
+    info!("[dfu] update ready, swapped in on the next reboot");
+    Status::Ok
+}
+
+/// Carry out a control point write, returning the notification
+pub async fn execute(request: Request) -> [u8; 2] {
+    let status = match request {
+        Request::Start { target, size, crc } => start(target, size, crc).await,
+        #[cfg(feature = "tls")]
+        Request::Fetch { size, crc, url } => {
+            if size == 0 || size as usize > capacity(Target::Application) {
+                Status::TooLarge
+            } else {
+                ota::fetch(url, size, crc);
+                Status::Ok
+            }
+        }
+        Request::Finish => finish().await,
+        Request::Abort => {
+            abort();
+            info!("[dfu] aborted");
+            Status::Ok
+        }
+    };
+    [request.op(), status as u8]
+}
+
+pub fn status() -> [u8; STATUS_SIZE] {
+    UPDATE.lock(|update| {
+        let update = update.borrow();
+        let mut out = [0; STATUS_SIZE];
+        out[0] = update.phase as u8;
+        let next = match update.target {
+            Target::Delta => update.patch_received,
+            _ => update.received(),
+        };
+        out[1..5].copy_from_slice(&next.to_le_bytes());
+        if let Some(transfer) = &update.transfer {
+            out[5..].copy_from_slice(&transfer.status());
+        }
+        out
+    })
+}
+
+/// Keep firmware the bootloader just swapped in, once it has run long
+/// enough to trust
+pub async fn run() {
+    match with_updater(|updater| updater.get_state()).await {
+        Ok(State::Swap) => {}
+        Ok(_) => return,
+        Err(e) => {
+            error!("[dfu] reading the bootloader state failed: {:?}", e);
+            return;
+        }
+    }
+    info!(
+        "[dfu] running new firmware, keeping it in {}s",
+        CONFIRM_AFTER.as_secs()
+    );
+    Timer::after(CONFIRM_AFTER).await;
+    match with_updater(|updater| updater.mark_booted()).await {
+        Ok(()) => info!("[dfu] new firmware kept"),
+        Err(e) => error!("[dfu] confirming the firmware failed: {:?}", e),
+    }
+}
+
+#[embassy_executor::task]
+async fn task() {
+    run().await
+}
+
+crate::register_module!(MODULE, "dfu", |resources| {
+    resources.spawner.must_spawn(task())
+});
@@ -0,0 +1,201 @@
+//! Disconnect reasons
+//!
+//! The host tells us a connection went away, but not why. [`Tap`] sits
+//! between it and the controller and notes the reason of every
+//! Disconnection Complete event going by. Once the connection has wound
+//! down, its serving slot [`take`]s the reason for
+//! [`crate::events::Event::Disconnected`] and acts on it, and it's counted
+//! by class for [`crate::stats`].
+
+use core::cell::Cell;
+use core::cell::RefCell;
+
+use bt_hci::cmd;
+use bt_hci::controller::Controller;
+use bt_hci::controller::ControllerCmdAsync;
+use bt_hci::controller::ControllerCmdSync;
+use bt_hci::data;
+use bt_hci::event::Event;
+use bt_hci::param::ConnHandle;
+use bt_hci::ControllerToHostPacket;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::blue::CONNECTIONS_MAX;
+use crate::info;
+
+/// Why a connection went away, by HCI error code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Reason {
+    /// The central hung up, ran low on resources or is powering off
+    Remote(u8),
+    /// The link went quiet for the supervision timeout, or the central
+    /// stopped answering link layer procedures
+    Timeout(u8),
+    /// A packet failed its integrity check: a key mismatch, or someone
+    /// tampering with the link
+    MicFailure,
+    /// We disconnected
+    Local,
+    /// Any other error code
+    Other(u8),
+    /// The event never came by
+    Unknown,
+}
+
+/// Number of [`Reason`] classes counted
+pub const CLASSES: usize = 5;
+
+impl Reason {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            // remote user terminated, low resources, power off
+            0x13..=0x15 => Self::Remote(code),
+            // connection timeout, LL response timeout
+            0x08 | 0x22 => Self::Timeout(code),
+            0x3d => Self::MicFailure,
+            0x16 => Self::Local,
+            code => Self::Other(code),
+        }
+    }
+
+    /// Index of the count, in the order of [`counts`]
+    fn class(&self) -> usize {
+        match self {
+            Self::Remote(_) => 0,
+            Self::Timeout(_) => 1,
+            Self::MicFailure => 2,
+            Self::Local => 3,
+            Self::Other(_) | Self::Unknown => 4,
+        }
+    }
+}
+
+struct Noted {
+    reasons: [Option<(ConnHandle, u8)>; CONNECTIONS_MAX],
+    /// Slot to take when there's no entry for the handle
+    next: usize,
+}
+
+/// Reasons not taken yet. Connections we reject are never served, so
+/// their entries stay until the handle comes around again or the slot is
+/// needed.
+static NOTED: Mutex<CriticalSectionRawMutex, RefCell<Noted>> = Mutex::new(RefCell::new(Noted {
+    reasons: [None; CONNECTIONS_MAX],
+    next: 0,
+}));
+
+static COUNTS: Mutex<CriticalSectionRawMutex, Cell<[u32; CLASSES]>> =
+    Mutex::new(Cell::new([0; CLASSES]));
+
+fn note(handle: ConnHandle, code: u8) {
+    NOTED.lock(|noted| {
+        let mut noted = noted.borrow_mut();
+        let idx = match noted
+            .reasons
+            .iter()
+            .position(|r| r.is_some_and(|(h, _)| h == handle))
+        {
+            Some(idx) => idx,
+            None => {
+                let idx = noted.next;
+                noted.next = (idx + 1) % CONNECTIONS_MAX;
+                idx
+            }
+        };
+        noted.reasons[idx] = Some((handle, code));
+    });
+}
+
+/// The reason `handle` disconnected, counting it. Call once, after the
+/// host reported the connection gone.
+pub fn take(handle: ConnHandle) -> Reason {
+    let code = NOTED.lock(|noted| {
+        noted
+            .borrow_mut()
+            .reasons
+            .iter_mut()
+            .find(|r| r.is_some_and(|(h, _)| h == handle))
+            .and_then(Option::take)
+            .map(|(_, code)| code)
+    });
+    let reason = code.map_or(Reason::Unknown, Reason::from_code);
+    COUNTS.lock(|counts| {
+        let mut all = counts.get();
+        let count = &mut all[reason.class()];
+        *count = count.saturating_add(1);
+        counts.set(all);
+    });
+    reason
+}
+
+/// Disconnects since boot: remote, timeout, MIC failure, local, other
+pub fn counts() -> [u32; CLASSES] {
+    COUNTS.lock(|counts| counts.get())
+}
+
+/// A controller that notes disconnect reasons on the way to the host
+pub struct Tap<C> {
+    controller: C,
+}
+
+impl<C> Tap<C> {
+    pub fn new(controller: C) -> Self {
+        Self { controller }
+    }
+}
+
+impl<C: Controller> embedded_io::ErrorType for Tap<C> {
+    type Error = C::Error;
+}
+
+impl<C: Controller> Controller for Tap<C> {
+    async fn write_acl_data(&self, packet: &data::AclPacket<'_>) -> Result<(), Self::Error> {
+        self.controller.write_acl_data(packet).await
+    }
+
+    async fn write_sync_data(&self, packet: &data::SyncPacket<'_>) -> Result<(), Self::Error> {
+        self.controller.write_sync_data(packet).await
+    }
+
+    async fn write_iso_data(&self, packet: &data::IsoPacket<'_>) -> Result<(), Self::Error> {
+        self.controller.write_iso_data(packet).await
+    }
+
+    async fn read<'a>(&self, buf: &'a mut [u8]) -> Result<ControllerToHostPacket<'a>, Self::Error> {
+        let packet = self.controller.read(buf).await?;
+        if let ControllerToHostPacket::Event(Event::DisconnectionComplete(e)) = &packet {
+            note(e.handle, e.reason.into_inner());
+        }
+        Ok(packet)
+    }
+}
+
+impl<C: ControllerCmdSync<Q>, Q: cmd::SyncCmd + ?Sized> ControllerCmdSync<Q> for Tap<C> {
+    async fn exec(&self, cmd: &Q) -> Result<Q::Return, cmd::Error<Self::Error>> {
+        ControllerCmdSync::<Q>::exec(&self.controller, cmd).await
+    }
+}
+
+impl<C: ControllerCmdAsync<Q>, Q: cmd::AsyncCmd + ?Sized> ControllerCmdAsync<Q> for Tap<C> {
+    async fn exec(&self, cmd: &Q) -> Result<(), cmd::Error<Self::Error>> {
+        ControllerCmdAsync::<Q>::exec(&self.controller, cmd).await
+    }
+}
+
+crate::register_command!(
+    DISCONNECTS,
+    "disconnects",
+    "",
+    "show disconnect counts by reason",
+    |args| {
+        args.end()?;
+        let [remote, timeout, mic, local, other] = counts();
+        info!(
+            "{} remote, {} timeout, {} MIC failure, {} local, {} other",
+            remote, timeout, mic, local, other
+        );
+        Ok(())
+    }
+);
@@ -0,0 +1,81 @@
+//! LAN discovery beacon
+//!
+//! Broadcasts a small JSON datagram with the device ID, firmware version
+//! and IP address every [`INTERVAL`], and answers `who are you` probes
+//! sent to [`PORT`] with the same datagram, unicast.
+
+use core::fmt::Write as _;
+
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_net::driver::Driver;
+use embassy_net::udp::PacketMetadata;
+use embassy_net::udp::UdpSocket;
+use embassy_net::IpAddress;
+use embassy_net::IpEndpoint;
+use embassy_net::Stack;
+use embassy_time::Duration;
+use embassy_time::Ticker;
+
+use crate::error;
+use crate::http::Body;
+use crate::info;
+use crate::system;
+
+pub const PORT: u16 = 41000;
+
+pub const INTERVAL: Duration = Duration::from_secs(10);
+
+const PROBE: &[u8] = b"who are you";
+
+pub async fn run<D: Driver>(stack: &Stack<D>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 256];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(PORT) {
+        error!("[discovery] bind failed: {:?}", e);
+        core::future::pending::<()>().await;
+    }
+
+    let broadcast = IpEndpoint::new(IpAddress::v4(255, 255, 255, 255), PORT);
+    let mut ticker = Ticker::every(INTERVAL);
+    let mut probe = [0; PROBE.len()];
+    loop {
+        let to = match select(ticker.next(), socket.recv_from(&mut probe)).await {
+            Either::First(()) => broadcast,
+            Either::Second(Ok((n, from))) if probe[..n] == *PROBE => {
+                info!("[discovery] probed by {}", from);
+                from
+            }
+            Either::Second(_) => continue,
+        };
+
+        let beacon = beacon(stack);
+        if let Err(e) = socket.send_to(beacon.as_bytes(), to).await {
+            error!("[discovery] send failed: {:?}", e);
+        }
+    }
+}
+
+fn beacon<D: Driver>(stack: &Stack<D>) -> Body {
+    let mut body = Body::new();
+    let _ = write!(
+        body,
+        "{{\"name\":\"mansion lighting\",\"id\":\"{}\",\"fw\":\"{}\"",
+        system::DeviceId::get(),
+        env!("CARGO_PKG_VERSION"),
+    );
+    if let Some(config) = stack.config_v4() {
+        let _ = write!(body, ",\"ip\":\"{}\"", config.address.address());
+    }
+    let _ = body.write_char('}');
+    body
+}
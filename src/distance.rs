@@ -0,0 +1,288 @@
+//! Distance and presence
+//!
+//! A VL53L0X time-of-flight sensor ([`crate::vl53l0x`]) on the shared bus
+//! ranges the nearest target every `distance.interval` milliseconds. The
+//! range falls into one of three [`Zone`]s, split at `distance.near` and
+//! `distance.far`; nothing in range counts as far. Moving to another zone
+//! takes going [`HYSTERESIS_MM`] past the split, so a target sitting on one
+//! doesn't flap between zones.
+//!
+//! Zone changes are published as [`Event::Zone`] for whatever reacts to
+//! presence, and notified over BLE along with the range. The range is
+//! kept as a [`Measurement`], and reads as unknown once a measurement
+//! failed or it went stale, while the zone stays where it was. The range
+//! is also an alarm signal ([`alarm::DISTANCE`]), with nothing in range as
+//! far as can be, so a low limit trips when something comes close and a
+//! high one when it goes away.
+//!
+//! While a central is polling, measurements are moved to finish just
+//! before its connection events (see [`crate::anchor`]), so the range it
+//! gets notified is as fresh as can be.
+
+use core::cell::Cell;
+use core::future::pending;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use trouble_host::prelude::*;
+
+use crate::alarm;
+use crate::anchor;
+use crate::blue::gen_uuid;
+use crate::bus::Bus;
+use crate::error;
+use crate::events;
+use crate::events::Event;
+use crate::fault;
+use crate::gattcheck;
+use crate::info;
+use crate::measurement;
+use crate::measurement::Measurement;
+use crate::measurement::Unit;
+use crate::modules;
+use crate::monitor;
+use crate::telemetry;
+use crate::vl53l0x::Vl53l0x;
+use crate::writes;
+
+crate::config_key!(
+    /// Time between measurements in ms
+    pub INTERVAL: u32 = "distance.interval",
+    200,
+);
+
+crate::config_key!(
+    /// Upper end of the near zone in mm
+    pub NEAR: u32 = "distance.near",
+    300,
+);
+
+crate::config_key!(
+    /// Lower end of the far zone in mm
+    pub FAR: u32 = "distance.far",
+    1000,
+);
+
+/// Shortest interval we measure at, a bit over the sensor's timing budget
+const MIN_INTERVAL_MS: u32 = 50;
+
+/// How far past a split a target has to go to change zones
+pub const HYSTERESIS_MM: u32 = 20;
+
+/// What the distance characteristic reads without a target in range
+pub const DISTANCE_UNKNOWN: u16 = u16::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Zone {
+    Near = 0,
+    Middle = 1,
+    /// Far away, or nothing in range
+    Far = 2,
+}
+
+impl Zone {
+    /// The zone of `mm` for a target that was in `last`
+    fn of(mm: Option<u16>, last: Self) -> Self {
+        let Some(mm) = mm else {
+            return Self::Far;
+        };
+        let mm = mm as u32;
+        let (near, far) = (NEAR.get(), FAR.get());
+        // the splits move away from the zone the target is in
+        let (near, far) = match last {
+            Self::Near => (near + HYSTERESIS_MM, far + HYSTERESIS_MM),
+            Self::Middle => (near.saturating_sub(HYSTERESIS_MM), far + HYSTERESIS_MM),
+            Self::Far => (
+                near.saturating_sub(HYSTERESIS_MM),
+                far.saturating_sub(HYSTERESIS_MM),
+            ),
+        };
+        if mm < near {
+            Self::Near
+        } else if mm < far {
+            Self::Middle
+        } else {
+            Self::Far
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Reading {
+    /// In mm, `None` with nothing in range, and no measurement before the
+    /// first
+    range: Option<Measurement<Option<u16>>>,
+    zone: Zone,
+}
+
+static READING: Mutex<CriticalSectionRawMutex, Cell<Reading>> = Mutex::new(Cell::new(Reading {
+    range: None,
+    zone: Zone::Far,
+}));
+
+/// The last range measurement, flagged stale if the sensor stopped
+pub fn measurement() -> Option<Measurement<Option<u16>>> {
+    READING.lock(|r| r.get().range).map(|m| m.aged(interval()))
+}
+
+/// Range to the nearest target in mm, `None` without one in range or a
+/// good measurement
+pub fn range() -> Option<u16> {
+    measurement().and_then(Measurement::good).flatten()
+}
+
+/// The distance characteristic, in mm little endian
+pub fn distance() -> [u8; 2] {
+    range().unwrap_or(DISTANCE_UNKNOWN).to_le_bytes()
+}
+
+pub fn zone() -> Zone {
+    READING.lock(|r| r.get().zone)
+}
+
+/// Serve the range, notified once it moved past its report delta
+fn notify_range() {
+    let value = distance();
+    modules::notify_reading(DISTANCE_UUID, &value, u16::from_le_bytes(value) as i32);
+}
+
+fn publish(mm: Option<u16>) {
+    let last = READING.lock(|r| r.get());
+    let zone = Zone::of(mm, last.zone);
+    let range = Measurement::new(mm, Unit::Millimetres);
+    READING.lock(|r| {
+        r.set(Reading {
+            range: Some(range),
+            zone,
+        })
+    });
+    let signal = mm.map_or(i32::MAX, i32::from);
+    alarm::check(alarm::DISTANCE, signal, signal);
+    if let Some(mm) = mm {
+        telemetry::record_measurement(telemetry::Signal::Distance, range.map(|_| i32::from(mm)));
+    }
+
+    if last.range.and_then(Measurement::good) != Some(mm) {
+        notify_range();
+    }
+    if zone != last.zone {
+        info!("[distance] {:?}", zone);
+        events::publish(Event::Zone(zone));
+        modules::notify(ZONE_UUID, &[zone as u8]);
+    }
+}
+
+/// Keep the last range, flagged as faulty
+fn publish_fault() {
+    let last = READING.lock(|r| r.get());
+    let Some(range) = last.range else {
+        return;
+    };
+    let range = range.with(measurement::FAULT);
+    READING.lock(|r| {
+        r.set(Reading {
+            range: Some(range),
+            ..last
+        })
+    });
+    if let Some(mm) = range.value {
+        telemetry::record_measurement(telemetry::Signal::Distance, range.map(|_| i32::from(mm)));
+    }
+    if last.range.is_some_and(|m| m.is_good()) {
+        notify_range();
+    }
+}
+
+fn interval() -> Duration {
+    Duration::from_millis(INTERVAL.get().max(MIN_INTERVAL_MS) as u64)
+}
+
+pub async fn run(bus: &'static Bus) -> ! {
+    let mut sensor = match Vl53l0x::probe(bus).await {
+        Ok(Some(sensor)) => sensor,
+        Ok(None) => {
+            info!("[distance] no VL53L0X found");
+            pending().await
+        }
+        Err(e) => {
+            info!("[distance] no VL53L0X: {:?}", e);
+            pending().await
+        }
+    };
+
+    loop {
+        monitor::DISTANCE.ping();
+        let at = Instant::now();
+        match sensor.measure(bus).await {
+            Ok(mm) => publish(mm),
+            Err(e) => {
+                error!("[distance] measurement failed: {:?}", e);
+                fault::raise(fault::DISTANCE_READ);
+                publish_fault();
+            }
+        }
+
+        // how long it took, to have the next one done before the anchor
+        let took = Instant::now() - at;
+
+        monitor::DISTANCE.pause();
+        anchor::align(at + interval(), took).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn task(bus: &'static Bus) -> ! {
+    run(bus).await
+}
+
+const SERVICE_UUID: Uuid = gen_uuid("distance sensor");
+const DISTANCE_UUID: Uuid = gen_uuid("distance");
+const ZONE_UUID: Uuid = gen_uuid("distance zone");
+const _: () = gattcheck::assert_distinct(&[&[DISTANCE_UUID, ZONE_UUID]]);
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    let props = [CharacteristicProp::Read, CharacteristicProp::Notify];
+    let spec = writes::Spec::new();
+    svc.add(
+        DISTANCE_UUID,
+        &props,
+        2,
+        spec.with_read(|| writes::Value::new(&distance())),
+    );
+    svc.add(
+        ZONE_UUID,
+        &props,
+        1,
+        spec.with_read(|| writes::Value::new(&[zone() as u8])),
+    );
+}
+
+crate::register_module!(
+    MODULE,
+    "distance",
+    |resources| resources.spawner.must_spawn(task(resources.bus)),
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |services| services.distance,
+        build: service,
+    },
+);
+
+crate::register_command!(
+    DISTANCE,
+    "distance",
+    "",
+    "show the range and zone",
+    |args| {
+        args.end()?;
+        match range() {
+            Some(mm) => info!("{} mm, {:?}", mm, zone()),
+            None => info!("nothing in range"),
+        }
+        Ok(())
+    }
+);
@@ -0,0 +1,51 @@
+//! Link quality ping
+//!
+//! A client writes a token of up to [`MAX_TOKEN`] bytes to the echo
+//! characteristic and we notify it straight back, followed by how long it
+//! spent with us in microseconds (little endian u32). Taking that out of
+//! the measured round trip leaves the time on the air, and its spread over
+//! a few pings is the link jitter.
+
+use embassy_time::Instant;
+
+/// Longest token echoed back
+pub const MAX_TOKEN: usize = 16;
+
+/// Size of the echo characteristic
+pub const RESPONSE_SIZE: usize = MAX_TOKEN + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Ping {
+    len: usize,
+    token: [u8; MAX_TOKEN],
+    received: Instant,
+}
+
+impl Ping {
+    /// `None` for an empty or too long token
+    pub fn parse(value: &[u8], received: Instant) -> Option<Self> {
+        if value.is_empty() || value.len() > MAX_TOKEN {
+            return None;
+        }
+        let mut token = [0; MAX_TOKEN];
+        token[..value.len()].copy_from_slice(value);
+        Some(Self {
+            len: value.len(),
+            token,
+            received,
+        })
+    }
+
+    /// The notification to send back, and its length. Call right before
+    /// sending so the delta covers everything up to it.
+    pub fn response(&self) -> ([u8; RESPONSE_SIZE], usize) {
+        let held = Instant::now().saturating_duration_since(self.received);
+        let held = held.as_micros().min(u32::MAX as u64) as u32;
+
+        let mut out = [0; RESPONSE_SIZE];
+        out[..self.len].copy_from_slice(&self.token[..self.len]);
+        out[self.len..self.len + 4].copy_from_slice(&held.to_le_bytes());
+        (out, self.len + 4)
+    }
+}
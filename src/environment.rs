@@ -0,0 +1,291 @@
+//! Environmental sensing
+//!
+//! A temperature, humidity and pressure sensor on the shared bus (see
+//! [`crate::bus`]), served over the Environmental Sensing Service. The
+//! drivers implement [`Sensor`], and whichever sensor answers first at
+//! start-up is used: a BME280 or BMP280 ([`crate::bme280`]), or an SHT31
+//! ([`crate::sht31`]). What it doesn't measure reads as the characteristic's
+//! unknown value.
+//!
+//! The sensor is sampled every `env.interval` milliseconds. Readings are
+//! kept here as [`Measurement`]s for the GATT server to read, and only the
+//! ones that changed are notified (see [`modules::notify`]), to the
+//! connections that subscribed to them. A failed measurement flags the
+//! last readings as faulty, and they read as unknown until the sensor
+//! answers again, as do readings gone stale.
+
+use core::cell::Cell;
+use core::future::pending;
+
+use embassy_rp::i2c;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::bme280::Bme280;
+use crate::bus::Bus;
+use crate::error;
+use crate::fault;
+use crate::info;
+use crate::measurement;
+use crate::measurement::Measurement;
+use crate::measurement::Unit;
+use crate::modules;
+use crate::monitor;
+use crate::sht31::Sht31;
+use crate::telemetry;
+use crate::weather;
+use crate::writes;
+
+crate::config_key!(
+    /// Time between measurements in ms
+    pub INTERVAL: u32 = "env.interval",
+    10_000,
+);
+
+/// Shortest interval we measure at, about what a BME280 measurement takes
+const MIN_INTERVAL_MS: u32 = 100;
+
+/// What the Temperature characteristic reads without a temperature
+pub const TEMPERATURE_UNKNOWN: i16 = i16::MIN;
+
+/// What the Humidity characteristic reads without a humidity
+pub const HUMIDITY_UNKNOWN: u16 = u16::MAX;
+
+/// One measurement, `None` for what the sensor doesn't measure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Reading {
+    /// In 0.01°C
+    pub temperature: Option<i16>,
+    /// In 0.01%
+    pub humidity: Option<u16>,
+    /// In 0.1Pa
+    pub pressure: Option<u32>,
+}
+
+/// A sensor driver. Drivers lock the bus per transfer, so others get it
+/// while a measurement is under way.
+pub(crate) trait Sensor {
+    /// Take a measurement
+    async fn measure(&mut self, bus: &Bus) -> Result<Reading, i2c::Error>;
+}
+
+/// The sensors we have drivers for
+enum Fitted {
+    Bme280(Bme280),
+    Sht31(Sht31),
+}
+
+impl Sensor for Fitted {
+    async fn measure(&mut self, bus: &Bus) -> Result<Reading, i2c::Error> {
+        match self {
+            Self::Bme280(sensor) => sensor.measure(bus).await,
+            Self::Sht31(sensor) => sensor.measure(bus).await,
+        }
+    }
+}
+
+/// The last measurement of each quantity, `None` for what the sensor
+/// doesn't measure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Measurements {
+    pub temperature: Option<Measurement<i16>>,
+    pub humidity: Option<Measurement<u16>>,
+    pub pressure: Option<Measurement<u32>>,
+}
+
+impl Measurements {
+    const NONE: Self = Self {
+        temperature: None,
+        humidity: None,
+        pressure: None,
+    };
+}
+
+static MEASUREMENTS: Mutex<CriticalSectionRawMutex, Cell<Measurements>> =
+    Mutex::new(Cell::new(Measurements::NONE));
+
+/// The last measurements, flagged stale if the sensor stopped
+pub fn measurements() -> Measurements {
+    let m = MEASUREMENTS.lock(|m| m.get());
+    Measurements {
+        temperature: m.temperature.map(|m| m.aged(interval())),
+        humidity: m.humidity.map(|m| m.aged(interval())),
+        pressure: m.pressure.map(|m| m.aged(interval())),
+    }
+}
+
+/// Temperature, little endian
+pub fn temperature() -> [u8; 2] {
+    measurements()
+        .temperature
+        .and_then(Measurement::good)
+        .unwrap_or(TEMPERATURE_UNKNOWN)
+        .to_le_bytes()
+}
+
+/// Humidity, little endian
+pub fn humidity() -> [u8; 2] {
+    measurements()
+        .humidity
+        .and_then(Measurement::good)
+        .unwrap_or(HUMIDITY_UNKNOWN)
+        .to_le_bytes()
+}
+
+/// Pressure, little endian. The characteristic has no unknown value, 0
+/// stands in for one.
+pub fn pressure() -> [u8; 4] {
+    measurements()
+        .pressure
+        .and_then(Measurement::good)
+        .unwrap_or(0)
+        .to_le_bytes()
+}
+
+/// What a characteristic serves of `m`, which changes with the value or
+/// with whether it's good
+fn served<T>(m: Option<Measurement<T>>) -> Option<T> {
+    m.and_then(Measurement::good)
+}
+
+fn publish(reading: Reading) {
+    publish_measurements(Measurements {
+        temperature: reading
+            .temperature
+            .map(|t| Measurement::new(t, Unit::Celsius)),
+        humidity: reading.humidity.map(|h| Measurement::new(h, Unit::Percent)),
+        pressure: reading.pressure.map(|p| Measurement::new(p, Unit::Pascal)),
+    });
+}
+
+/// Keep the last readings, flagged as faulty
+fn publish_fault() {
+    let m = MEASUREMENTS.lock(|m| m.get());
+    publish_measurements(Measurements {
+        temperature: m.temperature.map(|m| m.with(measurement::FAULT)),
+        humidity: m.humidity.map(|m| m.with(measurement::FAULT)),
+        pressure: m.pressure.map(|m| m.with(measurement::FAULT)),
+    });
+}
+
+fn publish_measurements(measurements: Measurements) {
+    if let Some(temperature) = measurements.temperature {
+        telemetry::record_measurement(telemetry::Signal::Temperature, temperature.map(i32::from));
+    }
+    if let Some(humidity) = measurements.humidity {
+        telemetry::record_measurement(telemetry::Signal::Humidity, humidity.map(i32::from));
+    }
+    if let Some(pressure) = measurements.pressure {
+        telemetry::record_measurement(telemetry::Signal::Pressure, pressure.map(|p| p as i32));
+    }
+    let last = MEASUREMENTS.lock(|m| m.replace(measurements));
+    if served(measurements.temperature) != served(last.temperature) {
+        modules::notify(TEMPERATURE_UUID, &temperature());
+    }
+    if served(measurements.humidity) != served(last.humidity) {
+        modules::notify(HUMIDITY_UUID, &humidity());
+    }
+    if served(measurements.pressure) != served(last.pressure) {
+        modules::notify(PRESSURE_UUID, &pressure());
+    }
+}
+
+fn interval() -> Duration {
+    Duration::from_millis(INTERVAL.get().max(MIN_INTERVAL_MS) as u64)
+}
+
+/// Find out which sensor is fitted, if any, and set it up
+async fn detect(bus: &Bus) -> Option<Fitted> {
+    match Bme280::probe(bus).await {
+        Ok(Some(sensor)) => return Some(Fitted::Bme280(sensor)),
+        Ok(None) => {}
+        Err(e) => info!("[env] no BME280: {:?}", e),
+    }
+    match Sht31::probe(bus).await {
+        Ok(sensor) => Some(Fitted::Sht31(sensor)),
+        Err(e) => {
+            info!("[env] no SHT31: {:?}", e);
+            None
+        }
+    }
+}
+
+pub async fn run(bus: &'static Bus) -> ! {
+    let Some(mut sensor) = detect(bus).await else {
+        info!("[env] no environmental sensor found");
+        pending().await
+    };
+
+    loop {
+        monitor::ENVIRONMENT.ping();
+        let at = Instant::now();
+        match sensor.measure(bus).await {
+            Ok(reading) => publish(reading),
+            Err(e) => {
+                error!("[env] measurement failed: {:?}", e);
+                fault::raise(fault::ENV_READ);
+                publish_fault();
+            }
+        }
+
+        monitor::ENVIRONMENT.pause();
+        Timer::at(at + interval()).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn task(bus: &'static Bus) -> ! {
+    run(bus).await
+}
+
+const SERVICE_UUID: Uuid = Uuid::Uuid16(0x181au16.to_le_bytes());
+const TEMPERATURE_UUID: Uuid = Uuid::Uuid16(0x2a6eu16.to_le_bytes());
+const HUMIDITY_UUID: Uuid = Uuid::Uuid16(0x2a6fu16.to_le_bytes());
+const PRESSURE_UUID: Uuid = Uuid::Uuid16(0x2a6du16.to_le_bytes());
+
+/// The Environmental Sensing Service, with the weather station's readings
+/// on boards that have one
+fn service(svc: &mut modules::Service<'_, '_>) {
+    let props = [CharacteristicProp::Read, CharacteristicProp::Notify];
+    let spec = writes::Spec::new();
+    if svc.services().environment {
+        svc.add(
+            TEMPERATURE_UUID,
+            &props,
+            2,
+            spec.with_read(|| writes::Value::new(&temperature())),
+        );
+        svc.add(
+            HUMIDITY_UUID,
+            &props,
+            2,
+            spec.with_read(|| writes::Value::new(&humidity())),
+        );
+        svc.add(
+            PRESSURE_UUID,
+            &props,
+            4,
+            spec.with_read(|| writes::Value::new(&pressure())),
+        );
+    }
+    if svc.services().weather {
+        weather::characteristics(svc);
+    }
+}
+
+crate::register_module!(
+    MODULE,
+    "environment",
+    |resources| resources.spawner.must_spawn(task(resources.bus)),
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |services| services.environment || services.weather,
+        build: service,
+    },
+);
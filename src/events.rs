@@ -0,0 +1,83 @@
+//! BLE lifecycle events
+//!
+//! Published by the BLE tasks and observed by anything that wants to react
+//! to connection activity: connects and disconnects, MTU changes,
+//! subscriptions, accesses to characteristics. [`crate::power`] publishes
+//! going dormant and waking up, [`crate::distance`] targets changing zones.
+//! Application tasks, like status LEDs or
+//! sensors, [`subscribe`] and match on the [`Event`]s they care about.
+
+use bt_hci::param::ConnHandle;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::pubsub::Subscriber;
+use trouble_host::prelude::*;
+
+use crate::blue::CONNECTIONS_MAX;
+use crate::conninfo;
+use crate::conninfo::ConnectionInfo;
+use crate::disconnects;
+use crate::distance;
+use crate::latency;
+
+/// Max number of queued events per subscriber
+const EVENTS_CAP: usize = 8;
+
+/// Max number of concurrent subscribers, three per connection for
+/// [`latency::run`], [`crate::boost::run`] and [`crate::ots::run`], and the
+/// rest for [`crate::power`] and application tasks
+const SUBSCRIBERS_MAX: usize = 3 * CONNECTIONS_MAX + 4;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// A central connected, with its address
+    Connected(ConnHandle, BdAddr),
+    /// A central went away, and why
+    Disconnected(ConnHandle, disconnects::Reason),
+    Read(Characteristic),
+    Write(Characteristic),
+    /// A periodic reading from [`conninfo::sample`]
+    ConnectionInfo(ConnHandle, ConnectionInfo),
+    /// Application code pinned or released the connection's profile, see
+    /// [`latency::pin`]
+    ProfileRequested(ConnHandle),
+    /// The client asked for a boost or to end it, see
+    /// [`crate::boost::request`]
+    BoostRequested(ConnHandle),
+    /// An object transfer can start on the connection's object channel,
+    /// see [`crate::ots::release`]
+    TransferRequested(ConnHandle),
+    /// The central accepted new connection parameters
+    ParamsUpdated(ConnHandle, conninfo::Params),
+    /// The ATT MTU was exchanged, with the new one
+    MtuChanged(ConnHandle, u16),
+    /// The central turned on notifications or indications of the
+    /// characteristic
+    Subscribed(ConnHandle, Characteristic),
+    /// The central turned them off again
+    Unsubscribed(ConnHandle, Characteristic),
+    /// Nobody connected for a while and we're on battery: tasks should do
+    /// as little as they can
+    Dormant,
+    /// Back from being dormant
+    Awake,
+    /// The distance sensor's target moved into another zone
+    Zone(distance::Zone),
+}
+
+pub type EventSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, Event, EVENTS_CAP, SUBSCRIBERS_MAX, 0>;
+
+static EVENTS: PubSubChannel<CriticalSectionRawMutex, Event, EVENTS_CAP, SUBSCRIBERS_MAX, 0> =
+    PubSubChannel::new();
+
+/// Publish an event. Slow subscribers lose their oldest events.
+pub fn publish(event: Event) {
+    EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// Subscribe to events, `None` if all subscriber slots are in use
+pub fn subscribe() -> Option<EventSubscriber> {
+    EVENTS.subscriber().ok()
+}
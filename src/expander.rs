@@ -0,0 +1,124 @@
+//! I²C GPIO expanders
+//!
+//! The relay bank's outputs go through one of two expanders, whichever the
+//! board revision is built for:
+//!
+//! - PCF8574: eight quasi-bidirectional lines, all driving relays. Writing
+//!   a byte sets them, there are no registers.
+//! - MCP23017: port A drives the relays and port B is eight inputs with
+//!   pull-ups. Port B interrupts on any change, pulling an interrupt line
+//!   low until the inputs are read.
+//!
+//! Input changes are read as soon as the interrupt line drops, and
+//! [`crate::relay`] notifies them to the client.
+
+use core::cell::Cell;
+
+use embassy_rp::i2c;
+use embassy_rp::i2c::I2c;
+use embassy_rp::peripherals::I2C0;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// The expander's address with A0 to A2 tied low, the same for both kinds
+pub const ADDRESS: u8 = 0x20;
+
+// MCP23017 registers, in the default IOCON.BANK = 0 layout
+const IODIRA: u8 = 0x00;
+const IODIRB: u8 = 0x01;
+const GPINTENB: u8 = 0x05;
+const GPPUB: u8 = 0x0d;
+const GPIOB: u8 = 0x13;
+const OLATA: u8 = 0x14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Expander {
+    Pcf8574,
+    Mcp23017,
+}
+
+impl Expander {
+    /// Whether there are inputs to read
+    pub fn has_inputs(&self) -> bool {
+        *self == Self::Mcp23017
+    }
+
+    /// What to write to set the relay outputs to `outputs`
+    fn output_frame(&self, outputs: u8) -> ([u8; 2], usize) {
+        match self {
+            Self::Pcf8574 => ([outputs, 0], 1),
+            Self::Mcp23017 => ([OLATA, outputs], 2),
+        }
+    }
+
+    /// Set up the lines, outputs first latched at `outputs` so nothing
+    /// switches on while the directions change
+    pub async fn init(
+        &self,
+        i2c: &mut I2c<'static, I2C0, i2c::Async>,
+        outputs: u8,
+    ) -> Result<(), i2c::Error> {
+        self.write_outputs(i2c, outputs).await?;
+        if *self == Self::Mcp23017 {
+            for frame in [
+                [IODIRA, 0x00],
+                [IODIRB, 0xff],
+                [GPPUB, 0xff],
+                [GPINTENB, 0xff],
+            ] {
+                i2c.write_async(ADDRESS as u16, frame).await?;
+            }
+            // clear an interrupt left from before the reset
+            self.read_inputs(i2c).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn write_outputs(
+        &self,
+        i2c: &mut I2c<'static, I2C0, i2c::Async>,
+        outputs: u8,
+    ) -> Result<(), i2c::Error> {
+        let (frame, len) = self.output_frame(outputs);
+        i2c.write_async(ADDRESS as u16, frame[..len].iter().copied())
+            .await
+    }
+
+    /// Blocking output write, for the panic handler
+    pub fn blocking_write_outputs<M: i2c::Mode>(
+        &self,
+        i2c: &mut I2c<'_, I2C0, M>,
+        outputs: u8,
+    ) -> Result<(), i2c::Error> {
+        let (frame, len) = self.output_frame(outputs);
+        i2c.blocking_write(ADDRESS, &frame[..len])
+    }
+
+    /// Read the inputs, which also releases the interrupt line. Always
+    /// zero without inputs.
+    pub async fn read_inputs(
+        &self,
+        i2c: &mut I2c<'static, I2C0, i2c::Async>,
+    ) -> Result<u8, i2c::Error> {
+        if !self.has_inputs() {
+            return Ok(0);
+        }
+        let mut inputs = [0];
+        i2c.write_read_async(ADDRESS as u16, [GPIOB], &mut inputs)
+            .await?;
+        Ok(inputs[0])
+    }
+}
+
+static INPUTS: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// The inputs as last read, a bit per line
+pub fn inputs() -> u8 {
+    INPUTS.lock(|inputs| inputs.get())
+}
+
+/// Record freshly read inputs, returning whether they changed
+pub fn update_inputs(value: u8) -> bool {
+    INPUTS.lock(|inputs| inputs.replace(value)) != value
+}
@@ -0,0 +1,462 @@
+//! GATT explorer on the console
+//!
+//! What a phone app like nRF Connect shows, from the USB console and
+//! without a central:
+//!
+//! - `gatt` lists the services and characteristics with their handles,
+//!   UUIDs, names and properties
+//! - `gatt read <characteristic>` logs the value we serve
+//! - `gatt write <characteristic> <hex>` puts a new one in its place
+//! - `gatt watch <characteristic> on|off` logs the value every time it
+//!   changes, like a subscription would, up to [`WATCH_MAX`] at once
+//!
+//! A characteristic is given by its value handle or its name, with `_` for
+//! the spaces: `audit_page_index`, `device_name`. Our own characteristics
+//! are named by their UUIDs (see `gen_uuid` in [`crate::blue`]), the
+//! standard ones we serve have their SIG names here.
+//!
+//! [`index`] notes the table as it's built, and the GATT task runs the
+//! reads and writes in [`run`], since only it has the server. A write only
+//! changes the stored value: it doesn't reach the code that acts on a
+//! client's write, and notifies nobody. Watching polls every
+//! [`WATCH_PERIOD`], so a value that changes and changes back in between
+//! goes unseen.
+
+use core::cell::Cell;
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::blue::MAX_ATTRIBUTES;
+use crate::console;
+use crate::error;
+use crate::fmtbuf;
+use crate::info;
+use crate::integrity;
+
+/// Characteristics watched at once
+pub const WATCH_MAX: usize = 4;
+
+/// Time between looks at the watched values
+pub const WATCH_PERIOD: Duration = Duration::from_millis(500);
+
+/// Services and characteristics we keep track of
+const ENTRIES_MAX: usize = MAX_ATTRIBUTES / 2;
+
+/// Longest value written from the console, more than a line holds in hex
+const WRITE_MAX: usize = 40;
+
+/// Bytes of a value logged, the rest is cut
+const LOG_MAX: usize = 64;
+
+/// Longest name, a `gen_uuid` one or the SIG's
+const NAME_MAX: usize = 20;
+
+// bits of `Entry::props`
+const READ: u8 = 0x01;
+const WRITE: u8 = 0x02;
+const NOTIFY: u8 = 0x04;
+
+/// SIG names of the standard services and characteristics we serve
+const SIG_NAMES: [(u16, &str); 32] = [
+    (0x1800, "generic access"),
+    (0x1801, "generic attribute"),
+    (0x1805, "current time"),
+    (0x180a, "device information"),
+    (0x180f, "battery"),
+    (0x1812, "human interface"),
+    (0x181a, "environmental"),
+    (0x1825, "object transfer"),
+    (0xfff6, "matter"),
+    (0x2a00, "device name"),
+    (0x2a01, "appearance"),
+    (0x2a19, "battery level"),
+    (0x2a24, "model number"),
+    (0x2a25, "serial number"),
+    (0x2a26, "firmware rev"),
+    (0x2a27, "hardware rev"),
+    (0x2a29, "manufacturer"),
+    (0x2a2b, "current time"),
+    (0x2a4a, "hid information"),
+    (0x2a4b, "report map"),
+    (0x2a4c, "hid control"),
+    (0x2a4d, "report"),
+    (0x2a6d, "pressure"),
+    (0x2a6e, "temperature"),
+    (0x2a6f, "humidity"),
+    (0x2a70, "wind speed"),
+    (0x2a71, "wind direction"),
+    (0x2a74, "gust factor"),
+    (0x2a78, "rainfall"),
+    (0x2abe, "object name"),
+    (0x2ac5, "object action"),
+    (0x2ac6, "object list"),
+];
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// The service's handle, or the characteristic's value handle
+    handle: u16,
+    uuid: Uuid,
+    /// `None` for a service
+    props: Option<u8>,
+    cccd: Option<u16>,
+}
+
+struct Catalog {
+    entries: [Option<Entry>; ENTRIES_MAX],
+    len: usize,
+}
+
+static CATALOG: Mutex<CriticalSectionRawMutex, RefCell<Catalog>> =
+    Mutex::new(RefCell::new(Catalog {
+        entries: [None; ENTRIES_MAX],
+        len: 0,
+    }));
+
+/// Value handles being watched
+static WATCHED: Mutex<CriticalSectionRawMutex, Cell<[Option<u16>; WATCH_MAX]>> =
+    Mutex::new(Cell::new([None; WATCH_MAX]));
+
+enum Request {
+    Read(Characteristic),
+    Write(Characteristic, [u8; WRITE_MAX], usize),
+}
+
+static REQUESTS: Channel<CriticalSectionRawMutex, Request, 2> = Channel::new();
+
+/// The values served, as the GATT task has them
+pub trait Values {
+    /// Copy the value of `characteristic` into `out`, returning how long
+    /// it is. What doesn't fit is cut.
+    fn read(&self, characteristic: Characteristic, out: &mut [u8]) -> Option<usize>;
+
+    fn write(&self, characteristic: Characteristic, value: &[u8]) -> bool;
+}
+
+/// Note the services and characteristics of `table`, once it's built
+pub fn index<M: RawMutex, const N: usize>(table: &AttributeTable<'_, M, N>) {
+    CATALOG.lock(|catalog| {
+        let mut catalog = catalog.borrow_mut();
+        catalog.entries = [None; ENTRIES_MAX];
+        catalog.len = 0;
+        let mut full = false;
+        table.iterate(|mut it| {
+            while let Some(att) = it.next() {
+                let entry = match &att.data {
+                    AttributeData::Service { uuid } => Entry {
+                        handle: att.handle,
+                        uuid: *uuid,
+                        props: None,
+                        cccd: None,
+                    },
+                    AttributeData::Declaration {
+                        props,
+                        handle,
+                        uuid,
+                    } => {
+                        let mut bits = 0;
+                        if props.any(&[CharacteristicProp::Read]) {
+                            bits |= READ;
+                        }
+                        if props.any(&[
+                            CharacteristicProp::Write,
+                            CharacteristicProp::WriteWithoutResponse,
+                        ]) {
+                            bits |= WRITE;
+                        }
+                        if props.any(&[CharacteristicProp::Notify, CharacteristicProp::Indicate]) {
+                            bits |= NOTIFY;
+                        }
+                        Entry {
+                            handle: *handle,
+                            uuid: *uuid,
+                            props: Some(bits),
+                            cccd: None,
+                        }
+                    }
+                    AttributeData::Cccd { .. } => {
+                        // the characteristic it's for came just before
+                        if let Some(idx) = catalog.len.checked_sub(1) {
+                            if let Some(entry) = catalog.entries[idx].as_mut() {
+                                entry.cccd = Some(att.handle);
+                            }
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if catalog.len == ENTRIES_MAX {
+                    full = true;
+                    continue;
+                }
+                let len = catalog.len;
+                catalog.entries[len] = Some(entry);
+                catalog.len += 1;
+            }
+        });
+        if full {
+            error!(
+                "[explorer] more than {} services and characteristics",
+                ENTRIES_MAX
+            );
+        }
+    });
+}
+
+/// The name of `uuid`, if we know one
+fn name(uuid: &Uuid) -> Option<fmtbuf::Buf<NAME_MAX>> {
+    let mut out = fmtbuf::Buf::new();
+    match uuid {
+        Uuid::Uuid16(bytes) => {
+            let short = u16::from_le_bytes(*bytes);
+            let (_, name) = SIG_NAMES.iter().find(|(u, _)| *u == short)?;
+            let _ = out.write_str(name);
+        }
+        Uuid::Uuid128(bytes) => {
+            // `gen_uuid` puts the name last to first, zero padded
+            let len = bytes.iter().rev().take_while(|&&b| b != 0).count();
+            let name = bytes.iter().rev().take(len);
+            if len == 0 || bytes[..16 - len].iter().any(|&b| b != 0) {
+                return None;
+            }
+            for &byte in name {
+                if !byte.is_ascii_graphic() && byte != b' ' {
+                    return None;
+                }
+                let _ = out.write_char(byte as char);
+            }
+        }
+    }
+    Some(out)
+}
+
+fn log_uuid(uuid: &Uuid) -> fmtbuf::Buf<36> {
+    let mut out = fmtbuf::Buf::new();
+    match uuid {
+        Uuid::Uuid16(bytes) => {
+            let _ = write!(out, "0x{:04x}", u16::from_le_bytes(*bytes));
+        }
+        Uuid::Uuid128(bytes) => {
+            let mut reversed = *bytes;
+            reversed.reverse();
+            let mut hex = [0; 32];
+            fmtbuf::hex(&reversed, &mut hex);
+            let _ = out.write_str(core::str::from_utf8(&hex).unwrap_or_default());
+        }
+    }
+    out
+}
+
+/// Whether the console's `arg` names `name`, `_` standing for spaces
+fn matches(arg: &str, name: &str) -> bool {
+    arg.len() == name.len()
+        && arg
+            .bytes()
+            .zip(name.bytes())
+            .all(|(a, n)| a == n || (a == b'_' && n == b' '))
+}
+
+/// The first characteristic `f` is true of
+fn find_by(f: impl Fn(&Entry) -> bool) -> Option<Entry> {
+    CATALOG.lock(|catalog| {
+        let catalog = catalog.borrow();
+        catalog.entries[..catalog.len]
+            .iter()
+            .flatten()
+            .find(|entry| entry.props.is_some() && f(entry))
+            .copied()
+    })
+}
+
+/// The characteristic `arg` is the value handle or name of
+fn find(arg: &str) -> Option<Entry> {
+    match arg.parse::<u16>() {
+        Ok(handle) => find_by(|entry| entry.handle == handle),
+        Err(_) => {
+            find_by(|entry| name(&entry.uuid).is_some_and(|name| matches(arg, name.as_str())))
+        }
+    }
+}
+
+fn characteristic(entry: &Entry) -> Characteristic {
+    Characteristic {
+        handle: entry.handle,
+        cccd_handle: entry.cccd,
+    }
+}
+
+fn list() {
+    CATALOG.lock(|catalog| {
+        let catalog = catalog.borrow();
+        for entry in catalog.entries[..catalog.len].iter().flatten() {
+            let name = name(&entry.uuid);
+            let name = name.as_ref().map_or("", |name| name.as_str());
+            match entry.props {
+                None => info!("{} {}", log_uuid(&entry.uuid).as_str(), name),
+                Some(props) => info!(
+                    "  {} {} {} [{}{}{}]",
+                    entry.handle,
+                    log_uuid(&entry.uuid).as_str(),
+                    name,
+                    if props & READ != 0 { "r" } else { "" },
+                    if props & WRITE != 0 { "w" } else { "" },
+                    if props & NOTIFY != 0 { "n" } else { "" },
+                ),
+            }
+        }
+    });
+}
+
+/// Log `value` of the characteristic at `handle` in hex
+fn log_value(handle: u16, value: &[u8], len: usize) {
+    let mut hex = [0; 2 * LOG_MAX];
+    let shown = fmtbuf::hex(&value[..value.len().min(LOG_MAX)], &mut hex);
+    info!(
+        "{}: {}{} ({} bytes)",
+        handle,
+        core::str::from_utf8(&hex[..shown]).unwrap_or_default(),
+        if len > LOG_MAX { ".." } else { "" },
+        len
+    );
+}
+
+/// Parse `hex` into `out`, returning the length
+fn parse_hex(hex: &str, out: &mut [u8]) -> Option<usize> {
+    let hex = hex.as_bytes();
+    if hex.len() % 2 != 0 || hex.len() / 2 > out.len() {
+        return None;
+    }
+    for (byte, pair) in out.iter_mut().zip(hex.chunks(2)) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(hex.len() / 2)
+}
+
+fn watch(handle: u16, on: bool) -> bool {
+    WATCHED.lock(|watched| {
+        let mut handles = watched.get();
+        let at = handles.iter().position(|h| *h == Some(handle));
+        let done = match (on, at) {
+            (true, Some(_)) | (false, None) => true,
+            (true, None) => match handles.iter_mut().find(|h| h.is_none()) {
+                Some(free) => {
+                    *free = Some(handle);
+                    true
+                }
+                None => false,
+            },
+            (false, Some(at)) => {
+                handles[at] = None;
+                true
+            }
+        };
+        watched.set(handles);
+        done
+    })
+}
+
+/// Run the console's reads and writes against `values`, and log the
+/// watched values that change
+pub async fn run(values: &impl Values) -> ! {
+    let mut last: [Option<(u16, u32)>; WATCH_MAX] = [None; WATCH_MAX];
+    let mut buf = [0; LOG_MAX];
+    loop {
+        match select(REQUESTS.receive(), Timer::after(WATCH_PERIOD)).await {
+            Either::First(Request::Read(characteristic)) => {
+                match values.read(characteristic, &mut buf) {
+                    Some(len) => log_value(characteristic.handle, &buf[..len.min(LOG_MAX)], len),
+                    None => error!("[explorer] reading {} failed", characteristic.handle),
+                }
+            }
+            Either::First(Request::Write(characteristic, value, len)) => {
+                if values.write(characteristic, &value[..len]) {
+                    info!("[explorer] {} written", characteristic.handle);
+                } else {
+                    error!("[explorer] writing {} failed", characteristic.handle);
+                }
+            }
+            Either::Second(()) => {
+                let watched = WATCHED.lock(|watched| watched.get());
+                for (slot, handle) in watched.iter().enumerate() {
+                    let Some(handle) = *handle else {
+                        last[slot] = None;
+                        continue;
+                    };
+                    let Some(entry) = find_by(|entry| entry.handle == handle) else {
+                        continue;
+                    };
+                    let Some(len) = values.read(characteristic(&entry), &mut buf) else {
+                        continue;
+                    };
+                    let shown = &buf[..len.min(LOG_MAX)];
+                    let crc = integrity::crc32(shown);
+                    // a newly watched value is logged as it is
+                    if last[slot] != Some((handle, crc)) {
+                        last[slot] = Some((handle, crc));
+                        log_value(handle, shown, len);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn request(request: Request) -> Result<(), console::Error> {
+    REQUESTS.try_send(request).map_err(|_| {
+        error!("[explorer] busy, or the GATT server isn't running");
+        console::Error::Failed
+    })
+}
+
+crate::register_command!(
+    GATT,
+    "gatt",
+    "[read <char>|write <char> <hex>|watch <char> on|off]",
+    "list the GATT table, or read, write or watch a characteristic",
+    |args| {
+        let Some(op) = args.opt_str() else {
+            list();
+            return Ok(());
+        };
+        let entry = find(args.str("char")?).ok_or(console::Error::Invalid("char"))?;
+        let props = entry.props.unwrap_or(0);
+        match op {
+            "read" => {
+                args.end()?;
+                request(Request::Read(characteristic(&entry)))
+            }
+            "write" => {
+                let mut value = [0; WRITE_MAX];
+                let len = parse_hex(args.str("hex")?, &mut value)
+                    .ok_or(console::Error::Invalid("hex"))?;
+                args.end()?;
+                if props & WRITE == 0 {
+                    info!("not writable");
+                    return Err(console::Error::Failed);
+                }
+                request(Request::Write(characteristic(&entry), value, len))
+            }
+            "watch" => {
+                let on = args.bool("on|off")?;
+                args.end()?;
+                if !watch(entry.handle, on) {
+                    info!("watching {} already", WATCH_MAX);
+                    return Err(console::Error::Failed);
+                }
+                Ok(())
+            }
+            _ => Err(console::Error::Invalid("read|write|watch")),
+        }
+    }
+);
@@ -0,0 +1,279 @@
+//! Fault log
+//!
+//! Errors worth a look from support are raised here on top of being
+//! logged, by their code in [`CATALOG`]. The most recent distinct faults
+//! are kept with how often each happened and when it last did, so a phone
+//! can tell what's been going wrong without a USB cable. Codes are stable
+//! across firmware versions: new ones get added, old ones aren't reused.
+//!
+//! The last fault characteristic is `[uptime s: u32, fault]`, each fault
+//! `[module, code, count: u16, at s: u32]` little endian with `at` in
+//! seconds since boot, all zero before the first fault. The kept faults
+//! are read in pages, most recent first.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+
+use crate::info;
+use crate::paging;
+
+/// Number of distinct faults kept, the least recent one making room
+pub const KEPT: usize = 8;
+
+/// Size of an encoded fault
+pub const FAULT_SIZE: usize = 8;
+
+/// Size of the last fault characteristic
+pub const CHARACTERISTIC_SIZE: usize = 4 + FAULT_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Module {
+    Flash = 1,
+    Crash = 2,
+    Monitor = 3,
+    Adc = 4,
+    Relay = 5,
+    Meter = 6,
+    Rtc = 7,
+    Config = 8,
+    Net = 9,
+    Environment = 10,
+    Ble = 11,
+    Distance = 12,
+    Level = 13,
+    Rollup = 14,
+}
+
+/// A fault's code, unique within its module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Code {
+    pub module: Module,
+    pub code: u8,
+}
+
+const fn code(module: Module, code: u8) -> Code {
+    Code { module, code }
+}
+
+pub const FLASH_JOB: Code = code(Module::Flash, 1);
+pub const CRASHED: Code = code(Module::Crash, 1);
+pub const TASK_STALLED: Code = code(Module::Monitor, 1);
+pub const ADC_CAPTURE: Code = code(Module::Adc, 1);
+pub const ADC_AUX: Code = code(Module::Adc, 2);
+pub const RELAY_WRITE: Code = code(Module::Relay, 1);
+pub const RELAY_READ: Code = code(Module::Relay, 2);
+pub const METER_READ: Code = code(Module::Meter, 1);
+pub const METER_STORE: Code = code(Module::Meter, 2);
+pub const RTC_STOPPED: Code = code(Module::Rtc, 1);
+pub const RTC_BUS: Code = code(Module::Rtc, 2);
+pub const CONFIG_STORE: Code = code(Module::Config, 1);
+pub const CONFIG_INVALID: Code = code(Module::Config, 2);
+pub const NET_JOIN: Code = code(Module::Net, 1);
+pub const NET_SYNC: Code = code(Module::Net, 2);
+pub const NET_GATEWAY: Code = code(Module::Net, 3);
+pub const NET_OTA: Code = code(Module::Net, 4);
+pub const ENV_READ: Code = code(Module::Environment, 1);
+pub const LINK_MIC: Code = code(Module::Ble, 1);
+pub const DISTANCE_READ: Code = code(Module::Distance, 1);
+pub const LEVEL_READ: Code = code(Module::Level, 1);
+pub const ROLLUP_STORE: Code = code(Module::Rollup, 1);
+
+/// Every code with what it means
+pub const CATALOG: [(Code, &str); 22] = [
+    (FLASH_JOB, "a flash erase or write failed"),
+    (CRASHED, "crashed before the last reset, see crash pages"),
+    (TASK_STALLED, "a task stopped making progress, see tasks"),
+    (ADC_CAPTURE, "an ADC block was lost to a FIFO error"),
+    (ADC_AUX, "reading the battery pin failed"),
+    (RELAY_WRITE, "writing the relay expander failed"),
+    (RELAY_READ, "reading the relay header inputs failed"),
+    (METER_READ, "reading the current monitor failed"),
+    (METER_STORE, "storing the meter configuration failed"),
+    (RTC_STOPPED, "the RTC lost its time, check its battery"),
+    (RTC_BUS, "talking to the RTC failed"),
+    (CONFIG_STORE, "storing the settings failed"),
+    (CONFIG_INVALID, "the stored settings were invalid"),
+    (NET_JOIN, "joining the Wi-Fi network failed"),
+    (NET_SYNC, "an SNTP time sync failed"),
+    (NET_GATEWAY, "talking to the MQTT broker failed"),
+    (NET_OTA, "a firmware download failed"),
+    (ENV_READ, "reading the environmental sensor failed"),
+    (LINK_MIC, "a link dropped on a failed integrity check (MIC)"),
+    (DISTANCE_READ, "reading the distance sensor failed"),
+    (LEVEL_READ, "the level sensor got too few echoes"),
+    (ROLLUP_STORE, "storing the daily statistics failed"),
+];
+
+/// What a code means
+pub fn describe(code: Code) -> &'static str {
+    CATALOG
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or("unknown", |(_, text)| text)
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Fault {
+    pub code: Code,
+    /// Times it happened since boot, saturating
+    pub count: u16,
+    /// Seconds since boot it last happened at
+    pub at_s: u32,
+}
+
+impl Fault {
+    pub fn encode(&self) -> [u8; FAULT_SIZE] {
+        let mut out = [0; FAULT_SIZE];
+        out[0] = self.code.module as u8;
+        out[1] = self.code.code;
+        out[2..4].copy_from_slice(&self.count.to_le_bytes());
+        out[4..8].copy_from_slice(&self.at_s.to_le_bytes());
+        out
+    }
+}
+
+/// Faults, the most recent first
+struct Log {
+    faults: [Option<Fault>; KEPT],
+}
+
+static LOG: Mutex<CriticalSectionRawMutex, RefCell<Log>> = Mutex::new(RefCell::new(Log {
+    faults: [None; KEPT],
+}));
+
+static RAISED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn uptime_s() -> u32 {
+    Instant::now().as_secs() as u32
+}
+
+/// Count a fault. Log the details alongside, this only keeps the code.
+pub fn raise(code: Code) {
+    let fault = LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        let idx = log
+            .faults
+            .iter()
+            .position(|f| f.is_some_and(|f| f.code == code))
+            .unwrap_or(KEPT - 1);
+        let count = match log.faults[idx] {
+            Some(f) if f.code == code => f.count.saturating_add(1),
+            _ => 1,
+        };
+        // move it to the front
+        log.faults.copy_within(..idx, 1);
+        let fault = Fault {
+            code,
+            count,
+            at_s: uptime_s(),
+        };
+        log.faults[0] = Some(fault);
+        fault
+    });
+    if fault.count == 1 {
+        info!("[fault] {:?}: {}", code, describe(code));
+    }
+    RAISED.signal(());
+}
+
+/// The most recent fault
+pub fn last() -> Option<Fault> {
+    LOG.lock(|log| log.borrow().faults[0])
+}
+
+/// Iterate over the kept faults, most recent first
+pub fn for_each(mut f: impl FnMut(&Fault)) {
+    LOG.lock(|log| log.borrow().faults.iter().flatten().for_each(&mut f));
+}
+
+/// Forget every fault
+pub fn clear() {
+    LOG.lock(|log| log.borrow_mut().faults = [None; KEPT]);
+    RAISED.signal(());
+}
+
+/// The last fault characteristic value
+pub fn encode() -> [u8; CHARACTERISTIC_SIZE] {
+    let mut out = [0; CHARACTERISTIC_SIZE];
+    if let Some(fault) = last() {
+        out[..4].copy_from_slice(&uptime_s().to_le_bytes());
+        out[4..].copy_from_slice(&fault.encode());
+    }
+    out
+}
+
+/// Wait for a fault to be raised or the log cleared, returning the last
+/// fault characteristic value. Only meant for a single waiter.
+pub async fn changed() -> [u8; CHARACTERISTIC_SIZE] {
+    RAISED.wait().await;
+    encode()
+}
+
+/// The kept faults as a pageable dataset, most recent first
+pub struct Dataset;
+
+impl paging::Dataset for Dataset {
+    fn len(&self) -> usize {
+        let mut len = 0;
+        for_each(|_| len += FAULT_SIZE);
+        len
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        let mut pos = 0;
+        for_each(|fault| {
+            let encoded = fault.encode();
+            let start = pos;
+            pos += FAULT_SIZE;
+            if pos <= offset || copied == buf.len() {
+                return;
+            }
+
+            let src = &encoded[offset.saturating_sub(start)..];
+            let n = src.len().min(buf.len() - copied);
+            buf[copied..copied + n].copy_from_slice(&src[..n]);
+            copied += n;
+        });
+        copied
+    }
+}
+
+crate::register_command!(
+    FAULTS,
+    "faults",
+    "[clear]",
+    "list the recent faults, most recent first",
+    |args| {
+        let clearing = match args.opt_str() {
+            Some("clear") => true,
+            Some(_) => return Err(crate::console::Error::Invalid("clear")),
+            None => false,
+        };
+        args.end()?;
+        if clearing {
+            clear();
+            return Ok(());
+        }
+        let now = uptime_s();
+        for_each(|fault| {
+            info!(
+                "{:?} #{} x{}, {}s ago: {}",
+                fault.code.module,
+                fault.code.code,
+                fault.count,
+                now.saturating_sub(fault.at_s),
+                describe(fault.code)
+            );
+        });
+        Ok(())
+    },
+);
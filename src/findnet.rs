@@ -0,0 +1,123 @@
+//! Offline-finding compatible advertising
+//!
+//! An experiment for asset tracking: broadcast windows send advertisements
+//! in the format offline-finding scanners relay (the OpenHaystack flavour
+//! of Apple's Find My frames), so the device's position can be looked up
+//! later without it ever connecting to anything.
+//!
+//! The keys are P-224 public keys derived offline from a seed, since there's
+//! no elliptic curve code on the device. The derived table is built in
+//! from `findnet_keys.bin` next to `Cargo.toml`, 28 bytes per key, and we
+//! move to the next key every [`ROTATION`] so the device can't be followed
+//! by its address. Off unless the `findnet` feature is enabled.
+
+use bt_hci::cmd::le::LeSetRandomAddr;
+use bt_hci::param::BdAddr;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::clock;
+use crate::info;
+
+/// Size of an advertised public key (the x coordinate)
+pub const KEY_SIZE: usize = 28;
+
+/// How long each key is used for
+pub const ROTATION: Duration = Duration::from_secs(15 * 60);
+
+const APPLE_COMPANY_ID: u16 = 0x004c;
+
+/// Offline finding frame type and length
+const FRAME_TYPE: u8 = 0x12;
+const FRAME_LEN: u8 = 0x19;
+
+static KEYS: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/findnet_keys.bin"));
+
+const _: () = assert!(
+    !KEYS.is_empty() && KEYS.len() % KEY_SIZE == 0,
+    "findnet_keys.bin must hold whole keys"
+);
+
+/// The key for the current rotation period. Uses the wall clock when we
+/// have one, so the schedule survives reboots, and uptime otherwise.
+pub fn current_key() -> &'static [u8; KEY_SIZE] {
+    let secs = match clock::now() {
+        Some((unix_ms, _)) => unix_ms / 1000,
+        None => Instant::now().as_secs(),
+    };
+    let count = KEYS.len() / KEY_SIZE;
+    let idx = (secs / ROTATION.as_secs()) as usize % count;
+    KEYS[idx * KEY_SIZE..][..KEY_SIZE].try_into().unwrap()
+}
+
+/// The random static address for `key`: its first 6 bytes with the top
+/// two bits set, least significant byte first
+pub fn address(key: &[u8; KEY_SIZE]) -> [u8; 6] {
+    let mut addr: [u8; 6] = key[..6].try_into().unwrap();
+    addr[0] |= 0xc0;
+    addr.reverse();
+    addr
+}
+
+/// Manufacturer data payload for `key`: the rest of the key, with the two
+/// bits the address couldn't carry
+pub fn payload(key: &[u8; KEY_SIZE]) -> [u8; 27] {
+    let mut payload = [0; 27];
+    payload[0] = FRAME_TYPE;
+    payload[1] = FRAME_LEN;
+    // status byte, nothing to report
+    payload[2] = 0;
+    payload[3..25].copy_from_slice(&key[6..]);
+    payload[25] = key[0] >> 6;
+    // hint byte
+    payload[26] = 0;
+    payload
+}
+
+/// Advertise the current key for `window`, then switch back to `own` as
+/// our address for connectable advertising.
+pub async fn broadcast<C: Controller>(
+    stack: Stack<'_, C>,
+    peripheral: &mut Peripheral<'_, C>,
+    own: [u8; 6],
+    window: Duration,
+) -> Result<(), BleHostError<C::Error>> {
+    let key = current_key();
+    let payload = payload(key);
+
+    // the frame fills the whole advertising packet, there's no room for
+    // flags
+    let mut adv_data = [0; 31];
+    let len = AdStructure::encode_slice(
+        &[AdStructure::ManufacturerSpecificData {
+            company_identifier: APPLE_COMPANY_ID,
+            payload: &payload,
+        }],
+        &mut adv_data[..],
+    )?;
+
+    info!("[findnet] broadcasting");
+    stack
+        .command(LeSetRandomAddr::new(BdAddr::new(address(key))))
+        .await?;
+    let result = async {
+        let _advertiser = peripheral
+            .advertise(
+                &Default::default(),
+                Advertisement::NonconnectableNonscannableUndirected {
+                    adv_data: &adv_data[..len],
+                },
+            )
+            .await?;
+        Timer::after(window).await;
+        Ok(())
+    }
+    .await;
+    stack
+        .command(LeSetRandomAddr::new(BdAddr::new(own)))
+        .await?;
+
+    result
+}
@@ -0,0 +1,189 @@
+//! Flash worker
+//!
+//! Erasing or programming flash stalls the whole chip, since we execute
+//! from it. Doing that from a GATT handler risks the connection timing
+//! out, so all flash writes go through a single worker task instead: jobs
+//! are queued, run one at a time in order, and their completions
+//! published. [`execute`] submits a job and waits for it, [`submit`] just
+//! queues it.
+//!
+//! Anything touching the flash directly goes through [`with_flash`], which
+//! hands out the driver one user at a time. Its closure is synchronous on
+//! purpose: nothing else gets to run on this core while the flash is busy,
+//! and the driver itself runs erase/program from RAM with interrupts
+//! masked and core 1 parked (core 1 has to have been started with
+//! `spawn_core1` for that).
+
+use core::cell::Cell;
+
+use embassy_rp::flash;
+use embassy_rp::flash::Blocking;
+use embassy_rp::flash::Flash;
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::pubsub::Subscriber;
+use embassy_sync::pubsub::WaitResult;
+
+use crate::error;
+use crate::fault;
+use crate::monitor;
+use crate::system::FLASH_SIZE;
+
+/// Largest write a single job carries; matches the flash page size
+pub const PAGE_SIZE: usize = 256;
+
+/// Jobs that can be queued before [`submit`] waits
+const QUEUE_LEN: usize = 4;
+
+/// Max number of concurrent completion subscribers, [`execute`] callers
+/// included
+const SUBSCRIBERS_MAX: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Flash(flash::Error),
+    /// Outside the flash chip
+    OutOfRange,
+    /// No completion subscriber left to wait with
+    Busy,
+}
+
+#[derive(Clone)]
+pub enum Op {
+    /// Erase `from..to`, both sector aligned
+    Erase { from: u32, to: u32 },
+    /// Program `data[..len]` at `offset`, which has to be erased
+    Write {
+        offset: u32,
+        len: u16,
+        data: [u8; PAGE_SIZE],
+    },
+}
+
+impl Op {
+    /// A write of up to [`PAGE_SIZE`] bytes, `None` if `data` is longer
+    pub fn write(offset: u32, data: &[u8]) -> Option<Self> {
+        let mut page = [0; PAGE_SIZE];
+        page.get_mut(..data.len())?.copy_from_slice(data);
+        Some(Self::Write {
+            offset,
+            len: data.len() as u16,
+            data: page,
+        })
+    }
+}
+
+/// Identifies a submitted job in its completion
+pub type JobId = u32;
+
+struct Job {
+    id: JobId,
+    op: Op,
+}
+
+static JOBS: Channel<CriticalSectionRawMutex, Job, QUEUE_LEN> = Channel::new();
+
+static NEXT_ID: Mutex<CriticalSectionRawMutex, Cell<JobId>> = Mutex::new(Cell::new(0));
+
+pub type Completion = (JobId, Result<(), Error>);
+
+pub type CompletionSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, Completion, QUEUE_LEN, SUBSCRIBERS_MAX, 0>;
+
+static COMPLETIONS: PubSubChannel<
+    CriticalSectionRawMutex,
+    Completion,
+    QUEUE_LEN,
+    SUBSCRIBERS_MAX,
+    0,
+> = PubSubChannel::new();
+
+/// Subscribe to job completions, `None` if all subscriber slots are in use
+pub fn completions() -> Option<CompletionSubscriber> {
+    COMPLETIONS.subscriber().ok()
+}
+
+/// Queue `op`, waiting for room in the queue
+pub async fn submit(op: Op) -> JobId {
+    let id = NEXT_ID.lock(|next| {
+        let id = next.get();
+        next.set(id.wrapping_add(1));
+        id
+    });
+    JOBS.send(Job { id, op }).await;
+    id
+}
+
+/// Queue `op` and wait for it to finish
+pub async fn execute(op: Op) -> Result<(), Error> {
+    // subscribe first so the completion can't slip past
+    let mut done = completions().ok_or(Error::Busy)?;
+    let id = submit(op).await;
+    loop {
+        match done.next_message().await {
+            WaitResult::Message((done_id, result)) if done_id == id => return result,
+            WaitResult::Message(_) => {}
+            WaitResult::Lagged(_) => error!("[flash] completion subscriber lagged"),
+        }
+    }
+}
+
+/// Write `data` at `offset`, split into pages. The range has to be erased.
+pub async fn write(offset: u32, data: &[u8]) -> Result<(), Error> {
+    for (i, chunk) in data.chunks(PAGE_SIZE).enumerate() {
+        let offset = offset + (i * PAGE_SIZE) as u32;
+        execute(Op::write(offset, chunk).unwrap()).await?;
+    }
+    Ok(())
+}
+
+pub type Driver = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+static DRIVER: mutex::Mutex<CriticalSectionRawMutex, Option<Driver>> = mutex::Mutex::new(None);
+
+/// Hand the driver over to [`with_flash`], once at boot
+pub fn init(flash: Driver) {
+    // nothing can hold the lock before there's a driver in it
+    *DRIVER.try_lock().unwrap() = Some(flash);
+}
+
+/// Run `f` with exclusive use of the flash, waiting for any other user to
+/// finish. Panics if [`init`] wasn't called.
+pub async fn with_flash<R>(f: impl FnOnce(&mut Driver) -> R) -> R {
+    let mut driver = DRIVER.lock().await;
+    f(driver.as_mut().expect("flash not initialized"))
+}
+
+/// Run jobs as they come in
+pub async fn run() -> ! {
+    loop {
+        monitor::FLASH.pause();
+        let Job { id, op } = JOBS.receive().await;
+        monitor::FLASH.ping();
+        let result = with_flash(|flash| match op {
+            Op::Erase { from, to } if to as usize <= FLASH_SIZE && from <= to => {
+                flash.blocking_erase(from, to).map_err(Error::Flash)
+            }
+            Op::Write { offset, len, data } if offset as usize + len as usize <= FLASH_SIZE => {
+                flash
+                    .blocking_write(offset, &data[..len as usize])
+                    .map_err(Error::Flash)
+            }
+            _ => Err(Error::OutOfRange),
+        })
+        .await;
+
+        if let Err(e) = result {
+            error!("[flash] job {} failed: {:?}", id, e);
+            fault::raise(fault::FLASH_JOB);
+        }
+        COMPLETIONS
+            .immediate_publisher()
+            .publish_immediate((id, result));
+    }
+}
@@ -0,0 +1,99 @@
+//! Logging backend
+//!
+//! The crate logs through the macros here rather than a logging crate
+//! directly, so the backend is picked with a feature: `log` (the default)
+//! goes out the USB serial port along with the [`crate::console`], `defmt`
+//! goes over RTT to a debug probe. With neither, logging compiles away.
+//!
+//! Only format strings both understand work: positional `{}` and `{:?}`
+//! with width and hex hints like `{:08x}`, no inline names. Values logged
+//! with `{:?}` need `defmt::Format` as well as `Debug`, which the crate's
+//! own types derive in `defmt` builds. Byte strings go through [`Bytes`]
+//! to come out as hex with either, and values only known to be `Debug`,
+//! like errors generic over the controller, through [`Dbg`].
+
+use core::fmt;
+
+#[cfg(all(feature = "log", feature = "defmt"))]
+compile_error!("the `log` and `defmt` features can't both be enabled");
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:ident, $s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(feature = "log")]
+        ::log::$level!($s $(, $x)*);
+        #[cfg(feature = "defmt")]
+        ::defmt::$level!($s $(, $x)*);
+        #[cfg(not(any(feature = "log", feature = "defmt")))]
+        let _ = ($(&$x),*);
+    }};
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::__log!(trace, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::__log!(debug, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::__log!(info, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::__log!(warn, $($arg)*)
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::__log!(error, $($arg)*)
+    };
+}
+
+/// Bytes to log as hex, like `[0a, ff]`
+pub struct Bytes<'a>(pub &'a [u8]);
+
+impl fmt::Debug for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x?}", self.0)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Bytes<'_> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "{=[u8]:02x}", self.0)
+    }
+}
+
+/// A value to log with its `Debug` impl, formatted on the device with
+/// `defmt`
+pub struct Dbg<'a, T: ?Sized>(pub &'a T);
+
+impl<T: fmt::Debug + ?Sized> fmt::Debug for Dbg<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: fmt::Debug + ?Sized> defmt::Format for Dbg<'_, T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "{:?}", defmt::Debug2Format(self.0))
+    }
+}
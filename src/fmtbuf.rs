@@ -0,0 +1,185 @@
+//! Formatting into fixed buffers
+//!
+//! [`Writer`] and [`Buf`] take `write!` output into a byte buffer,
+//! truncating at a character boundary instead of failing once it's full,
+//! so a `write!` that ran out of room leaves valid UTF-8 and an error
+//! never has to be unwrapped. Whether anything was dropped is kept for
+//! callers that care.
+//!
+//! [`decimal`] and [`hex`] render numbers without going through
+//! `core::fmt` at all, for characteristic values built on every read.
+
+use core::fmt;
+
+/// Appends to a borrowed buffer, dropping what doesn't fit
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    truncated: bool,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether some output was dropped
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only whole characters get copied in
+        core::str::from_utf8(self.as_bytes()).unwrap_or_default()
+    }
+}
+
+impl fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.truncated |= !append(self.buf, &mut self.len, s);
+        Ok(())
+    }
+}
+
+/// Copy as much of `s` to `buf` after `len` as fits without splitting a
+/// character, returning whether all of it did
+fn append(buf: &mut [u8], len: &mut usize, s: &str) -> bool {
+    let room = buf.len() - *len;
+    let n = if s.len() <= room {
+        s.len()
+    } else {
+        (0..=room)
+            .rev()
+            .find(|&i| s.is_char_boundary(i))
+            .unwrap_or(0)
+    };
+    buf[*len..*len + n].copy_from_slice(&s.as_bytes()[..n]);
+    *len += n;
+    n == s.len()
+}
+
+/// An owned buffer of `N` bytes that `write!` truncates into, like
+/// [`Writer`]
+#[derive(Clone, Copy)]
+pub struct Buf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> Buf<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether some output was dropped
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only whole characters get copied in
+        core::str::from_utf8(self.as_bytes()).unwrap_or_default()
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.truncated = false;
+    }
+}
+
+impl<const N: usize> Default for Buf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for Buf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.truncated |= !append(&mut self.buf, &mut self.len, s);
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Display for Buf<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Format into a new [`Buf`]: `fmtbuf::format::<20>(format_args!(..))`
+pub fn format<const N: usize>(args: fmt::Arguments<'_>) -> Buf<N> {
+    let mut buf = Buf::new();
+    // our write_str never fails, and Display impls only fail on that
+    let _ = fmt::write(&mut buf, args);
+    buf
+}
+
+/// Longest u32 in decimal
+pub const DECIMAL_MAX: usize = 10;
+
+/// `value` in decimal, without leading zeros
+pub fn decimal(value: u32) -> Buf<DECIMAL_MAX> {
+    let mut digits = [0; DECIMAL_MAX];
+    let mut at = DECIMAL_MAX;
+    let mut rest = value;
+    loop {
+        at -= 1;
+        digits[at] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+
+    let mut out = Buf::new();
+    let len = DECIMAL_MAX - at;
+    out.buf[..len].copy_from_slice(&digits[at..]);
+    out.len = len;
+    out
+}
+
+/// `bytes` in lowercase hex, two digits each, as far as they fit in `out`.
+/// Returns the number of bytes written.
+pub fn hex(bytes: &[u8], out: &mut [u8]) -> usize {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut len = 0;
+    for (byte, pair) in bytes.iter().zip(out.chunks_exact_mut(2)) {
+        pair[0] = DIGITS[(byte >> 4) as usize];
+        pair[1] = DIGITS[(byte & 0x0f) as usize];
+        len += 2;
+    }
+    len
+}
@@ -0,0 +1,421 @@
+//! Advertisement gateway
+//!
+//! Setting `gateway.broker` to `host` or `host:port` forwards the
+//! advertisements the observer sees to that MQTT broker, each on
+//! `<gateway.topic>/<address>` as `{"rssi":-60,"data":"0201..."}`. While
+//! the gateway is on, the central scans back to back instead of in beacon
+//! windows.
+//!
+//! A device sending the same data again within [`DEDUP_WINDOW`] isn't
+//! forwarded again, and no more than `gateway.rate` reports go out per
+//! second. `gateway.allow` and `gateway.deny` take comma separated
+//! addresses, `aa:bb:cc:dd:ee:ff,...`, three fit. With an allow list only
+//! the devices on it are forwarded, the deny list always applies.
+//!
+//! The session also publishes the telemetry the MQTT sink queues (see
+//! [`crate::telemetry`]), on `<telemetry.topic>/<signal>` with the spaces
+//! in the signal's name as underscores.
+//!
+//! With `gateway.tls` on, the session goes over TLS with the provisioned
+//! key (see [`crate::tls`]), to port 8883 unless the broker says otherwise.
+//! It steps aside for a firmware download, which needs the TLS records, and
+//! connects again after it.
+
+use core::fmt::Write as _;
+
+#[cfg(feature = "tls")]
+use embassy_futures::select::select;
+use embassy_futures::select::select4;
+#[cfg(feature = "tls")]
+use embassy_futures::select::Either;
+use embassy_futures::select::Either4;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::driver::Driver;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::IpEndpoint;
+use embassy_net::Stack;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use embedded_io_async::Read;
+use embedded_io_async::Write;
+
+use crate::beacons;
+use crate::config;
+use crate::config::Text;
+use crate::error;
+use crate::fault;
+use crate::fmtbuf;
+use crate::info;
+use crate::integrity;
+use crate::mqtt;
+use crate::observer;
+use crate::observer::Report;
+use crate::system;
+use crate::telemetry;
+#[cfg(feature = "tls")]
+use crate::tls;
+
+crate::config_key!(
+    /// Broker as `host` or `host:port`, empty to turn the gateway off
+    pub BROKER: Text = "gateway.broker",
+    Text::new(""),
+);
+
+crate::config_key!(
+    /// Topic the per-device topics go under
+    pub TOPIC: Text = "gateway.topic",
+    Text::new("mansion/adv"),
+);
+
+crate::config_key!(
+    /// Most reports forwarded per second
+    pub RATE: u32 = "gateway.rate",
+    10,
+);
+
+#[cfg(feature = "tls")]
+crate::config_key!(
+    /// Connect to the broker over TLS
+    pub TLS: bool = "gateway.tls",
+    false,
+);
+
+crate::config_key!(
+    /// Addresses to forward, empty for all
+    pub ALLOW: Text = "gateway.allow",
+    Text::new(""),
+);
+
+crate::config_key!(
+    /// Addresses never to forward
+    pub DENY: Text = "gateway.deny",
+    Text::new(""),
+);
+
+const PORT: u16 = 1883;
+
+#[cfg(feature = "tls")]
+const TLS_PORT: u16 = 8883;
+
+/// Keep alive we ask the broker for, we ping at half of it
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// How long the same data from a device counts as a duplicate
+pub const DEDUP_WINDOW: Duration = Duration::from_secs(30);
+
+/// Devices remembered for deduplication
+const SEEN_MAX: usize = 16;
+
+/// Time between attempts after a failure
+const RETRY: Duration = Duration::from_secs(10);
+
+/// `{"rssi":-128,"data":""}` around 31 bytes of data in hex
+const PAYLOAD_MAX: usize = 24 + 62;
+
+/// Whether reports are being forwarded
+pub fn enabled() -> bool {
+    !BROKER.get().is_empty()
+}
+
+/// Whether `address` is in the comma separated `list`. Entries that don't
+/// parse never match.
+fn listed(list: &str, address: &[u8]) -> bool {
+    list.split(',')
+        .filter_map(|entry| beacons::parse_address(entry.trim()))
+        .any(|entry| entry == address)
+}
+
+fn check_list(key: &config::Key<Text>) {
+    let list = key.get();
+    for entry in list.as_str().split(',').map(str::trim) {
+        if !entry.is_empty() && beacons::parse_address(entry).is_none() {
+            error!("[gateway] {} has invalid address {:?}", key.name, entry);
+        }
+    }
+}
+
+fn allowed(report: &Report) -> bool {
+    let address = report.addr.raw();
+    let allow = ALLOW.get();
+    if !allow.is_empty() && !listed(allow.as_str(), address) {
+        return false;
+    }
+    !listed(DENY.get().as_str(), address)
+}
+
+#[derive(Clone, Copy)]
+struct Seen {
+    address: [u8; 6],
+    crc: u32,
+    at: Instant,
+}
+
+/// What's been forwarded lately
+struct Filter {
+    seen: [Option<Seen>; SEEN_MAX],
+    window_start: Instant,
+    sent: u32,
+}
+
+impl Filter {
+    fn new() -> Self {
+        Self {
+            seen: [None; SEEN_MAX],
+            window_start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Whether `report` should go out, counting it if so
+    fn pass(&mut self, report: &Report) -> bool {
+        if !allowed(report) {
+            return false;
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.sent = 0;
+        }
+        if self.sent >= RATE.get() {
+            return false;
+        }
+
+        let mut address = [0; 6];
+        address.copy_from_slice(report.addr.raw());
+        let crc = integrity::crc32(report.data());
+        let slot = match self
+            .seen
+            .iter()
+            .position(|s| s.is_some_and(|s| s.address == address))
+        {
+            Some(idx) => {
+                let seen = self.seen[idx].unwrap();
+                if seen.crc == crc && now.duration_since(seen.at) < DEDUP_WINDOW {
+                    return false;
+                }
+                idx
+            }
+            // an empty slot, or the device we heard from longest ago
+            None => self
+                .seen
+                .iter()
+                .position(|s| s.is_none())
+                .or_else(|| {
+                    self.seen
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, s)| s.map(|s| s.at))
+                        .map(|(idx, _)| idx)
+                })
+                .unwrap_or(0),
+        };
+        self.seen[slot] = Some(Seen {
+            address,
+            crc,
+            at: now,
+        });
+        self.sent += 1;
+        true
+    }
+}
+
+async fn forward(socket: &mut (impl Read + Write), report: &Report) -> Result<(), mqtt::Error> {
+    // most significant byte first, as addresses are usually written
+    let mut address = [0; 6];
+    address.copy_from_slice(report.addr.raw());
+    address.reverse();
+    let mut hex = [0; 12];
+    fmtbuf::hex(&address, &mut hex);
+    let mut topic = fmtbuf::Buf::<{ mqtt::TOPIC_MAX }>::new();
+    let _ = write!(
+        topic,
+        "{}/{}",
+        TOPIC.get().as_str(),
+        core::str::from_utf8(&hex).unwrap_or_default()
+    );
+    if topic.is_truncated() {
+        return Err(mqtt::Error::TooLarge);
+    }
+
+    let mut data = [0; 62];
+    let len = fmtbuf::hex(report.data(), &mut data);
+    let mut payload = fmtbuf::Buf::<PAYLOAD_MAX>::new();
+    let _ = write!(
+        payload,
+        "{{\"rssi\":{},\"data\":\"{}\"}}",
+        report.rssi,
+        core::str::from_utf8(&data[..len]).unwrap_or_default()
+    );
+    mqtt::publish(socket, topic.as_str(), payload.as_bytes()).await
+}
+
+async fn publish_sample(
+    socket: &mut (impl Read + Write),
+    payload: &telemetry::Payload,
+) -> Result<(), mqtt::Error> {
+    let mut topic = fmtbuf::Buf::<{ mqtt::TOPIC_MAX }>::new();
+    let _ = write!(topic, "{}/", telemetry::TOPIC.get().as_str());
+    for c in payload.signal.name().chars() {
+        let _ = topic.write_char(if c == ' ' { '_' } else { c });
+    }
+    if topic.is_truncated() {
+        return Err(mqtt::Error::TooLarge);
+    }
+    mqtt::publish(socket, topic.as_str(), payload.as_bytes()).await
+}
+
+/// Split `host:port`, defaulting to `default`
+fn split_broker(broker: &str, default: u16) -> Result<(&str, u16), &'static str> {
+    match broker.rsplit_once(':') {
+        Some((host, port)) => Ok((host, port.parse().map_err(|_| "invalid port")?)),
+        None => Ok((broker, default)),
+    }
+}
+
+/// Connect to the broker and forward reports until something fails or the
+/// broker changes
+async fn session<D: Driver>(
+    stack: &Stack<D>,
+    reports: &mut observer::ReportSubscriber,
+    changes: &mut Option<config::ChangeSubscriber>,
+) -> Result<(), &'static str> {
+    let broker = BROKER.get();
+    #[cfg(feature = "tls")]
+    let secure = TLS.get();
+    #[cfg(feature = "tls")]
+    let port = if secure { TLS_PORT } else { PORT };
+    #[cfg(not(feature = "tls"))]
+    let port = PORT;
+    let (host, port) = split_broker(broker.as_str(), port)?;
+    // IP addresses come back as they are
+    let addrs = stack
+        .dns_query(host, DnsQueryType::A)
+        .await
+        .map_err(|_| "dns lookup failed")?;
+    let endpoint = IpEndpoint::new(*addrs.first().ok_or("no address")?, port);
+
+    let mut rx_buffer = [0; 256];
+    let mut tx_buffer = [0; 512];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(KEEP_ALIVE));
+    socket
+        .connect(endpoint)
+        .await
+        .map_err(|_| "connect failed")?;
+
+    #[cfg(feature = "tls")]
+    if secure {
+        let mut records = tls::take().await;
+        let mut session = tls::open(&mut socket, host, &mut records).await?;
+        let published = select(
+            publish(&mut session, broker.as_str(), reports, changes),
+            tls::wanted(),
+        )
+        .await;
+        match published {
+            Either::First(result) => result?,
+            Either::Second(()) => info!("[gateway] closing for another TLS connection"),
+        }
+        let _ = session.close().await;
+        socket.close();
+        let _ = socket.flush().await;
+        return Ok(());
+    }
+    publish(&mut socket, broker.as_str(), reports, changes).await?;
+    socket.close();
+    let _ = socket.flush().await;
+    Ok(())
+}
+
+/// Open the MQTT session over `socket` and forward reports until
+/// something fails or the broker changes
+async fn publish(
+    socket: &mut (impl Read + Write),
+    broker: &str,
+    reports: &mut observer::ReportSubscriber,
+    changes: &mut Option<config::ChangeSubscriber>,
+) -> Result<(), &'static str> {
+    let mut client_id = [0; 24];
+    client_id[..8].copy_from_slice(b"mansion-");
+    client_id[8..].copy_from_slice(&system::DeviceId::get().hex());
+    let client_id = core::str::from_utf8(&client_id).unwrap_or_default();
+    mqtt::connect(socket, client_id, KEEP_ALIVE)
+        .await
+        .map_err(|e| {
+            error!("[gateway] {:?}", e);
+            "broker refused the session"
+        })?;
+    info!("[gateway] forwarding to {}", broker);
+    check_list(&ALLOW);
+    check_list(&DENY);
+
+    // drop what queued up while we weren't connected
+    while reports.try_next_message_pure().is_some() {}
+    while telemetry::MQTT_QUEUE.try_receive().is_ok() {}
+
+    let mut filter = Filter::new();
+    let changed = async {
+        match changes.as_mut() {
+            Some(changes) => while !BROKER.is(changes.next_message_pure().await) {},
+            None => core::future::pending().await,
+        }
+    };
+    let mut changed = core::pin::pin!(changed);
+    loop {
+        match select4(
+            reports.next_message_pure(),
+            Timer::after(KEEP_ALIVE / 2),
+            changed.as_mut(),
+            telemetry::MQTT_QUEUE.receive(),
+        )
+        .await
+        {
+            Either4::First(report) => {
+                if filter.pass(&report) {
+                    if let Err(e) = forward(socket, &report).await {
+                        error!("[gateway] {:?}", e);
+                        return Err("publish failed");
+                    }
+                }
+            }
+            Either4::Second(()) => mqtt::ping(socket).await.map_err(|e| {
+                error!("[gateway] {:?}", e);
+                "ping failed"
+            })?,
+            Either4::Third(()) => {
+                info!("[gateway] broker changed");
+                return Ok(());
+            }
+            Either4::Fourth(payload) => {
+                if let Err(e) = publish_sample(socket, &payload).await {
+                    error!("[gateway] {:?}", e);
+                    return Err("publish failed");
+                }
+            }
+        }
+    }
+}
+
+pub async fn run<D: Driver>(stack: &Stack<D>) -> ! {
+    let Some(mut reports) = observer::subscribe() else {
+        error!("[gateway] no report subscriber left");
+        return core::future::pending().await;
+    };
+    let mut changes = config::subscribe();
+    loop {
+        while !enabled() {
+            let Some(changes) = changes.as_mut() else {
+                return core::future::pending().await;
+            };
+            while !BROKER.is(changes.next_message_pure().await) {}
+        }
+
+        if let Err(e) = session(stack, &mut reports, &mut changes).await {
+            error!("[gateway] {}", e);
+            fault::raise(fault::NET_GATEWAY);
+            Timer::after(RETRY).await;
+        }
+    }
+}
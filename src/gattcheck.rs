@@ -0,0 +1,223 @@
+//! Attribute table self-checks
+//!
+//! We build the GATT table by hand, so nothing stops a service definition
+//! from breaking the rules a central relies on. [`check`] walks the table
+//! once at startup and logs anything that breaks the structure GATT
+//! mandates:
+//!
+//! - the GAP service comes first and has the device name and appearance
+//! - the GATT service is present
+//! - characteristics that notify or indicate have a CCCD
+//! - properties match what's stored: writable properties need a writable
+//!   value, and every characteristic can be read, written or notified
+//! - characteristic UUIDs are unique within a service
+//!
+//! Our own services are checked before that, when building: [`assert_distinct`]
+//! and [`assert_fit`] fail the build on duplicate UUIDs and values longer
+//! than ATT allows, and [`is_sig`] keeps custom UUIDs out of the range the
+//! Bluetooth SIG assigns from. The runtime duplicate check stays for the
+//! standard services. Values live in buffers of ours rather than in the
+//! server's `attribute_data_size` storage, so [`VALUE_MAX`] is what they're
+//! held to.
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use trouble_host::prelude::*;
+
+use crate::error;
+use crate::info;
+
+const GAP: Uuid = Uuid::Uuid16(0x1800u16.to_le_bytes());
+const GATT: Uuid = Uuid::Uuid16(0x1801u16.to_le_bytes());
+const DEVICE_NAME: Uuid = Uuid::Uuid16(0x2a00u16.to_le_bytes());
+const APPEARANCE: Uuid = Uuid::Uuid16(0x2a01u16.to_le_bytes());
+
+/// Longest attribute value ATT allows
+pub const VALUE_MAX: usize = 512;
+
+/// The Bluetooth Base UUID, `00000000-0000-1000-8000-00805f9b34fb`, little
+/// endian like [`Uuid`]'s bytes. Every UUID sharing its first 12 bytes is
+/// the SIG's to assign.
+const BASE: [u8; 16] = [
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0, 0, 0, 0,
+];
+
+/// Characteristics per service we can check for duplicates
+const MAX_CHARACTERISTICS: usize = 24;
+
+#[derive(Default)]
+struct ServiceState {
+    uuid: Option<Uuid>,
+    characteristics: [Option<Uuid>; MAX_CHARACTERISTICS],
+    /// Handle of a notifying characteristic still waiting for its CCCD
+    needs_cccd: Option<u16>,
+}
+
+impl ServiceState {
+    /// Note a characteristic, `false` if the service already has one with
+    /// the same UUID. Past [`MAX_CHARACTERISTICS`] duplicates go unnoticed.
+    fn add_characteristic(&mut self, uuid: Uuid) -> bool {
+        if self.characteristics.iter().flatten().any(|u| *u == uuid) {
+            return false;
+        }
+        if let Some(slot) = self.characteristics.iter_mut().find(|u| u.is_none()) {
+            *slot = Some(uuid);
+        }
+        true
+    }
+}
+
+/// Whether the 128 bit `uuid` is in the range the SIG assigns from
+pub const fn is_sig(uuid: &[u8; 16]) -> bool {
+    let mut idx = 0;
+    while idx < 12 {
+        if uuid[idx] != BASE[idx] {
+            return false;
+        }
+        idx += 1;
+    }
+    true
+}
+
+const fn same(a: &Uuid, b: &Uuid) -> bool {
+    match (a, b) {
+        (Uuid::Uuid16(a), Uuid::Uuid16(b)) => a[0] == b[0] && a[1] == b[1],
+        (Uuid::Uuid128(a), Uuid::Uuid128(b)) => {
+            let mut idx = 0;
+            while idx < a.len() {
+                if a[idx] != b[idx] {
+                    return false;
+                }
+                idx += 1;
+            }
+            true
+        }
+        // a short UUID is the SIG's, which custom ones stay out of
+        _ => false,
+    }
+}
+
+/// Fail the build if two UUIDs in `groups` are the same. Call it in a
+/// `const _: () = ..` with the UUIDs of a service, or of every service.
+pub const fn assert_distinct(groups: &[&[Uuid]]) {
+    let mut group = 0;
+    while group < groups.len() {
+        let mut idx = 0;
+        while idx < groups[group].len() {
+            let uuid = &groups[group][idx];
+            // everything after it, in its group and the later ones
+            let mut other_group = group;
+            let mut other = idx + 1;
+            while other_group < groups.len() {
+                while other < groups[other_group].len() {
+                    if same(uuid, &groups[other_group][other]) {
+                        panic!("duplicate UUID");
+                    }
+                    other += 1;
+                }
+                other_group += 1;
+                other = 0;
+            }
+            idx += 1;
+        }
+        group += 1;
+    }
+}
+
+/// Fail the build if any of `sizes` is over `max`
+pub const fn assert_fit(sizes: &[usize], max: usize) {
+    let mut idx = 0;
+    while idx < sizes.len() {
+        if sizes[idx] > max {
+            panic!("characteristic value too long");
+        }
+        idx += 1;
+    }
+}
+
+/// Check `table`, logging every violation. Returns how many there were.
+pub fn check<M: RawMutex, const N: usize>(table: &AttributeTable<'_, M, N>) -> usize {
+    let mut violations = 0;
+    let mut violation = |handle: u16, what: &str| {
+        error!("[gattcheck] handle {}: {}", handle, what);
+        violations += 1;
+    };
+
+    let mut first_service = true;
+    let mut has_gatt = false;
+    let mut gap = (false, false);
+    let mut service = ServiceState::default();
+
+    table.iterate(|mut it| {
+        while let Some(att) = it.next() {
+            match &att.data {
+                AttributeData::Service { uuid } => {
+                    if let Some(handle) = service.needs_cccd {
+                        violation(handle, "notifying characteristic without a CCCD");
+                    }
+                    if first_service && *uuid != GAP {
+                        violation(att.handle, "GAP isn't the first service");
+                    }
+                    first_service = false;
+                    has_gatt |= *uuid == GATT;
+                    service = ServiceState {
+                        uuid: Some(*uuid),
+                        ..Default::default()
+                    };
+                }
+                AttributeData::Declaration { props, uuid, .. } => {
+                    if let Some(handle) = service.needs_cccd.take() {
+                        violation(handle, "notifying characteristic without a CCCD");
+                    }
+                    if !service.add_characteristic(*uuid) {
+                        violation(att.handle, "duplicate characteristic in service");
+                    }
+                    if !props.any(&[
+                        CharacteristicProp::Read,
+                        CharacteristicProp::Write,
+                        CharacteristicProp::WriteWithoutResponse,
+                        CharacteristicProp::Notify,
+                        CharacteristicProp::Indicate,
+                    ]) {
+                        violation(att.handle, "characteristic can't be accessed at all");
+                    }
+                    if props.any(&[CharacteristicProp::Notify, CharacteristicProp::Indicate]) {
+                        service.needs_cccd = Some(att.handle);
+                    }
+                    if service.uuid == Some(GAP) {
+                        gap.0 |= *uuid == DEVICE_NAME;
+                        gap.1 |= *uuid == APPEARANCE;
+                    }
+                }
+                AttributeData::ReadOnlyData { props, .. } => {
+                    if props.any(&[
+                        CharacteristicProp::Write,
+                        CharacteristicProp::WriteWithoutResponse,
+                    ]) {
+                        violation(att.handle, "writable characteristic with read-only value");
+                    }
+                }
+                AttributeData::Cccd { .. } => {
+                    if service.needs_cccd.take().is_none() {
+                        violation(att.handle, "CCCD on a characteristic that doesn't notify");
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+
+    if let Some(handle) = service.needs_cccd {
+        violation(handle, "notifying characteristic without a CCCD");
+    }
+    if !gap.0 || !gap.1 {
+        violation(0, "GAP service lacks the device name or appearance");
+    }
+    if !has_gatt {
+        violation(0, "no GATT service");
+    }
+
+    if violations == 0 {
+        info!("[gattcheck] attribute table ok");
+    }
+    violations
+}
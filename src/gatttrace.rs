@@ -0,0 +1,199 @@
+//! GATT operation trace
+//!
+//! A flight recorder for BLE interactions: the last [`RING_LEN`] reads,
+//! writes and notifications, with the connection, the handle, the length,
+//! how it went and when. The GATT task and the notification path
+//! [`record`] every operation. The `gatt` console command lists them, and
+//! they're read over a paged dataset (see [`crate::paging`]) too. Selecting
+//! page 0 takes a [`snapshot`], which the following pages are cut from, so
+//! the page reads don't push out what's being read.
+//!
+//! An entry is `[op, status, conn: u16, handle: u16, len: u16, timestamp
+//! ms: u32]`, little endian, most recent entry first. The op is 0 for a
+//! read, 1 for a write and 2 for a notification. The status is 0 if it
+//! went through, the ATT error a write was refused with, or [`FAILED`] for
+//! a notification that didn't go out.
+
+use core::cell::RefCell;
+
+use bt_hci::param::ConnHandle;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::info;
+use crate::paging;
+use crate::runtime;
+
+/// Number of operations kept in RAM
+pub const RING_LEN: usize = 64;
+
+/// Size of an encoded [`Entry`]
+pub const ENTRY_SIZE: usize = 12;
+
+/// Status of an operation that went through
+pub const OK: u8 = 0;
+
+/// Status of a notification that didn't go out
+pub const FAILED: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Op {
+    Read = 0,
+    Write = 1,
+    Notify = 2,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Entry {
+    pub op: Op,
+    pub status: u8,
+    pub conn: u16,
+    /// Attribute handle operated on
+    pub handle: u16,
+    /// Length of the value
+    pub len: u16,
+    /// Milliseconds since boot
+    pub timestamp_ms: u32,
+}
+
+impl Entry {
+    pub fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut out = [0; ENTRY_SIZE];
+        out[0] = self.op as u8;
+        out[1] = self.status;
+        out[2..4].copy_from_slice(&self.conn.to_le_bytes());
+        out[4..6].copy_from_slice(&self.handle.to_le_bytes());
+        out[6..8].copy_from_slice(&self.len.to_le_bytes());
+        out[8..12].copy_from_slice(&self.timestamp_ms.to_le_bytes());
+        out
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Ring {
+    entries: [Option<Entry>; RING_LEN],
+    next: usize,
+}
+
+impl Ring {
+    const EMPTY: Self = Self {
+        entries: [None; RING_LEN],
+        next: 0,
+    };
+
+    /// The entries, most recent first
+    fn iter(&self) -> impl Iterator<Item = &Entry> {
+        (1..=RING_LEN)
+            .filter_map(move |i| self.entries[(self.next + RING_LEN - i) % RING_LEN].as_ref())
+    }
+}
+
+static RING: Mutex<CriticalSectionRawMutex, RefCell<Ring>> = Mutex::new(RefCell::new(Ring::EMPTY));
+
+/// The ring as of the last [`snapshot`]
+static SNAPSHOT: Mutex<CriticalSectionRawMutex, RefCell<Ring>> =
+    Mutex::new(RefCell::new(Ring::EMPTY));
+
+/// Record `op` by `conn` on `handle`, with a value of `len` bytes
+pub fn record(conn: ConnHandle, handle: u16, op: Op, len: usize, status: u8) {
+    let entry = Entry {
+        op,
+        status,
+        conn: conn.raw(),
+        handle,
+        len: len.min(u16::MAX as usize) as u16,
+        timestamp_ms: runtime::now().as_millis() as u32,
+    };
+    RING.lock(|ring| {
+        let mut ring = ring.borrow_mut();
+        let next = ring.next;
+        ring.entries[next] = Some(entry);
+        ring.next = (next + 1) % RING_LEN;
+    });
+}
+
+/// Mark the last write by `conn` to `handle` as refused with the ATT error
+/// `code`, once the value turned out not to be acceptable after all
+pub fn refused(conn: ConnHandle, handle: u16, code: u8) {
+    let conn = conn.raw();
+    RING.lock(|ring| {
+        let mut ring = ring.borrow_mut();
+        let next = ring.next;
+        let last = (1..=RING_LEN)
+            .map(|i| (next + RING_LEN - i) % RING_LEN)
+            .find(|&idx| {
+                matches!(ring.entries[idx], Some(e)
+                    if e.op == Op::Write && e.conn == conn && e.handle == handle)
+            });
+        if let Some(entry) = last.and_then(|idx| ring.entries[idx].as_mut()) {
+            entry.status = code;
+        }
+    });
+}
+
+/// Copy the ring for [`Dataset`] to read out
+pub fn snapshot() {
+    let ring = RING.lock(|ring| *ring.borrow());
+    SNAPSHOT.lock(|snapshot| *snapshot.borrow_mut() = ring);
+}
+
+/// The ring as of the last [`snapshot`], most recent entry first
+pub struct Dataset;
+
+impl paging::Dataset for Dataset {
+    fn len(&self) -> usize {
+        SNAPSHOT.lock(|snapshot| snapshot.borrow().iter().count() * ENTRY_SIZE)
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        SNAPSHOT.lock(|snapshot| {
+            let mut copied = 0;
+            for (idx, entry) in snapshot.borrow().iter().enumerate() {
+                let start = idx * ENTRY_SIZE;
+                if start + ENTRY_SIZE <= offset || copied == buf.len() {
+                    continue;
+                }
+                let encoded = entry.encode();
+                let src = &encoded[offset.saturating_sub(start)..];
+                let n = src.len().min(buf.len() - copied);
+                buf[copied..copied + n].copy_from_slice(&src[..n]);
+                copied += n;
+            }
+            copied
+        })
+    }
+}
+
+crate::register_command!(
+    GATT,
+    "gatt",
+    "[count]",
+    "list the last GATT operations, most recent first",
+    |args| {
+        let count = match args.opt_str() {
+            Some(count) => count
+                .parse()
+                .map_err(|_| crate::console::Error::Invalid("count"))?,
+            None => RING_LEN,
+        };
+        args.end()?;
+        // logged outside the lock
+        let ring = RING.lock(|ring| *ring.borrow());
+        let now = runtime::now().as_millis() as u32;
+        for entry in ring.iter().take(count) {
+            info!(
+                "{:?} conn {} handle {} len {} status {:#04x}, {}ms ago",
+                entry.op,
+                entry.conn,
+                entry.handle,
+                entry.len,
+                entry.status,
+                now.wrapping_sub(entry.timestamp_ms)
+            );
+        }
+        Ok(())
+    }
+);
@@ -0,0 +1,377 @@
+//! Board GPIO over BLE
+//!
+//! Clients can switch the Pico W's onboard LED, a few spare pins set up as
+//! outputs with `gpio.out0` to `gpio.out3`, and dim an LED on the PWM
+//! output set with `gpio.pwm`. Pins are taken at boot and have to be free
+//! in the pin map, [`pinmap::UNUSED`] leaves one unset.
+//!
+//! The GATT server only records what clients ask for. [`run`] owns the
+//! pins and applies it, and the onboard LED, which hangs off the CYW43
+//! rather than the RP2040, is set by [`onboard`] through the radio's
+//! control, only when the network side isn't busy with it.
+//!
+//! The pins get a GATT service of their own (see [`crate::modules`]), with
+//! a characteristic for the onboard LED and one for each output and the
+//! PWM output assigned at boot. Levels are written as `[0]` or `[1]`, the
+//! duty cycle as a little endian u16 from 0 (off) to [`DUTY_MAX`] (on the
+//! whole period).
+//!
+//! Outputs start low and the duty cycle at 0 on every boot. With
+//! `gpio.persist` they come back as they were last set instead, which is
+//! only safe if whatever hangs off the pins can take being switched on by
+//! a reboot.
+
+use core::cell::Cell;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+use embassy_rp::gpio::Level;
+use embassy_rp::gpio::Output;
+use embassy_rp::pwm;
+use embassy_rp::pwm::Pwm;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use trouble_host::prelude::*;
+
+use crate::blue::gen_uuid;
+use crate::config;
+use crate::error;
+use crate::gattcheck;
+use crate::info;
+use crate::modules;
+use crate::net;
+use crate::persist;
+use crate::pinmap;
+use crate::pinmap::PinMap;
+use crate::settings;
+use crate::writes;
+
+crate::config_key!(
+    /// GPIO driven as an output by clients, unused if `0xff`
+    pub OUT0: u32 = "gpio.out0",
+    pinmap::UNUSED as u32,
+);
+
+crate::config_key!(
+    pub OUT1: u32 = "gpio.out1",
+    pinmap::UNUSED as u32,
+);
+
+crate::config_key!(
+    pub OUT2: u32 = "gpio.out2",
+    pinmap::UNUSED as u32,
+);
+
+crate::config_key!(
+    pub OUT3: u32 = "gpio.out3",
+    pinmap::UNUSED as u32,
+);
+
+crate::config_key!(
+    /// GPIO with the PWM output, unused if `0xff`
+    pub PWM: u32 = "gpio.pwm",
+    pinmap::UNUSED as u32,
+);
+
+crate::config_key!(
+    /// Keep output levels and the duty cycle across reboots
+    pub PERSIST: bool = "gpio.persist",
+    false,
+);
+
+/// Max number of outputs
+pub const OUTPUTS_MAX: usize = 4;
+
+static OUT_KEYS: [&config::Key<u32>; OUTPUTS_MAX] = [&OUT0, &OUT1, &OUT2, &OUT3];
+
+/// Duty cycle that keeps the PWM output high
+pub const DUTY_MAX: u16 = u16::MAX;
+
+/// GPIO of the onboard LED, on the CYW43
+const ONBOARD_LED: u8 = 0;
+
+/// The pins taken at boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Assignment {
+    pub outputs: [Option<u8>; OUTPUTS_MAX],
+    pub pwm: Option<u8>,
+}
+
+static ASSIGNED: Mutex<CriticalSectionRawMutex, Cell<Assignment>> =
+    Mutex::new(Cell::new(Assignment {
+        outputs: [None; OUTPUTS_MAX],
+        pwm: None,
+    }));
+
+static ONBOARD: AtomicBool = AtomicBool::new(false);
+
+static ONBOARD_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Output levels, a bit per output
+static LEVELS: AtomicU8 = AtomicU8::new(0);
+
+static DUTY: AtomicU16 = AtomicU16::new(0);
+
+/// The outputs or the duty cycle changed
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Pick the pins from the configuration, leaving out the ones `pins` has
+/// or that are set twice. Call once at boot, before the radio starts.
+pub fn assign(pins: &PinMap) -> Assignment {
+    let mut taken = [pinmap::UNUSED; OUTPUTS_MAX + 1];
+    let mut count = 0;
+    let mut take = |key: &config::Key<u32>| {
+        let pin = key.get();
+        if pin == pinmap::UNUSED as u32 {
+            return None;
+        }
+        let pin = u8::try_from(pin).ok().filter(|&pin| pins.is_free(pin));
+        match pin.filter(|pin| !taken[..count].contains(pin)) {
+            Some(pin) => {
+                taken[count] = pin;
+                count += 1;
+                Some(pin)
+            }
+            None => {
+                error!("[gpio] {} isn't a free pin, ignored", key.name);
+                None
+            }
+        }
+    };
+
+    let assignment = Assignment {
+        outputs: OUT_KEYS.map(&mut take),
+        pwm: take(&PWM),
+    };
+    info!("[gpio] {:?}", assignment);
+    ASSIGNED.lock(|assigned| assigned.set(assignment));
+    assignment
+}
+
+/// The pin of output `idx`, `None` if it's unset
+pub fn output(idx: usize) -> Option<u8> {
+    ASSIGNED.lock(|assigned| assigned.get().outputs.get(idx).copied().flatten())
+}
+
+/// Whether there's a PWM output
+pub fn has_pwm() -> bool {
+    ASSIGNED.lock(|assigned| assigned.get().pwm.is_some())
+}
+
+/// Parse a level write, `[0]` or `[1]`
+pub fn parse_level(value: &[u8]) -> Option<bool> {
+    match value {
+        [0] => Some(false),
+        [1] => Some(true),
+        _ => None,
+    }
+}
+
+pub fn parse_duty(value: &[u8]) -> Option<u16> {
+    Some(u16::from_le_bytes(value.try_into().ok()?))
+}
+
+pub fn onboard_led() -> bool {
+    ONBOARD.load(Ordering::Relaxed)
+}
+
+pub fn set_onboard_led(on: bool) {
+    ONBOARD.store(on, Ordering::Relaxed);
+    ONBOARD_CHANGED.signal(());
+}
+
+/// Whether output `idx` is high
+pub fn level(idx: usize) -> bool {
+    LEVELS.load(Ordering::Relaxed) & 1 << idx != 0
+}
+
+pub fn set_level(idx: usize, high: bool) {
+    if high {
+        LEVELS.fetch_or(1 << idx, Ordering::Relaxed);
+    } else {
+        LEVELS.fetch_and(!(1 << idx), Ordering::Relaxed);
+    }
+    CHANGED.signal(());
+    store();
+}
+
+pub fn duty() -> u16 {
+    DUTY.load(Ordering::Relaxed)
+}
+
+pub fn set_duty(duty: u16) {
+    DUTY.store(duty, Ordering::Relaxed);
+    CHANGED.signal(());
+    store();
+}
+
+/// Stored as `[levels, duty: u16]`, little endian
+fn store() {
+    if PERSIST.get() {
+        let [lo, hi] = duty().to_le_bytes();
+        persist::store(
+            settings::Key::GPIO,
+            &[LEVELS.load(Ordering::Relaxed), lo, hi],
+        );
+    }
+}
+
+/// Load the stored levels and duty cycle, if `gpio.persist` is set. Call
+/// once at boot, after [`crate::config::load`] and before [`run`].
+pub async fn load() {
+    if !PERSIST.get() {
+        return;
+    }
+    let mut record = [0; 3];
+    match settings::get(settings::Key::GPIO, &mut record).await {
+        Ok(Some(3)) => {
+            LEVELS.store(record[0], Ordering::Relaxed);
+            DUTY.store(
+                u16::from_le_bytes([record[1], record[2]]),
+                Ordering::Relaxed,
+            );
+            info!("[gpio] levels {:#04x}, duty {}", record[0], duty());
+        }
+        Ok(Some(_)) => error!("[gpio] invalid stored levels"),
+        Ok(None) => {}
+        Err(e) => error!("[gpio] loading levels failed: {:?}", e),
+    }
+}
+
+/// PWM configuration for `duty` on `pin`. Even pins are channel A of
+/// their slice, odd ones channel B.
+pub fn pwm_config(pin: u8, duty: u16) -> pwm::Config {
+    let mut config = pwm::Config::default();
+    // the output is high while the counter is below the compare value, so
+    // with top one short of the maximum DUTY_MAX keeps it high, and the
+    // period is about 2kHz at the default clock
+    config.top = DUTY_MAX - 1;
+    if pin % 2 == 0 {
+        config.compare_a = duty;
+    } else {
+        config.compare_b = duty;
+    }
+    config
+}
+
+/// Drive the pins [`assign`] gave, as clients ask
+pub async fn run(
+    mut outputs: [Option<Output<'static>>; OUTPUTS_MAX],
+    mut pwm: Option<Pwm<'static>>,
+) -> ! {
+    let pwm_pin = ASSIGNED.lock(|assigned| assigned.get().pwm);
+    loop {
+        for (idx, output) in outputs.iter_mut().enumerate() {
+            if let Some(output) = output {
+                output.set_level(Level::from(level(idx)));
+            }
+        }
+        if let (Some(pwm), Some(pin)) = (pwm.as_mut(), pwm_pin) {
+            pwm.set_config(&pwm_config(pin, duty()));
+        }
+        CHANGED.wait().await;
+    }
+}
+
+/// Keep the onboard LED as clients asked, for as long as the radio is up.
+/// Only meant for a single caller at a time.
+pub async fn onboard(control: &net::Control<'_>) -> ! {
+    loop {
+        let on = onboard_led();
+        control.lock().await.gpio_set(ONBOARD_LED, on).await;
+        ONBOARD_CHANGED.wait().await;
+    }
+}
+
+const SERVICE_UUID: Uuid = gen_uuid("board gpio");
+const ONBOARD_LED_UUID: Uuid = gen_uuid("onboard led");
+const OUTPUT_UUIDS: [Uuid; OUTPUTS_MAX] = [
+    gen_uuid("gpio out 0"),
+    gen_uuid("gpio out 1"),
+    gen_uuid("gpio out 2"),
+    gen_uuid("gpio out 3"),
+];
+const PWM_DUTY_UUID: Uuid = gen_uuid("pwm duty");
+const _: () = gattcheck::assert_distinct(&[&[ONBOARD_LED_UUID, PWM_DUTY_UUID], &OUTPUT_UUIDS]);
+
+fn write_onboard_led(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let on = parse_level(value).ok_or(writes::AttError::ValueNotAllowed)?;
+    set_onboard_led(on);
+    Ok(())
+}
+
+fn write_output<const IDX: usize>(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let high = parse_level(value).ok_or(writes::AttError::ValueNotAllowed)?;
+    set_level(IDX, high);
+    Ok(())
+}
+
+fn read_output<const IDX: usize>() -> writes::Value {
+    writes::Value::new(&[level(IDX) as u8])
+}
+
+const OUTPUT_WRITES: [writes::Handler; OUTPUTS_MAX] = [
+    write_output::<0>,
+    write_output::<1>,
+    write_output::<2>,
+    write_output::<3>,
+];
+const OUTPUT_READS: [writes::Reader; OUTPUTS_MAX] = [
+    read_output::<0>,
+    read_output::<1>,
+    read_output::<2>,
+    read_output::<3>,
+];
+
+fn write_duty(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let duty = parse_duty(value).ok_or(writes::AttError::InvalidLength)?;
+    set_duty(duty);
+    Ok(())
+}
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    let props = [CharacteristicProp::Read, CharacteristicProp::Write];
+    let spec = writes::Spec::new();
+    svc.add(
+        ONBOARD_LED_UUID,
+        &props,
+        1,
+        spec.with_read(|| writes::Value::new(&[onboard_led() as u8]))
+            .with_write(write_onboard_led),
+    );
+    // only the outputs and the PWM output assigned at boot have one
+    for idx in (0..OUTPUTS_MAX).filter(|&idx| output(idx).is_some()) {
+        svc.add(
+            OUTPUT_UUIDS[idx],
+            &props,
+            1,
+            spec.with_read(OUTPUT_READS[idx])
+                .with_write(OUTPUT_WRITES[idx]),
+        );
+    }
+    if has_pwm() {
+        svc.add(
+            PWM_DUTY_UUID,
+            &props,
+            2,
+            spec.with_read(|| writes::Value::new(&duty().to_le_bytes()))
+                .with_write(write_duty),
+        );
+    }
+}
+
+// the pins are set up and driven from main, which has them
+crate::register_module!(
+    MODULE,
+    "gpio",
+    |_| {},
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |_| true,
+        build: service,
+    },
+);
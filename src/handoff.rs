@@ -0,0 +1,249 @@
+//! Lock-free handoff out of interrupt context
+//!
+//! Interrupt handlers (edge detection, encoder steps) can't take the
+//! async mutexes the BLE tasks use, and must not wait for them. A
+//! [`Queue`] is a single producer, single consumer ring buffer that only
+//! needs atomic loads and stores, which the Cortex-M0+ has: the producer
+//! owns the head, the consumer the tail. A full queue drops the new value
+//! and counts an overflow rather than block.
+//!
+//! Each side is claimed once, so the producer can be moved into an
+//! interrupt handler and the consumer into a task.
+//!
+//! Characteristic updates that pile up while the previous notification is
+//! going out are coalesced: [`UpdateConsumer::receive_batch`] keeps only
+//! the latest value for each handle the caller says can be coalesced, and
+//! delivers every update to the others.
+
+use core::cell::Cell;
+use core::cell::UnsafeCell;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::AtomicWaker;
+
+/// Slots in [`UPDATES`]. A queue holds one value less than it has slots.
+pub const UPDATES_LEN: usize = 16;
+
+/// Longest value an [`Update`] carries
+pub const UPDATE_VALUE_LEN: usize = 8;
+
+/// A new value for the characteristic with attribute handle `handle`,
+/// notified to connected clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Update {
+    pub handle: u16,
+    len: u8,
+    value: [u8; UPDATE_VALUE_LEN],
+}
+
+impl Update {
+    const EMPTY: Self = Self {
+        handle: 0,
+        len: 0,
+        value: [0; UPDATE_VALUE_LEN],
+    };
+
+    /// `None` if `value` is longer than [`UPDATE_VALUE_LEN`]
+    pub fn new(handle: u16, value: &[u8]) -> Option<Self> {
+        let mut update = Self {
+            handle,
+            len: value.len() as u8,
+            ..Self::EMPTY
+        };
+        update.value.get_mut(..value.len())?.copy_from_slice(value);
+        Some(update)
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value[..self.len as usize]
+    }
+}
+
+/// Characteristic updates from interrupt handlers, forwarded by the BLE
+/// tasks
+pub static UPDATES: Queue<Update, UPDATES_LEN> = Queue::new();
+
+pub type UpdateProducer = Producer<Update, UPDATES_LEN>;
+
+pub type UpdateConsumer = Consumer<Update, UPDATES_LEN>;
+
+pub struct Queue<T: Copy, const N: usize> {
+    buf: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Next slot the producer writes, only stored by the producer
+    head: AtomicUsize,
+    /// Next slot the consumer reads, only stored by the consumer
+    tail: AtomicUsize,
+    /// Only stored by the producer
+    overflows: AtomicU32,
+    waker: AtomicWaker,
+    /// Whether the producer and consumer have been claimed
+    claimed: Mutex<CriticalSectionRawMutex, Cell<(bool, bool)>>,
+}
+
+// SAFETY: a slot is only written by the producer while it's free and
+// only read by the consumer once the producer published it through
+// `head`, and there's only ever one of each
+unsafe impl<T: Copy + Send, const N: usize> Sync for Queue<T, N> {}
+
+impl<T: Copy, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflows: AtomicU32::new(0),
+            waker: AtomicWaker::new(),
+            claimed: Mutex::new(Cell::new((false, false))),
+        }
+    }
+
+    /// Claim the producer side, `None` if it was already
+    pub fn producer(&'static self) -> Option<Producer<T, N>> {
+        self.claimed.lock(|claimed| {
+            let (producer, consumer) = claimed.get();
+            claimed.set((true, consumer));
+            (!producer).then_some(Producer { queue: self })
+        })
+    }
+
+    /// Claim the consumer side, `None` if it was already
+    pub fn consumer(&'static self) -> Option<Consumer<T, N>> {
+        self.claimed.lock(|claimed| {
+            let (producer, consumer) = claimed.get();
+            claimed.set((producer, true));
+            (!consumer).then_some(Consumer { queue: self })
+        })
+    }
+
+    /// Values dropped because the queue was full, wrapping
+    pub fn overflows(&self) -> u32 {
+        self.overflows.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Producer<T: Copy + 'static, const N: usize> {
+    queue: &'static Queue<T, N>,
+}
+
+impl<T: Copy, const N: usize> Producer<T, N> {
+    /// Enqueue `value` without blocking. Returns false and counts an
+    /// overflow if the queue is full.
+    pub fn enqueue(&mut self, value: T) -> bool {
+        let queue = self.queue;
+        let head = queue.head.load(Ordering::Relaxed);
+        let next = (head + 1) % N;
+        if next == queue.tail.load(Ordering::Acquire) {
+            let overflows = queue.overflows.load(Ordering::Relaxed);
+            queue
+                .overflows
+                .store(overflows.wrapping_add(1), Ordering::Relaxed);
+            return false;
+        }
+
+        // SAFETY: the slot at `head` isn't visible to the consumer until
+        // `head` moves past it below
+        unsafe { (*queue.buf[head].get()).write(value) };
+        queue.head.store(next, Ordering::Release);
+        queue.waker.wake();
+        true
+    }
+}
+
+pub struct Consumer<T: Copy + 'static, const N: usize> {
+    queue: &'static Queue<T, N>,
+}
+
+impl<T: Copy, const N: usize> Consumer<T, N> {
+    /// Take the oldest value, if any
+    pub fn dequeue(&mut self) -> Option<T> {
+        let queue = self.queue;
+        let tail = queue.tail.load(Ordering::Relaxed);
+        if tail == queue.head.load(Ordering::Acquire) {
+            return None;
+        }
+
+        // SAFETY: the producer published the slot at `tail` and won't
+        // touch it again until `tail` moves past it below
+        let value = unsafe { (*queue.buf[tail].get()).assume_init() };
+        queue.tail.store((tail + 1) % N, Ordering::Release);
+        Some(value)
+    }
+
+    /// Wait for the next value
+    pub async fn receive(&mut self) -> T {
+        poll_fn(|cx| {
+            self.queue.waker.register(cx.waker());
+            match self.dequeue() {
+                Some(value) => Poll::Ready(value),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Values the producer had to drop, wrapping
+    pub fn overflows(&self) -> u32 {
+        self.queue.overflows()
+    }
+}
+
+/// Updates received together, in arrival order except that a coalesced
+/// value takes the place of the one it replaced
+pub struct Batch {
+    updates: [Update; UPDATES_LEN],
+    len: usize,
+}
+
+impl Batch {
+    fn push(&mut self, update: Update, coalesce: bool) {
+        if coalesce {
+            let waiting = self.updates[..self.len]
+                .iter()
+                .position(|u| u.handle == update.handle);
+            if let Some(idx) = waiting {
+                self.updates[idx] = update;
+                return;
+            }
+        }
+        self.updates[self.len] = update;
+        self.len += 1;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Update> {
+        self.updates[..self.len].iter()
+    }
+}
+
+impl UpdateConsumer {
+    /// Wait for the next update, and take whatever else is waiting with it.
+    /// Updates to handles `coalesce` is true for replace waiting ones.
+    pub async fn receive_batch(&mut self, coalesce: impl Fn(u16) -> bool) -> Batch {
+        let mut batch = Batch {
+            updates: [Update::EMPTY; UPDATES_LEN],
+            len: 0,
+        };
+        let first = self.receive().await;
+        batch.push(first, coalesce(first.handle));
+        while batch.len < UPDATES_LEN {
+            match self.dequeue() {
+                Some(update) => batch.push(update, coalesce(update.handle)),
+                None => break,
+            }
+        }
+        batch
+    }
+}
@@ -0,0 +1,150 @@
+//! Controller health
+//!
+//! The CYW43 can lose its Bluetooth core without the HCI transport
+//! erroring. The firmware reports a Hardware Error and starts over, or
+//! stops answering altogether. Either way the host runner keeps waiting
+//! on a controller that has forgotten every connection and the
+//! advertising. [`Guard`] sits between the host and the controller and
+//! notes Hardware Error events. [`watch`] turns those into a [`Loss`].
+//! It also sends the controller a harmless command every
+//! [`PROBE_INTERVAL`] and calls it a loss when [`PROBE_MISSES`] in a row
+//! go unanswered.
+//!
+//! On a loss [`crate::blue::run`] ends with
+//! [`crate::supervisor::Exit::Controller`], and main power cycles the
+//! radio and builds the stack again, like after any other exit. The
+//! address book is kept in RAM (see [`crate::peers`]), not reloaded, and
+//! the address comes out the same (see [`crate::params::AddressMode`]), so
+//! centrals reconnect as before.
+
+use bt_hci::cmd;
+use bt_hci::cmd::info::ReadLocalVersionInformation;
+use bt_hci::controller::Controller;
+use bt_hci::controller::ControllerCmdAsync;
+use bt_hci::controller::ControllerCmdSync;
+use bt_hci::data;
+use bt_hci::event::Event;
+use bt_hci::ControllerToHostPacket;
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::with_timeout;
+use embassy_time::Duration;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::error;
+use crate::fmt;
+
+/// Time between probes
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the controller gets to answer a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Probes unanswered in a row before the controller counts as lost
+pub const PROBE_MISSES: u32 = 2;
+
+/// How the controller was lost
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Loss {
+    /// It reported a Hardware Error, with its code
+    HardwareError(u8),
+    /// It stopped answering commands
+    Unresponsive,
+}
+
+/// A controller that can be probed
+pub trait HealthController: Controller + ControllerCmdSync<ReadLocalVersionInformation> {}
+
+impl<C: Controller + ControllerCmdSync<ReadLocalVersionInformation>> HealthController for C {}
+
+static LOST: Signal<CriticalSectionRawMutex, Loss> = Signal::new();
+
+pub struct Guard<C> {
+    controller: C,
+}
+
+impl<C> Guard<C> {
+    pub fn new(controller: C) -> Self {
+        // a loss of the last controller mustn't end this one
+        LOST.reset();
+        Self { controller }
+    }
+}
+
+impl<C: Controller> embedded_io::ErrorType for Guard<C> {
+    type Error = C::Error;
+}
+
+impl<C: Controller> Controller for Guard<C> {
+    async fn write_acl_data(&self, packet: &data::AclPacket<'_>) -> Result<(), Self::Error> {
+        self.controller.write_acl_data(packet).await
+    }
+
+    async fn write_sync_data(&self, packet: &data::SyncPacket<'_>) -> Result<(), Self::Error> {
+        self.controller.write_sync_data(packet).await
+    }
+
+    async fn write_iso_data(&self, packet: &data::IsoPacket<'_>) -> Result<(), Self::Error> {
+        self.controller.write_iso_data(packet).await
+    }
+
+    async fn read<'a>(&self, buf: &'a mut [u8]) -> Result<ControllerToHostPacket<'a>, Self::Error> {
+        let packet = self.controller.read(buf).await?;
+        if let ControllerToHostPacket::Event(Event::HardwareError(e)) = &packet {
+            LOST.signal(Loss::HardwareError(e.hardware_code));
+        }
+        Ok(packet)
+    }
+}
+
+impl<C: ControllerCmdSync<Q>, Q: cmd::SyncCmd + ?Sized> ControllerCmdSync<Q> for Guard<C> {
+    async fn exec(&self, cmd: &Q) -> Result<Q::Return, cmd::Error<Self::Error>> {
+        ControllerCmdSync::<Q>::exec(&self.controller, cmd).await
+    }
+}
+
+impl<C: ControllerCmdAsync<Q>, Q: cmd::AsyncCmd + ?Sized> ControllerCmdAsync<Q> for Guard<C> {
+    async fn exec(&self, cmd: &Q) -> Result<(), cmd::Error<Self::Error>> {
+        ControllerCmdAsync::<Q>::exec(&self.controller, cmd).await
+    }
+}
+
+/// Wait for the controller behind `stack` to be lost
+pub async fn watch<C: HealthController>(stack: Stack<'_, C>) -> Loss {
+    let probe = async {
+        let mut misses = 0;
+        loop {
+            Timer::after(PROBE_INTERVAL).await;
+            match with_timeout(
+                PROBE_TIMEOUT,
+                stack.command(ReadLocalVersionInformation::new()),
+            )
+            .await
+            {
+                Ok(Ok(_)) => misses = 0,
+                Ok(Err(e)) => {
+                    error!("[hci] probe failed: {:?}", fmt::Dbg(&e));
+                    misses += 1;
+                }
+                Err(_) => {
+                    error!("[hci] probe timed out");
+                    misses += 1;
+                }
+            }
+            if misses >= PROBE_MISSES {
+                return Loss::Unresponsive;
+            }
+        }
+    };
+
+    match select(LOST.wait(), probe).await {
+        Either::First(loss) | Either::Second(loss) => {
+            error!("[hci] controller lost: {:?}", loss);
+            loss
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! HC-SR04 ultrasonic range finder
+//!
+//! A PIO state machine pulses the trigger pin and times the echo pulse,
+//! which is as long as the sound took there and back, to the microsecond
+//! however busy the executor is. The echo pin is 5V and needs a divider in
+//! front of the GPIO.
+//!
+//! Each measurement starts with the timeout pushed to the state machine.
+//! The program counts down in `x` from it, one count every two PIO cycles
+//! with the clock divided so a count is 1µs, first waiting for the echo to
+//! rise and then again while it's high. Running out of counts either way
+//! leaves all ones in `x`, which is pushed as no echo.
+
+use embassy_rp::pio;
+use embassy_rp::pio::Common;
+use embassy_rp::pio::Instance;
+use embassy_rp::pio::StateMachine;
+use embassy_time::Timer;
+use fixed::traits::ToFixed;
+use fixed_macro::types::U56F8;
+
+/// Longest echo waited for, the sensor gives up at about 38ms without a
+/// target
+const TIMEOUT_US: u32 = 30_000;
+
+/// Value pushed without an echo
+const NO_ECHO: u32 = u32::MAX;
+
+/// Rest between measurements so the last one's echoes die down
+const SETTLE_MS: u64 = 60;
+
+/// Speed of sound in µm/µs, in air at 20°C
+const SOUND_UM_PER_US: u32 = 343;
+
+pub struct Hcsr04<'d, PIO: Instance, const SM: usize> {
+    sm: StateMachine<'d, PIO, SM>,
+}
+
+impl<'d, PIO: Instance, const SM: usize> Hcsr04<'d, PIO, SM> {
+    pub fn new(
+        common: &mut Common<'d, PIO>,
+        mut sm: StateMachine<'d, PIO, SM>,
+        trigger: pio::Pin<'d, PIO>,
+        echo: pio::Pin<'d, PIO>,
+    ) -> Self {
+        let prg = pio_proc::pio_asm!(
+            ".wrap_target",
+            "pull block",
+            "mov x, osr",
+            "set pins, 1 [20]", // 10µs trigger pulse
+            "set pins, 0",
+            "rise:",
+            "jmp pin high",
+            "jmp x-- rise", // 2 cycles per count waiting
+            "jmp done",
+            "high:",
+            "mov x, osr",
+            "count:",
+            "jmp pin still_high",
+            "jmp done",
+            "still_high:",
+            "jmp x-- count", // 2 cycles per count while high
+            "done:",
+            "mov isr, x",
+            "push",
+            ".wrap"
+        );
+
+        let program = common.load_program(&prg.program);
+
+        let mut cfg = pio::Config::default();
+        cfg.use_program(&program, &[]);
+        cfg.set_set_pins(&[&trigger]);
+        cfg.set_jmp_pin(&echo);
+        // two cycles per count, a count per microsecond
+        cfg.clock_divider = (U56F8!(125_000_000) / 2_000_000).to_fixed();
+
+        sm.set_pin_dirs(pio::Direction::Out, &[&trigger]);
+        sm.set_pin_dirs(pio::Direction::In, &[&echo]);
+        sm.set_config(&cfg);
+        sm.set_enable(true);
+
+        Self { sm }
+    }
+
+    /// Length of the echo in µs, `None` if none came back
+    pub async fn echo(&mut self) -> Option<u32> {
+        self.sm.tx().wait_push(TIMEOUT_US).await;
+        let left = self.sm.rx().wait_pull().await;
+        Timer::after_millis(SETTLE_MS).await;
+        (left != NO_ECHO).then(|| TIMEOUT_US - left)
+    }
+
+    /// Range to the target in mm, `None` without an echo
+    pub async fn range(&mut self) -> Option<u32> {
+        // there and back
+        self.echo().await.map(|us| us * SOUND_UM_PER_US / 2000)
+    }
+}
@@ -0,0 +1,145 @@
+//! HID keyboard
+//!
+//! With [`crate::params::Services::hid`] set, the GATT server has a HID
+//! service with one keyboard input report: modifier bits, a reserved byte
+//! and up to six key usages from the HID keyboard page. Application code
+//! [`send`]s reports, which are notified to every host subscribed to them.
+//! A key stays down until a report without it is sent, [`tap`] sends both.
+//!
+//! Many hosts only take HID devices over an encrypted link, which the BLE
+//! host we're on (trouble 0.1) can't set up: it has no security manager.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use trouble_host::prelude::*;
+
+use crate::gattcheck;
+use crate::modules;
+use crate::writes;
+
+/// Size of an input report
+pub const REPORT_SIZE: usize = 8;
+
+pub const REPORT_ID: u8 = 1;
+
+/// Report Reference descriptor: the report id, and 1 for an input report
+pub const REPORT_REFERENCE: [u8; 2] = [REPORT_ID, 0x01];
+
+/// HID Information: HID 1.11, no country, normally connectable
+pub const INFORMATION: [u8; 4] = [0x11, 0x01, 0x00, 0x02];
+
+#[rustfmt::skip]
+pub const REPORT_MAP: &[u8] = &[
+    0x05, 0x01, // usage page (generic desktop)
+    0x09, 0x06, // usage (keyboard)
+    0xa1, 0x01, // collection (application)
+    0x85, REPORT_ID, // report id
+    0x05, 0x07, // usage page (keyboard)
+    0x19, 0xe0, // usage minimum (left control)
+    0x29, 0xe7, // usage maximum (right gui)
+    0x15, 0x00, // logical minimum (0)
+    0x25, 0x01, // logical maximum (1)
+    0x75, 0x01, // report size (1)
+    0x95, 0x08, // report count (8), the modifiers
+    0x81, 0x02, // input (data, variable, absolute)
+    0x75, 0x08, // report size (8)
+    0x95, 0x01, // report count (1), reserved
+    0x81, 0x03, // input (constant)
+    0x95, 0x06, // report count (6), the keys
+    0x25, 0x65, // logical maximum (101)
+    0x19, 0x00, // usage minimum (0)
+    0x29, 0x65, // usage maximum (101)
+    0x81, 0x00, // input (data, array)
+    0xc0,       // end collection
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Report {
+    /// Left control, shift, alt and GUI in the low bits, the right ones
+    /// above
+    pub modifiers: u8,
+    /// Usages of the keys down, zero for none
+    pub keys: [u8; 6],
+}
+
+impl Report {
+    pub const RELEASED: Self = Self {
+        modifiers: 0,
+        keys: [0; 6],
+    };
+
+    /// Just `key` down, with `modifiers`
+    pub const fn key(modifiers: u8, key: u8) -> Self {
+        Self {
+            modifiers,
+            keys: [key, 0, 0, 0, 0, 0],
+        }
+    }
+
+    pub fn encode(&self) -> [u8; REPORT_SIZE] {
+        let mut out = [0; REPORT_SIZE];
+        out[0] = self.modifiers;
+        out[2..].copy_from_slice(&self.keys);
+        out
+    }
+}
+
+/// The last report sent, what a read gets
+static LAST: Mutex<CriticalSectionRawMutex, Cell<[u8; REPORT_SIZE]>> =
+    Mutex::new(Cell::new([0; REPORT_SIZE]));
+
+/// Queue `report` to be notified, after the ones waiting
+pub fn send(report: Report) {
+    let value = report.encode();
+    LAST.lock(|last| last.set(value));
+    modules::notify_event(REPORT_UUID, &value);
+}
+
+/// Press and release `key`
+pub fn tap(modifiers: u8, key: u8) {
+    send(Report::key(modifiers, key));
+    send(Report::RELEASED);
+}
+
+const SERVICE_UUID: Uuid = Uuid::Uuid16(0x1812u16.to_le_bytes());
+const INFORMATION_UUID: Uuid = Uuid::Uuid16(0x2a4au16.to_le_bytes());
+const REPORT_MAP_UUID: Uuid = Uuid::Uuid16(0x2a4bu16.to_le_bytes());
+const CONTROL_POINT_UUID: Uuid = Uuid::Uuid16(0x2a4cu16.to_le_bytes());
+const REPORT_UUID: Uuid = Uuid::Uuid16(0x2a4du16.to_le_bytes());
+const REPORT_REFERENCE_UUID: Uuid = Uuid::Uuid16(0x2908u16.to_le_bytes());
+const _: () = gattcheck::assert_fit(&[REPORT_SIZE], writes::READ_MAX);
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    svc.add_static(INFORMATION_UUID, &INFORMATION);
+    svc.add_static(REPORT_MAP_UUID, REPORT_MAP);
+    // suspend and exit suspend, nothing to do for either
+    svc.add(
+        CONTROL_POINT_UUID,
+        &[CharacteristicProp::WriteWithoutResponse],
+        1,
+        writes::Spec::new().with_write(|_, _| Ok(())),
+    );
+    svc.add_described(
+        REPORT_UUID,
+        &[CharacteristicProp::Read, CharacteristicProp::Notify],
+        REPORT_SIZE,
+        writes::Spec::new()
+            .with_read(|| writes::Value::new(&LAST.lock(|last| last.get())))
+            .as_event(),
+        (REPORT_REFERENCE_UUID, &REPORT_REFERENCE),
+    );
+}
+
+crate::register_module!(
+    MODULE,
+    "hid",
+    |_| {},
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |services| services.hid,
+        build: service,
+    },
+);
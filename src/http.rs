@@ -0,0 +1,408 @@
+//! HTTP control API
+//!
+//! Mirrors the lighting controls from [`crate::controls`], the same ones the
+//! GATT service exposes:
+//!
+//! - `GET /state` returns the current value of every control as a JSON
+//!   object of hex strings
+//! - `POST /controls/<name>` with a hex body sets a control, e.g.
+//!   `curl -d ff8000 http://<device>/controls/base_color`
+//! - `GET /ws` upgrades to a WebSocket streaming changes, see [`crate::ws`]
+//! - `GET /snapshot` returns the device state as CBOR, and `POST /snapshot`
+//!   with such a dump restores it, see [`crate::snapshot`]. Tests set up
+//!   their preconditions with it, e.g.
+//!   `curl --data-binary @dump.cbor http://<device>/snapshot`
+//!
+//! Any other `GET` is served from the [`crate::assets`] files, `/` being
+//! `index.html`. Uploading a single-page dashboard that talks to the API
+//! above makes the device controllable from a browser with nothing to
+//! install.
+//!
+//! Only the lighting controls are exposed. The relays, GPIO, PWM outputs
+//! and alarm thresholds stay BLE-only: a write to them is checked against
+//! the peer's role (see [`crate::roles`]), the admin lock and maintenance
+//! mode, and goes on the audit trail, and an HTTP request comes with
+//! nothing to check. They go through [`crate::controls`] too once the API
+//! has a way to tell who's asking.
+
+use core::fmt::Write as _;
+
+use embassy_futures::join::join_array;
+use embassy_net::driver::Driver;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::Sender;
+use embassy_time::Duration;
+
+use crate::assets;
+use crate::controls;
+use crate::error;
+use crate::info;
+use crate::lighting::Message;
+use crate::snapshot;
+use crate::ws;
+
+const PORT: u16 = 80;
+
+/// Largest request we accept, headers included, room for a snapshot
+const REQUEST_MAX: usize = 256 + snapshot::SIZE_MAX;
+
+/// Largest response body we produce, a snapshot
+const BODY_MAX: usize = snapshot::SIZE_MAX;
+
+/// Number of connections served at once, so a WebSocket client doesn't
+/// lock everyone else out
+const WORKERS: usize = 2;
+
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    headers: &'a str,
+    pub body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    /// Parse a complete request, `None` if more data is needed or it's malformed
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        let header_end = buf.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let head = core::str::from_utf8(&buf[..header_end]).ok()?;
+
+        let (request_line, headers) = head.split_once("\r\n").unwrap_or((head, ""));
+        let mut request_line = request_line.split(' ');
+        let method = request_line.next()?;
+        let path = request_line.next()?;
+
+        let mut request = Self {
+            method,
+            path,
+            headers,
+            body: &[],
+        };
+
+        let content_length = request
+            .header("content-length")
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(0);
+        let body_start = header_end + 4;
+        request.body = buf.get(body_start..body_start + content_length)?;
+
+        Some(request)
+    }
+
+    /// Value of header `name`, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+}
+
+/// Fixed size text buffer
+pub struct Body {
+    buf: [u8; BODY_MAX],
+    len: usize,
+}
+
+impl Body {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; BODY_MAX],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Write for Body {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let dst = self
+            .buf
+            .get_mut(self.len..self.len + s.len())
+            .ok_or(core::fmt::Error)?;
+        dst.copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: Body,
+    /// Sent instead of `body`, straight from flash
+    pub file: Option<&'static [u8]>,
+}
+
+impl Response {
+    pub fn empty(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: Body::new(),
+            file: None,
+        }
+    }
+
+    fn content(&self) -> &[u8] {
+        self.file.unwrap_or(self.body.as_bytes())
+    }
+}
+
+pub async fn serve<D: Driver, M: RawMutex, const N: usize>(
+    stack: &Stack<D>,
+    sender: Sender<'_, M, Message, N>,
+) -> ! {
+    info!("[http] listening on port {}", PORT);
+    join_array([(); WORKERS].map(|_| worker(stack, &sender))).await;
+    unreachable!()
+}
+
+async fn worker<D: Driver, M: RawMutex, const N: usize>(
+    stack: &Stack<D>,
+    sender: &Sender<'_, M, Message, N>,
+) -> ! {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        if let Err(e) = socket.accept(PORT).await {
+            error!("[http] accept error: {:?}", e);
+            continue;
+        }
+
+        let mut buf = [0; REQUEST_MAX];
+        let response = match read_request(&mut socket, &mut buf).await {
+            Some(request) if request.path == "/ws" => match request.header("sec-websocket-key") {
+                Some(key) => {
+                    ws::stream(&mut socket, key).await;
+                    socket.close();
+                    let _ = socket.flush().await;
+                    continue;
+                }
+                None => Response::empty("400 Bad Request"),
+            },
+            Some(request) => handle(&request, sender).await,
+            None => Response::empty("400 Bad Request"),
+        };
+
+        if let Err(e) = write_response(&mut socket, &response).await {
+            error!("[http] write error: {:?}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+async fn read_request<'a>(socket: &mut TcpSocket<'_>, buf: &'a mut [u8]) -> Option<Request<'a>> {
+    let mut len = 0;
+    loop {
+        if Request::parse(&buf[..len]).is_some() {
+            break;
+        }
+        if len == buf.len() {
+            return None;
+        }
+
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => len += n,
+        }
+    }
+
+    Request::parse(&buf[..len])
+}
+
+async fn write_response(
+    socket: &mut TcpSocket<'_>,
+    response: &Response,
+) -> Result<(), embassy_net::tcp::Error> {
+    let mut head = Body::new();
+    let _ = write!(
+        head,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.content().len()
+    );
+
+    write_all(socket, head.as_bytes()).await?;
+    write_all(socket, response.content()).await
+}
+
+pub async fn write_all(
+    socket: &mut TcpSocket<'_>,
+    mut data: &[u8],
+) -> Result<(), embassy_net::tcp::Error> {
+    while !data.is_empty() {
+        let n = socket.write(data).await?;
+        data = &data[n..];
+    }
+    Ok(())
+}
+
+async fn handle<M: RawMutex, const N: usize>(
+    request: &Request<'_>,
+    sender: &Sender<'_, M, Message, N>,
+) -> Response {
+    info!("[http] {} {}", request.method, request.path);
+
+    match (request.method, request.path) {
+        ("GET", "/state") => state(),
+        ("GET", "/snapshot") => capture(),
+        ("POST", "/snapshot") => restore(request.body, sender).await,
+        ("POST", path) => {
+            let Some(idx) = path.strip_prefix("/controls/").and_then(controls::find) else {
+                return Response::empty("404 Not Found");
+            };
+
+            let mut value = [0; controls::MAX_LEN];
+            let Some(len) = decode_hex(request.body, &mut value) else {
+                return Response::empty("400 Bad Request");
+            };
+            match controls::apply(idx, &value[..len]) {
+                Some(message) => {
+                    sender.send(message).await;
+                    Response::empty("204 No Content")
+                }
+                None => Response::empty("400 Bad Request"),
+            }
+        }
+        (_, "/state" | "/snapshot") => Response::empty("405 Method Not Allowed"),
+        ("GET", path) => file(path),
+        _ => Response::empty("404 Not Found"),
+    }
+}
+
+/// The asset file at `path`
+fn file(path: &str) -> Response {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let name = match path.trim_start_matches('/') {
+        "" => "index.html",
+        name => name,
+    };
+    let Some(data) = assets::open(name) else {
+        return Response::empty("404 Not Found");
+    };
+    let mut response = Response::empty("200 OK");
+    response.content_type = content_type(name);
+    response.file = Some(data);
+    response
+}
+
+/// Content type for a file name, by its extension
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map_or("", |(_, extension)| extension) {
+        "html" | "htm" => "text/html",
+        "js" => "text/javascript",
+        "css" => "text/css",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "ico" => "image/x-icon",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Snapshot of every control as JSON
+fn state() -> Response {
+    let mut response = Response::empty("200 OK");
+    response.content_type = "application/json";
+    write_controls(&mut response.body, 0..controls::CONTROLS.len());
+
+    response
+}
+
+/// The device state as CBOR
+fn capture() -> Response {
+    let mut response = Response::empty("200 OK");
+    response.content_type = "application/cbor";
+    match snapshot::capture(&mut response.body.buf) {
+        Ok(len) => response.body.len = len,
+        Err(e) => {
+            error!("[http] snapshot failed: {:?}", e);
+            return Response::empty("500 Internal Server Error");
+        }
+    }
+    response
+}
+
+/// Restore a dump made by [`capture`], passing its control values on to the
+/// lighting task
+async fn restore<M: RawMutex, const N: usize>(
+    dump: &[u8],
+    sender: &Sender<'_, M, Message, N>,
+) -> Response {
+    let mut messages: [Option<Message>; controls::CONTROLS.len()] = core::array::from_fn(|_| None);
+    let mut count = 0;
+    let restored = snapshot::restore(dump, |message| {
+        if let Some(slot) = messages.get_mut(count) {
+            *slot = Some(message);
+            count += 1;
+        }
+    });
+    // what was restored before an error stays, so the lights follow it
+    for message in messages.into_iter().flatten() {
+        sender.send(message).await;
+    }
+    match restored {
+        Ok(()) => {
+            info!("[http] snapshot restored");
+            Response::empty("204 No Content")
+        }
+        Err(e) => {
+            error!("[http] restoring the snapshot failed: {:?}", e);
+            Response::empty("400 Bad Request")
+        }
+    }
+}
+
+/// Write the given controls as a JSON object of hex strings
+pub fn write_controls(body: &mut Body, idxs: impl Iterator<Item = usize>) {
+    let _ = body.write_char('{');
+    for (n, idx) in idxs.enumerate() {
+        let control = &controls::CONTROLS[idx];
+        if n != 0 {
+            let _ = body.write_char(',');
+        }
+        let _ = body.write_char('"');
+        for c in control.name.chars() {
+            let _ = body.write_char(if c == ' ' { '_' } else { c });
+        }
+        let _ = body.write_str("\":\"");
+        for byte in &controls::value(idx)[..control.len] {
+            let _ = write!(body, "{:02x}", byte);
+        }
+        let _ = body.write_char('"');
+    }
+    let _ = body.write_char('}');
+}
+
+/// Decode hex digits (surrounding whitespace allowed) into `out`
+fn decode_hex(text: &[u8], out: &mut [u8]) -> Option<usize> {
+    let text = text.trim_ascii();
+    if text.len() % 2 != 0 || text.len() / 2 > out.len() {
+        return None;
+    }
+
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    for (byte, pair) in out.iter_mut().zip(text.chunks_exact(2)) {
+        *byte = (digit(pair[0])? << 4) | digit(pair[1])?;
+    }
+    Some(text.len() / 2)
+}
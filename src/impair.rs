@@ -0,0 +1,82 @@
+//! Injected notification loss and delay
+//!
+//! Debug builds only. Every notification goes through [`admit`], which
+//! can be told to drop a fraction of them and hold back another fraction
+//! for a while, like a poor link would. Lets the companion app's retry
+//! and catch-up handling be exercised on the bench.
+//!
+//! Writes to the impairment characteristic are `[drop: u16, delay: u16,
+//! delay ms: u16]`, little endian, with the fractions in parts per
+//! thousand. All zero turns it off, which is where it starts.
+
+use core::cell::Cell;
+
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Timer;
+use rand_core::RngCore;
+
+use crate::info;
+
+/// Size of the impairment characteristic
+pub const CHARACTERISTIC_SIZE: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Impairment {
+    /// Notifications dropped, per thousand
+    pub drop: u16,
+    /// Notifications delayed, per thousand
+    pub delay: u16,
+    /// How long delayed notifications are held
+    pub delay_for: Duration,
+}
+
+impl Impairment {
+    pub const NONE: Self = Self {
+        drop: 0,
+        delay: 0,
+        delay_for: Duration::from_ticks(0),
+    };
+
+    /// `None` if the fractions add up to more than all notifications
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let [d0, d1, l0, l1, m0, m1] = *value else {
+            return None;
+        };
+        let impairment = Self {
+            drop: u16::from_le_bytes([d0, d1]),
+            delay: u16::from_le_bytes([l0, l1]),
+            delay_for: Duration::from_millis(u16::from_le_bytes([m0, m1]) as u64),
+        };
+        (impairment.drop as u32 + impairment.delay as u32 <= 1000).then_some(impairment)
+    }
+}
+
+static CURRENT: Mutex<CriticalSectionRawMutex, Cell<Impairment>> =
+    Mutex::new(Cell::new(Impairment::NONE));
+
+pub fn set(impairment: Impairment) {
+    info!("[impair] {:?}", impairment);
+    CURRENT.lock(|current| current.set(impairment));
+}
+
+/// Decide a notification's fate, waiting out its delay if it gets one.
+/// Returns false if it should be dropped.
+pub async fn admit() -> bool {
+    let impairment = CURRENT.lock(|current| current.get());
+    if impairment == Impairment::NONE {
+        return true;
+    }
+
+    let roll = (RoscRng.next_u32() % 1000) as u16;
+    if roll < impairment.drop {
+        return false;
+    }
+    if roll < impairment.drop + impairment.delay {
+        Timer::after(impairment.delay_for).await;
+    }
+    true
+}
@@ -0,0 +1,202 @@
+//! Adaptive connection latency
+//!
+//! Watches the event bus while a connection is up. After a while without
+//! characteristic activity we ask the central for a slow, high-latency
+//! connection to save power, and go back to a fast one as soon as a write
+//! comes in. Application code can [`pin`] a connection to a [`Profile`]
+//! instead, fast while it streams data for instance, and hand it back to
+//! the policy when it's done.
+//!
+//! The central doesn't have to take what we ask for. If it refuses, we
+//! keep the parameters we had and ask again after [`RETRY_AFTER`], for as
+//! long as we still want the change. Accepted parameters are kept for
+//! [`conninfo::params`] and published as [`Event::ParamsUpdated`].
+
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3;
+use embassy_futures::yield_now;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::conninfo;
+use crate::error;
+use crate::events;
+use crate::events::Event;
+use crate::info;
+use crate::session;
+
+/// How long to wait before asking again for parameters the central refused
+pub const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// The sets of parameters in a [`Policy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Profile {
+    /// Fast, low-latency connection
+    Active,
+    /// Slow, high-latency connection
+    Idle,
+    /// The shortest connection interval there is, only ever pinned, see
+    /// [`crate::boost`]
+    Boost,
+}
+
+pub struct Policy {
+    /// How long a connection has to be quiet before it's considered idle
+    pub idle_after: Duration,
+    /// Parameters requested while there's activity
+    pub active: ConnectParams,
+    /// Parameters requested once the connection goes idle
+    pub idle: ConnectParams,
+    /// Parameters requested for a connection pinned to [`Profile::Boost`]
+    pub boost: ConnectParams,
+}
+
+impl Policy {
+    pub const DEFAULT: Self = Self {
+        idle_after: Duration::from_secs(30),
+        active: ConnectParams {
+            min_connection_interval: Duration::from_millis(15),
+            max_connection_interval: Duration::from_millis(30),
+            max_latency: 0,
+            event_length: Duration::from_ticks(0),
+            supervision_timeout: Duration::from_secs(4),
+        },
+        idle: ConnectParams {
+            min_connection_interval: Duration::from_millis(100),
+            max_connection_interval: Duration::from_millis(125),
+            max_latency: 10,
+            event_length: Duration::from_ticks(0),
+            supervision_timeout: Duration::from_secs(6),
+        },
+        boost: ConnectParams {
+            min_connection_interval: Duration::from_micros(7500),
+            max_connection_interval: Duration::from_micros(7500),
+            max_latency: 0,
+            event_length: Duration::from_ticks(0),
+            supervision_timeout: Duration::from_secs(4),
+        },
+    };
+
+    pub fn params(&self, profile: Profile) -> &ConnectParams {
+        match profile {
+            Profile::Active => &self.active,
+            Profile::Idle => &self.idle,
+            Profile::Boost => &self.boost,
+        }
+    }
+}
+
+/// Profiles application code pinned connections to
+static PINNED: session::PerConnection<Option<Profile>> = session::PerConnection::new(None);
+
+/// The profile each connection has. A connection starts out with whatever
+/// the central chose, which counts as active.
+static CURRENT: session::PerConnection<Profile> = session::PerConnection::new(Profile::Active);
+
+/// Keep `conn` on `profile` whatever its activity, or with `None` leave it
+/// to the policy again
+pub fn pin(conn: ConnHandle, profile: Option<Profile>) {
+    PINNED.set(conn, profile);
+    events::publish(Event::ProfileRequested(conn));
+}
+
+/// The profile `conn` is pinned to, if any
+pub fn pinned(conn: ConnHandle) -> Option<Profile> {
+    PINNED.get(conn)
+}
+
+/// The profile the central last accepted for `conn`
+pub fn profile(conn: ConnHandle) -> Profile {
+    CURRENT.get(conn)
+}
+
+/// Apply `policy` to `conn` until it disconnects
+pub async fn run<C: Controller>(stack: Stack<'_, C>, conn: &Connection<'_>, policy: &Policy) {
+    let Some(mut events) = events::subscribe() else {
+        error!("[latency] no event subscriber available, policy disabled");
+        wait_disconnected(conn).await;
+        return;
+    };
+
+    let handle = conn.handle();
+    let mut idle = false;
+    let mut deadline = Instant::now() + policy.idle_after;
+    let mut retry_at = Instant::MIN;
+    loop {
+        let activity = if idle { Profile::Idle } else { Profile::Active };
+        let wanted = PINNED.get(handle).unwrap_or(activity);
+        let pending = wanted != CURRENT.get(handle);
+        if pending && Instant::now() >= retry_at {
+            info!("[latency] requesting {:?}", wanted);
+            if request(stack, conn, wanted, policy.params(wanted)).await {
+                continue;
+            }
+            retry_at = Instant::now() + RETRY_AFTER;
+        }
+
+        let wake = if pending {
+            deadline.min(retry_at)
+        } else {
+            deadline
+        };
+        match select3(
+            events.next_message_pure(),
+            Timer::at(wake),
+            wait_disconnected(conn),
+        )
+        .await
+        {
+            Either3::First(Event::Write(_)) => {
+                deadline = Instant::now() + policy.idle_after;
+                idle = false;
+            }
+            Either3::First(Event::Read(_)) => {
+                deadline = Instant::now() + policy.idle_after;
+            }
+            Either3::First(Event::ProfileRequested(h)) if h == handle => {
+                // the application changed its mind, don't hold it back
+                retry_at = Instant::MIN;
+            }
+            Either3::First(_) => {}
+            Either3::Second(()) => {
+                if Instant::now() >= deadline {
+                    deadline = Instant::MAX;
+                    idle = true;
+                }
+            }
+            Either3::Third(()) => return,
+        }
+    }
+}
+
+/// Ask the central for `params`, `false` if it refused them or the request
+/// didn't go out
+async fn request<C: Controller>(
+    stack: Stack<'_, C>,
+    conn: &Connection<'_>,
+    profile: Profile,
+    params: &ConnectParams,
+) -> bool {
+    match conn.update_connection_params(&stack, params.clone()).await {
+        Ok(()) => {
+            CURRENT.set(conn.handle(), profile);
+            let params = conninfo::record_params(conn.handle(), params);
+            events::publish(Event::ParamsUpdated(conn.handle(), params));
+            true
+        }
+        Err(e) => {
+            error!("[latency] connection parameter update failed: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Wait for `conn` to go away
+pub async fn wait_disconnected(conn: &Connection<'_>) {
+    while conn.is_connected() {
+        yield_now().await;
+    }
+}
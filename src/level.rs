@@ -0,0 +1,329 @@
+//! Tank level
+//!
+//! An HC-SR04 ([`crate::hcsr04`]) on two spare pins, `level.trigger` and
+//! `level.echo`, looks down at the surface of a tank. Every
+//! `level.interval` milliseconds it takes a burst of [`BURST`] ranges and
+//! keeps their median, which drops the odd echo off the tank wall or a
+//! ripple. A burst with fewer than [`VALID_MIN`] echoes in the sensor's
+//! range counts as a failed reading.
+//!
+//! The range turns into a level with the tank's geometry: `level.empty` is
+//! the range to the bottom, `level.full` to the surface of a full tank (at
+//! least the sensor's blind spot). Between them the level is linear for an
+//! upright tank, and follows the circle for a `horizontal` cylinder (see
+//! `level.shape`). With `level.capacity` set the volume is worked out too.
+//!
+//! The level is an alarm signal ([`alarm::LEVEL`]), so a low limit on it is
+//! a low level alarm. The level characteristic is notified as it changes:
+//!
+//! | bytes | content                                              |
+//! |-------|------------------------------------------------------|
+//! | 0..2  | level in 0.01%, [`LEVEL_UNKNOWN`] without a good one |
+//! | 2..6  | volume in litres, zero without a capacity            |
+//! | 6..8  | range in mm                                          |
+//!
+//! After a failed reading the last one is kept as a faulty
+//! [`Measurement`] and the level is unknown, as it is once the reading has
+//! gone stale.
+
+use core::cell::Cell;
+
+use embassy_rp::pio::Instance;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::alarm;
+use crate::blue::gen_uuid;
+use crate::config;
+use crate::config::Text;
+use crate::error;
+use crate::fault;
+use crate::gattcheck;
+use crate::gpio;
+use crate::hcsr04::Hcsr04;
+use crate::info;
+use crate::measurement;
+use crate::measurement::Measurement;
+use crate::measurement::Unit;
+use crate::modules;
+use crate::monitor;
+use crate::pinmap;
+use crate::pinmap::PinMap;
+use crate::telemetry;
+use crate::writes;
+
+crate::config_key!(
+    /// GPIO with the sensor's trigger input, unused if `0xff`
+    pub TRIGGER: u32 = "level.trigger",
+    pinmap::UNUSED as u32,
+);
+
+crate::config_key!(
+    /// GPIO with the sensor's echo output, through a divider, unused if
+    /// `0xff`
+    pub ECHO: u32 = "level.echo",
+    pinmap::UNUSED as u32,
+);
+
+crate::config_key!(
+    /// Time between readings in ms
+    pub INTERVAL: u32 = "level.interval",
+    5_000,
+);
+
+crate::config_key!(
+    /// Range to the bottom of the tank in mm
+    pub EMPTY: u32 = "level.empty",
+    1_000,
+);
+
+crate::config_key!(
+    /// Range to the surface of a full tank in mm
+    pub FULL: u32 = "level.full",
+    250,
+);
+
+crate::config_key!(
+    /// "upright", or "horizontal" for a cylinder on its side
+    pub SHAPE: Text = "level.shape",
+    Text::new("upright"),
+);
+
+crate::config_key!(
+    /// What the tank holds when full in litres, 0 if unknown
+    pub CAPACITY: u32 = "level.capacity",
+    0,
+);
+
+/// Ranges taken per reading
+pub const BURST: usize = 5;
+
+/// Echoes a reading needs, a majority of the burst so the median is one of
+/// the good ones
+pub const VALID_MIN: usize = 3;
+
+/// Ranges the sensor gives reliably
+const RANGE_MIN_MM: u32 = 20;
+const RANGE_MAX_MM: u32 = 4_000;
+
+/// Shortest interval we read at, about what a burst takes
+const MIN_INTERVAL_MS: u32 = 500;
+
+/// What the level reads without a reading
+pub const LEVEL_UNKNOWN: u16 = u16::MAX;
+
+/// Size of the level characteristic
+pub const CHARACTERISTIC_SIZE: usize = 8;
+
+/// Fraction of a horizontal cylinder's volume below each tenth of its
+/// height, in ‰
+const CYLINDER: [u32; 11] = [0, 52, 142, 252, 374, 500, 626, 748, 858, 948, 1000];
+
+/// The pins the sensor is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pins {
+    pub trigger: u8,
+    pub echo: u8,
+}
+
+/// Pick the pins from the configuration, `None` unless both are set to
+/// pins neither `pins` nor the GPIO outputs have. Call once at boot, after
+/// [`gpio::assign`].
+pub fn assign(pins: &PinMap, outputs: &gpio::Assignment) -> Option<Pins> {
+    let taken = |pin: u8| outputs.outputs.contains(&Some(pin)) || outputs.pwm == Some(pin);
+    let pin = |key: &config::Key<u32>| {
+        let pin = key.get();
+        if pin == pinmap::UNUSED as u32 {
+            return None;
+        }
+        let pin = u8::try_from(pin)
+            .ok()
+            .filter(|&pin| pins.is_free(pin) && !taken(pin));
+        if pin.is_none() {
+            error!("[level] {} isn't a free pin, ignored", key.name);
+        }
+        pin
+    };
+
+    let (trigger, echo) = (pin(&TRIGGER)?, pin(&ECHO)?);
+    if trigger == echo {
+        error!("[level] trigger and echo are the same pin");
+        return None;
+    }
+    let pins = Pins { trigger, echo };
+    info!("[level] {:?}", pins);
+    Some(pins)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Reading {
+    pub range_mm: u32,
+    /// In 0.01%
+    pub level: u16,
+    /// In litres, `None` without a capacity
+    pub volume: Option<u32>,
+}
+
+impl Reading {
+    fn from_range(range_mm: u32) -> Self {
+        let (empty, full) = (EMPTY.get(), FULL.get());
+        let height = empty.saturating_sub(full).max(1);
+        let depth = empty.saturating_sub(range_mm).min(height);
+        let fill = depth * 10_000 / height;
+        let fraction = match SHAPE.get().as_str() {
+            "horizontal" => {
+                let (tenth, within) = ((fill / 1_000) as usize, fill % 1_000);
+                let low = CYLINDER[tenth];
+                let high = CYLINDER[(tenth + 1).min(10)];
+                // ‰ times 10 is 0.01%
+                low * 10 + (high - low) * within / 100
+            }
+            _ => fill,
+        };
+        let capacity = CAPACITY.get();
+        Self {
+            range_mm,
+            level: fraction as u16,
+            volume: (capacity != 0).then(|| (capacity as u64 * fraction as u64 / 10_000) as u32),
+        }
+    }
+}
+
+/// The last reading, measured in the level's unit
+static READING: Mutex<CriticalSectionRawMutex, Cell<Option<Measurement<Reading>>>> =
+    Mutex::new(Cell::new(None));
+
+/// The last reading, flagged stale if the sensor stopped
+pub fn measurement() -> Option<Measurement<Reading>> {
+    READING.lock(|r| r.get()).map(|m| m.aged(interval()))
+}
+
+/// The last reading, if it's good
+pub fn reading() -> Option<Reading> {
+    measurement().and_then(Measurement::good)
+}
+
+/// The level characteristic
+pub fn encode() -> [u8; CHARACTERISTIC_SIZE] {
+    let mut out = [0; CHARACTERISTIC_SIZE];
+    let reading = reading();
+    let level = reading.map_or(LEVEL_UNKNOWN, |r| r.level);
+    out[0..2].copy_from_slice(&level.to_le_bytes());
+    if let Some(reading) = reading {
+        out[2..6].copy_from_slice(&reading.volume.unwrap_or(0).to_le_bytes());
+        out[6..8].copy_from_slice(&(reading.range_mm.min(u16::MAX as u32) as u16).to_le_bytes());
+    }
+    out
+}
+
+/// The median of the ranges in the sensor's range, `None` if too few
+fn median(ranges: &[Option<u32>; BURST]) -> Option<u32> {
+    let mut valid = [0; BURST];
+    let mut count = 0;
+    for range in ranges
+        .iter()
+        .flatten()
+        .filter(|&&mm| (RANGE_MIN_MM..=RANGE_MAX_MM).contains(&mm))
+    {
+        valid[count] = *range;
+        count += 1;
+    }
+    if count < VALID_MIN {
+        return None;
+    }
+    let valid = &mut valid[..count];
+    valid.sort_unstable();
+    Some(valid[count / 2])
+}
+
+fn publish(reading: Reading) {
+    alarm::check(alarm::LEVEL, reading.level as i32, reading.level as i32);
+    publish_measurement(Measurement::new(reading, Unit::Percent));
+}
+
+/// Keep the last reading, flagged as faulty
+fn publish_fault() {
+    if let Some(m) = READING.lock(|r| r.get()) {
+        publish_measurement(m.with(measurement::FAULT));
+    }
+}
+
+fn publish_measurement(m: Measurement<Reading>) {
+    telemetry::record_measurement(telemetry::Signal::Level, m.map(|r| r.level.into()));
+    let last = READING.lock(|r| r.replace(Some(m)));
+    if last.and_then(Measurement::good) != m.good() {
+        modules::notify(LEVEL_UUID, &encode());
+    }
+}
+
+fn interval() -> Duration {
+    Duration::from_millis(INTERVAL.get().max(MIN_INTERVAL_MS) as u64)
+}
+
+pub async fn run<PIO: Instance, const SM: usize>(mut sensor: Hcsr04<'_, PIO, SM>) -> ! {
+    loop {
+        monitor::LEVEL.ping();
+        let at = Instant::now();
+        let mut ranges = [None; BURST];
+        for range in &mut ranges {
+            *range = sensor.range().await;
+        }
+        match median(&ranges) {
+            Some(range_mm) => publish(Reading::from_range(range_mm)),
+            None => {
+                error!("[level] too few echoes: {:?}", ranges);
+                fault::raise(fault::LEVEL_READ);
+                publish_fault();
+            }
+        }
+
+        monitor::LEVEL.pause();
+        Timer::at(at + interval()).await;
+    }
+}
+
+crate::register_command!(LEVEL, "level", "", "show the tank level", |args| {
+    args.end()?;
+    match reading() {
+        Some(reading) => info!(
+            "{}.{:02}% at {} mm, {} l",
+            reading.level / 100,
+            reading.level % 100,
+            reading.range_mm,
+            reading.volume.unwrap_or(0)
+        ),
+        None => info!("no reading"),
+    }
+    Ok(())
+});
+
+const SERVICE_UUID: Uuid = gen_uuid("tank level");
+const LEVEL_UUID: Uuid = gen_uuid("level");
+const _: () = gattcheck::assert_fit(&[CHARACTERISTIC_SIZE], writes::READ_MAX);
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    svc.add(
+        LEVEL_UUID,
+        &[CharacteristicProp::Read, CharacteristicProp::Notify],
+        CHARACTERISTIC_SIZE,
+        writes::Spec::new().with_read(|| writes::Value::new(&encode())),
+    );
+}
+
+// the sensor is driven from main, which has its pins
+crate::register_module!(
+    MODULE,
+    "level",
+    |_| {},
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |services| services.level,
+        build: service,
+    },
+);
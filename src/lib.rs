@@ -3,7 +3,128 @@
 mod color;
 pub use color::Color;
 
+pub mod accept;
+pub mod adcstream;
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod adparse;
+pub mod adv;
+#[cfg(feature = "aes")]
+pub mod aes;
+pub mod aggregate;
+pub mod alarm;
+pub mod anchor;
+pub mod arbiter;
+pub mod assets;
+pub mod audit;
+pub mod battery;
+pub mod beacons;
+pub mod blob;
 pub mod blue;
+pub mod bme280;
+pub mod board;
+pub mod boardrev;
+pub mod boost;
+pub mod bthome;
+pub mod bus;
+pub mod calibration;
+pub mod capture;
+pub mod cbor;
+pub mod central;
+pub mod clock;
+pub mod compress;
+pub mod config;
+pub mod conninfo;
+pub mod console;
+pub mod controls;
+pub mod crash;
+pub mod delta;
+#[cfg(feature = "dfu")]
+pub mod dfu;
+pub mod disconnects;
+pub mod discovery;
+pub mod distance;
+pub mod echo;
+pub mod environment;
+pub mod events;
+pub mod expander;
+pub mod explorer;
+pub mod fault;
+#[cfg(feature = "findnet")]
+pub mod findnet;
+pub mod flash;
+pub mod fmt;
+pub mod fmtbuf;
+pub mod gateway;
+pub mod gattcheck;
+pub mod gatttrace;
+pub mod gpio;
+pub mod handoff;
+pub mod hcilink;
+pub mod hcsr04;
+pub mod hid;
+pub mod http;
+#[cfg(debug_assertions)]
+pub mod impair;
+pub mod integrity;
+pub mod latency;
 pub mod led;
+pub mod level;
 pub mod lighting;
+pub mod links;
+pub mod matter;
+pub mod measurement;
+pub mod meter;
+pub mod mode;
+pub mod modules;
+pub mod monitor;
+pub mod mqtt;
+pub mod net;
+pub mod observer;
+#[cfg(all(feature = "dfu", feature = "tls"))]
+pub mod ota;
+pub mod ots;
+#[cfg(debug_assertions)]
+pub mod overrides;
+pub mod paging;
 pub mod panic;
+pub mod params;
+pub mod partitions;
+#[cfg(feature = "peek")]
+pub mod peek;
+pub mod peers;
+pub mod persist;
+pub mod pinmap;
+pub mod power;
+pub mod preset;
+pub mod provision;
+pub mod proxy;
+pub mod radiofw;
+pub mod relay;
+pub mod resume;
+pub mod roles;
+#[cfg(feature = "rolling")]
+pub mod rolling;
+pub mod rollup;
+pub mod rtc;
+pub mod runtime;
+pub mod session;
+pub mod settings;
+pub mod sht31;
+pub mod snapshot;
+pub mod sntp;
+pub mod stats;
+pub mod store;
+pub mod strings;
+pub mod subscriptions;
+pub mod supervisor;
+pub mod system;
+pub mod telemetry;
+pub mod threshold;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod vl53l0x;
+pub mod watchdog;
+pub mod weather;
+pub mod writes;
+pub mod ws;
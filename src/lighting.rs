@@ -1,18 +1,19 @@
 //! Lighting state and task
 
-use log::info;
-
 use embassy_rp::pio::Instance;
 use embassy_sync::{blocking_mutex::raw::RawMutex, channel::Receiver};
 
+use crate::info;
 use crate::led::LedDriver;
 use crate::led::NUM_LEDS;
 use crate::Color;
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Animation {}
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Message {
     // Set the lights a color
     SetColor(Color),
@@ -22,6 +23,10 @@ pub enum Message {
     SetSkip(u8),
     // Use a color animation
     UseAnimation(Animation),
+    // Light the first LED to show maintenance mode
+    SetIndicator(bool),
+    // Light the last LED red for an unacknowledged alarm
+    SetAlarm(bool),
 }
 
 pub async fn run<M: RawMutex, PIO: Instance, const N: usize, const SM: usize>(
@@ -31,10 +36,12 @@ pub async fn run<M: RawMutex, PIO: Instance, const N: usize, const SM: usize>(
     let mut brightness = 1.0;
     let mut base_color = Color::BLACK;
     let mut skip = 0;
+    let mut indicator = false;
+    let mut alarm = false;
 
     loop {
         let message = recv.receive().await;
-        info!("[lighting] handling message {message:?}");
+        info!("[lighting] handling message {:?}", message);
         match message {
             Message::SetColor(c) => {
                 base_color = c;
@@ -45,12 +52,23 @@ pub async fn run<M: RawMutex, PIO: Instance, const N: usize, const SM: usize>(
             Message::SetSkip(s) => {
                 skip = s;
             }
+            Message::SetIndicator(on) => {
+                indicator = on;
+            }
+            Message::SetAlarm(on) => {
+                alarm = on;
+            }
         }
 
         let color = base_color.dim(brightness);
         let mut n = skip;
-        for _ in 0..NUM_LEDS {
-            if n == 0 {
+        for i in 0..NUM_LEDS {
+            if i == 0 && indicator {
+                led_driver.send_color(Color::BLUE).await;
+                n = skip;
+            } else if i == NUM_LEDS - 1 && alarm {
+                led_driver.send_color(Color::RED).await;
+            } else if n == 0 {
                 led_driver.send_color(color).await;
                 n = skip;
             } else {
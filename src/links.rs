@@ -0,0 +1,116 @@
+//! Connection registry
+//!
+//! Up to [`CONNECTIONS_MAX`] centrals, or the limit given, can be connected
+//! at once. The advertising task adds every connection it accepts and goes
+//! on advertising while there's room. A free serving slot picks the
+//! connection up and runs its per-connection work until it disconnects.
+//! Values that go to every client, like summaries, are notified to each
+//! connection in [`Links::connections`].
+//!
+//! Everything here runs in the task that owns the BLE stack, so there's no
+//! locking.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use trouble_host::prelude::*;
+
+use crate::blue::CONNECTIONS_MAX;
+
+pub struct Links<'d> {
+    /// Most connections accepted, up to [`CONNECTIONS_MAX`]
+    limit: usize,
+    conns: RefCell<[Option<Connection<'d>>; CONNECTIONS_MAX]>,
+    /// Connections waiting for a serving slot
+    accepted: Channel<NoopRawMutex, Connection<'d>, CONNECTIONS_MAX>,
+    /// Signaled when a connection goes away
+    freed: Signal<NoopRawMutex, ()>,
+    /// Signaled when a connection is added
+    joined: Signal<NoopRawMutex, ()>,
+}
+
+impl<'d> Links<'d> {
+    pub fn new() -> Self {
+        Self::with_limit(CONNECTIONS_MAX)
+    }
+
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            limit: limit.min(CONNECTIONS_MAX),
+            conns: RefCell::new([const { None }; CONNECTIONS_MAX]),
+            accepted: Channel::new(),
+            freed: Signal::new(),
+            joined: Signal::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.borrow().iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.limit
+    }
+
+    /// Register an accepted connection for a slot to serve, `false` if
+    /// there's no room for it
+    pub fn add(&self, conn: Connection<'d>) -> bool {
+        let mut conns = self.conns.borrow_mut();
+        let Some(idx) = conns.iter().position(Option::is_none) else {
+            return false;
+        };
+        conns[idx] = Some(conn.clone());
+        // can't be full, there's a slot per registered connection
+        let _ = self.accepted.try_send(conn);
+        self.joined.signal(());
+        true
+    }
+
+    /// Wait for the next connection to serve
+    pub async fn next(&self) -> Connection<'d> {
+        self.accepted.receive().await
+    }
+
+    /// Forget a connection that went away, returning how many are left
+    pub fn remove(&self, conn: &Connection<'d>) -> usize {
+        let mut conns = self.conns.borrow_mut();
+        for slot in conns.iter_mut() {
+            if slot.as_ref().is_some_and(|c| c.handle() == conn.handle()) {
+                *slot = None;
+            }
+        }
+        self.freed.signal(());
+        conns.iter().flatten().count()
+    }
+
+    /// Wait until there's room for another connection
+    pub async fn wait_free(&self) {
+        while self.is_full() {
+            self.freed.wait().await;
+        }
+    }
+
+    /// Wait until at least one central is connected
+    pub async fn wait_any(&self) {
+        while self.is_empty() {
+            self.joined.wait().await;
+        }
+    }
+
+    /// The connections right now, to notify without holding the registry
+    pub fn connections(&self) -> [Option<Connection<'d>>; CONNECTIONS_MAX] {
+        self.conns.borrow().clone()
+    }
+}
+
+impl Default for Links<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
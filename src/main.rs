@@ -7,6 +7,7 @@ use core::fmt::Write;
 use embassy_executor::Executor;
 use embassy_futures::join::join;
 use embassy_futures::select::select;
+use embassy_futures::select::Either;
 use embassy_rp::multicore::Stack;
 
 use emb_test::lighting::Message;
@@ -15,11 +16,13 @@ use embassy_rp::PeripheralRef;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
 use embassy_sync::channel::Receiver;
-use log::info;
+use embassy_sync::channel::Sender;
 
 use bt_hci::controller::ExternalController;
 use embassy_rp::peripherals::USB;
+#[cfg(feature = "log")]
 use embassy_rp::usb::Driver;
+use embassy_time::with_timeout;
 use embassy_time::Timer;
 use static_cell::ConstStaticCell;
 use static_cell::StaticCell;
@@ -27,28 +30,141 @@ use static_cell::StaticCell;
 use cyw43_pio::PioSpi;
 
 use embassy_executor::Spawner;
+use embassy_rp::adc::{self, Adc};
+use embassy_rp::flash::Flash;
+use embassy_rp::gpio::Input;
 use embassy_rp::gpio::Level;
 use embassy_rp::gpio::Output;
+use embassy_rp::gpio::Pull;
 use embassy_rp::i2c::{self, I2c};
 use embassy_rp::pio::Pio;
+use embassy_rp::pwm::Pwm;
 
+use embassy_rp::adc::InterruptHandler as ADCInterruptHandler;
 use embassy_rp::bind_interrupts;
 use embassy_rp::i2c::InterruptHandler as I2CInterruptHandler;
+use embassy_rp::peripherals::DMA_CH1;
 use embassy_rp::peripherals::I2C0;
 use embassy_rp::peripherals::PIO0;
 use embassy_rp::peripherals::PIO1;
+use embassy_rp::peripherals::WATCHDOG;
 use embassy_rp::pio::InterruptHandler as PIOInterruptHandler;
 use embassy_rp::usb::InterruptHandler as USBInterruptHandler;
 
-use defmt as _;
+#[cfg(feature = "defmt")]
 use defmt_rtt as _;
 
 use ssd1306::I2CDisplayInterface;
 use ssd1306::{prelude::*, Ssd1306};
 
+use emb_test::accept;
+use emb_test::adcstream;
+#[cfg(feature = "admin")]
+use emb_test::admin;
+use emb_test::alarm;
 use emb_test::blue;
+use emb_test::board;
+use emb_test::boardrev;
+use emb_test::bus;
+use emb_test::bus::Bus;
+use emb_test::calibration;
+use emb_test::capture::EdgeCapture;
+use emb_test::config;
+#[cfg(feature = "log")]
+use emb_test::console;
+use emb_test::crash;
+use emb_test::error;
+use emb_test::expander::Expander;
+use emb_test::flash;
+use emb_test::gpio;
+use emb_test::handoff;
+use emb_test::hcsr04::Hcsr04;
+use emb_test::info;
 use emb_test::led::LedDriver;
+use emb_test::level;
 use emb_test::lighting;
+use emb_test::mode;
+use emb_test::modules;
+use emb_test::modules::Resources;
+use emb_test::monitor;
+use emb_test::net;
+use emb_test::params;
+use emb_test::peers;
+use emb_test::persist;
+use emb_test::pinmap;
+use emb_test::preset;
+use emb_test::radiofw;
+use emb_test::relay;
+#[cfg(feature = "rolling")]
+use emb_test::rolling;
+use emb_test::supervisor;
+use emb_test::system;
+use emb_test::watchdog;
+use emb_test::weather;
+
+/// Take GPIO `$n` as its own peripheral type, so it can go to drivers that
+/// only take particular pins, and evaluate `$body` with it. Only the pins
+/// listed are accepted.
+macro_rules! with_pin {
+    ($n:expr, [$($num:literal => $name:ident),* $(,)?], |$pin:ident| $body:expr) => {
+        match $n {
+            $($num => {
+                // SAFETY: the pin map was validated, no pin is mapped twice
+                // and main doesn't take mapped pins out of `p`
+                let $pin = unsafe { embassy_rp::peripherals::$name::steal() };
+                $body
+            })*
+            n => unreachable!("pin {} passed validation", n),
+        }
+    };
+}
+
+/// Any GPIO the radio doesn't own
+macro_rules! with_gpio {
+    ($n:expr, |$pin:ident| $body:expr) => {
+        with_pin!($n, [
+            0 => PIN_0, 1 => PIN_1, 2 => PIN_2, 3 => PIN_3, 4 => PIN_4,
+            5 => PIN_5, 6 => PIN_6, 7 => PIN_7, 8 => PIN_8, 9 => PIN_9,
+            10 => PIN_10, 11 => PIN_11, 12 => PIN_12, 13 => PIN_13,
+            14 => PIN_14, 15 => PIN_15, 16 => PIN_16, 17 => PIN_17,
+            18 => PIN_18, 19 => PIN_19, 20 => PIN_20, 21 => PIN_21,
+            22 => PIN_22, 26 => PIN_26, 28 => PIN_28,
+        ], |$pin| $body)
+    };
+}
+
+/// PWM output on GPIO `$n`, which fixes the slice and the channel
+macro_rules! with_pwm {
+    ($n:expr, $config:expr) => {
+        with_pwm!(@ $n, $config, [
+            0 => PWM_SLICE0 PIN_0 new_output_a, 1 => PWM_SLICE0 PIN_1 new_output_b,
+            2 => PWM_SLICE1 PIN_2 new_output_a, 3 => PWM_SLICE1 PIN_3 new_output_b,
+            4 => PWM_SLICE2 PIN_4 new_output_a, 5 => PWM_SLICE2 PIN_5 new_output_b,
+            6 => PWM_SLICE3 PIN_6 new_output_a, 7 => PWM_SLICE3 PIN_7 new_output_b,
+            8 => PWM_SLICE4 PIN_8 new_output_a, 9 => PWM_SLICE4 PIN_9 new_output_b,
+            10 => PWM_SLICE5 PIN_10 new_output_a, 11 => PWM_SLICE5 PIN_11 new_output_b,
+            12 => PWM_SLICE6 PIN_12 new_output_a, 13 => PWM_SLICE6 PIN_13 new_output_b,
+            14 => PWM_SLICE7 PIN_14 new_output_a, 15 => PWM_SLICE7 PIN_15 new_output_b,
+            16 => PWM_SLICE0 PIN_16 new_output_a, 17 => PWM_SLICE0 PIN_17 new_output_b,
+            18 => PWM_SLICE1 PIN_18 new_output_a, 19 => PWM_SLICE1 PIN_19 new_output_b,
+            20 => PWM_SLICE2 PIN_20 new_output_a, 21 => PWM_SLICE2 PIN_21 new_output_b,
+            22 => PWM_SLICE3 PIN_22 new_output_a, 26 => PWM_SLICE5 PIN_26 new_output_a,
+            28 => PWM_SLICE6 PIN_28 new_output_a,
+        ])
+    };
+    (@ $n:expr, $config:expr, [$($num:literal => $slice:ident $name:ident $new:ident),* $(,)?]) => {
+        match $n {
+            $($num => {
+                // SAFETY: as for with_pin!, and nothing else takes a PWM
+                // slice
+                let slice = unsafe { embassy_rp::peripherals::$slice::steal() };
+                let pin = unsafe { embassy_rp::peripherals::$name::steal() };
+                Pwm::$new(slice, pin, $config)
+            })*
+            n => unreachable!("pin {} passed validation", n),
+        }
+    };
+}
 
 // Bind interrupts to their handlers.
 bind_interrupts!(struct Irqs {
@@ -56,14 +172,24 @@ bind_interrupts!(struct Irqs {
     I2C0_IRQ => I2CInterruptHandler<I2C0>;
     PIO0_IRQ_0 => PIOInterruptHandler<PIO0>;
     PIO1_IRQ_0 => PIOInterruptHandler<PIO1>;
+    ADC_IRQ_FIFO => ADCInterruptHandler;
 });
 
+/// Rate of the sensor on the ADC, summarized over BLE
+const ADC_RATE_HZ: u32 = 1000;
+
+/// BLE stack restarts in a row before rebooting
+const STACK_RESTARTS_MAX: u32 = 8;
+
 static CORE1_STACK: ConstStaticCell<Stack<4096>> = ConstStaticCell::new(Stack::new());
 static EXECUTOR1: StaticCell<Executor> = StaticCell::new();
 
+/// Logs go out the USB serial port, which also carries the console. With
+/// `defmt` they go over RTT instead, and there's no console.
+#[cfg(feature = "log")]
 #[embassy_executor::task]
 async fn logger_task(driver: Driver<'static, USB>) {
-    embassy_usb_logger::run!(1024, log::LevelFilter::Info, driver);
+    embassy_usb_logger::run!(1024, log::LevelFilter::Info, driver, console::Handler);
 }
 
 #[embassy_executor::task]
@@ -74,32 +200,152 @@ async fn lighting_task(
     lighting::run(led_driver, recv).await;
 }
 
+#[embassy_executor::task]
+async fn mode_task(
+    button: Input<'static>,
+    sender: Sender<'static, CriticalSectionRawMutex, Message, 1>,
+) -> ! {
+    mode::run(button, sender).await;
+}
+
+#[embassy_executor::task]
+async fn alarm_task(
+    sender: Sender<'static, CriticalSectionRawMutex, Message, 1>,
+    buzzer: Option<Output<'static>>,
+) -> ! {
+    alarm::run(sender, buzzer).await;
+}
+
+#[embassy_executor::task]
+async fn monitor_task() -> ! {
+    monitor::run().await;
+}
+
+#[embassy_executor::task]
+async fn watchdog_task(watchdog: WATCHDOG) -> ! {
+    watchdog::run(watchdog).await;
+}
+
+#[embassy_executor::task]
+async fn flash_task() -> ! {
+    flash::run().await;
+}
+
+#[embassy_executor::task]
+async fn persist_task() -> ! {
+    persist::run().await;
+}
+
+#[embassy_executor::task]
+async fn relay_task(bus: &'static Bus, expander: Expander, interrupt: Option<Input<'static>>) -> ! {
+    relay::run(bus, expander, interrupt).await;
+}
+
+#[embassy_executor::task]
+async fn gpio_task(
+    outputs: [Option<Output<'static>>; gpio::OUTPUTS_MAX],
+    pwm: Option<Pwm<'static>>,
+) -> ! {
+    gpio::run(outputs, pwm).await;
+}
+
+#[embassy_executor::task]
+async fn level_task(sensor: Hcsr04<'static, PIO1, 1>) -> ! {
+    level::run(sensor).await;
+}
+
+#[embassy_executor::task]
+async fn weather_task(
+    wind: Option<EdgeCapture<'static, PIO1, 2>>,
+    rain: Option<EdgeCapture<'static, PIO1, 3>>,
+) -> ! {
+    weather::run(wind, rain).await;
+}
+
+#[embassy_executor::task]
+async fn adc_task(
+    mut adc: Adc<'static, adc::Async>,
+    mut channel: adc::Channel<'static>,
+    mut battery: Option<adc::Channel<'static>>,
+    dma: DMA_CH1,
+) -> ! {
+    let div = adcstream::divider(ADC_RATE_HZ).unwrap();
+    adcstream::run(&mut adc, &mut channel, battery.as_mut(), dma, div).await;
+}
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     // Initialize peripherals and USB driver.
-    let p = embassy_rp::init(Default::default());
+    let mut p = embassy_rp::init(Default::default());
+
+    // finish a reboot-to-bootloader request from before the reset
+    system::handle_boot_request();
+    system::DeviceId::init(&mut p.FLASH);
+    flash::init(Flash::new_blocking(p.FLASH));
+    spawner.must_spawn(flash_task());
+    spawner.must_spawn(monitor_task());
+    let revision = boardrev::detect(&mut p.ADC, &mut p.PIN_27);
+    crash::recover().await;
+    let pins = pinmap::load(revision.pins).await;
+    calibration::load().await;
+    relay::load().await;
+    peers::load().await;
+    config::load().await;
+    alarm::load().await;
+    gpio::load().await;
+    #[cfg(feature = "rolling")]
+    rolling::load().await;
+    #[cfg(feature = "admin")]
+    admin::load().await;
+    spawner.must_spawn(persist_task());
+    spawner.must_spawn(watchdog_task(p.WATCHDOG));
 
     // Spawn USB logger
-    let usb_driver = Driver::new(p.USB, Irqs);
-    spawner.must_spawn(logger_task(usb_driver));
+    #[cfg(feature = "log")]
+    spawner.must_spawn(logger_task(Driver::new(p.USB, Irqs)));
 
     // sleep 1 second to give us time to start the serial connection
     Timer::after_secs(1).await;
     info!("Hello, world!");
 
-    // initialize the OLED (SSD1306)
-    let i2c = I2c::new_async(p.I2C0, p.PIN_1, p.PIN_0, Irqs, i2c::Config::default());
-    let interface = I2CDisplayInterface::new(i2c);
-    let mut display =
-        Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0).into_terminal_mode();
-    display.init().unwrap();
-    display.clear().unwrap();
-    let _ = write!(display, "Hello, world!");
+    let mut i2c = with_pin!(
+        pins.i2c_scl,
+        [1 => PIN_1, 5 => PIN_5, 9 => PIN_9, 13 => PIN_13, 17 => PIN_17, 21 => PIN_21],
+        |scl| {
+            with_pin!(
+                pins.i2c_sda,
+                [0 => PIN_0, 4 => PIN_4, 8 => PIN_8, 12 => PIN_12, 16 => PIN_16, 20 => PIN_20],
+                |sda| I2c::new_async(p.I2C0, scl, sda, Irqs, i2c::Config::default())
+            )
+        }
+    );
+
+    // initialize the OLED (SSD1306), on the revisions that have one
+    if revision.display {
+        let interface = I2CDisplayInterface::new(&mut i2c);
+        let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
+            .into_terminal_mode();
+        display.init().unwrap();
+        display.clear().unwrap();
+        let _ = write!(display, "Hello, world!");
+    }
+
+    // the relay bank and the registered modules (the RTC, the energy meter)
+    // share the bus once the display is done with it
+    let bus = bus::share(i2c);
+    modules::init_all(&Resources { spawner, bus });
+    let interrupt = pins
+        .expander_int
+        .filter(|_| revision.expander.has_inputs())
+        .map(|n| with_gpio!(n, |pin| Input::new(pin, Pull::Up)));
+    spawner.must_spawn(relay_task(bus, revision.expander, interrupt));
 
     let mut pio = Pio::new(p.PIO1, Irqs);
 
     // initialize the w2812 LEDs
-    let leds = { LedDriver::new(&mut pio.common, pio.sm0, p.PIN_28) };
+    let leds = with_gpio!(pins.led, |pin| {
+        LedDriver::new(&mut pio.common, pio.sm0, pin)
+    });
 
     let lighting_channel = {
         static LIGHTING_CHANNEL: ConstStaticCell<Channel<CriticalSectionRawMutex, Message, 1>> =
@@ -113,12 +359,69 @@ async fn main(spawner: Spawner) {
         executor1.run(|spawner| spawner.spawn(lighting_task(leds, recv)).unwrap());
     });
 
+    // hold the button for a few seconds to enter maintenance mode
+    let button = with_gpio!(pins.button, |pin| Input::new(pin, Pull::Up));
+    spawner.must_spawn(mode_task(button, lighting_channel.sender()));
+
+    // sensor on the ADC, streamed and summarized for BLE clients, and the
+    // battery read in between
+    let adc = Adc::new(p.ADC, Irqs, adc::Config::default());
+    let channel = with_pin!(pins.adc, [26 => PIN_26, 28 => PIN_28], |pin| {
+        adc::Channel::new_pin(pin, Pull::None)
+    });
+    let battery = pins.battery.map(|n| {
+        with_pin!(n, [26 => PIN_26, 28 => PIN_28], |pin| {
+            adc::Channel::new_pin(pin, Pull::None)
+        })
+    });
+    spawner.must_spawn(adc_task(adc, channel, battery, p.DMA_CH1));
+
+    // buzzer, if the board has one, for alarms nobody acknowledged
+    let buzzer = pins
+        .buzzer
+        .map(|n| with_gpio!(n, |pin| Output::new(pin, Level::Low)));
+    spawner.must_spawn(alarm_task(lighting_channel.sender(), buzzer));
+
+    // spare pins for clients to drive, and an LED to dim
+    let assignment = gpio::assign(&pins);
+    let outputs = assignment
+        .outputs
+        .map(|pin| pin.map(|n| with_gpio!(n, |pin| Output::new(pin, Level::Low))));
+    let pwm = assignment.pwm.map(|n| with_pwm!(n, gpio::pwm_config(n, 0)));
+    spawner.must_spawn(gpio_task(outputs, pwm));
+
+    // an ultrasonic tank level sensor, if it's given spare pins
+    let level_pins = level::assign(&pins, &assignment);
+    if let Some(level_pins) = level_pins {
+        let trigger = with_gpio!(level_pins.trigger, |pin| pio.common.make_pio_pin(pin));
+        let echo = with_gpio!(level_pins.echo, |pin| pio.common.make_pio_pin(pin));
+        let sensor = Hcsr04::new(&mut pio.common, pio.sm1, trigger, echo);
+        spawner.must_spawn(level_task(sensor));
+    }
+
+    // the anemometer and rain gauge, on whatever spare pins they're given
+    let weather_pins = weather::assign(&pins, &assignment, level_pins);
+    let wind = weather_pins.wind.map(|n| {
+        with_gpio!(n, |pin| EdgeCapture::new(
+            &mut pio.common,
+            pio.sm2,
+            pin,
+            Pull::Up
+        ))
+    });
+    let rain = weather_pins.rain.map(|n| {
+        with_gpio!(n, |pin| EdgeCapture::new(
+            &mut pio.common,
+            pio.sm3,
+            pin,
+            Pull::Up
+        ))
+    });
+    spawner.must_spawn(weather_task(wind, rain));
+
     // initialize the bluetooth chip
-    // first, lets get the firmware in here. we need this firmware to use
-    // the onboard bluetooth chip
-    let fw = include_bytes!("../firmware/43439A0.bin");
-    let clm = include_bytes!("../firmware/43439A0_clm.bin");
-    let btfw = include_bytes!("../firmware/43439A0_btfw.bin");
+    // we need the built-in firmware to use the onboard bluetooth chip. dfu
+    // builds can replace it with a package in flash
 
     // We're gonna loop here because the bluetooth driver cannot handle reconnection.
     // The bluetooth driver crashes after the client disconnects, and I can't be bothered
@@ -129,6 +432,11 @@ async fn main(spawner: Spawner) {
     let mut dma = PeripheralRef::new(p.DMA_CH0);
     let mut pio = PeripheralRef::new(p.PIO0);
 
+    // interrupt handlers claim the producer side
+    let mut updates = handoff::UPDATES.consumer().unwrap();
+
+    let mut stack = supervisor::Child::new("ble stack", STACK_RESTARTS_MAX);
+
     let cyw43_state = {
         static STATE: StaticCell<cyw43::State> = StaticCell::new();
         STATE.init(cyw43::State::new())
@@ -155,14 +463,57 @@ async fn main(spawner: Spawner) {
 
         // spin up the driver
         *cyw43_state = cyw43::State::new();
-        let (_net_device, bt_device, mut control, runner) =
-            cyw43::new_with_bluetooth(cyw43_state, pwr, spi, fw, btfw).await;
+        let blobs = radiofw::select(&board::BUILT_IN);
+        let radio =
+            cyw43::new_with_bluetooth(cyw43_state, pwr, spi, blobs.firmware, blobs.bluetooth);
+        let Ok((net_device, bt_device, mut control, runner)) =
+            with_timeout(radiofw::INIT_TIMEOUT, radio).await
+        else {
+            error!("radio didn't come up with {:?}", blobs.source);
+            radiofw::reject(blobs.source);
+            // counts like any other stack failure, so a radio that never
+            // comes up ends in a reboot rather than a busy loop
+            if !stack.failed(&"radio init timed out").await {
+                system::reboot();
+            }
+            watchdog::stack_restarted();
+            continue;
+        };
         let controller: ExternalController<_, 10> = ExternalController::new(bt_device);
 
-        select(
-            join(control.init(clm), runner.run()), // run the cyw43 driver
-            blue::run(controller, lighting_channel.sender()), // run the ble driver
+        let exit = select(
+            // run the cyw43 driver, then wifi once it's up
+            join(
+                async {
+                    control.init(blobs.clm).await;
+                    let control = net::Control::new(control);
+                    join(
+                        net::run(&control, net_device, lighting_channel.sender()),
+                        gpio::onboard(&control),
+                    )
+                    .await;
+                },
+                runner.run(),
+            ),
+            blue::run(
+                controller,
+                lighting_channel.sender(),
+                &preset::configured(params::Config::DEFAULT),
+                &accept::Lockout,
+                &mut updates,
+            ), // run the ble driver
         )
         .await;
+
+        relay::all_off();
+        if let Either::Second(exit) = exit {
+            // back off while the stack keeps failing, reboot once it's
+            // failed too often in a row
+            if !stack.failed(&exit).await {
+                system::reboot();
+            }
+            watchdog::stack_restarted();
+            info!("restarting the radio");
+        }
     }
 }
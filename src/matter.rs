@@ -0,0 +1,159 @@
+//! Matter commissioning over BLE, the BLE side only
+//!
+//! Enough for a Matter commissioner to find the device and open a BTP
+//! (Bluetooth Transport Protocol) session: the commissionable advertisement
+//! and the BTP service with its handshake. Once the session is up, BTP
+//! packets are handed to an external Matter stack through [`receive`],
+//! which does the reassembly and runs PASE from there.
+//!
+//! BTP says C2 indicates. The BLE host can't send indications, so C2
+//! notifies instead, and commissioners that only subscribe to indications
+//! won't get past the handshake.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+/// 16-bit UUID of the BTP service, also used for the advertisement's
+/// service data
+pub const SERVICE_UUID: u16 = 0xfff6;
+
+/// C1, written by the commissioner
+pub const C1_UUID: [u8; 16] = uuid(0x11);
+/// C2, notified to the commissioner
+pub const C2_UUID: [u8; 16] = uuid(0x12);
+
+/// The BTP characteristic UUIDs, 18EE2EF5-263D-4559-959F-4F9C429F9Dxx,
+/// least significant byte first
+const fn uuid(last: u8) -> [u8; 16] {
+    [
+        last, 0x9d, 0x9f, 0x42, 0x9c, 0x4f, 0x9f, 0x95, 0x59, 0x45, 0x3d, 0x26, 0xf5, 0x2e, 0xee,
+        0x18,
+    ]
+}
+
+/// BTP protocol version we speak
+const BTP_VERSION: u8 = 4;
+
+/// Handshake flags byte (handshake, management, beginning and end of
+/// message) and management opcode
+const HANDSHAKE_FLAGS: u8 = 0x65;
+const HANDSHAKE_OPCODE: u8 = 0x6c;
+
+/// Largest BTP packet we take, the ATT MTU minus the write header
+pub const MAX_SEGMENT: usize = 244;
+
+/// Packets we buffer for the external stack
+const WINDOW_SIZE: u8 = 4;
+
+/// What the commissionable advertisement says about us
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Commissioning {
+    /// 12-bit discriminator matching the setup code
+    pub discriminator: u16,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl Commissioning {
+    /// The test vendor and product ids with the default test discriminator
+    pub const TEST: Self = Self {
+        discriminator: 3840,
+        vendor_id: 0xfff1,
+        product_id: 0x8000,
+    };
+
+    /// Service data for the advertisement (after the UUID): opcode,
+    /// discriminator and advertisement version, vendor, product,
+    /// additional data flag
+    pub fn service_data(&self) -> [u8; 8] {
+        let disc = (self.discriminator & 0x0fff).to_le_bytes();
+        let vendor = self.vendor_id.to_le_bytes();
+        let product = self.product_id.to_le_bytes();
+        [
+            0x00, disc[0], disc[1], vendor[0], vendor[1], product[0], product[1], 0x00,
+        ]
+    }
+}
+
+/// A BTP handshake request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Handshake {
+    /// Highest version both sides support
+    pub version: u8,
+    pub att_mtu: u16,
+    pub window_size: u8,
+}
+
+impl Handshake {
+    /// Parse a C1 write, `None` if it isn't a handshake we can serve
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let [HANDSHAKE_FLAGS, HANDSHAKE_OPCODE, versions @ .., mtu_lo, mtu_hi, window_size] = value
+        else {
+            return None;
+        };
+        // four bytes of version nibbles, newest first
+        let versions: [u8; 4] = versions.try_into().ok()?;
+        let version = versions
+            .iter()
+            .flat_map(|b| [b & 0x0f, b >> 4])
+            .filter(|&v| v != 0 && v <= BTP_VERSION)
+            .max()?;
+
+        Some(Self {
+            version,
+            att_mtu: u16::from_le_bytes([*mtu_lo, *mtu_hi]),
+            window_size: *window_size,
+        })
+    }
+
+    /// Response to notify on C2
+    pub fn response(&self) -> [u8; 6] {
+        let segment = (self.att_mtu.saturating_sub(3))
+            .clamp(20, MAX_SEGMENT as u16)
+            .to_le_bytes();
+        [
+            HANDSHAKE_FLAGS,
+            HANDSHAKE_OPCODE,
+            self.version,
+            segment[0],
+            segment[1],
+            self.window_size.min(WINDOW_SIZE),
+        ]
+    }
+}
+
+/// A BTP packet received on C1 after the handshake
+#[derive(Clone)]
+pub struct Packet {
+    len: u8,
+    data: [u8; MAX_SEGMENT],
+}
+
+impl Packet {
+    pub fn new(data: &[u8]) -> Option<Self> {
+        let mut packet = Self {
+            len: data.len() as u8,
+            data: [0; MAX_SEGMENT],
+        };
+        packet.data.get_mut(..data.len())?.copy_from_slice(data);
+        Some(packet)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+static INBOUND: Channel<CriticalSectionRawMutex, Packet, { WINDOW_SIZE as usize }> = Channel::new();
+
+/// Hand a packet to the external stack, dropped if it isn't keeping up
+pub(crate) fn deliver(packet: Packet) -> bool {
+    INBOUND.try_send(packet).is_ok()
+}
+
+/// Next BTP packet for the external stack
+pub async fn receive() -> Packet {
+    INBOUND.receive().await
+}
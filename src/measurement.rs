@@ -0,0 +1,119 @@
+//! Time-stamped readings
+//!
+//! A bare number loses its context on the way through the modules. By the
+//! time it reaches a sink or a characteristic, nothing says how old it is
+//! or whether the sensor could be trusted when it took it. A
+//! [`Measurement`] carries that context with the value: its [`Unit`],
+//! when it was taken, and quality flags. It goes from the sensor that
+//! takes it to the state it's kept in, then to the telemetry sinks (see
+//! [`crate::telemetry`]) and the GATT encoders.
+//!
+//! A sensor that fails keeps its last value and flags it [`FAULT`]. A
+//! value that hasn't been refreshed for a few of its sensor's intervals is
+//! [`STALE`] by [`Measurement::aged`]. Encoders serve a measurement that
+//! isn't good as their characteristic's unknown value. Only good
+//! measurements are checked against the alarms and go into the daily
+//! statistics (see [`crate::rollup`]).
+
+use embassy_time::Duration;
+use embassy_time::Instant;
+
+// quality flags, none for a good measurement
+/// Not refreshed for longer than its sensor should take
+pub const STALE: u8 = 0x01;
+/// The sensor failed, the value is the last one it gave
+pub const FAULT: u8 = 0x02;
+
+/// Intervals of its sensor a measurement may go without a refresh
+pub const STALE_INTERVALS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Unit {
+    Celsius,
+    Percent,
+    Pascal,
+    MetresPerSecond,
+    Degrees,
+    Millimetres,
+    Volts,
+    Amperes,
+}
+
+impl Unit {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Celsius => "C",
+            Self::Percent => "%",
+            Self::Pascal => "Pa",
+            Self::MetresPerSecond => "m/s",
+            Self::Degrees => "deg",
+            Self::Millimetres => "mm",
+            Self::Volts => "V",
+            Self::Amperes => "A",
+        }
+    }
+}
+
+/// A value, in the fixed point its producer documents, and when and how
+/// well it was taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Measurement<T> {
+    pub value: T,
+    pub unit: Unit,
+    pub at: Instant,
+    /// Quality flags, 0 if good
+    pub quality: u8,
+}
+
+impl<T> Measurement<T> {
+    /// A good measurement taken now
+    pub fn new(value: T, unit: Unit) -> Self {
+        Self {
+            value,
+            unit,
+            at: Instant::now(),
+            quality: 0,
+        }
+    }
+
+    /// Add quality flags
+    pub fn with(mut self, quality: u8) -> Self {
+        self.quality |= quality;
+        self
+    }
+
+    pub fn age(&self) -> Duration {
+        Instant::now().saturating_duration_since(self.at)
+    }
+
+    /// Flagged [`STALE`] if older than [`STALE_INTERVALS`] of `interval`,
+    /// the time between two measurements of its sensor
+    pub fn aged(self, interval: Duration) -> Self {
+        if self.age() > interval * STALE_INTERVALS {
+            self.with(STALE)
+        } else {
+            self
+        }
+    }
+
+    pub fn is_good(&self) -> bool {
+        self.quality == 0
+    }
+
+    /// The value, if the measurement is good
+    pub fn good(self) -> Option<T> {
+        self.is_good().then_some(self.value)
+    }
+
+    /// The same measurement of another value
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Measurement<U> {
+        Measurement {
+            value: f(self.value),
+            unit: self.unit,
+            at: self.at,
+            quality: self.quality,
+        }
+    }
+}
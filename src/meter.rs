@@ -0,0 +1,398 @@
+//! Energy metering
+//!
+//! An INA219 or INA226 current monitor on the shared bus (see
+//! [`crate::bus`]) measures the bus voltage and the voltage across a shunt
+//! resistor. Whichever of the two is fitted is found at start-up. The
+//! current is worked out from the shunt voltage and the configured shunt
+//! resistance, and integrated into energy every period.
+//!
+//! The bus voltage and current are alarm signals ([`alarm::BUS_VOLTAGE`],
+//! [`alarm::CURRENT`]), with limits set like any other signal's.
+//!
+//! The module serves its own GATT service (see [`crate::modules`]): the
+//! bus voltage, current and energy as read, the energy writable to zero
+//! it, and the configuration. That's `[shunt µΩ: u32, period ms: u16]`
+//! little endian, only written in maintenance mode, and kept in flash
+//! behind a version byte once taken.
+
+use core::cell::Cell;
+use core::future::pending;
+
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_rp::i2c;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::alarm;
+use crate::blue::gen_uuid;
+use crate::bus::Bus;
+use crate::error;
+use crate::fault;
+use crate::gattcheck;
+use crate::info;
+use crate::modules;
+use crate::monitor;
+use crate::roles;
+use crate::store;
+use crate::telemetry;
+use crate::writes;
+
+/// The monitor's address with A0 and A1 tied low, the same for both kinds
+const ADDRESS: u8 = 0x40;
+
+// registers, big endian
+const CONFIG: u8 = 0x00;
+const SHUNT_VOLTAGE: u8 = 0x01;
+const BUS_VOLTAGE: u8 = 0x02;
+/// INA226 only
+const MANUFACTURER_ID: u8 = 0xfe;
+
+/// "TI" in the INA226's manufacturer ID register
+const TI: u16 = 0x5449;
+
+/// INA219: 32V range, ±320mV shunt range, 12-bit, continuous
+const INA219_CONFIG: u16 = 0x399f;
+
+/// INA226: 16 averages, 1.1ms conversions, continuous
+const INA226_CONFIG: u16 = 0x4527;
+
+/// Size of the configuration characteristic
+pub const CONFIG_SIZE: usize = 6;
+
+const VERSION: u8 = 1;
+
+/// Shortest period we sample at
+const MIN_PERIOD_MS: u16 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Chip {
+    Ina219,
+    Ina226,
+}
+
+impl Chip {
+    /// Shunt voltage per count, in nV
+    fn shunt_lsb_nv(&self) -> i64 {
+        match self {
+            Self::Ina219 => 10_000,
+            Self::Ina226 => 2_500,
+        }
+    }
+
+    fn bus_mv(&self, raw: u16) -> u16 {
+        match self {
+            // the low bits are flags
+            Self::Ina219 => (raw >> 3) * 4,
+            Self::Ina226 => (raw as u32 * 5 / 4) as u16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    pub shunt_uohm: u32,
+    pub period: Duration,
+}
+
+impl Config {
+    /// The 0.1Ω shunt on the usual breakout boards, sampled every second
+    pub const DEFAULT: Self = Self {
+        shunt_uohm: 100_000,
+        period: Duration::from_secs(1),
+    };
+
+    pub fn encode(&self) -> [u8; CONFIG_SIZE] {
+        let mut out = [0; CONFIG_SIZE];
+        out[..4].copy_from_slice(&self.shunt_uohm.to_le_bytes());
+        out[4..].copy_from_slice(&(self.period.as_millis() as u16).to_le_bytes());
+        out
+    }
+
+    /// `None` without a shunt or with a period too short to sample at
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let [s0, s1, s2, s3, p0, p1] = *value else {
+            return None;
+        };
+        let shunt_uohm = u32::from_le_bytes([s0, s1, s2, s3]);
+        let period_ms = u16::from_le_bytes([p0, p1]);
+        (shunt_uohm != 0 && period_ms >= MIN_PERIOD_MS).then_some(Self {
+            shunt_uohm,
+            period: Duration::from_millis(period_ms as u64),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Malformed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Reading {
+    bus_mv: u16,
+    current_ua: i32,
+    energy_nj: i64,
+}
+
+static CONFIGURED: Mutex<CriticalSectionRawMutex, Cell<Config>> =
+    Mutex::new(Cell::new(Config::DEFAULT));
+
+static READING: Mutex<CriticalSectionRawMutex, Cell<Reading>> = Mutex::new(Cell::new(Reading {
+    bus_mv: 0,
+    current_ua: 0,
+    energy_nj: 0,
+}));
+
+pub fn config() -> Config {
+    CONFIGURED.lock(|config| config.get())
+}
+
+/// A configuration to keep in flash
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Set the configuration from a write to the configuration characteristic.
+/// It's kept in flash right after.
+pub fn set_config(value: &[u8]) -> Result<(), Error> {
+    let config = Config::parse(value).ok_or(Error::Malformed)?;
+    CONFIGURED.lock(|configured| configured.set(config));
+    info!("[meter] {:?}", config);
+    CHANGED.signal(());
+    Ok(())
+}
+
+/// Keep the configuration in flash as it's changed
+async fn save() -> ! {
+    loop {
+        CHANGED.wait().await;
+        let mut record = [0; 1 + CONFIG_SIZE];
+        record[0] = VERSION;
+        record[1..].copy_from_slice(&config().encode());
+        if let Err(e) = store::commit(store::Slot::METER, &record).await {
+            error!("[meter] storing the configuration failed: {:?}", e);
+            fault::raise(fault::METER_STORE);
+        }
+    }
+}
+
+/// Load the configuration from flash
+async fn load() {
+    let mut record = [0; 1 + CONFIG_SIZE];
+    match store::load(store::Slot::METER, &mut record).await {
+        Ok(Some(len)) => {
+            let config = match record[..len].split_first() {
+                Some((&VERSION, value)) => Config::parse(value),
+                _ => None,
+            };
+            match config {
+                Some(config) => {
+                    info!("[meter] loaded {:?}", config);
+                    CONFIGURED.lock(|configured| configured.set(config));
+                }
+                None => error!("[meter] invalid stored record"),
+            }
+        }
+        Ok(None) => info!("[meter] default configuration"),
+        Err(e) => error!("[meter] loading failed: {:?}", e),
+    }
+}
+
+/// Bus voltage in mV, little endian
+pub fn bus_voltage() -> [u8; 2] {
+    READING.lock(|r| r.get().bus_mv.to_le_bytes())
+}
+
+/// Current in µA, little endian
+pub fn current() -> [u8; 4] {
+    READING.lock(|r| r.get().current_ua.to_le_bytes())
+}
+
+/// Energy since boot (or the last write) in mJ, little endian
+pub fn energy() -> [u8; 8] {
+    READING.lock(|r| (r.get().energy_nj / 1_000_000).to_le_bytes())
+}
+
+/// Set the energy accumulator from a write to the energy characteristic,
+/// usually to zero it
+pub fn set_energy(value: &[u8]) -> Result<(), Error> {
+    let mj = i64::from_le_bytes(value.try_into().map_err(|_| Error::Malformed)?);
+    READING.lock(|r| {
+        let mut reading = r.get();
+        reading.energy_nj = mj.saturating_mul(1_000_000);
+        r.set(reading);
+    });
+    info!("[meter] energy set to {}mJ", mj);
+    Ok(())
+}
+
+async fn read_reg(bus: &Bus, reg: u8) -> Result<u16, i2c::Error> {
+    let mut value = [0; 2];
+    bus.lock()
+        .await
+        .write_read_async(ADDRESS as u16, [reg], &mut value)
+        .await?;
+    Ok(u16::from_be_bytes(value))
+}
+
+async fn write_reg(bus: &Bus, reg: u8, value: u16) -> Result<(), i2c::Error> {
+    let [hi, lo] = value.to_be_bytes();
+    bus.lock()
+        .await
+        .write_async(ADDRESS as u16, [reg, hi, lo])
+        .await
+}
+
+/// Find out which monitor is fitted and set it up. The INA219 doesn't
+/// have the manufacturer ID register.
+async fn detect(bus: &Bus) -> Result<Chip, i2c::Error> {
+    let chip = match read_reg(bus, MANUFACTURER_ID).await? {
+        TI => Chip::Ina226,
+        _ => Chip::Ina219,
+    };
+    let config = match chip {
+        Chip::Ina219 => INA219_CONFIG,
+        Chip::Ina226 => INA226_CONFIG,
+    };
+    write_reg(bus, CONFIG, config).await?;
+    Ok(chip)
+}
+
+/// Read the monitor and integrate the power since `last`, returning when
+/// the reading was taken
+async fn sample(bus: &Bus, chip: Chip, last: Instant) -> Result<Instant, i2c::Error> {
+    let shunt = read_reg(bus, SHUNT_VOLTAGE).await? as i16;
+    let bus_mv = chip.bus_mv(read_reg(bus, BUS_VOLTAGE).await?);
+    let now = Instant::now();
+
+    // nV / µΩ is mA
+    let shunt_nv = shunt as i64 * chip.shunt_lsb_nv();
+    let current_ua = (shunt_nv * 1000 / config().shunt_uohm as i64) as i32;
+    // µW · ms is nJ
+    let power_uw = bus_mv as i64 * current_ua as i64 / 1000;
+    let elapsed_ms = now.saturating_duration_since(last).as_millis() as i64;
+
+    READING.lock(|r| {
+        let reading = r.get();
+        r.set(Reading {
+            bus_mv,
+            current_ua,
+            energy_nj: reading.energy_nj.saturating_add(power_uw * elapsed_ms),
+        })
+    });
+    alarm::check(alarm::BUS_VOLTAGE, bus_mv as i32, bus_mv as i32);
+    alarm::check(alarm::CURRENT, current_ua, current_ua);
+    telemetry::record(telemetry::Signal::BusVoltage, bus_mv as i32);
+    telemetry::record(telemetry::Signal::Current, current_ua);
+    Ok(now)
+}
+
+pub async fn run(bus: &'static Bus) -> ! {
+    load().await;
+    match select(save(), measure(bus)).await {
+        Either::First(never) | Either::Second(never) => never,
+    }
+}
+
+async fn measure(bus: &'static Bus) -> ! {
+    let chip = match detect(bus).await {
+        Ok(chip) => chip,
+        Err(e) => {
+            info!("[meter] no current monitor found: {:?}", e);
+            pending().await
+        }
+    };
+    info!("[meter] {:?}", chip);
+
+    let mut last = Instant::now();
+    loop {
+        monitor::METER.ping();
+        let at = Instant::now();
+        match sample(bus, chip, last).await {
+            Ok(taken) => last = taken,
+            Err(e) => {
+                error!("[meter] reading failed: {:?}", e);
+                fault::raise(fault::METER_READ);
+            }
+        }
+
+        monitor::METER.pause();
+        Timer::at(at + config().period).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn task(bus: &'static Bus) -> ! {
+    run(bus).await
+}
+
+const SERVICE_UUID: Uuid = gen_uuid("energy meter");
+const BUS_VOLTAGE_UUID: Uuid = gen_uuid("bus voltage");
+const CURRENT_UUID: Uuid = gen_uuid("current");
+const ENERGY_UUID: Uuid = gen_uuid("energy");
+const CONFIG_UUID: Uuid = gen_uuid("meter config");
+const _: () =
+    gattcheck::assert_distinct(&[&[BUS_VOLTAGE_UUID, CURRENT_UUID, ENERGY_UUID, CONFIG_UUID]]);
+const _: () = gattcheck::assert_fit(&[2, 4, 8, CONFIG_SIZE], writes::VALUE_MAX);
+
+fn write_energy(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    set_energy(value).map_err(|_| writes::AttError::InvalidLength)
+}
+
+fn write_config(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    set_config(value).map_err(|_| writes::AttError::ValueNotAllowed)
+}
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    use CharacteristicProp::Read;
+    use CharacteristicProp::Write;
+
+    let spec = writes::Spec::new();
+    svc.add(
+        BUS_VOLTAGE_UUID,
+        &[Read],
+        2,
+        spec.with_read(|| writes::Value::new(&bus_voltage())),
+    );
+    svc.add(
+        CURRENT_UUID,
+        &[Read],
+        4,
+        spec.with_read(|| writes::Value::new(&current())),
+    );
+    svc.add(
+        ENERGY_UUID,
+        &[Read, Write],
+        8,
+        spec.with_read(|| writes::Value::new(&energy()))
+            .with_write(write_energy)
+            .with_access(roles::Access::Configure),
+    );
+    svc.add(
+        CONFIG_UUID,
+        &[Read, Write],
+        CONFIG_SIZE,
+        spec.with_read(|| writes::Value::new(&config().encode()))
+            .with_write(write_config)
+            .with_access(roles::Access::Configure)
+            .in_maintenance(),
+    );
+}
+
+crate::register_module!(
+    MODULE,
+    "meter",
+    |resources| resources.spawner.must_spawn(task(resources.bus)),
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |services| services.meter,
+        build: service,
+    },
+);
@@ -0,0 +1,102 @@
+//! Normal and maintenance mode
+//!
+//! Normally the device advertises as a plain light and refuses writes to
+//! the sensitive characteristics (system control, firmware hashing). A long
+//! press on the button opens a maintenance window: the advertising data
+//! changes so setup apps can find us, the sensitive characteristics open up
+//! and the first LED of the strip lights up until the window closes.
+
+use core::cell::Cell;
+
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_rp::gpio::Input;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Sender;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+
+use crate::info;
+use crate::lighting::Message;
+use crate::power;
+
+/// How long the button has to be held to enter maintenance mode
+pub const LONG_PRESS: Duration = Duration::from_secs(3);
+
+/// How long maintenance mode lasts
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    Normal,
+    Maintenance,
+}
+
+static MODE: Mutex<CriticalSectionRawMutex, Cell<Mode>> = Mutex::new(Cell::new(Mode::Normal));
+
+/// Raised on every mode change, waited on by the advertiser
+static CHANGED: Signal<CriticalSectionRawMutex, Mode> = Signal::new();
+
+pub fn current() -> Mode {
+    MODE.lock(|mode| mode.get())
+}
+
+pub fn is_maintenance() -> bool {
+    current() == Mode::Maintenance
+}
+
+/// Wait for the next mode change. Only meant for a single waiter.
+pub async fn changed() -> Mode {
+    CHANGED.wait().await
+}
+
+fn set(mode: Mode) {
+    info!("[mode] {:?}", mode);
+    MODE.lock(|m| m.set(mode));
+    CHANGED.signal(mode);
+}
+
+/// Watch the button (active low) and run the maintenance window
+pub async fn run<M: RawMutex, const N: usize>(
+    mut button: Input<'_>,
+    sender: Sender<'_, M, Message, N>,
+) -> ! {
+    let mut deadline = Instant::MAX;
+    loop {
+        match select(long_press(&mut button), Timer::at(deadline)).await {
+            Either::First(()) => {
+                // pressing again extends the window
+                deadline = Instant::now() + WINDOW;
+                if !is_maintenance() {
+                    set(Mode::Maintenance);
+                    sender.send(Message::SetIndicator(true)).await;
+                }
+            }
+            Either::Second(()) => {
+                deadline = Instant::MAX;
+                set(Mode::Normal);
+                sender.send(Message::SetIndicator(false)).await;
+            }
+        }
+    }
+}
+
+async fn long_press(button: &mut Input<'_>) {
+    loop {
+        button.wait_for_low().await;
+        // any press wakes us up
+        power::wake();
+        match select(button.wait_for_high(), Timer::after(LONG_PRESS)).await {
+            Either::First(()) => continue,
+            Either::Second(()) => {
+                button.wait_for_high().await;
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Self-registering optional modules
+//!
+//! A module that only needs starting, like a driver with its own task,
+//! registers itself with [`register_module!`] instead of being wired up in
+//! `main`. The entries go into the `.modules` linker section (see
+//! `sections.x`), and [`init_all`] runs every one of them once the shared
+//! resources exist. Modules don't depend on each other, so there's no
+//! order between them.
+//!
+//! A module can also add a GATT service of its own, with a [`Gatt`] in its
+//! entry. [`crate::blue::run`] builds it with the others every time the
+//! stack starts, and serves its characteristics by the [`writes::Spec`]
+//! each was added with (see [`crate::writes`]), so `blue.rs` has nothing
+//! of the module's to know. What changes on its own is sent with
+//! [`notify`] from the module's task, and the answer to a write with
+//! [`respond`], which is also how a write that has to wait on something
+//! gets answered once its task is done with it. They queue until the
+//! stack runs, the latest value of each, or all of them in order with
+//! [`notify_event`].
+
+use core::cell::RefCell;
+use core::ptr::addr_of;
+
+use embassy_executor::Spawner;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use trouble_host::prelude::*;
+
+use crate::blue::MAX_ATTRIBUTES;
+use crate::bus::Bus;
+use crate::error;
+use crate::info;
+use crate::params;
+use crate::writes;
+
+/// Room for the values of every module's service
+pub const VALUES_MAX: usize = 1024;
+
+/// Most values waiting to go out, see [`notify`]
+const NOTES_MAX: usize = 16;
+
+/// What modules get to start with
+pub struct Resources {
+    pub spawner: Spawner,
+    pub bus: &'static Bus,
+}
+
+pub struct Module {
+    pub name: &'static str,
+    /// Set up the module, spawning its tasks. Anything slow belongs in the
+    /// tasks.
+    pub init: fn(&Resources),
+    pub service: Option<Gatt>,
+}
+
+/// A GATT service a module adds
+pub struct Gatt {
+    pub uuid: Uuid,
+    /// Whether the board has it, by the services it's configured with
+    pub enabled: fn(&params::Services) -> bool,
+    /// Add the characteristics
+    pub build: fn(&mut Service<'_, '_>),
+}
+
+/// Register a module: `register_module!(NAME, "name", |resources| ...)`,
+/// with `service: Gatt { .. }` after the closure if it adds one. The
+/// closures can't capture anything.
+#[macro_export]
+macro_rules! register_module {
+    ($static:ident, $name:expr, $init:expr $(,)?) => {
+        #[used]
+        #[link_section = ".modules"]
+        static $static: $crate::modules::Module = $crate::modules::Module {
+            name: $name,
+            init: $init,
+            service: None,
+        };
+    };
+    ($static:ident, $name:expr, $init:expr, service: $service:expr $(,)?) => {
+        #[used]
+        #[link_section = ".modules"]
+        static $static: $crate::modules::Module = $crate::modules::Module {
+            name: $name,
+            init: $init,
+            service: Some($service),
+        };
+    };
+}
+
+/// A module's service being built
+pub struct Service<'r, 'd> {
+    builder: ServiceBuilder<'r, 'd, NoopRawMutex, MAX_ATTRIBUTES>,
+    /// What's left of the room for values
+    values: &'r mut &'d mut [u8],
+    writes: &'r mut writes::Registry,
+    services: &'r params::Services,
+}
+
+impl<'r, 'd> Service<'r, 'd> {
+    pub(crate) fn new(
+        builder: ServiceBuilder<'r, 'd, NoopRawMutex, MAX_ATTRIBUTES>,
+        values: &'r mut &'d mut [u8],
+        writes: &'r mut writes::Registry,
+        services: &'r params::Services,
+    ) -> Self {
+        Self {
+            builder,
+            values,
+            writes,
+            services,
+        }
+    }
+
+    /// The services the board is configured with, for a service that only
+    /// has some characteristics on some boards
+    pub fn services(&self) -> &params::Services {
+        self.services
+    }
+
+    /// Add a characteristic with room for `len` bytes, served by `spec`
+    /// and starting out as its reader has it. `None`, logged, once the
+    /// room for values or handlers is used up.
+    pub fn add(
+        &mut self,
+        uuid: Uuid,
+        props: &[CharacteristicProp],
+        len: usize,
+        spec: writes::Spec,
+    ) -> Option<Characteristic> {
+        self.add_with(uuid, props, len, spec, None)
+    }
+
+    /// Like [`Service::add`], with a read-only descriptor `(uuid, value)`
+    pub fn add_described(
+        &mut self,
+        uuid: Uuid,
+        props: &[CharacteristicProp],
+        len: usize,
+        spec: writes::Spec,
+        descriptor: (Uuid, &'static [u8]),
+    ) -> Option<Characteristic> {
+        self.add_with(uuid, props, len, spec, Some(descriptor))
+    }
+
+    /// Add a read-only characteristic with a value that never changes,
+    /// taking none of the room for values
+    pub fn add_static(&mut self, uuid: Uuid, value: &'static [u8]) {
+        let _ = self.builder.add_characteristic_ro(uuid, value);
+    }
+
+    fn add_with(
+        &mut self,
+        uuid: Uuid,
+        props: &[CharacteristicProp],
+        len: usize,
+        spec: writes::Spec,
+        descriptor: Option<(Uuid, &'static [u8])>,
+    ) -> Option<Characteristic> {
+        if self.values.len() < len || !self.writes.has_room() {
+            error!("[modules] no room for characteristic {:?}", uuid);
+            return None;
+        }
+        let (value, rest) = core::mem::take(self.values).split_at_mut(len);
+        *self.values = rest;
+        if let Some(read) = spec.read {
+            let initial = read();
+            let n = initial.as_bytes().len().min(len);
+            value[..n].copy_from_slice(&initial.as_bytes()[..n]);
+        }
+        let mut builder = self.builder.add_characteristic(uuid, props, value);
+        if let Some((uuid, value)) = descriptor {
+            builder.add_descriptor_ro(uuid, value);
+        }
+        let characteristic = builder.build();
+        self.writes.serve(uuid, characteristic, spec);
+        Some(characteristic)
+    }
+
+    pub(crate) fn build(self) {
+        self.builder.build();
+    }
+}
+
+/// A value a module has for the clients
+#[derive(Clone, Copy)]
+pub struct Note {
+    /// Of the characteristic it's for
+    pub uuid: Uuid,
+    pub value: writes::Value,
+    /// The connection it answers, `None` if it's for all of them
+    pub to: Option<ConnHandle>,
+    /// What it measures, for the report delta of the characteristic (see
+    /// [`crate::threshold`])
+    pub reading: Option<i32>,
+}
+
+static NOTES: Mutex<CriticalSectionRawMutex, RefCell<[Option<Note>; NOTES_MAX]>> =
+    Mutex::new(RefCell::new([None; NOTES_MAX]));
+
+static NOTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Serve `value` for the characteristic with `uuid`, and notify it to the
+/// connections that subscribed
+pub fn notify(uuid: Uuid, value: &[u8]) {
+    note(
+        Note {
+            uuid,
+            value: writes::Value::new(value),
+            to: None,
+            reading: None,
+        },
+        true,
+    );
+}
+
+/// Like [`notify`] for a characteristic whose every value matters (see
+/// [`writes::Spec::as_event`]): it goes out after the ones waiting for
+/// the same characteristic instead of in their place
+pub fn notify_event(uuid: Uuid, value: &[u8]) {
+    note(
+        Note {
+            uuid,
+            value: writes::Value::new(value),
+            to: None,
+            reading: None,
+        },
+        false,
+    );
+}
+
+/// Like [`notify`], only notifying once `reading` moved past the report
+/// delta of the characteristic, if it has one
+pub fn notify_reading(uuid: Uuid, value: &[u8], reading: i32) {
+    note(
+        Note {
+            uuid,
+            value: writes::Value::new(value),
+            to: None,
+            reading: Some(reading),
+        },
+        true,
+    );
+}
+
+/// Notify `value` to `conn` alone, if it subscribed, as the answer to its
+/// write to the characteristic with `uuid`. The value we serve stays as
+/// it is.
+pub fn respond(conn: ConnHandle, uuid: Uuid, value: &[u8]) {
+    note(
+        Note {
+            uuid,
+            value: writes::Value::new(value),
+            to: Some(conn),
+            reading: None,
+        },
+        true,
+    );
+}
+
+/// Queue `note`, with `coalesce` in place of the one for the same
+/// characteristic and connection if it hasn't gone out yet. The queue is
+/// kept in order, waiting notes first.
+fn note(note: Note, coalesce: bool) {
+    let queued = NOTES.lock(|notes| {
+        let mut notes = notes.borrow_mut();
+        let same = |n: &Option<Note>| {
+            n.as_ref()
+                .is_some_and(|n| n.uuid == note.uuid && n.to == note.to)
+        };
+        let slot = match notes.iter().position(same).filter(|_| coalesce) {
+            Some(idx) => Some(idx),
+            None => notes.iter().position(|n| n.is_none()),
+        };
+        slot.map(|idx| notes[idx] = Some(note)).is_some()
+    });
+    if queued {
+        NOTED.signal(());
+    } else {
+        error!("[modules] no room to notify {:?}", note.uuid);
+    }
+}
+
+/// The next value for the clients, in the order they were queued
+pub async fn next_note() -> Note {
+    loop {
+        let next = NOTES.lock(|notes| {
+            let mut notes = notes.borrow_mut();
+            let next = notes[0].take();
+            notes.rotate_left(1);
+            next
+        });
+        if let Some(note) = next {
+            return note;
+        }
+        NOTED.wait().await;
+    }
+}
+
+extern "C" {
+    // bounds of the section, from sections.x
+    static __smodules: u8;
+    static __emodules: u8;
+}
+
+/// Every registered module
+pub fn all() -> &'static [Module] {
+    // SAFETY: the section only holds `Module`s, placed there by
+    // `register_module!`, and is aligned for them
+    unsafe {
+        let start = addr_of!(__smodules) as *const Module;
+        let end = addr_of!(__emodules) as *const Module;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// The services of the registered modules that `services` has
+pub fn services(services: &params::Services) -> impl Iterator<Item = &'static Gatt> + '_ {
+    all()
+        .iter()
+        .filter_map(|module| module.service.as_ref())
+        .filter(|gatt| (gatt.enabled)(services))
+}
+
+/// Start every registered module. Call once.
+pub fn init_all(resources: &Resources) {
+    for module in all() {
+        info!("[modules] starting {}", module.name);
+        (module.init)(resources);
+    }
+}
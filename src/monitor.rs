@@ -0,0 +1,175 @@
+//! Task liveness monitor
+//!
+//! Every monitored task has a [`Task`] slot. A task pings it whenever it
+//! makes progress and pauses it before waiting on something that may
+//! legitimately take forever (the next job, the next GATT event). A task
+//! that goes longer than its timeout without a ping while not paused is
+//! stalled: it's stuck in the middle of its work.
+//!
+//! [`run`] logs tasks as they stall and recover. The diagnostics
+//! characteristic shows all of them, and [`Task::stalled`] lets a
+//! supervisor restart a task that got stuck. One that stays stuck is left
+//! to the hardware watchdog, see [`crate::watchdog`].
+
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+
+use crate::error;
+use crate::fault;
+use crate::info;
+
+/// How often stalls are checked for
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Size of an encoded task in the diagnostics characteristic
+const ENTRY_SIZE: usize = 3;
+
+/// Number of monitored tasks
+const TASKS_LEN: usize = 10;
+
+/// Size of the diagnostics characteristic
+pub const REPORT_SIZE: usize = TASKS_LEN * ENTRY_SIZE;
+
+const PAUSED: u8 = 0;
+const BUSY: u8 = 1;
+const STALLED: u8 = 2;
+
+pub struct Task {
+    pub name: &'static str,
+    pub timeout: Duration,
+    state: AtomicU8,
+    /// Milliseconds since boot of the last ping
+    last_ms: AtomicU32,
+}
+
+impl Task {
+    pub const fn new(name: &'static str, timeout: Duration) -> Self {
+        Self {
+            name,
+            timeout,
+            state: AtomicU8::new(PAUSED),
+            last_ms: AtomicU32::new(0),
+        }
+    }
+
+    /// Report progress
+    pub fn ping(&self) {
+        self.last_ms.store(now_ms(), Ordering::Relaxed);
+        if self.state.load(Ordering::Relaxed) == STALLED {
+            info!("[monitor] {} recovered", self.name);
+        }
+        self.state.store(BUSY, Ordering::Relaxed);
+    }
+
+    /// Stop watching until the next ping, before an unbounded wait
+    pub fn pause(&self) {
+        self.state.store(PAUSED, Ordering::Relaxed);
+    }
+
+    fn age_ms(&self) -> u32 {
+        now_ms().wrapping_sub(self.last_ms.load(Ordering::Relaxed))
+    }
+
+    /// Mark the task stalled if it's overdue, returning whether it is
+    fn check(&self) -> bool {
+        match self.state.load(Ordering::Relaxed) {
+            BUSY if self.age_ms() as u64 > self.timeout.as_millis() => {
+                error!("[monitor] {} stalled for {}ms", self.name, self.age_ms());
+                fault::raise(fault::TASK_STALLED);
+                self.state.store(STALLED, Ordering::Relaxed);
+                true
+            }
+            STALLED => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the task has been stalled for `grace` past its timeout
+    pub fn stuck(&self, grace: Duration) -> bool {
+        self.state.load(Ordering::Relaxed) == STALLED
+            && self.age_ms() as u64 > (self.timeout + grace).as_millis()
+    }
+
+    /// Wait until the task stalls
+    pub async fn stalled(&self) {
+        while !self.check() {
+            Timer::after(CHECK_PERIOD).await;
+        }
+    }
+}
+
+fn now_ms() -> u32 {
+    Instant::now().as_millis() as u32
+}
+
+pub static ADC: Task = Task::new("adc", Duration::from_secs(5));
+pub static ALARM: Task = Task::new("alarm", Duration::from_secs(2));
+pub static FLASH: Task = Task::new("flash", Duration::from_secs(5));
+pub static GATT: Task = Task::new("gatt", Duration::from_secs(5));
+pub static CONNINFO: Task = Task::new("conninfo", Duration::from_secs(15));
+pub static RELAY: Task = Task::new("relay", Duration::from_secs(5));
+pub static METER: Task = Task::new("meter", Duration::from_secs(5));
+pub static ENVIRONMENT: Task = Task::new("env", Duration::from_secs(5));
+pub static DISTANCE: Task = Task::new("distance", Duration::from_secs(5));
+pub static LEVEL: Task = Task::new("level", Duration::from_secs(5));
+
+/// Every monitored task, in diagnostics characteristic order
+pub static TASKS: [&Task; TASKS_LEN] = [
+    &ADC,
+    &ALARM,
+    &FLASH,
+    &GATT,
+    &CONNINFO,
+    &RELAY,
+    &METER,
+    &ENVIRONMENT,
+    &DISTANCE,
+    &LEVEL,
+];
+
+/// The diagnostics characteristic: per task in [`TASKS`] order its state
+/// (0 paused, 1 busy, 2 stalled) and the time since its last ping in
+/// 100ms units as a little endian u16, saturating
+pub fn report() -> [u8; REPORT_SIZE] {
+    let mut out = [0; REPORT_SIZE];
+    for (task, entry) in TASKS.iter().zip(out.chunks_mut(ENTRY_SIZE)) {
+        let age = (task.age_ms() / 100).min(u16::MAX as u32) as u16;
+        entry[0] = task.state.load(Ordering::Relaxed);
+        entry[1..3].copy_from_slice(&age.to_le_bytes());
+    }
+    out
+}
+
+/// Log tasks as they stall
+pub async fn run() -> ! {
+    loop {
+        Timer::after(CHECK_PERIOD).await;
+        for task in TASKS {
+            task.check();
+        }
+    }
+}
+
+crate::register_command!(
+    TASKS_COMMAND,
+    "tasks",
+    "",
+    "show the monitored tasks",
+    |args| {
+        args.end()?;
+        for task in TASKS {
+            let state = match task.state.load(Ordering::Relaxed) {
+                PAUSED => "paused",
+                BUSY => "busy",
+                _ => "stalled",
+            };
+            info!("{} {}, last ping {}ms ago", task.name, state, task.age_ms());
+        }
+        Ok(())
+    }
+);
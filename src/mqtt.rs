@@ -0,0 +1,158 @@
+//! Minimal MQTT 3.1.1 publisher
+//!
+//! Just enough of the protocol to push QoS 0 messages to a broker: connect
+//! with a clean session, publish, and ping to keep the session alive. No
+//! subscriptions, no QoS 1/2, no authentication. It runs over any stream,
+//! a TCP socket or a TLS session on one (see [`crate::tls`]).
+
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_time::Duration;
+use embassy_time::Timer;
+use embedded_io_async::Read;
+use embedded_io_async::Write;
+
+/// Longest topic we send
+pub const TOPIC_MAX: usize = 64;
+
+/// How long the broker gets to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+/// Only a clean session, no will, no credentials
+const CLEAN_SESSION: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Reading or writing the stream failed
+    Io(embedded_io::ErrorKind),
+    /// The broker refused the connection with this return code
+    Refused(u8),
+    /// The broker sent something we didn't expect
+    Protocol,
+    /// The broker didn't answer in time
+    Timeout,
+    /// A topic or payload too big for a packet
+    TooLarge,
+}
+
+fn io(e: impl embedded_io::Error) -> Error {
+    Error::Io(e.kind())
+}
+
+/// Put the remaining length of a packet, returning how many bytes it took
+fn put_length(out: &mut [u8; 4], mut len: usize) -> usize {
+    let mut n = 0;
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out[n] = byte;
+        n += 1;
+        if len == 0 || n == out.len() {
+            return n;
+        }
+    }
+}
+
+async fn write_header(socket: &mut (impl Read + Write), ty: u8, len: usize) -> Result<(), Error> {
+    // 4 bytes of remaining length go up to 256MB
+    if len >= 1 << 28 {
+        return Err(Error::TooLarge);
+    }
+    let mut length = [0; 4];
+    let n = put_length(&mut length, len);
+    socket.write_all(&[ty]).await.map_err(io)?;
+    socket.write_all(&length[..n]).await.map_err(io)?;
+    Ok(())
+}
+
+async fn write_str(socket: &mut (impl Read + Write), s: &[u8]) -> Result<(), Error> {
+    let len = u16::try_from(s.len()).map_err(|_| Error::TooLarge)?;
+    socket.write_all(&len.to_be_bytes()).await.map_err(io)?;
+    socket.write_all(s).await.map_err(io)?;
+    Ok(())
+}
+
+/// Read a packet of type `ty` with `len` bytes of payload, at most two,
+/// and return the payload
+async fn expect(socket: &mut (impl Read + Write), ty: u8, len: usize) -> Result<[u8; 2], Error> {
+    let mut packet = [0; 4];
+    let wanted = 2 + len;
+    let mut got = 0;
+    let read = async {
+        while got < wanted {
+            let n = socket.read(&mut packet[got..wanted]).await.map_err(io)?;
+            if n == 0 {
+                return Err(Error::Protocol);
+            }
+            got += n;
+        }
+        Ok::<_, Error>(())
+    };
+    match select(read, Timer::after(TIMEOUT)).await {
+        Either::First(result) => result?,
+        Either::Second(()) => return Err(Error::Timeout),
+    }
+    if packet[0] != ty || packet[1] as usize != len {
+        return Err(Error::Protocol);
+    }
+    Ok([packet[2], packet[3]])
+}
+
+/// Open a session on a connected socket
+pub async fn connect(
+    socket: &mut (impl Read + Write),
+    client_id: &str,
+    keep_alive: Duration,
+) -> Result<(), Error> {
+    let keep_alive = keep_alive.as_secs().min(u16::MAX as u64) as u16;
+    // protocol name, level, flags, keep alive; then the client id
+    let len = 6 + 1 + 1 + 2 + 2 + client_id.len();
+    write_header(socket, CONNECT, len).await?;
+    write_str(socket, b"MQTT").await?;
+    socket.write_all(&[4, CLEAN_SESSION]).await.map_err(io)?;
+    socket
+        .write_all(&keep_alive.to_be_bytes())
+        .await
+        .map_err(io)?;
+    write_str(socket, client_id.as_bytes()).await?;
+    socket.flush().await.map_err(io)?;
+
+    match expect(socket, CONNACK, 2).await? {
+        [_, 0] => Ok(()),
+        [_, code] => Err(Error::Refused(code)),
+    }
+}
+
+/// Publish `payload` to `topic` at QoS 0
+pub async fn publish(
+    socket: &mut (impl Read + Write),
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), Error> {
+    if topic.len() > TOPIC_MAX {
+        return Err(Error::TooLarge);
+    }
+    write_header(socket, PUBLISH, 2 + topic.len() + payload.len()).await?;
+    write_str(socket, topic.as_bytes()).await?;
+    socket.write_all(payload).await.map_err(io)?;
+    socket.flush().await.map_err(io)?;
+    Ok(())
+}
+
+/// Ping the broker and wait for its answer
+pub async fn ping(socket: &mut (impl Read + Write)) -> Result<(), Error> {
+    write_header(socket, PINGREQ, 0).await?;
+    socket.flush().await.map_err(io)?;
+    expect(socket, PINGRESP, 0).await?;
+    Ok(())
+}
@@ -0,0 +1,168 @@
+//! Wi-Fi networking
+//!
+//! Wi-Fi is enabled by setting `wifi.ssid` (and `wifi.password` for
+//! protected networks). Building with `WIFI_SSID` and `WIFI_PASSWORD` set
+//! in the environment makes them the defaults. Without an SSID the network
+//! side waits for one and only BLE is available. New credentials are
+//! picked up on the next join attempt, a network we've joined is kept
+//! until the radio restarts. The network is left while the battery is low
+//! (see [`crate::power`]), and joined again once it recovers. Credentials
+//! provisioned over BLE (see [`crate::provision`]) are joined right away,
+//! leaving the network we're on.
+
+use embassy_futures::join::join;
+use embassy_futures::join::join4;
+use embassy_futures::select::select;
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3;
+use embassy_net::Stack;
+use embassy_net::StackResources;
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::Sender;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Timer;
+use rand_core::RngCore;
+
+use crate::config;
+use crate::config::Text;
+use crate::discovery;
+use crate::error;
+use crate::fault;
+use crate::gateway;
+use crate::http;
+use crate::info;
+use crate::lighting::Message;
+#[cfg(all(feature = "dfu", feature = "tls"))]
+use crate::ota;
+use crate::power;
+use crate::provision;
+use crate::sntp;
+
+crate::config_key!(
+    /// Network to join, empty for none
+    pub SSID: Text = "wifi.ssid",
+    Text::new(match option_env!("WIFI_SSID") {
+        Some(ssid) => ssid,
+        None => "",
+    }),
+);
+
+crate::config_key!(
+    /// Empty for open networks
+    pub PASSWORD: Text = "wifi.password",
+    Text::new(match option_env!("WIFI_PASSWORD") {
+        Some(password) => password,
+        None => "",
+    }),
+);
+
+/// Max number of sockets
+const SOCKETS_MAX: usize = 6;
+
+/// The radio's control, shared with [`crate::gpio::onboard`]. Joining holds
+/// it for as long as the attempt takes.
+pub type Control<'a> = Mutex<NoopRawMutex, cyw43::Control<'a>>;
+
+/// Join the network and run the network services. Never returns.
+pub async fn run<M: RawMutex, const N: usize>(
+    control: &Control<'_>,
+    device: cyw43::NetDriver<'_>,
+    sender: Sender<'_, M, Message, N>,
+) {
+    let mut changes = config::subscribe();
+    while SSID.get().is_empty() {
+        info!("[net] wifi.ssid not set, wifi disabled");
+        provision::set_state(provision::State::Unconfigured, None);
+        let Some(changes) = changes.as_mut() else {
+            core::future::pending::<()>().await;
+            return;
+        };
+        while !SSID.is(changes.next_message_pure().await) {}
+    }
+    drop(changes);
+
+    // we're not using static_cell here for the same reason as in blue.rs,
+    // this gets set up again every time the radio is restarted
+    let mut resources = StackResources::<SOCKETS_MAX>::new();
+    let stack = Stack::new(
+        device,
+        embassy_net::Config::dhcpv4(Default::default()),
+        &mut resources,
+        RoscRng.next_u64(),
+    );
+
+    join(stack.run(), async {
+        let mut levels = power::subscribe();
+        loop {
+            power::until(power::Feature::Wifi, true, levels.as_mut()).await;
+            join_network(control, &stack).await;
+            let services = join4(
+                http::serve(&stack, sender),
+                discovery::run(&stack),
+                sntp::run(&stack),
+                gateway::run(&stack),
+            );
+            #[cfg(all(feature = "dfu", feature = "tls"))]
+            let services = join(services, ota::run(&stack));
+            let left = select3(
+                services,
+                power::until(power::Feature::Wifi, false, levels.as_mut()),
+                provision::requested(),
+            )
+            .await;
+            if let Either3::Third(()) = left {
+                info!("[net] leaving the network to join again");
+            } else {
+                info!("[net] leaving the network to save power");
+                provision::set_state(provision::State::Off, None);
+            }
+            control.lock().await.leave().await;
+        }
+    })
+    .await;
+}
+
+/// Join the network, retrying until it works, and wait for an address.
+/// Without credentials, wait for some to be provisioned.
+async fn join_network(control: &Control<'_>, stack: &Stack<cyw43::NetDriver<'_>>) {
+    loop {
+        provision::clear_request();
+        let ssid = SSID.get();
+        if ssid.is_empty() {
+            info!("[net] wifi.ssid not set, waiting for credentials");
+            provision::set_state(provision::State::Unconfigured, None);
+            provision::requested().await;
+            continue;
+        }
+        let ssid = ssid.as_str();
+        let password = PASSWORD.get();
+        info!("[net] joining {}", ssid);
+        provision::set_state(provision::State::Joining, None);
+        let joined = {
+            let mut control = control.lock().await;
+            match password.as_str() {
+                "" => control.join_open(ssid).await,
+                password => control.join_wpa2(ssid, password).await,
+            }
+        };
+        match joined {
+            Ok(()) => break,
+            Err(e) => {
+                error!("[net] failed to join {}: {:?}", ssid, e);
+                fault::raise(fault::NET_JOIN);
+                provision::set_state(provision::State::Failed, None);
+                // new credentials don't wait for the retry
+                select(Timer::after_secs(5), provision::requested()).await;
+            }
+        }
+    }
+
+    stack.wait_config_up().await;
+    let address = stack.config_v4().map(|config| {
+        info!("[net] up at {}", config.address);
+        config.address.address().0
+    });
+    provision::set_state(provision::State::Up, address);
+}
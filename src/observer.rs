@@ -0,0 +1,122 @@
+//! Observer role
+//!
+//! A scan-only alternative to [`crate::blue::run`]: no advertising, no GATT
+//! server, just passive scanning. Every advertising report is published for
+//! subscribers to decode with [`crate::adparse`].
+
+use core::convert::Infallible;
+use core::future::pending;
+
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::pubsub::Subscriber;
+use embassy_time::Duration;
+use trouble_host::prelude::*;
+
+use crate::adparse;
+use crate::error;
+use crate::info;
+
+/// Max number of queued reports per subscriber
+const REPORTS_CAP: usize = 8;
+
+/// Max number of concurrent subscribers
+const SUBSCRIBERS_MAX: usize = 3;
+
+/// Size of legacy advertising data
+const ADV_DATA_MAX: usize = 31;
+
+/// An advertising report, copied out of the HCI event
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Report {
+    pub kind: AddrKind,
+    pub addr: BdAddr,
+    pub rssi: i8,
+    len: u8,
+    data: [u8; ADV_DATA_MAX],
+}
+
+impl Report {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    pub fn structures(&self) -> adparse::Parser<'_> {
+        adparse::parse(self.data())
+    }
+}
+
+pub type ReportSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, Report, REPORTS_CAP, SUBSCRIBERS_MAX, 0>;
+
+static REPORTS: PubSubChannel<CriticalSectionRawMutex, Report, REPORTS_CAP, SUBSCRIBERS_MAX, 0> =
+    PubSubChannel::new();
+
+/// Subscribe to reports, `None` if all subscriber slots are in use
+pub fn subscribe() -> Option<ReportSubscriber> {
+    REPORTS.subscriber().ok()
+}
+
+/// Publishes the reports of a runner, also used by [`crate::blue::run`]
+/// for [`crate::central`]
+pub(crate) struct Handler;
+
+impl EventHandler for Handler {
+    fn on_adv_reports(&self, mut reports: LeAdvReportsIter<'_>) {
+        while let Some(Ok(report)) = reports.next() {
+            // extended reports don't fit, and we only scan legacy anyway
+            let Some(data) = report.data.get(..report.data.len().min(ADV_DATA_MAX)) else {
+                continue;
+            };
+            let mut buf = [0; ADV_DATA_MAX];
+            buf[..data.len()].copy_from_slice(data);
+
+            REPORTS.immediate_publisher().publish_immediate(Report {
+                kind: report.addr_kind,
+                addr: report.addr,
+                rssi: report.rssi,
+                len: data.len() as u8,
+                data: buf,
+            });
+        }
+    }
+}
+
+type Resources<C> = HostResources<C, 0, 1, 27>;
+
+/// Scan passively until the host fails
+pub async fn run<C: Controller>(controller: C) -> BleHostError<C::Error> {
+    let mut resources = Resources::new(PacketQos::None);
+    let (_, _, central, mut runner) = trouble_host::new(controller, &mut resources).build();
+    let mut scanner = Scanner::new(central);
+
+    let config = ScanConfig {
+        active: false,
+        interval: Duration::from_millis(100),
+        window: Duration::from_millis(100),
+        ..Default::default()
+    };
+
+    let scan = async {
+        let _session = scanner.scan(&config).await?;
+        info!("[observer] scanning");
+        pending::<Result<Infallible, BleHostError<C::Error>>>().await
+    };
+    let host = async {
+        loop {
+            if let Err(e) = runner.run_with_handler(&Handler).await {
+                return e;
+            }
+        }
+    };
+
+    let e = match select(host, scan).await {
+        Either::First(e) | Either::Second(Err(e)) => e,
+        Either::Second(Ok(never)) => match never {},
+    };
+    error!("[observer] stopped: {:?}", e);
+    e
+}
@@ -0,0 +1,258 @@
+//! Firmware downloads over Wi-Fi
+//!
+//! A firmware image takes minutes over BLE. With the device on Wi-Fi, a
+//! client can instead send the DFU control point a FETCH with the image's
+//! URL, size and CRC-32 (see [`crate::dfu`]), and the network side
+//! downloads it in the background. The image goes to the DFU partition
+//! through the same path as chunks over BLE, and is read back and checked
+//! once it's in. It then waits in the verified state: nothing is swapped
+//! in until a BLE client sends FINISH and reboots the device, the install.
+//! The DFU status characteristic shows the download's progress, a failed
+//! one raises [`crate::fault::NET_OTA`] with the reason in the log.
+//!
+//! Only `https://` URLs are taken, fetched over TLS with the provisioned
+//! key (see [`crate::tls`]), so the downloads need the `tls` feature. The
+//! CRC only guards against a corrupted download, not a server handing out
+//! another image. A download cut short, by a dropped connection or leaving
+//! the network, leaves what it wrote. The same FETCH again goes on from the
+//! sectors already in flash.
+
+use core::fmt::Write as _;
+
+use embassy_net::dns::DnsQueryType;
+use embassy_net::driver::Driver;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::IpEndpoint;
+use embassy_net::Stack;
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embedded_io_async::Read;
+use embedded_io_async::Write;
+
+use crate::dfu;
+use crate::error;
+use crate::fault;
+use crate::fmtbuf;
+use crate::info;
+use crate::tls;
+
+/// Longest URL a FETCH takes
+pub const URL_MAX: usize = 96;
+
+/// Longest response head we take
+const HEAD_MAX: usize = 512;
+
+/// How long the server gets to send more
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A URL, up to [`URL_MAX`] bytes of UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Url {
+    bytes: [u8; URL_MAX],
+    len: u8,
+}
+
+impl Url {
+    /// `None` unless `bytes` is a valid URL
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        core::str::from_utf8(bytes).ok()?;
+        let mut url = Self {
+            bytes: [0; URL_MAX],
+            len: bytes.len() as u8,
+        };
+        url.bytes.get_mut(..bytes.len())?.copy_from_slice(bytes);
+        split(url.as_str())?;
+        Some(url)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // checked when made
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Job {
+    url: Url,
+    size: u32,
+    crc: u32,
+}
+
+static JOB: Signal<CriticalSectionRawMutex, Job> = Signal::new();
+
+/// Have the image of `size` bytes at `url` downloaded, replacing a
+/// download that hasn't started yet
+pub fn fetch(url: Url, size: u32, crc: u32) {
+    JOB.signal(Job { url, size, crc });
+}
+
+/// The host, port and path of an `https://` URL
+fn split(url: &str) -> Option<(&str, u16, &str)> {
+    let rest = url.strip_prefix("https://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(at) => rest.split_at(at),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, 443),
+    };
+    (!host.is_empty()).then_some((host, port, path))
+}
+
+/// Check the response head is a 200 with the body we expect
+fn check_head(head: &str, size: u32) -> Result<(), &'static str> {
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .ok_or("malformed response")?;
+    if status != "200" {
+        error!("[ota] the server answered {}", status);
+        return Err("download refused");
+    }
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        if name.trim().eq_ignore_ascii_case("content-length")
+            && value.trim().parse::<u32>().ok() != Some(size)
+        {
+            error!("[ota] the server has {} bytes", value.trim());
+            return Err("not the size given");
+        }
+    }
+    Ok(())
+}
+
+/// Hand `data`, from `offset` of the image, to the update. The chunks
+/// don't straddle sectors, so a download that starts over is taken where
+/// the last one stopped.
+async fn write(mut offset: u32, mut data: &[u8]) -> Result<u32, &'static str> {
+    while !data.is_empty() {
+        let room = ERASE_SIZE - offset as usize % ERASE_SIZE;
+        let n = data.len().min(dfu::CHUNK_MAX).min(room);
+        let chunk = dfu::Chunk::new(offset, &data[..n]).ok_or("empty chunk")?;
+        dfu::receive(chunk).await.map_err(|status| {
+            error!("[ota] writing at {} failed: {:?}", offset, status);
+            "writing the image failed"
+        })?;
+        offset += n as u32;
+        data = &data[n..];
+    }
+    Ok(offset)
+}
+
+async fn download<D: Driver>(stack: &Stack<D>, job: &Job) -> Result<(), &'static str> {
+    // checked when the URL was made
+    let (host, port, path) = split(job.url.as_str()).ok_or("not a URL we take")?;
+    let addrs = stack
+        .dns_query(host, DnsQueryType::A)
+        .await
+        .map_err(|_| "dns lookup failed")?;
+    let endpoint = IpEndpoint::new(*addrs.first().ok_or("no address")?, port);
+
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 256];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(TIMEOUT));
+    socket
+        .connect(endpoint)
+        .await
+        .map_err(|_| "connect failed")?;
+
+    let mut records = tls::take().await;
+    let mut session = tls::open(&mut socket, host, &mut records).await?;
+    transfer(&mut session, host, path, job).await?;
+    let _ = session.close().await;
+    socket.close();
+    let _ = socket.flush().await;
+    drop(records);
+    check().await
+}
+
+/// Ask for the image over `socket` and write what comes
+async fn transfer(
+    socket: &mut (impl Read + Write),
+    host: &str,
+    path: &str,
+    job: &Job,
+) -> Result<(), &'static str> {
+    let mut request = fmtbuf::Buf::<{ URL_MAX + 64 }>::new();
+    let _ = write!(
+        request,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    socket
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|_| "sending the request failed")?;
+    socket
+        .flush()
+        .await
+        .map_err(|_| "sending the request failed")?;
+
+    // the head, and what came of the body with it
+    let mut buf = [0; HEAD_MAX];
+    let mut len = 0;
+    let body = loop {
+        if let Some(end) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if len == buf.len() {
+            return Err("response head too long");
+        }
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return Err("connection closed"),
+            Ok(n) => len += n,
+        }
+    };
+    let head = core::str::from_utf8(&buf[..body]).map_err(|_| "malformed response")?;
+    check_head(head, job.size)?;
+
+    let started = dfu::start(dfu::Target::Application, job.size, job.crc).await;
+    if started != dfu::Status::Ok {
+        error!("[ota] starting the update failed: {:?}", started);
+        return Err("the update can't start");
+    }
+    let mut offset = write(0, &buf[body..len]).await?;
+    while offset < job.size {
+        let n = match socket.read(&mut buf).await {
+            Ok(0) | Err(_) => return Err("connection closed early"),
+            Ok(n) => n,
+        };
+        offset = write(offset, &buf[..n]).await?;
+    }
+    Ok(())
+}
+
+/// Read back the image now in
+async fn check() -> Result<(), &'static str> {
+    match dfu::verify().await {
+        dfu::Status::Ok => Ok(()),
+        status => {
+            error!("[ota] checking the image failed: {:?}", status);
+            Err("the image isn't what was asked for")
+        }
+    }
+}
+
+/// Carry out the downloads asked for, forever
+pub async fn run<D: Driver>(stack: &Stack<D>) -> ! {
+    loop {
+        let job = JOB.wait().await;
+        info!(
+            "[ota] fetching {} bytes from {}",
+            job.size,
+            job.url.as_str()
+        );
+        match download(stack, &job).await {
+            Ok(()) => info!("[ota] image checked, FINISH and reboot to install it"),
+            Err(e) => {
+                error!("[ota] {}", e);
+                fault::raise(fault::NET_OTA);
+            }
+        }
+    }
+}
@@ -0,0 +1,769 @@
+//! Object Transfer Service
+//!
+//! The [`crate::assets`] files as OTS objects (service 0x1825), so stock
+//! OTS clients can list, read, replace and delete them and upload new
+//! ones, instead of going through the DFU control point. Each file is an
+//! object, its ID `0x100` plus its slot in the directory. A client picks
+//! the current object with the object list control point (OLCP), reads its
+//! name, size, ID and properties, and acts on it with the object action
+//! control point (OACP). The contents go over an L2CAP credit-based
+//! channel the client opens on [`PSM`], one per connection: after an OACP
+//! read the object is sent down it, after an OACP write the client sends
+//! the new contents up it.
+//!
+//! What's supported, as the feature characteristic says:
+//! - OACP create, delete, calculate checksum, read, write with truncation,
+//!   and abort of a read
+//! - OLCP first, last, previous, next, go to and request number of objects
+//!
+//! Files are written whole, like every assets write: a write starts at
+//! offset 0 and, unless it truncates, covers the whole object. The file is
+//! only replaced once all of it is in, so an interrupted write leaves the
+//! old one. A created object isn't a file until it's written: create makes
+//! a draft the current object, which the client names through the name
+//! characteristic and then writes. Object types aren't kept, every object
+//! reads as unspecified (0x2aca). There's no directory listing object and
+//! no object changed characteristic.
+//!
+//! Only one connection writes at a time, the others get object locked.
+//! Writes take the assets filesystem's one file being written, so a file
+//! upload over DFU started meanwhile drops the write, and the other way
+//! round. Creating, writing and deleting are for peers that may change the
+//! configuration, in maintenance mode (see [`crate::blue`]).
+//!
+//! The metadata characteristics are shared by every connection, and show
+//! the current object of the connection that last chose one or read them.
+//!
+//! OTS says the control points indicate their responses. The BLE host
+//! can't send indications, so OACP and OLCP notify them instead. Clients
+//! that only subscribe to indications won't see the responses.
+
+use core::cell::Cell;
+
+use bt_hci::param::ConnHandle;
+use embassy_futures::select::select;
+use embassy_futures::select::select3;
+use embassy_futures::select::Either;
+use embassy_futures::select::Either3;
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::mutex;
+use embassy_time::Duration;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::assets;
+use crate::blue::L2CAP_MTU;
+use crate::error;
+use crate::events;
+use crate::events::Event;
+use crate::events::EventSubscriber;
+use crate::fmt;
+use crate::info;
+use crate::integrity;
+use crate::integrity::Crc32;
+use crate::latency;
+use crate::session::PerConnection;
+
+pub const SERVICE_UUID: u16 = 0x1825;
+pub const FEATURE_UUID: u16 = 0x2abd;
+pub const NAME_UUID: u16 = 0x2abe;
+pub const TYPE_UUID: u16 = 0x2abf;
+pub const SIZE_UUID: u16 = 0x2ac0;
+pub const ID_UUID: u16 = 0x2ac3;
+pub const PROPERTIES_UUID: u16 = 0x2ac4;
+pub const OACP_UUID: u16 = 0x2ac5;
+pub const OLCP_UUID: u16 = 0x2ac6;
+
+/// LE PSM of the object channel
+pub const PSM: u16 = 0x0025;
+
+/// Largest SDU on the object channel, what fits an L2CAP packet with the
+/// SDU length
+pub const SDU_MAX: usize = L2CAP_MTU - 6;
+
+/// Size of the size characteristic, current and allocated
+pub const SIZE_SIZE: usize = 8;
+
+/// Size of an object ID, a u48
+pub const ID_SIZE: usize = 6;
+
+pub const PROPERTIES_SIZE: usize = 4;
+
+/// Longest OACP request, a create with a 128 bit type
+pub const OACP_MAX: usize = 1 + 4 + 16;
+
+/// Longest OLCP request, a go to
+pub const OLCP_MAX: usize = 1 + ID_SIZE;
+
+/// Longest response on either control point
+pub const RESPONSE_MAX: usize = 3 + 4;
+
+/// How long a write waits for more of the object before it's dropped
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The feature characteristic
+pub const FEATURES: [u8; 8] = {
+    let oacp = OACP_CREATE
+        | OACP_DELETE
+        | OACP_CHECKSUM
+        | OACP_READ
+        | OACP_WRITE
+        | OACP_TRUNCATE
+        | OACP_ABORT;
+    let olcp = OLCP_GO_TO | OLCP_COUNT;
+    let (oacp, olcp) = (oacp.to_le_bytes(), olcp.to_le_bytes());
+    [
+        oacp[0], oacp[1], oacp[2], oacp[3], olcp[0], olcp[1], olcp[2], olcp[3],
+    ]
+};
+
+/// The type characteristic, unspecified for every object
+pub const TYPE: [u8; 2] = 0x2acau16.to_le_bytes();
+
+// OACP features
+const OACP_CREATE: u32 = 1 << 0;
+const OACP_DELETE: u32 = 1 << 1;
+const OACP_CHECKSUM: u32 = 1 << 2;
+const OACP_READ: u32 = 1 << 4;
+const OACP_WRITE: u32 = 1 << 5;
+const OACP_TRUNCATE: u32 = 1 << 7;
+const OACP_ABORT: u32 = 1 << 9;
+
+// OLCP features
+const OLCP_GO_TO: u32 = 1 << 0;
+const OLCP_COUNT: u32 = 1 << 2;
+
+// object properties
+const PROPERTY_DELETE: u32 = 1 << 0;
+const PROPERTY_READ: u32 = 1 << 2;
+const PROPERTY_WRITE: u32 = 1 << 3;
+const PROPERTY_TRUNCATE: u32 = 1 << 5;
+
+// OACP op codes
+const CREATE: u8 = 0x01;
+const DELETE: u8 = 0x02;
+const CHECKSUM: u8 = 0x03;
+const READ: u8 = 0x05;
+const WRITE: u8 = 0x06;
+const ABORT: u8 = 0x07;
+const ACTION_RESPONSE: u8 = 0x60;
+
+/// Write mode asking to truncate the object after what's written
+const MODE_TRUNCATE: u8 = 0x02;
+
+// OLCP op codes
+const FIRST: u8 = 0x01;
+const LAST: u8 = 0x02;
+const PREVIOUS: u8 = 0x03;
+const NEXT: u8 = 0x04;
+const GO_TO: u8 = 0x05;
+const COUNT: u8 = 0x07;
+const LIST_RESPONSE: u8 = 0x70;
+
+/// ID of the object in the first slot
+const FIRST_ID: u64 = 0x100;
+
+/// ID of a created object until it's written
+const DRAFT_ID: u64 = FIRST_ID + assets::FILES_MAX as u64;
+
+/// Name of a created object until the client names it
+const DRAFT_NAME: &str = "unnamed";
+
+/// Result of an OACP request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum ActionResult {
+    Success = 0x01,
+    NotSupported = 0x02,
+    InvalidParameter = 0x03,
+    InsufficientResources = 0x04,
+    InvalidObject = 0x05,
+    ChannelUnavailable = 0x06,
+    UnsupportedType = 0x07,
+    NotPermitted = 0x08,
+    ObjectLocked = 0x09,
+    Failed = 0x0a,
+}
+
+/// Result of an OLCP request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum ListResult {
+    Success = 0x01,
+    NotSupported = 0x02,
+    InvalidParameter = 0x03,
+    Failed = 0x04,
+    OutOfBounds = 0x05,
+    TooManyObjects = 0x06,
+    NoObject = 0x07,
+    IdNotFound = 0x08,
+}
+
+/// The object a connection chose
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Current {
+    None,
+    /// A file, by its slot in the directory
+    Stored(usize),
+    /// A created object, not written yet
+    Draft {
+        name: assets::Name,
+        allocated: u32,
+    },
+}
+
+/// What a connection's object channel is to do
+#[derive(Debug, Clone, Copy)]
+enum Transfer {
+    Idle,
+    /// Send these bytes
+    Read(&'static [u8]),
+    /// Receive the `len` bytes of the file [`assets::create`] set up
+    Write {
+        name: assets::Name,
+        len: u32,
+    },
+}
+
+/// How a transfer failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failed {
+    /// The channel is gone
+    Channel,
+    /// The transfer is, the channel can take the next one
+    Transfer,
+}
+
+static CURRENT: PerConnection<Current> = PerConnection::new(Current::None);
+
+static TRANSFER: PerConnection<Transfer> = PerConnection::new(Transfer::Idle);
+
+/// Connections with the object channel open
+static CHANNEL: PerConnection<bool> = PerConnection::new(false);
+
+/// The connection writing an object
+static WRITER: Mutex<CriticalSectionRawMutex, Cell<Option<ConnHandle>>> =
+    Mutex::new(Cell::new(None));
+
+/// The sector being received, only ever taken by the writer
+static SECTOR: mutex::Mutex<CriticalSectionRawMutex, [u8; ERASE_SIZE]> =
+    mutex::Mutex::new([0; ERASE_SIZE]);
+
+fn file(slot: usize) -> Option<assets::File> {
+    assets::files().get(slot).copied().flatten()
+}
+
+/// The current object of `conn`, if it's still there
+fn current(conn: ConnHandle) -> Current {
+    match CURRENT.get(conn) {
+        Current::Stored(slot) if file(slot).is_none() => Current::None,
+        current => current,
+    }
+}
+
+/// The name characteristic for `conn`, and its length
+pub fn name(conn: ConnHandle) -> ([u8; assets::NAME_MAX], usize) {
+    let mut out = [0; assets::NAME_MAX];
+    let name = match current(conn) {
+        Current::None => return (out, 0),
+        Current::Stored(slot) => file(slot).map(|f| f.name),
+        Current::Draft { name, .. } => Some(name),
+    };
+    let bytes = name.as_ref().map_or(&[][..], |n| n.as_str().as_bytes());
+    out[..bytes.len()].copy_from_slice(bytes);
+    (out, bytes.len())
+}
+
+/// The size characteristic for `conn`: current then allocated size
+pub fn size(conn: ConnHandle) -> [u8; SIZE_SIZE] {
+    let (len, allocated) = match current(conn) {
+        Current::None => (0, 0),
+        Current::Stored(slot) => {
+            let len = file(slot).map_or(0, |f| f.data.len() as u32);
+            (len, len.div_ceil(ERASE_SIZE as u32) * ERASE_SIZE as u32)
+        }
+        Current::Draft { allocated, .. } => (0, allocated),
+    };
+    let mut out = [0; SIZE_SIZE];
+    out[..4].copy_from_slice(&len.to_le_bytes());
+    out[4..].copy_from_slice(&allocated.to_le_bytes());
+    out
+}
+
+/// The ID characteristic for `conn`
+pub fn id(conn: ConnHandle) -> [u8; ID_SIZE] {
+    let id = match current(conn) {
+        Current::None => 0,
+        Current::Stored(slot) => FIRST_ID + slot as u64,
+        Current::Draft { .. } => DRAFT_ID,
+    };
+    id.to_le_bytes()[..ID_SIZE].try_into().unwrap()
+}
+
+/// The properties characteristic for `conn`
+pub fn properties(conn: ConnHandle) -> [u8; PROPERTIES_SIZE] {
+    let properties = match current(conn) {
+        Current::None => 0,
+        Current::Stored(_) => PROPERTY_DELETE | PROPERTY_READ | PROPERTY_WRITE | PROPERTY_TRUNCATE,
+        Current::Draft { .. } => PROPERTY_DELETE | PROPERTY_WRITE | PROPERTY_TRUNCATE,
+    };
+    properties.to_le_bytes()
+}
+
+/// Name the draft of `conn` from a write to the name characteristic. Only
+/// drafts can be named, files keep theirs.
+pub fn rename(conn: ConnHandle, value: &[u8]) -> bool {
+    let Some(new) = assets::Name::from_bytes(value) else {
+        return false;
+    };
+    match current(conn) {
+        Current::Draft { allocated, .. } => {
+            CURRENT.set(
+                conn,
+                Current::Draft {
+                    name: new,
+                    allocated,
+                },
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+fn word(data: &[u8]) -> u32 {
+    u32::from_le_bytes(data.try_into().unwrap())
+}
+
+/// Take an OLCP write from `conn`, returning the response to notify and
+/// its length
+pub fn list(conn: ConnHandle, value: &[u8]) -> ([u8; RESPONSE_MAX], usize) {
+    let op = value.first().copied().unwrap_or(0);
+    let (result, count) = navigate(conn, value);
+    let mut out = [LIST_RESPONSE, op, result as u8, 0, 0, 0, 0];
+    match count {
+        Some(count) => {
+            out[3..].copy_from_slice(&count.to_le_bytes());
+            (out, 7)
+        }
+        None => (out, 3),
+    }
+}
+
+fn navigate(conn: ConnHandle, value: &[u8]) -> (ListResult, Option<u32>) {
+    let Some((&op, args)) = value.split_first() else {
+        return (ListResult::NotSupported, None);
+    };
+    let files = assets::files();
+    let mut slots = files.iter().enumerate().filter(|(_, f)| f.is_some());
+    let here = match current(conn) {
+        Current::Stored(slot) => Some(slot),
+        _ => None,
+    };
+
+    let target = match (op, args) {
+        (FIRST, []) => slots.next().ok_or(ListResult::NoObject),
+        (LAST, []) => slots.last().ok_or(ListResult::NoObject),
+        (PREVIOUS, []) => match here {
+            Some(here) => slots
+                .filter(|(slot, _)| *slot < here)
+                .last()
+                .ok_or(ListResult::OutOfBounds),
+            None => Err(ListResult::Failed),
+        },
+        (NEXT, []) => match here {
+            Some(here) => slots
+                .find(|(slot, _)| *slot > here)
+                .ok_or(ListResult::OutOfBounds),
+            None => Err(ListResult::Failed),
+        },
+        (GO_TO, &[a, b, c, d, e, f]) => {
+            let id = u64::from_le_bytes([a, b, c, d, e, f, 0, 0]);
+            id.checked_sub(FIRST_ID)
+                .and_then(|slot| slots.find(|(s, _)| *s as u64 == slot))
+                .ok_or(ListResult::IdNotFound)
+        }
+        (COUNT, []) => return (ListResult::Success, Some(slots.count() as u32)),
+        (FIRST | LAST | PREVIOUS | NEXT | GO_TO | COUNT, _) => Err(ListResult::InvalidParameter),
+        _ => Err(ListResult::NotSupported),
+    };
+    match target {
+        Ok((slot, _)) => {
+            CURRENT.set(conn, Current::Stored(slot));
+            (ListResult::Success, None)
+        }
+        Err(result) => (result, None),
+    }
+}
+
+/// Take an OACP write from `conn`, returning the response to notify and
+/// its length. `modify` is whether `conn` may create, write and delete.
+/// A read or write it accepts starts on the object channel once the
+/// response is out and [`release`] is called.
+pub async fn execute(conn: ConnHandle, value: &[u8], modify: bool) -> ([u8; RESPONSE_MAX], usize) {
+    let op = value.first().copied().unwrap_or(0);
+    let (result, checksum) = act(conn, value, modify).await;
+    if result != ActionResult::Success {
+        info!("[ots] op {:#04x} failed: {:?}", op, result);
+    }
+    let mut out = [ACTION_RESPONSE, op, result as u8, 0, 0, 0, 0];
+    match checksum {
+        Some(crc) => {
+            out[3..].copy_from_slice(&crc.to_le_bytes());
+            (out, 7)
+        }
+        None => (out, 3),
+    }
+}
+
+/// `Ok` if `offset..offset + len` is within `data`
+fn range(data: &'static [u8], offset: u32, len: u32) -> Result<&'static [u8], ActionResult> {
+    let start = offset as usize;
+    let end = start
+        .checked_add(len as usize)
+        .filter(|&end| end <= data.len());
+    end.map(|end| &data[start..end])
+        .ok_or(ActionResult::InvalidParameter)
+}
+
+/// Whether the channel of `conn` can take a transfer
+fn channel_free(conn: ConnHandle) -> bool {
+    CHANNEL.get(conn) && matches!(TRANSFER.get(conn), Transfer::Idle)
+}
+
+async fn act(conn: ConnHandle, value: &[u8], modify: bool) -> (ActionResult, Option<u32>) {
+    let Some((&op, args)) = value.split_first() else {
+        return (ActionResult::NotSupported, None);
+    };
+    let current = current(conn);
+    let result = match (op, args) {
+        (CREATE | DELETE | WRITE, _) if !modify => Err(ActionResult::NotPermitted),
+        // a size, then a 16 or 128 bit type
+        (CREATE, _) if args.len() == 6 || args.len() == 20 => {
+            let allocated = word(&args[..4]);
+            if allocated as usize > assets::capacity() {
+                Err(ActionResult::InsufficientResources)
+            } else {
+                // anything the client writes gets the unspecified type
+                CURRENT.set(
+                    conn,
+                    Current::Draft {
+                        name: assets::Name::new(DRAFT_NAME).unwrap(),
+                        allocated,
+                    },
+                );
+                Ok(None)
+            }
+        }
+        (DELETE, []) => match current {
+            Current::None => Err(ActionResult::InvalidObject),
+            Current::Draft { .. } => {
+                CURRENT.set(conn, Current::None);
+                Ok(None)
+            }
+            Current::Stored(slot) => {
+                let file = file(slot).unwrap();
+                if WRITER.lock(|writer| writer.get()).is_some() {
+                    Err(ActionResult::ObjectLocked)
+                } else {
+                    match assets::remove(file.name.as_str()).await {
+                        Ok(()) => {
+                            CURRENT.set(conn, Current::None);
+                            Ok(None)
+                        }
+                        Err(e) => {
+                            error!("[ots] deleting {} failed: {:?}", file.name.as_str(), e);
+                            Err(ActionResult::Failed)
+                        }
+                    }
+                }
+            }
+        },
+        (CHECKSUM, _) if args.len() == 8 => match current {
+            Current::Stored(slot) => {
+                range(file(slot).unwrap().data, word(&args[..4]), word(&args[4..]))
+                    .map(|data| Some(integrity::crc32(data)))
+            }
+            _ => Err(ActionResult::InvalidObject),
+        },
+        (READ, _) if args.len() == 8 => match current {
+            Current::Stored(slot) => {
+                range(file(slot).unwrap().data, word(&args[..4]), word(&args[4..])).and_then(
+                    |data| {
+                        if !channel_free(conn) {
+                            return Err(ActionResult::ChannelUnavailable);
+                        }
+                        TRANSFER.set(conn, Transfer::Read(data));
+                        Ok(None)
+                    },
+                )
+            }
+            _ => Err(ActionResult::InvalidObject),
+        },
+        (WRITE, _) if args.len() == 9 => {
+            write(conn, current, word(&args[..4]), word(&args[4..8]), args[8])
+        }
+        (ABORT, []) => {
+            if matches!(TRANSFER.get(conn), Transfer::Read(_)) {
+                TRANSFER.set(conn, Transfer::Idle);
+            }
+            Ok(None)
+        }
+        (CREATE | DELETE | CHECKSUM | READ | WRITE | ABORT, _) => {
+            Err(ActionResult::InvalidParameter)
+        }
+        _ => Err(ActionResult::NotSupported),
+    };
+    match result {
+        Ok(checksum) => (ActionResult::Success, checksum),
+        Err(result) => (result, None),
+    }
+}
+
+/// Set up a write of `len` bytes at `offset` to `current`
+fn write(
+    conn: ConnHandle,
+    current: Current,
+    offset: u32,
+    len: u32,
+    mode: u8,
+) -> Result<Option<u32>, ActionResult> {
+    let (name, covered) = match current {
+        Current::None => return Err(ActionResult::InvalidObject),
+        Current::Stored(slot) => {
+            let file = file(slot).unwrap();
+            (file.name, len as usize >= file.data.len())
+        }
+        Current::Draft { name, allocated } if len <= allocated => (name, true),
+        Current::Draft { .. } => return Err(ActionResult::InvalidParameter),
+    };
+    // no patching, the file is written whole
+    if offset != 0 || !(covered || mode & MODE_TRUNCATE != 0) {
+        return Err(ActionResult::NotPermitted);
+    }
+    if !channel_free(conn) {
+        return Err(ActionResult::ChannelUnavailable);
+    }
+    let claimed = WRITER.lock(|writer| {
+        let free = writer.get().is_none();
+        if free {
+            writer.set(Some(conn));
+        }
+        free
+    });
+    if !claimed {
+        return Err(ActionResult::ObjectLocked);
+    }
+    if let Err(e) = assets::create(name, len) {
+        error!("[ots] no room for {}: {:?}", name.as_str(), e);
+        WRITER.lock(|writer| writer.set(None));
+        return Err(ActionResult::InsufficientResources);
+    }
+    TRANSFER.set(conn, Transfer::Write { name, len });
+    Ok(None)
+}
+
+/// Start the transfer [`execute`] set up for `conn`, once its response is
+/// out
+pub fn release(conn: ConnHandle) {
+    if !matches!(TRANSFER.get(conn), Transfer::Idle) {
+        events::publish(Event::TransferRequested(conn));
+    }
+}
+
+/// Drop whatever transfer `conn` had, after its channel went away
+fn reset(conn: ConnHandle) {
+    if let Transfer::Write { .. } = TRANSFER.get(conn) {
+        assets::abandon();
+    }
+    TRANSFER.set(conn, Transfer::Idle);
+    WRITER.lock(|writer| {
+        if writer.get() == Some(conn) {
+            writer.set(None);
+        }
+    });
+}
+
+/// Serve the object channels `conn` opens, one after the other, until it
+/// disconnects
+pub async fn run<C: Controller>(stack: Stack<'_, C>, conn: &Connection<'_>) {
+    let Some(mut events) = events::subscribe() else {
+        error!("[ots] no event subscriber available, no object transfers");
+        latency::wait_disconnected(conn).await;
+        return;
+    };
+
+    let handle = conn.handle();
+    let config = L2capChannelConfig {
+        mtu: SDU_MAX as u16,
+        ..Default::default()
+    };
+    loop {
+        let accepted = select(
+            L2capChannel::accept(stack, conn, &[PSM], &config),
+            latency::wait_disconnected(conn),
+        )
+        .await;
+        let mut channel = match accepted {
+            Either::First(Ok(channel)) => channel,
+            Either::First(Err(e)) => {
+                error!(
+                    "[ots] accepting the object channel failed: {:?}",
+                    fmt::Dbg(&e)
+                );
+                Timer::after(Duration::from_secs(1)).await;
+                continue;
+            }
+            Either::Second(()) => break,
+        };
+        info!("[ots] object channel open");
+        CHANNEL.set(handle, true);
+        let disconnected = serve(stack, conn, &mut channel, &mut events).await;
+        CHANNEL.set(handle, false);
+        reset(handle);
+        if disconnected {
+            break;
+        }
+    }
+}
+
+/// Carry out the transfers on `channel` until it closes, returning whether
+/// the connection went with it
+async fn serve<C: Controller>(
+    stack: Stack<'_, C>,
+    conn: &Connection<'_>,
+    channel: &mut L2capChannel<'_>,
+    events: &mut EventSubscriber,
+) -> bool {
+    let handle = conn.handle();
+    let mut buf = [0; SDU_MAX];
+    loop {
+        let next = select3(
+            events.next_message_pure(),
+            channel.receive(stack, &mut buf),
+            latency::wait_disconnected(conn),
+        )
+        .await;
+        match next {
+            Either3::First(Event::TransferRequested(c)) if c == handle => {}
+            Either3::First(_) => continue,
+            Either3::Second(Ok(len)) => {
+                error!("[ots] dropped {} bytes outside a write", len);
+                continue;
+            }
+            Either3::Second(Err(e)) => {
+                info!("[ots] object channel closed: {:?}", fmt::Dbg(&e));
+                return false;
+            }
+            Either3::Third(()) => return true,
+        }
+
+        let done = match TRANSFER.get(handle) {
+            Transfer::Idle => continue,
+            Transfer::Read(data) => send(stack, handle, channel, data).await,
+            Transfer::Write { name, len } => receive(stack, handle, channel, name, len).await,
+        };
+        TRANSFER.set(handle, Transfer::Idle);
+        WRITER.lock(|writer| {
+            if writer.get() == Some(handle) {
+                writer.set(None);
+            }
+        });
+        if done == Err(Failed::Channel) {
+            return !conn.is_connected();
+        }
+    }
+}
+
+/// Send `data` down the channel, unless the read is aborted
+async fn send<C: Controller>(
+    stack: Stack<'_, C>,
+    conn: ConnHandle,
+    channel: &mut L2capChannel<'_>,
+    data: &'static [u8],
+) -> Result<(), Failed> {
+    for chunk in data.chunks(SDU_MAX) {
+        // an abort puts the transfer back to idle
+        if !matches!(TRANSFER.get(conn), Transfer::Read(_)) {
+            info!("[ots] read aborted");
+            return Ok(());
+        }
+        if let Err(e) = channel.send::<_, L2CAP_MTU>(stack, chunk).await {
+            error!("[ots] sending failed: {:?}", fmt::Dbg(&e));
+            return Err(Failed::Channel);
+        }
+    }
+    info!("[ots] sent {} bytes", data.len());
+    Ok(())
+}
+
+/// Receive the `len` bytes of file `name` from the channel and commit it
+async fn receive<C: Controller>(
+    stack: Stack<'_, C>,
+    conn: ConnHandle,
+    channel: &mut L2capChannel<'_>,
+    name: assets::Name,
+    len: u32,
+) -> Result<(), Failed> {
+    let mut sector = SECTOR.lock().await;
+    let mut buf = [0; SDU_MAX];
+    let mut crc = Crc32::new();
+    let mut received = 0;
+    let mut buffered = 0;
+    while received < len {
+        let n = match select(
+            channel.receive(stack, &mut buf),
+            Timer::after(WRITE_TIMEOUT),
+        )
+        .await
+        {
+            Either::First(Ok(n)) => n,
+            Either::First(Err(e)) => {
+                error!("[ots] receiving failed: {:?}", fmt::Dbg(&e));
+                assets::abandon();
+                return Err(Failed::Channel);
+            }
+            Either::Second(()) => {
+                error!("[ots] write timed out after {} of {} bytes", received, len);
+                assets::abandon();
+                return Err(Failed::Transfer);
+            }
+        };
+        // past the end of the object is dropped
+        let mut data = &buf[..n.min((len - received) as usize)];
+        crc.update(data);
+        while !data.is_empty() {
+            let take = data.len().min(ERASE_SIZE - buffered);
+            sector[buffered..buffered + take].copy_from_slice(&data[..take]);
+            buffered += take;
+            received += take as u32;
+            data = &data[take..];
+            if buffered == ERASE_SIZE || received == len {
+                sector[buffered..].fill(0xff);
+                let offset = received - buffered as u32;
+                if let Err(e) = assets::write(offset, &sector).await {
+                    error!("[ots] writing at {} failed: {:?}", offset, e);
+                    assets::abandon();
+                    return Err(Failed::Transfer);
+                }
+                buffered = 0;
+            }
+        }
+    }
+    if let Err(e) = assets::commit(crc.finish()).await {
+        error!("[ots] storing {} failed: {:?}", name.as_str(), e);
+        return Err(Failed::Transfer);
+    }
+    // the draft is a file now
+    let slot = assets::files()
+        .iter()
+        .position(|f| f.is_some_and(|f| f.name == name));
+    if let Some(slot) = slot {
+        CURRENT.set(conn, Current::Stored(slot));
+    }
+    Ok(())
+}
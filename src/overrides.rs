@@ -0,0 +1,115 @@
+//! Characteristic overrides for demos
+//!
+//! Debug builds only. A write to the override characteristic pins any
+//! characteristic to a value of our choosing and freezes it: whatever
+//! normally updates it, firmware or client, is ignored until the override
+//! is released. Lets demos and UI work go ahead without real data behind
+//! the values.
+//!
+//! Writes are `[0x01, handle: u16, value...]` to override,
+//! `[0x00, handle: u16]` to release one and `[0x02]` to release all.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Longest value an override can hold
+pub const MAX_VALUE: usize = 32;
+
+/// Size of the override characteristic
+pub const CHARACTERISTIC_SIZE: usize = 3 + MAX_VALUE;
+
+/// Max number of characteristics overridden at once
+const SLOTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Request<'a> {
+    Override { handle: u16, value: &'a [u8] },
+    Release { handle: u16 },
+    ReleaseAll,
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(value: &'a [u8]) -> Option<Self> {
+        match value {
+            [0x01, lo, hi, value @ ..] if value.len() <= MAX_VALUE => Some(Self::Override {
+                handle: u16::from_le_bytes([*lo, *hi]),
+                value,
+            }),
+            [0x00, lo, hi] => Some(Self::Release {
+                handle: u16::from_le_bytes([*lo, *hi]),
+            }),
+            [0x02] => Some(Self::ReleaseAll),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Frozen {
+    handle: u16,
+    len: usize,
+    value: [u8; MAX_VALUE],
+}
+
+static FROZEN: Mutex<CriticalSectionRawMutex, RefCell<[Option<Frozen>; SLOTS]>> =
+    Mutex::new(RefCell::new([None; SLOTS]));
+
+/// Freeze `handle` at `value`, `false` if all slots are taken
+pub fn freeze(handle: u16, value: &[u8]) -> bool {
+    let mut frozen = Frozen {
+        handle,
+        len: value.len(),
+        value: [0; MAX_VALUE],
+    };
+    frozen.value[..value.len()].copy_from_slice(value);
+
+    FROZEN.lock(|slots| {
+        let mut slots = slots.borrow_mut();
+        let slot = match slots
+            .iter()
+            .position(|s| matches!(s, Some(f) if f.handle == handle))
+        {
+            Some(idx) => &mut slots[idx],
+            None => match slots.iter_mut().find(|s| s.is_none()) {
+                Some(slot) => slot,
+                None => return false,
+            },
+        };
+        *slot = Some(frozen);
+        true
+    })
+}
+
+pub fn release(handle: u16) {
+    FROZEN.lock(|slots| {
+        for slot in slots.borrow_mut().iter_mut() {
+            if matches!(slot, Some(f) if f.handle == handle) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+pub fn release_all() {
+    FROZEN.lock(|slots| *slots.borrow_mut() = [None; SLOTS]);
+}
+
+/// Call `f` with the frozen value of `handle`, if it's frozen
+pub fn with_frozen<R>(handle: u16, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+    let frozen = FROZEN.lock(|slots| {
+        slots
+            .borrow()
+            .iter()
+            .flatten()
+            .find(|f| f.handle == handle)
+            .copied()
+    })?;
+    Some(f(&frozen.value[..frozen.len]))
+}
+
+pub fn is_frozen(handle: u16) -> bool {
+    with_frozen(handle, |_| ()).is_some()
+}
@@ -0,0 +1,148 @@
+//! Paged reads of datasets too big for one characteristic
+//!
+//! A dataset is exposed as two characteristics: a writable page index and
+//! a readable page. Writing a page index (u16, little endian) fills the
+//! page characteristic with:
+//!
+//! | bytes | content                           |
+//! |-------|-----------------------------------|
+//! | 0..2  | total dataset length              |
+//! | 2..4  | offset of this page               |
+//! | 4..   | up to [`PAGE_DATA_SIZE`] of data  |
+//!
+//! Pages are sized to fit the default ATT MTU so no long reads are needed.
+//!
+//! Each connection has its own page index, and gets its page notified if
+//! it subscribed to the page characteristic. Without a subscription it's
+//! left to be read, and the last connection to select a page wins.
+//!
+//! An optional flags byte after the page index negotiates features. With
+//! [`FLAG_COMPRESSED`] set, pages are cut from the dataset compressed with
+//! [`crate::compress`] instead, and the total length is the compressed
+//! length with [`TOTAL_COMPRESSED`] set. A dataset longer than
+//! [`MAX_COMPRESSED_DATASET`], or one that doesn't get any shorter, is
+//! served as it is, without the bit.
+
+use bt_hci::param::ConnHandle;
+
+use crate::compress;
+use crate::session::PerConnection;
+
+pub const HEADER_SIZE: usize = 4;
+
+/// Dataset bytes per page
+pub const PAGE_DATA_SIZE: usize = 18;
+
+/// Size of the page characteristic
+pub const PAGE_SIZE: usize = HEADER_SIZE + PAGE_DATA_SIZE;
+
+/// Size of the page index characteristic, index plus flags
+pub const INDEX_SIZE: usize = 3;
+
+/// Page index flag asking for the compressed dataset
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+/// Largest dataset that can be served compressed
+pub const MAX_COMPRESSED_DATASET: usize = 512;
+
+/// Bit of the total length marking a compressed page. Longer datasets are
+/// cut short of it.
+pub const TOTAL_COMPRESSED: u16 = 0x8000;
+
+/// Something that can be read out in pages
+pub trait Dataset {
+    /// Total length in bytes
+    fn len(&self) -> usize;
+
+    /// Copy bytes starting at `offset` into `buf`, returning how many were copied
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    index: u16,
+    flags: u8,
+}
+
+/// Tracks which page each connection is looking at
+pub struct Pager {
+    cursor: PerConnection<Cursor>,
+}
+
+impl Pager {
+    pub const fn new() -> Self {
+        Self {
+            cursor: PerConnection::new(Cursor { index: 0, flags: 0 }),
+        }
+    }
+
+    /// Handle a write to the page index characteristic
+    pub fn select(&self, conn: ConnHandle, value: &[u8]) -> Option<u16> {
+        let index = u16::from_le_bytes(value.get(..2)?.try_into().ok()?);
+        let flags = value.get(2).copied().unwrap_or(0);
+        self.cursor.set(conn, Cursor { index, flags });
+        Some(index)
+    }
+
+    /// The page `conn` has selected
+    pub fn current(&self, conn: ConnHandle, dataset: &(impl Dataset + ?Sized)) -> [u8; PAGE_SIZE] {
+        let cursor = self.cursor.get(conn);
+        if cursor.flags & FLAG_COMPRESSED == 0 {
+            return encode_page(dataset, cursor.index);
+        }
+
+        if dataset.len() > MAX_COMPRESSED_DATASET {
+            return encode_page(dataset, cursor.index);
+        }
+        let mut raw = [0; MAX_COMPRESSED_DATASET];
+        let len = dataset.read(0, &mut raw);
+        let mut compressed = [0; compress::max_compressed_len(MAX_COMPRESSED_DATASET)];
+        match compress::compress(&raw[..len], &mut compressed) {
+            Some(n) if n < len => {
+                let mut page = encode_page(&compressed[..n], cursor.index);
+                page[0..2].copy_from_slice(&(n as u16 | TOTAL_COMPRESSED).to_le_bytes());
+                page
+            }
+            _ => encode_page(&raw[..len], cursor.index),
+        }
+    }
+}
+
+impl Default for Pager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encode page `index` of `dataset`
+pub fn encode_page(dataset: &(impl Dataset + ?Sized), index: u16) -> [u8; PAGE_SIZE] {
+    let mut page = [0; PAGE_SIZE];
+    let total = dataset.len().min((TOTAL_COMPRESSED - 1) as usize);
+    let offset = (index as usize * PAGE_DATA_SIZE).min(total);
+
+    page[0..2].copy_from_slice(&(total as u16).to_le_bytes());
+    page[2..4].copy_from_slice(&(offset as u16).to_le_bytes());
+    if offset < total {
+        dataset.read(offset, &mut page[HEADER_SIZE..]);
+    }
+
+    page
+}
+
+/// Any byte slice is a dataset
+impl Dataset for [u8] {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let src = self.get(offset..).unwrap_or(&[]);
+        let n = src.len().min(buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        n
+    }
+}
@@ -9,12 +9,25 @@ use ssd1306::prelude::*;
 use ssd1306::I2CDisplayInterface;
 use ssd1306::Ssd1306;
 
+use crate::boardrev;
+use crate::crash;
+use crate::relay;
+
+/// How long the message stays up before the reset
+const DISPLAY_CYCLES: u32 = 5 * 125_000_000;
+
 #[panic_handler]
 pub fn panic_handler(panic_info: &PanicInfo) -> ! {
+    // first, in case the display panics too
+    crash::record_panic(panic_info);
+
     // SAFETY: we just panicked, therefore nobody has these
     let p = unsafe { Peripherals::steal() };
 
-    let i2c = I2c::new_blocking(p.I2C0, p.PIN_1, p.PIN_0, i2c::Config::default());
+    let mut i2c = I2c::new_blocking(p.I2C0, p.PIN_1, p.PIN_0, i2c::Config::default());
+    // nothing stays switched on while we're down
+    let expander = boardrev::current().expander;
+    let _ = expander.blocking_write_outputs(&mut i2c, relay::ALL_OFF);
     let interface = I2CDisplayInterface::new(i2c);
     let mut display =
         Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0).into_terminal_mode();
@@ -28,5 +41,6 @@ pub fn panic_handler(panic_info: &PanicInfo) -> ! {
         let _ = writeln!(display, "line: {}", location.line());
     }
 
-    loop {}
+    cortex_m::asm::delay(DISPLAY_CYCLES);
+    cortex_m::peripheral::SCB::sys_reset();
 }
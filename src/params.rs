@@ -0,0 +1,258 @@
+//! Parameters of the BLE peripheral
+//!
+//! What [`crate::blue::run`] used to hard-code: the address, the names and
+//! appearance it shows, how it advertises and what it asks of connections.
+//! Start from [`Config::DEFAULT`] and change what differs:
+//!
+//! ```ignore
+//! let config = Config::DEFAULT
+//!     .with_name("garage lighting")
+//!     .with_address(AddressMode::DeviceId);
+//! ```
+//!
+//! The L2CAP MTU and the most connections there can be size the host
+//! resources, they stay constants in `blue.rs`. [`Config::connections_max`]
+//! can only lower the latter.
+//!
+//! A [`crate::preset`] sets what makes a kind of product in one go.
+
+use core::cell::Cell;
+
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use rand_core::RngCore;
+
+use crate::adv;
+use crate::blue::CONNECTIONS_MAX;
+use crate::error;
+use crate::latency;
+use crate::settings;
+use crate::system;
+use crate::writes;
+
+/// The address [`AddressMode::Random`] drew this boot
+static RANDOM: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; 6]>>> = Mutex::new(Cell::new(None));
+
+/// Where our random static address comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AddressMode {
+    /// This address, least significant byte first
+    Static([u8; 6]),
+    /// Derived from the flash unique ID, the same on every boot of a board
+    DeviceId,
+    /// Drawn again on every boot, and kept while the radio restarts. Bonds
+    /// don't survive a reboot.
+    Random,
+    /// Drawn once and kept in [`settings`]
+    Persisted,
+}
+
+impl AddressMode {
+    /// The address to use, with the top two bits set as a random static
+    /// address needs
+    pub async fn resolve(&self) -> [u8; 6] {
+        let mut address = match *self {
+            Self::Static(address) => address,
+            Self::DeviceId => {
+                let id = system::DeviceId::get().0;
+                let mut address = [0; 6];
+                address.copy_from_slice(&id[..6]);
+                address
+            }
+            Self::Random => RANDOM.lock(|random| {
+                random.get().unwrap_or_else(|| {
+                    let mut address = [0; 6];
+                    RoscRng.fill_bytes(&mut address);
+                    random.set(Some(address));
+                    address
+                })
+            }),
+            Self::Persisted => {
+                let mut address = [0; 6];
+                match settings::get(settings::Key::ADDRESS, &mut address).await {
+                    Ok(Some(6)) => {}
+                    stored => {
+                        if let Err(e) = stored {
+                            error!("[params] reading the address failed: {:?}", e);
+                        }
+                        RoscRng.fill_bytes(&mut address);
+                        address[5] |= 0xc0;
+                        if let Err(e) = settings::set(settings::Key::ADDRESS, &address).await {
+                            error!("[params] storing the address failed: {:?}", e);
+                        }
+                    }
+                }
+                address
+            }
+        };
+        address[5] |= 0xc0;
+        address
+    }
+}
+
+/// The optional services [`crate::blue::run`] adds to the GATT server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Services {
+    /// Environmental Sensing, see [`crate::environment`]
+    pub environment: bool,
+    /// The energy meter, see [`crate::meter`]
+    pub meter: bool,
+    /// The relay board, see [`crate::relay`]
+    pub relays: bool,
+    /// The distance sensor, see [`crate::distance`]
+    pub distance: bool,
+    /// The tank level, see [`crate::level`]
+    pub level: bool,
+    /// Wind and rain, see [`crate::weather`]
+    pub weather: bool,
+    /// The asset files over Object Transfer, see [`crate::ots`]
+    pub objects: bool,
+    /// Wi-Fi provisioning, see [`crate::provision`]
+    pub wifi: bool,
+    /// A keyboard over HID over GATT, see [`crate::hid`]
+    pub hid: bool,
+}
+
+impl Services {
+    /// Everything but the keyboard
+    pub const DEFAULT: Self = Self {
+        environment: true,
+        meter: true,
+        relays: true,
+        distance: true,
+        level: true,
+        weather: true,
+        objects: true,
+        wifi: true,
+        hid: false,
+    };
+
+    pub const NONE: Self = Self {
+        environment: false,
+        meter: false,
+        relays: false,
+        distance: false,
+        level: false,
+        weather: false,
+        objects: false,
+        wifi: false,
+        hid: false,
+    };
+}
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    pub address: AddressMode,
+    /// Device name in GAP and the advertising data, unless one is kept in
+    /// [`settings`]
+    pub name: &'static str,
+    /// Name advertised in maintenance mode
+    pub setup_name: &'static str,
+    /// GAP appearance
+    pub appearance: u16,
+    /// 16 bit service UUIDs in the advertising data, in normal mode
+    pub service_uuids: &'static [u16],
+    pub services: Services,
+    /// Bounds of the advertising interval
+    pub adv_interval_min: Duration,
+    pub adv_interval_max: Duration,
+    pub schedule: adv::Schedule,
+    /// Connection parameters asked for, see [`crate::latency`]
+    pub latency: &'static latency::Policy,
+    /// Most centrals to accept at once, up to [`CONNECTIONS_MAX`]
+    pub connections_max: usize,
+    /// Handlers for writes to characteristics, see [`crate::writes`]
+    pub write_handlers: &'static [writes::Registration],
+}
+
+impl Config {
+    pub const DEFAULT: Self = Self {
+        address: AddressMode::Static([0xff, 0x9f, 0x1a, 0x05, 0xe4, 0xff]),
+        name: "mansion lighting",
+        setup_name: "mansion setup",
+        // generic light fixture
+        appearance: 0x0780,
+        // battery service
+        service_uuids: &[0x180f],
+        services: Services::DEFAULT,
+        adv_interval_min: Duration::from_millis(160),
+        adv_interval_max: Duration::from_millis(160),
+        schedule: adv::Schedule::DEFAULT,
+        latency: &latency::Policy::DEFAULT,
+        connections_max: CONNECTIONS_MAX,
+        write_handlers: &[],
+    };
+
+    pub const fn with_address(mut self, address: AddressMode) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub const fn with_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub const fn with_setup_name(mut self, name: &'static str) -> Self {
+        self.setup_name = name;
+        self
+    }
+
+    pub const fn with_appearance(mut self, appearance: u16) -> Self {
+        self.appearance = appearance;
+        self
+    }
+
+    pub const fn with_service_uuids(mut self, uuids: &'static [u16]) -> Self {
+        self.service_uuids = uuids;
+        self
+    }
+
+    pub const fn with_services(mut self, services: Services) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub const fn with_adv_interval(mut self, min: Duration, max: Duration) -> Self {
+        self.adv_interval_min = min;
+        self.adv_interval_max = max;
+        self
+    }
+
+    pub const fn with_schedule(mut self, schedule: adv::Schedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    pub const fn with_latency(mut self, latency: &'static latency::Policy) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub const fn with_write_handlers(mut self, handlers: &'static [writes::Registration]) -> Self {
+        self.write_handlers = handlers;
+        self
+    }
+
+    /// Clamped to [`CONNECTIONS_MAX`], and to at least one
+    pub const fn with_connections_max(mut self, max: usize) -> Self {
+        self.connections_max = if max > CONNECTIONS_MAX {
+            CONNECTIONS_MAX
+        } else if max == 0 {
+            1
+        } else {
+            max
+        };
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
@@ -0,0 +1,80 @@
+//! Flash partition table
+//!
+//! What we keep in flash besides the firmware, from the top of the chip
+//! down: [`crate::store`]'s records, the [`crate::settings`] log, the
+//! [`crate::assets`] filesystem and, in builds with the `dfu` feature, the
+//! [`crate::radiofw`] package. The firmware gets everything below
+//! [`FIRMWARE_END`]; `memory.x` and `memory-dfu.x` have to keep out of the
+//! partitions here, and are commented with their sizes.
+//!
+//! Offsets are from the start of flash, partitions are whole sectors.
+
+use embassy_rp::flash::ERASE_SIZE;
+
+use crate::system::FLASH_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Partition {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Partition {
+    /// `sectors` sectors ending at `end`
+    const fn below(end: u32, sectors: usize) -> Self {
+        Self {
+            start: end - (sectors * ERASE_SIZE) as u32,
+            end,
+        }
+    }
+
+    pub const fn len(&self) -> usize {
+        (self.end - self.start) as usize
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn sectors(&self) -> usize {
+        self.len() / ERASE_SIZE
+    }
+
+    /// Offset of sector `idx`
+    pub const fn sector(&self, idx: usize) -> u32 {
+        self.start + (idx * ERASE_SIZE) as u32
+    }
+
+    /// The partition as the CPU sees it, through XIP. Flash writes flush
+    /// the XIP cache, so this reads what was last programmed.
+    pub fn mapped(&self) -> &'static [u8] {
+        let base = (XIP_BASE + self.start as usize) as *const u8;
+        // SAFETY: flash is mapped read-only at XIP_BASE, and the partition
+        // lies within it
+        unsafe { core::slice::from_raw_parts(base, self.len()) }
+    }
+}
+
+/// Where the flash shows up in the address space
+const XIP_BASE: usize = 0x1000_0000;
+
+/// Records, 64K
+pub const STORE: Partition = Partition::below(FLASH_SIZE as u32, 16);
+
+/// Settings log, 16K
+pub const SETTINGS: Partition = Partition::below(STORE.start, 4);
+
+/// Asset filesystem, 128K
+pub const ASSETS: Partition = Partition::below(SETTINGS.start, 32);
+
+/// Radio firmware package, 256K
+#[cfg(feature = "dfu")]
+pub const RADIO: Partition = Partition::below(ASSETS.start, 64);
+
+/// End of the space the firmware is linked into, with the bootloader and
+/// its partitions in `dfu` builds
+#[cfg(not(feature = "dfu"))]
+pub const FIRMWARE_END: u32 = ASSETS.start;
+#[cfg(feature = "dfu")]
+pub const FIRMWARE_END: u32 = RADIO.start;
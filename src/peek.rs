@@ -0,0 +1,168 @@
+//! Memory peek for field debugging
+//!
+//! Debug builds with the `peek` feature only. Writing `[address: u32,
+//! len: u8]` to the peek request characteristic reads up to [`MAX_READ`]
+//! bytes of RAM into the peek result characteristic, so counters and state
+//! can be inspected on a board no debugger can reach. Addresses come from
+//! the symbols in the `.elf`. Like the other sensitive writes, requests
+//! are only taken in maintenance mode.
+//!
+//! Only statics can be read: a read has to lie entirely within `.data` or
+//! `.bss`. The stack and the peripheral registers are off limits.
+//!
+//! The result is `[status, address: u32, data...]`, with status 0 for a
+//! read and 1 for a rejected request.
+
+#[cfg(not(debug_assertions))]
+compile_error!("the peek feature is for debug builds only");
+
+use core::cell::Cell;
+use core::ops::Range;
+use core::ptr::addr_of;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use trouble_host::prelude::*;
+
+use crate::blue::gen_uuid;
+use crate::error;
+use crate::gattcheck;
+use crate::info;
+use crate::modules;
+use crate::roles;
+use crate::writes;
+
+/// Longest read, so a result fits the default ATT MTU
+pub const MAX_READ: usize = 16;
+
+/// Size of the peek request characteristic
+pub const REQUEST_SIZE: usize = 5;
+
+/// Size of the peek result characteristic
+pub const RESULT_SIZE: usize = 5 + MAX_READ;
+
+const OK: u8 = 0;
+const REJECTED: u8 = 1;
+
+/// The result of the last request, and its length
+static RESULT: Mutex<CriticalSectionRawMutex, Cell<([u8; RESULT_SIZE], usize)>> =
+    Mutex::new(Cell::new(([0; RESULT_SIZE], 0)));
+
+extern "C" {
+    // from the cortex-m-rt linker script
+    static __sdata: u8;
+    static __edata: u8;
+    static __sbss: u8;
+    static __ebss: u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Request {
+    pub address: u32,
+    pub len: u8,
+}
+
+impl Request {
+    fn parse(value: &[u8]) -> Option<Self> {
+        let [a0, a1, a2, a3, len] = *value else {
+            return None;
+        };
+        Some(Self {
+            address: u32::from_le_bytes([a0, a1, a2, a3]),
+            len,
+        })
+    }
+}
+
+/// The address ranges reads are allowed in
+fn whitelist() -> [Range<u32>; 2] {
+    // SAFETY: only the symbols' addresses are taken
+    unsafe {
+        [
+            addr_of!(__sdata) as u32..addr_of!(__edata) as u32,
+            addr_of!(__sbss) as u32..addr_of!(__ebss) as u32,
+        ]
+    }
+}
+
+/// Whether `request` is short enough and lies within one whitelisted range
+fn allowed(request: &Request) -> bool {
+    let len = request.len as u32;
+    let Some(end) = request.address.checked_add(len) else {
+        return false;
+    };
+    len > 0
+        && len as usize <= MAX_READ
+        && whitelist()
+            .iter()
+            .any(|range| range.start <= request.address && end <= range.end)
+}
+
+/// Carry out a peek request, returning the result and its length
+fn read(request: &Request) -> ([u8; RESULT_SIZE], usize) {
+    let mut result = [0; RESULT_SIZE];
+    result[1..5].copy_from_slice(&request.address.to_le_bytes());
+    if !allowed(request) {
+        result[0] = REJECTED;
+        return (result, 5);
+    }
+
+    result[0] = OK;
+    let len = request.len as usize;
+    for (i, byte) in result[5..5 + len].iter_mut().enumerate() {
+        // SAFETY: the whole read is within a static section, checked above.
+        // Tasks may be updating it, so multi-byte values can come out torn.
+        *byte = unsafe { core::ptr::read_volatile((request.address as usize + i) as *const u8) };
+    }
+    (result, 5 + len)
+}
+
+const SERVICE_UUID: Uuid = gen_uuid("memory peek");
+const REQUEST_UUID: Uuid = gen_uuid("peek request");
+const RESULT_UUID: Uuid = gen_uuid("peek result");
+const _: () = gattcheck::assert_distinct(&[&[REQUEST_UUID, RESULT_UUID]]);
+const _: () = gattcheck::assert_fit(&[REQUEST_SIZE, RESULT_SIZE], writes::VALUE_MAX);
+
+fn write_request(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let Some(request) = Request::parse(value) else {
+        error!("[peek] invalid request");
+        return Err(writes::AttError::InvalidLength);
+    };
+    let (result, len) = read(&request);
+    info!("[peek] {:?}, status {}", request, result[0]);
+    RESULT.lock(|last| last.set((result, len)));
+    Ok(())
+}
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    let spec = writes::Spec::new();
+    svc.add(
+        REQUEST_UUID,
+        &[CharacteristicProp::Write],
+        REQUEST_SIZE,
+        spec.with_write(write_request)
+            .with_access(roles::Access::Configure)
+            .in_maintenance(),
+    );
+    svc.add(
+        RESULT_UUID,
+        &[CharacteristicProp::Read],
+        RESULT_SIZE,
+        spec.with_read(|| {
+            let (result, len) = RESULT.lock(|last| last.get());
+            writes::Value::new(&result[..len])
+        }),
+    );
+}
+
+crate::register_module!(
+    MODULE,
+    "peek",
+    |_| {},
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |_| true,
+        build: service,
+    },
+);
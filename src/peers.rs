@@ -0,0 +1,425 @@
+//! Address book of the peers that connected
+//!
+//! The last [`PEERS_MAX`] peers to connect are kept in flash, each with
+//! its preferences:
+//! - a [`latency::Profile`] its connection is pinned to, for a phone that
+//!   always streams or a remote that's fine with a slow link
+//! - a [`Role`] (see [`crate::roles`])
+//! - up to [`MUTED_MAX`] characteristics it isn't notified of even when
+//!   subscribed, by value handle
+//!
+//! They're applied as the peer connects. A peer connecting once more
+//! moves to the front, and the one that connected longest ago makes room
+//! for a new one. Each peer is a [`crate::settings`] value of its own,
+//! from [`settings::Key::PEERS`] on, written through [`crate::persist`].
+//!
+//! The peers characteristic manages them. It takes `[op,
+//! args...]` and notifies `[op, `[`Status`]`, peer]`:
+//! - `[LIST, index]` gives the peer at `index`, most recent first
+//! - `[GET, address: 6]` gives the peer
+//! - `[SET, address: 6, profile, role, muted: u16 each]` sets its
+//!   preferences, adding it if it's new
+//! - `[FORGET, address: 6]` forgets it
+//!
+//! A peer is `[address: 6, profile, role, muted: u16 each]`, the
+//! profile 0 for active and 1 for idle, 0xff for no profile or role and 0
+//! for no muted characteristic. Numbers are little endian.
+
+use core::cell::RefCell;
+
+use bt_hci::param::BdAddr;
+use bt_hci::param::ConnHandle;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::beacons;
+use crate::error;
+use crate::fmt;
+use crate::info;
+use crate::latency;
+use crate::persist;
+use crate::roles::Role;
+use crate::session::PerConnection;
+use crate::settings;
+
+/// Max number of peers kept
+pub const PEERS_MAX: usize = 8;
+
+/// Max number of muted characteristics per peer
+pub const MUTED_MAX: usize = 4;
+
+/// A peer on the characteristic
+pub const PEER_SIZE: usize = 6 + 1 + 1 + 2 * MUTED_MAX;
+
+/// Longest request, a set
+pub const REQUEST_MAX: usize = 1 + 6 + 1 + 1 + 2 * MUTED_MAX;
+
+/// Size of a response
+pub const RESPONSE_SIZE: usize = 2 + PEER_SIZE;
+
+/// A peer in flash: address, sequence number, profile, role, muted
+const STORED_SIZE: usize = 6 + 4 + 1 + 1 + 2 * MUTED_MAX;
+
+/// No profile or role
+const NONE: u8 = 0xff;
+
+pub const LIST: u8 = 0;
+pub const GET: u8 = 1;
+pub const SET: u8 = 2;
+pub const FORGET: u8 = 3;
+
+/// How a request went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Status {
+    Done = 0,
+    Malformed = 1,
+    /// No such peer, or nothing at that index
+    Unknown = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Peer {
+    pub address: [u8; 6],
+    /// Higher connected more recently
+    seq: u32,
+    pub profile: Option<latency::Profile>,
+    pub role: Option<Role>,
+    /// Value handles, 0 for none
+    pub muted: [u16; MUTED_MAX],
+}
+
+impl Peer {
+    fn new(address: [u8; 6], seq: u32) -> Self {
+        Self {
+            address,
+            seq,
+            profile: None,
+            role: None,
+            muted: [0; MUTED_MAX],
+        }
+    }
+
+    /// The profile and role bytes, then the muted handles
+    fn encode_preferences(&self, out: &mut [u8]) {
+        out[0] = match self.profile {
+            Some(latency::Profile::Active) => 0,
+            Some(latency::Profile::Idle) => 1,
+            // only ever pinned by the boost
+            Some(latency::Profile::Boost) | None => NONE,
+        };
+        out[1] = self.role.map_or(NONE, |role| role as u8);
+        for (handle, chunk) in self.muted.iter().zip(out[2..].chunks_exact_mut(2)) {
+            chunk.copy_from_slice(&handle.to_le_bytes());
+        }
+    }
+
+    /// Parse what [`Self::encode_preferences`] makes into this peer
+    fn decode_preferences(&mut self, data: &[u8]) -> Option<()> {
+        let [profile, role, ref muted @ ..] = *data else {
+            return None;
+        };
+        if muted.len() != 2 * MUTED_MAX {
+            return None;
+        }
+        self.profile = match profile {
+            0 => Some(latency::Profile::Active),
+            1 => Some(latency::Profile::Idle),
+            NONE => None,
+            _ => return None,
+        };
+        self.role = match role {
+            NONE => None,
+            role => Some(Role::from_u8(role)?),
+        };
+        for (handle, chunk) in self.muted.iter_mut().zip(muted.chunks_exact(2)) {
+            *handle = u16::from_le_bytes([chunk[0], chunk[1]]);
+        }
+        Some(())
+    }
+
+    fn encode(&self) -> [u8; STORED_SIZE] {
+        let mut out = [0; STORED_SIZE];
+        out[0..6].copy_from_slice(&self.address);
+        out[6..10].copy_from_slice(&self.seq.to_le_bytes());
+        self.encode_preferences(&mut out[10..]);
+        out
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != STORED_SIZE {
+            return None;
+        }
+        let mut peer = Self::new(
+            data[0..6].try_into().unwrap(),
+            u32::from_le_bytes(data[6..10].try_into().unwrap()),
+        );
+        peer.decode_preferences(&data[10..])?;
+        Some(peer)
+    }
+
+    /// The peer as the characteristic has it
+    fn describe(&self) -> [u8; PEER_SIZE] {
+        let mut out = [0; PEER_SIZE];
+        out[0..6].copy_from_slice(&self.address);
+        self.encode_preferences(&mut out[6..]);
+        out
+    }
+}
+
+struct Book {
+    /// By settings key
+    peers: [Option<Peer>; PEERS_MAX],
+    /// Sequence number of the next peer to connect
+    seq: u32,
+}
+
+static BOOK: Mutex<CriticalSectionRawMutex, RefCell<Book>> = Mutex::new(RefCell::new(Book {
+    peers: [None; PEERS_MAX],
+    seq: 0,
+}));
+
+/// What each connection's peer muted
+static MUTED: PerConnection<[u16; MUTED_MAX]> = PerConnection::new([0; MUTED_MAX]);
+
+fn key(idx: usize) -> settings::Key {
+    settings::Key(settings::Key::PEERS.0 + idx as u32)
+}
+
+/// Write the peer with settings key `idx` out, or its removal
+fn save(idx: usize, peer: Option<Peer>) {
+    match peer {
+        Some(peer) => persist::store(key(idx), &peer.encode()),
+        None => persist::store(key(idx), &[]),
+    }
+}
+
+/// Load the address book from flash, at boot
+pub async fn load() {
+    let mut count = 0;
+    for idx in 0..PEERS_MAX {
+        let mut record = [0; STORED_SIZE];
+        let peer = match settings::get(key(idx), &mut record).await {
+            Ok(Some(0)) | Ok(None) => continue,
+            Ok(Some(len)) => Peer::decode(&record[..len]),
+            Err(e) => {
+                error!("[peers] loading peer {} failed: {:?}", idx, e);
+                continue;
+            }
+        };
+        let Some(peer) = peer else {
+            error!("[peers] invalid stored peer {}", idx);
+            continue;
+        };
+        BOOK.lock(|book| {
+            let mut book = book.borrow_mut();
+            book.peers[idx] = Some(peer);
+            book.seq = book.seq.max(peer.seq.wrapping_add(1));
+        });
+        count += 1;
+    }
+    info!("[peers] {} known peers", count);
+}
+
+/// The preferences of `peer`, if it's in the book
+pub fn find(peer: BdAddr) -> Option<Peer> {
+    BOOK.lock(|book| {
+        let book = book.borrow();
+        book.peers
+            .iter()
+            .flatten()
+            .find(|p| p.address == peer.raw())
+            .copied()
+    })
+}
+
+/// Iterate over the peers, the most recently connected first
+pub fn for_each(mut f: impl FnMut(&Peer)) {
+    let mut peers = BOOK.lock(|book| book.borrow().peers);
+    peers.sort_unstable_by_key(|peer| core::cmp::Reverse(peer.map(|p| p.seq)));
+    peers.iter().flatten().for_each(&mut f);
+}
+
+/// Change `peer`'s entry with `f`, adding it in place of the peer that
+/// connected longest ago if it's new. Returns the entry.
+fn update(peer: BdAddr, f: impl FnOnce(&mut Peer)) -> Peer {
+    let (idx, entry) = BOOK.lock(|book| {
+        let mut book = book.borrow_mut();
+        let idx = match book
+            .peers
+            .iter()
+            .position(|p| p.is_some_and(|p| p.address == peer.raw()))
+        {
+            Some(idx) => idx,
+            None => {
+                let idx = (0..PEERS_MAX)
+                    .min_by_key(|&idx| book.peers[idx].map(|p| p.seq))
+                    .unwrap();
+                let seq = book.seq;
+                book.seq = book.seq.wrapping_add(1);
+                book.peers[idx] = Some(Peer::new(peer.raw().try_into().unwrap(), seq));
+                idx
+            }
+        };
+        let entry = book.peers[idx].as_mut().unwrap();
+        f(entry);
+        (idx, *entry)
+    });
+    save(idx, Some(entry));
+    entry
+}
+
+/// Note that `peer` connected on `conn` and apply its preferences
+pub fn connected(conn: ConnHandle, peer: BdAddr) {
+    let seq = BOOK.lock(|book| {
+        let mut book = book.borrow_mut();
+        let seq = book.seq;
+        book.seq = book.seq.wrapping_add(1);
+        seq
+    });
+    let entry = update(peer, |entry| entry.seq = seq);
+    MUTED.set(conn, entry.muted);
+    if entry.profile.is_some() {
+        latency::pin(conn, entry.profile);
+    }
+    if entry.profile.is_some() || entry.role.is_some() || entry.muted.iter().any(|&h| h != 0) {
+        info!(
+            "[peers] {:?} back: profile {:?} role {:?} muted {:?}",
+            fmt::Bytes(peer.raw()),
+            entry.profile,
+            entry.role,
+            entry.muted
+        );
+    }
+}
+
+/// Whether the peer on `conn` muted the characteristic with value handle
+/// `handle`
+pub fn muted(conn: ConnHandle, handle: u16) -> bool {
+    handle != 0 && MUTED.get(conn).contains(&handle)
+}
+
+/// Set the role of a peer in the book, returning whether it's there
+pub fn set_role(peer: BdAddr, role: Role) -> bool {
+    if find(peer).is_none() {
+        return false;
+    }
+    update(peer, |entry| entry.role = Some(role));
+    info!("[peers] {:?} is {:?}", fmt::Bytes(peer.raw()), role);
+    true
+}
+
+/// Forget `peer`, returning whether it was in the book
+pub fn forget(peer: BdAddr) -> bool {
+    let idx = BOOK.lock(|book| {
+        let mut book = book.borrow_mut();
+        let idx = book
+            .peers
+            .iter()
+            .position(|p| p.is_some_and(|p| p.address == peer.raw()));
+        idx.inspect(|&idx| book.peers[idx] = None)
+    });
+    if let Some(idx) = idx {
+        save(idx, None);
+        info!("[peers] forgot {:?}", fmt::Bytes(peer.raw()));
+    }
+    idx.is_some()
+}
+
+fn request(value: &[u8]) -> (Status, Option<Peer>) {
+    let Some((&op, args)) = value.split_first() else {
+        return (Status::Malformed, None);
+    };
+    if op == LIST {
+        let [index] = *args else {
+            return (Status::Malformed, None);
+        };
+        let mut found = None;
+        let mut at = 0;
+        for_each(|peer| {
+            if at == index as usize {
+                found = Some(*peer);
+            }
+            at += 1;
+        });
+        return (found.map_or(Status::Unknown, |_| Status::Done), found);
+    }
+
+    let Some(address) = args.get(..6) else {
+        return (Status::Malformed, None);
+    };
+    let peer = BdAddr::new(address.try_into().unwrap());
+    match (op, &args[6..]) {
+        (GET, []) => {
+            let found = find(peer);
+            (found.map_or(Status::Unknown, |_| Status::Done), found)
+        }
+        (SET, preferences) => {
+            let mut parsed = Peer::new(peer.raw().try_into().unwrap(), 0);
+            if parsed.decode_preferences(preferences).is_none() {
+                return (Status::Malformed, None);
+            }
+            let entry = update(peer, |entry| {
+                entry.profile = parsed.profile;
+                entry.role = parsed.role;
+                entry.muted = parsed.muted;
+            });
+            info!("[peers] {:?} set", fmt::Bytes(peer.raw()));
+            (Status::Done, Some(entry))
+        }
+        (FORGET, []) => {
+            let status = if forget(peer) {
+                Status::Done
+            } else {
+                Status::Unknown
+            };
+            (status, None)
+        }
+        _ => (Status::Malformed, None),
+    }
+}
+
+/// Take a write to the peers characteristic, returning the response to
+/// notify
+pub fn execute(value: &[u8]) -> [u8; RESPONSE_SIZE] {
+    let (status, peer) = request(value);
+    let mut out = [0; RESPONSE_SIZE];
+    out[0] = value.first().copied().unwrap_or(0);
+    out[1] = status as u8;
+    if let Some(peer) = peer {
+        out[2..].copy_from_slice(&peer.describe());
+    }
+    out
+}
+
+crate::register_command!(
+    PEERS,
+    "peers",
+    "[forget <address>]",
+    "list the known peers, most recent first, or forget one",
+    |args| {
+        match args.opt_str() {
+            Some("forget") => {
+                let peer = beacons::parse_address(args.str("address")?)
+                    .map(BdAddr::new)
+                    .ok_or(crate::console::Error::Invalid("address"))?;
+                args.end()?;
+                if !forget(peer) {
+                    info!("not known");
+                    return Err(crate::console::Error::Failed);
+                }
+            }
+            Some(_) => return Err(crate::console::Error::Invalid("forget")),
+            None => for_each(|peer| {
+                let [a0, a1, a2, a3, a4, a5] = peer.address;
+                info!(
+                    "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} profile {:?} role {:?} muted {:?}",
+                    a5, a4, a3, a2, a1, a0, peer.profile, peer.role, peer.muted
+                );
+            }),
+        }
+        Ok(())
+    }
+);
@@ -0,0 +1,129 @@
+//! Debounced persistence of written values
+//!
+//! Clients drag sliders: a brightness or a duty cycle can be written many
+//! times a second, and every one of them going into [`crate::settings`]
+//! would wear the flash. [`store`] only takes note of the value, and [`run`]
+//! writes it out once it's gone [`DEBOUNCE`] without changing. Only the
+//! last value of a key gets there.
+//!
+//! What's still pending is lost on a power cut. Reboots we do ourselves
+//! [`flush`] first.
+//!
+//! Time and wakeups go through [`crate::runtime`], [`run_on`] takes the
+//! runtime to sleep on.
+
+use core::cell::RefCell;
+
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embassy_time::Instant;
+
+use crate::error;
+use crate::runtime;
+use crate::runtime::Runtime;
+use crate::runtime::Wake;
+use crate::settings;
+
+/// How long a value has to stay put before it's written
+pub const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Longest value
+pub const VALUE_MAX: usize = 32;
+
+/// Max number of keys waiting at once
+const PENDING_MAX: usize = 12;
+
+#[derive(Clone, Copy)]
+struct Pending {
+    key: settings::Key,
+    value: [u8; VALUE_MAX],
+    len: usize,
+    /// When the value was last changed
+    at: Instant,
+}
+
+static PENDING: Mutex<CriticalSectionRawMutex, RefCell<[Option<Pending>; PENDING_MAX]>> =
+    Mutex::new(RefCell::new([None; PENDING_MAX]));
+
+/// A value was stored
+static STORED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Keep `value` for `key` once it's settled, in place of any value still
+/// pending for it
+pub fn store(key: settings::Key, value: &[u8]) {
+    if value.len() > VALUE_MAX {
+        error!("[persist] {} bytes for {:?} is too long", value.len(), key);
+        return;
+    }
+    let mut pending = Pending {
+        key,
+        value: [0; VALUE_MAX],
+        len: value.len(),
+        at: runtime::now(),
+    };
+    pending.value[..value.len()].copy_from_slice(value);
+
+    let queued = PENDING.lock(|slots| {
+        let mut slots = slots.borrow_mut();
+        let slot = match slots.iter().position(|p| p.is_some_and(|p| p.key == key)) {
+            Some(idx) => Some(idx),
+            None => slots.iter().position(|p| p.is_none()),
+        };
+        slot.map(|idx| slots[idx] = Some(pending)).is_some()
+    });
+    if !queued {
+        error!("[persist] too many values pending, {:?} dropped", key);
+        return;
+    }
+    STORED.wake();
+}
+
+/// Take the first value `due` picks out of the pending ones
+fn take(due: impl Fn(&Pending) -> bool) -> Option<Pending> {
+    PENDING.lock(|slots| {
+        slots
+            .borrow_mut()
+            .iter_mut()
+            .find(|p| p.as_ref().is_some_and(&due))
+            .and_then(Option::take)
+    })
+}
+
+async fn write(pending: Pending) {
+    if let Err(e) = settings::set(pending.key, &pending.value[..pending.len]).await {
+        error!("[persist] storing {:?} failed: {:?}", pending.key, e);
+    }
+}
+
+/// Write out everything pending now, before a reboot
+pub async fn flush() {
+    while let Some(pending) = take(|_| true) {
+        write(pending).await;
+    }
+}
+
+/// Write values out as they settle, on embassy
+pub async fn run() -> ! {
+    run_on(&runtime::Embassy).await
+}
+
+/// Write values out as they settle, sleeping on `rt`
+pub async fn run_on(rt: &impl Runtime) -> ! {
+    loop {
+        let now = rt.now();
+        while let Some(pending) = take(|p| now.saturating_duration_since(p.at) >= DEBOUNCE) {
+            write(pending).await;
+        }
+
+        let next = PENDING.lock(|slots| slots.borrow().iter().flatten().map(|p| p.at).min());
+        match next {
+            Some(at) => {
+                select(rt.until(at + DEBOUNCE), STORED.wait()).await;
+            }
+            None => STORED.wait().await,
+        }
+    }
+}
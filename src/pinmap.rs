@@ -0,0 +1,210 @@
+//! Board pin assignments
+//!
+//! Which GPIO does what differs between board revisions, so instead of
+//! being fixed at compile time the assignments come from a pin map kept
+//! in flash. At boot the map is checked against what the RP2040 pins can
+//! actually do, and the detected board revision's defaults are used if
+//! it's missing or invalid.
+//! Provisioning writes a new map, which takes effect on the next boot.
+//!
+//! Encoded as `[version, led, button, buzzer, i2c sda, i2c scl, adc,
+//! expander interrupt, battery]`, with [`UNUSED`] for an optional function
+//! left unconnected. Older maps, from before the expander interrupt
+//! (version 1) or the battery (version 2), are still accepted.
+
+use crate::boardrev;
+use crate::error;
+use crate::info;
+use crate::store;
+
+/// Size of an encoded pin map
+pub const ENCODED_SIZE: usize = 9;
+
+/// Pin number for optional functions that aren't connected
+pub const UNUSED: u8 = 0xff;
+
+const VERSION: u8 = 3;
+
+/// Taken by the CYW43 radio on the Pico W, and the board ID resistor
+const RESERVED: [u8; 5] = [23, 24, 25, 29, boardrev::ID_PIN];
+
+/// Pins the I²C0 block can use
+const I2C0_SDA: [u8; 6] = [0, 4, 8, 12, 16, 20];
+const I2C0_SCL: [u8; 6] = [1, 5, 9, 13, 17, 21];
+
+/// Pins with a free ADC input
+const ADC: [u8; 2] = [26, 28];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PinMap {
+    /// WS2812 data, driven by PIO
+    pub led: u8,
+    /// Active low
+    pub button: u8,
+    pub buzzer: Option<u8>,
+    pub i2c_sda: u8,
+    pub i2c_scl: u8,
+    pub adc: u8,
+    /// Input change interrupt from the relay header's expander, active low
+    pub expander_int: Option<u8>,
+    /// VSYS through a 1:3 divider, on an ADC pin
+    pub battery: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Not a GPIO, or one kept for the radio or the board ID
+    Unavailable(u8),
+    /// Mapped to more than one function
+    Duplicate(u8),
+    /// The pin can't do what it's mapped to
+    Incapable(u8),
+    /// Unknown version or wrong length
+    Malformed,
+}
+
+impl PinMap {
+    /// The original Pico W board
+    pub const DEFAULT: Self = Self {
+        led: 28,
+        button: 15,
+        buzzer: Some(16),
+        i2c_sda: 0,
+        i2c_scl: 1,
+        adc: 26,
+        expander_int: None,
+        battery: None,
+    };
+
+    fn pins(&self) -> impl Iterator<Item = u8> {
+        [self.led, self.button, self.i2c_sda, self.i2c_scl, self.adc]
+            .into_iter()
+            .chain(self.buzzer)
+            .chain(self.expander_int)
+            .chain(self.battery)
+    }
+
+    /// Whether `pin` is a GPIO nothing else has, so it can be handed out
+    /// at runtime
+    pub fn is_free(&self, pin: u8) -> bool {
+        pin <= 29 && !RESERVED.contains(&pin) && !self.pins().any(|p| p == pin)
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        for (i, pin) in self.pins().enumerate() {
+            if pin > 29 || RESERVED.contains(&pin) {
+                return Err(Error::Unavailable(pin));
+            }
+            if self.pins().skip(i + 1).any(|p| p == pin) {
+                return Err(Error::Duplicate(pin));
+            }
+        }
+        if !I2C0_SDA.contains(&self.i2c_sda) {
+            return Err(Error::Incapable(self.i2c_sda));
+        }
+        if !I2C0_SCL.contains(&self.i2c_scl) {
+            return Err(Error::Incapable(self.i2c_scl));
+        }
+        if !ADC.contains(&self.adc) {
+            return Err(Error::Incapable(self.adc));
+        }
+        if let Some(pin) = self.battery.filter(|pin| !ADC.contains(pin)) {
+            return Err(Error::Incapable(pin));
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> [u8; ENCODED_SIZE] {
+        [
+            VERSION,
+            self.led,
+            self.button,
+            self.buzzer.unwrap_or(UNUSED),
+            self.i2c_sda,
+            self.i2c_scl,
+            self.adc,
+            self.expander_int.unwrap_or(UNUSED),
+            self.battery.unwrap_or(UNUSED),
+        ]
+    }
+
+    /// Decode and validate a pin map
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        let (led, button, buzzer, i2c_sda, i2c_scl, adc, expander_int, battery) = match *data {
+            [VERSION, led, button, buzzer, i2c_sda, i2c_scl, adc, expander_int, battery] => (
+                led,
+                button,
+                buzzer,
+                i2c_sda,
+                i2c_scl,
+                adc,
+                expander_int,
+                battery,
+            ),
+            [2, led, button, buzzer, i2c_sda, i2c_scl, adc, expander_int] => (
+                led,
+                button,
+                buzzer,
+                i2c_sda,
+                i2c_scl,
+                adc,
+                expander_int,
+                UNUSED,
+            ),
+            [1, led, button, buzzer, i2c_sda, i2c_scl, adc] => {
+                (led, button, buzzer, i2c_sda, i2c_scl, adc, UNUSED, UNUSED)
+            }
+            _ => return Err(Error::Malformed),
+        };
+        let map = Self {
+            led,
+            button,
+            buzzer: (buzzer != UNUSED).then_some(buzzer),
+            i2c_sda,
+            i2c_scl,
+            adc,
+            expander_int: (expander_int != UNUSED).then_some(expander_int),
+            battery: (battery != UNUSED).then_some(battery),
+        };
+        map.validate()?;
+        Ok(map)
+    }
+}
+
+/// The stored pin map, or `default`. Call once at boot, after
+/// [`crate::flash::init`].
+pub async fn load(default: PinMap) -> PinMap {
+    let mut record = [0; ENCODED_SIZE];
+    let map = match store::load(store::Slot::PINS, &mut record).await {
+        Ok(Some(len)) => PinMap::decode(&record[..len])
+            .inspect_err(|e| error!("[pinmap] stored map rejected: {:?}", e))
+            .ok(),
+        Ok(None) => None,
+        Err(e) => {
+            error!("[pinmap] loading failed: {:?}", e);
+            None
+        }
+    };
+
+    let map = map.unwrap_or(default);
+    info!("[pinmap] {:?}", map);
+    map
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommitError {
+    Invalid(Error),
+    Store(store::Error),
+}
+
+/// Validate and store a pin map write, for the next boot
+pub async fn commit(data: &[u8]) -> Result<PinMap, CommitError> {
+    let map = PinMap::decode(data).map_err(CommitError::Invalid)?;
+    store::commit(store::Slot::PINS, &map.encode())
+        .await
+        .map_err(CommitError::Store)?;
+    Ok(map)
+}
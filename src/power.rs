@@ -0,0 +1,437 @@
+//! Battery-aware degradation
+//!
+//! As the battery runs down, expensive features are given up one step at a
+//! time, in [`Feature`] order: Wi-Fi, then the display, then fast
+//! notifications, then fast advertising. Each step is taken once the charge
+//! drops below its threshold in [`THRESHOLDS`], and undone once it's back
+//! [`RECOVERY`] points above it. While charging everything is restored.
+//! There's no charger status to read, so the charge level rising counts as
+//! charging and falling as not.
+//!
+//! On battery, the device also goes dormant after [`IDLE_MINUTES`] without
+//! a connection: everything is given up, advertising and ADC streaming
+//! included, and the chip sleeps between events. Every [`WAKE_EVERY`] it
+//! wakes for [`WAKE_WINDOW`] of slow advertising, so a central can still
+//! connect, and a press of the button wakes it for good, as does a
+//! connection. Boards without a battery level and charging ones stay
+//! awake. Going dormant and waking are published on the event bus (see
+//! [`crate::events`]) for tasks outside the policy.
+//!
+//! The step we're at is the degradation level, 0 with everything on and
+//! [`DORMANT`] while dormant. The features check [`allows`], and tasks that
+//! have to act on a change, like the network leaving, [`subscribe`] to the
+//! level. The power characteristic is `[level, charge %, charging]`,
+//! notified as the level changes.
+
+use core::cell::Cell;
+use core::future::pending;
+
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::pubsub::Subscriber;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+
+use crate::boardrev;
+use crate::bus::Bus;
+use crate::error;
+use crate::events;
+use crate::events::Event;
+use crate::info;
+
+crate::config_key!(
+    /// Minutes without a connection before going dormant, 0 for never
+    pub IDLE_MINUTES: u32 = "power.idle_minutes",
+    10,
+);
+
+/// Charge in percent below which each step is taken, in [`Feature`] order
+pub const THRESHOLDS: [u8; 4] = [40, 25, 15, 5];
+
+/// Points above its threshold the charge has to get back to for a step to
+/// be undone
+pub const RECOVERY: u8 = 5;
+
+/// Shortest summary window without fast notifications
+pub const SLOW_NOTIFY_WINDOW: Duration = Duration::from_secs(10);
+
+/// Advertising interval without fast advertising
+pub const SLOW_ADV_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// How long dormancy lasts before waking to advertise
+pub const WAKE_EVERY: Duration = Duration::from_secs(5 * 60);
+
+/// How long each wake advertises for
+pub const WAKE_WINDOW: Duration = Duration::from_secs(30);
+
+/// The level while dormant, with everything given up
+pub const DORMANT: u8 = Feature::Advertising as u8;
+
+/// Size of the power characteristic
+pub const STATUS_SIZE: usize = 3;
+
+/// The display's I²C address
+const DISPLAY_ADDRESS: u8 = 0x3c;
+
+// SSD1306 commands, after a command control byte
+const DISPLAY_OFF: u8 = 0xae;
+const DISPLAY_ON: u8 = 0xaf;
+
+const LEVELS_CAP: usize = 1;
+
+/// The network, advertising, the ADC stream, the display and the power
+/// characteristic
+const SUBSCRIBERS_MAX: usize = 5;
+
+/// What gets given up, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Feature {
+    Wifi = 1,
+    Display = 2,
+    /// Summaries notified more often than [`SLOW_NOTIFY_WINDOW`]
+    FastNotifications = 3,
+    /// Advertising more often than [`SLOW_ADV_INTERVAL`]
+    FastAdvertising = 4,
+    /// Only given up while dormant
+    Advertising = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Idle {
+    Awake,
+    /// Dormant, but advertising for a while
+    Waking,
+    Dormant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    /// The level for the charge
+    level: u8,
+    charge: Option<u8>,
+    charging: bool,
+    idle: Idle,
+}
+
+impl State {
+    fn level(&self) -> u8 {
+        match self.idle {
+            Idle::Awake => self.level,
+            Idle::Waking => self.level.max(Feature::FastAdvertising as u8),
+            Idle::Dormant => DORMANT,
+        }
+    }
+
+    /// Whether there's a battery to save
+    fn on_battery(&self) -> bool {
+        self.charge.is_some() && !self.charging
+    }
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, Cell<State>> = Mutex::new(Cell::new(State {
+    level: 0,
+    charge: None,
+    charging: false,
+    idle: Idle::Awake,
+}));
+
+/// Raised by [`wake`]
+static WAKE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The degradation level, as it changes
+pub type LevelSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, u8, LEVELS_CAP, SUBSCRIBERS_MAX, 0>;
+
+static LEVELS: PubSubChannel<CriticalSectionRawMutex, u8, LEVELS_CAP, SUBSCRIBERS_MAX, 0> =
+    PubSubChannel::new();
+
+/// Subscribe to level changes, `None` if all subscriber slots are in use
+pub fn subscribe() -> Option<LevelSubscriber> {
+    LEVELS.subscriber().ok()
+}
+
+/// The degradation level, 0 for none
+pub fn level() -> u8 {
+    STATE.lock(|state| state.get().level())
+}
+
+pub fn idle() -> Idle {
+    STATE.lock(|state| state.get().idle)
+}
+
+pub fn is_dormant() -> bool {
+    idle() != Idle::Awake
+}
+
+/// Wake up for good, as if a central connected. Called when the button is
+/// pressed.
+pub fn wake() {
+    WAKE.signal(());
+}
+
+/// Update the state, publishing the level if it changed
+fn update(f: impl FnOnce(State) -> State) -> (State, State) {
+    let (old, new) = STATE.lock(|state| {
+        let old = state.get();
+        let new = f(old);
+        state.set(new);
+        (old, new)
+    });
+    if new.level() != old.level() {
+        info!("[power] degradation level {}", new.level());
+        LEVELS.immediate_publisher().publish_immediate(new.level());
+    }
+    (old, new)
+}
+
+/// Whether `feature` is on at the current level
+pub fn allows(feature: Feature) -> bool {
+    level() < feature as u8
+}
+
+/// The level for `charge` coming from `level`
+fn target(mut level: u8, charge: u8, charging: bool) -> u8 {
+    if charging {
+        return 0;
+    }
+    while THRESHOLDS
+        .get(level as usize)
+        .is_some_and(|&threshold| charge < threshold)
+    {
+        level += 1;
+    }
+    while level > 0 && charge >= THRESHOLDS[level as usize - 1] + RECOVERY {
+        level -= 1;
+    }
+    level
+}
+
+/// Take a new charge level from [`crate::battery`]
+pub(crate) fn charge_changed(charge: u8) {
+    let (old, new) = update(|old| {
+        let charging = match old.charge {
+            Some(last) if charge != last => charge > last,
+            _ => old.charging,
+        };
+        State {
+            level: target(old.level, charge, charging),
+            charge: Some(charge),
+            charging,
+            ..old
+        }
+    });
+    if new.charging != old.charging {
+        info!(
+            "[power] {}",
+            if new.charging {
+                "charging"
+            } else {
+                "discharging"
+            }
+        );
+    }
+    // plugged in, nothing to save
+    if !new.on_battery() && new.idle != Idle::Awake {
+        WAKE.signal(());
+    }
+}
+
+pub fn status() -> [u8; STATUS_SIZE] {
+    let state = STATE.lock(|state| state.get());
+    [
+        state.level(),
+        state.charge.unwrap_or(0),
+        state.charging as u8,
+    ]
+}
+
+fn set_idle(idle: Idle) {
+    let (old, _) = update(|old| State { idle, ..old });
+    if old.idle == idle {
+        return;
+    }
+    info!("[power] {:?}", idle);
+    match idle {
+        Idle::Dormant if old.idle == Idle::Awake => events::publish(Event::Dormant),
+        Idle::Awake => events::publish(Event::Awake),
+        _ => {}
+    }
+}
+
+fn idle_deadline() -> Instant {
+    match IDLE_MINUTES.get() {
+        0 => Instant::MAX,
+        minutes => Instant::now() + Duration::from_secs(minutes as u64 * 60),
+    }
+}
+
+/// Go dormant while nobody is connected, and wake now and then
+async fn run_idle() -> ! {
+    let Some(mut events) = events::subscribe() else {
+        error!("[power] no event subscriber slot left, staying awake");
+        pending().await
+    };
+    let mut connections = 0u32;
+    let mut deadline = idle_deadline();
+    loop {
+        let wake = select3(events.next_message_pure(), Timer::at(deadline), WAKE.wait()).await;
+        match wake {
+            Either3::First(Event::Connected(..)) => {
+                connections += 1;
+                deadline = Instant::MAX;
+                set_idle(Idle::Awake);
+            }
+            Either3::First(Event::Disconnected(..)) => {
+                connections = connections.saturating_sub(1);
+                if connections == 0 {
+                    deadline = idle_deadline();
+                }
+            }
+            Either3::First(_) => {}
+            Either3::Second(()) => {
+                let on_battery = STATE.lock(|state| state.get().on_battery());
+                match idle() {
+                    Idle::Awake | Idle::Waking if !on_battery => {
+                        set_idle(Idle::Awake);
+                        deadline = idle_deadline();
+                    }
+                    Idle::Awake | Idle::Waking => {
+                        set_idle(Idle::Dormant);
+                        deadline = Instant::now() + WAKE_EVERY;
+                    }
+                    Idle::Dormant => {
+                        set_idle(Idle::Waking);
+                        deadline = Instant::now() + WAKE_WINDOW;
+                    }
+                }
+            }
+            Either3::Third(()) => {
+                set_idle(Idle::Awake);
+                if connections == 0 {
+                    deadline = idle_deadline();
+                }
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn idle_task() -> ! {
+    run_idle().await
+}
+
+/// Wait for the level to change, forever without a subscriber
+pub async fn changed(levels: Option<&mut LevelSubscriber>) -> u8 {
+    match levels {
+        Some(levels) => levels.next_message_pure().await,
+        None => pending().await,
+    }
+}
+
+/// Wait until `feature` is `allowed` or not. Without a subscriber the
+/// feature stays on.
+pub async fn until(feature: Feature, allowed: bool, levels: Option<&mut LevelSubscriber>) {
+    let Some(levels) = levels else {
+        if !allowed {
+            pending::<()>().await;
+        }
+        return;
+    };
+    while allows(feature) != allowed {
+        levels.next_message_pure().await;
+    }
+}
+
+/// The summary window to use instead of `window`
+pub fn notify_window(window: Duration) -> Duration {
+    if allows(Feature::FastNotifications) {
+        window
+    } else {
+        window.max(SLOW_NOTIFY_WINDOW)
+    }
+}
+
+/// The advertising interval to use instead of `interval`
+pub fn adv_interval(interval: Duration) -> Duration {
+    if allows(Feature::FastAdvertising) {
+        interval
+    } else {
+        interval.max(SLOW_ADV_INTERVAL)
+    }
+}
+
+/// Switch the display off and on with the level
+async fn run_display(bus: &Bus) -> ! {
+    let Some(mut levels) = subscribe() else {
+        error!("[power] no level subscriber slot left, the display stays on");
+        pending().await
+    };
+    loop {
+        let on = allows(Feature::Display);
+        let command = if on { DISPLAY_ON } else { DISPLAY_OFF };
+        let sent = bus
+            .lock()
+            .await
+            .write_async(DISPLAY_ADDRESS as u16, [0x00, command])
+            .await;
+        if let Err(e) = sent {
+            error!("[power] switching the display failed: {:?}", e);
+        }
+        until(Feature::Display, !on, Some(&mut levels)).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn display_task(bus: &'static Bus) -> ! {
+    run_display(bus).await
+}
+
+crate::register_module!(MODULE, "power", |resources| {
+    resources.spawner.must_spawn(idle_task());
+    if boardrev::current().display {
+        resources.spawner.must_spawn(display_task(resources.bus))
+    }
+});
+
+crate::register_command!(
+    POWER,
+    "power",
+    "",
+    "show the charge and the degradation level",
+    |args| {
+        args.end()?;
+        let [level, charge, charging] = status();
+        info!(
+            "level {}, {}%{}, {:?}",
+            level,
+            charge,
+            if charging != 0 { ", charging" } else { "" },
+            idle()
+        );
+        for (feature, threshold) in [
+            Feature::Wifi,
+            Feature::Display,
+            Feature::FastNotifications,
+            Feature::FastAdvertising,
+        ]
+        .into_iter()
+        .zip(THRESHOLDS)
+        {
+            info!(
+                "{:?} below {}%: {}",
+                feature,
+                threshold,
+                if allows(feature) { "on" } else { "off" }
+            );
+        }
+        Ok(())
+    }
+);
@@ -0,0 +1,154 @@
+//! Product presets
+//!
+//! The same firmware makes different products: a preset sets the GAP
+//! appearance, what's advertised, the connection parameters asked for and
+//! which optional services there are, all at once, over a base
+//! [`params::Config`]. Names and addresses stay the base's.
+//!
+//! `gap.preset` picks one at runtime, applied the next time the radio
+//! starts. Builds for one product apply theirs to the config they pass
+//! [`crate::blue::run`] instead:
+//!
+//! ```ignore
+//! static CONFIG: Config = Preset::Sensor.apply(Config::DEFAULT).with_name("sensor node");
+//! ```
+
+use embassy_time::Duration;
+use trouble_host::prelude::*;
+
+use crate::adv;
+use crate::config::Text;
+use crate::error;
+use crate::latency;
+use crate::params;
+use crate::params::Services;
+
+crate::config_key!(
+    /// Product preset, any of [`Preset::parse`], empty for the base config
+    pub PRESET: Text = "gap.preset",
+    Text::new(""),
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Preset {
+    /// Environmental and energy readings, broadcast between connections
+    Sensor,
+    /// A remote control, answering quickly and sleeping in between
+    Remote,
+    /// A keyboard over HID over GATT, see [`crate::hid`]
+    Keyboard,
+    /// Broadcasts mostly, with a short connectable window for setup
+    Beacon,
+}
+
+const fn params(min_us: u64, max_us: u64, latency: u16, timeout_s: u64) -> ConnectParams {
+    ConnectParams {
+        min_connection_interval: Duration::from_micros(min_us),
+        max_connection_interval: Duration::from_micros(max_us),
+        max_latency: latency,
+        event_length: Duration::from_ticks(0),
+        supervision_timeout: Duration::from_secs(timeout_s),
+    }
+}
+
+/// Readings come every few seconds, nothing is in a hurry
+const SENSOR_LATENCY: latency::Policy = latency::Policy {
+    idle_after: Duration::from_secs(10),
+    active: params(50_000, 100_000, 0, 6),
+    idle: params(500_000, 1_000_000, 4, 20),
+    ..latency::Policy::DEFAULT
+};
+
+/// Presses go out right away, idle links skip most events
+const REMOTE_LATENCY: latency::Policy = latency::Policy {
+    idle_after: Duration::from_secs(5),
+    active: params(15_000, 30_000, 0, 4),
+    idle: params(30_000, 60_000, 30, 6),
+    ..latency::Policy::DEFAULT
+};
+
+/// Typing wants short intervals, and keeps its latency low between keys
+const KEYBOARD_LATENCY: latency::Policy = latency::Policy {
+    idle_after: Duration::from_secs(60),
+    active: params(7_500, 15_000, 0, 4),
+    idle: params(15_000, 30_000, 30, 6),
+    ..latency::Policy::DEFAULT
+};
+
+impl Preset {
+    /// "sensor", "remote", "keyboard" or "beacon"
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sensor" => Some(Self::Sensor),
+            "remote" => Some(Self::Remote),
+            "keyboard" => Some(Self::Keyboard),
+            "beacon" => Some(Self::Beacon),
+            _ => None,
+        }
+    }
+
+    /// `base` made into this kind of product
+    pub const fn apply(self, base: params::Config) -> params::Config {
+        match self {
+            Self::Sensor => base
+                // generic sensor
+                .with_appearance(0x0540)
+                // environmental sensing, battery
+                .with_service_uuids(&[0x181a, 0x180f])
+                .with_latency(&SENSOR_LATENCY)
+                .with_services(Services {
+                    environment: true,
+                    meter: true,
+                    distance: true,
+                    level: true,
+                    weather: true,
+                    wifi: true,
+                    ..Services::NONE
+                }),
+            Self::Remote => base
+                // generic remote control
+                .with_appearance(0x0180)
+                .with_service_uuids(&[0x180f])
+                .with_latency(&REMOTE_LATENCY)
+                .with_schedule(adv::Schedule::connectable_only())
+                .with_services(Services::NONE)
+                .with_connections_max(1),
+            Self::Keyboard => base
+                // keyboard
+                .with_appearance(0x03c1)
+                // HID, battery
+                .with_service_uuids(&[0x1812, 0x180f])
+                .with_latency(&KEYBOARD_LATENCY)
+                .with_schedule(adv::Schedule::connectable_only())
+                .with_services(Services {
+                    hid: true,
+                    ..Services::NONE
+                })
+                .with_connections_max(1),
+            Self::Beacon => base
+                // generic tag
+                .with_appearance(0x0200)
+                .with_service_uuids(&[])
+                .with_adv_interval(Duration::from_millis(1000), Duration::from_millis(1000))
+                .with_schedule(adv::Schedule::with_duty_cycle(Duration::from_secs(10), 10))
+                .with_services(Services::NONE)
+                .with_connections_max(1),
+        }
+    }
+}
+
+/// `base` with the preset `gap.preset` names, if any
+pub fn configured(base: params::Config) -> params::Config {
+    let name = PRESET.get();
+    if name.is_empty() {
+        return base;
+    }
+    match Preset::parse(name.as_str()) {
+        Some(preset) => preset.apply(base),
+        None => {
+            error!("[preset] unknown preset {}", name.as_str());
+            base
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Wi-Fi provisioning over BLE
+//!
+//! A setup app writes the network name and passphrase to the provisioning
+//! service, which only stages them, then writes [`Command::Connect`] to its
+//! control point. That stores them as `wifi.ssid` and `wifi.password` (see
+//! [`crate::net`]) and has the network side leave whatever it's on and join
+//! with them right away. A passphrase that wasn't staged means an open
+//! network, and without a staged name the stored credentials are joined
+//! again. [`Command::Forget`] clears them and leaves. Like the other setup
+//! characteristics, these only take writes in maintenance mode.
+//!
+//! The status characteristic follows the join and is notified as it goes:
+//!
+//! | bytes | content                                   |
+//! |-------|-------------------------------------------|
+//! | 0     | [`State`]                                 |
+//! | 1..5  | IPv4 address once up, zero otherwise      |
+
+use core::cell::Cell;
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use trouble_host::prelude::*;
+
+use crate::blue::gen_uuid;
+use crate::config;
+use crate::config::Text;
+use crate::error;
+use crate::gattcheck;
+use crate::info;
+use crate::modules;
+use crate::net;
+use crate::roles;
+use crate::writes;
+
+/// Size of the status characteristic
+pub const STATUS_SIZE: usize = 5;
+
+/// Longest name or passphrase
+pub const VALUE_MAX: usize = config::VALUE_MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum State {
+    /// No network to join
+    Unconfigured = 0,
+    Joining = 1,
+    /// The last attempt failed, another follows
+    Failed = 2,
+    /// Joined and given an address
+    Up = 3,
+    /// Left the network to save power
+    Off = 4,
+}
+
+/// What the control point takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// Store the staged credentials and join with them
+    Connect,
+    /// Clear the stored credentials and leave
+    Forget,
+}
+
+impl Command {
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        match value {
+            [0x01] => Some(Self::Connect),
+            [0x02] => Some(Self::Forget),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Staged {
+    ssid: Option<Text>,
+    password: Option<Text>,
+}
+
+static STAGED: Mutex<CriticalSectionRawMutex, RefCell<Staged>> = Mutex::new(RefCell::new(Staged {
+    ssid: None,
+    password: None,
+}));
+
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<(State, [u8; 4])>> =
+    Mutex::new(Cell::new((State::Unconfigured, [0; 4])));
+
+/// The network side is to join again
+static REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn text(value: &[u8]) -> Result<Text, config::Error> {
+    if value.len() > VALUE_MAX {
+        return Err(config::Error::Invalid);
+    }
+    let text = core::str::from_utf8(value).map_err(|_| config::Error::Invalid)?;
+    Ok(Text::new(text))
+}
+
+/// Stage the name of the network to join
+fn stage_ssid(value: &[u8]) -> Result<(), config::Error> {
+    let ssid = text(value)?;
+    STAGED.lock(|staged| staged.borrow_mut().ssid = Some(ssid));
+    Ok(())
+}
+
+/// Stage its passphrase
+fn stage_password(value: &[u8]) -> Result<(), config::Error> {
+    let password = text(value)?;
+    STAGED.lock(|staged| staged.borrow_mut().password = Some(password));
+    Ok(())
+}
+
+/// Run a control point command
+fn execute(command: Command) -> Result<(), config::Error> {
+    let staged = STAGED.lock(|staged| {
+        staged.replace(Staged {
+            ssid: None,
+            password: None,
+        })
+    });
+    match command {
+        Command::Connect => {
+            if let Some(ssid) = staged.ssid {
+                info!("[provision] joining {}", ssid.as_str());
+                net::SSID.set(ssid)?;
+                net::PASSWORD.set(staged.password.unwrap_or_default())?;
+            }
+        }
+        Command::Forget => {
+            info!("[provision] forgetting the network");
+            config::reset(net::SSID.name)?;
+            config::reset(net::PASSWORD.name)?;
+        }
+    }
+    REQUESTED.signal(());
+    Ok(())
+}
+
+/// Wait for a command to join again. Only meant for [`crate::net`].
+pub(crate) async fn requested() {
+    REQUESTED.wait().await
+}
+
+/// Drop a command that came in before the join now starting
+pub(crate) fn clear_request() {
+    REQUESTED.reset();
+}
+
+/// Report how the join is going, with the address once up
+pub(crate) fn set_state(state: State, address: Option<[u8; 4]>) {
+    STATUS.lock(|status| status.set((state, address.unwrap_or_default())));
+    modules::notify(STATUS_UUID, &status());
+}
+
+/// The status characteristic
+fn status() -> [u8; STATUS_SIZE] {
+    let (state, address) = STATUS.lock(|status| status.get());
+    let mut out = [0; STATUS_SIZE];
+    out[0] = state as u8;
+    out[1..5].copy_from_slice(&address);
+    out
+}
+
+const SERVICE_UUID: Uuid = gen_uuid("wifi provision");
+const SSID_UUID: Uuid = gen_uuid("wifi ssid");
+const PASSWORD_UUID: Uuid = gen_uuid("wifi passphrase");
+const CONTROL_UUID: Uuid = gen_uuid("wifi control");
+const STATUS_UUID: Uuid = gen_uuid("wifi status");
+const _: () = gattcheck::assert_distinct(&[&[SSID_UUID, PASSWORD_UUID, CONTROL_UUID, STATUS_UUID]]);
+const _: () = gattcheck::assert_fit(&[VALUE_MAX, STATUS_SIZE], writes::READ_MAX);
+
+fn write_ssid(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    stage_ssid(value).map_err(|_| writes::AttError::ValueNotAllowed)
+}
+
+fn write_password(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    stage_password(value).map_err(|_| writes::AttError::ValueNotAllowed)
+}
+
+fn write_control(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let command = Command::parse(value).ok_or(writes::AttError::ValueNotAllowed)?;
+    execute(command).map_err(|e| {
+        error!("[provision] {:?} failed: {:?}", command, e);
+        writes::AttError::ValueNotAllowed
+    })
+}
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    use CharacteristicProp::Notify;
+    use CharacteristicProp::Read;
+    use CharacteristicProp::Write;
+    let setup = writes::Spec::new()
+        .with_access(roles::Access::Configure)
+        .in_maintenance();
+    svc.add(
+        SSID_UUID,
+        &[Read, Write],
+        VALUE_MAX,
+        setup
+            .with_read(|| writes::Value::new(net::SSID.get().as_str().as_bytes()))
+            .with_write(write_ssid),
+    );
+    // staged, never read back
+    svc.add(
+        PASSWORD_UUID,
+        &[Write],
+        VALUE_MAX,
+        setup
+            .with_read(|| writes::Value::new(&[]))
+            .with_write(write_password),
+    );
+    svc.add(
+        CONTROL_UUID,
+        &[Write],
+        1,
+        setup.with_write(write_control).as_event(),
+    );
+    svc.add(
+        STATUS_UUID,
+        &[Read, Notify],
+        STATUS_SIZE,
+        writes::Spec::new().with_read(|| writes::Value::new(&status())),
+    );
+}
+
+// the joining is done by crate::net, which has the radio
+crate::register_module!(
+    MODULE,
+    "provision",
+    |_| {},
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |services| services.wifi,
+        build: service,
+    },
+);
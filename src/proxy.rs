@@ -0,0 +1,279 @@
+//! GATT proxy
+//!
+//! Re-exposes characteristics of the peripheral [`crate::central`] connects
+//! to, for clients too far away to reach it themselves. Setting
+//! `proxy.char0` to `proxy.char3` to 16-bit characteristic UUIDs of the
+//! target gives each of them a slot with its own characteristic when the
+//! radio starts. The target is picked with `central.name` and
+//! `central.service` as for bridging, `central.notify` isn't needed.
+//!
+//! A slot's value is `[len, value]`, the remote value being up to
+//! [`VALUE_MAX`] bytes, and all zero while there's no link. It's read once
+//! the link is up and kept current from notifications, or read again every
+//! [`POLL_INTERVAL`] for characteristics that don't notify. Writing
+//! `[len, value]` to a slot writes the value to the target.
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embassy_futures::select::select;
+use embassy_futures::select::select_array;
+use embassy_futures::select::Either;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::arbiter::Priority;
+use crate::blue::gen_uuid;
+use crate::central;
+use crate::config;
+use crate::error;
+use crate::gattcheck;
+use crate::info;
+use crate::modules;
+use crate::roles;
+use crate::writes;
+
+crate::config_key!(
+    /// 16-bit UUID of a target characteristic to proxy, 0 for none
+    pub CHAR0: u32 = "proxy.char0",
+    0,
+);
+
+crate::config_key!(
+    pub CHAR1: u32 = "proxy.char1",
+    0,
+);
+
+crate::config_key!(
+    pub CHAR2: u32 = "proxy.char2",
+    0,
+);
+
+crate::config_key!(
+    pub CHAR3: u32 = "proxy.char3",
+    0,
+);
+
+/// Max number of proxied characteristics
+pub const SLOTS_MAX: usize = 4;
+
+static CHAR_KEYS: [&config::Key<u32>; SLOTS_MAX] = [&CHAR0, &CHAR1, &CHAR2, &CHAR3];
+
+/// Longest remote value we pass on
+pub const VALUE_MAX: usize = 32;
+
+/// Size of a slot characteristic
+pub const SLOT_SIZE: usize = 1 + VALUE_MAX;
+
+/// How often characteristics that don't notify are read
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Writes queued for the target
+const WRITES_CAP: usize = 4;
+
+static VALUES: Mutex<CriticalSectionRawMutex, RefCell<[[u8; SLOT_SIZE]; SLOTS_MAX]>> =
+    Mutex::new(RefCell::new([[0; SLOT_SIZE]; SLOTS_MAX]));
+
+static WRITES: Channel<CriticalSectionRawMutex, (usize, [u8; SLOT_SIZE]), WRITES_CAP> =
+    Channel::new();
+
+/// The target characteristic of `slot`, `None` if it's unset
+pub fn remote(slot: usize) -> Option<Uuid> {
+    match CHAR_KEYS.get(slot)?.get() {
+        0 => None,
+        uuid => Some(Uuid::new_short(uuid as u16)),
+    }
+}
+
+/// Whether a name from [`config::subscribe`] is one of the slot keys
+pub fn is_key(name: &str) -> bool {
+    CHAR_KEYS.iter().any(|key| key.is(name))
+}
+
+/// Whether any slot is set
+pub fn enabled() -> bool {
+    (0..SLOTS_MAX).any(|slot| remote(slot).is_some())
+}
+
+/// The current value of `slot`
+fn encode(slot: usize) -> [u8; SLOT_SIZE] {
+    VALUES.lock(|values| values.borrow()[slot])
+}
+
+fn publish(slot: usize, value: &[u8]) {
+    let len = value.len().min(VALUE_MAX);
+    let mut frame = [0; SLOT_SIZE];
+    frame[0] = len as u8;
+    frame[1..1 + len].copy_from_slice(&value[..len]);
+    VALUES.lock(|values| values.borrow_mut()[slot] = frame);
+    modules::notify(SLOT_UUIDS[slot], &frame);
+}
+
+/// Forget the values of the last link
+pub(crate) fn clear() {
+    VALUES.lock(|values| *values.borrow_mut() = [[0; SLOT_SIZE]; SLOTS_MAX]);
+    for uuid in SLOT_UUIDS {
+        modules::notify(uuid, &[0; SLOT_SIZE]);
+    }
+}
+
+/// Queue a `[len, value]` written to `slot` for the target. `false` if it's
+/// malformed or the queue is full.
+fn write(slot: usize, frame: &[u8]) -> bool {
+    let Some((&len, value)) = frame.split_first() else {
+        return false;
+    };
+    let Some(value) = value.get(..len as usize).filter(|v| v.len() <= VALUE_MAX) else {
+        return false;
+    };
+    let mut queued = [0; SLOT_SIZE];
+    queued[0] = len;
+    queued[1..1 + value.len()].copy_from_slice(value);
+    WRITES.try_send((slot, queued)).is_ok()
+}
+
+/// Keep `slot` current from `remote`
+async fn follow<C: Controller>(
+    client: &central::Client<'_, C>,
+    slot: usize,
+    remote: Option<Characteristic>,
+) -> Result<Infallible, BleHostError<C::Error>> {
+    let Some(remote) = remote else {
+        return core::future::pending().await;
+    };
+    let mut value = [0; VALUE_MAX];
+    let read = client.read_characteristic(&remote, &mut value);
+    let len = central::exclusive(Priority::Normal, read).await?;
+    publish(slot, &value[..len]);
+
+    match central::exclusive(Priority::Normal, client.subscribe(&remote, false)).await {
+        Ok(mut listener) => loop {
+            let notification = listener.next().await;
+            publish(slot, notification.as_ref());
+        },
+        Err(_) => loop {
+            Timer::after(POLL_INTERVAL).await;
+            let read = client.read_characteristic(&remote, &mut value);
+            let len = central::exclusive(Priority::Background, read).await?;
+            publish(slot, &value[..len]);
+        },
+    }
+}
+
+/// Pass the writes to the slots on to the target
+async fn forward_writes<C: Controller>(
+    client: &central::Client<'_, C>,
+    remotes: &[Option<Characteristic>; SLOTS_MAX],
+) -> Result<Infallible, BleHostError<C::Error>> {
+    // left from before the link
+    while WRITES.try_receive().is_ok() {}
+    loop {
+        let (slot, frame) = WRITES.receive().await;
+        let Some(remote) = remotes[slot] else {
+            error!("[proxy] slot {} isn't on the target", slot);
+            continue;
+        };
+        let value = &frame[1..1 + frame[0] as usize];
+        let write = client.write_characteristic(&remote, value);
+        central::exclusive(Priority::Interactive, write).await?;
+    }
+}
+
+/// Proxy the set slots found in `services` of the target, until the link
+/// fails
+pub(crate) async fn relay<C: Controller>(
+    client: &central::Client<'_, C>,
+    services: &[ServiceHandle],
+) -> Result<Infallible, BleHostError<C::Error>> {
+    let mut remotes = [None; SLOTS_MAX];
+    for (slot, found) in remotes.iter_mut().enumerate() {
+        let Some(uuid) = remote(slot) else {
+            continue;
+        };
+        for service in services {
+            let lookup = client.characteristic_by_uuid(service, &uuid);
+            if let Ok(c) = central::exclusive(Priority::Normal, lookup).await {
+                *found = Some(c);
+                break;
+            }
+        }
+        match found {
+            Some(_) => info!("[proxy] slot {} is {:?}", slot, uuid),
+            None => info!("[proxy] target lacks {:?}", uuid),
+        }
+    }
+
+    let slots: [_; SLOTS_MAX] = core::array::from_fn(|slot| follow(client, slot, remotes[slot]));
+    match select(select_array(slots), forward_writes(client, &remotes)).await {
+        Either::First((result, _)) => result,
+        Either::Second(result) => result,
+    }
+}
+
+const SERVICE_UUID: Uuid = gen_uuid("proxy");
+const SLOT_UUIDS: [Uuid; SLOTS_MAX] = [
+    gen_uuid("proxy slot 0"),
+    gen_uuid("proxy slot 1"),
+    gen_uuid("proxy slot 2"),
+    gen_uuid("proxy slot 3"),
+];
+const _: () = gattcheck::assert_distinct(&[&SLOT_UUIDS]);
+const _: () = gattcheck::assert_fit(&[SLOT_SIZE], writes::READ_MAX);
+
+fn write_slot<const SLOT: usize>(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    write(SLOT, value)
+        .then_some(())
+        .ok_or(writes::AttError::ValueNotAllowed)
+}
+
+fn read_slot<const SLOT: usize>() -> writes::Value {
+    writes::Value::new(&encode(SLOT))
+}
+
+const SLOT_WRITES: [writes::Handler; SLOTS_MAX] = [
+    write_slot::<0>,
+    write_slot::<1>,
+    write_slot::<2>,
+    write_slot::<3>,
+];
+const SLOT_READS: [writes::Reader; SLOTS_MAX] = [
+    read_slot::<0>,
+    read_slot::<1>,
+    read_slot::<2>,
+    read_slot::<3>,
+];
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    let props = [
+        CharacteristicProp::Read,
+        CharacteristicProp::Write,
+        CharacteristicProp::Notify,
+    ];
+    let spec = writes::Spec::new().with_access(roles::Access::Configure);
+    // only the slots set when the radio starts get one
+    for slot in (0..SLOTS_MAX).filter(|&slot| remote(slot).is_some()) {
+        svc.add(
+            SLOT_UUIDS[slot],
+            &props,
+            SLOT_SIZE,
+            spec.with_read(SLOT_READS[slot])
+                .with_write(SLOT_WRITES[slot]),
+        );
+    }
+}
+
+// the slots are followed from crate::central, which has the link
+crate::register_module!(
+    MODULE,
+    "proxy",
+    |_| {},
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |_| enabled(),
+        build: service,
+    },
+);
@@ -0,0 +1,224 @@
+//! Radio firmware
+//!
+//! The CYW43 needs its firmware, its CLM blob and the Bluetooth firmware
+//! loaded every time the radio starts. Those are built in, and builds with
+//! the `dfu` feature can also take a package of newer ones over the DFU
+//! characteristics (see [`crate::dfu`]), kept in the
+//! [`crate::partitions::RADIO`] partition. Other builds always use the built-in blobs.
+//!
+//! A package is `[magic, version, firmware len, clm len, bluetooth len,
+//! crc]` followed by the three blobs back to back, numbers u32 little
+//! endian, the CRC-32 over the blobs. Each time the radio starts,
+//! [`select`] hands out the package if it's intact, the built-in blobs
+//! otherwise. A package the radio doesn't come up with within
+//! [`INIT_TIMEOUT`] is [`reject`]ed, and the built-in blobs are used until
+//! another one is uploaded or we reboot.
+//!
+//! The info characteristic is `[source, version: u32, stored version:
+//! u32]`: what the radio runs, 0 for built in and 1 for a package, the
+//! package's version if so, and the version of the intact package in
+//! flash, 0 if there's none.
+
+#[cfg(feature = "dfu")]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+#[cfg(feature = "dfu")]
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_time::Duration;
+
+#[cfg(feature = "dfu")]
+use crate::error;
+#[cfg(feature = "dfu")]
+use crate::flash;
+use crate::info;
+#[cfg(feature = "dfu")]
+use crate::integrity;
+#[cfg(feature = "dfu")]
+use crate::partitions;
+
+/// How long the radio gets to come up with its firmware loaded
+pub const INIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of the info characteristic
+pub const INFO_SIZE: usize = 9;
+
+#[cfg(feature = "dfu")]
+const MAGIC: u32 = 0x5746_5243; // "CRFW"
+
+/// magic, version, three lengths, CRC
+#[cfg(feature = "dfu")]
+const HEADER_SIZE: usize = 24;
+
+/// Where the blobs come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Source {
+    BuiltIn,
+    /// A package, with its version
+    Package(u32),
+}
+
+/// What the radio is started with
+#[derive(Debug, Clone, Copy)]
+pub struct Blobs {
+    pub firmware: &'static [u8],
+    pub clm: &'static [u8],
+    pub bluetooth: &'static [u8],
+    pub source: Source,
+}
+
+/// The source the radio was last started with, as in the info
+/// characteristic
+static RUNNING: AtomicU8 = AtomicU8::new(0);
+
+static RUNNING_VERSION: AtomicU32 = AtomicU32::new(0);
+
+/// Version of the intact package in flash, as of the last check
+static STORED_VERSION: AtomicU32 = AtomicU32::new(0);
+
+/// Set once the package in flash failed to bring the radio up
+#[cfg(feature = "dfu")]
+static REJECTED: AtomicBool = AtomicBool::new(false);
+
+pub fn info() -> [u8; INFO_SIZE] {
+    let mut out = [0; INFO_SIZE];
+    out[0] = RUNNING.load(Ordering::Relaxed);
+    out[1..5].copy_from_slice(&RUNNING_VERSION.load(Ordering::Relaxed).to_le_bytes());
+    out[5..].copy_from_slice(&STORED_VERSION.load(Ordering::Relaxed).to_le_bytes());
+    out
+}
+
+fn started(source: Source) {
+    let (kind, version) = match source {
+        Source::BuiltIn => (0, 0),
+        Source::Package(version) => (1, version),
+    };
+    RUNNING.store(kind, Ordering::Relaxed);
+    RUNNING_VERSION.store(version, Ordering::Relaxed);
+    info!("[radiofw] starting the radio with {:?}", source);
+}
+
+/// Size of the radio partition
+#[cfg(feature = "dfu")]
+pub fn capacity() -> usize {
+    partitions::RADIO.len()
+}
+
+#[cfg(feature = "dfu")]
+fn word(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+/// The package in flash, if it's intact
+#[cfg(feature = "dfu")]
+fn stored() -> Option<Blobs> {
+    // only DFU updates write the partition. They come over BLE, which
+    // doesn't run while the radio starts, the only time the blobs are read.
+    let (header, rest) = partitions::RADIO.mapped().split_at(HEADER_SIZE);
+    if word(header, 0) != MAGIC {
+        return None;
+    }
+    let lens = [word(header, 8), word(header, 12), word(header, 16)];
+    let total = lens
+        .iter()
+        .try_fold(0usize, |n, &len| n.checked_add(len as usize))?;
+    let blobs = rest.get(..total)?;
+    if integrity::crc32(blobs) != word(header, 20) {
+        return None;
+    }
+    let (firmware, rest) = blobs.split_at(lens[0] as usize);
+    let (clm, bluetooth) = rest.split_at(lens[1] as usize);
+    Some(Blobs {
+        firmware,
+        clm,
+        bluetooth,
+        source: Source::Package(word(header, 4)),
+    })
+}
+
+/// Check the package in flash, returning it if it's intact
+#[cfg(feature = "dfu")]
+fn check() -> Option<Blobs> {
+    let package = stored();
+    let version = match package.map(|p| p.source) {
+        Some(Source::Package(version)) => version,
+        _ => 0,
+    };
+    STORED_VERSION.store(version, Ordering::Relaxed);
+    package
+}
+
+/// The blobs to start the radio with
+#[cfg(not(feature = "dfu"))]
+pub fn select(built_in: &Blobs) -> Blobs {
+    started(built_in.source);
+    *built_in
+}
+
+/// The blobs to start the radio with: the package in flash if it's intact
+/// and wasn't rejected, `built_in` if not
+#[cfg(feature = "dfu")]
+pub fn select(built_in: &Blobs) -> Blobs {
+    let blobs = match check() {
+        Some(package) if !REJECTED.load(Ordering::Relaxed) => package,
+        _ => *built_in,
+    };
+    started(blobs.source);
+    blobs
+}
+
+/// Stop using a package the radio didn't come up with. Nothing to do
+/// without packages.
+#[cfg(not(feature = "dfu"))]
+pub fn reject(_: Source) {}
+
+/// Stop using the package the radio didn't come up with
+#[cfg(feature = "dfu")]
+pub fn reject(source: Source) {
+    if let Source::Package(version) = source {
+        error!("[radiofw] package {} rejected, back to built in", version);
+        REJECTED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Write `sector`, a whole one, at `offset` in the partition
+#[cfg(feature = "dfu")]
+pub async fn write(offset: u32, sector: &[u8; ERASE_SIZE]) -> Result<(), flash::Error> {
+    if offset == 0 {
+        // the package is being replaced
+        STORED_VERSION.store(0, Ordering::Relaxed);
+    }
+    let at = partitions::RADIO.start + offset;
+    flash::execute(flash::Op::Erase {
+        from: at,
+        to: at + ERASE_SIZE as u32,
+    })
+    .await?;
+    flash::write(at, sector).await
+}
+
+/// Read from the partition at `offset`
+#[cfg(feature = "dfu")]
+pub async fn read(offset: u32, buf: &mut [u8]) -> Result<(), flash::Error> {
+    flash::with_flash(|driver| driver.blocking_read(partitions::RADIO.start + offset, buf))
+        .await
+        .map_err(flash::Error::Flash)
+}
+
+/// Check a package that was just written, and let the radio have it the
+/// next time it starts. `false` if it isn't intact.
+#[cfg(feature = "dfu")]
+pub fn installed() -> bool {
+    let Some(package) = check() else {
+        return false;
+    };
+    info!(
+        "[radiofw] {:?} stored, used once the radio restarts",
+        package.source
+    );
+    REJECTED.store(false, Ordering::Relaxed);
+    true
+}
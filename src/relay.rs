@@ -0,0 +1,577 @@
+//! External relay bank
+//!
+//! Eight relays behind an I²C expander (see [`crate::expander`]), on the
+//! shared bus (see [`crate::bus`]). Each channel has its own
+//! characteristic. A write asks for the channel to switch, and [`run`]
+//! switches it as soon as the rules allow:
+//!
+//! - Interlocks: channels in the same interlock group may not be on at the
+//!   same time. Asking for a second one is refused, and a channel asked for
+//!   while another one is still on, waiting out its minimum on time after
+//!   being switched off, waits for it (break before make).
+//! - Minimum times: a channel stays on for at least its minimum on time,
+//!   and off for at least its minimum off time, before switching again.
+//!
+//! [`all_off`] is the fail-safe, run when the last client disconnects or
+//! the BLE stack stops. It ignores the minimum times. The panic handler switches
+//! the bank off too, writing [`ALL_OFF`] itself.
+//!
+//! The same task reads the inputs of expanders that have them, and the
+//! inputs characteristic is notified with every change.
+//!
+//! The interlocks and minimum times are kept in flash and encoded as
+//! `[version, group masks..., per channel: min on ms: u16, min off ms: u16]`
+//! with the times little endian.
+
+use core::cell::RefCell;
+use core::future::pending;
+
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3;
+use embassy_rp::gpio::Input;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use trouble_host::prelude::*;
+
+use crate::blue::gen_uuid;
+use crate::blue::gen_uuids;
+use crate::bus::Bus;
+use crate::console;
+use crate::error;
+use crate::expander;
+use crate::expander::Expander;
+use crate::fault;
+use crate::gattcheck;
+use crate::info;
+use crate::modules;
+use crate::monitor;
+use crate::roles;
+#[cfg(feature = "rolling")]
+use crate::rolling;
+use crate::store;
+use crate::writes;
+
+pub const CHANNELS: usize = 8;
+
+/// Characteristic names, by channel
+pub const NAMES: [&str; CHANNELS] = [
+    "relay 0", "relay 1", "relay 2", "relay 3", "relay 4", "relay 5", "relay 6", "relay 7",
+];
+
+/// Expander output with every relay off. The usual opto-isolated boards
+/// switch a relay on by pulling its input low.
+pub const ALL_OFF: u8 = 0xff;
+
+/// Max number of interlock groups
+pub const GROUPS: usize = 4;
+
+/// Size of the interlocks characteristic, a channel mask per group
+pub const INTERLOCKS_SIZE: usize = GROUPS;
+
+/// Size of a timing write: channel, min on ms, min off ms
+pub const TIMING_SIZE: usize = 5;
+
+/// Channel in a timing write that sets every channel
+pub const ALL_CHANNELS: u8 = 0xff;
+
+/// Size of the stored configuration
+const ENCODED_SIZE: usize = 1 + GROUPS + CHANNELS * 4;
+
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No such channel
+    Channel(usize),
+    /// Another channel in an interlock group with this one is asked for
+    Interlocked(usize),
+    /// New interlocks that channels asked for right now would break
+    Conflict,
+    Malformed,
+    Store(store::Error),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Channel {
+    wanted: bool,
+    on: bool,
+    /// When the channel last switched
+    switched: Instant,
+    min_on: Duration,
+    min_off: Duration,
+}
+
+struct Bank {
+    channels: [Channel; CHANNELS],
+    /// Channel masks, at most one channel of each may be on
+    groups: [u8; GROUPS],
+    /// Switch everything off now, minimum times or not
+    fail_safe: bool,
+}
+
+impl Bank {
+    /// Channels sharing an interlock group with `channel`, not counting
+    /// `channel` itself
+    fn interlocked(&self, channel: usize) -> u8 {
+        let bit = 1 << channel;
+        self.groups
+            .iter()
+            .filter(|group| *group & bit != 0)
+            .fold(0, |mask, group| mask | group)
+            & !bit
+    }
+
+    fn mask(&self, f: impl Fn(&Channel) -> bool) -> u8 {
+        (0..CHANNELS)
+            .filter(|&i| f(&self.channels[i]))
+            .fold(0, |mask, i| mask | 1 << i)
+    }
+
+    /// Switch whatever can be switched at `now`. Returns the relays that
+    /// are on, and when something waiting on a minimum time can go next.
+    fn step(&mut self, now: Instant) -> (u8, Option<Instant>) {
+        let fail_safe = core::mem::take(&mut self.fail_safe);
+        let mut next: Option<Instant> = None;
+
+        // offs first, so an interlocked channel can come on in the same step
+        for channel in self.channels.iter_mut().filter(|c| c.on && !c.wanted) {
+            let at = channel.switched + channel.min_on;
+            if fail_safe || now >= at {
+                channel.on = false;
+                channel.switched = now;
+            } else {
+                next = Some(next.map_or(at, |n| n.min(at)));
+            }
+        }
+
+        let mut on = self.mask(|c| c.on);
+        for i in 0..CHANNELS {
+            let channel = &self.channels[i];
+            if channel.on || !channel.wanted || on & self.interlocked(i) != 0 {
+                continue;
+            }
+            let at = channel.switched + channel.min_off;
+            if now >= at {
+                let channel = &mut self.channels[i];
+                channel.on = true;
+                channel.switched = now;
+                on |= 1 << i;
+            } else {
+                next = Some(next.map_or(at, |n| n.min(at)));
+            }
+        }
+
+        (on, next)
+    }
+
+    fn encode(&self) -> [u8; ENCODED_SIZE] {
+        let mut out = [0; ENCODED_SIZE];
+        out[0] = VERSION;
+        out[1..1 + GROUPS].copy_from_slice(&self.groups);
+        for (channel, entry) in self
+            .channels
+            .iter()
+            .zip(out[1 + GROUPS..].chunks_exact_mut(4))
+        {
+            entry[..2].copy_from_slice(&millis(channel.min_on).to_le_bytes());
+            entry[2..].copy_from_slice(&millis(channel.min_off).to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Option<()> {
+        if data.len() != ENCODED_SIZE || data[0] != VERSION {
+            return None;
+        }
+        self.groups.copy_from_slice(&data[1..1 + GROUPS]);
+        for (channel, entry) in self
+            .channels
+            .iter_mut()
+            .zip(data[1 + GROUPS..].chunks_exact(4))
+        {
+            channel.min_on = Duration::from_millis(u16::from_le_bytes([entry[0], entry[1]]) as u64);
+            channel.min_off =
+                Duration::from_millis(u16::from_le_bytes([entry[2], entry[3]]) as u64);
+        }
+        Some(())
+    }
+}
+
+fn millis(duration: Duration) -> u16 {
+    duration.as_millis().min(u16::MAX as u64) as u16
+}
+
+static BANK: Mutex<CriticalSectionRawMutex, RefCell<Bank>> = Mutex::new(RefCell::new(Bank {
+    channels: [Channel {
+        wanted: false,
+        on: false,
+        switched: Instant::from_ticks(0),
+        min_on: Duration::from_ticks(0),
+        min_off: Duration::from_ticks(0),
+    }; CHANNELS],
+    groups: [0; GROUPS],
+    fail_safe: false,
+}));
+
+/// Signaled whenever [`run`] has something new to switch
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Ask for `channel` to switch on or off
+pub fn request(channel: usize, on: bool) -> Result<(), Error> {
+    if channel >= CHANNELS {
+        return Err(Error::Channel(channel));
+    }
+    BANK.lock(|bank| {
+        let mut bank = bank.borrow_mut();
+        if on {
+            let interlocked = bank.interlocked(channel);
+            let busy = bank.mask(|c| c.wanted) & interlocked;
+            if busy != 0 {
+                return Err(Error::Interlocked(busy.trailing_zeros() as usize));
+            }
+        }
+        bank.channels[channel].wanted = on;
+        Ok(())
+    })?;
+    CHANGED.signal(());
+    Ok(())
+}
+
+/// Parse a channel write, `[0]` for off and `[1]` for on
+fn parse_request(value: &[u8]) -> Option<bool> {
+    match value {
+        [0] => Some(false),
+        [1] => Some(true),
+        _ => None,
+    }
+}
+
+/// Whether `channel` is switched on right now
+pub fn is_on(channel: usize) -> bool {
+    BANK.lock(|bank| bank.borrow().channels.get(channel).is_some_and(|c| c.on))
+}
+
+/// Switch every relay off right away
+pub fn all_off() {
+    let was_on = BANK.lock(|bank| {
+        let mut bank = bank.borrow_mut();
+        let was_on = bank.mask(|c| c.wanted || c.on);
+        for channel in bank.channels.iter_mut() {
+            channel.wanted = false;
+        }
+        bank.fail_safe = true;
+        was_on
+    });
+    if was_on != 0 {
+        info!("[relay] fail-safe off, {:#04x} were on", was_on);
+    }
+    CHANGED.signal(());
+}
+
+async fn store_config() -> Result<(), Error> {
+    let record = BANK.lock(|bank| bank.borrow().encode());
+    store::commit(store::Slot::RELAYS, &record)
+        .await
+        .map_err(Error::Store)
+}
+
+/// The interlock groups, as the interlocks characteristic takes them
+fn interlocks() -> [u8; INTERLOCKS_SIZE] {
+    BANK.lock(|bank| bank.borrow().groups)
+}
+
+/// Set the interlock groups from a write to the interlocks characteristic
+async fn set_interlocks(value: &[u8]) -> Result<(), Error> {
+    let groups: [u8; GROUPS] = value.try_into().map_err(|_| Error::Malformed)?;
+    BANK.lock(|bank| {
+        let mut bank = bank.borrow_mut();
+        let busy = bank.mask(|c| c.wanted);
+        if groups.iter().any(|group| (group & busy).count_ones() > 1) {
+            return Err(Error::Conflict);
+        }
+        bank.groups = groups;
+        Ok(())
+    })?;
+    info!("[relay] interlocks {:?}", groups);
+    store_config().await
+}
+
+/// Set minimum times from a write to the timing characteristic
+async fn set_timing(value: &[u8]) -> Result<(), Error> {
+    let [channel, on0, on1, off0, off1] = *value else {
+        return Err(Error::Malformed);
+    };
+    let min_on = Duration::from_millis(u16::from_le_bytes([on0, on1]) as u64);
+    let min_off = Duration::from_millis(u16::from_le_bytes([off0, off1]) as u64);
+    BANK.lock(|bank| {
+        let mut bank = bank.borrow_mut();
+        let channels = match channel {
+            ALL_CHANNELS => &mut bank.channels[..],
+            n => core::slice::from_mut(
+                bank.channels
+                    .get_mut(n as usize)
+                    .ok_or(Error::Channel(n as usize))?,
+            ),
+        };
+        for c in channels {
+            c.min_on = min_on;
+            c.min_off = min_off;
+        }
+        Ok(())
+    })?;
+    info!(
+        "[relay] channel {} on for {}ms, off for {}ms at least",
+        channel,
+        min_on.as_millis(),
+        min_off.as_millis()
+    );
+    store_config().await
+}
+
+/// Load the stored interlocks and minimum times. Call once at boot, after
+/// [`crate::flash::init`].
+pub async fn load() {
+    let mut record = [0; ENCODED_SIZE];
+    match store::load(store::Slot::RELAYS, &mut record).await {
+        Ok(Some(len)) => {
+            let decoded = BANK.lock(|bank| bank.borrow_mut().decode(&record[..len]));
+            match decoded {
+                Some(()) => info!("[relay] configuration loaded"),
+                None => error!("[relay] invalid stored configuration"),
+            }
+        }
+        Ok(None) => info!("[relay] no interlocks configured"),
+        Err(e) => error!("[relay] loading failed: {:?}", e),
+    }
+}
+
+/// Drive the expander, and read its inputs whenever its interrupt line
+/// drops
+pub async fn run(
+    bus: &'static Bus,
+    expander: Expander,
+    mut interrupt: Option<Input<'static>>,
+) -> ! {
+    let mut written = None;
+    loop {
+        monitor::RELAY.ping();
+        let (on, next) = BANK.lock(|bank| bank.borrow_mut().step(Instant::now()));
+        if written != Some(on) {
+            // the expander is set up again after it went missing
+            let mut i2c = bus.lock().await;
+            let result = match written {
+                Some(_) => expander.write_outputs(&mut i2c, !on).await,
+                None => expander.init(&mut i2c, !on).await,
+            };
+            drop(i2c);
+            match result {
+                Ok(()) => {
+                    info!("[relay] {:#04x} on", on);
+                    written = Some(on);
+                }
+                Err(e) => {
+                    // try again shortly, the board may just be unplugged
+                    error!("[relay] writing the expander failed: {:?}", e);
+                    fault::raise(fault::RELAY_WRITE);
+                    written = None;
+                    Timer::after_secs(1).await;
+                    continue;
+                }
+            }
+        }
+
+        monitor::RELAY.pause();
+        let timeout = async {
+            match next {
+                Some(at) => Timer::at(at).await,
+                None => pending().await,
+            }
+        };
+        let interrupted = async {
+            match interrupt.as_mut() {
+                Some(line) => line.wait_for_low().await,
+                None => pending().await,
+            }
+        };
+        if let Either3::Third(()) = select3(CHANGED.wait(), timeout, interrupted).await {
+            monitor::RELAY.ping();
+            let inputs = expander.read_inputs(&mut *bus.lock().await).await;
+            match inputs {
+                Ok(inputs) => {
+                    if expander::update_inputs(inputs) {
+                        modules::notify_event(INPUTS_UUID, &[inputs]);
+                    }
+                }
+                Err(e) => {
+                    error!("[relay] reading the expander inputs failed: {:?}", e);
+                    fault::raise(fault::RELAY_READ);
+                    // the line stays low until a read goes through
+                    Timer::after_millis(100).await;
+                }
+            }
+        }
+    }
+}
+
+crate::register_command!(
+    RELAY,
+    "relay",
+    "<channel> on|off",
+    "switch a relay",
+    |args| {
+        let channel = args.u32("channel")? as usize;
+        let on = args.bool("on|off")?;
+        args.end()?;
+        request(channel, on).map_err(|e| {
+            error!("[relay] refused: {:?}", e);
+            console::Error::Failed
+        })
+    }
+);
+
+/// A write to the interlocks or timing characteristic, stored by [`task`]
+#[derive(Clone, Copy)]
+enum Setting {
+    Interlocks([u8; INTERLOCKS_SIZE]),
+    Timing([u8; TIMING_SIZE]),
+}
+
+static SETTINGS: Channel<CriticalSectionRawMutex, Setting, 2> = Channel::new();
+
+#[embassy_executor::task]
+async fn task() -> ! {
+    loop {
+        let result = match SETTINGS.receive().await {
+            Setting::Interlocks(value) => set_interlocks(&value).await,
+            Setting::Timing(value) => set_timing(&value).await,
+        };
+        if let Err(e) = result {
+            error!("[relay] configuration failed: {:?}", e);
+        }
+    }
+}
+
+const SERVICE_UUID: Uuid = gen_uuid("relay bank");
+const INTERLOCKS_UUID: Uuid = gen_uuid("relay interlocks");
+const TIMING_UUID: Uuid = gen_uuid("relay timing");
+const INPUTS_UUID: Uuid = gen_uuid("expander inputs");
+const RELAY_UUIDS: [Uuid; CHANNELS] = gen_uuids(NAMES);
+const _: () =
+    gattcheck::assert_distinct(&[&RELAY_UUIDS, &[INTERLOCKS_UUID, TIMING_UUID, INPUTS_UUID]]);
+const _: () = gattcheck::assert_fit(&[INTERLOCKS_SIZE, TIMING_SIZE], writes::VALUE_MAX);
+
+fn write_channel<const IDX: usize>(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    #[cfg(feature = "rolling")]
+    if rolling::exclusive() {
+        error!("[relay] {} only takes rolling-code commands", NAMES[IDX]);
+        return Err(writes::AttError::WriteNotPermitted);
+    }
+    let on = parse_request(value).ok_or(writes::AttError::ValueNotAllowed)?;
+    request(IDX, on).map_err(|e| {
+        error!("[relay] {} refused: {:?}", NAMES[IDX], e);
+        writes::AttError::ValueNotAllowed
+    })
+}
+
+fn read_channel<const IDX: usize>() -> writes::Value {
+    writes::Value::new(&[is_on(IDX) as u8])
+}
+
+const CHANNEL_WRITES: [writes::Handler; CHANNELS] = [
+    write_channel::<0>,
+    write_channel::<1>,
+    write_channel::<2>,
+    write_channel::<3>,
+    write_channel::<4>,
+    write_channel::<5>,
+    write_channel::<6>,
+    write_channel::<7>,
+];
+const CHANNEL_READS: [writes::Reader; CHANNELS] = [
+    read_channel::<0>,
+    read_channel::<1>,
+    read_channel::<2>,
+    read_channel::<3>,
+    read_channel::<4>,
+    read_channel::<5>,
+    read_channel::<6>,
+    read_channel::<7>,
+];
+
+/// Hand a setting to [`task`], it writes the flash
+fn queue(setting: Setting) -> Result<(), writes::AttError> {
+    SETTINGS.try_send(setting).map_err(|_| {
+        error!("[relay] busy storing the configuration");
+        writes::AttError::ValueNotAllowed
+    })
+}
+
+fn write_interlocks(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let groups = value
+        .try_into()
+        .map_err(|_| writes::AttError::InvalidLength)?;
+    queue(Setting::Interlocks(groups))
+}
+
+fn write_timing(_: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let timing = value
+        .try_into()
+        .map_err(|_| writes::AttError::InvalidLength)?;
+    queue(Setting::Timing(timing))
+}
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    use CharacteristicProp::Notify;
+    use CharacteristicProp::Read;
+    use CharacteristicProp::Write;
+    let spec = writes::Spec::new();
+    for idx in 0..CHANNELS {
+        svc.add(
+            RELAY_UUIDS[idx],
+            &[Read, Write],
+            1,
+            spec.with_read(CHANNEL_READS[idx])
+                .with_write(CHANNEL_WRITES[idx]),
+        );
+    }
+    let setup = spec.with_access(roles::Access::Configure).in_maintenance();
+    svc.add(
+        INTERLOCKS_UUID,
+        &[Write],
+        INTERLOCKS_SIZE,
+        setup
+            .with_read(|| writes::Value::new(&interlocks()))
+            .with_write(write_interlocks),
+    );
+    svc.add(
+        TIMING_UUID,
+        &[Write],
+        TIMING_SIZE,
+        setup.with_write(write_timing),
+    );
+    svc.add(
+        INPUTS_UUID,
+        &[Read, Notify],
+        1,
+        spec.with_read(|| writes::Value::new(&[expander::inputs()]))
+            .as_event(),
+    );
+}
+
+// the bank itself is driven from main, which has the bus and the pins
+crate::register_module!(
+    MODULE,
+    "relay",
+    |resources| resources.spawner.must_spawn(task()),
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |services| services.relays,
+        build: service,
+    },
+);
@@ -0,0 +1,162 @@
+//! Resumable chunked transfers
+//!
+//! Tracks which chunks of a transfer have been received and verified in a
+//! bitmap, so a transfer cut short by a disconnect or reboot can pick up
+//! where it stopped. The state encodes to a small CRC-protected record for
+//! persisting, and to a status value clients can read to see what's
+//! still missing.
+
+use crate::integrity;
+
+/// Largest number of chunks a transfer can have
+pub const MAX_CHUNKS: usize = 4096;
+
+const WORDS: usize = MAX_CHUNKS / 32;
+
+const MAGIC: u32 = 0x5245_5355; // "RESU"
+
+/// Size of an encoded [`Transfer`]
+pub const ENCODED_SIZE: usize = 4 + 4 + 4 + 2 + WORDS * 4 + 4;
+
+/// Size of the status value: total length, chunk size, chunks received,
+/// first missing chunk
+pub const STATUS_SIZE: usize = 4 + 2 + 2 + 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The transfer needs more than [`MAX_CHUNKS`] chunks
+    TooLarge,
+    /// Chunk index or offset past the end of the transfer
+    OutOfRange,
+    /// The chunk doesn't start on a chunk boundary
+    Misaligned,
+    /// A persisted record is corrupt or from another transfer
+    Corrupt,
+}
+
+#[derive(Clone)]
+pub struct Transfer {
+    /// Identifies the image, e.g. its CRC32, so we only resume the same one
+    pub id: u32,
+    pub total_len: u32,
+    pub chunk_size: u16,
+    received: [u32; WORDS],
+}
+
+impl Transfer {
+    pub fn new(id: u32, total_len: u32, chunk_size: u16) -> Result<Self, Error> {
+        if chunk_size == 0 || (total_len as usize).div_ceil(chunk_size as usize) > MAX_CHUNKS {
+            return Err(Error::TooLarge);
+        }
+
+        Ok(Self {
+            id,
+            total_len,
+            chunk_size,
+            received: [0; WORDS],
+        })
+    }
+
+    pub fn chunks(&self) -> usize {
+        (self.total_len as usize).div_ceil(self.chunk_size as usize)
+    }
+
+    /// Chunk index for a byte offset
+    pub fn chunk_at(&self, offset: u32) -> Result<usize, Error> {
+        if offset >= self.total_len {
+            return Err(Error::OutOfRange);
+        }
+        if offset % self.chunk_size as u32 != 0 {
+            return Err(Error::Misaligned);
+        }
+        Ok((offset / self.chunk_size as u32) as usize)
+    }
+
+    /// Mark a chunk as received and verified
+    pub fn mark(&mut self, chunk: usize) -> Result<(), Error> {
+        if chunk >= self.chunks() {
+            return Err(Error::OutOfRange);
+        }
+        self.received[chunk / 32] |= 1 << (chunk % 32);
+        Ok(())
+    }
+
+    pub fn has(&self, chunk: usize) -> bool {
+        chunk < self.chunks() && self.received[chunk / 32] & (1 << (chunk % 32)) != 0
+    }
+
+    pub fn received(&self) -> usize {
+        (0..self.chunks()).filter(|&c| self.has(c)).count()
+    }
+
+    /// The first chunk still missing, `None` once complete
+    pub fn first_missing(&self) -> Option<usize> {
+        (0..self.chunks()).find(|&c| !self.has(c))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.first_missing().is_none()
+    }
+
+    /// little endian: total length, chunk size, chunks received, first
+    /// missing chunk (`0xffff` when complete)
+    pub fn status(&self) -> [u8; STATUS_SIZE] {
+        let mut out = [0; STATUS_SIZE];
+        out[0..4].copy_from_slice(&self.total_len.to_le_bytes());
+        out[4..6].copy_from_slice(&self.chunk_size.to_le_bytes());
+        out[6..8].copy_from_slice(&(self.received() as u16).to_le_bytes());
+        let missing = self.first_missing().map_or(0xffff, |c| c as u16);
+        out[8..10].copy_from_slice(&missing.to_le_bytes());
+        out
+    }
+
+    /// Encode for persisting: magic, id, total length, chunk size, bitmap, CRC32
+    pub fn encode(&self) -> [u8; ENCODED_SIZE] {
+        let mut out = [0; ENCODED_SIZE];
+        out[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        out[4..8].copy_from_slice(&self.id.to_le_bytes());
+        out[8..12].copy_from_slice(&self.total_len.to_le_bytes());
+        out[12..14].copy_from_slice(&self.chunk_size.to_le_bytes());
+        for (chunk, word) in out[14..ENCODED_SIZE - 4]
+            .chunks_exact_mut(4)
+            .zip(self.received)
+        {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        let crc = integrity::crc32(&out[..ENCODED_SIZE - 4]);
+        out[ENCODED_SIZE - 4..].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, Error> {
+        let data = data.get(..ENCODED_SIZE).ok_or(Error::Corrupt)?;
+        let word = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+
+        if word(0) != MAGIC || word(ENCODED_SIZE - 4) != integrity::crc32(&data[..ENCODED_SIZE - 4])
+        {
+            return Err(Error::Corrupt);
+        }
+
+        let chunk_size = u16::from_le_bytes([data[12], data[13]]);
+        let mut transfer = Self::new(word(4), word(8), chunk_size).map_err(|_| Error::Corrupt)?;
+        for (i, word) in transfer.received.iter_mut().enumerate() {
+            *word = u32::from_le_bytes(data[14 + i * 4..18 + i * 4].try_into().unwrap());
+        }
+        Ok(transfer)
+    }
+
+    /// Resume from a persisted record if it belongs to the same transfer,
+    /// otherwise start over
+    pub fn resume_or_new(
+        persisted: &[u8],
+        id: u32,
+        total_len: u32,
+        chunk_size: u16,
+    ) -> Result<Self, Error> {
+        match Self::decode(persisted) {
+            Ok(t) if t.id == id && t.total_len == total_len && t.chunk_size == chunk_size => Ok(t),
+            _ => Self::new(id, total_len, chunk_size),
+        }
+    }
+}
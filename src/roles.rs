@@ -0,0 +1,131 @@
+//! Per-peer roles
+//!
+//! Several people share a board: the family reads the telemetry, someone
+//! switches things, and the installer sets it up. Each peer gets the
+//! [`Role`] kept in the address book (see [`crate::peers`]), or
+//! `roles.default`. Every write a peer makes is checked against
+//! [`PERMISSIONS`] for the kind of [`Access`] it takes. Reads and
+//! notifications are open to every role.
+//!
+//! A peer nobody handed a role to is a `viewer` unless `roles.default`
+//! says otherwise. Roles are handed out from the console, with `roles
+//! <address> <role>`, or by an admin over the peers characteristic.
+//!
+//! This is not access control against an attacker. The BLE host we're on
+//! (trouble 0.1) can't pair or encrypt links, so a peer is only known by
+//! its address, and anyone in range can connect with the address of a peer
+//! that has a role and get that role. Only the admin lock (with the `admin`
+//! feature) holds against that, since it asks for a PIN rather than an
+//! address. Boards that take configuration over BLE should turn it on.
+
+use bt_hci::param::BdAddr;
+
+use crate::beacons;
+use crate::config::Text;
+use crate::info;
+use crate::peers;
+
+crate::config_key!(
+    /// Role of peers without one in the address book: "viewer", "operator"
+    /// or "admin"
+    pub DEFAULT: Text = "roles.default",
+    Text::new("viewer"),
+);
+
+/// Number of roles
+pub const ROLES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Role {
+    /// Reads the telemetry
+    Viewer = 0,
+    /// Switches relays, outputs and the lighting too
+    Operator = 1,
+    /// Changes the configuration and calibration too
+    Admin = 2,
+}
+
+impl Role {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Viewer),
+            1 => Some(Self::Operator),
+            2 => Some(Self::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "viewer" => Some(Self::Viewer),
+            "operator" => Some(Self::Operator),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// What a write does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Access {
+    /// Picks what to read, like a page index or the locale
+    View = 0,
+    /// Drives the outputs: controls, relays, GPIO
+    Operate = 1,
+    /// Changes how the board is set up: limits, calibration, pin map,
+    /// Wi-Fi, firmware
+    Configure = 2,
+}
+
+/// Which roles may take which access, by access then role
+pub const PERMISSIONS: [[bool; ROLES]; 3] = [
+    // viewer, operator, admin
+    [true, true, true],   // view
+    [false, true, true],  // operate
+    [false, false, true], // configure
+];
+
+pub fn permits(role: Role, access: Access) -> bool {
+    PERMISSIONS[access as usize][role as usize]
+}
+
+/// The role of `peer`
+pub fn of(peer: BdAddr) -> Role {
+    peers::find(peer)
+        .and_then(|p| p.role)
+        .unwrap_or_else(|| Role::parse(DEFAULT.get().as_str()).unwrap_or(Role::Viewer))
+}
+
+crate::register_command!(
+    ROLE,
+    "roles",
+    "[address role]",
+    "list the known peers' roles, or set one of a known peer",
+    |args| {
+        let Some(address) = args.opt_str() else {
+            info!("default {}", DEFAULT.get().as_str());
+            peers::for_each(|peer| {
+                let [a0, a1, a2, a3, a4, a5] = peer.address;
+                info!(
+                    "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} {:?}",
+                    a5, a4, a3, a2, a1, a0, peer.role
+                );
+            });
+            return Ok(());
+        };
+        let peer = beacons::parse_address(address)
+            .map(BdAddr::new)
+            .ok_or(crate::console::Error::Invalid("address"))?;
+        let role = Role::parse(args.str("role")?).ok_or(crate::console::Error::Invalid("role"))?;
+        args.end()?;
+        if !peers::set_role(peer, role) {
+            info!("not known");
+            return Err(crate::console::Error::Failed);
+        }
+        Ok(())
+    }
+);
@@ -0,0 +1,228 @@
+//! Rolling-code actuator commands
+//!
+//! Relays drive locks and garage doors, and without pairing, which the BLE
+//! host can't do, anyone in range can write the relay characteristics,
+//! or replay what they overheard. The rolling code characteristic takes
+//! commands that carry a counter and a MAC under a key shared with the
+//! phone, so they keep working over an unencrypted link:
+//!
+//! | bytes  | content                                               |
+//! |--------|-------------------------------------------------------|
+//! | 0..4   | counter, little endian                                |
+//! | 4      | relay channel                                         |
+//! | 5      | 0 off, 1 on                                           |
+//! | 6..14  | first 8 bytes of HMAC-SHA256(key, device ID, 0..6)    |
+//!
+//! The device ID (see [`crate::system::DeviceId`]) keeps a command for one
+//! board from working on another sharing the key. A command is taken only
+//! if its counter is above the last one taken, which is stored before the
+//! relay switches, so a replay is refused across reboots too. Counters may
+//! skip ahead, for commands that never arrived.
+//!
+//! The key is `rolling.key`, 64 hex digits, set from the console or a
+//! config import. With `rolling.exclusive` set, plain writes to the relay
+//! characteristics are refused and only commands switch relays.
+//!
+//! Every command is answered with a notification of `[`[`Status`]`, last
+//! counter: u32]`, which tells a phone that lost track where to go on from.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use trouble_host::prelude::*;
+
+use crate::blue::gen_uuid;
+use crate::config::Text;
+use crate::error;
+use crate::gattcheck;
+use crate::info;
+use crate::integrity;
+use crate::modules;
+use crate::relay;
+use crate::settings;
+use crate::system;
+use crate::writes;
+
+crate::config_key!(
+    /// Shared key for rolling-code commands, 64 hex digits, empty to
+    /// refuse every command
+    pub KEY: Text = "rolling.key",
+    Text::new(""),
+);
+
+crate::config_key!(
+    /// Refuse plain writes to the relay characteristics
+    pub EXCLUSIVE: bool = "rolling.exclusive",
+    false,
+);
+
+/// Size of a command
+pub const COMMAND_SIZE: usize = 14;
+
+/// Size of a response
+pub const RESPONSE_SIZE: usize = 5;
+
+const KEY_SIZE: usize = 32;
+
+const MAC_SIZE: usize = 8;
+
+/// How a command went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Status {
+    Done = 0,
+    Malformed = 1,
+    /// `rolling.key` isn't set, or isn't 64 hex digits
+    NoKey = 2,
+    BadMac = 3,
+    /// The counter isn't above the last one taken
+    Replayed = 4,
+    /// The relay refused, see [`relay::request`]
+    Refused = 5,
+    /// Storing the counter failed, the command wasn't run
+    NotStored = 6,
+}
+
+/// The last counter taken
+static LAST: Mutex<CriticalSectionRawMutex, Cell<u32>> = Mutex::new(Cell::new(0));
+
+fn key() -> Option<[u8; KEY_SIZE]> {
+    fn digit(c: u8) -> Option<u8> {
+        (c as char).to_digit(16).map(|d| d as u8)
+    }
+
+    let text = KEY.get();
+    let hex = text.as_str().as_bytes();
+    if hex.len() != 2 * KEY_SIZE {
+        return None;
+    }
+    let mut key = [0; KEY_SIZE];
+    for (byte, pair) in key.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Some(key)
+}
+
+/// Read the last counter taken back from flash. Call once at boot, before
+/// the BLE stack starts.
+pub async fn load() {
+    let mut stored = [0u8; 4];
+    match settings::get(settings::Key::ROLLING_COUNTER, &mut stored).await {
+        Ok(Some(4)) => LAST.lock(|last| last.set(u32::from_le_bytes(stored))),
+        Ok(_) => {}
+        Err(e) => error!("[rolling] reading the counter failed: {:?}", e),
+    }
+}
+
+/// Whether plain relay writes are refused
+pub fn exclusive() -> bool {
+    EXCLUSIVE.get()
+}
+
+/// Check and run a command, returning the response
+async fn execute(command: &[u8]) -> [u8; RESPONSE_SIZE] {
+    let status = run(command).await;
+    if status == Status::Done {
+        info!("[rolling] command taken");
+    } else {
+        error!("[rolling] command refused: {:?}", status);
+    }
+    let mut response = [0; RESPONSE_SIZE];
+    response[0] = status as u8;
+    response[1..].copy_from_slice(&LAST.lock(|last| last.get()).to_le_bytes());
+    response
+}
+
+async fn run(command: &[u8]) -> Status {
+    let Ok(command) = <&[u8; COMMAND_SIZE]>::try_from(command) else {
+        return Status::Malformed;
+    };
+    let Some(key) = key() else {
+        return Status::NoKey;
+    };
+    let (signed, mac) = command.split_at(COMMAND_SIZE - MAC_SIZE);
+    let expected = integrity::hmac_sha256(&key, &[&system::DeviceId::get().0, signed]);
+    if !integrity::constant_time_eq(&expected[..MAC_SIZE], mac) {
+        return Status::BadMac;
+    }
+
+    let counter = u32::from_le_bytes(command[0..4].try_into().unwrap());
+    let channel = command[4] as usize;
+    let on = match command[5] {
+        0 => false,
+        1 => true,
+        _ => return Status::Malformed,
+    };
+    if channel >= relay::CHANNELS {
+        return Status::Malformed;
+    }
+    if counter <= LAST.lock(|last| last.get()) {
+        return Status::Replayed;
+    }
+
+    // taken before it's run: a command that fails still can't be replayed
+    if let Err(e) = settings::set(settings::Key::ROLLING_COUNTER, &counter.to_le_bytes()).await {
+        error!("[rolling] storing the counter failed: {:?}", e);
+        return Status::NotStored;
+    }
+    LAST.lock(|last| last.set(counter));
+
+    match relay::request(channel, on) {
+        Ok(()) => Status::Done,
+        Err(e) => {
+            error!("[rolling] {} refused: {:?}", relay::NAMES[channel], e);
+            Status::Refused
+        }
+    }
+}
+
+/// Commands waiting for [`task`], with the connection they came from
+static COMMANDS: Channel<CriticalSectionRawMutex, (ConnHandle, [u8; COMMAND_SIZE], usize), 2> =
+    Channel::new();
+
+#[embassy_executor::task]
+async fn task() -> ! {
+    loop {
+        let (conn, command, len) = COMMANDS.receive().await;
+        let response = execute(&command[..len]).await;
+        modules::respond(conn, COMMAND_UUID, &response);
+    }
+}
+
+const SERVICE_UUID: Uuid = gen_uuid("rolling code");
+const COMMAND_UUID: Uuid = gen_uuid("rolling command");
+const _: () = gattcheck::assert_fit(&[COMMAND_SIZE, RESPONSE_SIZE], writes::READ_MAX);
+
+/// Hand a command to [`task`], it stores the counter before switching
+fn write_command(conn: ConnHandle, value: &[u8]) -> Result<(), writes::AttError> {
+    let mut command = [0; COMMAND_SIZE];
+    let len = value.len().min(COMMAND_SIZE);
+    command[..len].copy_from_slice(&value[..len]);
+    COMMANDS.try_send((conn, command, len)).map_err(|_| {
+        error!("[rolling] busy with other commands");
+        writes::AttError::ValueNotAllowed
+    })
+}
+
+fn service(svc: &mut modules::Service<'_, '_>) {
+    svc.add(
+        COMMAND_UUID,
+        &[CharacteristicProp::Write, CharacteristicProp::Notify],
+        COMMAND_SIZE,
+        writes::Spec::new().with_write(write_command).as_event(),
+    );
+}
+
+crate::register_module!(
+    MODULE,
+    "rolling",
+    |resources| resources.spawner.must_spawn(task()),
+    service: modules::Gatt {
+        uuid: SERVICE_UUID,
+        enabled: |_| true,
+        build: service,
+    },
+);
@@ -0,0 +1,301 @@
+//! Daily statistics
+//!
+//! Every sample of the signals selected in `rollup.signals`, a mask of
+//! [`alarm`] signals, goes into the day's minimum, maximum and average as
+//! [`alarm::check`] gets it. The day ends at local midnight by the
+//! [`clock`] (see `clock.utc_offset`): its figures are kept and committed
+//! to flash, and the next day starts from nothing. Samples taken before
+//! the clock is set count towards the first day it knows. The last
+//! [`DAYS_KEPT`] days survive reboots, the day in progress doesn't.
+//!
+//! A companion app reads the days in pages (see [`crate::paging`]), most
+//! recent first, each day [`DAY_SIZE`] bytes little endian:
+//!
+//! | bytes | content                                            |
+//! |-------|----------------------------------------------------|
+//! | 0..4  | local days since the unix epoch                    |
+//! | 4     | mask of the signals with samples that day          |
+//! | 5..   | min, max and average per signal, `i32` each        |
+//!
+//! Signals without samples have all three zero.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Ticker;
+
+use crate::alarm;
+use crate::clock;
+use crate::error;
+use crate::fault;
+use crate::info;
+use crate::paging;
+use crate::store;
+
+crate::config_key!(
+    /// Mask of the alarm signals to keep daily statistics of
+    pub SELECTED: u32 = "rollup.signals",
+    (1 << alarm::SIGNALS) - 1,
+);
+
+/// Days kept
+pub const DAYS_KEPT: usize = 30;
+
+/// Size of an encoded day
+pub const DAY_SIZE: usize = 4 + 1 + 12 * alarm::SIGNALS;
+
+/// How often we look for midnight
+const CHECK_EVERY: Duration = Duration::from_secs(60);
+
+const VERSION: u8 = 1;
+
+/// Largest stored record: version, number of days, the days
+const RECORD_MAX: usize = 2 + DAYS_KEPT * DAY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Summary {
+    pub min: i32,
+    pub max: i32,
+    pub avg: i32,
+}
+
+/// A finished day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Day {
+    /// Local days since the unix epoch
+    pub day: u32,
+    pub signals: [Option<Summary>; alarm::SIGNALS],
+}
+
+impl Day {
+    pub fn encode(&self) -> [u8; DAY_SIZE] {
+        let mut out = [0; DAY_SIZE];
+        out[0..4].copy_from_slice(&self.day.to_le_bytes());
+        for (idx, summary) in self.signals.iter().enumerate() {
+            let Some(summary) = summary else {
+                continue;
+            };
+            out[4] |= 1 << idx;
+            let at = 5 + idx * 12;
+            out[at..at + 4].copy_from_slice(&summary.min.to_le_bytes());
+            out[at + 4..at + 8].copy_from_slice(&summary.max.to_le_bytes());
+            out[at + 8..at + 12].copy_from_slice(&summary.avg.to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(data: &[u8; DAY_SIZE]) -> Self {
+        let word = |at: usize| i32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let mut signals = [None; alarm::SIGNALS];
+        for (idx, summary) in signals.iter_mut().enumerate() {
+            let at = 5 + idx * 12;
+            *summary = (data[4] & 1 << idx != 0).then(|| Summary {
+                min: word(at),
+                max: word(at + 4),
+                avg: word(at + 8),
+            });
+        }
+        Self {
+            day: word(0) as u32,
+            signals,
+        }
+    }
+}
+
+/// One signal's samples so far today
+#[derive(Debug, Clone, Copy)]
+struct Accumulator {
+    min: i32,
+    max: i32,
+    sum: i64,
+    count: u32,
+}
+
+impl Accumulator {
+    const EMPTY: Self = Self {
+        min: i32::MAX,
+        max: i32::MIN,
+        sum: 0,
+        count: 0,
+    };
+
+    fn summary(&self) -> Option<Summary> {
+        (self.count != 0).then(|| Summary {
+            min: self.min,
+            max: self.max,
+            avg: (self.sum / self.count as i64) as i32,
+        })
+    }
+}
+
+struct Rollup {
+    today: [Accumulator; alarm::SIGNALS],
+    /// Finished days, most recent first
+    days: [Option<Day>; DAYS_KEPT],
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, RefCell<Rollup>> = Mutex::new(RefCell::new(Rollup {
+    today: [Accumulator::EMPTY; alarm::SIGNALS],
+    days: [None; DAYS_KEPT],
+}));
+
+/// Take a sample spanning `min..=max` of `signal` into today's figures,
+/// if the signal is selected. [`i32::MAX`] and [`i32::MIN`] stand for
+/// nothing to measure (like no target in range) and aren't counted.
+pub fn sample(signal: usize, min: i32, max: i32) {
+    if SELECTED.get() & 1 << signal == 0 || min == i32::MIN || max == i32::MAX {
+        return;
+    }
+    STATS.lock(|rollup| {
+        let acc = &mut rollup.borrow_mut().today[signal];
+        acc.min = acc.min.min(min);
+        acc.max = acc.max.max(max);
+        acc.sum += (min as i64 + max as i64) / 2;
+        acc.count = acc.count.saturating_add(1);
+    });
+}
+
+/// Iterate over the finished days, most recent first
+pub fn for_each(mut f: impl FnMut(&Day)) {
+    STATS.lock(|rollup| rollup.borrow().days.iter().flatten().for_each(&mut f));
+}
+
+/// Close `day` with today's figures, making room for it
+fn close(day: u32) -> Day {
+    STATS.lock(|rollup| {
+        let mut rollup = rollup.borrow_mut();
+        let today = core::mem::replace(&mut rollup.today, [Accumulator::EMPTY; alarm::SIGNALS]);
+        let closed = Day {
+            day,
+            signals: today.map(|acc| acc.summary()),
+        };
+        rollup.days.copy_within(..DAYS_KEPT - 1, 1);
+        rollup.days[0] = Some(closed);
+        closed
+    })
+}
+
+async fn save() {
+    let mut record = [0; RECORD_MAX];
+    record[0] = VERSION;
+    let mut len = 2;
+    for_each(|day| {
+        record[len..len + DAY_SIZE].copy_from_slice(&day.encode());
+        record[1] += 1;
+        len += DAY_SIZE;
+    });
+    if let Err(e) = store::commit(store::Slot::ROLLUP, &record[..len]).await {
+        error!("[rollup] storing failed: {:?}", e);
+        fault::raise(fault::ROLLUP_STORE);
+    }
+}
+
+async fn load() {
+    let mut record = [0; RECORD_MAX];
+    let len = match store::load(store::Slot::ROLLUP, &mut record).await {
+        Ok(Some(len)) => len,
+        Ok(None) => return,
+        Err(e) => {
+            error!("[rollup] loading failed: {:?}", e);
+            return;
+        }
+    };
+    let count = record[1] as usize;
+    if len < 2 || record[0] != VERSION || count > DAYS_KEPT || len != 2 + count * DAY_SIZE {
+        error!("[rollup] invalid stored record");
+        return;
+    }
+    STATS.lock(|rollup| {
+        let mut rollup = rollup.borrow_mut();
+        for (idx, data) in record[2..len].chunks_exact(DAY_SIZE).enumerate() {
+            rollup.days[idx] = Some(Day::decode(data.try_into().unwrap()));
+        }
+    });
+    info!("[rollup] loaded {} days", count);
+}
+
+/// Close the day at every local midnight, forever
+pub async fn run() -> ! {
+    load().await;
+
+    let mut current = None;
+    let mut ticker = Ticker::every(CHECK_EVERY);
+    loop {
+        ticker.next().await;
+        let Some(today) = clock::local_day() else {
+            continue;
+        };
+        if let Some(day) = current.filter(|&day| day != today) {
+            let closed = close(day);
+            info!("[rollup] day {} closed: {:?}", day, closed.signals);
+            save().await;
+        }
+        current = Some(today);
+    }
+}
+
+#[embassy_executor::task]
+async fn task() -> ! {
+    run().await
+}
+
+crate::register_module!(MODULE, "rollup", |resources| {
+    resources.spawner.must_spawn(task())
+});
+
+/// The finished days as a pageable dataset, most recent first
+pub struct Dataset;
+
+impl paging::Dataset for Dataset {
+    fn len(&self) -> usize {
+        let mut len = 0;
+        for_each(|_| len += DAY_SIZE);
+        len
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        let mut pos = 0;
+        for_each(|day| {
+            let encoded = day.encode();
+            let start = pos;
+            pos += DAY_SIZE;
+            if pos <= offset || copied == buf.len() {
+                return;
+            }
+
+            let src = &encoded[offset.saturating_sub(start)..];
+            let n = src.len().min(buf.len() - copied);
+            buf[copied..copied + n].copy_from_slice(&src[..n]);
+            copied += n;
+        });
+        copied
+    }
+}
+
+crate::register_command!(
+    ROLLUP,
+    "rollup",
+    "",
+    "list the daily statistics, most recent first",
+    |args| {
+        args.end()?;
+        for_each(|day| {
+            let (year, month, date) = clock::civil_from_days(day.day as i64);
+            info!("{}-{:02}-{:02}", year, month, date);
+            for (signal, summary) in day.signals.iter().enumerate() {
+                if let Some(summary) = summary {
+                    info!(
+                        "  {}: min {} max {} avg {}",
+                        signal, summary.min, summary.max, summary.avg
+                    );
+                }
+            }
+        });
+        Ok(())
+    }
+);
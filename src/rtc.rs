@@ -0,0 +1,382 @@
+//! DS3231 real time clock
+//!
+//! The DS3231 keeps time on its coin cell while the board is off, with a
+//! crystal it compensates for temperature itself, good for about 2ppm. At
+//! boot it's the first source the clock gets, and it goes on reporting
+//! until a more precise one shows up.
+//!
+//! Its registers only count whole seconds, so a read waits for the seconds
+//! to tick over, which puts the time within a poll interval.
+//!
+//! While a precise source like SNTP is in use, the RTC is checked against
+//! it instead. How the offset between them moves over a few days is the
+//! RTC's drift, which the aging offset register trims: each step is about
+//! 0.1ppm, positive slowing the crystal down. An RTC that's too far off,
+//! or that lost its time along with its battery, is set from the clock.
+//!
+//! Drift measurements are logged along with the temperature.
+
+use core::future::pending;
+
+use embassy_rp::i2c;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+
+use crate::bus::Bus;
+use crate::clock;
+use crate::clock::Source;
+use crate::error;
+use crate::fault;
+use crate::info;
+
+const ADDRESS: u8 = 0x68;
+
+// registers
+const SECONDS: u8 = 0x00;
+const CONTROL: u8 = 0x0e;
+const STATUS: u8 = 0x0f;
+const AGING: u8 = 0x10;
+const TEMPERATURE: u8 = 0x11;
+
+/// Oscillator stopped flag, set when the RTC lost its time
+const STATUS_OSF: u8 = 0x80;
+
+/// Oscillator running on the battery, no square wave, alarms off
+const CONTROL_DEFAULT: u8 = 0x1c;
+
+/// Start a temperature conversion, which applies a new aging offset
+const CONTROL_CONV: u8 = 0x20;
+
+/// Century flag in the month register
+const MONTH_CENTURY: u8 = 0x80;
+
+/// Interval between polls for the seconds ticking over
+const POLL_MS: u64 = 10;
+
+/// Give up waiting for a tick after this many polls
+const MAX_POLLS: u64 = 1100 / POLL_MS;
+
+/// Time between reads
+const INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The DS3231's rated accuracy from 0°C to 40°C
+const RATED_PPM: u64 = 2;
+
+/// Error reported for a time we didn't set ourselves, which could have
+/// been drifting for a long time
+const UNKNOWN_ERROR_MS: u32 = 5000;
+
+/// Largest error of a source we check the RTC against
+const REFERENCE_ERROR_MS: u32 = 100;
+
+/// Offset from the reference over which the RTC is set
+const MAX_OFFSET_MS: i64 = 500;
+
+/// Shortest window to measure drift over. The reference is only good to
+/// some tens of ms, so it takes days for that to shrink below an aging
+/// step.
+const DRIFT_WINDOW: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+/// Drift corrected per aging offset step
+const AGING_STEP_PPB: i64 = 100;
+
+#[derive(Debug, Default)]
+struct Stats {
+    windows: u32,
+    min_ppb: i64,
+    max_ppb: i64,
+    sum_ppb: i64,
+}
+
+impl Stats {
+    fn add(&mut self, ppb: i64) {
+        if self.windows == 0 {
+            self.min_ppb = ppb;
+            self.max_ppb = ppb;
+        }
+        self.windows += 1;
+        self.min_ppb = self.min_ppb.min(ppb);
+        self.max_ppb = self.max_ppb.max(ppb);
+        self.sum_ppb += ppb;
+    }
+
+    fn mean_ppb(&self) -> i64 {
+        self.sum_ppb / self.windows.max(1) as i64
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+/// Unix seconds from the time registers, `None` if they don't hold a time
+fn decode(regs: &[u8; 7]) -> Option<u64> {
+    let [seconds, minutes, hours, _day, date, month, year] = *regs;
+    let seconds = from_bcd(seconds & 0x7f) as u64;
+    let minutes = from_bcd(minutes & 0x7f) as u64;
+    let hours = if hours & 0x40 != 0 {
+        // 12 hour mode, 12 AM is midnight
+        from_bcd(hours & 0x1f) % 12 + if hours & 0x20 != 0 { 12 } else { 0 }
+    } else {
+        from_bcd(hours & 0x3f)
+    } as u64;
+    let date = from_bcd(date & 0x3f) as u32;
+    let century = if month & MONTH_CENTURY != 0 {
+        2100
+    } else {
+        2000
+    };
+    let month = from_bcd(month & 0x1f) as u32;
+    let year = century + from_bcd(year) as i32;
+    if seconds > 59
+        || minutes > 59
+        || hours > 23
+        || !(1..=31).contains(&date)
+        || !(1..=12).contains(&month)
+    {
+        return None;
+    }
+
+    let days = clock::days_from_civil(year, month, date) as u64;
+    Some(days * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+/// The time registers for unix seconds, in 24 hour mode
+fn encode(secs: u64) -> [u8; 7] {
+    let days = (secs / 86400) as i64;
+    let (year, month, date) = clock::civil_from_days(days);
+    let of_day = secs % 86400;
+    let century = if year >= 2100 { MONTH_CENTURY } else { 0 };
+    [
+        to_bcd((of_day % 60) as u8),
+        to_bcd((of_day / 60 % 60) as u8),
+        to_bcd((of_day / 3600) as u8),
+        // day of the week, 1 for Monday. The epoch was a Thursday.
+        ((days + 3) % 7 + 1) as u8,
+        to_bcd(date as u8),
+        century | to_bcd(month as u8),
+        to_bcd((year % 100) as u8),
+    ]
+}
+
+async fn read(bus: &Bus, reg: u8, buf: &mut [u8]) -> Result<(), i2c::Error> {
+    bus.lock()
+        .await
+        .write_read_async(ADDRESS as u16, [reg], buf)
+        .await
+}
+
+async fn read_reg(bus: &Bus, reg: u8) -> Result<u8, i2c::Error> {
+    let mut value = [0];
+    read(bus, reg, &mut value).await?;
+    Ok(value[0])
+}
+
+async fn write_reg(bus: &Bus, reg: u8, value: u8) -> Result<(), i2c::Error> {
+    bus.lock()
+        .await
+        .write_async(ADDRESS as u16, [reg, value])
+        .await
+}
+
+/// Wait for the seconds to tick over and read the time in unix
+/// milliseconds. `None` if it didn't tick, or doesn't hold a time.
+async fn read_time(bus: &Bus) -> Result<Option<u64>, i2c::Error> {
+    let first = read_reg(bus, SECONDS).await?;
+    for _ in 0..MAX_POLLS {
+        Timer::after_millis(POLL_MS).await;
+        let mut regs = [0; 7];
+        read(bus, SECONDS, &mut regs).await?;
+        if regs[0] != first {
+            // it ticked somewhere in the last poll interval
+            return Ok(decode(&regs).map(|secs| secs * 1000 + POLL_MS / 2));
+        }
+    }
+    Ok(None)
+}
+
+/// Temperature in °C, as the RTC last compensated for it
+async fn temperature(bus: &Bus) -> Result<f32, i2c::Error> {
+    let mut regs = [0; 2];
+    read(bus, TEMPERATURE, &mut regs).await?;
+    let quarters = (regs[0] as i8 as i16) << 2 | (regs[1] >> 6) as i16;
+    Ok(quarters as f32 / 4.0)
+}
+
+/// The time of a source precise enough to check the RTC against
+fn reference() -> Option<u64> {
+    if matches!(clock::source(), Source::Rtc | Source::None) {
+        return None;
+    }
+    clock::now()
+        .filter(|&(_, error_ms)| error_ms <= REFERENCE_ERROR_MS)
+        .map(|(unix_ms, _)| unix_ms)
+}
+
+struct Rtc {
+    bus: &'static Bus,
+    /// Whether the RTC holds a time
+    valid: bool,
+    /// When we last set the RTC
+    set_at: Option<Instant>,
+    /// Offset from the reference at the start of the drift window
+    baseline: Option<(Instant, i64)>,
+    stats: Stats,
+}
+
+impl Rtc {
+    /// Error of the RTC's time, growing at its rated accuracy since we set it
+    fn error_ms(&self) -> u32 {
+        match self.set_at {
+            Some(at) => {
+                let drift =
+                    Instant::now().saturating_duration_since(at).as_secs() * RATED_PPM / 1000;
+                (POLL_MS + drift).min(UNKNOWN_ERROR_MS as u64) as u32
+            }
+            None => UNKNOWN_ERROR_MS,
+        }
+    }
+
+    /// Set the RTC from the clock. Writing the seconds restarts the RTC's
+    /// second, so it's done on a second boundary.
+    async fn set(&mut self) -> Result<(), i2c::Error> {
+        let Some((unix_ms, _)) = clock::now() else {
+            return Ok(());
+        };
+        Timer::after_millis(1000 - unix_ms % 1000).await;
+        let regs = encode(unix_ms / 1000 + 1);
+
+        let mut frame = [0; 8];
+        frame[0] = SECONDS;
+        frame[1..].copy_from_slice(&regs);
+        self.bus
+            .lock()
+            .await
+            .write_async(ADDRESS as u16, frame)
+            .await?;
+        let status = read_reg(self.bus, STATUS).await?;
+        write_reg(self.bus, STATUS, status & !STATUS_OSF).await?;
+
+        info!("[rtc] set from {:?}", clock::source());
+        self.valid = true;
+        self.set_at = Some(Instant::now());
+        self.baseline = None;
+        Ok(())
+    }
+
+    /// Track the offset from the reference, trimming the drift once the
+    /// window is long enough
+    async fn measure(&mut self, offset_ms: i64) -> Result<(), i2c::Error> {
+        let now = Instant::now();
+        let Some((start, start_offset)) = self.baseline else {
+            self.baseline = Some((now, offset_ms));
+            return Ok(());
+        };
+        let window = now.saturating_duration_since(start);
+        if window < DRIFT_WINDOW {
+            return Ok(());
+        }
+
+        // positive when the RTC runs fast
+        let drift_ppb = (offset_ms - start_offset) * 1_000_000_000 / window.as_millis() as i64;
+        self.stats.add(drift_ppb);
+        let aging = read_reg(self.bus, AGING).await? as i8;
+        info!(
+            "[rtc] drift {}ppb over {}h at {}°C, aging offset {} (min {}, max {}, mean {}ppb over {} windows)",
+            drift_ppb,
+            window.as_secs() / 3600,
+            temperature(self.bus).await?,
+            aging,
+            self.stats.min_ppb,
+            self.stats.max_ppb,
+            self.stats.mean_ppb(),
+            self.stats.windows,
+        );
+
+        let steps = (drift_ppb + drift_ppb.signum() * AGING_STEP_PPB / 2) / AGING_STEP_PPB;
+        let trimmed = (aging as i64 + steps).clamp(i8::MIN as i64, i8::MAX as i64) as i8;
+        if trimmed != aging {
+            write_reg(self.bus, AGING, trimmed as u8).await?;
+            // apply it now rather than at the next conversion
+            write_reg(self.bus, CONTROL, CONTROL_DEFAULT | CONTROL_CONV).await?;
+            info!("[rtc] aging offset {} -> {}", aging, trimmed);
+        }
+        self.baseline = Some((now, offset_ms));
+        Ok(())
+    }
+
+    async fn step(&mut self) -> Result<(), i2c::Error> {
+        if !self.valid {
+            if reference().is_some() {
+                self.set().await?;
+            }
+            return Ok(());
+        }
+
+        let Some(rtc_ms) = read_time(self.bus).await? else {
+            error!("[rtc] not keeping time");
+            fault::raise(fault::RTC_STOPPED);
+            self.valid = false;
+            return Ok(());
+        };
+        let Some(reference_ms) = reference() else {
+            clock::report(Source::Rtc, rtc_ms, self.error_ms());
+            return Ok(());
+        };
+
+        let offset_ms = rtc_ms as i64 - reference_ms as i64;
+        if offset_ms.abs() > MAX_OFFSET_MS {
+            info!("[rtc] {}ms off, setting it", offset_ms);
+            return self.set().await;
+        }
+        self.measure(offset_ms).await
+    }
+}
+
+pub async fn run(bus: &'static Bus) -> ! {
+    let status = match read_reg(bus, STATUS).await {
+        Ok(status) => status,
+        Err(e) => {
+            info!("[rtc] no DS3231 found: {:?}", e);
+            pending().await
+        }
+    };
+    // keep time on the battery while the board is off
+    if let Err(e) = write_reg(bus, CONTROL, CONTROL_DEFAULT).await {
+        error!("[rtc] writing the control register failed: {:?}", e);
+    }
+
+    let valid = status & STATUS_OSF == 0;
+    if !valid {
+        info!("[rtc] lost its time, waiting for a sync to set it");
+    }
+    let mut rtc = Rtc {
+        bus,
+        valid,
+        set_at: None,
+        baseline: None,
+        stats: Stats::default(),
+    };
+
+    loop {
+        if let Err(e) = rtc.step().await {
+            error!("[rtc] {:?}", e);
+            fault::raise(fault::RTC_BUS);
+        }
+        Timer::after(INTERVAL).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn task(bus: &'static Bus) -> ! {
+    run(bus).await
+}
+
+crate::register_module!(MODULE, "rtc", |resources| {
+    resources.spawner.must_spawn(task(resources.bus))
+});
@@ -0,0 +1,93 @@
+//! Timing and wakeups for the service layer
+//!
+//! Most of the GATT application layer (the persisted values, counters and
+//! traces behind the characteristics) only needs to know the time, sleep
+//! until a deadline and wake a task from another. Those go through the
+//! small traits here instead of embassy directly, so the layer can run on
+//! another executor (an RTOS task, a host test harness) by implementing
+//! them; [`Embassy`] is what the firmware uses.
+//!
+//! - [`Clock`] is installed once with [`install_clock`], before anything
+//!   reads it, since the readers are plain functions called from anywhere
+//!   (like [`crate::stats::record`] and [`crate::gatttrace::record`]).
+//!   [`now`] reads it.
+//! - [`Sleep`] goes to the tasks that sleep as a parameter, see
+//!   [`crate::persist::run_on`].
+//! - [`Wake`] is implemented for embassy-sync's [`Signal`], which like the
+//!   rest of embassy-sync doesn't depend on the executor.
+//!
+//! Times stay embassy-time's [`Instant`] and [`Duration`], which are plain
+//! tick counts; only `Instant::now` needs embassy's time driver.
+//! [`crate::session`] and [`crate::paging`] need nothing beyond
+//! embassy-sync's blocking mutexes, which take a critical section.
+
+use core::cell::Cell;
+use core::future::Future;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+use embassy_time::Timer;
+
+/// A monotonic clock
+pub trait Clock: Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Sleeping until a deadline
+pub trait Sleep {
+    fn until(&self, at: Instant) -> impl Future<Output = ()>;
+}
+
+/// What a task that keeps time and sleeps needs
+pub trait Runtime: Clock + Sleep {}
+
+impl<T: Clock + Sleep> Runtime for T {}
+
+/// A wakeup one task waits for and anything else gives. Wakeups given
+/// while nobody waits are kept, but don't add up.
+pub trait Wake {
+    fn wake(&self);
+
+    fn wait(&self) -> impl Future<Output = ()>;
+}
+
+/// Embassy's time driver and timers
+pub struct Embassy;
+
+impl Clock for Embassy {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl Sleep for Embassy {
+    fn until(&self, at: Instant) -> impl Future<Output = ()> {
+        Timer::at(at)
+    }
+}
+
+impl<M: RawMutex> Wake for Signal<M, ()> {
+    fn wake(&self) {
+        self.signal(());
+    }
+
+    fn wait(&self) -> impl Future<Output = ()> {
+        Signal::wait(self)
+    }
+}
+
+static CLOCK: Mutex<CriticalSectionRawMutex, Cell<&'static dyn Clock>> =
+    Mutex::new(Cell::new(&Embassy));
+
+/// Use `clock` in place of [`Embassy`]'s. Call once at startup.
+pub fn install_clock(clock: &'static dyn Clock) {
+    CLOCK.lock(|installed| installed.set(clock));
+}
+
+/// The time on the installed clock
+pub fn now() -> Instant {
+    CLOCK.lock(|clock| clock.get()).now()
+}
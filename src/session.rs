@@ -0,0 +1,161 @@
+//! Per-connection values
+//!
+//! Every connection gets a session id when it's established. Values stored
+//! in a [`PerConnection`] are tagged with that id, so once the connection
+//! goes away its values are dead: they read back as the initial value and
+//! their slot is reused by the next session.
+
+use core::cell::RefCell;
+
+use bt_hci::param::ConnHandle;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::blue::CONNECTIONS_MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SessionId(u32);
+
+struct Sessions {
+    live: [Option<(ConnHandle, SessionId)>; CONNECTIONS_MAX],
+    next_id: u32,
+}
+
+static SESSIONS: Mutex<CriticalSectionRawMutex, RefCell<Sessions>> =
+    Mutex::new(RefCell::new(Sessions {
+        live: [None; CONNECTIONS_MAX],
+        next_id: 0,
+    }));
+
+/// Start a session for a new connection
+pub fn open(conn: ConnHandle) {
+    SESSIONS.lock(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let id = SessionId(sessions.next_id);
+        sessions.next_id = sessions.next_id.wrapping_add(1);
+
+        let live = &mut sessions.live;
+        if let Some(slot) = live
+            .iter_mut()
+            .find(|s| matches!(s, Some((c, _)) if *c == conn))
+        {
+            *slot = Some((conn, id));
+        } else if let Some(slot) = live.iter_mut().find(|s| s.is_none()) {
+            *slot = Some((conn, id));
+        }
+    });
+}
+
+/// End the session of a disconnected connection, dropping its values
+pub fn close(conn: ConnHandle) {
+    SESSIONS.lock(|sessions| {
+        for slot in sessions.borrow_mut().live.iter_mut() {
+            if matches!(slot, Some((c, _)) if *c == conn) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// End every session, for when the stack went away without telling the
+/// connections. Returns the connections that were open.
+pub fn close_all() -> [Option<ConnHandle>; CONNECTIONS_MAX] {
+    SESSIONS.lock(|sessions| {
+        let live = &mut sessions.borrow_mut().live;
+        let open = live.map(|slot| slot.map(|(conn, _)| conn));
+        *live = [None; CONNECTIONS_MAX];
+        open
+    })
+}
+
+fn current(conn: ConnHandle) -> Option<SessionId> {
+    SESSIONS.lock(|sessions| {
+        sessions
+            .borrow()
+            .live
+            .iter()
+            .flatten()
+            .find(|(c, _)| *c == conn)
+            .map(|(_, id)| *id)
+    })
+}
+
+/// A value tracked per connection rather than globally
+pub struct PerConnection<T: Copy> {
+    init: T,
+    slots: Mutex<CriticalSectionRawMutex, RefCell<[Option<(SessionId, T)>; CONNECTIONS_MAX]>>,
+}
+
+impl<T: Copy> PerConnection<T> {
+    pub const fn new(init: T) -> Self {
+        Self {
+            init,
+            slots: Mutex::new(RefCell::new([None; CONNECTIONS_MAX])),
+        }
+    }
+
+    /// Read the value for `conn`, the initial value if it never set one
+    pub fn get(&self, conn: ConnHandle) -> T {
+        let Some(id) = current(conn) else {
+            return self.init;
+        };
+
+        self.slots.lock(|slots| {
+            slots
+                .borrow()
+                .iter()
+                .flatten()
+                .find(|(s, _)| *s == id)
+                .map_or(self.init, |(_, value)| *value)
+        })
+    }
+
+    /// Set the value for `conn`. Ignored if `conn` has no open session.
+    pub fn set(&self, conn: ConnHandle, value: T) {
+        self.update(conn, |_| value);
+    }
+
+    /// Update the value for `conn` in place, returning the new value
+    pub fn update(&self, conn: ConnHandle, f: impl FnOnce(T) -> T) -> T {
+        let Some(id) = current(conn) else {
+            return f(self.init);
+        };
+
+        SESSIONS.lock(|sessions| {
+            let sessions = sessions.borrow();
+            self.slots.lock(|slots| {
+                let mut slots = slots.borrow_mut();
+                let is_live = |s: &SessionId| sessions.live.iter().flatten().any(|(_, l)| l == s);
+
+                let slot = match slots
+                    .iter()
+                    .position(|s| matches!(s, Some((s, _)) if *s == id))
+                {
+                    Some(idx) => &mut slots[idx],
+                    // reuse the slot of a dead session
+                    None => match slots
+                        .iter()
+                        .position(|s| !matches!(s, Some((s, _)) if is_live(s)))
+                    {
+                        Some(idx) => &mut slots[idx],
+                        None => return f(self.init),
+                    },
+                };
+
+                let old = match slot {
+                    Some((s, value)) if *s == id => *value,
+                    _ => self.init,
+                };
+                let new = f(old);
+                *slot = Some((id, new));
+                new
+            })
+        })
+    }
+
+    /// Put every connection back to the initial value
+    pub fn clear(&self) {
+        self.slots
+            .lock(|slots| *slots.borrow_mut() = [None; CONNECTIONS_MAX]);
+    }
+}
@@ -0,0 +1,373 @@
+//! Persistent binary settings
+//!
+//! Small values that have to survive a reboot: our BLE address and name,
+//! and the values clients write to characteristics. Where
+//! [`crate::config`] rewrites its whole record on every commit, [`set`]
+//! appends one entry to a log, so values written often don't wear a
+//! sector out.
+//!
+//! The log takes the [`SECTORS`] sectors of [`partitions::SETTINGS`], one
+//! of them active at a time. A sector starts with `[magic, version,
+//! sequence number]`, and the active one is the valid sector with
+//! the highest sequence number; one from another version counts as empty.
+//! Entries follow as `[key: 4, len: 2, crc: 4, value]`, the CRC32 over
+//! key, length and value, and the last entry for a key is its value.
+//!
+//! Once the active sector is full, the latest value of every key is copied
+//! to the next sector, going round all of them in turn. That sector's
+//! header is written last, so a power cut leaves the old sector active
+//! until the new one is complete. A torn entry ends the log, and the next
+//! [`set`] starts a new sector past it.
+
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use trouble_host::prelude::Uuid;
+
+use crate::flash;
+use crate::integrity;
+use crate::partitions;
+
+const MAGIC: u32 = 0x5345_5454; // "SETT"
+
+const VERSION: u32 = 1;
+
+/// magic, version, sequence number
+const HEADER_SIZE: usize = 12;
+
+/// key, length, CRC32
+const ENTRY_HEADER: usize = 10;
+
+/// Longest value
+pub const VALUE_MAX: usize = 64;
+
+/// Sectors the log goes round
+pub const SECTORS: usize = partitions::SETTINGS.sectors();
+
+/// What an erased entry header reads as
+const ERASED: [u8; ENTRY_HEADER] = [0xff; ENTRY_HEADER];
+
+/// Names a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Key(pub u32);
+
+impl Key {
+    /// Our address, for [`crate::params::AddressMode::Persisted`]
+    pub const ADDRESS: Self = Self(1);
+    /// Device name, in place of [`crate::params::Config::name`]
+    pub const NAME: Self = Self(2);
+    /// Alarm limits, with [`crate::alarm::PERSIST`]
+    pub const ALARM_LIMITS: Self = Self(3);
+    /// Output levels and duty cycle, with [`crate::gpio::PERSIST`]
+    pub const GPIO: Self = Self(4);
+    /// Last rolling-code command counter taken, see [`crate::rolling`]
+    pub const ROLLING_COUNTER: Self = Self(5);
+    /// Salted hash of the admin PIN, see [`crate::admin`]
+    pub const ADMIN_PIN: Self = Self(6);
+    /// The first of [`crate::peers::PEERS_MAX`] keys, one per known peer
+    pub const PEERS: Self = Self(0x100);
+
+    /// The value of the characteristic with `uuid`. These have the top
+    /// bit set, below it is for fixed keys like the ones above.
+    pub fn characteristic(uuid: &Uuid) -> Self {
+        // all ones is what erased flash reads as
+        Self((integrity::crc32(uuid.as_raw()) | 1 << 31).min(u32::MAX - 1))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Flash(flash::Error),
+    /// Longer than [`VALUE_MAX`], or than the buffer it's read into
+    TooLarge,
+    /// The latest values of all keys fill a sector
+    Full,
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Self::Flash(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Log {
+    /// `None` until the first sector is started
+    active: Option<usize>,
+    seq: u32,
+    /// Where the next entry goes in the active sector
+    end: usize,
+    /// The next entry has to start a new sector
+    rotate: bool,
+}
+
+/// The log, found on first use
+static LOG: Mutex<CriticalSectionRawMutex, Option<Log>> = Mutex::new(None);
+
+enum Next {
+    End,
+    Torn,
+    Entry { key: u32, len: usize },
+}
+
+const fn sector(idx: usize) -> u32 {
+    partitions::SETTINGS.sector(idx)
+}
+
+fn read(driver: &mut flash::Driver, offset: u32, buf: &mut [u8]) -> Result<(), flash::Error> {
+    driver
+        .blocking_read(offset, buf)
+        .map_err(flash::Error::Flash)
+}
+
+fn entry_crc(key: u32, value: &[u8]) -> u32 {
+    let mut crc = integrity::Crc32::new();
+    crc.update(&key.to_le_bytes());
+    crc.update(&(value.len() as u16).to_le_bytes());
+    crc.update(value);
+    crc.finish()
+}
+
+/// Read the entry at `at` in sector `idx`, its value into `value`
+fn read_entry(
+    driver: &mut flash::Driver,
+    idx: usize,
+    at: usize,
+    value: &mut [u8; VALUE_MAX],
+) -> Result<Next, flash::Error> {
+    if at + ENTRY_HEADER > ERASE_SIZE {
+        return Ok(Next::End);
+    }
+    let mut header = [0; ENTRY_HEADER];
+    read(driver, sector(idx) + at as u32, &mut header)?;
+    if header == ERASED {
+        return Ok(Next::End);
+    }
+
+    let key = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let len = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[6..10].try_into().unwrap());
+    if len > VALUE_MAX || at + ENTRY_HEADER + len > ERASE_SIZE {
+        return Ok(Next::Torn);
+    }
+    read(
+        driver,
+        sector(idx) + (at + ENTRY_HEADER) as u32,
+        &mut value[..len],
+    )?;
+    if entry_crc(key, &value[..len]) != crc {
+        return Ok(Next::Torn);
+    }
+    Ok(Next::Entry { key, len })
+}
+
+/// The last value of `key` in `from..end` of sector `idx`, into `out`
+fn lookup(
+    driver: &mut flash::Driver,
+    idx: usize,
+    from: usize,
+    end: usize,
+    key: u32,
+    out: &mut [u8; VALUE_MAX],
+) -> Result<Option<usize>, flash::Error> {
+    let mut value = [0; VALUE_MAX];
+    let mut found = None;
+    let mut at = from;
+    while at < end {
+        let Next::Entry { key: k, len } = read_entry(driver, idx, at, &mut value)? else {
+            break;
+        };
+        if k == key {
+            out[..len].copy_from_slice(&value[..len]);
+            found = Some(len);
+        }
+        at += ENTRY_HEADER + len;
+    }
+    Ok(found)
+}
+
+/// Find the active sector and the end of its log
+fn open(driver: &mut flash::Driver) -> Result<Log, flash::Error> {
+    let mut best: Option<(usize, u32)> = None;
+    for idx in 0..SECTORS {
+        let mut header = [0; HEADER_SIZE];
+        read(driver, sector(idx), &mut header)?;
+        let word = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        if word(0) != MAGIC || word(4) != VERSION {
+            continue;
+        }
+        let seq = word(8);
+        // sequence numbers wrap, compare them by distance
+        let newer = match best {
+            Some((_, b)) => seq.wrapping_sub(b) as i32 > 0,
+            None => true,
+        };
+        if newer {
+            best = Some((idx, seq));
+        }
+    }
+
+    let Some((active, seq)) = best else {
+        return Ok(Log {
+            active: None,
+            // the first sector gets 0
+            seq: u32::MAX,
+            end: HEADER_SIZE,
+            rotate: true,
+        });
+    };
+    let mut value = [0; VALUE_MAX];
+    let mut at = HEADER_SIZE;
+    loop {
+        let rotate = match read_entry(driver, active, at, &mut value)? {
+            Next::Entry { len, .. } => {
+                at += ENTRY_HEADER + len;
+                continue;
+            }
+            Next::End => false,
+            Next::Torn => true,
+        };
+        return Ok(Log {
+            active: Some(active),
+            seq,
+            end: at,
+            rotate,
+        });
+    }
+}
+
+async fn opened(log: &mut Option<Log>) -> Result<&mut Log, Error> {
+    if log.is_none() {
+        *log = Some(flash::with_flash(open).await?);
+    }
+    Ok(log.as_mut().unwrap())
+}
+
+async fn append(idx: usize, at: usize, key: u32, value: &[u8]) -> Result<(), flash::Error> {
+    let mut entry = [0; ENTRY_HEADER + VALUE_MAX];
+    entry[0..4].copy_from_slice(&key.to_le_bytes());
+    entry[4..6].copy_from_slice(&(value.len() as u16).to_le_bytes());
+    entry[6..10].copy_from_slice(&entry_crc(key, value).to_le_bytes());
+    entry[ENTRY_HEADER..ENTRY_HEADER + value.len()].copy_from_slice(value);
+    flash::write(
+        sector(idx) + at as u32,
+        &entry[..ENTRY_HEADER + value.len()],
+    )
+    .await
+}
+
+/// Start the next sector with the latest value of every key
+async fn rotate(log: &mut Log) -> Result<(), Error> {
+    let next = log.active.map_or(0, |active| (active + 1) % SECTORS);
+    let base = sector(next);
+    flash::execute(flash::Op::Erase {
+        from: base,
+        to: base + ERASE_SIZE as u32,
+    })
+    .await?;
+
+    let mut end = HEADER_SIZE;
+    if let Some(active) = log.active {
+        let mut value = [0; VALUE_MAX];
+        let mut at = HEADER_SIZE;
+        while at < log.end {
+            let entry = flash::with_flash(|driver| read_entry(driver, active, at, &mut value));
+            let Next::Entry { key, len } = entry.await? else {
+                break;
+            };
+            let after = at + ENTRY_HEADER + len;
+            let mut later = [0; VALUE_MAX];
+            let superseded =
+                flash::with_flash(|driver| lookup(driver, active, after, log.end, key, &mut later))
+                    .await?
+                    .is_some();
+            if !superseded {
+                append(next, end, key, &value[..len]).await?;
+                end += ENTRY_HEADER + len;
+            }
+            at = after;
+        }
+    }
+
+    // the header makes it the active sector
+    let seq = log.seq.wrapping_add(1);
+    let mut header = [0; HEADER_SIZE];
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&VERSION.to_le_bytes());
+    header[8..12].copy_from_slice(&seq.to_le_bytes());
+    flash::write(base, &header).await?;
+
+    *log = Log {
+        active: Some(next),
+        seq,
+        end,
+        rotate: false,
+    };
+    Ok(())
+}
+
+/// Read the value of `key` into `out`, returning its length. `None` if it
+/// was never set.
+pub async fn get(key: Key, out: &mut [u8]) -> Result<Option<usize>, Error> {
+    let mut log = LOG.lock().await;
+    let log = opened(&mut log).await?;
+    let Some(active) = log.active else {
+        return Ok(None);
+    };
+    let mut value = [0; VALUE_MAX];
+    let end = log.end;
+    let found =
+        flash::with_flash(|driver| lookup(driver, active, HEADER_SIZE, end, key.0, &mut value))
+            .await?;
+    let Some(len) = found else {
+        return Ok(None);
+    };
+    out.get_mut(..len)
+        .ok_or(Error::TooLarge)?
+        .copy_from_slice(&value[..len]);
+    Ok(Some(len))
+}
+
+/// Store `value` as the value of `key`. Setting the value it has already
+/// doesn't touch the flash.
+pub async fn set(key: Key, value: &[u8]) -> Result<(), Error> {
+    if value.len() > VALUE_MAX {
+        return Err(Error::TooLarge);
+    }
+    let mut log = LOG.lock().await;
+    let log = opened(&mut log).await?;
+
+    if let Some(active) = log.active {
+        let mut current = [0; VALUE_MAX];
+        let end = log.end;
+        let found = flash::with_flash(|driver| {
+            lookup(driver, active, HEADER_SIZE, end, key.0, &mut current)
+        })
+        .await?;
+        if found.is_some_and(|len| current[..len] == *value) {
+            return Ok(());
+        }
+    }
+
+    let len = ENTRY_HEADER + value.len();
+    if log.rotate || log.end + len > ERASE_SIZE {
+        if let Err(e) = rotate(log).await {
+            log.rotate = true;
+            return Err(e);
+        }
+    }
+    if log.end + len > ERASE_SIZE {
+        return Err(Error::Full);
+    }
+    // rotate() gave us a sector if there wasn't one
+    let active = log.active.unwrap();
+    if let Err(e) = append(active, log.end, key.0, value).await {
+        // whatever made it to the flash is in the way of the next entry
+        log.rotate = true;
+        return Err(e.into());
+    }
+    log.end += len;
+    Ok(())
+}
@@ -0,0 +1,81 @@
+//! SHT31 temperature and humidity sensor
+//!
+//! Measured with single shot, high repeatability commands without clock
+//! stretching, so the bus is free while the sensor converts. Each 16-bit
+//! word the sensor sends is followed by its CRC-8, and a value that fails
+//! the check reads as unknown.
+
+use embassy_rp::i2c;
+use embassy_time::Timer;
+
+use crate::bus::Bus;
+use crate::environment::Reading;
+use crate::environment::Sensor;
+
+/// With ADDR tied low
+const ADDRESS: u8 = 0x44;
+
+// commands, big endian
+const MEASURE: [u8; 2] = [0x24, 0x00];
+const READ_STATUS: [u8; 2] = [0xf3, 0x2d];
+
+/// A high repeatability measurement takes up to 15ms
+const MEASUREMENT_MS: u64 = 16;
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xffu8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The word in `[msb, lsb, crc]`, `None` if the CRC doesn't match
+fn word(data: &[u8]) -> Option<u16> {
+    (crc8(&data[..2]) == data[2]).then(|| u16::from_be_bytes([data[0], data[1]]))
+}
+
+pub struct Sht31;
+
+impl Sht31 {
+    /// Check that the sensor answers
+    pub async fn probe(bus: &Bus) -> Result<Self, i2c::Error> {
+        let mut status = [0; 3];
+        bus.lock()
+            .await
+            .write_read_async(ADDRESS as u16, READ_STATUS, &mut status)
+            .await?;
+        Ok(Self)
+    }
+}
+
+impl Sensor for Sht31 {
+    async fn measure(&mut self, bus: &Bus) -> Result<Reading, i2c::Error> {
+        bus.lock()
+            .await
+            .write_async(ADDRESS as u16, MEASURE)
+            .await?;
+        Timer::after_millis(MEASUREMENT_MS).await;
+        let mut data = [0; 6];
+        bus.lock()
+            .await
+            .read_async(ADDRESS as u16, &mut data)
+            .await?;
+
+        // -45°C + 175°C and 100% of the raw value over its range
+        let temperature = word(&data[..3]).map(|raw| (-4500 + 17_500 * raw as i32 / 65_535) as i16);
+        let humidity = word(&data[3..]).map(|raw| (10_000 * raw as u32 / 65_535) as u16);
+        Ok(Reading {
+            temperature,
+            humidity,
+            pressure: None,
+        })
+    }
+}
@@ -0,0 +1,163 @@
+//! Device state snapshots
+//!
+//! Dumps the whole state layer (control values, lockout, counters, the
+//! audit trail) as a CBOR map, and restores it from one. Host-side tests
+//! use it to set up preconditions, and a dump captured from a misbehaving
+//! device can be loaded into another to reproduce the problem.
+//!
+//! Restoring ignores keys it doesn't know, so older firmware can load
+//! newer dumps. Dumps are taken and loaded over HTTP, see [`crate::http`].
+
+use core::sync::atomic::Ordering;
+
+use crate::accept;
+use crate::audit;
+use crate::bthome;
+use crate::cbor;
+use crate::controls;
+use crate::lighting::Message;
+
+/// Bumped when the meaning of existing keys changes
+const VERSION: u64 = 1;
+
+/// Largest dump [`capture`] makes, with every audit entry
+pub const SIZE_MAX: usize = 384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Cbor(cbor::Error),
+    /// A dump from an incompatible firmware
+    Version(u64),
+    /// A value is well-formed CBOR but doesn't make sense
+    Invalid,
+}
+
+impl From<cbor::Error> for Error {
+    fn from(e: cbor::Error) -> Self {
+        Self::Cbor(e)
+    }
+}
+
+/// Encode the current state into `out`, returning the length used
+pub fn capture(out: &mut [u8]) -> Result<usize, Error> {
+    let mut enc = cbor::Encoder::new(out);
+    enc.map(5)?;
+
+    enc.text("version")?;
+    enc.uint(VERSION)?;
+
+    enc.text("controls")?;
+    enc.map(controls::CONTROLS.len())?;
+    for (idx, control) in controls::CONTROLS.iter().enumerate() {
+        enc.text(control.name)?;
+        enc.bytes(&controls::value(idx)[..control.len])?;
+    }
+
+    enc.text("lockout")?;
+    enc.bool(accept::LOCKOUT.load(Ordering::Relaxed))?;
+
+    enc.text("bthome counter")?;
+    enc.uint(bthome::counter() as u64)?;
+
+    enc.text("audit")?;
+    let mut entries = [None; audit::TRAIL_LEN];
+    let mut count = 0;
+    audit::for_each(|entry| {
+        entries[count] = Some(*entry);
+        count += 1;
+    });
+    enc.map(2)?;
+    enc.text("total")?;
+    enc.uint(audit::total() as u64)?;
+    enc.text("entries")?;
+    enc.array(count)?;
+    // oldest first, the order they get recorded back in
+    for entry in entries[..count].iter().rev().flatten() {
+        enc.array(5)?;
+        enc.bytes(&entry.peer)?;
+        enc.uint(entry.handle as u64)?;
+        enc.uint(entry.timestamp_ms as u64)?;
+        enc.uint(entry.len as u64)?;
+        enc.bytes(&entry.value)?;
+    }
+
+    Ok(enc.len())
+}
+
+/// Restore the state from a dump made by [`capture`]. Control values are
+/// handed to `on_message` so the caller can pass them to the lighting task.
+///
+/// The dump is validated as it's applied, so a bad dump can leave the
+/// state partly restored.
+pub fn restore(data: &[u8], mut on_message: impl FnMut(Message)) -> Result<(), Error> {
+    let mut dec = cbor::Decoder::new(data);
+    for _ in 0..dec.map()? {
+        match dec.text()? {
+            "version" => match dec.uint()? {
+                VERSION => {}
+                v => return Err(Error::Version(v)),
+            },
+            "controls" => {
+                for _ in 0..dec.map()? {
+                    let key = dec.text()?;
+                    let value = dec.bytes()?;
+                    let idx = controls::CONTROLS
+                        .iter()
+                        .position(|c| c.name == key)
+                        .ok_or(Error::Invalid)?;
+                    on_message(controls::apply(idx, value).ok_or(Error::Invalid)?);
+                }
+            }
+            "lockout" => accept::LOCKOUT.store(dec.bool()?, Ordering::Relaxed),
+            "bthome counter" => {
+                let counter = dec.uint()?.try_into().map_err(|_| Error::Invalid)?;
+                bthome::set_counter(counter);
+            }
+            "audit" => restore_audit(&mut dec)?,
+            _ => dec.skip()?,
+        }
+    }
+
+    Ok(())
+}
+
+fn restore_audit(dec: &mut cbor::Decoder<'_>) -> Result<(), Error> {
+    let mut total = None;
+    let mut entries = [None; audit::TRAIL_LEN];
+    let mut count = 0;
+
+    for _ in 0..dec.map()? {
+        match dec.text()? {
+            "total" => total = Some(dec.uint()?.try_into().map_err(|_| Error::Invalid)?),
+            "entries" => {
+                for _ in 0..dec.array()? {
+                    if dec.array()? != 5 {
+                        return Err(Error::Invalid);
+                    }
+                    let entry = audit::Entry {
+                        peer: dec.bytes()?.try_into().map_err(|_| Error::Invalid)?,
+                        handle: dec.uint()?.try_into().map_err(|_| Error::Invalid)?,
+                        timestamp_ms: dec.uint()?.try_into().map_err(|_| Error::Invalid)?,
+                        len: dec.uint()?.try_into().map_err(|_| Error::Invalid)?,
+                        value: dec.bytes()?.try_into().map_err(|_| Error::Invalid)?,
+                    };
+                    // only the newest entries fit
+                    if count == entries.len() {
+                        entries.rotate_left(1);
+                        count -= 1;
+                    }
+                    entries[count] = Some(entry);
+                    count += 1;
+                }
+            }
+            _ => dec.skip()?,
+        }
+    }
+
+    audit::restore(
+        entries[..count].iter().flatten(),
+        total.unwrap_or(count as u16),
+    );
+    Ok(())
+}
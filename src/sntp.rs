@@ -0,0 +1,107 @@
+//! SNTP client feeding the clock with network time
+
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::driver::Driver;
+use embassy_net::udp::PacketMetadata;
+use embassy_net::udp::UdpSocket;
+use embassy_net::IpEndpoint;
+use embassy_net::Stack;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+
+use crate::clock;
+use crate::error;
+use crate::fault;
+
+const SERVER: &str = "pool.ntp.org";
+const NTP_PORT: u16 = 123;
+
+/// Local port we send requests from
+const LOCAL_PORT: u16 = 50123;
+
+/// Time between syncs once we have one
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Time between attempts after a failure
+const RETRY: Duration = Duration::from_secs(30);
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds from the NTP epoch (1900) to the unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+const PACKET_SIZE: usize = 48;
+
+pub async fn run<D: Driver>(stack: &Stack<D>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; PACKET_SIZE];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(LOCAL_PORT) {
+        error!("[sntp] bind failed: {:?}", e);
+        core::future::pending::<()>().await;
+    }
+
+    loop {
+        let wait = match sync(stack, &socket).await {
+            Ok(()) => INTERVAL,
+            Err(e) => {
+                error!("[sntp] sync failed: {}", e);
+                fault::raise(fault::NET_SYNC);
+                RETRY
+            }
+        };
+        Timer::after(wait).await;
+    }
+}
+
+async fn sync<D: Driver>(stack: &Stack<D>, socket: &UdpSocket<'_>) -> Result<(), &'static str> {
+    let addrs = stack
+        .dns_query(SERVER, DnsQueryType::A)
+        .await
+        .map_err(|_| "dns lookup failed")?;
+    let server = IpEndpoint::new(*addrs.first().ok_or("no address")?, NTP_PORT);
+
+    // version 4, client mode
+    let mut packet = [0; PACKET_SIZE];
+    packet[0] = (4 << 3) | 3;
+    let sent = Instant::now();
+    socket
+        .send_to(&packet, server)
+        .await
+        .map_err(|_| "send failed")?;
+
+    let n = match select(socket.recv_from(&mut packet), Timer::after(TIMEOUT)).await {
+        Either::First(Ok((n, _))) => n,
+        Either::First(Err(_)) => return Err("receive failed"),
+        Either::Second(()) => return Err("timed out"),
+    };
+    let rtt = sent.elapsed();
+    if n < PACKET_SIZE || packet[0] & 0x7 != 4 {
+        return Err("bad response");
+    }
+
+    // transmit timestamp: seconds and fraction since 1900
+    let secs = u32::from_be_bytes(packet[40..44].try_into().unwrap()) as u64;
+    let fraction = u32::from_be_bytes(packet[44..48].try_into().unwrap()) as u64;
+    let unix_ms = secs
+        .checked_sub(NTP_UNIX_OFFSET)
+        .ok_or("time before 1970")?
+        * 1000
+        + ((fraction * 1000) >> 32);
+
+    // the answer is somewhere within the round trip
+    let half_rtt = rtt.as_millis() / 2;
+    clock::report(clock::Source::Sntp, unix_ms + half_rtt, half_rtt as u32 + 1);
+    Ok(())
+}
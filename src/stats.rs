@@ -0,0 +1,273 @@
+//! Characteristic statistics
+//!
+//! Counts reads, writes and notifications, per characteristic value handle
+//! and per client address, with their rates over the last [`WINDOW`], so
+//! hot characteristics and chatty clients stand out, and disconnects by
+//! reason (see [`crate::disconnects`]). The GATT task and the
+//! notification path call [`record`]. The first [`HANDLES_MAX`]
+//! characteristics seen get a row, and the [`CLIENTS_MAX`] clients seen
+//! last; clients that went away keep theirs until a new one needs it.
+//!
+//! The table is read as CBOR over a paged dataset (see [`crate::paging`]).
+//! Selecting page 0 takes a [`snapshot`], which the following pages are cut
+//! from, so the table stays put while it's read out:
+//!
+//! ```text
+//! {
+//!   "window": seconds,
+//!   "handles": [[handle, reads, writes, notifies, reads/min, writes/min, notifies/min], ...],
+//!   "clients": [[address, reads, writes, notifies, reads/min, writes/min, notifies/min], ...],
+//!   "disconnects": [remote, timeout, mic failure, local, other],
+//! }
+//! ```
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use trouble_host::prelude::*;
+
+use crate::cbor;
+use crate::disconnects;
+use crate::error;
+use crate::paging;
+use crate::runtime;
+
+/// What rates are measured over
+pub const WINDOW: Duration = Duration::from_secs(10);
+
+/// Characteristics with a row
+pub const HANDLES_MAX: usize = 32;
+
+/// Clients with a row
+pub const CLIENTS_MAX: usize = 8;
+
+/// Largest encoded table
+const TABLE_MAX: usize = 1536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Op {
+    Read,
+    Write,
+    Notify,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Counters {
+    pub reads: u32,
+    pub writes: u32,
+    pub notifies: u32,
+}
+
+impl Counters {
+    const ZERO: Self = Self {
+        reads: 0,
+        writes: 0,
+        notifies: 0,
+    };
+
+    fn count(&mut self, op: Op) {
+        let counter = match op {
+            Op::Read => &mut self.reads,
+            Op::Write => &mut self.writes,
+            Op::Notify => &mut self.notifies,
+        };
+        *counter = counter.saturating_add(1);
+    }
+
+    fn encode(&self, enc: &mut cbor::Encoder<'_>) -> Result<(), cbor::Error> {
+        enc.uint(self.reads as u64)?;
+        enc.uint(self.writes as u64)?;
+        enc.uint(self.notifies as u64)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Row<K> {
+    key: K,
+    total: Counters,
+    /// Counted in the current window
+    window: Counters,
+    /// Counted in the last full window
+    last: Counters,
+    /// When it was last counted in, to pick the row to reuse
+    seen: Instant,
+}
+
+impl<K: Copy + PartialEq> Row<K> {
+    /// The row for `key` in `rows`, taking the one seen least recently if
+    /// `reuse` and there's none
+    fn find(rows: &mut [Option<Self>], key: K, reuse: bool, now: Instant) -> Option<&mut Self> {
+        let idx = match rows.iter().position(|r| r.is_some_and(|r| r.key == key)) {
+            Some(idx) => idx,
+            None => {
+                let idx = match rows.iter().position(Option::is_none) {
+                    Some(idx) => idx,
+                    None if reuse => rows
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, r)| r.map(|r| r.seen))
+                        .map(|(idx, _)| idx)?,
+                    None => return None,
+                };
+                rows[idx] = Some(Self {
+                    key,
+                    total: Counters::ZERO,
+                    window: Counters::ZERO,
+                    last: Counters::ZERO,
+                    seen: now,
+                });
+                idx
+            }
+        };
+        rows[idx].as_mut()
+    }
+
+    fn count(&mut self, op: Op, now: Instant) {
+        self.total.count(op);
+        self.window.count(op);
+        self.seen = now;
+    }
+
+    /// End the window, `lapsed` being whether more than one went by
+    fn roll(&mut self, lapsed: bool) {
+        self.last = if lapsed { Counters::ZERO } else { self.window };
+        self.window = Counters::ZERO;
+    }
+
+    /// The last full window's counts, per minute
+    fn rates(&self) -> Counters {
+        let scale = (60 / WINDOW.as_secs()) as u32;
+        Counters {
+            reads: self.last.reads.saturating_mul(scale),
+            writes: self.last.writes.saturating_mul(scale),
+            notifies: self.last.notifies.saturating_mul(scale),
+        }
+    }
+}
+
+struct Stats {
+    handles: [Option<Row<u16>>; HANDLES_MAX],
+    clients: [Option<Row<BdAddr>>; CLIENTS_MAX],
+    window_start: Instant,
+}
+
+impl Stats {
+    /// Start a new window if the current one is over
+    fn roll(&mut self, now: Instant) {
+        let end = self.window_start + WINDOW;
+        if now < end {
+            return;
+        }
+        let lapsed = now >= end + WINDOW;
+        for row in self.handles.iter_mut().flatten() {
+            row.roll(lapsed);
+        }
+        for row in self.clients.iter_mut().flatten() {
+            row.roll(lapsed);
+        }
+        self.window_start = if lapsed { now } else { end };
+    }
+}
+
+static STATS: Mutex<CriticalSectionRawMutex, RefCell<Stats>> = Mutex::new(RefCell::new(Stats {
+    handles: [None; HANDLES_MAX],
+    clients: [None; CLIENTS_MAX],
+    window_start: Instant::from_ticks(0),
+}));
+
+/// The table as of the last [`snapshot`], and its length
+static SNAPSHOT: Mutex<CriticalSectionRawMutex, RefCell<([u8; TABLE_MAX], usize)>> =
+    Mutex::new(RefCell::new(([0; TABLE_MAX], 0)));
+
+/// Count `op` on the characteristic with value handle `handle`, by `conn`
+pub fn record(conn: &Connection<'_>, handle: u16, op: Op) {
+    let now = runtime::now();
+    let peer = conn.peer_address();
+    STATS.lock(|stats| {
+        let mut stats = stats.borrow_mut();
+        stats.roll(now);
+        if let Some(row) = Row::find(&mut stats.handles, handle, false, now) {
+            row.count(op, now);
+        }
+        if let Some(row) = Row::find(&mut stats.clients, peer, true, now) {
+            row.count(op, now);
+        }
+    });
+}
+
+fn encode(stats: &Stats, out: &mut [u8]) -> Result<usize, cbor::Error> {
+    let mut enc = cbor::Encoder::new(out);
+    enc.map(4)?;
+
+    enc.text("window")?;
+    enc.uint(WINDOW.as_secs())?;
+
+    enc.text("handles")?;
+    enc.array(stats.handles.iter().flatten().count())?;
+    for row in stats.handles.iter().flatten() {
+        enc.array(7)?;
+        enc.uint(row.key as u64)?;
+        row.total.encode(&mut enc)?;
+        row.rates().encode(&mut enc)?;
+    }
+
+    enc.text("clients")?;
+    enc.array(stats.clients.iter().flatten().count())?;
+    for row in stats.clients.iter().flatten() {
+        enc.array(7)?;
+        enc.bytes(row.key.raw())?;
+        row.total.encode(&mut enc)?;
+        row.rates().encode(&mut enc)?;
+    }
+
+    enc.text("disconnects")?;
+    let counts = disconnects::counts();
+    enc.array(counts.len())?;
+    for count in counts {
+        enc.uint(count as u64)?;
+    }
+
+    Ok(enc.len())
+}
+
+/// Encode the table for [`Dataset`] to read out
+pub fn snapshot() {
+    let now = runtime::now();
+    let encoded = STATS.lock(|stats| {
+        let mut stats = stats.borrow_mut();
+        stats.roll(now);
+        SNAPSHOT.lock(|snapshot| {
+            let (buf, len) = &mut *snapshot.borrow_mut();
+            let encoded = encode(&stats, buf);
+            *len = *encoded.as_ref().unwrap_or(&0);
+            encoded
+        })
+    });
+    if let Err(e) = encoded {
+        error!("[stats] encoding failed: {:?}", e);
+    }
+}
+
+/// The table as of the last [`snapshot`]
+pub struct Dataset;
+
+impl paging::Dataset for Dataset {
+    fn len(&self) -> usize {
+        SNAPSHOT.lock(|snapshot| snapshot.borrow().1)
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        SNAPSHOT.lock(|snapshot| {
+            let (table, len) = &*snapshot.borrow();
+            let rest = table[..*len].get(offset..).unwrap_or(&[]);
+            let n = rest.len().min(buf.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            n
+        })
+    }
+}
@@ -0,0 +1,200 @@
+//! Powerfail-safe persistent records
+//!
+//! Every record gets two flash sectors. A commit goes to whichever sector
+//! doesn't hold the current copy, in two phases: first the data, then the
+//! header that marks the copy valid. The header is the last thing
+//! programmed, so a power cut at any point leaves either the new copy
+//! complete or the old one untouched. Loading picks the valid copy with
+//! the highest sequence number.
+//!
+//! The records live in the [`partitions::STORE`] partition, at the top of
+//! flash.
+
+use embassy_rp::flash::ERASE_SIZE;
+
+use crate::flash;
+use crate::integrity;
+use crate::partitions;
+
+const MAGIC: u32 = 0x434d_4954; // "CMIT"
+
+/// magic, sequence number, length, CRC32 of the data
+const HEADER_SIZE: usize = 16;
+
+/// Largest record that fits in a sector after the header
+pub const MAX_RECORD: usize = ERASE_SIZE - HEADER_SIZE;
+
+/// Number of slots, each taking two sectors
+const SLOTS: usize = partitions::STORE.sectors() / 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Slot {
+    /// Offset of the first of the slot's two sectors
+    base: u32,
+}
+
+impl Slot {
+    pub const CONFIG: Self = Self::new(0);
+    // 1 is free
+    pub const CALIBRATION: Self = Self::new(2);
+    pub const PINS: Self = Self::new(3);
+    pub const CRASH: Self = Self::new(4);
+    pub const RELAYS: Self = Self::new(5);
+    pub const METER: Self = Self::new(6);
+    pub const ROLLUP: Self = Self::new(7);
+
+    const fn new(idx: u32) -> Self {
+        Self {
+            base: partitions::STORE.sector(idx as usize * 2),
+        }
+    }
+
+    const fn sector(&self, copy: usize) -> u32 {
+        self.base + (copy * ERASE_SIZE) as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Flash(flash::Error),
+    /// The record is larger than [`MAX_RECORD`], or than the buffer it's
+    /// loaded into
+    TooLarge,
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Self::Flash(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    seq: u32,
+    len: usize,
+    crc: u32,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut out = [0; HEADER_SIZE];
+        out[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        out[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        out[8..12].copy_from_slice(&(self.len as u32).to_le_bytes());
+        out[12..16].copy_from_slice(&self.crc.to_le_bytes());
+        out
+    }
+
+    fn decode(data: &[u8; HEADER_SIZE]) -> Option<Self> {
+        let word = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        if word(0) != MAGIC || word(8) as usize > MAX_RECORD {
+            return None;
+        }
+        Some(Self {
+            seq: word(4),
+            len: word(8) as usize,
+            crc: word(12),
+        })
+    }
+}
+
+/// Read and verify one copy's header and data, `None` if it isn't valid
+fn verify(driver: &mut flash::Driver, sector: u32) -> Result<Option<Header>, flash::Error> {
+    let read = |driver: &mut flash::Driver, offset: u32, buf: &mut [u8]| {
+        driver
+            .blocking_read(offset, buf)
+            .map_err(flash::Error::Flash)
+    };
+
+    let mut header = [0; HEADER_SIZE];
+    read(driver, sector, &mut header)?;
+    let Some(header) = Header::decode(&header) else {
+        return Ok(None);
+    };
+
+    let mut crc = integrity::Crc32::new();
+    let mut chunk = [0; 64];
+    let mut offset = 0;
+    while offset < header.len {
+        let n = chunk.len().min(header.len - offset);
+        read(
+            driver,
+            sector + (HEADER_SIZE + offset) as u32,
+            &mut chunk[..n],
+        )?;
+        crc.update(&chunk[..n]);
+        offset += n;
+    }
+    Ok((crc.finish() == header.crc).then_some(header))
+}
+
+/// The current copy of `slot` and its header
+fn current(
+    driver: &mut flash::Driver,
+    slot: Slot,
+) -> Result<Option<(usize, Header)>, flash::Error> {
+    let mut best: Option<(usize, Header)> = None;
+    for copy in 0..2 {
+        let Some(header) = verify(driver, slot.sector(copy))? else {
+            continue;
+        };
+        // sequence numbers wrap, compare them by distance
+        let newer = match best {
+            Some((_, b)) => header.seq.wrapping_sub(b.seq) as i32 > 0,
+            None => true,
+        };
+        if newer {
+            best = Some((copy, header));
+        }
+    }
+    Ok(best)
+}
+
+/// Load the current contents of `slot` into `buf`, returning their
+/// length. `None` if the slot was never committed.
+pub async fn load(slot: Slot, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+    flash::with_flash(|driver| {
+        let Some((copy, header)) = current(driver, slot)? else {
+            return Ok(None);
+        };
+        let buf = buf.get_mut(..header.len).ok_or(Error::TooLarge)?;
+        driver
+            .blocking_read(slot.sector(copy) + HEADER_SIZE as u32, buf)
+            .map_err(|e| Error::Flash(flash::Error::Flash(e)))?;
+        Ok(Some(header.len))
+    })
+    .await
+}
+
+/// Commit `data` as the new contents of `slot`
+pub async fn commit(slot: Slot, data: &[u8]) -> Result<(), Error> {
+    if data.len() > MAX_RECORD {
+        return Err(Error::TooLarge);
+    }
+
+    let (copy, seq) = match flash::with_flash(|driver| current(driver, slot)).await? {
+        Some((copy, header)) => (1 - copy, header.seq.wrapping_add(1)),
+        None => (0, 0),
+    };
+    let sector = slot.sector(copy);
+
+    // phase one: the data, into the sector that doesn't hold the current copy
+    flash::execute(flash::Op::Erase {
+        from: sector,
+        to: sector + ERASE_SIZE as u32,
+    })
+    .await?;
+    flash::write(sector + HEADER_SIZE as u32, data).await?;
+
+    // phase two: the header makes it valid
+    let header = Header {
+        seq,
+        len: data.len(),
+        crc: integrity::crc32(data),
+    };
+    flash::write(sector, &header.encode()).await?;
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+//! Localized strings for the companion app
+//!
+//! The app shows firmware-defined things like alarm texts without knowing
+//! them up front: it picks a locale and reads the table for it in pages
+//! (see [`crate::paging`]). The table is a sequence of entries, each
+//! `[id, len]` followed by `len` bytes of UTF-8. Ids are stable across
+//! firmware versions, the app falls back to its own text for ids it
+//! doesn't get.
+
+use crate::alarm;
+use crate::paging;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Locale {
+    En,
+    De,
+    Fr,
+}
+
+impl Locale {
+    /// Parse a locale write, an ISO 639-1 language code optionally
+    /// followed by a region (`en`, `de-AT`), which is ignored
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        match value.get(..2)? {
+            b"en" => Some(Self::En),
+            b"de" => Some(Self::De),
+            b"fr" => Some(Self::Fr),
+            _ => None,
+        }
+    }
+}
+
+/// Name of signal `n`
+pub const fn signal(n: usize) -> u8 {
+    0x01 + n as u8
+}
+
+/// Text for an alarm condition, by its code in the alarm characteristic
+pub const fn condition(condition: alarm::Condition) -> u8 {
+    0x10 + condition as u8
+}
+
+/// Text for an alarm state, by its code in the alarm characteristic
+pub const fn state(code: u8) -> u8 {
+    0x20 + code
+}
+
+/// id, then the text in each locale in [`Locale`] order
+const STRINGS: [(u8, [&str; 3]); 10] = [
+    (signal(alarm::ADC), ["Sensor", "Sensor", "Capteur"]),
+    (
+        signal(alarm::BUS_VOLTAGE),
+        ["Bus voltage", "Busspannung", "Tension du bus"],
+    ),
+    (signal(alarm::CURRENT), ["Current", "Strom", "Courant"]),
+    (signal(alarm::DISTANCE), ["Distance", "Abstand", "Distance"]),
+    (signal(alarm::LEVEL), ["Tank level", "Füllstand", "Niveau"]),
+    (
+        condition(alarm::Condition::Low),
+        ["Below limit", "Unter Grenzwert", "Sous la limite"],
+    ),
+    (
+        condition(alarm::Condition::High),
+        ["Above limit", "Über Grenzwert", "Au-dessus de la limite"],
+    ),
+    (state(1), ["Alarm", "Alarm", "Alarme"]),
+    (
+        state(2),
+        [
+            "Alarm cleared, acknowledge to reset",
+            "Alarm behoben, zum Zurücksetzen quittieren",
+            "Alarme terminée, acquitter pour réinitialiser",
+        ],
+    ),
+    (state(3), ["Acknowledged", "Quittiert", "Acquittée"]),
+];
+
+/// The string table of a locale, as a pageable dataset
+pub struct Table(pub Locale);
+
+impl Table {
+    fn entries(&self) -> impl Iterator<Item = (u8, &'static str)> + '_ {
+        STRINGS
+            .iter()
+            .map(|(id, texts)| (*id, texts[self.0 as usize]))
+    }
+}
+
+impl paging::Dataset for Table {
+    fn len(&self) -> usize {
+        self.entries().map(|(_, text)| 2 + text.len()).sum()
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        let mut pos = 0;
+        for (id, text) in self.entries() {
+            let header = [id, text.len() as u8];
+            for part in [&header[..], text.as_bytes()] {
+                let start = pos;
+                pos += part.len();
+                if pos <= offset || copied == buf.len() {
+                    continue;
+                }
+
+                let src = &part[offset.saturating_sub(start)..];
+                let n = src.len().min(buf.len() - copied);
+                buf[copied..copied + n].copy_from_slice(&src[..n]);
+                copied += n;
+            }
+        }
+        copied
+    }
+}
@@ -0,0 +1,46 @@
+//! Notification subscriptions
+//!
+//! The attribute table keeps one value per CCCD, whoever wrote it last, so
+//! it can't say which connection subscribed. The GATT task [`set`]s a
+//! connection's subscription as it writes a CCCD, and values are only
+//! notified to connections that [`is_subscribed`]. Notifications and
+//! indications count alike. A connection starts out subscribed to nothing,
+//! until it writes the CCCDs again.
+
+use bt_hci::param::ConnHandle;
+
+use crate::blue::MAX_ATTRIBUTES;
+use crate::session;
+
+/// Bits for every attribute handle, handles starting at 1
+const WORDS: usize = (MAX_ATTRIBUTES + 1).div_ceil(32);
+
+/// Characteristic value handles each connection subscribed to
+static SUBSCRIBED: session::PerConnection<[u32; WORDS]> = session::PerConnection::new([0; WORDS]);
+
+/// Record whether `conn` subscribed to the characteristic with value
+/// handle `handle`
+pub fn set(conn: ConnHandle, handle: u16, on: bool) {
+    let (word, bit) = (handle as usize / 32, 1 << (handle % 32));
+    if word >= WORDS {
+        return;
+    }
+    SUBSCRIBED.update(conn, |mut bits| {
+        if on {
+            bits[word] |= bit;
+        } else {
+            bits[word] &= !bit;
+        }
+        bits
+    });
+}
+
+/// Whether `conn` subscribed to the characteristic with value handle
+/// `handle`
+pub fn is_subscribed(conn: ConnHandle, handle: u16) -> bool {
+    let (word, bit) = (handle as usize / 32, 1 << (handle % 32));
+    SUBSCRIBED
+        .get(conn)
+        .get(word)
+        .is_some_and(|bits| bits & bit != 0)
+}
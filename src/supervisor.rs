@@ -0,0 +1,101 @@
+//! Restarts of the BLE stack
+//!
+//! The BLE tasks aren't spawned on the executor. They borrow the host
+//! resources, the stack and the GATT server, which live in the frame of
+//! [`crate::blue::run`] and are built again every time the radio
+//! restarts, and a spawned task has to be `'static`. It can't be generic
+//! over the controller either, as `run()` is. So `run()` polls the
+//! runner, the GATT handler, advertising and the watchdogs in one select,
+//! and the first of them to end drops the others, in no particular order.
+//! There's no restart of one task but advertising, and no ordered
+//! shutdown.
+//!
+//! A [`Child`] decides whether a failure gets a restart, after a backoff,
+//! or is escalated. Only two things restart: advertising, inside `run()`,
+//! and the whole stack, which main builds again after `run()` ends with an
+//! [`Exit`] saying which task ended it and why.
+
+use core::fmt::Debug;
+
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::Timer;
+use trouble_host::BleHostError;
+
+use crate::error;
+use crate::fmt;
+use crate::hcilink;
+use crate::info;
+
+/// First delay before a restart, doubled for every consecutive failure
+const BACKOFF: Duration = Duration::from_millis(250);
+
+/// Longest delay before a restart
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// A child that ran this long before failing starts over with a fresh
+/// restart budget
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Why the BLE stack stopped
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Exit<E> {
+    /// The host runner failed; nothing works without it
+    Runner(BleHostError<E>),
+    /// Advertising kept failing after all its restarts
+    Advertising(BleHostError<E>),
+    /// A task stopped making progress, see [`crate::monitor`]
+    Stalled(&'static str),
+    /// The controller reset or stopped answering, see [`crate::hcilink`]
+    Controller(hcilink::Loss),
+}
+
+pub struct Child {
+    name: &'static str,
+    max_restarts: u32,
+    restarts: u32,
+    started: Instant,
+}
+
+impl Child {
+    pub fn new(name: &'static str, max_restarts: u32) -> Self {
+        Self {
+            name,
+            max_restarts,
+            restarts: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Report a failure. Waits out the backoff and returns `true` if the
+    /// child should be restarted, `false` if it should be escalated.
+    pub async fn failed(&mut self, error: &impl Debug) -> bool {
+        error!("[supervisor] {} failed: {:?}", self.name, fmt::Dbg(error));
+
+        if self.started.elapsed() >= STABLE_AFTER {
+            self.restarts = 0;
+        }
+        if self.restarts >= self.max_restarts {
+            error!(
+                "[supervisor] {} failed {} times, giving up",
+                self.name,
+                self.restarts + 1
+            );
+            return false;
+        }
+
+        let backoff = (BACKOFF * (1 << self.restarts.min(16))).min(MAX_BACKOFF);
+        self.restarts += 1;
+        info!(
+            "[supervisor] restarting {} in {}ms (attempt {})",
+            self.name,
+            backoff.as_millis(),
+            self.restarts
+        );
+        Timer::after(backoff).await;
+
+        self.started = Instant::now();
+        true
+    }
+}
@@ -0,0 +1,165 @@
+//! System control: reboots, bootloader entry and device identity
+
+use core::cell::Cell;
+use core::fmt;
+
+use embassy_rp::flash::Blocking;
+use embassy_rp::flash::Flash;
+use embassy_rp::peripherals::FLASH;
+use embassy_rp::peripherals::WATCHDOG;
+use embassy_rp::watchdog::Watchdog;
+use embassy_rp::Peripheral;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::error;
+use crate::fmtbuf;
+use crate::info;
+
+/// Watchdog scratch register used to carry a boot request across a reset.
+/// [`crate::watchdog`] has scratch 1.
+const BOOT_REQUEST_SCRATCH: usize = 0;
+
+/// Magic left in the scratch register to ask for the UF2 bootloader
+const BOOT_REQUEST_BOOTSEL: u32 = 0xb007_5e1c;
+
+fn watchdog() -> Watchdog {
+    // SAFETY: only touched from here, one call at a time, and from
+    // crate::watchdog, which has its own scratch register and leaves
+    // resets to us
+    Watchdog::new(unsafe { WATCHDOG::steal() })
+}
+
+/// Reboot straight back into the application.
+pub fn reboot() -> ! {
+    info!("[system] rebooting");
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// Reboot into the UF2/BOOTSEL bootloader.
+///
+/// The request is left in a watchdog scratch register (which survives the
+/// reset) and picked up by [`handle_boot_request`] on the next boot, so
+/// we jump into the bootrom from a clean state.
+pub fn reboot_to_bootloader() -> ! {
+    info!("[system] rebooting into bootloader");
+    let mut watchdog = watchdog();
+    watchdog.set_scratch(BOOT_REQUEST_SCRATCH, BOOT_REQUEST_BOOTSEL);
+    watchdog.trigger_reset();
+
+    // the reset takes a few cycles to come
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Finish a bootloader request made before the last reset, if any.
+///
+/// Must be called early in `main`, before anything else is set up.
+pub fn handle_boot_request() {
+    let mut watchdog = watchdog();
+    if watchdog.get_scratch(BOOT_REQUEST_SCRATCH) != BOOT_REQUEST_BOOTSEL {
+        return;
+    }
+
+    watchdog.set_scratch(BOOT_REQUEST_SCRATCH, 0);
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+
+    // the bootrom doesn't come back
+    cortex_m::asm::udf()
+}
+
+/// Size of the flash chip on the Pico W
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Unique ID of the board, read from the flash chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceId(pub [u8; 8]);
+
+static DEVICE_ID: Mutex<CriticalSectionRawMutex, Cell<DeviceId>> =
+    Mutex::new(Cell::new(DeviceId([0; 8])));
+
+impl DeviceId {
+    /// Read the ID from the flash chip. Call once at boot, before anything
+    /// else uses the flash.
+    pub fn init(flash: impl Peripheral<P = FLASH>) {
+        let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(flash);
+        let mut id = [0; 8];
+        match flash.blocking_unique_id(&mut id) {
+            Ok(()) => DEVICE_ID.lock(|cell| cell.set(DeviceId(id))),
+            Err(e) => error!("[system] failed to read flash unique id: {:?}", e),
+        }
+    }
+
+    /// The ID read by [`DeviceId::init`], all zeros before that
+    pub fn get() -> Self {
+        DEVICE_ID.lock(|cell| cell.get())
+    }
+
+    /// The ID in lowercase hex, as its [`Display`](fmt::Display) shows it
+    pub fn hex(&self) -> [u8; 16] {
+        let mut out = [0; 16];
+        fmtbuf::hex(&self.0, &mut out);
+        out
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // hex digits are ascii
+        f.write_str(core::str::from_utf8(&self.hex()).unwrap_or_default())
+    }
+}
+
+/// Commands accepted on the control characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    Reboot,
+    Bootloader,
+}
+
+impl Command {
+    /// Parse a control write.
+    ///
+    /// Writes are `[opcode, !opcode]`; the complement byte guards against
+    /// stray writes rebooting the device.
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let [op, check, ..] = *value else {
+            return None;
+        };
+        if op != !check {
+            return None;
+        }
+
+        match op {
+            0x01 => Some(Self::Reboot),
+            0x02 => Some(Self::Bootloader),
+            _ => None,
+        }
+    }
+
+    pub fn execute(self) -> ! {
+        match self {
+            Self::Reboot => reboot(),
+            Self::Bootloader => reboot_to_bootloader(),
+        }
+    }
+}
+
+crate::register_command!(REBOOT, "reboot", "", "reboot the board", |args| {
+    args.end()?;
+    reboot()
+});
+
+crate::register_command!(
+    BOOTLOADER,
+    "bootloader",
+    "",
+    "reboot into the UF2 bootloader",
+    |args| {
+        args.end()?;
+        reboot_to_bootloader()
+    }
+);
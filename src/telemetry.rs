@@ -0,0 +1,495 @@
+//! Telemetry sinks
+//!
+//! Sensor code hands every reading of a [`Signal`] to [`record`], or to
+//! [`record_measurement`] with its [`Measurement`], and doesn't know where
+//! it goes from there. Each output path is a [`Sink`] running [`route`],
+//! which takes the samples its [`Route`] asks for, no more often than its
+//! interval, formats them and sends them on. The
+//! same sample fans out to every sink, so adding one is a [`Sink`] impl
+//! with its config keys and a task, and nothing in the sensors.
+//!
+//! | sink    | signals                | interval                        | format                        |
+//! |---------|------------------------|---------------------------------|-------------------------------|
+//! | console | `telemetry.console`    | `telemetry.console_interval`    | `telemetry.console_format`    |
+//! | MQTT    | `telemetry.mqtt`       | `telemetry.mqtt_interval`       | `telemetry.mqtt_format`       |
+//! | BLE     | `telemetry.ble`        | `telemetry.ble_interval`        | `telemetry.ble_format`        |
+//!
+//! Signals are comma separated [`Signal`] names, like
+//! `temperature,wind speed`, or `*` for all of them. The interval is the
+//! shortest time in ms between two samples of a signal that go out, 0 for
+//! every one. The [`Format`]s:
+//!
+//! - `text`: `temperature 21.53 C`, then `stale` or `fault` for each
+//!   quality flag
+//! - `json`: `{"signal":"temperature","value":21.53,"unit":"C",
+//!   "age_ms":12,"quality":0}`, the age when it went out
+//! - `binary`: `[signal, value: i32, quality]`, little endian, the value
+//!   in the signal's own unit ([`Signal::decimals`] digits after the
+//!   point), the quality flags as in [`crate::measurement`]
+//!
+//! A sensor that fails keeps sending its last value, flagged as faulty.
+//!
+//! The MQTT sink publishes on `<telemetry.topic>/<signal>` through the
+//! gateway's broker session (see [`crate::gateway`]), and the BLE sink
+//! notifies the telemetry characteristic. Both queue a few samples and
+//! drop the rest while nobody takes them.
+
+use core::fmt::Write;
+use core::future::Future;
+
+use embassy_futures::join::join3;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_time::Duration;
+use embassy_time::Instant;
+
+use crate::config;
+use crate::config::Text;
+use crate::error;
+use crate::fmtbuf;
+use crate::info;
+use crate::measurement;
+use crate::measurement::Measurement;
+use crate::measurement::Unit;
+
+crate::config_key!(
+    /// Signals the console prints, comma separated or `*`
+    pub CONSOLE: Text = "telemetry.console",
+    Text::new(""),
+);
+
+crate::config_key!(
+    /// Shortest time between two printed samples of a signal in ms
+    pub CONSOLE_INTERVAL: u32 = "telemetry.console_interval",
+    10_000,
+);
+
+crate::config_key!(
+    /// How the console prints samples: "text", "json" or "binary"
+    pub CONSOLE_FORMAT: Text = "telemetry.console_format",
+    Text::new("text"),
+);
+
+crate::config_key!(
+    /// Signals published to the gateway's broker, comma separated or `*`
+    pub MQTT: Text = "telemetry.mqtt",
+    Text::new("*"),
+);
+
+crate::config_key!(
+    /// Shortest time between two published samples of a signal in ms
+    pub MQTT_INTERVAL: u32 = "telemetry.mqtt_interval",
+    10_000,
+);
+
+crate::config_key!(
+    /// How samples are published: "text", "json" or "binary"
+    pub MQTT_FORMAT: Text = "telemetry.mqtt_format",
+    Text::new("json"),
+);
+
+crate::config_key!(
+    /// Topic the per-signal topics go under
+    pub TOPIC: Text = "telemetry.topic",
+    Text::new("mansion/telemetry"),
+);
+
+crate::config_key!(
+    /// Signals notified over BLE, comma separated or `*`
+    pub BLE: Text = "telemetry.ble",
+    Text::new("*"),
+);
+
+crate::config_key!(
+    /// Shortest time between two notified samples of a signal in ms
+    pub BLE_INTERVAL: u32 = "telemetry.ble_interval",
+    0,
+);
+
+crate::config_key!(
+    /// How samples are notified: "text", "json" or "binary"
+    pub BLE_FORMAT: Text = "telemetry.ble_format",
+    Text::new("binary"),
+);
+
+/// Number of signals
+pub const SIGNALS: usize = 11;
+
+/// Longest formatted sample
+pub const PAYLOAD_MAX: usize = 96;
+
+/// Most sinks subscribed at once
+const SINKS_MAX: usize = 4;
+
+/// Samples a slow sink can fall behind by before losing the oldest
+const SAMPLES_CAP: usize = 8;
+
+/// Formatted samples queued for the broker or the notifications
+const QUEUE_CAP: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Signal {
+    /// In 0.01°C
+    Temperature = 0,
+    /// In 0.01%
+    Humidity = 1,
+    /// In 0.1Pa
+    Pressure = 2,
+    /// Mean wind speed in 0.01 m/s
+    WindSpeed = 3,
+    /// In 0.01°
+    WindDirection = 4,
+    /// Over the rainfall window, in µm
+    Rainfall = 5,
+    /// The energy meter's, in mV
+    BusVoltage = 6,
+    /// The energy meter's, in µA
+    Current = 7,
+    /// In mm
+    Distance = 8,
+    /// The tank's, in 0.01%
+    Level = 9,
+    /// The battery's charge in %
+    Battery = 10,
+}
+
+impl Signal {
+    pub const ALL: [Self; SIGNALS] = [
+        Self::Temperature,
+        Self::Humidity,
+        Self::Pressure,
+        Self::WindSpeed,
+        Self::WindDirection,
+        Self::Rainfall,
+        Self::BusVoltage,
+        Self::Current,
+        Self::Distance,
+        Self::Level,
+        Self::Battery,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::Humidity => "humidity",
+            Self::Pressure => "pressure",
+            Self::WindSpeed => "wind speed",
+            Self::WindDirection => "wind direction",
+            Self::Rainfall => "rainfall",
+            Self::BusVoltage => "bus voltage",
+            Self::Current => "current",
+            Self::Distance => "distance",
+            Self::Level => "level",
+            Self::Battery => "battery",
+        }
+    }
+
+    /// Unit of the formatted value
+    pub fn unit(self) -> Unit {
+        match self {
+            Self::Temperature => Unit::Celsius,
+            Self::Humidity | Self::Level | Self::Battery => Unit::Percent,
+            Self::Pressure => Unit::Pascal,
+            Self::WindSpeed => Unit::MetresPerSecond,
+            Self::WindDirection => Unit::Degrees,
+            Self::Rainfall | Self::Distance => Unit::Millimetres,
+            Self::BusVoltage => Unit::Volts,
+            Self::Current => Unit::Amperes,
+        }
+    }
+
+    /// Digits of the value after the point, in [`Self::unit`]
+    pub fn decimals(self) -> u32 {
+        match self {
+            Self::Temperature
+            | Self::Humidity
+            | Self::WindSpeed
+            | Self::WindDirection
+            | Self::Level => 2,
+            Self::Pressure => 1,
+            Self::Rainfall | Self::BusVoltage => 3,
+            Self::Current => 6,
+            Self::Distance | Self::Battery => 0,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.name() == name)
+    }
+}
+
+/// A reading of a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sample {
+    pub signal: Signal,
+    /// In the signal's unit, see [`Signal`]
+    pub measurement: Measurement<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Format {
+    Text,
+    Json,
+    Binary,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            "binary" => Some(Self::Binary),
+            _ => None,
+        }
+    }
+}
+
+/// A formatted sample
+#[derive(Clone)]
+pub struct Payload {
+    pub signal: Signal,
+    len: usize,
+    data: [u8; PAYLOAD_MAX],
+}
+
+impl Payload {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+/// Where a sink's settings are
+pub struct Route {
+    pub name: &'static str,
+    /// Signals to send, comma separated or `*`
+    pub signals: &'static config::Key<Text>,
+    /// Shortest time between two samples of a signal in ms
+    pub interval: &'static config::Key<u32>,
+    /// A [`Format`] name
+    pub format: &'static config::Key<Text>,
+}
+
+impl Route {
+    fn wants(&self, signal: Signal) -> bool {
+        let signals = self.signals.get();
+        signals
+            .as_str()
+            .split(',')
+            .map(str::trim)
+            .any(|name| name == "*" || name == signal.name())
+    }
+
+    fn format(&self) -> Format {
+        let format = self.format.get();
+        Format::parse(format.as_str()).unwrap_or_else(|| {
+            error!(
+                "[telemetry] {} has invalid format {:?}",
+                self.format.name,
+                format.as_str()
+            );
+            Format::Text
+        })
+    }
+}
+
+/// An output path for samples
+pub trait Sink {
+    fn route(&self) -> &Route;
+
+    /// Send a sample, formatted as the route asks
+    fn send(&mut self, payload: &Payload) -> impl Future<Output = ()>;
+}
+
+static SAMPLES: PubSubChannel<CriticalSectionRawMutex, Sample, SAMPLES_CAP, SINKS_MAX, 0> =
+    PubSubChannel::new();
+
+/// Samples for the broker, see [`crate::gateway`]
+pub static MQTT_QUEUE: Channel<CriticalSectionRawMutex, Payload, QUEUE_CAP> = Channel::new();
+
+/// Samples to notify, see [`crate::blue`]
+pub static BLE_QUEUE: Channel<CriticalSectionRawMutex, Payload, QUEUE_CAP> = Channel::new();
+
+/// Hand a good reading taken now to the sinks
+pub fn record(signal: Signal, value: i32) {
+    record_measurement(signal, Measurement::new(value, signal.unit()));
+}
+
+/// Hand a measurement to the sinks
+pub fn record_measurement(signal: Signal, measurement: Measurement<i32>) {
+    SAMPLES.immediate_publisher().publish_immediate(Sample {
+        signal,
+        measurement,
+    });
+}
+
+/// `value` with `decimals` digits after the point
+fn write_value(out: &mut impl Write, value: i32, decimals: u32) {
+    let scale = 10u32.pow(decimals);
+    let sign = if value < 0 { "-" } else { "" };
+    let magnitude = value.unsigned_abs();
+    let _ = if decimals == 0 {
+        write!(out, "{}{}", sign, magnitude)
+    } else {
+        write!(
+            out,
+            "{}{}.{:0width$}",
+            sign,
+            magnitude / scale,
+            magnitude % scale,
+            width = decimals as usize
+        )
+    };
+}
+
+/// Names of the quality flags, for the text format
+const QUALITY_NAMES: [(u8, &str); 2] =
+    [(measurement::STALE, "stale"), (measurement::FAULT, "fault")];
+
+pub fn format(sample: &Sample, format: Format) -> Payload {
+    let signal = sample.signal;
+    let m = &sample.measurement;
+    let mut payload = Payload {
+        signal,
+        len: 0,
+        data: [0; PAYLOAD_MAX],
+    };
+    if format == Format::Binary {
+        payload.data[0] = signal as u8;
+        payload.data[1..5].copy_from_slice(&m.value.to_le_bytes());
+        payload.data[5] = m.quality;
+        payload.len = 6;
+        return payload;
+    }
+    let mut out = fmtbuf::Writer::new(&mut payload.data);
+    if format == Format::Json {
+        let _ = write!(out, "{{\"signal\":\"{}\",\"value\":", signal.name());
+        write_value(&mut out, m.value, signal.decimals());
+        let _ = write!(
+            out,
+            ",\"unit\":\"{}\",\"age_ms\":{},\"quality\":{}}}",
+            m.unit.symbol(),
+            m.age().as_millis(),
+            m.quality
+        );
+    } else {
+        let _ = write!(out, "{} ", signal.name());
+        write_value(&mut out, m.value, signal.decimals());
+        let _ = write!(out, " {}", m.unit.symbol());
+        for (flag, name) in QUALITY_NAMES {
+            if m.quality & flag != 0 {
+                let _ = write!(out, " {}", name);
+            }
+        }
+    }
+    payload.len = out.len();
+    payload
+}
+
+/// Feed `sink` the samples its route asks for, until there are no
+/// subscribers left for it
+pub async fn route(sink: &mut impl Sink) {
+    let Ok(mut samples) = SAMPLES.subscriber() else {
+        error!(
+            "[telemetry] no sample subscriber left for {}",
+            sink.route().name
+        );
+        return;
+    };
+    let mut sent: [Option<Instant>; SIGNALS] = [None; SIGNALS];
+    loop {
+        let sample = samples.next_message_pure().await;
+        let route = sink.route();
+        if !route.wants(sample.signal) {
+            continue;
+        }
+        let now = Instant::now();
+        let interval = Duration::from_millis(route.interval.get() as u64);
+        let last = &mut sent[sample.signal as usize];
+        if last.is_some_and(|at| now.duration_since(at) < interval) {
+            continue;
+        }
+        *last = Some(now);
+        let payload = format(&sample, route.format());
+        sink.send(&payload).await;
+    }
+}
+
+/// Prints samples
+pub struct Console;
+
+static CONSOLE_ROUTE: Route = Route {
+    name: "console",
+    signals: &CONSOLE,
+    interval: &CONSOLE_INTERVAL,
+    format: &CONSOLE_FORMAT,
+};
+
+impl Sink for Console {
+    fn route(&self) -> &Route {
+        &CONSOLE_ROUTE
+    }
+
+    async fn send(&mut self, payload: &Payload) {
+        match core::str::from_utf8(payload.as_bytes()) {
+            Ok(text) => info!("[telemetry] {}", text),
+            Err(_) => info!("[telemetry] {:?}", payload.as_bytes()),
+        }
+    }
+}
+
+/// Queues samples for another task to take
+pub struct Queue {
+    route: &'static Route,
+    queue: &'static Channel<CriticalSectionRawMutex, Payload, QUEUE_CAP>,
+}
+
+impl Sink for Queue {
+    fn route(&self) -> &Route {
+        self.route
+    }
+
+    async fn send(&mut self, payload: &Payload) {
+        // nobody's taking them, the newest one can wait for the next
+        let _ = self.queue.try_send(payload.clone());
+    }
+}
+
+static MQTT_ROUTE: Route = Route {
+    name: "mqtt",
+    signals: &MQTT,
+    interval: &MQTT_INTERVAL,
+    format: &MQTT_FORMAT,
+};
+
+static BLE_ROUTE: Route = Route {
+    name: "ble",
+    signals: &BLE,
+    interval: &BLE_INTERVAL,
+    format: &BLE_FORMAT,
+};
+
+pub async fn run() {
+    let mut mqtt = Queue {
+        route: &MQTT_ROUTE,
+        queue: &MQTT_QUEUE,
+    };
+    let mut ble = Queue {
+        route: &BLE_ROUTE,
+        queue: &BLE_QUEUE,
+    };
+    join3(route(&mut Console), route(&mut mqtt), route(&mut ble)).await;
+}
+
+#[embassy_executor::task]
+async fn task() {
+    run().await
+}
+
+crate::register_module!(MODULE, "telemetry", |resources| {
+    resources.spawner.must_spawn(task())
+});
@@ -0,0 +1,126 @@
+//! Notify-on-change thresholds
+//!
+//! A characteristic with a report delta only notifies once its value has
+//! moved far enough from the last one notified, so slowly changing values
+//! like temperature don't burn radio time on noise. Deltas are absolute or
+//! a percentage of the last reported value, and set per characteristic.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Max number of characteristics with a delta
+const DELTAS_MAX: usize = 4;
+
+/// Size of a delta write
+pub const REQUEST_SIZE: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Delta {
+    /// Change of at least this much
+    Absolute(u32),
+    /// Change of at least this many percent of the last reported value
+    Percent(u16),
+}
+
+impl Delta {
+    /// Whether going from `last` to `value` is a change worth reporting
+    pub fn exceeded(&self, last: i32, value: i32) -> bool {
+        let change = (value as i64 - last as i64).unsigned_abs();
+        match *self {
+            Self::Absolute(min) => change >= min as u64,
+            // anything is a big change from zero
+            Self::Percent(pct) => change * 100 >= pct as u64 * (last as i64).unsigned_abs(),
+        }
+    }
+}
+
+/// A write to the delta characteristic: `[handle: u16, kind, amount: u16]`
+/// little endian, kind 0 to clear, 1 absolute, 2 percent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Request {
+    pub handle: u16,
+    pub delta: Option<Delta>,
+}
+
+impl Request {
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let [h0, h1, kind, a0, a1, ..] = *value else {
+            return None;
+        };
+        let amount = u16::from_le_bytes([a0, a1]);
+        let delta = match kind {
+            0 => None,
+            1 => Some(Delta::Absolute(amount as u32)),
+            2 => Some(Delta::Percent(amount)),
+            _ => return None,
+        };
+
+        Some(Self {
+            handle: u16::from_le_bytes([h0, h1]),
+            delta,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    handle: u16,
+    delta: Delta,
+    /// Last value reported, `None` until the first
+    last: Option<i32>,
+}
+
+static DELTAS: Mutex<CriticalSectionRawMutex, RefCell<[Option<Entry>; DELTAS_MAX]>> =
+    Mutex::new(RefCell::new([None; DELTAS_MAX]));
+
+/// Set or clear the delta of `handle`. Returns false if [`DELTAS_MAX`]
+/// others already have one.
+pub fn set(handle: u16, delta: Option<Delta>) -> bool {
+    DELTAS.lock(|deltas| {
+        let mut deltas = deltas.borrow_mut();
+        let existing = deltas
+            .iter()
+            .position(|e| matches!(e, Some(e) if e.handle == handle));
+        let Some(delta) = delta else {
+            if let Some(idx) = existing {
+                deltas[idx] = None;
+            }
+            return true;
+        };
+
+        match existing.or_else(|| deltas.iter().position(|e| e.is_none())) {
+            Some(idx) => {
+                deltas[idx] = Some(Entry {
+                    handle,
+                    delta,
+                    last: None,
+                });
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Whether `value` should be notified on `handle`, remembering it as the
+/// last reported value if so. Always true without a delta.
+pub fn report(handle: u16, value: i32) -> bool {
+    DELTAS.lock(|deltas| {
+        let mut deltas = deltas.borrow_mut();
+        let Some(entry) = deltas.iter_mut().flatten().find(|e| e.handle == handle) else {
+            return true;
+        };
+        if entry
+            .last
+            .is_some_and(|last| !entry.delta.exceeded(last, value))
+        {
+            return false;
+        }
+        entry.last = Some(value);
+        true
+    })
+}
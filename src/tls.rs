@@ -0,0 +1,176 @@
+//! TLS for outbound connections
+//!
+//! The MQTT gateway (see [`crate::gateway`]) and firmware downloads (see
+//! [`crate::ota`]) can go over TLS 1.3 with a pre-shared key instead of in
+//! the clear. A PSK needs no certificate store or parsing on the device,
+//! and it authenticates both ends: only a server holding the key finishes
+//! the handshake, which is why the handshake doesn't check certificates.
+//!
+//! The key is provisioned with the Wi-Fi credentials, as two more config
+//! keys: `tls.identity`, the name the servers know the key by, and
+//! `tls.psk`, the key in hex. Without both, TLS connections fail rather
+//! than fall back to plaintext.
+//!
+//! A TLS record is up to 16 KiB, too big to go on a task's stack, and RAM
+//! has no room for a set of record buffers per client next to the BLE and
+//! Wi-Fi buffers. There's one [`Records`], in a static, and a connection
+//! holds it from [`take`] for as long as it's open, so there's only ever
+//! one TLS connection. The gateway's session would hold it for good, so it
+//! ends whenever another client is [`wanted`] waiting for it, and connects
+//! again once the other is done.
+
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::mutex::MutexGuard;
+use embassy_sync::signal::Signal;
+use embedded_io_async::Read;
+use embedded_io_async::Write;
+use embedded_tls::Aes128GcmSha256;
+use embedded_tls::TlsConfig;
+use embedded_tls::TlsConnection;
+use embedded_tls::TlsContext;
+use embedded_tls::UnsecureProvider;
+use rand_core::CryptoRng;
+use rand_core::RngCore;
+
+use crate::config::Text;
+use crate::error;
+use crate::fmt;
+
+crate::config_key!(
+    /// Name of the pre-shared key at the servers
+    pub IDENTITY: Text = "tls.identity",
+    Text::new(""),
+);
+
+crate::config_key!(
+    /// Pre-shared key, in hex
+    pub PSK: Text = "tls.psk",
+    Text::new(""),
+);
+
+/// Longest key there's room for
+pub const PSK_MAX: usize = 32;
+
+/// A whole record of the largest size, and its header and tag
+const READ_MAX: usize = 16384 + 256;
+
+/// What we send is small, records are split to fit
+const WRITE_MAX: usize = 2048;
+
+/// Record buffers for one connection
+pub struct Records {
+    read: [u8; READ_MAX],
+    write: [u8; WRITE_MAX],
+}
+
+impl Records {
+    pub const fn new() -> Self {
+        Self {
+            read: [0; READ_MAX],
+            write: [0; WRITE_MAX],
+        }
+    }
+}
+
+impl Default for Records {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static RECORDS: Mutex<CriticalSectionRawMutex, Records> = Mutex::new(Records::new());
+
+/// A client is waiting for the records
+static WANTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Take the record buffers, waiting for the connection that has them to
+/// hand them over
+pub async fn take() -> MutexGuard<'static, CriticalSectionRawMutex, Records> {
+    if let Ok(records) = RECORDS.try_lock() {
+        return records;
+    }
+    WANTED.signal(());
+    let records = RECORDS.lock().await;
+    // whoever asked got them
+    WANTED.reset();
+    records
+}
+
+/// Wait for another client to want the record buffers. A connection that
+/// stays open should close when this returns.
+pub async fn wanted() {
+    WANTED.wait().await
+}
+
+/// The ring oscillator's random bits, all the entropy the RP2040 has
+struct Rng;
+
+impl RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        RoscRng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RoscRng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RoscRng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        RoscRng.try_fill_bytes(dest)
+    }
+}
+
+impl CryptoRng for Rng {}
+
+/// The provisioned key, `None` if it's missing or not hex
+fn key(out: &mut [u8; PSK_MAX]) -> Option<usize> {
+    let psk = PSK.get();
+    let hex = psk.as_str().as_bytes();
+    if hex.is_empty() || hex.len() % 2 != 0 || hex.len() / 2 > out.len() {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    for (byte, pair) in out.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = (digit(pair[0])? << 4) | digit(pair[1])?;
+    }
+    Some(hex.len() / 2)
+}
+
+/// Whether a key is provisioned
+pub fn provisioned() -> bool {
+    let mut psk = [0; PSK_MAX];
+    key(&mut psk).is_some() && !IDENTITY.get().is_empty()
+}
+
+/// Open a TLS session with `server` over `socket`, a connected TCP socket
+pub async fn open<'a, S: Read + Write + 'a>(
+    socket: S,
+    server: &str,
+    records: &'a mut Records,
+) -> Result<TlsConnection<'a, S, Aes128GcmSha256>, &'static str> {
+    let identity = IDENTITY.get();
+    let mut psk = [0; PSK_MAX];
+    let len = key(&mut psk).ok_or("no TLS key provisioned")?;
+    if identity.is_empty() {
+        return Err("no TLS key provisioned");
+    }
+    let identities = [identity.as_str().as_bytes()];
+    let config = TlsConfig::new()
+        .with_server_name(server)
+        .with_psk(&psk[..len], &identities);
+
+    let mut tls = TlsConnection::new(socket, &mut records.read, &mut records.write);
+    // unsecure only in that certificates aren't verified, the key
+    // authenticates the server
+    let context = TlsContext::new(&config, UnsecureProvider::new::<Aes128GcmSha256>(Rng));
+    tls.open(context).await.map_err(|e| {
+        error!("[tls] {:?}", fmt::Dbg(&e));
+        "TLS handshake failed"
+    })?;
+    Ok(tls)
+}
@@ -0,0 +1,285 @@
+//! VL53L0X time-of-flight distance sensor
+//!
+//! ST doesn't document the registers, only its API. Setting the sensor up
+//! follows what the API does at start-up: the tuning settings it loads,
+//! picking the reference SPADs from the counts stored at the factory, and
+//! the VHV and phase calibrations. The default timing budget of about 33ms
+//! is kept. Ranging is single shot, the sensor idling in between.
+
+use embassy_rp::i2c;
+use embassy_time::Timer;
+
+use crate::bus::Bus;
+
+/// Fixed, unless changed after every power up
+const ADDRESS: u8 = 0x29;
+
+// registers
+const SYSRANGE_START: u8 = 0x00;
+const SYSTEM_SEQUENCE_CONFIG: u8 = 0x01;
+const SYSTEM_INTERRUPT_CONFIG_GPIO: u8 = 0x0a;
+const SYSTEM_INTERRUPT_CLEAR: u8 = 0x0b;
+const RESULT_INTERRUPT_STATUS: u8 = 0x13;
+const RESULT_RANGE: u8 = 0x1e;
+const FINAL_RANGE_MIN_COUNT_RATE: u8 = 0x44;
+const MSRC_CONFIG_CONTROL: u8 = 0x60;
+const GPIO_HV_MUX_ACTIVE_HIGH: u8 = 0x84;
+const VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV: u8 = 0x89;
+const DYNAMIC_SPAD_REF_EN_START_OFFSET: u8 = 0x4f;
+const DYNAMIC_SPAD_NUM_REQUESTED_REF_SPAD: u8 = 0x4e;
+const GLOBAL_CONFIG_REF_EN_START_SELECT: u8 = 0xb6;
+const GLOBAL_CONFIG_SPAD_ENABLES_REF: u8 = 0xb0;
+const MODEL_ID: u8 = 0xc0;
+/// Selects the page of other registers, 0 for the ones above
+const PAGE: u8 = 0xff;
+
+const VL53L0X_ID: u8 = 0xee;
+
+/// Steps of a sequence run, all but the ones the API leaves off by default
+/// (TCC, MSRC)
+const SEQUENCE_DEFAULT: u8 = 0xe8;
+
+/// Return signal rate limit of 0.25 MCPS, in 9.7 fixed point
+const SIGNAL_RATE_LIMIT: u16 = 32;
+
+/// What the range reads without a target in range
+const OUT_OF_RANGE_MM: u16 = 8190;
+
+/// Time between polls of a measurement
+const POLL_MS: u64 = 5;
+
+/// Polls before giving up on a measurement, well beyond the timing budget
+const MAX_POLLS: u32 = 40;
+
+/// The tuning settings from the API, `(register, value)`
+#[rustfmt::skip]
+const TUNING: [(u8, u8); 80] = [
+    (0xff, 0x01), (0x00, 0x00),
+    (0xff, 0x00), (0x09, 0x00), (0x10, 0x00), (0x11, 0x00), (0x24, 0x01), (0x25, 0xff),
+    (0x75, 0x00),
+    (0xff, 0x01), (0x4e, 0x2c), (0x48, 0x00), (0x30, 0x20),
+    (0xff, 0x00), (0x30, 0x09), (0x54, 0x00), (0x31, 0x04), (0x32, 0x03), (0x40, 0x83),
+    (0x46, 0x25), (0x60, 0x00), (0x27, 0x00), (0x50, 0x06), (0x51, 0x00), (0x52, 0x96),
+    (0x56, 0x08), (0x57, 0x30), (0x61, 0x00), (0x62, 0x00), (0x64, 0x00), (0x65, 0x00),
+    (0x66, 0xa0),
+    (0xff, 0x01), (0x22, 0x32), (0x47, 0x14), (0x49, 0xff), (0x4a, 0x00),
+    (0xff, 0x00), (0x7a, 0x0a), (0x7b, 0x00), (0x78, 0x21),
+    (0xff, 0x01), (0x23, 0x34), (0x42, 0x00), (0x44, 0xff), (0x45, 0x26), (0x46, 0x05),
+    (0x40, 0x40), (0x0e, 0x06), (0x20, 0x1a), (0x43, 0x40),
+    (0xff, 0x00), (0x34, 0x03), (0x35, 0x44),
+    (0xff, 0x01), (0x31, 0x04), (0x4b, 0x09), (0x4c, 0x05), (0x4d, 0x04),
+    (0xff, 0x00), (0x44, 0x00), (0x45, 0x20), (0x47, 0x08), (0x48, 0x28), (0x67, 0x00),
+    (0x70, 0x04), (0x71, 0x01), (0x72, 0xfe), (0x76, 0x00), (0x77, 0x00),
+    (0xff, 0x01), (0x0d, 0x01),
+    (0xff, 0x00), (0x80, 0x01), (0x01, 0xf8),
+    (0xff, 0x01), (0x8e, 0x01), (0x00, 0x01), (0xff, 0x00), (0x80, 0x00),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Bus(i2c::Error),
+    /// The sensor never finished
+    Timeout,
+}
+
+impl From<i2c::Error> for Error {
+    fn from(e: i2c::Error) -> Self {
+        Self::Bus(e)
+    }
+}
+
+async fn read(bus: &Bus, reg: u8, buf: &mut [u8]) -> Result<(), i2c::Error> {
+    bus.lock()
+        .await
+        .write_read_async(ADDRESS as u16, [reg], buf)
+        .await
+}
+
+async fn read_u8(bus: &Bus, reg: u8) -> Result<u8, i2c::Error> {
+    let mut value = [0];
+    read(bus, reg, &mut value).await?;
+    Ok(value[0])
+}
+
+async fn write(bus: &Bus, reg: u8, value: &[u8]) -> Result<(), i2c::Error> {
+    let mut buf = [0; 7];
+    buf[0] = reg;
+    buf[1..=value.len()].copy_from_slice(value);
+    bus.lock()
+        .await
+        .write_async(ADDRESS as u16, buf[..=value.len()].iter().copied())
+        .await
+}
+
+async fn write_u8(bus: &Bus, reg: u8, value: u8) -> Result<(), i2c::Error> {
+    write(bus, reg, &[value]).await
+}
+
+async fn write_all(bus: &Bus, writes: &[(u8, u8)]) -> Result<(), i2c::Error> {
+    for &(reg, value) in writes {
+        write_u8(bus, reg, value).await?;
+    }
+    Ok(())
+}
+
+/// Poll `reg` until `done` holds for it
+async fn wait(bus: &Bus, reg: u8, done: impl Fn(u8) -> bool) -> Result<(), Error> {
+    for _ in 0..MAX_POLLS {
+        if done(read_u8(bus, reg).await?) {
+            return Ok(());
+        }
+        Timer::after_millis(POLL_MS).await;
+    }
+    Err(Error::Timeout)
+}
+
+pub struct Vl53l0x {
+    /// Written back before every measurement, as the API does
+    stop_variable: u8,
+}
+
+impl Vl53l0x {
+    /// Find the sensor and set it up, `None` if it isn't there
+    pub async fn probe(bus: &Bus) -> Result<Option<Self>, Error> {
+        if read_u8(bus, MODEL_ID).await? != VL53L0X_ID {
+            return Ok(None);
+        }
+
+        // 2.8V I/O, the pads are fed 3.3V
+        let pad = read_u8(bus, VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV).await?;
+        write_u8(bus, VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV, pad | 0x01).await?;
+        // standard I²C mode
+        write_u8(bus, 0x88, 0x00).await?;
+        write_all(bus, &[(0x80, 0x01), (PAGE, 0x01), (0x00, 0x00)]).await?;
+        let stop_variable = read_u8(bus, 0x91).await?;
+        write_all(bus, &[(0x00, 0x01), (PAGE, 0x00), (0x80, 0x00)]).await?;
+
+        // no MSRC and pre-range signal rate limit checks
+        let msrc = read_u8(bus, MSRC_CONFIG_CONTROL).await?;
+        write_u8(bus, MSRC_CONFIG_CONTROL, msrc | 0x12).await?;
+        write(
+            bus,
+            FINAL_RANGE_MIN_COUNT_RATE,
+            &SIGNAL_RATE_LIMIT.to_be_bytes(),
+        )
+        .await?;
+        write_u8(bus, SYSTEM_SEQUENCE_CONFIG, 0xff).await?;
+
+        set_ref_spads(bus).await?;
+        write_all(bus, &TUNING).await?;
+
+        // interrupt on a new sample, active low
+        write_u8(bus, SYSTEM_INTERRUPT_CONFIG_GPIO, 0x04).await?;
+        let mux = read_u8(bus, GPIO_HV_MUX_ACTIVE_HIGH).await?;
+        write_u8(bus, GPIO_HV_MUX_ACTIVE_HIGH, mux & !0x10).await?;
+        write_u8(bus, SYSTEM_INTERRUPT_CLEAR, 0x01).await?;
+
+        // VHV, then phase calibration
+        write_u8(bus, SYSTEM_SEQUENCE_CONFIG, 0x01).await?;
+        calibrate(bus, 0x40).await?;
+        write_u8(bus, SYSTEM_SEQUENCE_CONFIG, 0x02).await?;
+        calibrate(bus, 0x00).await?;
+        write_u8(bus, SYSTEM_SEQUENCE_CONFIG, SEQUENCE_DEFAULT).await?;
+
+        Ok(Some(Self { stop_variable }))
+    }
+
+    /// Range to the nearest target in mm, `None` without one in range
+    pub async fn measure(&mut self, bus: &Bus) -> Result<Option<u16>, Error> {
+        write_all(
+            bus,
+            &[
+                (0x80, 0x01),
+                (PAGE, 0x01),
+                (0x00, 0x00),
+                (0x91, self.stop_variable),
+                (0x00, 0x01),
+                (PAGE, 0x00),
+                (0x80, 0x00),
+                (SYSRANGE_START, 0x01),
+            ],
+        )
+        .await?;
+        wait(bus, SYSRANGE_START, |start| start & 0x01 == 0).await?;
+        wait(bus, RESULT_INTERRUPT_STATUS, |status| status & 0x07 != 0).await?;
+
+        let mut range = [0; 2];
+        read(bus, RESULT_RANGE, &mut range).await?;
+        write_u8(bus, SYSTEM_INTERRUPT_CLEAR, 0x01).await?;
+        let mm = u16::from_be_bytes(range);
+        Ok((mm < OUT_OF_RANGE_MM).then_some(mm))
+    }
+}
+
+/// Enable the number and kind of reference SPADs found at the factory
+async fn set_ref_spads(bus: &Bus) -> Result<(), Error> {
+    write_all(
+        bus,
+        &[(0x80, 0x01), (PAGE, 0x01), (0x00, 0x00), (PAGE, 0x06)],
+    )
+    .await?;
+    let reg = read_u8(bus, 0x83).await?;
+    write_u8(bus, 0x83, reg | 0x04).await?;
+    write_all(
+        bus,
+        &[
+            (PAGE, 0x07),
+            (0x81, 0x01),
+            (0x80, 0x01),
+            (0x94, 0x6b),
+            (0x83, 0x00),
+        ],
+    )
+    .await?;
+    wait(bus, 0x83, |reg| reg != 0x00).await?;
+    write_u8(bus, 0x83, 0x01).await?;
+    let info = read_u8(bus, 0x92).await?;
+    write_all(bus, &[(0x81, 0x00), (PAGE, 0x06)]).await?;
+    let reg = read_u8(bus, 0x83).await?;
+    write_u8(bus, 0x83, reg & !0x04).await?;
+    write_all(
+        bus,
+        &[(PAGE, 0x01), (0x00, 0x01), (PAGE, 0x00), (0x80, 0x00)],
+    )
+    .await?;
+    let count = info & 0x7f;
+    let aperture = info & 0x80 != 0;
+
+    let mut map = [0; 6];
+    read(bus, GLOBAL_CONFIG_SPAD_ENABLES_REF, &mut map).await?;
+    write_all(
+        bus,
+        &[
+            (PAGE, 0x01),
+            (DYNAMIC_SPAD_REF_EN_START_OFFSET, 0x00),
+            (DYNAMIC_SPAD_NUM_REQUESTED_REF_SPAD, 0x2c),
+            (PAGE, 0x00),
+            (GLOBAL_CONFIG_REF_EN_START_SELECT, 0xb4),
+        ],
+    )
+    .await?;
+
+    // aperture SPADs start at 12
+    let first = if aperture { 12 } else { 0 };
+    let mut enabled = 0;
+    for spad in 0..48 {
+        let bit = 1 << (spad % 8);
+        let byte = &mut map[spad / 8];
+        if spad < first || enabled == count {
+            *byte &= !bit;
+        } else if *byte & bit != 0 {
+            enabled += 1;
+        }
+    }
+    write(bus, GLOBAL_CONFIG_SPAD_ENABLES_REF, &map).await?;
+    Ok(())
+}
+
+async fn calibrate(bus: &Bus, vhv: u8) -> Result<(), Error> {
+    write_u8(bus, SYSRANGE_START, 0x01 | vhv).await?;
+    wait(bus, RESULT_INTERRUPT_STATUS, |status| status & 0x07 != 0).await?;
+    write_u8(bus, SYSTEM_INTERRUPT_CLEAR, 0x01).await?;
+    write_u8(bus, SYSRANGE_START, 0x00).await?;
+    Ok(())
+}
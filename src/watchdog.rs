@@ -0,0 +1,116 @@
+//! Hardware watchdog and recovery counters
+//!
+//! [`run`] starts the RP2040 watchdog and feeds it as long as the executor
+//! runs and no monitored task (see [`crate::monitor`]) stays stalled for
+//! [`STALL_GRACE`] past its timeout. That's the supervisors' time to get
+//! it going again, the BLE stack getting rebuilt by main; whatever is still
+//! stuck after it resets the board, and a hung executor does so within
+//! [`TIMEOUT`].
+//!
+//! The recovery characteristic counts both, little endian:
+//!
+//! | bytes | content                                           |
+//! |-------|---------------------------------------------------|
+//! | 0..2  | BLE stack restarts since boot                     |
+//! | 2..4  | watchdog resets since power on, saturating        |
+
+use core::cell::Cell;
+
+use embassy_rp::peripherals::WATCHDOG;
+use embassy_rp::watchdog::ResetReason;
+use embassy_rp::watchdog::Watchdog;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Timer;
+
+use crate::error;
+use crate::info;
+use crate::monitor;
+
+/// Time without a feed before the watchdog resets the board, well under
+/// the 8.3s the hardware counts to
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the watchdog is fed
+const FEED_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long a task may stay stalled before we let the watchdog reset
+pub const STALL_GRACE: Duration = Duration::from_secs(30);
+
+/// Watchdog scratch register counting watchdog resets. Scratch registers
+/// survive every reset but power on; [`crate::system`] uses scratch 0.
+const RESETS_SCRATCH: usize = 1;
+
+/// Size of the recovery characteristic
+pub const REPORT_SIZE: usize = 4;
+
+static STACK_RESTARTS: Mutex<CriticalSectionRawMutex, Cell<u16>> = Mutex::new(Cell::new(0));
+
+static RESETS: Mutex<CriticalSectionRawMutex, Cell<u16>> = Mutex::new(Cell::new(0));
+
+/// Count a teardown and rebuild of the BLE stack
+pub fn stack_restarted() {
+    STACK_RESTARTS.lock(|cell| cell.set(cell.get().saturating_add(1)));
+}
+
+/// The recovery characteristic
+pub fn report() -> [u8; REPORT_SIZE] {
+    let mut out = [0; REPORT_SIZE];
+    out[0..2].copy_from_slice(&STACK_RESTARTS.lock(|cell| cell.get()).to_le_bytes());
+    out[2..4].copy_from_slice(&RESETS.lock(|cell| cell.get()).to_le_bytes());
+    out
+}
+
+/// Count the last reset if the watchdog did it, then start the watchdog
+/// and feed it until something stays stuck.
+///
+/// Call once boot is done: the flash and config work before it blocks for
+/// longer than [`TIMEOUT`].
+pub async fn run(watchdog: WATCHDOG) -> ! {
+    let mut watchdog = Watchdog::new(watchdog);
+
+    let mut resets = watchdog.get_scratch(RESETS_SCRATCH);
+    if watchdog.reset_reason() == Some(ResetReason::TimedOut) {
+        resets = resets.saturating_add(1);
+        watchdog.set_scratch(RESETS_SCRATCH, resets);
+        error!(
+            "[watchdog] reset by the watchdog ({} since power on)",
+            resets
+        );
+    }
+    RESETS.lock(|cell| cell.set(resets.min(u16::MAX as u32) as u16));
+
+    // a probe halting the core isn't a hang
+    watchdog.pause_on_debug(true);
+    watchdog.start(TIMEOUT);
+    info!("[watchdog] started, {}ms timeout", TIMEOUT.as_millis());
+
+    loop {
+        if let Some(task) = monitor::TASKS.iter().find(|task| task.stuck(STALL_GRACE)) {
+            error!("[watchdog] {} stuck, letting the watchdog reset", task.name);
+            // the executor keeps running, only the feeding stops
+            loop {
+                Timer::after(FEED_PERIOD).await;
+            }
+        }
+        watchdog.feed();
+        Timer::after(FEED_PERIOD).await;
+    }
+}
+
+crate::register_command!(
+    RECOVERY,
+    "recovery",
+    "",
+    "show BLE stack restarts and watchdog resets",
+    |args| {
+        args.end()?;
+        info!(
+            "{} BLE stack restarts, {} watchdog resets",
+            STACK_RESTARTS.lock(|cell| cell.get()),
+            RESETS.lock(|cell| cell.get())
+        );
+        Ok(())
+    }
+);
@@ -0,0 +1,422 @@
+//! Wind and rain
+//!
+//! The usual weather station kit: a cup anemometer and a tipping bucket
+//! rain gauge, both closing a reed switch to ground, on two spare pins
+//! (`weather.wind` and `weather.rain`), and a wind vane on the ADC sensor
+//! input with `weather.vane` set. A PIO state machine each timestamps the
+//! switches' edges (see [`crate::capture`]), so the debounce goes by when
+//! an edge happened rather than when the task got to it. The anemometer's
+//! pulses per second and the bucket's tips per minute are kept in a
+//! history.
+//!
+//! - Wind speed is the mean over `weather.average` seconds (10 minutes by
+//!   default, as weather services report it), at `weather.wind_factor`
+//!   mm/s per pulse a second.
+//! - The gust is the highest mean over `weather.gust` seconds within that,
+//!   reported as its factor over the mean wind speed.
+//! - Rainfall is what fell over the last `weather.rain_window` minutes, at
+//!   `weather.rain_tip` µm a tip.
+//! - The vane switches one of 16 resistors against a 10kΩ pull-up, and each
+//!   direction reads as the nearest of their voltages.
+//!
+//! The readings go out on the Environmental Sensing Service next to the
+//! environmental sensor's (see [`crate::environment`]), as True Wind Speed,
+//! True Wind Direction, Gust Factor and Rainfall.
+
+use core::cell::Cell;
+use core::cell::RefCell;
+use core::future::pending;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use embassy_futures::join::join3;
+use embassy_rp::pio::Instance;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use embassy_time::Ticker;
+use trouble_host::prelude::*;
+
+use crate::adcstream;
+use crate::capture::EdgeCapture;
+use crate::config;
+use crate::error;
+use crate::gpio;
+use crate::info;
+use crate::level;
+use crate::modules;
+use crate::pinmap;
+use crate::pinmap::PinMap;
+use crate::telemetry;
+use crate::writes;
+
+crate::config_key!(
+    /// GPIO with the anemometer's switch, unused if `0xff`
+    pub WIND: u32 = "weather.wind",
+    pinmap::UNUSED as u32,
+);
+
+crate::config_key!(
+    /// GPIO with the rain gauge's switch, unused if `0xff`
+    pub RAIN: u32 = "weather.rain",
+    pinmap::UNUSED as u32,
+);
+
+crate::config_key!(
+    /// Read the ADC sensor input as a wind vane
+    pub VANE: bool = "weather.vane",
+    false,
+);
+
+crate::config_key!(
+    /// Wind speed per anemometer pulse a second, in mm/s
+    pub WIND_FACTOR: u32 = "weather.wind_factor",
+    667,
+);
+
+crate::config_key!(
+    /// Rain per bucket tip, in µm
+    pub RAIN_TIP: u32 = "weather.rain_tip",
+    279,
+);
+
+crate::config_key!(
+    /// Window of the mean wind speed in s, at most [`AVERAGE_MAX_S`]
+    pub AVERAGE: u32 = "weather.average",
+    600,
+);
+
+crate::config_key!(
+    /// Window of a gust in s
+    pub GUST: u32 = "weather.gust",
+    3,
+);
+
+crate::config_key!(
+    /// Window of the rainfall in minutes, at most [`RAIN_MAX_MIN`]
+    pub RAIN_WINDOW: u32 = "weather.rain_window",
+    60,
+);
+
+/// Longest wind speed window
+pub const AVERAGE_MAX_S: usize = 600;
+
+/// Longest rainfall window, a day
+pub const RAIN_MAX_MIN: usize = 24 * 60;
+
+/// Reed switches bounce for a few ms
+const WIND_DEBOUNCE: Duration = Duration::from_millis(5);
+
+/// The bucket takes a while to settle after tipping
+const RAIN_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// The vane's 12-bit readings, from north clockwise in 22.5° steps
+const VANE_READINGS: [u16; 16] = [
+    3143, 1624, 1845, 335, 372, 264, 738, 506, 1149, 979, 2520, 2397, 3780, 3309, 3548, 2810,
+];
+
+/// The pins the switches are on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pins {
+    pub wind: Option<u8>,
+    pub rain: Option<u8>,
+}
+
+/// Pick the pins from the configuration, leaving out the ones `pins`, the
+/// GPIO outputs and the level sensor have. Call once at boot, after
+/// [`level::assign`].
+pub fn assign(pins: &PinMap, outputs: &gpio::Assignment, level: Option<level::Pins>) -> Pins {
+    let taken = |pin: u8| {
+        outputs.outputs.contains(&Some(pin))
+            || outputs.pwm == Some(pin)
+            || level.is_some_and(|l| l.trigger == pin || l.echo == pin)
+    };
+    let pin = |key: &config::Key<u32>| {
+        let pin = key.get();
+        if pin == pinmap::UNUSED as u32 {
+            return None;
+        }
+        let pin = u8::try_from(pin)
+            .ok()
+            .filter(|&pin| pins.is_free(pin) && !taken(pin));
+        if pin.is_none() {
+            error!("[weather] {} isn't a free pin, ignored", key.name);
+        }
+        pin
+    };
+
+    let mut assigned = Pins {
+        wind: pin(&WIND),
+        rain: pin(&RAIN),
+    };
+    if assigned.wind.is_some() && assigned.wind == assigned.rain {
+        error!("[weather] wind and rain are the same pin, rain ignored");
+        assigned.rain = None;
+    }
+    info!("[weather] {:?}", assigned);
+    assigned
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Reading {
+    /// Mean wind speed in 0.01 m/s
+    pub wind: u16,
+    /// Strongest gust in 0.01 m/s
+    pub gust: u16,
+    /// Where the wind comes from in 0.01°, `None` without a vane
+    pub direction: Option<u16>,
+    /// In µm
+    pub rain: u32,
+}
+
+static READING: Mutex<CriticalSectionRawMutex, Cell<Reading>> = Mutex::new(Cell::new(Reading {
+    wind: 0,
+    gust: 0,
+    direction: None,
+    rain: 0,
+}));
+
+/// Counted since the last second, or minute for the rain
+static PULSES: AtomicU32 = AtomicU32::new(0);
+static TIPS: AtomicU32 = AtomicU32::new(0);
+
+struct History {
+    /// Anemometer pulses in each of the last seconds
+    pulses: [u16; AVERAGE_MAX_S],
+    /// Bucket tips in each of the last minutes
+    tips: [u8; RAIN_MAX_MIN],
+    /// Seconds seen, the next slot at its remainder
+    seconds: usize,
+    minutes: usize,
+}
+
+static HISTORY: Mutex<CriticalSectionRawMutex, RefCell<History>> =
+    Mutex::new(RefCell::new(History {
+        pulses: [0; AVERAGE_MAX_S],
+        tips: [0; RAIN_MAX_MIN],
+        seconds: 0,
+        minutes: 0,
+    }));
+
+impl History {
+    /// The last `n` entries of `ring`, newest first, with `seen` entries
+    /// written so far
+    fn last<T: Copy>(ring: &[T], seen: usize, n: usize) -> impl Iterator<Item = T> + '_ {
+        let n = n.min(seen).min(ring.len());
+        (1..=n).map(move |back| ring[(seen - back) % ring.len()])
+    }
+
+    /// Mean and strongest gust, in pulses per 1000 s
+    fn wind(&self) -> (u32, u32) {
+        let average = (AVERAGE.get() as usize).clamp(1, AVERAGE_MAX_S);
+        let gust = (GUST.get() as usize).clamp(1, average);
+        let seconds = average.min(self.seconds).max(1);
+
+        let mut total = 0;
+        let mut window = 0;
+        let mut strongest = 0;
+        // the gust window's far end, `gust` seconds behind
+        let mut trailing = Self::last(&self.pulses, self.seconds, average);
+        for (idx, pulses) in Self::last(&self.pulses, self.seconds, average).enumerate() {
+            total += pulses as u32;
+            window += pulses as u32;
+            if idx >= gust {
+                window -= trailing.next().unwrap_or(0) as u32;
+            }
+            if idx + 1 >= gust.min(seconds) {
+                strongest = strongest.max(window);
+            }
+        }
+        (
+            total * 1000 / seconds as u32,
+            strongest * 1000 / gust.min(seconds) as u32,
+        )
+    }
+
+    /// Tips over the rainfall window
+    fn tips(&self) -> u32 {
+        let window = (RAIN_WINDOW.get() as usize).clamp(1, RAIN_MAX_MIN);
+        Self::last(&self.tips, self.minutes, window)
+            .map(u32::from)
+            .sum()
+    }
+}
+
+/// The direction of a vane reading in 0.01°
+fn direction(raw: u16) -> u16 {
+    let (idx, _) = VANE_READINGS
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, &v)| v.abs_diff(raw))
+        .unwrap();
+    idx as u16 * 2250
+}
+
+pub fn reading() -> Reading {
+    READING.lock(|r| r.get())
+}
+
+/// True Wind Speed, little endian
+pub fn wind_speed() -> [u8; 2] {
+    reading().wind.to_le_bytes()
+}
+
+/// True Wind Direction, little endian. The characteristic has no unknown
+/// value, north stands in for one.
+pub fn wind_direction() -> [u8; 2] {
+    reading().direction.unwrap_or(0).to_le_bytes()
+}
+
+/// Gust Factor, the gust over the mean wind speed in 0.1, 0 in calm air
+pub fn gust_factor() -> [u8; 1] {
+    let reading = reading();
+    let factor = match reading.wind {
+        0 => 0,
+        wind => (reading.gust as u32 * 10 / wind as u32).min(u8::MAX as u32) as u8,
+    };
+    [factor]
+}
+
+/// Rainfall in mm, little endian
+pub fn rainfall() -> [u8; 2] {
+    ((reading().rain / 1000).min(u16::MAX as u32) as u16).to_le_bytes()
+}
+
+fn publish(reading: Reading) {
+    telemetry::record(telemetry::Signal::WindSpeed, reading.wind.into());
+    if let Some(direction) = reading.direction {
+        telemetry::record(telemetry::Signal::WindDirection, direction.into());
+    }
+    telemetry::record(
+        telemetry::Signal::Rainfall,
+        reading.rain.min(i32::MAX as u32) as i32,
+    );
+    let last = READING.lock(|r| r.replace(reading));
+    if reading.wind != last.wind || reading.gust != last.gust {
+        modules::notify(WIND_SPEED_UUID, &wind_speed());
+        modules::notify(GUST_FACTOR_UUID, &gust_factor());
+    }
+    if reading.direction != last.direction {
+        modules::notify(WIND_DIRECTION_UUID, &wind_direction());
+    }
+    if reading.rain != last.rain {
+        modules::notify(RAINFALL_UUID, &rainfall());
+    }
+}
+
+const WIND_SPEED_UUID: Uuid = Uuid::Uuid16(0x2a70u16.to_le_bytes());
+const WIND_DIRECTION_UUID: Uuid = Uuid::Uuid16(0x2a71u16.to_le_bytes());
+const GUST_FACTOR_UUID: Uuid = Uuid::Uuid16(0x2a74u16.to_le_bytes());
+const RAINFALL_UUID: Uuid = Uuid::Uuid16(0x2a78u16.to_le_bytes());
+
+/// Add the readings to the Environmental Sensing Service, which
+/// [`crate::environment`] has
+pub(crate) fn characteristics(svc: &mut modules::Service<'_, '_>) {
+    let props = [CharacteristicProp::Read, CharacteristicProp::Notify];
+    let spec = writes::Spec::new();
+    svc.add(
+        WIND_SPEED_UUID,
+        &props,
+        2,
+        spec.with_read(|| writes::Value::new(&wind_speed())),
+    );
+    svc.add(
+        WIND_DIRECTION_UUID,
+        &props,
+        2,
+        spec.with_read(|| writes::Value::new(&wind_direction())),
+    );
+    svc.add(
+        GUST_FACTOR_UUID,
+        &props,
+        1,
+        spec.with_read(|| writes::Value::new(&gust_factor())),
+    );
+    svc.add(
+        RAINFALL_UUID,
+        &props,
+        2,
+        spec.with_read(|| writes::Value::new(&rainfall())),
+    );
+}
+
+/// Count the falling edges of `input` into `count`, leaving out the ones
+/// within `debounce` of the last one counted
+async fn count<PIO: Instance, const SM: usize>(
+    input: Option<EdgeCapture<'_, PIO, SM>>,
+    count: &AtomicU32,
+    debounce: Duration,
+) -> ! {
+    let Some(mut input) = input else {
+        pending().await
+    };
+    let mut last = None;
+    loop {
+        let edge = input.next().await;
+        if edge.rising || last.is_some_and(|at| edge.at_us - at < debounce.as_micros()) {
+            continue;
+        }
+        last = Some(edge.at_us);
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Move the counts into the history every second and work out the
+/// readings
+async fn tick() -> ! {
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    loop {
+        ticker.next().await;
+        let (pulses, tips) = HISTORY.lock(|history| {
+            let mut history = history.borrow_mut();
+            let slot = history.seconds % AVERAGE_MAX_S;
+            history.pulses[slot] = PULSES.swap(0, Ordering::Relaxed).min(u16::MAX as u32) as u16;
+            history.seconds += 1;
+            if history.seconds % 60 == 0 {
+                let slot = history.minutes % RAIN_MAX_MIN;
+                history.tips[slot] = TIPS.swap(0, Ordering::Relaxed).min(u8::MAX as u32) as u8;
+                history.minutes += 1;
+            }
+            // the running minute counts too
+            (
+                history.wind(),
+                history.tips() + TIPS.load(Ordering::Relaxed),
+            )
+        });
+
+        let factor = WIND_FACTOR.get();
+        // pulses per 1000 s times mm/s per Hz is µm/s
+        let speed = |rate: u32| (rate as u64 * factor as u64 / 10_000).min(u16::MAX as u64) as u16;
+        let direction = VANE
+            .get()
+            .then(adcstream::latest)
+            .flatten()
+            .map(|levels| direction(levels.mean));
+        publish(Reading {
+            wind: speed(pulses.0),
+            gust: speed(pulses.1),
+            direction,
+            rain: tips * RAIN_TIP.get(),
+        });
+    }
+}
+
+/// Count the switches and work out the readings, forever
+pub async fn run<PIO: Instance, const WIND_SM: usize, const RAIN_SM: usize>(
+    wind: Option<EdgeCapture<'_, PIO, WIND_SM>>,
+    rain: Option<EdgeCapture<'_, PIO, RAIN_SM>>,
+) -> ! {
+    if wind.is_none() && rain.is_none() && !VANE.get() {
+        info!("[weather] no station");
+        pending().await
+    }
+    let (never, _, _) = join3(
+        count(wind, &PULSES, WIND_DEBOUNCE),
+        count(rain, &TIPS, RAIN_DEBOUNCE),
+        tick(),
+    )
+    .await;
+    never
+}
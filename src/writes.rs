@@ -0,0 +1,421 @@
+//! Write handlers
+//!
+//! Code outside `blue.rs` reacts to writes by listing a [`Handler`] for a
+//! characteristic's UUID in [`crate::params::Config::write_handlers`].
+//! Handlers run for every write that gets past the maintenance mode check,
+//! in place of any built-in handling, and return an [`AttError`] to reject
+//! the value. They run in the GATT task, so they have to be quick.
+//!
+//! The host answers a write before handing it to us, so the client still
+//! sees it succeed. A rejected value is replaced with the last accepted
+//! one instead, and the error is logged.
+//!
+//! Accepted values of a registration with `persist` set are kept in
+//! [`crate::settings`] once they settle (see [`crate::persist`]), and
+//! served again after a reboot before anything is written. Their handler
+//! doesn't see the restored value, its owner reads it with
+//! [`settings::get`] at startup if it wants it.
+//!
+//! The characteristics modules add themselves (see [`crate::modules`]) are
+//! served entirely from here, by a [`Spec`]: its handler takes the writes,
+//! its reader encodes what we hold for reads and after every write, taken
+//! or not, and it says what a write takes instead of `blue.rs`'s lists. A
+//! handler that has to wait on something hands the value to its module's
+//! task and returns, the task answers with [`crate::modules::respond`].
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use trouble_host::prelude::*;
+
+use crate::error;
+use crate::roles;
+use crate::settings;
+
+/// Max number of characteristics with a handler, or served from here
+pub const HANDLERS_MAX: usize = 64;
+
+/// Longest value kept to put back after a rejected write
+pub const VALUE_MAX: usize = 32;
+
+/// Longest value a [`Reader`] encodes
+pub const READ_MAX: usize = 64;
+
+/// Why a write was rejected, as ATT error codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AttError {
+    WriteNotPermitted,
+    InsufficientAuthorization,
+    InvalidLength,
+    ValueNotAllowed,
+    /// Application errors are 0x80 to 0x9f
+    Application(u8),
+}
+
+impl AttError {
+    pub fn code(&self) -> u8 {
+        match *self {
+            Self::WriteNotPermitted => 0x03,
+            Self::InsufficientAuthorization => 0x08,
+            Self::InvalidLength => 0x0d,
+            Self::ValueNotAllowed => 0x13,
+            Self::Application(code) => 0x80 | (code & 0x1f),
+        }
+    }
+}
+
+/// Takes a written value, `Err` to reject it
+pub type Handler = fn(ConnHandle, &[u8]) -> Result<(), AttError>;
+
+/// Encodes the value of a characteristic
+pub type Reader = fn() -> Value;
+
+/// A value a [`Reader`] encodes, up to [`READ_MAX`] bytes
+#[derive(Clone, Copy)]
+pub struct Value {
+    bytes: [u8; READ_MAX],
+    len: usize,
+}
+
+impl Value {
+    /// Panics if `bytes` is longer than [`READ_MAX`]
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut value = Self {
+            bytes: [0; READ_MAX],
+            len: bytes.len(),
+        };
+        value.bytes[..bytes.len()].copy_from_slice(bytes);
+        value
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// How a characteristic a module adds is served
+#[derive(Clone, Copy)]
+pub struct Spec {
+    /// Takes the writes, `None` if it isn't writable
+    pub write: Option<Handler>,
+    pub read: Option<Reader>,
+    /// What a write takes, see [`roles::PERMISSIONS`]. `Configure` also
+    /// needs the admin lock open.
+    pub access: roles::Access,
+    /// Writes are only taken in maintenance mode
+    pub maintenance: bool,
+    /// Every write matters, like a command's, rather than the latest. A
+    /// maintenance mode one goes on the audit trail, and it can't be
+    /// frozen by a demo override.
+    pub event: bool,
+}
+
+impl Spec {
+    /// Read-only, with nothing to read until [`Spec::with_read`]
+    pub const fn new() -> Self {
+        Self {
+            write: None,
+            read: None,
+            access: roles::Access::Operate,
+            maintenance: false,
+            event: false,
+        }
+    }
+
+    pub const fn with_read(mut self, read: Reader) -> Self {
+        self.read = Some(read);
+        self
+    }
+
+    pub const fn with_write(mut self, write: Handler) -> Self {
+        self.write = Some(write);
+        self
+    }
+
+    pub const fn with_access(mut self, access: roles::Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    pub const fn in_maintenance(mut self) -> Self {
+        self.maintenance = true;
+        self
+    }
+
+    pub const fn as_event(mut self) -> Self {
+        self.event = true;
+        self
+    }
+}
+
+impl Default for Spec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A handler for the characteristic with `uuid`, the first one there is
+#[derive(Clone, Copy)]
+pub struct Registration {
+    pub uuid: Uuid,
+    pub handler: Handler,
+    /// Keep accepted values across reboots
+    pub persist: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    characteristic: Characteristic,
+    uuid: Option<Uuid>,
+    handler: Option<Handler>,
+    read: Option<Reader>,
+    /// What a write takes, for characteristics served from here
+    policy: Option<(roles::Access, bool)>,
+    event: bool,
+    /// Where accepted values are kept, if they are
+    persist: Option<settings::Key>,
+    /// The last accepted value, `None` before the first one
+    last: Option<([u8; VALUE_MAX], usize)>,
+}
+
+/// The handlers of a running server, by value handle
+pub struct Registry {
+    entries: [Option<Entry>; HANDLERS_MAX],
+}
+
+impl Registry {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; HANDLERS_MAX],
+        }
+    }
+
+    /// Add a handler for `characteristic`, `false` if there's no room
+    pub fn on_write(&mut self, characteristic: Characteristic, handler: Handler) -> bool {
+        self.add(Entry {
+            characteristic,
+            uuid: None,
+            handler: Some(handler),
+            read: None,
+            policy: None,
+            event: false,
+            persist: None,
+            last: None,
+        })
+    }
+
+    /// Serve `characteristic`, of `uuid`, by `spec`, `false` if there's no
+    /// room
+    pub fn serve(&mut self, uuid: Uuid, characteristic: Characteristic, spec: Spec) -> bool {
+        self.add(Entry {
+            characteristic,
+            uuid: Some(uuid),
+            handler: spec.write,
+            read: spec.read,
+            policy: Some((spec.access, spec.maintenance)),
+            event: spec.event,
+            persist: None,
+            last: None,
+        })
+    }
+
+    /// Whether another characteristic can be added
+    pub fn has_room(&self) -> bool {
+        self.entries.iter().any(|e| e.is_none())
+    }
+
+    fn add(&mut self, entry: Entry) -> bool {
+        let Some(slot) = self.entries.iter_mut().find(|e| e.is_none()) else {
+            return false;
+        };
+        *slot = Some(entry);
+        true
+    }
+
+    fn find(&self, handle: u16) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|e| e.characteristic.handle == handle)
+    }
+
+    /// Add the `registrations` for the characteristics of `table`, logging
+    /// the ones whose UUID isn't in it
+    pub fn register<M: RawMutex, const N: usize>(
+        &mut self,
+        table: &AttributeTable<'_, M, N>,
+        registrations: &[Registration],
+    ) {
+        for registration in registrations {
+            let mut found = None;
+            table.iterate(|mut it| {
+                while let Some(att) = it.next() {
+                    if let AttributeData::Declaration { handle, uuid, .. } = &att.data {
+                        if *uuid == registration.uuid {
+                            found = Some(*handle);
+                            return;
+                        }
+                    }
+                }
+            });
+            match found {
+                Some(handle) => {
+                    let persist = registration
+                        .persist
+                        .then(|| settings::Key::characteristic(&registration.uuid));
+                    let entry = Entry {
+                        characteristic: Characteristic {
+                            handle,
+                            cccd_handle: None,
+                        },
+                        uuid: Some(registration.uuid),
+                        handler: Some(registration.handler),
+                        read: None,
+                        policy: None,
+                        event: false,
+                        persist,
+                        last: None,
+                    };
+                    if !self.add(entry) {
+                        error!("[writes] no room for the {:?} handler", registration.uuid);
+                    }
+                }
+                None => error!("[writes] no characteristic {:?}", registration.uuid),
+            }
+        }
+    }
+
+    /// Run the handler of `handle`, `None` if it has none
+    pub fn dispatch(
+        &mut self,
+        handle: u16,
+        conn: ConnHandle,
+        value: &[u8],
+    ) -> Option<Result<(), AttError>> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|e| e.characteristic.handle == handle)?;
+        let result = match entry.handler {
+            Some(handler) => handler(conn, value),
+            None => Err(AttError::WriteNotPermitted),
+        };
+        // a reader has what's served after a write, and what's written
+        // may be a secret
+        if result.is_ok() && entry.read.is_none() && value.len() <= VALUE_MAX {
+            let mut last = [0; VALUE_MAX];
+            last[..value.len()].copy_from_slice(value);
+            entry.last = Some((last, value.len()));
+        }
+        Some(result)
+    }
+
+    /// The last value accepted for `handle`
+    pub fn last(&self, handle: u16) -> Option<&[u8]> {
+        let entry = self.find(handle)?;
+        entry.last.as_ref().map(|(value, len)| &value[..*len])
+    }
+
+    /// The value of `handle` as its reader encodes it, `None` without one
+    pub fn read(&self, handle: u16) -> Option<Value> {
+        self.find(handle)?.read.map(|read| read())
+    }
+
+    /// What a write to `handle` takes and whether only in maintenance
+    /// mode, `None` unless it's served from here
+    pub fn policy(&self, handle: u16) -> Option<(roles::Access, bool)> {
+        self.find(handle)?.policy
+    }
+
+    /// Whether every write to `handle` matters, see [`Spec::as_event`]
+    pub fn is_event(&self, handle: u16) -> bool {
+        self.find(handle).is_some_and(|e| e.event)
+    }
+
+    /// Whether writes to `handle` go into the audit trail: the ones a
+    /// [`Spec`] takes only in maintenance mode, as events
+    pub fn audited(&self, handle: u16) -> bool {
+        self.find(handle)
+            .is_some_and(|e| e.event && e.policy.is_some_and(|(_, maintenance)| maintenance))
+    }
+
+    /// The characteristics served from here
+    pub fn served(&self) -> impl Iterator<Item = Characteristic> + '_ {
+        self.entries
+            .iter()
+            .flatten()
+            .filter(|e| e.policy.is_some())
+            .map(|e| e.characteristic)
+    }
+
+    /// The characteristics served from here by UUID, for what modules
+    /// notify
+    pub fn targets(&self) -> Targets {
+        let mut targets = Targets {
+            entries: [None; HANDLERS_MAX],
+        };
+        let served = self.entries.iter().flatten().filter(|e| e.policy.is_some());
+        for (target, entry) in targets.entries.iter_mut().zip(served) {
+            *target = entry.uuid.map(|uuid| (uuid, entry.characteristic));
+        }
+        targets
+    }
+
+    /// Where accepted values of `handle` are kept, `None` if they aren't
+    pub fn persisted(&self, handle: u16) -> Option<settings::Key> {
+        self.find(handle)?.persist
+    }
+
+    /// Take the kept values as the last accepted ones
+    pub async fn restore(&mut self) {
+        for entry in self.entries.iter_mut().flatten() {
+            let Some(key) = entry.persist else {
+                continue;
+            };
+            let mut value = [0; VALUE_MAX];
+            match settings::get(key, &mut value).await {
+                Ok(Some(len)) => entry.last = Some((value, len)),
+                Ok(None) => {}
+                Err(e) => error!(
+                    "[writes] restoring {} failed: {:?}",
+                    entry.characteristic.handle, e
+                ),
+            }
+        }
+    }
+
+    /// The handles with a kept value, and the value
+    pub fn restored(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.entries
+            .iter()
+            .flatten()
+            .filter(|e| e.persist.is_some())
+            .filter_map(|e| {
+                e.last
+                    .as_ref()
+                    .map(|(value, len)| (e.characteristic.handle, &value[..*len]))
+            })
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The characteristics a [`Registry`] serves, by UUID
+#[derive(Clone, Copy)]
+pub struct Targets {
+    entries: [Option<(Uuid, Characteristic)>; HANDLERS_MAX],
+}
+
+impl Targets {
+    pub fn find(&self, uuid: &Uuid) -> Option<Characteristic> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|(u, _)| u == uuid)
+            .map(|(_, characteristic)| *characteristic)
+    }
+}
@@ -0,0 +1,124 @@
+//! WebSocket telemetry stream
+//!
+//! `GET /ws` on the HTTP server upgrades to a WebSocket. The device first
+//! sends a text frame with the full state (same JSON as `GET /state`), then
+//! one text frame per control change holding only the changed control.
+//! Anything the client sends is ignored except a close frame.
+
+use core::fmt::Write as _;
+
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_net::tcp::Error;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::tcp::TcpWriter;
+
+use crate::controls;
+use crate::error;
+use crate::http;
+use crate::http::Body;
+use crate::info;
+use crate::integrity::Sha1;
+
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const FIN: u8 = 0x80;
+
+/// Base64 of the SHA-1 of the client key and the protocol GUID
+fn accept_key(key: &str) -> [u8; 28] {
+    let mut hash = Sha1::new();
+    hash.update(key.as_bytes());
+    hash.update(GUID);
+    let digest = hash.finish();
+
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = [b'='; 28];
+    for (chunk, out) in digest.chunks(3).zip(out.chunks_mut(4)) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out[i] = ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f];
+        }
+    }
+    out
+}
+
+/// Complete the upgrade and stream changes until the client goes away
+pub async fn stream(socket: &mut TcpSocket<'_>, key: &str) {
+    let Some(mut changes) = controls::subscribe() else {
+        error!("[ws] no change subscriber available");
+        let _ = http::write_all(socket, b"HTTP/1.1 503 Service Unavailable\r\n\r\n").await;
+        return;
+    };
+
+    let mut head = Body::new();
+    let _ = write!(
+        head,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        // the alphabet is ascii
+        core::str::from_utf8(&accept_key(key)).unwrap()
+    );
+    if http::write_all(socket, head.as_bytes()).await.is_err() {
+        return;
+    }
+    info!("[ws] client connected");
+
+    let (mut reader, mut writer) = socket.split();
+    let mut snapshot = Body::new();
+    http::write_controls(&mut snapshot, 0..controls::CONTROLS.len());
+    if send(&mut writer, OPCODE_TEXT, snapshot.as_bytes())
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let mut buf = [0; 64];
+    loop {
+        match select(changes.next_message_pure(), reader.read(&mut buf)).await {
+            Either::First(idx) => {
+                let mut delta = Body::new();
+                http::write_controls(&mut delta, core::iter::once(idx));
+                if send(&mut writer, OPCODE_TEXT, delta.as_bytes())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Either::Second(Ok(0) | Err(_)) => break,
+            Either::Second(Ok(_)) if buf[0] & 0x0f == OPCODE_CLOSE => {
+                let _ = send(&mut writer, OPCODE_CLOSE, &[]).await;
+                break;
+            }
+            Either::Second(Ok(_)) => {}
+        }
+    }
+
+    info!("[ws] client disconnected");
+}
+
+/// Send one unmasked frame
+async fn send(writer: &mut TcpWriter<'_>, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+    let mut header = [FIN | opcode, 0, 0, 0];
+    let header = if payload.len() < 126 {
+        header[1] = payload.len() as u8;
+        &header[..2]
+    } else {
+        header[1] = 126;
+        header[2..4].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        &header[..]
+    };
+
+    for mut data in [header, payload] {
+        while !data.is_empty() {
+            let n = writer.write(data).await?;
+            data = &data[n..];
+        }
+    }
+    writer.flush().await
+}